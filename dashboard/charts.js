@@ -184,6 +184,25 @@ var recordProcessedChart = {
   }
 };
 
+var epochLatencyChart = {
+  "width": 600,
+  "autosize": { "resize": true },
+  "mark": { "type": "line", "point": true },
+  "data": { "name": "table" },
+  "encoding": {
+    "x": {
+      "field": "e",
+      "type": "quantitative",
+      "title": "epoch"
+    },
+    "y": {
+      "field": "ms",
+      "type": "quantitative",
+      "title": "latency (ms)"
+    }
+  }
+};
+
 var types = (_types = {}, _defineProperty(_types, "Processing", "#0b6623"), _defineProperty(_types, "Spinning", "#e48282"), _defineProperty(_types, "ControlMessage", "#4b5f53"), _defineProperty(_types, "DataMessage", "#971757"), _defineProperty(_types, "Waiting", "#FF0000"), _defineProperty(_types, "Busy", "#059dc0"), _types);
 
 var margins = {
@@ -286,12 +305,16 @@ function updatePAG() {
   }).text(genTitle);
 }
 
-var socket = new WebSocket('ws:127.0.0.1:3012');
+// served by `st2 dashboard`, falls back to its default address if opened from disk
+var socket = new WebSocket(location.protocol.startsWith("http") ? "ws://" + location.host + "/ws" + location.search : 'ws://127.0.0.1:3012/ws');
+// whether to switch to epochs as they complete
+var following = true;
 socket.addEventListener("open", function (e) {
   socket.send(JSON.stringify({ type: 'PAG', epoch: 1 }));
   socket.send(JSON.stringify({ type: 'AGG', epoch: 1 }));
   socket.send(JSON.stringify({ type: 'ALL', epoch: 1 }));
   socket.send(JSON.stringify({ type: 'MET', epoch: 1 }));
+  socket.send(JSON.stringify({ type: 'LAT' }));
   socket.send(JSON.stringify({ type: 'INV' }));
   setInterval(function () {
     socket.send(JSON.stringify({ type: 'INV' }));
@@ -324,6 +347,11 @@ function App() {
       splitWorker = _React$useState10[0],
       setSplitWorker = _React$useState10[1];
 
+  var _React$useState11 = React.useState(true),
+      _React$useState12 = _slicedToArray(_React$useState11, 2),
+      follow = _React$useState12[0],
+      setFollow = _React$useState12[1];
+
   React.useEffect(function () {
     var svgParent = d3.select("#d3").append("svg").attr("id", "graph");
     var svg = svgParent.append("g");
@@ -360,6 +388,8 @@ function App() {
       } else if (type == "PAG") {
         pag = payload;
         setPAGEpoch(pagState);
+      } else if (type === "EPOCH" && following) {
+        loadEpoch(payload.e);
       }
     });
 
@@ -378,8 +408,7 @@ function App() {
     updatePAG();
   };
 
-  var epochUpdate = function epochUpdate(e) {
-    var epoch = parseInt(e.target.value);
+  var loadEpoch = function loadEpoch(epoch) {
     setEpoch(epoch || '');
     setKhop(1);
     if (epoch) {
@@ -395,6 +424,18 @@ function App() {
     }
   };
 
+  var epochUpdate = function epochUpdate(e) {
+    // stop following once an epoch is picked manually
+    following = false;
+    setFollow(false);
+    loadEpoch(parseInt(e.target.value));
+  };
+
+  var followUpdate = function followUpdate(e) {
+    following = e.target.checked;
+    setFollow(e.target.checked);
+  };
+
   var highlightUpdate = function highlightUpdate(e) {
     updatePAG();
     setHighlight(e.target.checked);
@@ -408,6 +449,7 @@ function App() {
       { style: { textAlign: "center" } },
       " ST2 Dashboard"
     ),
+    React.createElement(EpochLatency, null),
     React.createElement(
       "h1",
       null,
@@ -436,7 +478,13 @@ function App() {
         { style: { marginRight: "6px" } },
         "Epoch: "
       ),
-      React.createElement("input", { id: "epoch", type: "text", value: epoch, onChange: epochUpdate })
+      React.createElement("input", { id: "epoch", type: "text", value: epoch, onChange: epochUpdate }),
+      React.createElement(
+        "b",
+        { style: { marginLeft: "24px", marginRight: "6px" } },
+        "Follow: "
+      ),
+      React.createElement("input", { id: "follow", type: "checkbox", checked: follow, onChange: followUpdate })
     ),
     React.createElement(
       "div",
@@ -501,22 +549,22 @@ function RecordMetrics(_ref3) {
       splitWorker = _ref3.splitWorker;
 
   // Plot 1: # records sent by each worker to each worker
-  var _React$useState11 = React.useState(undefined),
-      _React$useState12 = _slicedToArray(_React$useState11, 2),
-      p1 = _React$useState12[0],
-      setP1 = _React$useState12[1];
-  // Plot 2: # records processed by each worker
-
-
   var _React$useState13 = React.useState(undefined),
       _React$useState14 = _slicedToArray(_React$useState13, 2),
-      p2 = _React$useState14[0],
-      setP2 = _React$useState14[1];
+      p1 = _React$useState14[0],
+      setP1 = _React$useState14[1];
+  // Plot 2: # records processed by each worker
 
-  var _React$useState15 = React.useState([]),
+
+  var _React$useState15 = React.useState(undefined),
       _React$useState16 = _slicedToArray(_React$useState15, 2),
-      metricsData = _React$useState16[0],
-      setMetricsData = _React$useState16[1];
+      p2 = _React$useState16[0],
+      setP2 = _React$useState16[1];
+
+  var _React$useState17 = React.useState([]),
+      _React$useState18 = _slicedToArray(_React$useState17, 2),
+      metricsData = _React$useState18[0],
+      setMetricsData = _React$useState18[1];
 
   var p1Ref = React.useRef(null);
   var p2Ref = React.useRef(null);
@@ -606,24 +654,24 @@ function CrossMetrics(_ref4) {
 
   // Plot 1
   // # data messages between workers
-  var _React$useState17 = React.useState(undefined),
-      _React$useState18 = _slicedToArray(_React$useState17, 2),
-      p1 = _React$useState18[0],
-      setP1 = _React$useState18[1];
+  var _React$useState19 = React.useState(undefined),
+      _React$useState20 = _slicedToArray(_React$useState19, 2),
+      p1 = _React$useState20[0],
+      setP1 = _React$useState20[1];
   // Plot 2
   // t data messages between workers
   // t control messages between workers
 
 
-  var _React$useState19 = React.useState(undefined),
-      _React$useState20 = _slicedToArray(_React$useState19, 2),
-      p2 = _React$useState20[0],
-      setP2 = _React$useState20[1];
-
-  var _React$useState21 = React.useState([]),
+  var _React$useState21 = React.useState(undefined),
       _React$useState22 = _slicedToArray(_React$useState21, 2),
-      metricsData = _React$useState22[0],
-      setMetricsData = _React$useState22[1];
+      p2 = _React$useState22[0],
+      setP2 = _React$useState22[1];
+
+  var _React$useState23 = React.useState([]),
+      _React$useState24 = _slicedToArray(_React$useState23, 2),
+      metricsData = _React$useState24[0],
+      setMetricsData = _React$useState24[1];
 
   var p1Ref = React.useRef(null);
   var p2Ref = React.useRef(null);
@@ -707,20 +755,20 @@ function ActivityMetrics(_ref5) {
       showWaiting = _ref5.showWaiting,
       splitWorker = _ref5.splitWorker;
 
-  var _React$useState23 = React.useState(undefined),
-      _React$useState24 = _slicedToArray(_React$useState23, 2),
-      aC = _React$useState24[0],
-      setAC = _React$useState24[1];
-
   var _React$useState25 = React.useState(undefined),
       _React$useState26 = _slicedToArray(_React$useState25, 2),
-      aD = _React$useState26[0],
-      setAD = _React$useState26[1];
+      aC = _React$useState26[0],
+      setAC = _React$useState26[1];
 
-  var _React$useState27 = React.useState([]),
+  var _React$useState27 = React.useState(undefined),
       _React$useState28 = _slicedToArray(_React$useState27, 2),
-      metricsData = _React$useState28[0],
-      setMetricsData = _React$useState28[1];
+      aD = _React$useState28[0],
+      setAD = _React$useState28[1];
+
+  var _React$useState29 = React.useState([]),
+      _React$useState30 = _slicedToArray(_React$useState29, 2),
+      metricsData = _React$useState30[0],
+      setMetricsData = _React$useState30[1];
 
   var aCRef = React.useRef(null);
   var aDRef = React.useRef(null);
@@ -801,20 +849,20 @@ function KHops(_ref6) {
       showWaiting = _ref6.showWaiting,
       splitWorker = _ref6.splitWorker;
 
-  var _React$useState29 = React.useState(undefined),
-      _React$useState30 = _slicedToArray(_React$useState29, 2),
-      vis = _React$useState30[0],
-      setVis = _React$useState30[1];
-
   var _React$useState31 = React.useState(undefined),
       _React$useState32 = _slicedToArray(_React$useState31, 2),
-      wVis = _React$useState32[0],
-      setWVis = _React$useState32[1];
+      vis = _React$useState32[0],
+      setVis = _React$useState32[1];
 
-  var _React$useState33 = React.useState([]),
+  var _React$useState33 = React.useState(undefined),
       _React$useState34 = _slicedToArray(_React$useState33, 2),
-      visData = _React$useState34[0],
-      setVisData = _React$useState34[1];
+      wVis = _React$useState34[0],
+      setWVis = _React$useState34[1];
+
+  var _React$useState35 = React.useState([]),
+      _React$useState36 = _slicedToArray(_React$useState35, 2),
+      visData = _React$useState36[0],
+      setVisData = _React$useState36[1];
 
   var visRef = React.useRef(null);
   var wVisRef = React.useRef(null);
@@ -986,35 +1034,35 @@ var formatM = function formatM(e) {
 };
 
 function Invariants() {
-  var _React$useState35 = React.useState([]),
-      _React$useState36 = _slicedToArray(_React$useState35, 2),
-      mEpoch = _React$useState36[0],
-      setMEpoch = _React$useState36[1];
-
   var _React$useState37 = React.useState([]),
       _React$useState38 = _slicedToArray(_React$useState37, 2),
-      mOp = _React$useState38[0],
-      setMOp = _React$useState38[1];
+      mEpoch = _React$useState38[0],
+      setMEpoch = _React$useState38[1];
 
   var _React$useState39 = React.useState([]),
       _React$useState40 = _slicedToArray(_React$useState39, 2),
-      mMsg = _React$useState40[0],
-      setMMsg = _React$useState40[1];
+      mOp = _React$useState40[0],
+      setMOp = _React$useState40[1];
 
-  var _React$useState41 = React.useState(null),
+  var _React$useState41 = React.useState([]),
       _React$useState42 = _slicedToArray(_React$useState41, 2),
-      mE = _React$useState42[0],
-      setME = _React$useState42[1];
+      mMsg = _React$useState42[0],
+      setMMsg = _React$useState42[1];
 
   var _React$useState43 = React.useState(null),
       _React$useState44 = _slicedToArray(_React$useState43, 2),
-      mO = _React$useState44[0],
-      setMO = _React$useState44[1];
+      mE = _React$useState44[0],
+      setME = _React$useState44[1];
 
   var _React$useState45 = React.useState(null),
       _React$useState46 = _slicedToArray(_React$useState45, 2),
-      mM = _React$useState46[0],
-      setMM = _React$useState46[1];
+      mO = _React$useState46[0],
+      setMO = _React$useState46[1];
+
+  var _React$useState47 = React.useState(null),
+      _React$useState48 = _slicedToArray(_React$useState47, 2),
+      mM = _React$useState48[0],
+      setMM = _React$useState48[1];
 
   React.useEffect(function () {
     socket.addEventListener("message", function (e) {
@@ -1098,6 +1146,62 @@ function Invariants() {
     )
   );
 }
+function EpochLatency() {
+  var _React$useState49 = React.useState(undefined),
+      _React$useState50 = _slicedToArray(_React$useState49, 2),
+      view = _React$useState50[0],
+      setView = _React$useState50[1];
+
+  var _React$useState51 = React.useState([]),
+      _React$useState52 = _slicedToArray(_React$useState51, 2),
+      latencies = _React$useState52[0],
+      setLatencies = _React$useState52[1];
+
+  var viewRef = React.useRef(null);
+
+  React.useEffect(function () {
+    vegaEmbed(viewRef.current, epochLatencyChart, { actions: false }).then(function (res) {
+      return setView(res.view);
+    });
+
+    socket.addEventListener("message", function (e) {
+      var _JSON$parse7 = JSON.parse(e.data),
+          type = _JSON$parse7.type,
+          payload = _JSON$parse7.payload;
+
+      if (type === "LAT") {
+        setLatencies(payload);
+      } else if (type === "EPOCH") {
+        setLatencies(function (prev) {
+          return [].concat(_toConsumableArray(prev), [payload]);
+        });
+      }
+    });
+  }, []);
+
+  React.useEffect(function () {
+    if (view) {
+      var data = latencies.map(function (d) {
+        return { e: d.e, ms: d.l / 1000000 };
+      });
+      view.change('table', vega.changeset().remove(function () {
+        return true;
+      }).insert(data)).run();
+    }
+  });
+
+  return React.createElement(
+    "div",
+    null,
+    React.createElement(
+      "h1",
+      null,
+      "Epoch Latency"
+    ),
+    React.createElement("div", { ref: viewRef })
+  );
+}
+
 
 var domContainer = document.querySelector('#react-container');
 ReactDOM.render(React.createElement(App, null), domContainer);
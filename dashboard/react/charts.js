@@ -176,6 +176,25 @@ const recordProcessedChart = {
   }
 };
 
+const epochLatencyChart = {
+  "width": 600,
+  "autosize": { "resize": true },
+  "mark": { "type": "line", "point": true },
+  "data": { "name": "table" },
+  "encoding": {
+    "x": {
+      "field": "e",
+      "type": "quantitative",
+      "title": "epoch"
+    },
+    "y": {
+      "field": "ms",
+      "type": "quantitative",
+      "title": "latency (ms)"
+    }
+  }
+};
+
 const types = {
   ["Processing"]: "#0b6623",
   ["Spinning"]: "#e48282",
@@ -298,12 +317,16 @@ function updatePAG() {
     .text(genTitle);
 }
 
-const socket = new WebSocket('ws:127.0.0.1:3012');
+// served by `st2 dashboard`, falls back to its default address if opened from disk
+const socket = new WebSocket(location.protocol.startsWith("http") ? `ws://${location.host}/ws` : 'ws://127.0.0.1:3012/ws');
+// whether to switch to epochs as they complete
+let following = true;
 socket.addEventListener("open", e => {
   socket.send(JSON.stringify({ type: 'PAG', epoch: 1 }));
   socket.send(JSON.stringify({ type: 'AGG', epoch: 1 }));
   socket.send(JSON.stringify({ type: 'ALL', epoch: 1 }));
   socket.send(JSON.stringify({ type: 'MET', epoch: 1 }));
+  socket.send(JSON.stringify({ type: 'LAT' }));
   socket.send(JSON.stringify({ type: 'INV' }));
   setInterval(() => { socket.send(JSON.stringify({ type: 'INV' })); }, 5000);
 });
@@ -314,6 +337,7 @@ function App() {
   const [highlight, setHighlight] = React.useState(true);
   const [showWaiting, setShowWaiting] = React.useState(true);
   const [splitWorker, setSplitWorker] = React.useState(false);
+  const [follow, setFollow] = React.useState(true);
 
   React.useEffect(() => {
     const svgParent = d3.select("#d3").append("svg").attr("id", "graph");
@@ -346,6 +370,8 @@ function App() {
       } else if (type == "PAG") {
         pag = payload;
         setPAGEpoch(pagState);
+      } else if (type === "EPOCH" && following) {
+        loadEpoch(payload.e);
       }
     });
 
@@ -362,8 +388,7 @@ function App() {
     updatePAG();
   };
 
-  const epochUpdate = e => {
-    let epoch = parseInt(e.target.value);
+  const loadEpoch = epoch => {
     setEpoch(epoch || '');
     setKhop(1);
     if (epoch) {
@@ -379,6 +404,18 @@ function App() {
     }
   };
 
+  const epochUpdate = e => {
+    // stop following once an epoch is picked manually
+    following = false;
+    setFollow(false);
+    loadEpoch(parseInt(e.target.value));
+  };
+
+  const followUpdate = e => {
+    following = e.target.checked;
+    setFollow(e.target.checked);
+  };
+
   const highlightUpdate = e => {
     updatePAG();
     setHighlight(e.target.checked);
@@ -387,6 +424,7 @@ function App() {
   return (
     <div>
       <h1 style={{ textAlign: "center" }}> ST2 Dashboard</h1>
+      <EpochLatency></EpochLatency>
       <h1>PAG Viz</h1>
       <div id="d3"></div>
       <div className="viz-settings">
@@ -396,6 +434,8 @@ function App() {
         <input id="hop-highlight" type="checkbox" style={{ marginRight: "24px" }} checked={highlight} onChange={highlightUpdate}></input>
         <b style={{ marginRight: "6px" }}>Epoch: </b>
         <input id="epoch" type="text" value={epoch} onChange={epochUpdate}></input>
+        <b style={{ marginLeft: "24px", marginRight: "6px" }}>Follow: </b>
+        <input id="follow" type="checkbox" checked={follow} onChange={followUpdate}></input>
       </div>
       <div style={{ flex: "0 1 auto" }}>
         <b style={{ marginRight: "6px" }}>Show waiting/busy: </b>
@@ -769,6 +809,40 @@ function Invariants() {
   );
 }
 
+function EpochLatency() {
+  const [view, setView] = React.useState(undefined);
+  const [latencies, setLatencies] = React.useState([]);
+
+  const viewRef = React.useRef(null);
+
+  React.useEffect(() => {
+    vegaEmbed(viewRef.current, epochLatencyChart, { actions: false }).then(res => setView(res.view));
+
+    socket.addEventListener("message", e => {
+      const { type, payload } = JSON.parse(e.data);
+      if (type === "LAT") {
+        setLatencies(payload);
+      } else if (type === "EPOCH") {
+        setLatencies(prev => [...prev, payload]);
+      }
+    });
+  }, []);
+
+  React.useEffect(() => {
+    if (view) {
+      const data = latencies.map(d => ({ e: d.e, ms: d.l / 1000000 }));
+      view.change('table', vega.changeset().remove(() => true).insert(data)).run();
+    }
+  });
+
+  return (
+    <div>
+      <h1>Epoch Latency</h1>
+      <div ref={viewRef}></div>
+    </div>
+  );
+}
+
 
 let domContainer = document.querySelector('#react-container');
 ReactDOM.render(<App />, domContainer);
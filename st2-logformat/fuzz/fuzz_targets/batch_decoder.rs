@@ -0,0 +1,23 @@
+//! Decodes arbitrary bytes as a batch payload, in every encoding that doesn't
+//! trust its input (i.e., all but abomonation).
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use st2_logformat::encoding::{self, Encoding};
+
+fuzz_target!(|data: &[u8]| {
+    let (selector, payload) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let encoding = match selector % 3 {
+        0 => Encoding::Bincode,
+        1 => Encoding::Protobuf,
+        _ => Encoding::Compact,
+    };
+
+    if let Ok(batch) = encoding::decode_batch(encoding, &mut payload.to_vec()) {
+        // whatever decodes must encode again
+        encoding::encode_batch(encoding, &batch).expect("decoded batch doesn't encode");
+    }
+});
@@ -0,0 +1,17 @@
+//! Reads arbitrary bytes as checksummed blocks, skipping damaged ones.
+
+#![no_main]
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+use libfuzzer_sys::fuzz_target;
+use st2_logformat::block::{BlockReader, Damage};
+
+fuzz_target!(|data: &[u8]| {
+    let damage = Arc::new(Mutex::new(Damage::default()));
+    let mut payloads = Vec::new();
+    BlockReader::new(data, Arc::clone(&damage)).read_to_end(&mut payloads).expect("reading from memory fails");
+
+    let damage = *damage.lock().unwrap();
+    assert!(payloads.len() as u64 + damage.bytes + damage.truncated <= data.len() as u64);
+});
@@ -0,0 +1,97 @@
+//! Replays the event stream of a synthetic source computation as ST2 receives it
+//! online (cf. `st2_timely::connect::TraceReplayer`): in arbitrary chunks, and
+//! cut off at an arbitrary byte, as when a source disconnects mid-frame.
+//!
+//! The stream's bytes are those a `PAGLogger` writes, not arbitrary ones:
+//! abomonation decodes in place, trusting its input (cf. README.md).
+
+#![no_main]
+use std::cell::Cell;
+use std::io::Read;
+use std::rc::Rc;
+use std::time::Duration;
+
+use libfuzzer_sys::fuzz_target;
+use timely::dataflow::operators::capture::{EventReader, EventWriter};
+use timely::dataflow::operators::capture::event::{EventIterator, EventPusher};
+
+use st2_logformat::pair::Pair;
+use st2_timely::connect::{CompEvent, TraceReplayer};
+use st2_timely::synthetic::{MessagePattern, Workload};
+
+/// Delivers `bytes` in reads of `sizes` (cycled), like a socket
+struct Chunked {
+    bytes: Vec<u8>,
+    sizes: Vec<u8>,
+    offset: usize,
+    reads: usize,
+    /// Set once a read returned no bytes
+    exhausted: Rc<Cell<bool>>,
+}
+
+impl Read for Chunked {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = match self.sizes.len() {
+            0 => buf.len(),
+            n => self.sizes[self.reads % n] as usize + 1,
+        };
+        self.reads += 1;
+        let len = size.min(buf.len()).min(self.bytes.len() - self.offset);
+        buf[.. len].copy_from_slice(&self.bytes[self.offset .. self.offset + len]);
+        self.offset += len;
+        if len == 0 {
+            self.exhausted.set(true);
+        }
+        Ok(len)
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 7 {
+        return;
+    }
+    let (params, sizes) = data.split_at(7);
+    let workload = Workload {
+        workers: params[0] as usize % 3 + 1,
+        operators: params[1] as usize % 4 + 1,
+        epochs: params[2] as u64 % 4 + 1,
+        messages: [MessagePattern::Silent, MessagePattern::Ring, MessagePattern::AllToAll][params[3] as usize % 3],
+        records: 1,
+        ..Default::default()
+    };
+    let events = workload.events(params[4] as usize % workload.workers);
+    // bytes missing at the end of the stream
+    let cut = u16::from_le_bytes([params[5], params[6]]) as usize;
+
+    let mut bytes = Vec::new();
+    {
+        let mut writer = EventWriter::<Pair<u64, Duration>, CompEvent, _>::new(&mut bytes);
+        for event in events.iter().cloned() {
+            writer.push(event);
+        }
+    }
+    bytes.truncate(bytes.len().saturating_sub(cut));
+
+    let exhausted = Rc::new(Cell::new(false));
+    let stream = Chunked { bytes, sizes: sizes.to_vec(), offset: 0, reads: 0, exhausted: Rc::clone(&exhausted) };
+    let mut replayer = TraceReplayer::Stream(EventReader::new(stream));
+
+    // the replayed events are a prefix of the written ones, all of them if
+    // nothing was cut off
+    let mut replayed = 0;
+    loop {
+        match replayer.next() {
+            Some(event) => {
+                assert!(replayed < events.len(), "replayed more events than were written");
+                assert_eq!(format!("{:?}", event), format!("{:?}", events[replayed]), "event {} differs", replayed);
+                replayed += 1;
+            }
+            // nothing left to decode, and nothing left to read
+            None if exhausted.get() => break,
+            None => (),
+        }
+    }
+    if cut == 0 {
+        assert_eq!(replayed, events.len(), "complete stream not replayed completely");
+    }
+});
@@ -0,0 +1,26 @@
+//! Reads arbitrary bytes as a trace file, from the start and seeking to an epoch.
+
+#![no_main]
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use st2_logformat::encoding::Encoding;
+use st2_logformat::trace::{TraceHeader, TraceReader};
+
+fuzz_target!(|data: &[u8]| {
+    // abomonated batches are decoded in place and must come from trusted producers
+    match TraceHeader::read_from(&mut &data[..]) {
+        Ok(header) if header.encoding == Encoding::Abomonation => return,
+        _ => (),
+    }
+
+    if let Ok(mut reader) = TraceReader::new(Cursor::new(data.to_vec())) {
+        while let Ok(Some(_)) = reader.next_batch() {}
+        let _ = reader.truncation();
+    }
+
+    let epoch = data.last().copied().unwrap_or_default() as u64;
+    if let Ok(mut reader) = TraceReader::seek_epoch(Cursor::new(data.to_vec()), epoch) {
+        while let Ok(Some(_)) = reader.next_batch() {}
+    }
+});
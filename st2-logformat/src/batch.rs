@@ -0,0 +1,255 @@
+//! Struct-of-arrays batches of `LogRecord`s for hot internal paths, e.g. PAG
+//! construction: every field is stored in a column of its own, so passes over
+//! a few fields (e.g. filtering by `activity_type`) touch only these columns,
+//! behave well in caches, and vectorize.
+//!
+//! Batches are converted from and to `LogRecord`s at the edges, where records
+//! are read, written, or kept individually (e.g. `LogRecordBatch::from` and
+//! `LogRecordBatch::into_records`). Unlike the Arrow batches of `columnar`, they
+//! need no feature and keep the types of `LogRecord`'s fields.
+
+use std::iter::FromIterator;
+
+use crate::{ActivityType, ChannelId, CorrelatorId, EventType, LogRecord, OperatorId, Origin, Timestamp, Worker};
+use crate::tagged::TaggedField;
+
+/// A batch of `LogRecord`s, one column per field (cf. `LogRecord` for the
+/// fields' meaning). All columns have the same length.
+#[derive(Abomonation, PartialEq, Eq, Clone, Debug, Default)]
+pub struct LogRecordBatch {
+    /// `LogRecord::seq_no`s
+    pub seq_no: Vec<u64>,
+    /// `LogRecord::epoch`s
+    pub epoch: Vec<u64>,
+    /// `LogRecord::timestamp`s
+    pub timestamp: Vec<Timestamp>,
+    /// `LogRecord::local_worker`s
+    pub local_worker: Vec<Worker>,
+    /// `LogRecord::activity_type`s
+    pub activity_type: Vec<ActivityType>,
+    /// `LogRecord::event_type`s
+    pub event_type: Vec<EventType>,
+    /// `LogRecord::remote_worker`s
+    pub remote_worker: Vec<Option<Worker>>,
+    /// `LogRecord::operator_id`s
+    pub operator_id: Vec<Option<OperatorId>>,
+    /// `LogRecord::channel_id`s
+    pub channel_id: Vec<Option<ChannelId>>,
+    /// `LogRecord::correlator_id`s
+    pub correlator_id: Vec<Option<CorrelatorId>>,
+    /// `LogRecord::length`s
+    pub length: Vec<Option<usize>>,
+    /// `LogRecord::origin`s
+    pub origin: Vec<Option<Origin>>,
+    /// `LogRecord::tagged` fields
+    pub tagged: Vec<Vec<TaggedField>>,
+}
+
+impl LogRecordBatch {
+    /// An empty batch with room for `capacity` records
+    pub fn with_capacity(capacity: usize) -> Self {
+        LogRecordBatch {
+            seq_no: Vec::with_capacity(capacity),
+            epoch: Vec::with_capacity(capacity),
+            timestamp: Vec::with_capacity(capacity),
+            local_worker: Vec::with_capacity(capacity),
+            activity_type: Vec::with_capacity(capacity),
+            event_type: Vec::with_capacity(capacity),
+            remote_worker: Vec::with_capacity(capacity),
+            operator_id: Vec::with_capacity(capacity),
+            channel_id: Vec::with_capacity(capacity),
+            correlator_id: Vec::with_capacity(capacity),
+            length: Vec::with_capacity(capacity),
+            origin: Vec::with_capacity(capacity),
+            tagged: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// The number of records in the batch
+    pub fn len(&self) -> usize {
+        self.seq_no.len()
+    }
+
+    /// Whether the batch has no records
+    pub fn is_empty(&self) -> bool {
+        self.seq_no.is_empty()
+    }
+
+    /// Appends `record` to the batch.
+    pub fn push(&mut self, record: LogRecord) {
+        self.seq_no.push(record.seq_no);
+        self.epoch.push(record.epoch);
+        self.timestamp.push(record.timestamp);
+        self.local_worker.push(record.local_worker);
+        self.activity_type.push(record.activity_type);
+        self.event_type.push(record.event_type);
+        self.remote_worker.push(record.remote_worker);
+        self.operator_id.push(record.operator_id);
+        self.channel_id.push(record.channel_id);
+        self.correlator_id.push(record.correlator_id);
+        self.length.push(record.length);
+        self.origin.push(record.origin);
+        self.tagged.push(record.tagged);
+    }
+
+    /// The `index`th record of the batch
+    pub fn record(&self, index: usize) -> LogRecord {
+        LogRecord {
+            seq_no: self.seq_no[index],
+            epoch: self.epoch[index],
+            timestamp: self.timestamp[index],
+            local_worker: self.local_worker[index],
+            activity_type: self.activity_type[index],
+            event_type: self.event_type[index],
+            remote_worker: self.remote_worker[index],
+            operator_id: self.operator_id[index],
+            channel_id: self.channel_id[index],
+            correlator_id: self.correlator_id[index],
+            length: self.length[index],
+            origin: self.origin[index],
+            tagged: self.tagged[index].clone(),
+        }
+    }
+
+    /// Converts the batch to `LogRecord`s, in order.
+    pub fn into_records(self) -> Vec<LogRecord> {
+        let mut records = Vec::with_capacity(self.len());
+        let columns = self.seq_no.into_iter()
+            .zip(self.epoch).zip(self.timestamp).zip(self.local_worker)
+            .zip(self.activity_type).zip(self.event_type).zip(self.remote_worker)
+            .zip(self.operator_id).zip(self.channel_id).zip(self.correlator_id)
+            .zip(self.length).zip(self.origin).zip(self.tagged);
+        for ((((((((((((seq_no, epoch), timestamp), local_worker), activity_type), event_type), remote_worker),
+            operator_id), channel_id), correlator_id), length), origin), tagged) in columns {
+            records.push(LogRecord {
+                seq_no, epoch, timestamp, local_worker, activity_type, event_type, remote_worker,
+                operator_id, channel_id, correlator_id, length, origin, tagged,
+            });
+        }
+        records
+    }
+
+    /// Which records have one of `activities` as their `activity_type`, cf. `select`
+    pub fn activity_mask(&self, activities: &[ActivityType]) -> Vec<bool> {
+        self.activity_type.iter().map(|activity| activities.contains(activity)).collect()
+    }
+
+    /// The records for which `mask` is set, in order
+    pub fn select(&self, mask: &[bool]) -> LogRecordBatch {
+        assert_eq!(mask.len(), self.len(), "mask doesn't fit the batch");
+        LogRecordBatch {
+            seq_no: pick(&self.seq_no, mask),
+            epoch: pick(&self.epoch, mask),
+            timestamp: pick(&self.timestamp, mask),
+            local_worker: pick(&self.local_worker, mask),
+            activity_type: pick(&self.activity_type, mask),
+            event_type: pick(&self.event_type, mask),
+            remote_worker: pick(&self.remote_worker, mask),
+            operator_id: pick(&self.operator_id, mask),
+            channel_id: pick(&self.channel_id, mask),
+            correlator_id: pick(&self.correlator_id, mask),
+            length: pick(&self.length, mask),
+            origin: pick(&self.origin, mask),
+            tagged: pick(&self.tagged, mask),
+        }
+    }
+
+    /// Splits the batch by the `key` of its records' epochs into batches of
+    /// records with the same key, in order of their first record. A batch whose
+    /// records all have the same key isn't copied.
+    pub fn split_by_epoch<F: Fn(u64) -> u64>(self, key: F) -> Vec<(u64, LogRecordBatch)> {
+        let keys: Vec<u64> = self.epoch.iter().map(|epoch| key(*epoch)).collect();
+        match keys.first() {
+            None => Vec::new(),
+            Some(first) if keys.iter().all(|k| k == first) => vec![(*first, self)],
+            Some(_) => {
+                let mut distinct: Vec<u64> = Vec::new();
+                for k in keys.iter() {
+                    if !distinct.contains(k) {
+                        distinct.push(*k);
+                    }
+                }
+                distinct.into_iter()
+                    .map(|k| {
+                        let mask: Vec<bool> = keys.iter().map(|other| *other == k).collect();
+                        (k, self.select(&mask))
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// The elements of `column` for which `mask` is set
+fn pick<T: Clone>(column: &[T], mask: &[bool]) -> Vec<T> {
+    column.iter().zip(mask).filter(|(_, keep)| **keep).map(|(value, _)| value.clone()).collect()
+}
+
+impl From<Vec<LogRecord>> for LogRecordBatch {
+    fn from(records: Vec<LogRecord>) -> Self {
+        let mut batch = LogRecordBatch::with_capacity(records.len());
+        for record in records {
+            batch.push(record);
+        }
+        batch
+    }
+}
+
+impl FromIterator<LogRecord> for LogRecordBatch {
+    fn from_iter<I: IntoIterator<Item = LogRecord>>(records: I) -> Self {
+        let mut batch = LogRecordBatch::default();
+        for record in records {
+            batch.push(record);
+        }
+        batch
+    }
+}
+
+#[cfg(test)]
+fn records() -> Vec<LogRecord> {
+    (0 .. 6).map(|i| {
+        let record = LogRecord {
+            timestamp: std::time::Duration::from_nanos(10 * i),
+            local_worker: i % 2,
+            operator_id: Some(i),
+            length: Some(i as usize),
+            ..crate::test_util::record(i, i / 2)
+        };
+        if i % 3 != 0 {
+            return record;
+        }
+        LogRecord {
+            activity_type: ActivityType::DataMessage,
+            event_type: EventType::Sent,
+            remote_worker: Some(1 - i % 2),
+            operator_id: None,
+            channel_id: Some(7),
+            correlator_id: Some(i),
+            ..record
+        }
+    }).collect()
+}
+
+#[test]
+fn roundtrip_batch() {
+    let batch = LogRecordBatch::from(records());
+    assert_eq!(batch.len(), 6);
+    assert_eq!(batch.record(3), records()[3]);
+    assert_eq!(batch.into_records(), records());
+}
+
+#[test]
+fn select_by_activity() {
+    let batch: LogRecordBatch = records().into_iter().collect();
+    let messages = batch.select(&batch.activity_mask(&[ActivityType::DataMessage]));
+    let expected: Vec<_> = records().into_iter().filter(|r| r.activity_type == ActivityType::DataMessage).collect();
+    assert_eq!(messages.into_records(), expected);
+}
+
+#[test]
+fn split_by_epoch_block() {
+    let batch = LogRecordBatch::from(records());
+    let blocks = batch.clone().split_by_epoch(|epoch| epoch / 2);
+    assert_eq!(blocks.iter().map(|(key, block)| (*key, block.len())).collect::<Vec<_>>(), vec![(0, 4), (1, 2)]);
+    assert_eq!(batch.clone().split_by_epoch(|_| 3), vec![(3, batch)]);
+}
@@ -0,0 +1,217 @@
+//! Checksummed blocks that trace frames are wrapped in (since trace format version 3).
+//!
+//! Block layout (integers in little endian):
+//! `[magic: "ST2B"][payload length: u32][CRC-32 of payload: u32][payload]`.
+//! Every block contains exactly one frame (cf. `encoding`). A `BlockReader` detects
+//! damaged blocks, skips ahead to the next intact block, and keeps track of the
+//! damage, so a single corrupted region doesn't render the rest of a trace unreadable.
+//! Blocks end at the end of the stream, or at an `encoding::END_OF_FRAMES` marker.
+//! An incomplete block at the end of the stream, e.g. because the producer was killed
+//! while writing it, is reported as truncation rather than damage.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::sync::{Arc, Mutex};
+use std::convert::TryInto;
+
+/// Magic bytes every block starts with
+pub const BLOCK_MAGIC: &[u8; 4] = b"ST2B";
+/// Maximum payload length of a block
+pub const MAX_BLOCK_SIZE: usize = 1 << 30;
+
+/// Size of a block's header
+pub(crate) const HEADER_SIZE: usize = 12;
+
+/// Damage a `BlockReader` has encountered and skipped so far
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Damage {
+    /// Number of damaged regions skipped
+    pub regions: u64,
+    /// Number of bytes skipped, including those of damaged blocks
+    pub bytes: u64,
+    /// Number of bytes of an incomplete block at the end of the stream
+    /// (not included in `bytes`)
+    pub truncated: u64,
+}
+
+/// Writes `payload` as a block to `writer`.
+/// Returns the size of the block in bytes.
+pub fn write_block<W: Write>(payload: &[u8], writer: &mut W) -> Result<u64> {
+    if payload.len() > MAX_BLOCK_SIZE {
+        return Err(Error::new(ErrorKind::InvalidInput, "block too large"));
+    }
+
+    writer.write_all(BLOCK_MAGIC)?;
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&crc32fast::hash(payload).to_le_bytes())?;
+    writer.write_all(payload)?;
+    Ok((HEADER_SIZE + payload.len()) as u64)
+}
+
+/// Reads the payloads of all intact blocks in a stream as a contiguous stream of bytes.
+pub struct BlockReader<R: Read> {
+    reader: R,
+    /// bytes read from `reader`, but not yet consumed
+    raw: Vec<u8>,
+    /// the remainder of the current block's payload
+    payload: Vec<u8>,
+    consumed: usize,
+    /// whether the current position follows damaged data
+    damaged: bool,
+    done: bool,
+    damage: Arc<Mutex<Damage>>,
+}
+
+impl<R: Read> BlockReader<R> {
+    /// Reads blocks from `reader`, recording skipped data to `damage`.
+    pub fn new(reader: R, damage: Arc<Mutex<Damage>>) -> Self {
+        BlockReader {
+            reader,
+            raw: Vec::new(),
+            payload: Vec::new(),
+            consumed: 0,
+            damaged: false,
+            done: false,
+            damage,
+        }
+    }
+
+    /// Reads until `raw` contains at least `n` bytes.
+    /// Returns `false` if the stream ended before.
+    fn fill(&mut self, n: usize) -> Result<bool> {
+        let mut chunk = [0u8; 8192];
+        while self.raw.len() < n {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => return Ok(false),
+                Ok(read) => self.raw.extend_from_slice(&chunk[.. read]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Skips `n` bytes of damaged data.
+    fn skip(&mut self, n: usize) {
+        self.raw.drain(.. n);
+        let mut damage = self.damage.lock().expect("couldn't lock damage");
+        if !self.damaged {
+            damage.regions += 1;
+            self.damaged = true;
+        }
+        damage.bytes += n as u64;
+    }
+
+    /// Drops the incomplete block at the end of the stream.
+    fn truncate(&mut self) {
+        let mut damage = self.damage.lock().expect("couldn't lock damage");
+        damage.truncated += self.raw.len() as u64;
+        self.raw.clear();
+    }
+
+    /// Whether `raw` contains another block magic after the current one.
+    fn has_next_block(&self) -> bool {
+        self.raw[1 ..].windows(4).any(|w| w == BLOCK_MAGIC)
+    }
+
+    /// Returns the payload of the next intact block.
+    fn next_block(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if !self.fill(4)? {
+                let rest = self.raw.len();
+                if self.damaged && rest > 0 {
+                    self.skip(rest);
+                } else {
+                    self.truncate();
+                }
+                return Ok(None);
+            }
+
+            if self.raw[.. 4] == [0xff; 4] && !self.damaged {
+                // `END_OF_FRAMES`
+                return Ok(None);
+            }
+
+            if &self.raw[.. 4] == BLOCK_MAGIC {
+                if !self.fill(HEADER_SIZE)? {
+                    self.truncate();
+                    return Ok(None);
+                }
+                let length = u32::from_le_bytes(self.raw[4 .. 8].try_into().unwrap()) as usize;
+                let checksum = u32::from_le_bytes(self.raw[8 .. 12].try_into().unwrap());
+
+                if length <= MAX_BLOCK_SIZE && !self.fill(HEADER_SIZE + length)? && !self.has_next_block() {
+                    // the stream ended within the last block
+                    self.truncate();
+                    return Ok(None);
+                }
+                if length <= MAX_BLOCK_SIZE
+                    && self.raw.len() >= HEADER_SIZE + length
+                    && crc32fast::hash(&self.raw[HEADER_SIZE .. HEADER_SIZE + length]) == checksum {
+                    let payload = self.raw[HEADER_SIZE .. HEADER_SIZE + length].to_vec();
+                    self.raw.drain(.. HEADER_SIZE + length);
+                    self.damaged = false;
+                    return Ok(Some(payload));
+                }
+            }
+
+            // damaged: resynchronize at the next block magic
+            let next = self.raw[1 ..].windows(4).position(|w| w == BLOCK_MAGIC)
+                .map(|i| i + 1)
+                .unwrap_or_else(|| std::cmp::max(self.raw.len(), 4) - 3);
+            self.skip(next);
+        }
+    }
+}
+
+impl<R: Read> Read for BlockReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.consumed == self.payload.len() && !self.done {
+            match self.next_block()? {
+                Some(payload) => {
+                    self.payload = payload;
+                    self.consumed = 0;
+                }
+                None => self.done = true,
+            }
+        }
+
+        let n = std::cmp::min(buf.len(), self.payload.len() - self.consumed);
+        buf[.. n].copy_from_slice(&self.payload[self.consumed .. self.consumed + n]);
+        self.consumed += n;
+        Ok(n)
+    }
+}
+
+#[test]
+fn skip_damaged_blocks() {
+    let mut bytes = Vec::new();
+    write_block(b"first", &mut bytes).unwrap();
+    let second = bytes.len();
+    write_block(b"second", &mut bytes).unwrap();
+    write_block(b"third", &mut bytes).unwrap();
+
+    // flip a payload bit of the second block
+    bytes[second + HEADER_SIZE] ^= 1;
+
+    let damage = Arc::new(Mutex::new(Damage::default()));
+    let mut reader = BlockReader::new(&bytes[..], Arc::clone(&damage));
+    let mut read = Vec::new();
+    reader.read_to_end(&mut read).unwrap();
+
+    assert_eq!(read, b"firstthird");
+    assert_eq!(*damage.lock().unwrap(), Damage { regions: 1, bytes: (HEADER_SIZE + 6) as u64, truncated: 0 });
+
+    // a partially written last block is truncation, not damage
+    let mut bytes = Vec::new();
+    write_block(b"first", &mut bytes).unwrap();
+    write_block(b"second", &mut bytes).unwrap();
+    bytes.truncate(bytes.len() - 3);
+
+    let damage = Arc::new(Mutex::new(Damage::default()));
+    let mut reader = BlockReader::new(&bytes[..], Arc::clone(&damage));
+    let mut read = Vec::new();
+    reader.read_to_end(&mut read).unwrap();
+
+    assert_eq!(read, b"first");
+    assert_eq!(*damage.lock().unwrap(), Damage { regions: 0, bytes: 0, truncated: (HEADER_SIZE + 3) as u64 });
+}
@@ -0,0 +1,215 @@
+//! Columnar representation of `LogRecord` batches as Arrow `RecordBatch`es
+//! (requires the `arrow` feature).
+//!
+//! Every `LogRecord` field maps to one column of the same name (cf. `schema`).
+//! Timestamps are stored as `timestamp_ns`, enums by their discriminants, and
+//! `Option`al fields as nullable columns. The fields of `origin` are stored in the nullable
+//! columns `host`, `process_id`, and `thread_id`, `tagged` fields in a nullable binary
+//! column in their compact encoding (cf. `compact`). This allows vectorized processing of
+//! traces and handing them to Arrow-based query engines without copying.
+
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use std::convert::TryInto;
+
+use arrow::array::{Array, ArrayRef, BinaryArray, BinaryBuilder, UInt8Array, UInt32Array, UInt64Array};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+
+use crate::{LogRecord, Origin};
+use crate::compact::{read_tagged, write_tagged};
+use crate::proto::{activity_type, event_type};
+
+/// The Arrow schema of `LogRecord` batches
+pub fn schema() -> Schema {
+    Schema::new(vec![
+        Field::new("seq_no", DataType::UInt64, false),
+        Field::new("epoch", DataType::UInt64, false),
+        Field::new("timestamp_ns", DataType::UInt64, false),
+        Field::new("local_worker", DataType::UInt64, false),
+        Field::new("activity_type", DataType::UInt8, false),
+        Field::new("event_type", DataType::UInt8, false),
+        Field::new("remote_worker", DataType::UInt64, true),
+        Field::new("operator_id", DataType::UInt64, true),
+        Field::new("channel_id", DataType::UInt64, true),
+        Field::new("correlator_id", DataType::UInt64, true),
+        Field::new("length", DataType::UInt64, true),
+        Field::new("host", DataType::UInt32, true),
+        Field::new("process_id", DataType::UInt32, true),
+        Field::new("thread_id", DataType::UInt64, true),
+        Field::new("tagged", DataType::Binary, true),
+    ])
+}
+
+/// Converts `records` to their columnar representation.
+pub fn to_record_batch(records: &[LogRecord]) -> Result<RecordBatch> {
+    fn column<T, F: Fn(&LogRecord) -> T>(records: &[LogRecord], f: F) -> Vec<T> {
+        records.iter().map(f).collect()
+    }
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt64Array::from(column(records, |r| r.seq_no))),
+        Arc::new(UInt64Array::from(column(records, |r| r.epoch))),
+        Arc::new(UInt64Array::from(column(records, |r| r.timestamp.as_nanos() as u64))),
+        Arc::new(UInt64Array::from(column(records, |r| r.local_worker))),
+        Arc::new(UInt8Array::from(column(records, |r| r.activity_type as u8))),
+        Arc::new(UInt8Array::from(column(records, |r| r.event_type as u8))),
+        Arc::new(UInt64Array::from(column(records, |r| r.remote_worker))),
+        Arc::new(UInt64Array::from(column(records, |r| r.operator_id))),
+        Arc::new(UInt64Array::from(column(records, |r| r.channel_id))),
+        Arc::new(UInt64Array::from(column(records, |r| r.correlator_id))),
+        Arc::new(UInt64Array::from(column(records, |r| r.length.map(|l| l as u64)))),
+        Arc::new(UInt32Array::from(column(records, |r| r.origin.map(|o| o.host)))),
+        Arc::new(UInt32Array::from(column(records, |r| r.origin.map(|o| o.process_id)))),
+        Arc::new(UInt64Array::from(column(records, |r| r.origin.map(|o| o.thread_id)))),
+        Arc::new(tagged_column(records)?),
+    ];
+
+    RecordBatch::try_new(Arc::new(schema()), columns)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{:?}", e)))
+}
+
+fn tagged_column(records: &[LogRecord]) -> Result<BinaryArray> {
+    let mut builder = BinaryBuilder::new(records.len());
+    let mut buf = Vec::new();
+    for record in records.iter() {
+        if record.tagged.is_empty() {
+            builder.append_null()
+        } else {
+            buf.clear();
+            write_tagged(&record.tagged, &mut buf);
+            builder.append_value(&buf)
+        }.map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{:?}", e)))?;
+    }
+    Ok(builder.finish())
+}
+
+/// Converts a columnar `batch` back to `LogRecord`s.
+/// Columns are looked up by name, so additional columns are ignored.
+/// Batches without `origin` or `tagged` columns (as written before trace format
+/// versions 4 and 5) are supported.
+pub fn from_record_batch(batch: &RecordBatch) -> Result<Vec<LogRecord>> {
+    let seq_no = u64_column(batch, "seq_no")?;
+    let epoch = u64_column(batch, "epoch")?;
+    let timestamp = u64_column(batch, "timestamp_ns")?;
+    let local_worker = u64_column(batch, "local_worker")?;
+    let activity_types = u8_column(batch, "activity_type")?;
+    let event_types = u8_column(batch, "event_type")?;
+    let remote_worker = u64_column(batch, "remote_worker")?;
+    let operator_id = u64_column(batch, "operator_id")?;
+    let channel_id = u64_column(batch, "channel_id")?;
+    let correlator_id = u64_column(batch, "correlator_id")?;
+    let length = u64_column(batch, "length")?;
+    let origin = if batch.schema().index_of("host").is_ok() {
+        Some((u32_column(batch, "host")?, u32_column(batch, "process_id")?, u64_column(batch, "thread_id")?))
+    } else {
+        None
+    };
+
+    let tagged = if batch.schema().index_of("tagged").is_ok() {
+        Some(column(batch, "tagged")?.as_any().downcast_ref::<BinaryArray>()
+            .ok_or_else(|| invalid("column tagged is not Binary"))?)
+    } else {
+        None
+    };
+
+    let optional = |column: &UInt64Array, row: usize| if column.is_null(row) {
+        None
+    } else {
+        Some(column.value(row))
+    };
+
+    (0 .. batch.num_rows()).map(|row| {
+        let length = match optional(length, row) {
+            Some(l) => Some(l.try_into().map_err(|_| invalid("length exceeds usize"))?),
+            None => None,
+        };
+        let origin = match origin {
+            Some((host, process_id, thread_id)) if !host.is_null(row) => Some(Origin {
+                host: host.value(row),
+                process_id: process_id.value(row),
+                thread_id: thread_id.value(row),
+            }),
+            _ => None,
+        };
+        let tagged = match tagged {
+            Some(tagged) if !tagged.is_null(row) => read_tagged(&mut tagged.value(row))?,
+            _ => Vec::new(),
+        };
+
+        Ok(LogRecord {
+            seq_no: seq_no.value(row),
+            epoch: epoch.value(row),
+            timestamp: Duration::from_nanos(timestamp.value(row)),
+            local_worker: local_worker.value(row),
+            activity_type: activity_type(activity_types.value(row) as u64)?,
+            event_type: event_type(event_types.value(row) as u64)?,
+            remote_worker: optional(remote_worker, row),
+            operator_id: optional(operator_id, row),
+            channel_id: optional(channel_id, row),
+            correlator_id: optional(correlator_id, row),
+            length,
+            origin,
+            tagged,
+        })
+    }).collect()
+}
+
+fn column<'a>(batch: &'a RecordBatch, name: &str) -> Result<&'a ArrayRef> {
+    let index = batch.schema().index_of(name)
+        .map_err(|_| invalid(&format!("missing column {}", name)))?;
+    Ok(batch.column(index))
+}
+
+fn u64_column<'a>(batch: &'a RecordBatch, name: &str) -> Result<&'a UInt64Array> {
+    column(batch, name)?.as_any().downcast_ref::<UInt64Array>()
+        .ok_or_else(|| invalid(&format!("column {} is not UInt64", name)))
+}
+
+fn u32_column<'a>(batch: &'a RecordBatch, name: &str) -> Result<&'a UInt32Array> {
+    column(batch, name)?.as_any().downcast_ref::<UInt32Array>()
+        .ok_or_else(|| invalid(&format!("column {} is not UInt32", name)))
+}
+
+fn u8_column<'a>(batch: &'a RecordBatch, name: &str) -> Result<&'a UInt8Array> {
+    column(batch, name)?.as_any().downcast_ref::<UInt8Array>()
+        .ok_or_else(|| invalid(&format!("column {} is not UInt8", name)))
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, reason.to_string())
+}
+
+#[test]
+fn roundtrip_columnar() {
+    use crate::{ActivityType, EventType};
+
+    let records = vec![
+        LogRecord {
+            timestamp: Duration::from_nanos(10),
+            event_type: EventType::End,
+            operator_id: Some(3),
+            length: Some(20),
+            ..crate::test_util::record(1, 1)
+        },
+        LogRecord {
+            timestamp: Duration::from_nanos(12),
+            activity_type: ActivityType::DataMessage,
+            event_type: EventType::Sent,
+            remote_worker: Some(1),
+            operator_id: None,
+            channel_id: Some(4),
+            correlator_id: Some(5),
+            length: Some(20),
+            origin: Some(Origin { host: 0, process_id: 4711, thread_id: 2 }),
+            tagged: vec![crate::tagged::TaggedField { tag: crate::tagged::MESSAGE_BYTES, value: crate::tagged::Value::U64(160) }],
+            ..crate::test_util::record(2, 1)
+        },
+    ];
+
+    let batch = to_record_batch(&records).unwrap();
+    assert_eq!(batch.num_rows(), 2);
+    assert_eq!(batch.num_columns(), schema().fields().len());
+    assert_eq!(from_record_batch(&batch).unwrap(), records);
+}
@@ -0,0 +1,229 @@
+//! Compact encoding of `LogRecord` batches (cf. `encoding::Encoding::Compact`).
+//!
+//! Records are encoded in order; `seq_no`, `epoch`, and `timestamp` are delta-encoded
+//! against the previous record of the same `local_worker` within the batch, and all
+//! integers are written as (zigzag) varints. Per record:
+//! `[local_worker][Δseq_no][Δepoch][Δtimestamp ns][activity_type << 3 | event_type][present]`
+//! followed by the optional fields flagged in `present`, in declaration order.
+//! An `origin` (flag `1 << 5`) is written as `[host][process_id][thread_id]`, and
+//! `tagged` fields (flag `1 << 6`) as `[count]([tag][kind][value])*`, with kind 0 for
+//! `U64` (varint value) and 1 for `Bytes` (`[length][bytes]`).
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+use std::convert::TryInto;
+
+use crate::{LogRecord, Origin, Worker};
+use crate::tagged::{TaggedField, Value};
+use crate::proto::{activity_type, event_type};
+use crate::varint::{read_varint, write_varint, read_signed_varint, write_signed_varint};
+
+/// `(seq_no, epoch, timestamp ns)` of a worker's previous record
+type Previous = (u64, u64, u64);
+
+/// `present` flag of a record's `origin`
+const ORIGIN: u8 = 1 << 5;
+/// `present` flag of a record's `tagged` fields
+const TAGGED: u8 = 1 << 6;
+
+/// Appends the compact encoding of `batch` to `buf`.
+pub fn encode_batch(batch: &[LogRecord], buf: &mut Vec<u8>) {
+    let mut previous: HashMap<Worker, Previous> = HashMap::new();
+
+    write_varint(buf, batch.len() as u64);
+    for record in batch.iter() {
+        let timestamp = record.timestamp.as_nanos() as u64;
+        let prev = previous.insert(record.local_worker, (record.seq_no, record.epoch, timestamp))
+            .unwrap_or_default();
+
+        write_varint(buf, record.local_worker);
+        write_signed_varint(buf, record.seq_no.wrapping_sub(prev.0) as i64);
+        write_signed_varint(buf, record.epoch.wrapping_sub(prev.1) as i64);
+        write_signed_varint(buf, timestamp.wrapping_sub(prev.2) as i64);
+        buf.push((record.activity_type as u8) << 3 | record.event_type as u8);
+
+        let optional = [
+            record.remote_worker,
+            record.operator_id,
+            record.channel_id,
+            record.correlator_id,
+            record.length.map(|l| l as u64),
+        ];
+        let mut present = optional.iter().enumerate()
+            .filter(|(_, value)| value.is_some())
+            .fold(0u8, |present, (i, _)| present | 1 << i);
+        if record.origin.is_some() {
+            present |= ORIGIN;
+        }
+        if !record.tagged.is_empty() {
+            present |= TAGGED;
+        }
+        buf.push(present);
+        for value in optional.iter().flatten() {
+            write_varint(buf, *value);
+        }
+        if let Some(origin) = record.origin {
+            write_varint(buf, origin.host as u64);
+            write_varint(buf, origin.process_id as u64);
+            write_varint(buf, origin.thread_id);
+        }
+        if !record.tagged.is_empty() {
+            write_tagged(&record.tagged, buf);
+        }
+    }
+}
+
+/// Appends the compact encoding of `tagged` fields to `buf`.
+pub(crate) fn write_tagged(tagged: &[TaggedField], buf: &mut Vec<u8>) {
+    write_varint(buf, tagged.len() as u64);
+    for field in tagged.iter() {
+        write_varint(buf, field.tag as u64);
+        match &field.value {
+            Value::U64(value) => {
+                buf.push(0);
+                write_varint(buf, *value);
+            }
+            Value::Bytes(bytes) => {
+                buf.push(1);
+                write_varint(buf, bytes.len() as u64);
+                buf.extend_from_slice(bytes);
+            }
+        }
+    }
+}
+
+/// Decodes a compactly encoded batch.
+pub fn decode_batch(mut bytes: &[u8]) -> Result<Vec<LogRecord>> {
+    let mut previous: HashMap<Worker, Previous> = HashMap::new();
+
+    let count = read_varint(&mut bytes)?;
+    // every record takes at least 6 bytes, so don't trust `count` blindly
+    let mut batch = Vec::with_capacity(std::cmp::min(count as usize, bytes.len() / 6));
+    for _ in 0 .. count {
+        let local_worker = read_varint(&mut bytes)?;
+        let prev = previous.get(&local_worker).cloned().unwrap_or_default();
+        let seq_no = prev.0.wrapping_add(read_signed_varint(&mut bytes)? as u64);
+        let epoch = prev.1.wrapping_add(read_signed_varint(&mut bytes)? as u64);
+        let timestamp = prev.2.wrapping_add(read_signed_varint(&mut bytes)? as u64);
+        previous.insert(local_worker, (seq_no, epoch, timestamp));
+
+        let types = read_byte(&mut bytes)?;
+        let present = read_byte(&mut bytes)?;
+        let mut optional = [None; 5];
+        for (i, value) in optional.iter_mut().enumerate() {
+            if present & 1 << i != 0 {
+                *value = Some(read_varint(&mut bytes)?);
+            }
+        }
+        let length = match optional[4] {
+            Some(l) => Some(l.try_into().map_err(|_| invalid("length exceeds usize"))?),
+            None => None,
+        };
+        let origin = if present & ORIGIN != 0 {
+            Some(Origin {
+                host: read_varint(&mut bytes)?.try_into().map_err(|_| invalid("host exceeds u32"))?,
+                process_id: read_varint(&mut bytes)?.try_into().map_err(|_| invalid("process_id exceeds u32"))?,
+                thread_id: read_varint(&mut bytes)?,
+            })
+        } else {
+            None
+        };
+        let tagged = if present & TAGGED != 0 {
+            read_tagged(&mut bytes)?
+        } else {
+            Vec::new()
+        };
+
+        batch.push(LogRecord {
+            seq_no,
+            epoch,
+            timestamp: Duration::from_nanos(timestamp),
+            local_worker,
+            activity_type: activity_type((types >> 3) as u64)?,
+            event_type: event_type((types & 0x7) as u64)?,
+            remote_worker: optional[0],
+            operator_id: optional[1],
+            channel_id: optional[2],
+            correlator_id: optional[3],
+            length,
+            origin,
+            tagged,
+        });
+    }
+
+    if bytes.is_empty() {
+        Ok(batch)
+    } else {
+        Err(invalid("trailing bytes after compact batch"))
+    }
+}
+
+/// Decodes compactly encoded `tagged` fields.
+pub(crate) fn read_tagged(bytes: &mut &[u8]) -> Result<Vec<TaggedField>> {
+    let count = read_varint(bytes)?;
+    let mut tagged = Vec::with_capacity(std::cmp::min(count as usize, bytes.len() / 3));
+    for _ in 0 .. count {
+        let tag = read_varint(bytes)?.try_into().map_err(|_| invalid("tag exceeds u32"))?;
+        let value = match read_byte(bytes)? {
+            0 => Value::U64(read_varint(bytes)?),
+            1 => {
+                let length = read_varint(bytes)? as usize;
+                if length > bytes.len() {
+                    return Err(invalid("truncated compact batch"));
+                }
+                let (value, rest) = bytes.split_at(length);
+                *bytes = rest;
+                Value::Bytes(value.to_vec())
+            }
+            kind => return Err(invalid(&format!("unknown tagged value kind {}", kind))),
+        };
+        tagged.push(TaggedField { tag, value });
+    }
+    Ok(tagged)
+}
+
+fn read_byte(bytes: &mut &[u8]) -> Result<u8> {
+    let (&byte, rest) = bytes.split_first().ok_or_else(|| invalid("truncated compact batch"))?;
+    *bytes = rest;
+    Ok(byte)
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, reason.to_string())
+}
+
+#[test]
+fn roundtrip_compact() {
+    use crate::EventType;
+
+    let record = |worker, seq_no, nanos, event_type, length| LogRecord {
+        timestamp: Duration::from_nanos(1_570_000_000_000_000_000 + nanos),
+        local_worker: worker,
+        event_type,
+        operator_id: Some(12),
+        length,
+        origin: Some(Origin { host: 0, process_id: 4711, thread_id: worker }),
+        tagged: vec![TaggedField { tag: crate::tagged::MESSAGE_BYTES, value: Value::U64(seq_no * 8) }],
+        ..crate::test_util::record(seq_no, 7)
+    };
+    // two interleaved worker streams
+    let batch = vec![
+        record(0, 10, 0, EventType::Start, None),
+        record(1, 20, 5, EventType::Start, None),
+        record(0, 11, 900, EventType::End, Some(1000)),
+        record(1, 21, 700, EventType::End, None),
+        record(0, 12, 1000, EventType::Start, None),
+    ];
+
+    let mut compact = Vec::new();
+    encode_batch(&batch, &mut compact);
+    assert_eq!(decode_batch(&compact).unwrap(), batch);
+
+    // after the first record of every worker, a record takes a handful of bytes
+    let mut abomonated = Vec::new();
+    unsafe { abomonation::encode(&batch, &mut abomonated).unwrap() };
+    assert!(compact.len() * 4 < abomonated.len());
+
+    assert!(decode_batch(&compact[.. compact.len() - 1]).is_err());
+}
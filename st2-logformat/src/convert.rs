@@ -0,0 +1,85 @@
+//! Conversion of whole trace files between encodings and compressions.
+//!
+//! Batch-level conversions live next to their representations: `encoding::convert`
+//! transcodes raw frame streams (e.g. abomonation to serde-based bincode), and
+//! `columnar` converts between rows and Arrow record batches.
+
+use std::io::{Read, Result, Write};
+
+use crate::encoding::Encoding;
+use crate::names::NameId;
+use crate::trace::{Compression, TraceHeader, TraceReader, TraceWriter};
+
+/// Rewrites the trace in `reader` to `writer` with `encoding` and `compression`,
+/// in the current trace format version. Header metadata and interned names are preserved.
+/// Returns the number of converted records.
+pub fn transcode<R, W>(reader: R, writer: W, encoding: Encoding, compression: Compression) -> Result<usize>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let mut reader = TraceReader::new(reader)?;
+    let mut header = TraceHeader::new(reader.header().workers, reader.header().source.clone(), encoding, compression);
+    header.start_time = reader.header().start_time;
+    let mut writer = TraceWriter::new(&header, writer)?;
+
+    let mut count = 0;
+    while let Some(batch) = reader.next_batch()? {
+        copy_names(&reader, &mut writer);
+        count += batch.len();
+        writer.write_batch(&batch)?;
+    }
+    copy_names(&reader, &mut writer);
+    writer.finish()?;
+    Ok(count)
+}
+
+/// Interns names `reader` has read since the last call.
+/// Names keep their ids, as they are interned in the same order.
+fn copy_names(reader: &TraceReader, writer: &mut TraceWriter) {
+    let names = reader.names();
+    for id in writer.names().len() .. names.len() {
+        writer.intern(names.resolve(id as NameId).expect("names are consecutive"));
+    }
+}
+
+#[test]
+fn transcode_trace() {
+    use std::sync::{Arc, Mutex};
+    use crate::LogRecord;
+
+    /// A `Write` whose contents remain accessible after the writer is consumed
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // operator 0 is `Map`
+    let batch = vec![LogRecord { operator_id: Some(0), ..crate::test_util::record(1, 1) }];
+
+    let original = Shared(Arc::new(Mutex::new(Vec::new())));
+    let header = TraceHeader::new(2, "test".to_string(), Encoding::Abomonation, Compression::None);
+    let mut writer = TraceWriter::new(&header, original.clone()).unwrap();
+    writer.intern("Map");
+    writer.write_batch(&batch).unwrap();
+    writer.finish().unwrap();
+    let original = original.0.lock().unwrap().clone();
+
+    let converted = Shared(Arc::new(Mutex::new(Vec::new())));
+    let count = transcode(std::io::Cursor::new(original), converted.clone(), Encoding::Protobuf, Compression::None).unwrap();
+    assert_eq!(count, 1);
+
+    let converted = converted.0.lock().unwrap().clone();
+    let mut reader = TraceReader::new(std::io::Cursor::new(converted)).unwrap();
+    assert_eq!(reader.header().encoding, Encoding::Protobuf);
+    assert_eq!(reader.header().start_time, header.start_time);
+    assert_eq!(reader.header().workers, 2);
+    assert_eq!(reader.next_batch().unwrap(), Some(batch));
+    assert_eq!(reader.names().resolve(0), Some("Map"));
+}
@@ -0,0 +1,153 @@
+//! Plain CSV/TSV export of `LogRecord`s, e.g. for pandas, spreadsheets, or DuckDB.
+//!
+//! Every record becomes one row of `COLUMNS`: enums are written by name,
+//! timestamps in nanoseconds, absent optional fields as empty cells, and
+//! interned names (hosts, operators) are resolved. An operator's name is only
+//! carried by some of its records (cf. `tagged::OPERATOR_NAME`), so the writer
+//! remembers it and repeats it on all records of the operator written afterwards;
+//! names can also be given up front with `name_operator`. User-defined tagged
+//! fields aren't exported.
+
+use std::collections::HashMap;
+use std::io::{Result, Write};
+
+use crate::LogRecord;
+use crate::names::NameTable;
+use crate::tagged::{self, Value};
+
+/// Columns of exported records
+pub const COLUMNS: &[&str] = &[
+    "seq_no",
+    "epoch",
+    "timestamp_ns",
+    "local_worker",
+    "activity_type",
+    "event_type",
+    "remote_worker",
+    "operator_id",
+    "operator_name",
+    "channel_id",
+    "correlator_id",
+    "length",
+    "host",
+    "process_id",
+    "thread_id",
+    "message_bytes",
+    "gc_ns",
+];
+
+/// Writes `LogRecord`s as delimited text with a header line to `W`
+pub struct CsvWriter<W: Write> {
+    out: W,
+    delimiter: char,
+    operator_names: HashMap<u64, String>,
+    row: Vec<String>,
+}
+
+impl<W: Write> CsvWriter<W> {
+    /// A writer of rows separated by `delimiter` (e.g. `,` or `\t`) to `out`.
+    /// Writes the header line.
+    pub fn new(mut out: W, delimiter: char) -> Result<Self> {
+        writeln!(out, "{}", COLUMNS.join(&delimiter.to_string()))?;
+        Ok(CsvWriter { out, delimiter, operator_names: HashMap::new(), row: Vec::with_capacity(COLUMNS.len()) })
+    }
+
+    /// Names the operator `id`, e.g. with a name not carried by the trace.
+    pub fn name_operator(&mut self, id: u64, name: &str) {
+        self.operator_names.insert(id, name.to_string());
+    }
+
+    /// Writes `record`, resolving its names in `names`.
+    pub fn write(&mut self, record: &LogRecord, names: &NameTable) -> Result<()> {
+        if let (Some(id), Some(Value::U64(name))) = (record.operator_id, tagged::get(&record.tagged, tagged::OPERATOR_NAME)) {
+            if let Some(name) = names.resolve(*name as u32) {
+                self.name_operator(id, name);
+            }
+        }
+
+        let optional = |value: Option<u64>| value.map_or_else(String::new, |value| value.to_string());
+        let tag = |tag| match tagged::get(&record.tagged, tag) {
+            Some(Value::U64(value)) => value.to_string(),
+            _ => String::new(),
+        };
+
+        self.row.clear();
+        self.row.push(record.seq_no.to_string());
+        self.row.push(record.epoch.to_string());
+        self.row.push(record.timestamp.as_nanos().to_string());
+        self.row.push(record.local_worker.to_string());
+        self.row.push(format!("{:?}", record.activity_type));
+        self.row.push(format!("{:?}", record.event_type));
+        self.row.push(optional(record.remote_worker));
+        self.row.push(optional(record.operator_id));
+        self.row.push(record.operator_id.and_then(|id| self.operator_names.get(&id)).cloned().unwrap_or_default());
+        self.row.push(optional(record.channel_id));
+        self.row.push(optional(record.correlator_id));
+        self.row.push(optional(record.length.map(|length| length as u64)));
+        match record.origin {
+            Some(ref origin) => {
+                self.row.push(names.resolve(origin.host).unwrap_or_default().to_string());
+                self.row.push(origin.process_id.to_string());
+                self.row.push(origin.thread_id.to_string());
+            }
+            None => self.row.extend(vec![String::new(); 3]),
+        }
+        self.row.push(tag(tagged::MESSAGE_BYTES));
+        self.row.push(tag(tagged::GC_NANOS));
+
+        let delimiter = self.delimiter;
+        let row: Vec<String> = self.row.iter().map(|cell| quote(cell, delimiter)).collect();
+        writeln!(self.out, "{}", row.join(&delimiter.to_string()))
+    }
+
+    /// Flushes the output and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Quotes `cell` if it contains `delimiter`, quotes, or line breaks (RFC 4180).
+fn quote(cell: &str, delimiter: char) -> String {
+    if cell.contains(|c| c == delimiter || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell.to_string()
+    }
+}
+
+#[test]
+fn rows() {
+    use std::time::Duration;
+    use crate::{ActivityType, EventType, Origin};
+
+    let mut names = NameTable::default();
+    let host = names.intern("host-1");
+    let operator = names.intern("Map, \"squares\"");
+
+    let mut first = LogRecord {
+        timestamp: Duration::from_nanos(1500),
+        activity_type: ActivityType::Processing,
+        operator_id: Some(4),
+        length: Some(10),
+        origin: Some(Origin { host, process_id: 42, thread_id: 7 }),
+        ..crate::test_util::record(0, 1)
+    };
+    tagged::set(&mut first.tagged, tagged::OPERATOR_NAME, Value::U64(operator as u64));
+    let mut second = first.clone();
+    second.seq_no = 1;
+    second.event_type = EventType::End;
+    second.origin = None;
+    second.tagged.clear();
+
+    let mut writer = CsvWriter::new(Vec::new(), ',').unwrap();
+    writer.write(&first, &names).unwrap();
+    writer.write(&second, &names).unwrap();
+    let csv = String::from_utf8(writer.finish().unwrap()).unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+
+    assert_eq!(lines[0], COLUMNS.join(","));
+    assert_eq!(lines[1], "0,1,1500,0,Processing,Start,,4,\"Map, \"\"squares\"\"\",,,10,host-1,42,7,,");
+    // the operator's name is repeated, its origin isn't
+    assert_eq!(lines[2], "1,1,1500,0,Processing,End,,4,\"Map, \"\"squares\"\"\",,,10,,,,,");
+}
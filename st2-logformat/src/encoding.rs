@@ -0,0 +1,222 @@
+//! Encodings of `LogRecord` traces.
+//!
+//! A trace is a sequence of frames, each containing a batch of `LogRecord`s:
+//! `[payload length: u64 (little endian)][payload]`.
+//! The payload is a batch encoded with one of the supported `Encoding`s.
+//! A payload length of `END_OF_FRAMES` ends the trace early, e.g. to append
+//! an index to it (cf. `trace::TraceIndex`). A payload length of `NAMES_FRAME`
+//! is followed by `[payload length: u64][payload]` with newly interned names
+//! (cf. `names::NameTable`).
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::str::FromStr;
+
+use crate::LogRecord;
+use crate::{compact, proto};
+
+/// Frame length that marks the end of a trace's frames
+pub const END_OF_FRAMES: u64 = u64::MAX;
+/// Frame length that marks a frame of interned names
+pub const NAMES_FRAME: u64 = u64::MAX - 1;
+
+/// A frame of a trace
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Frame {
+    /// A batch of `LogRecord`s
+    Batch(Vec<LogRecord>),
+    /// The payload of a names frame (cf. `names::NameTable`)
+    Names(Vec<u8>),
+}
+
+/// Supported encodings for batches of `LogRecord`s
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum Encoding {
+    /// Abomonation: fast, but tied to the exact struct layout of the
+    /// crate version and platform that wrote it.
+    Abomonation,
+    /// Serde-based bincode: survives crate upgrades.
+    #[cfg(feature = "bincode")]
+    Bincode,
+    /// Protobuf `LogRecordBatch` messages (cf. `proto/logrecord.proto`):
+    /// language-agnostic interchange format.
+    Protobuf,
+    /// Per-worker delta-encoded timestamps and varint-encoded ids (cf. `compact`):
+    /// several times smaller than the other encodings.
+    Compact,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "abomonation" => Ok(Encoding::Abomonation),
+            #[cfg(feature = "bincode")]
+            "bincode" => Ok(Encoding::Bincode),
+            "protobuf" => Ok(Encoding::Protobuf),
+            "compact" => Ok(Encoding::Compact),
+            _ => Err(format!("unsupported encoding: {}", s)),
+        }
+    }
+}
+
+/// Encodes `batch` into its payload bytes.
+pub fn encode_batch(encoding: Encoding, batch: &Vec<LogRecord>) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    match encoding {
+        Encoding::Abomonation => unsafe { abomonation::encode(batch, &mut bytes)? },
+        #[cfg(feature = "bincode")]
+        Encoding::Bincode => bincode::serialize_into(&mut bytes, batch)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+        Encoding::Protobuf => proto::encode_batch(batch, &mut bytes),
+        Encoding::Compact => compact::encode_batch(batch, &mut bytes),
+    }
+    Ok(bytes)
+}
+
+/// Decodes a batch from its payload bytes.
+pub fn decode_batch(encoding: Encoding, bytes: &mut [u8]) -> Result<Vec<LogRecord>> {
+    match encoding {
+        Encoding::Abomonation => {
+            match unsafe { abomonation::decode::<Vec<LogRecord>>(bytes) } {
+                Some((batch, rest)) if rest.is_empty() => Ok(batch.clone()),
+                _ => Err(Error::new(ErrorKind::InvalidData, "malformed abomonation payload")),
+            }
+        }
+        #[cfg(feature = "bincode")]
+        Encoding::Bincode => bincode::deserialize(bytes)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+        Encoding::Protobuf => proto::decode_batch(bytes),
+        Encoding::Compact => compact::decode_batch(bytes),
+    }
+}
+
+/// Writes `batch` as a single frame to `writer`.
+/// Returns the size of the frame in bytes.
+pub fn write_batch<W: Write>(encoding: Encoding, batch: &Vec<LogRecord>, writer: &mut W) -> Result<u64> {
+    let payload = encode_batch(encoding, batch)?;
+    writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(8 + payload.len() as u64)
+}
+
+/// Writes a names frame with `payload` to `writer`.
+/// Returns the size of the frame in bytes.
+pub fn write_names<W: Write>(payload: &[u8], writer: &mut W) -> Result<u64> {
+    writer.write_all(&NAMES_FRAME.to_le_bytes())?;
+    writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+    writer.write_all(payload)?;
+    Ok(16 + payload.len() as u64)
+}
+
+/// Reads the next frame from `reader`.
+/// Returns `None` if the trace ended cleanly or `END_OF_FRAMES` was reached.
+pub fn read_frame<R: Read>(encoding: Encoding, reader: &mut R) -> Result<Option<Frame>> {
+    read_frame_using(reader, |payload| decode_batch(encoding, payload))
+}
+
+/// Reads the next frame from `reader`, decoding batches with `decode`.
+pub(crate) fn read_frame_using<R, F>(reader: &mut R, decode: F) -> Result<Option<Frame>>
+where
+    R: Read,
+    F: FnOnce(&mut [u8]) -> Result<Vec<LogRecord>>,
+{
+    let mut length = [0u8; 8];
+    if reader.read(&mut length[.. 1])? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut length[1 ..])?;
+    let mut length = u64::from_le_bytes(length);
+    let is_names = length == NAMES_FRAME;
+    if length == END_OF_FRAMES {
+        return Ok(None);
+    } else if is_names {
+        let mut names_length = [0u8; 8];
+        reader.read_exact(&mut names_length)?;
+        length = u64::from_le_bytes(names_length);
+    }
+
+    let mut payload = read_payload(reader, length)?;
+    if is_names {
+        Ok(Some(Frame::Names(payload)))
+    } else {
+        decode(&mut payload).map(|batch| Some(Frame::Batch(batch)))
+    }
+}
+
+/// Reads a payload of `length` bytes from `reader`. The buffer grows as bytes
+/// arrive, so a corrupt `length` can't allocate more than the input holds.
+pub(crate) fn read_payload<R: Read>(reader: &mut R, length: u64) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    reader.take(length).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < length {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated payload"));
+    }
+    Ok(payload)
+}
+
+/// Reads the next batch from `reader`, skipping names frames.
+/// Returns `None` if the trace ended cleanly or `END_OF_FRAMES` was reached.
+pub fn read_batch<R: Read>(encoding: Encoding, reader: &mut R) -> Result<Option<Vec<LogRecord>>> {
+    loop {
+        match read_frame(encoding, reader)? {
+            Some(Frame::Batch(batch)) => return Ok(Some(batch)),
+            Some(Frame::Names(_)) => (),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Transcodes a trace from encoding `from` to encoding `to`.
+/// Names frames are passed through unchanged.
+/// Returns the number of converted records.
+pub fn convert<R: Read, W: Write>(from: Encoding, reader: &mut R, to: Encoding, writer: &mut W) -> Result<usize> {
+    let mut count = 0;
+    while let Some(frame) = read_frame(from, reader)? {
+        match frame {
+            Frame::Batch(batch) => {
+                count += batch.len();
+                write_batch(to, &batch, writer)?;
+            }
+            Frame::Names(payload) => { write_names(&payload, writer)?; }
+        }
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+#[test]
+fn roundtrip_abomonation() {
+    use crate::{ActivityType, EventType};
+
+    let batch = vec![LogRecord {
+        timestamp: std::time::Duration::from_nanos(3),
+        activity_type: ActivityType::DataMessage,
+        event_type: EventType::Sent,
+        remote_worker: Some(1),
+        operator_id: None,
+        channel_id: Some(4),
+        correlator_id: Some(5),
+        length: Some(6),
+        ..crate::test_util::record(1, 2)
+    }];
+
+    let mut trace = Vec::new();
+    write_batch(Encoding::Abomonation, &batch, &mut trace).unwrap();
+    write_batch(Encoding::Abomonation, &batch, &mut trace).unwrap();
+
+    let mut reader = &trace[..];
+    assert_eq!(read_batch(Encoding::Abomonation, &mut reader).unwrap(), Some(batch.clone()));
+    assert_eq!(read_batch(Encoding::Abomonation, &mut reader).unwrap(), Some(batch));
+    assert_eq!(read_batch(Encoding::Abomonation, &mut reader).unwrap(), None);
+}
+
+#[test]
+fn corrupt_frame_length() {
+    // a length close to `NAMES_FRAME`, with only a few payload bytes
+    let mut trace = (u64::MAX - 2).to_le_bytes().to_vec();
+    trace.extend_from_slice(&[0; 16]);
+
+    let error = read_batch(Encoding::Compact, &mut &trace[..]).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+}
@@ -0,0 +1,229 @@
+//! Typed view of `LogRecord`s.
+//!
+//! A flat `LogRecord` carries every field any activity might need, most of them
+//! `Option`al. An `Event` instead distinguishes the kinds of records by variant,
+//! each with only (and all of) the fields it needs. Records convert losslessly
+//! from and to `LogRecord`s, so instrumentation and analyses can use either form.
+
+use std::convert::TryFrom;
+
+use crate::{ActivityType, EventType, LogRecord, Origin, Timestamp, Worker, OperatorId, ChannelId, CorrelatorId};
+use crate::tagged::TaggedField;
+
+/// Fields shared by all kinds of records
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub struct Meta {
+    /// worker-unique identifier of a message, given in order the events are logged
+    pub seq_no: u64,
+    /// epoch of the computation this record belongs to
+    pub epoch: u64,
+    /// Event time in nanoseconds since the Epoch
+    pub timestamp: Timestamp,
+    /// Worker the event occured at
+    pub local_worker: Worker,
+    /// Host, process, and thread the event occured at, if known
+    pub origin: Option<Origin>,
+    /// Additional optional fields (cf. `tagged`)
+    pub tagged: Vec<TaggedField>,
+}
+
+/// Start or end of an operator's scheduling
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub struct ScheduleRecord {
+    /// Shared fields
+    pub meta: Meta,
+    /// `Start` or `End`
+    pub event_type: EventType,
+    /// The scheduled operator
+    pub operator_id: OperatorId,
+    /// Number of records processed (only known at `End`)
+    pub length: Option<usize>,
+}
+
+/// One side of a remote data message
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub struct DataMessageRecord {
+    /// Shared fields
+    pub meta: Meta,
+    /// `Sent` or `Received`
+    pub event_type: EventType,
+    /// Receiver (when sent) or sender (when received)
+    pub remote_worker: Worker,
+    /// The channel the message was sent on
+    pub channel_id: ChannelId,
+    /// Matches the sent and received sides of the message
+    pub correlator_id: CorrelatorId,
+    /// Number of records in the message
+    pub length: usize,
+}
+
+/// One side of a remote control (progress) message
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub struct ControlMessageRecord {
+    /// Shared fields
+    pub meta: Meta,
+    /// `Sent` or `Received`
+    pub event_type: EventType,
+    /// Sender (when received). `None` for sends, which are broadcasts.
+    pub remote_worker: Option<Worker>,
+    /// The channel the message was sent on
+    pub channel_id: ChannelId,
+    /// Matches the sent and received sides of the message
+    pub correlator_id: CorrelatorId,
+}
+
+/// A typed `LogRecord`
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub enum Event {
+    /// Operator scheduling
+    Schedule(ScheduleRecord),
+    /// Remote data message
+    DataMessage(DataMessageRecord),
+    /// Remote control message
+    ControlMessage(ControlMessageRecord),
+    /// Any other activity, e.g. emitted by non-timely adapters
+    Other(LogRecord),
+}
+
+impl Event {
+    /// Fields shared by all kinds of records
+    pub fn meta(&self) -> Meta {
+        match self {
+            Event::Schedule(r) => r.meta.clone(),
+            Event::DataMessage(r) => r.meta.clone(),
+            Event::ControlMessage(r) => r.meta.clone(),
+            Event::Other(r) => Meta {
+                seq_no: r.seq_no,
+                epoch: r.epoch,
+                timestamp: r.timestamp,
+                local_worker: r.local_worker,
+                origin: r.origin,
+                tagged: r.tagged.clone(),
+            },
+        }
+    }
+
+    /// The event's activity type
+    pub fn activity_type(&self) -> ActivityType {
+        match self {
+            Event::Schedule(_) => ActivityType::Scheduling,
+            Event::DataMessage(_) => ActivityType::DataMessage,
+            Event::ControlMessage(_) => ActivityType::ControlMessage,
+            Event::Other(r) => r.activity_type,
+        }
+    }
+}
+
+impl TryFrom<LogRecord> for Event {
+    type Error = String;
+
+    /// Fails if `record` lacks a field its activity type requires.
+    fn try_from(record: LogRecord) -> Result<Self, Self::Error> {
+        let meta = Meta {
+            seq_no: record.seq_no,
+            epoch: record.epoch,
+            timestamp: record.timestamp,
+            local_worker: record.local_worker,
+            origin: record.origin,
+            tagged: record.tagged.clone(),
+        };
+        let missing = |field: &str| format!("{:?} record lacks {}: {:?}", record.activity_type, field, record);
+        let is_message = record.event_type == EventType::Sent || record.event_type == EventType::Received;
+
+        match record.activity_type {
+            ActivityType::Scheduling if !is_message => Ok(Event::Schedule(ScheduleRecord {
+                meta,
+                event_type: record.event_type,
+                operator_id: record.operator_id.ok_or_else(|| missing("operator_id"))?,
+                length: record.length,
+            })),
+            ActivityType::DataMessage if is_message => Ok(Event::DataMessage(DataMessageRecord {
+                meta,
+                event_type: record.event_type,
+                remote_worker: record.remote_worker.ok_or_else(|| missing("remote_worker"))?,
+                channel_id: record.channel_id.ok_or_else(|| missing("channel_id"))?,
+                correlator_id: record.correlator_id.ok_or_else(|| missing("correlator_id"))?,
+                length: record.length.ok_or_else(|| missing("length"))?,
+            })),
+            ActivityType::ControlMessage if is_message => Ok(Event::ControlMessage(ControlMessageRecord {
+                meta,
+                event_type: record.event_type,
+                remote_worker: record.remote_worker,
+                channel_id: record.channel_id.ok_or_else(|| missing("channel_id"))?,
+                correlator_id: record.correlator_id.ok_or_else(|| missing("correlator_id"))?,
+            })),
+            ActivityType::Scheduling | ActivityType::DataMessage | ActivityType::ControlMessage =>
+                Err(format!("{:?} record can't be {:?}", record.activity_type, record.event_type)),
+            _ => Ok(Event::Other(record)),
+        }
+    }
+}
+
+impl From<Event> for LogRecord {
+    fn from(event: Event) -> Self {
+        let meta = event.meta();
+        let record = |activity_type: ActivityType, event_type: EventType| LogRecord {
+            seq_no: meta.seq_no,
+            epoch: meta.epoch,
+            timestamp: meta.timestamp,
+            local_worker: meta.local_worker,
+            activity_type,
+            event_type,
+            remote_worker: None,
+            operator_id: None,
+            channel_id: None,
+            correlator_id: None,
+            length: None,
+            origin: meta.origin,
+            tagged: meta.tagged.clone(),
+        };
+
+        match event {
+            Event::Schedule(r) => LogRecord {
+                operator_id: Some(r.operator_id),
+                length: r.length,
+                ..record(ActivityType::Scheduling, r.event_type)
+            },
+            Event::DataMessage(r) => LogRecord {
+                remote_worker: Some(r.remote_worker),
+                channel_id: Some(r.channel_id),
+                correlator_id: Some(r.correlator_id),
+                length: Some(r.length),
+                ..record(ActivityType::DataMessage, r.event_type)
+            },
+            Event::ControlMessage(r) => LogRecord {
+                remote_worker: r.remote_worker,
+                channel_id: Some(r.channel_id),
+                correlator_id: Some(r.correlator_id),
+                ..record(ActivityType::ControlMessage, r.event_type)
+            },
+            Event::Other(r) => r,
+        }
+    }
+}
+
+#[test]
+fn typed_events() {
+    use crate::tagged::{Value, MESSAGE_BYTES};
+
+    let tagged = vec![TaggedField { tag: MESSAGE_BYTES, value: Value::U64(96) }];
+    let meta = Meta { seq_no: 4, epoch: 1, timestamp: Timestamp::from_nanos(10), local_worker: 2, origin: None, tagged };
+    let events = vec![
+        Event::Schedule(ScheduleRecord { meta: meta.clone(), event_type: EventType::End, operator_id: 3, length: Some(12) }),
+        Event::DataMessage(DataMessageRecord { meta: meta.clone(), event_type: EventType::Received, remote_worker: 0, channel_id: 5, correlator_id: 6, length: 12 }),
+        Event::ControlMessage(ControlMessageRecord { meta: meta.clone(), event_type: EventType::Sent, remote_worker: None, channel_id: 7, correlator_id: 8 }),
+    ];
+
+    for event in events.into_iter() {
+        let record = LogRecord::from(event.clone());
+        assert_eq!(record.activity_type, event.activity_type());
+        assert_eq!(Event::try_from(record), Ok(event));
+    }
+
+    // a data message needs to know where it's going
+    let mut record = LogRecord::from(Event::DataMessage(DataMessageRecord {
+        meta, event_type: EventType::Sent, remote_worker: 0, channel_id: 5, correlator_id: 6, length: 12
+    }));
+    record.remote_worker = None;
+    assert!(Event::try_from(record).is_err());
+}
@@ -0,0 +1,161 @@
+//! `LogRecord` layouts of older trace format versions: before version 4, records
+//! lacked `origin`, before version 5 they lacked `tagged` fields.
+//! Abomonation and bincode payloads depend on the exact struct layout, so
+//! `TraceReader` decodes them of older traces via `LogRecordV3` and `LogRecordV4`.
+//! The protobuf and compact encodings are forward-compatible and need no special handling.
+//! Since version 5, new fields are added as tagged fields, so no further layouts are needed.
+
+use std::io::{Error, ErrorKind, Result};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ActivityType, EventType, LogRecord, Origin, Timestamp, Worker, OperatorId, ChannelId};
+use crate::encoding::{self, Encoding};
+
+/// A `LogRecord` as written before trace format version 4
+#[derive(Abomonation, PartialEq, Eq, Hash, Clone, Debug, Deserialize, Serialize)]
+pub struct LogRecordV3 {
+    /// cf. `LogRecord::seq_no`
+    pub seq_no: u64,
+    /// cf. `LogRecord::epoch`
+    pub epoch: u64,
+    /// cf. `LogRecord::timestamp`
+    pub timestamp: Timestamp,
+    /// cf. `LogRecord::local_worker`
+    pub local_worker: Worker,
+    /// cf. `LogRecord::activity_type`
+    pub activity_type: ActivityType,
+    /// cf. `LogRecord::event_type`
+    pub event_type: EventType,
+    /// cf. `LogRecord::remote_worker`
+    pub remote_worker: Option<Worker>,
+    /// cf. `LogRecord::operator_id`
+    pub operator_id: Option<OperatorId>,
+    /// cf. `LogRecord::channel_id`
+    pub channel_id: Option<ChannelId>,
+    /// cf. `LogRecord::correlator_id`
+    pub correlator_id: Option<u64>,
+    /// cf. `LogRecord::length`
+    pub length: Option<usize>,
+}
+
+/// A `LogRecord` as written with trace format version 4
+#[derive(Abomonation, PartialEq, Eq, Hash, Clone, Debug, Deserialize, Serialize)]
+pub struct LogRecordV4 {
+    /// cf. `LogRecord::seq_no`
+    pub seq_no: u64,
+    /// cf. `LogRecord::epoch`
+    pub epoch: u64,
+    /// cf. `LogRecord::timestamp`
+    pub timestamp: Timestamp,
+    /// cf. `LogRecord::local_worker`
+    pub local_worker: Worker,
+    /// cf. `LogRecord::activity_type`
+    pub activity_type: ActivityType,
+    /// cf. `LogRecord::event_type`
+    pub event_type: EventType,
+    /// cf. `LogRecord::remote_worker`
+    pub remote_worker: Option<Worker>,
+    /// cf. `LogRecord::operator_id`
+    pub operator_id: Option<OperatorId>,
+    /// cf. `LogRecord::channel_id`
+    pub channel_id: Option<ChannelId>,
+    /// cf. `LogRecord::correlator_id`
+    pub correlator_id: Option<u64>,
+    /// cf. `LogRecord::length`
+    pub length: Option<usize>,
+    /// cf. `LogRecord::origin`
+    pub origin: Option<Origin>,
+}
+
+impl From<LogRecordV3> for LogRecord {
+    fn from(record: LogRecordV3) -> Self {
+        LogRecord {
+            seq_no: record.seq_no,
+            epoch: record.epoch,
+            timestamp: record.timestamp,
+            local_worker: record.local_worker,
+            activity_type: record.activity_type,
+            event_type: record.event_type,
+            remote_worker: record.remote_worker,
+            operator_id: record.operator_id,
+            channel_id: record.channel_id,
+            correlator_id: record.correlator_id,
+            length: record.length,
+            origin: None,
+            tagged: Vec::new(),
+        }
+    }
+}
+
+impl From<LogRecordV4> for LogRecord {
+    fn from(record: LogRecordV4) -> Self {
+        LogRecord {
+            seq_no: record.seq_no,
+            epoch: record.epoch,
+            timestamp: record.timestamp,
+            local_worker: record.local_worker,
+            activity_type: record.activity_type,
+            event_type: record.event_type,
+            remote_worker: record.remote_worker,
+            operator_id: record.operator_id,
+            channel_id: record.channel_id,
+            correlator_id: record.correlator_id,
+            length: record.length,
+            origin: record.origin,
+            tagged: Vec::new(),
+        }
+    }
+}
+
+/// Decodes a batch written with trace format `version`.
+pub fn decode_batch(version: u16, encoding: Encoding, bytes: &mut [u8]) -> Result<Vec<LogRecord>> {
+    match version {
+        0 ..= 3 => decode_as::<LogRecordV3>(encoding, bytes),
+        4 => decode_as::<LogRecordV4>(encoding, bytes),
+        _ => encoding::decode_batch(encoding, bytes),
+    }
+}
+
+/// Decodes a batch of records with layout `T`.
+fn decode_as<T>(encoding: Encoding, bytes: &mut [u8]) -> Result<Vec<LogRecord>>
+where
+    T: abomonation::Abomonation + serde::de::DeserializeOwned + Clone + Into<LogRecord>,
+{
+    let batch: Vec<T> = match encoding {
+        Encoding::Abomonation => {
+            match unsafe { abomonation::decode::<Vec<T>>(bytes) } {
+                Some((batch, rest)) if rest.is_empty() => batch.clone(),
+                _ => return Err(Error::new(ErrorKind::InvalidData, "malformed abomonation payload")),
+            }
+        }
+        #[cfg(feature = "bincode")]
+        Encoding::Bincode => bincode::deserialize(bytes)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+        _ => return encoding::decode_batch(encoding, bytes),
+    };
+    Ok(batch.into_iter().map(Into::into).collect())
+}
+
+#[test]
+fn decode_v3_abomonation() {
+    let v3 = vec![LogRecordV3 {
+        seq_no: 1,
+        epoch: 2,
+        timestamp: Timestamp::from_nanos(3),
+        local_worker: 0,
+        activity_type: ActivityType::Scheduling,
+        event_type: EventType::Start,
+        remote_worker: None,
+        operator_id: Some(4),
+        channel_id: None,
+        correlator_id: None,
+        length: None,
+    }];
+    let mut bytes = Vec::new();
+    unsafe { abomonation::encode(&v3, &mut bytes).unwrap() };
+
+    let batch = decode_batch(3, Encoding::Abomonation, &mut bytes).unwrap();
+    assert_eq!(batch, vec![LogRecord::from(v3[0].clone())]);
+    assert_eq!(batch[0].origin, None);
+}
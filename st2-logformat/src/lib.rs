@@ -2,6 +2,32 @@
 //! A `LogRecord` constitutes the unified `struct` representation of
 //! log messages from various stream processors.
 //! It is the underlying structure from which the PAG construction starts.
+//! The `event` module offers a typed view of `LogRecord`s, the `tagged` module
+//! an extensible set of optional fields.
+//! Traces of `LogRecord`s can be (de)serialized with the `encoding` module,
+//! and stored as self-describing trace files with the `trace` module, optionally
+//! rotated across several files (cf. the `rotation` module).
+//! Non-Rust producers can emit traces via the protobuf schema in
+//! `proto/logrecord.proto` (cf. the `proto` module).
+//! Hot internal paths batch records column-wise with the `batch` module.
+//! With the `arrow` feature, batches can be converted to Arrow's columnar
+//! format with the `columnar` module. Whole traces can be converted between
+//! encodings with the `convert` module, and, with the `parquet` feature, stored
+//! as Parquet files with the `parquet` module. The `perfetto` module writes
+//! Perfetto traces, e.g. of PAGs, and the `csv` module exports records as
+//! plain CSV/TSV.
+//! The `pag` module defines the edges of SnailTrail's program activity graph
+//! (PAG) and its critical path analysis. Without the default `pair` feature,
+//! the crate doesn't depend on timely and builds for wasm32 (cf. `st2-wasm`).
+//!
+//! # Stability
+//!
+//! This crate is the interchange format between SnailTrail and its producers and
+//! consumers, and follows semver: `LogRecord`, its encodings, and the trace file
+//! format only change in backwards-compatible ways within a major version.
+//! Readers accept all trace format versions up to `trace::FORMAT_VERSION`; new
+//! optional fields are added as tagged fields (cf. `tagged`). The conformance tests
+//! in `tests/conformance.rs` pin the wire formats.
 
 #![deny(missing_docs)]
 
@@ -12,6 +38,29 @@ use std::cmp::Ordering;
 
 use serde::{Deserialize, Serialize};
 
+pub mod event;
+pub mod encoding;
+pub mod trace;
+pub mod proto;
+pub mod names;
+pub mod block;
+pub mod tagged;
+pub mod rotation;
+pub mod convert;
+pub mod perfetto;
+pub mod csv;
+pub mod pag;
+pub mod batch;
+mod compact;
+mod legacy;
+#[cfg(feature = "arrow")]
+pub mod columnar;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+mod varint;
+#[cfg(test)]
+mod test_util;
+
 /// The various types of activity that can happen in a dataflow.
 /// `Unknown` et al. shouldn't be emitted by instrumentation. Instead,
 /// they might be inserted as helpers during PAG construction.
@@ -47,7 +96,7 @@ pub enum ActivityType {
 /// What "side" of the event did we log? E.g., for
 /// scheduling events, it might be the start or end of the event;
 /// for messages, we might log the sender or receiver.
-#[derive(Abomonation, PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Clone, Copy)]
+#[derive(Abomonation, PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Clone, Copy, Deserialize, Serialize)]
 pub enum EventType {
     /// Start of an event (e.g. ScheduleStart, Sending a Message)
     Start = 1,
@@ -70,13 +119,26 @@ pub type OperatorId = u64;
 /// A worker-local channel ID
 pub type ChannelId = u64;
 
+/// Where an event was logged, beyond its worker: distinguishes workers
+/// on the same machine from workers communicating over the network.
+#[derive(Abomonation, PartialEq, Eq, Hash, Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Origin {
+    /// Hostname, interned in the trace's `names::NameTable`
+    pub host: names::NameId,
+    /// Id of the process on `host`
+    pub process_id: u32,
+    /// Id of the thread within the process
+    pub thread_id: u64,
+}
+
 
 /// A `LogRecord` constitutes the unified `struct` representation of
 /// log messages from various stream processors.
 ///
 /// It is the underlying structure from which the PAG construction starts.
-/// If necessary, it can also be serialized e.g. into a `msgpack` representation.
-#[derive(Abomonation, PartialEq, Eq, Hash, Clone, Debug)]
+/// If necessary, it can also be serialized e.g. into a `bincode` representation
+/// (cf. the `encoding` module).
+#[derive(Abomonation, PartialEq, Eq, Hash, Clone, Debug, Deserialize, Serialize)]
 pub struct LogRecord {
     /// worker-unique identifier of a message, given in order the events are logged
     /// in the computation.
@@ -101,6 +163,12 @@ pub struct LogRecord {
     pub correlator_id: Option<u64>,
     /// Number of records to detect skew
     pub length: Option<usize>,
+    /// Host, process, and thread the event was logged at, if known
+    /// (added in trace format version 4).
+    pub origin: Option<Origin>,
+    /// Additional optional fields, ordered by tag (added in trace format version 5).
+    /// New fields are added as tags rather than struct fields (cf. the `tagged` module).
+    pub tagged: Vec<tagged::TaggedField>,
 }
 
 impl Ord for LogRecord {
@@ -117,9 +185,11 @@ impl PartialOrd for LogRecord {
 
 
 /// This module contains a definition of a new timestamp time, a "pair" or product.
+/// It requires the `pair` feature (on by default).
 ///
 /// Note: Its partial order trait is modified so that it follows a lexicographical order;
 /// It is not truly partially ordered (cf. the `compare_pairs` test)!
+#[cfg(feature = "pair")]
 pub mod pair {
     use differential_dataflow::lattice::Lattice;
 
@@ -0,0 +1,133 @@
+//! Interning of names (e.g. of operators or hosts) referenced by `LogRecord`s.
+//!
+//! Every distinct name is stored once per trace file and referenced by its `NameId`,
+//! so records keep a fixed size and can be joined on names cheaply.
+//! Names are assigned consecutive ids in the order they are interned, and written
+//! to the trace in names frames (cf. `encoding::NAMES_FRAME`) before the first batch
+//! referencing them: `[first id: varint][count: varint]([length: varint][name: UTF-8])*`.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::convert::TryInto;
+
+use crate::varint::{read_varint, write_varint};
+
+/// Identifies an interned name within a trace
+pub type NameId = u32;
+
+/// A bidirectional mapping between names and their `NameId`s
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct NameTable {
+    names: Vec<String>,
+    ids: HashMap<String, NameId>,
+    /// Number of names already written to a names frame
+    written: usize,
+}
+
+impl NameTable {
+    /// Returns the id of `name`, interning it if necessary.
+    pub fn intern(&mut self, name: &str) -> NameId {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+
+        let id = self.names.len() as NameId;
+        self.names.push(name.to_string());
+        self.ids.insert(name.to_string(), id);
+        id
+    }
+
+    /// Returns the name interned as `id`.
+    pub fn resolve(&self, id: NameId) -> Option<&str> {
+        self.names.get(id as usize).map(|name| name.as_str())
+    }
+
+    /// Returns the id of `name`, if it has been interned.
+    pub fn id_of(&self, name: &str) -> Option<NameId> {
+        self.ids.get(name).cloned()
+    }
+
+    /// Number of interned names
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Whether no names have been interned
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Encodes all names interned since the last call as a names frame payload.
+    /// Returns `None` if there are no new names.
+    pub(crate) fn encode_new(&mut self) -> Option<Vec<u8>> {
+        if self.written == self.names.len() {
+            return None;
+        }
+
+        let mut buf = Vec::new();
+        write_varint(&mut buf, self.written as u64);
+        write_varint(&mut buf, (self.names.len() - self.written) as u64);
+        for name in self.names[self.written ..].iter() {
+            write_varint(&mut buf, name.len() as u64);
+            buf.extend_from_slice(name.as_bytes());
+        }
+        self.written = self.names.len();
+
+        Some(buf)
+    }
+
+    /// Adds the names of a names frame payload.
+    pub(crate) fn decode(&mut self, mut bytes: &[u8]) -> Result<()> {
+        let first = read_varint(&mut bytes)?;
+        if first != self.names.len() as u64 {
+            return Err(invalid(&format!("names frame starts at id {}, expected {}", first, self.names.len())));
+        }
+
+        let count = read_varint(&mut bytes)?;
+        for _ in 0 .. count {
+            let length: usize = read_varint(&mut bytes)?.try_into()
+                .map_err(|_| invalid("name too long"))?;
+            if length > bytes.len() {
+                return Err(invalid("truncated names frame"));
+            }
+            let (name, rest) = bytes.split_at(length);
+            bytes = rest;
+
+            let name = std::str::from_utf8(name).map_err(|_| invalid("malformed name"))?;
+            if self.ids.contains_key(name) {
+                return Err(invalid(&format!("duplicate name {} in names frame", name)));
+            }
+            self.intern(name);
+        }
+        self.written = self.names.len();
+
+        Ok(())
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, reason.to_string())
+}
+
+#[test]
+fn intern_names() {
+    let mut table = NameTable::default();
+    let map = table.intern("Map");
+    let host = table.intern("host-1");
+    assert_eq!(table.intern("Map"), map);
+    assert_eq!(table.resolve(host), Some("host-1"));
+    assert_eq!(table.resolve(42), None);
+
+    // names are transferred incrementally
+    let mut copy = NameTable::default();
+    copy.decode(&table.encode_new().unwrap()).unwrap();
+    assert_eq!(table.encode_new(), None);
+    table.intern("Join");
+    copy.decode(&table.encode_new().unwrap()).unwrap();
+    assert_eq!(copy, table);
+
+    // out-of-order frames are rejected
+    let mut fresh = NameTable::default();
+    fresh.intern("Map");
+    assert!(copy.decode(&fresh.encode_new().unwrap()).is_err());
+}
@@ -0,0 +1,267 @@
+//! Nodes and edges of the program activity graph (PAG), and its critical path.
+//!
+//! SnailTrail constructs the PAG from `LogRecord`s; its edges are activities of
+//! a single worker or messages between workers. These definitions don't depend
+//! on timely, so PAGs exported by SnailTrail (e.g. `st2 snapshot`) can be
+//! analyzed elsewhere, e.g. in a browser (cf. `st2-wasm`).
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ActivityType, LogRecord, OperatorId, Origin};
+
+/// A node in the PAG
+#[derive(Abomonation, Clone, PartialEq, Hash, Eq, Copy, Serialize, Deserialize)]
+pub struct PagNode {
+    /// Timestamp of the event (also a unique identifier!)
+    pub timestamp: crate::Timestamp,
+    /// Unique ID of the worker the event belongs to
+    pub worker_id: crate::Worker,
+    /// Epoch of PagNode
+    pub epoch: u64,
+    /// seq_no of PagNode
+    pub seq_no: u64,
+}
+
+/// Nodes are ordered by timestamp; ties (e.g. of clock-skewed workers) are
+/// broken by the remaining fields, so the order is total.
+impl Ord for PagNode {
+    fn cmp(&self, other: &PagNode) -> Ordering {
+        (self.timestamp, self.worker_id, self.epoch, self.seq_no).cmp(&(other.timestamp, other.worker_id, other.epoch, other.seq_no))
+    }
+}
+
+impl PartialOrd for PagNode {
+    fn partial_cmp(&self, other: &PagNode) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a> From<&'a LogRecord> for PagNode {
+    fn from(record: &'a LogRecord) -> Self {
+        PagNode {
+            timestamp: record.timestamp,
+            worker_id: record.local_worker,
+            epoch: record.epoch,
+            seq_no: record.seq_no,
+        }
+    }
+}
+
+impl Default for PagNode {
+    fn default() -> Self {
+        PagNode {
+            timestamp: Default::default(),
+            worker_id: Default::default(),
+            epoch: Default::default(),
+            seq_no: Default::default()
+        }
+    }
+}
+
+impl std::fmt::Debug for PagNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // write!(f, "{}|{:?}@w{} (s{})", self.epoch, self.timestamp, self.worker_id, self.seq_no)
+        write!(f, "{},{},{},{}", self.epoch, self.timestamp.as_nanos(), self.worker_id, self.seq_no)
+    }
+}
+
+/// Information on how to traverse an edge. This is used e.g. in critical
+/// participation to decide whether an edge should be included in the critical
+/// path calculation. A `Block`ed edge can't be traversed (e.g. waiting activities)
+#[derive(Abomonation, Hash, Clone, Eq, Ord, PartialEq, PartialOrd, Debug, Serialize, Deserialize)]
+pub enum TraversalType {
+    /// Unclear traversal
+    Undefined,
+    /// No traversal possible
+    Block,
+    /// Traversal possible
+    Unbounded,
+}
+
+/// What a message between two workers crossed, as told by the `Origin`s of its
+/// sender and receiver
+#[derive(Abomonation, Hash, Clone, Copy, Eq, Ord, PartialEq, PartialOrd, Debug, Serialize, Deserialize)]
+pub enum Link {
+    /// Workers of the same process, i.e. shared memory
+    SameProcess,
+    /// Processes on the same host
+    SameHost,
+    /// Hosts, i.e. the network
+    Network,
+}
+
+impl Link {
+    /// The link between workers at `from` and `to`. Hosts are compared by their
+    /// `NameId`s, so both origins have to be interned in the same name table.
+    pub fn between(from: &Origin, to: &Origin) -> Link {
+        if from.host != to.host {
+            Link::Network
+        } else if from.process_id != to.process_id {
+            Link::SameHost
+        } else {
+            Link::SameProcess
+        }
+    }
+}
+
+/// An edge in the activity graph
+#[derive(Abomonation, Clone, PartialEq, Hash, Eq, Serialize, Deserialize)]
+pub struct PagEdge {
+    /// The source node
+    pub source: PagNode,
+    /// The destination node
+    pub destination: PagNode,
+    /// The activity type
+    pub edge_type: ActivityType,
+    /// An optional operator ID
+    pub operator_id: Option<OperatorId>,
+    /// Edge dependency information
+    pub traverse: TraversalType,
+    /// record count
+    pub length: Option<usize>,
+    /// For message edges, what the message crossed, if the origins of both of
+    /// its workers are known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<Link>,
+}
+
+impl PagEdge {
+    /// PagEdge's duration in ns.
+    /// Due to clock skew, we can't give guarantees that `to.timestamp > from.timestamp`.
+    /// We report a duration of 0 in the case that `to.timestamp < from.timestamp`.
+    pub fn duration(&self) -> u64 {
+        let dst_ts = self.destination.timestamp.as_nanos();
+        let src_ts = self.source.timestamp.as_nanos();
+
+        if src_ts > dst_ts {
+            0
+        } else {
+            (dst_ts - src_ts).try_into().unwrap()
+        }
+    }
+}
+
+/// Edges are ordered by source node; ties are broken by the remaining fields,
+/// so sorting edges is deterministic.
+impl Ord for PagEdge {
+    fn cmp(&self, other: &PagEdge) -> Ordering {
+        (&self.source, &self.destination, &self.edge_type, &self.operator_id, &self.traverse, &self.length, &self.link)
+            .cmp(&(&other.source, &other.destination, &other.edge_type, &other.operator_id, &other.traverse, &other.length, &other.link))
+    }
+}
+
+impl PartialOrd for PagEdge {
+    fn partial_cmp(&self, other: &PagEdge) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Default for PagEdge {
+    fn default() -> Self {
+        PagEdge {
+            source: Default::default(),
+            destination: Default::default(),
+            edge_type: ActivityType::Waiting,
+            operator_id: None,
+            traverse: TraversalType::Block,
+            length: None,
+            link: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for PagEdge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // write!(f, "{:?} -> {:?} | {:?} {:?}\t| oid: {:?}\t",
+        //        self.source, self.destination,
+        //        self.traverse, self.edge_type,
+        //        self.operator_id)
+
+        write!(f, "{:?},{:?},{:?},{:?},{:?}",
+               self.source, self.destination,
+               self.edge_type, self.operator_id, self.length)
+    }
+}
+
+/// The longest path through `edges` that doesn't traverse blocked (i.e., waiting) edges,
+/// weighted by edge duration. This is a path of activities and messages that all
+/// contributed to the epoch's latency. Ties between equally long paths are broken
+/// by their edges, so the path doesn't depend on the order of `edges`.
+pub fn critical_path(edges: &[PagEdge]) -> Vec<PagEdge> {
+    // edges in topological order, as time only moves forward along edges
+    let mut order: Vec<&PagEdge> = edges.iter().filter(|edge| edge.traverse != TraversalType::Block).collect();
+    order.sort_by(|a, b| (a.destination.timestamp, a.destination.seq_no, *a).cmp(&(b.destination.timestamp, b.destination.seq_no, *b)));
+
+    // node -> (length of the longest path ending at the node, its last edge)
+    let mut longest: HashMap<PagNode, (u64, &PagEdge)> = HashMap::new();
+    for edge in order {
+        let length = longest.get(&edge.source).map(|(length, _)| *length).unwrap_or(0) + edge.duration();
+        let best = longest.entry(edge.destination).or_insert((length, edge));
+        if length > best.0 {
+            *best = (length, edge);
+        }
+    }
+
+    let mut node = match longest.iter().max_by_key(|(node, (length, _))| (*length, **node)) {
+        Some((node, _)) => *node,
+        None => return Vec::new(),
+    };
+    let mut path = Vec::new();
+    while let Some((_, edge)) = longest.get(&node) {
+        // guards against cycles of zero-duration edges
+        if path.len() == longest.len() {
+            break;
+        }
+        path.push((*edge).clone());
+        node = edge.source;
+    }
+    path.reverse();
+    path
+}
+
+#[test]
+fn critical_path_skips_blocked_edges() {
+    use std::time::Duration;
+
+    let node = |worker, ns| PagNode { timestamp: Duration::from_nanos(ns), worker_id: worker, ..Default::default() };
+    let edge = |from, to, edge_type, traverse| PagEdge { source: from, destination: to, edge_type, traverse, ..Default::default() };
+    let edges = vec![
+        edge(node(0, 0), node(0, 10), ActivityType::Processing, TraversalType::Unbounded),
+        edge(node(0, 10), node(0, 100), ActivityType::Waiting, TraversalType::Block),
+        edge(node(0, 10), node(1, 20), ActivityType::DataMessage, TraversalType::Unbounded),
+        edge(node(1, 20), node(1, 50), ActivityType::Processing, TraversalType::Unbounded),
+    ];
+
+    let path = critical_path(&edges);
+    assert_eq!(path, vec![edges[0].clone(), edges[2].clone(), edges[3].clone()]);
+}
+
+#[test]
+fn critical_path_ignores_edge_order() {
+    use std::time::Duration;
+
+    let node = |worker, ns| PagNode { timestamp: Duration::from_nanos(ns), worker_id: worker, ..Default::default() };
+    let edge = |from, to| PagEdge { source: from, destination: to, edge_type: ActivityType::Processing, traverse: TraversalType::Unbounded, ..Default::default() };
+    // two paths of the same length to the same timestamp
+    let mut edges = vec![
+        edge(node(0, 0), node(0, 10)),
+        edge(node(1, 0), node(1, 10)),
+    ];
+
+    let path = critical_path(&edges);
+    edges.reverse();
+    assert_eq!(critical_path(&edges), path);
+}
+
+#[test]
+fn links_compare_hosts_then_processes() {
+    let origin = |host, process_id, thread_id| Origin { host, process_id, thread_id };
+
+    assert_eq!(Link::between(&origin(0, 1, 1), &origin(0, 1, 2)), Link::SameProcess);
+    assert_eq!(Link::between(&origin(0, 1, 1), &origin(0, 2, 1)), Link::SameHost);
+    assert_eq!(Link::between(&origin(0, 1, 1), &origin(1, 1, 1)), Link::Network);
+}
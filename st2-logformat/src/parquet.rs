@@ -0,0 +1,381 @@
+//! Traces as Apache Parquet files (requires the `parquet` feature).
+//!
+//! A Parquet trace has the columns of the Arrow schema of `LogRecord` batches
+//! (cf. `columnar::schema`), so Arrow-based query engines can read it directly,
+//! and holds batches in row groups of up to `ROW_GROUP_RECORDS` records.
+//! The trace's header and names are stored in the file's key-value metadata
+//! (hex-encoded as in trace files, under `st2.header` and `st2.names`), so traces
+//! convert from and to trace files without loss.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::rc::Rc;
+use std::time::Duration;
+
+use ::parquet::column::reader::ColumnReader;
+use ::parquet::column::writer::ColumnWriter;
+use ::parquet::data_type::ByteArray;
+use ::parquet::errors::ParquetError;
+use ::parquet::file::metadata::KeyValue;
+use ::parquet::file::properties::WriterProperties;
+use ::parquet::file::reader::{FileReader, RowGroupReader, SerializedFileReader};
+use ::parquet::file::writer::{FileWriter, RowGroupWriter, SerializedFileWriter};
+use ::parquet::schema::parser::parse_message_type;
+
+use crate::{LogRecord, Origin};
+use crate::compact::{read_tagged, write_tagged};
+use crate::names::{NameId, NameTable};
+use crate::proto::{activity_type, event_type};
+use crate::trace::TraceHeader;
+
+/// Maximum number of records per row group
+pub const ROW_GROUP_RECORDS: usize = 1 << 16;
+
+/// The Parquet schema of traces, with the columns of `columnar::schema`
+const SCHEMA: &str = "message log_record {
+    OPTIONAL INT64 seq_no (UINT_64);
+    OPTIONAL INT64 epoch (UINT_64);
+    OPTIONAL INT64 timestamp_ns (UINT_64);
+    OPTIONAL INT64 local_worker (UINT_64);
+    OPTIONAL INT32 activity_type (UINT_8);
+    OPTIONAL INT32 event_type (UINT_8);
+    OPTIONAL INT64 remote_worker (UINT_64);
+    OPTIONAL INT64 operator_id (UINT_64);
+    OPTIONAL INT64 channel_id (UINT_64);
+    OPTIONAL INT64 correlator_id (UINT_64);
+    OPTIONAL INT64 length (UINT_64);
+    OPTIONAL INT32 host (UINT_32);
+    OPTIONAL INT32 process_id (UINT_32);
+    OPTIONAL INT64 thread_id (UINT_64);
+    OPTIONAL BYTE_ARRAY tagged;
+}";
+
+/// Values of a column, in one of the physical types of `SCHEMA`
+enum Column {
+    Int64(Vec<Option<u64>>),
+    Int32(Vec<Option<u32>>),
+    Bytes(Vec<Option<Vec<u8>>>),
+}
+
+/// Writes a trace with `header` and `names` as a Parquet file to `file`.
+/// As the metadata is written first, `names` has to contain all names the
+/// records of `batches` refer to. Returns the number of written records.
+pub fn write_trace<I>(header: &TraceHeader, names: &NameTable, batches: I, file: File) -> Result<usize>
+where
+    I: IntoIterator<Item = Result<Vec<LogRecord>>>,
+{
+    let mut header_bytes = Vec::new();
+    header.write_to(&mut header_bytes)?;
+    let mut metadata = vec![KeyValue { key: "st2.header".to_string(), value: Some(to_hex(&header_bytes)) }];
+    // re-intern into a fresh table, as `names` may have been written to a names frame already
+    let mut all = NameTable::default();
+    for id in 0 .. names.len() {
+        all.intern(names.resolve(id as NameId).expect("names are consecutive"));
+    }
+    if let Some(names_bytes) = all.encode_new() {
+        metadata.push(KeyValue { key: "st2.names".to_string(), value: Some(to_hex(&names_bytes)) });
+    }
+
+    let schema = Rc::new(parse_message_type(SCHEMA).map_err(to_io)?);
+    let properties = Rc::new(WriterProperties::builder().set_key_value_metadata(Some(metadata)).build());
+    let mut writer = SerializedFileWriter::new(file, schema, properties).map_err(to_io)?;
+
+    let mut count = 0;
+    let mut pending = Vec::new();
+    for batch in batches {
+        pending.extend(batch?);
+        while pending.len() >= ROW_GROUP_RECORDS {
+            let rest = pending.split_off(ROW_GROUP_RECORDS);
+            count += write_row_group(&mut writer, &pending)?;
+            pending = rest;
+        }
+    }
+    if !pending.is_empty() {
+        count += write_row_group(&mut writer, &pending)?;
+    }
+    writer.close().map_err(to_io)?;
+    Ok(count)
+}
+
+fn write_row_group(writer: &mut SerializedFileWriter<File>, records: &[LogRecord]) -> Result<usize> {
+    let mut columns = to_columns(records).into_iter();
+    let mut row_group = writer.next_row_group().map_err(to_io)?;
+    while let Some(mut column_writer) = row_group.next_column().map_err(to_io)? {
+        let column = columns.next().ok_or_else(|| invalid("schema has more columns than records"))?;
+        match (&mut column_writer, column) {
+            (ColumnWriter::Int64ColumnWriter(ref mut typed), Column::Int64(values)) => {
+                let present: Vec<i64> = values.iter().flatten().map(|x| *x as i64).collect();
+                typed.write_batch(&present, Some(&levels(&values)), None).map_err(to_io)?;
+            }
+            (ColumnWriter::Int32ColumnWriter(ref mut typed), Column::Int32(values)) => {
+                let present: Vec<i32> = values.iter().flatten().map(|x| *x as i32).collect();
+                typed.write_batch(&present, Some(&levels(&values)), None).map_err(to_io)?;
+            }
+            (ColumnWriter::ByteArrayColumnWriter(ref mut typed), Column::Bytes(values)) => {
+                let present: Vec<ByteArray> = values.iter().flatten().map(|x| ByteArray::from(x.clone())).collect();
+                typed.write_batch(&present, Some(&levels(&values)), None).map_err(to_io)?;
+            }
+            _ => return Err(invalid("column type doesn't match schema")),
+        }
+        row_group.close_column(column_writer).map_err(to_io)?;
+    }
+    writer.close_row_group(row_group).map_err(to_io)?;
+    Ok(records.len())
+}
+
+/// Splits `records` into the columns of `SCHEMA`.
+fn to_columns(records: &[LogRecord]) -> Vec<Column> {
+    fn column<T, F: Fn(&LogRecord) -> Option<T>>(records: &[LogRecord], f: F) -> Vec<Option<T>> {
+        records.iter().map(f).collect()
+    }
+
+    vec![
+        Column::Int64(column(records, |r| Some(r.seq_no))),
+        Column::Int64(column(records, |r| Some(r.epoch))),
+        Column::Int64(column(records, |r| Some(r.timestamp.as_nanos() as u64))),
+        Column::Int64(column(records, |r| Some(r.local_worker))),
+        Column::Int32(column(records, |r| Some(r.activity_type as u32))),
+        Column::Int32(column(records, |r| Some(r.event_type as u32))),
+        Column::Int64(column(records, |r| r.remote_worker)),
+        Column::Int64(column(records, |r| r.operator_id)),
+        Column::Int64(column(records, |r| r.channel_id)),
+        Column::Int64(column(records, |r| r.correlator_id)),
+        Column::Int64(column(records, |r| r.length.map(|l| l as u64))),
+        Column::Int32(column(records, |r| r.origin.map(|o| o.host))),
+        Column::Int32(column(records, |r| r.origin.map(|o| o.process_id))),
+        Column::Int64(column(records, |r| r.origin.map(|o| o.thread_id))),
+        Column::Bytes(column(records, |r| if r.tagged.is_empty() {
+            None
+        } else {
+            let mut buf = Vec::new();
+            write_tagged(&r.tagged, &mut buf);
+            Some(buf)
+        })),
+    ]
+}
+
+/// Definition levels of an optional column: 1 for present values, 0 for nulls
+fn levels<T>(values: &[Option<T>]) -> Vec<i16> {
+    values.iter().map(|x| if x.is_some() { 1 } else { 0 }).collect()
+}
+
+/// Reads `LogRecord` batches, one per row group, from a Parquet trace.
+pub struct ParquetReader {
+    reader: SerializedFileReader<File>,
+    header: TraceHeader,
+    names: NameTable,
+    next_row_group: usize,
+}
+
+impl ParquetReader {
+    /// Reads the metadata of the Parquet trace in `file`.
+    pub fn new(file: File) -> Result<Self> {
+        let reader = SerializedFileReader::new(file).map_err(to_io)?;
+        let metadata: HashMap<String, String> = reader.metadata().file_metadata().key_value_metadata().iter()
+            .flatten()
+            .filter_map(|kv| kv.value.clone().map(|value| (kv.key.clone(), value)))
+            .collect();
+
+        let header_bytes = from_hex(metadata.get("st2.header").ok_or_else(|| invalid("not an ST2 trace: missing st2.header"))?)?;
+        let header = TraceHeader::read_from(&mut &header_bytes[..])?;
+        let mut names = NameTable::default();
+        if let Some(names_bytes) = metadata.get("st2.names") {
+            names.decode(&from_hex(names_bytes)?)?;
+        }
+
+        Ok(ParquetReader { reader, header, names, next_row_group: 0 })
+    }
+
+    /// The header of the trace the Parquet file was converted from
+    pub fn header(&self) -> &TraceHeader {
+        &self.header
+    }
+
+    /// Names the trace's records refer to
+    pub fn names(&self) -> &NameTable {
+        &self.names
+    }
+
+    /// Reads the next row group as a batch. Returns `None` after the last one.
+    pub fn next_batch(&mut self) -> Result<Option<Vec<LogRecord>>> {
+        if self.next_row_group >= self.reader.num_row_groups() {
+            return Ok(None);
+        }
+        let row_group = self.reader.get_row_group(self.next_row_group).map_err(to_io)?;
+        self.next_row_group += 1;
+
+        let rows = row_group.metadata().num_rows() as usize;
+        let indices: HashMap<String, usize> = (0 .. row_group.num_columns())
+            .map(|i| (row_group.metadata().column(i).column_descr().name().to_string(), i))
+            .collect();
+        let int64 = |name: &str| read_int64(row_group.as_ref(), &indices, name, rows);
+        let int32 = |name: &str| read_int32(row_group.as_ref(), &indices, name, rows);
+
+        let seq_no = int64("seq_no")?;
+        let epoch = int64("epoch")?;
+        let timestamp = int64("timestamp_ns")?;
+        let local_worker = int64("local_worker")?;
+        let activity_types = int32("activity_type")?;
+        let event_types = int32("event_type")?;
+        let remote_worker = int64("remote_worker")?;
+        let operator_id = int64("operator_id")?;
+        let channel_id = int64("channel_id")?;
+        let correlator_id = int64("correlator_id")?;
+        let length = int64("length")?;
+        let host = int32("host")?;
+        let process_id = int32("process_id")?;
+        let thread_id = int64("thread_id")?;
+        let tagged = read_bytes(row_group.as_ref(), &indices, "tagged", rows)?;
+
+        let required = |column: &[Option<u64>], row: usize| column[row].ok_or_else(|| invalid("missing required value"));
+
+        (0 .. rows).map(|row| {
+            let length = match length[row] {
+                Some(l) => Some(l.try_into().map_err(|_| invalid("length exceeds usize"))?),
+                None => None,
+            };
+            let origin = match (host[row], process_id[row], thread_id[row]) {
+                (Some(host), Some(process_id), Some(thread_id)) => Some(Origin { host, process_id, thread_id }),
+                _ => None,
+            };
+            let tagged = match &tagged[row] {
+                Some(bytes) => read_tagged(&mut &bytes[..])?,
+                None => Vec::new(),
+            };
+
+            Ok(LogRecord {
+                seq_no: required(&seq_no, row)?,
+                epoch: required(&epoch, row)?,
+                timestamp: Duration::from_nanos(required(&timestamp, row)?),
+                local_worker: required(&local_worker, row)?,
+                activity_type: activity_type(activity_types[row].ok_or_else(|| invalid("missing activity type"))? as u64)?,
+                event_type: event_type(event_types[row].ok_or_else(|| invalid("missing event type"))? as u64)?,
+                remote_worker: remote_worker[row],
+                operator_id: operator_id[row],
+                channel_id: channel_id[row],
+                correlator_id: correlator_id[row],
+                length,
+                origin,
+                tagged,
+            })
+        }).collect::<Result<Vec<_>>>().map(Some)
+    }
+}
+
+/// Reads all `rows` values of column `name`, including nulls.
+fn read_column<T: Default + Clone, F>(row_group: &dyn RowGroupReader, indices: &HashMap<String, usize>, name: &str, rows: usize, mut read: F) -> Result<Vec<Option<T>>>
+where
+    F: FnMut(ColumnReader, &mut Vec<i16>, &mut Vec<T>) -> Result<()>,
+{
+    let index = *indices.get(name).ok_or_else(|| invalid(&format!("missing column {}", name)))?;
+    let mut levels = vec![0; rows];
+    let mut values = vec![T::default(); rows];
+    read(row_group.get_column_reader(index).map_err(to_io)?, &mut levels, &mut values)?;
+
+    let mut values = values.into_iter();
+    Ok(levels.iter().map(|level| if *level > 0 { values.next() } else { None }).collect())
+}
+
+/// Reads batches of `reader` until `levels` is full.
+macro_rules! read_all {
+    ($reader:expr, $levels:expr, $values:expr) => {{
+        let rows = $levels.len();
+        let (mut values_read, mut levels_read) = (0, 0);
+        while levels_read < rows {
+            let (values, levels) = $reader.read_batch(rows - levels_read, Some(&mut $levels[levels_read ..]), None, &mut $values[values_read ..]).map_err(to_io)?;
+            if levels == 0 {
+                return Err(invalid("column has fewer values than its row group"));
+            }
+            values_read += values;
+            levels_read += levels;
+        }
+        Ok(())
+    }};
+}
+
+fn read_int64(row_group: &dyn RowGroupReader, indices: &HashMap<String, usize>, name: &str, rows: usize) -> Result<Vec<Option<u64>>> {
+    let column = read_column(row_group, indices, name, rows, |reader, levels, values: &mut Vec<i64>| match reader {
+        ColumnReader::Int64ColumnReader(mut typed) => read_all!(typed, levels, values),
+        _ => Err(invalid(&format!("column {} is not INT64", name))),
+    })?;
+    Ok(column.into_iter().map(|x| x.map(|x| x as u64)).collect())
+}
+
+fn read_int32(row_group: &dyn RowGroupReader, indices: &HashMap<String, usize>, name: &str, rows: usize) -> Result<Vec<Option<u32>>> {
+    let column = read_column(row_group, indices, name, rows, |reader, levels, values: &mut Vec<i32>| match reader {
+        ColumnReader::Int32ColumnReader(mut typed) => read_all!(typed, levels, values),
+        _ => Err(invalid(&format!("column {} is not INT32", name))),
+    })?;
+    Ok(column.into_iter().map(|x| x.map(|x| x as u32)).collect())
+}
+
+fn read_bytes(row_group: &dyn RowGroupReader, indices: &HashMap<String, usize>, name: &str, rows: usize) -> Result<Vec<Option<Vec<u8>>>> {
+    let column = read_column(row_group, indices, name, rows, |reader, levels, values: &mut Vec<ByteArray>| match reader {
+        ColumnReader::ByteArrayColumnReader(mut typed) => read_all!(typed, levels, values),
+        _ => Err(invalid(&format!("column {} is not BYTE_ARRAY", name))),
+    })?;
+    Ok(column.into_iter().map(|x| x.map(|x| x.data().to_vec())).collect())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return Err(invalid("malformed hex metadata"));
+    }
+    (0 .. hex.len()).step_by(2)
+        .map(|i| hex.get(i .. i + 2)
+             .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+             .ok_or_else(|| invalid("malformed hex metadata")))
+        .collect()
+}
+
+fn to_io(error: ParquetError) -> Error {
+    Error::new(ErrorKind::InvalidData, error.to_string())
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, reason.to_string())
+}
+
+#[test]
+fn roundtrip_parquet() {
+    use crate::{ActivityType, EventType};
+    use crate::encoding::Encoding;
+    use crate::tagged::{TaggedField, Value, MESSAGE_BYTES};
+    use crate::trace::Compression;
+
+    let records: Vec<_> = (0 .. 3).map(|i| LogRecord {
+        timestamp: Duration::from_nanos(10 + i),
+        activity_type: ActivityType::DataMessage,
+        event_type: EventType::Sent,
+        remote_worker: Some(1),
+        operator_id: None,
+        channel_id: Some(4),
+        correlator_id: Some(i),
+        length: if i == 0 { None } else { Some(20) },
+        origin: Some(Origin { host: 0, process_id: 4711, thread_id: 2 }),
+        tagged: vec![TaggedField { tag: MESSAGE_BYTES, value: Value::U64(160) }],
+        ..crate::test_util::record(i, 1)
+    }).collect();
+
+    let header = TraceHeader::new(2, "test".to_string(), Encoding::Compact, Compression::None);
+    let mut names = NameTable::default();
+    names.intern("host-1");
+
+    let path = std::env::temp_dir().join(format!("st2-parquet-{}.parquet", std::process::id()));
+    let count = write_trace(&header, &names, vec![Ok(records.clone())], File::create(&path).unwrap()).unwrap();
+    assert_eq!(count, 3);
+
+    let mut reader = ParquetReader::new(File::open(&path).unwrap()).unwrap();
+    assert_eq!(reader.header(), &header);
+    assert_eq!(reader.names().resolve(0), Some("host-1"));
+    assert_eq!(reader.next_batch().unwrap(), Some(records));
+    assert_eq!(reader.next_batch().unwrap(), None);
+
+    std::fs::remove_file(&path).unwrap();
+}
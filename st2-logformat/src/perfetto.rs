@@ -0,0 +1,231 @@
+//! Writer for Perfetto traces (cf. https://perfetto.dev): a protobuf `Trace` of
+//! `TracePacket`s with track descriptors and `TrackEvent`s, as defined in
+//! Perfetto's `protos/perfetto/trace/trace_packet.proto`. Only the few messages
+//! needed to show slices, instants, flows, and counters on custom tracks are
+//! encoded, so no protobuf tooling is required.
+
+use std::io::{Result, Write};
+
+use crate::varint::write_varint;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LENGTH_DELIMITED: u64 = 2;
+
+/// All packets are written on a single sequence
+const SEQUENCE_ID: u64 = 1;
+
+/// `TrackEvent.Type`
+const TYPE_SLICE_BEGIN: u64 = 1;
+const TYPE_SLICE_END: u64 = 2;
+const TYPE_INSTANT: u64 = 3;
+const TYPE_COUNTER: u64 = 4;
+
+/// Unit of a counter track (`CounterDescriptor.Unit`)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CounterUnit {
+    /// Nanoseconds
+    TimeNs = 1,
+    /// A plain count
+    Count = 2,
+    /// Bytes
+    SizeBytes = 3,
+}
+
+/// Name, category, annotations, and flows of a slice or instant event
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Event<'a> {
+    /// The event's name
+    pub name: &'a str,
+    /// The event's category
+    pub category: &'a str,
+    /// Debug annotations shown with the event
+    pub args: Vec<(&'a str, u64)>,
+    /// Flows starting or continuing at the event
+    pub flows: Vec<u64>,
+    /// Flows ending at the event
+    pub terminating_flows: Vec<u64>,
+}
+
+/// Writes a Perfetto trace to `W`. Tracks have to be declared before events
+/// are added to them; track ids (`uuid`s) are chosen by the caller.
+pub struct PerfettoWriter<W: Write> {
+    out: W,
+    packet: Vec<u8>,
+    first: bool,
+}
+
+impl<W: Write> PerfettoWriter<W> {
+    /// A writer of a trace to `out`
+    pub fn new(out: W) -> Self {
+        PerfettoWriter { out, packet: Vec::new(), first: true }
+    }
+
+    /// Declares a process track, e.g. of the analyzed computation.
+    pub fn process_track(&mut self, uuid: u64, pid: u64, name: &str) -> Result<()> {
+        let mut process = Vec::new();
+        varint_field(&mut process, 1, pid);
+        bytes_field(&mut process, 6, name.as_bytes());
+
+        let mut track = Vec::new();
+        varint_field(&mut track, 1, uuid);
+        bytes_field(&mut track, 3, &process);
+        self.packet(None, 60, &track)
+    }
+
+    /// Declares a thread track `tid` of the process `pid`, e.g. of a worker.
+    pub fn thread_track(&mut self, uuid: u64, pid: u64, tid: u64, name: &str) -> Result<()> {
+        let mut thread = Vec::new();
+        varint_field(&mut thread, 1, pid);
+        varint_field(&mut thread, 2, tid);
+        bytes_field(&mut thread, 5, name.as_bytes());
+
+        let mut track = Vec::new();
+        varint_field(&mut track, 1, uuid);
+        bytes_field(&mut track, 4, &thread);
+        self.packet(None, 60, &track)
+    }
+
+    /// Declares a track nested in the track `parent`, e.g. of an operator on a worker.
+    pub fn track(&mut self, uuid: u64, parent: u64, name: &str) -> Result<()> {
+        let mut track = Vec::new();
+        varint_field(&mut track, 1, uuid);
+        bytes_field(&mut track, 2, name.as_bytes());
+        varint_field(&mut track, 5, parent);
+        self.packet(None, 60, &track)
+    }
+
+    /// Declares a counter track nested in the track `parent`.
+    pub fn counter_track(&mut self, uuid: u64, parent: u64, name: &str, unit: CounterUnit) -> Result<()> {
+        let mut counter = Vec::new();
+        varint_field(&mut counter, 3, unit as u64);
+
+        let mut track = Vec::new();
+        varint_field(&mut track, 1, uuid);
+        bytes_field(&mut track, 2, name.as_bytes());
+        varint_field(&mut track, 5, parent);
+        bytes_field(&mut track, 8, &counter);
+        self.packet(None, 60, &track)
+    }
+
+    /// Adds a slice from `start` to `end` (in ns) to `track`.
+    pub fn slice(&mut self, track: u64, start: u64, end: u64, event: &Event) -> Result<()> {
+        let begin = track_event(TYPE_SLICE_BEGIN, track, Some(event));
+        self.packet(Some(start), 11, &begin)?;
+        let end_event = track_event(TYPE_SLICE_END, track, None);
+        self.packet(Some(end), 11, &end_event)
+    }
+
+    /// Adds an instant event at `timestamp` (in ns) to `track`.
+    pub fn instant(&mut self, track: u64, timestamp: u64, event: &Event) -> Result<()> {
+        let instant = track_event(TYPE_INSTANT, track, Some(event));
+        self.packet(Some(timestamp), 11, &instant)
+    }
+
+    /// Sets the counter `track` to `value` at `timestamp` (in ns).
+    pub fn counter(&mut self, track: u64, timestamp: u64, value: i64) -> Result<()> {
+        let mut counter = track_event(TYPE_COUNTER, track, None);
+        varint_field(&mut counter, 30, value as u64);
+        self.packet(Some(timestamp), 11, &counter)
+    }
+
+    /// Flushes the trace and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    /// Writes a `TracePacket` with the message `payload` in `field`.
+    fn packet(&mut self, timestamp: Option<u64>, field: u64, payload: &[u8]) -> Result<()> {
+        self.packet.clear();
+        if let Some(timestamp) = timestamp {
+            varint_field(&mut self.packet, 8, timestamp);
+        }
+        varint_field(&mut self.packet, 10, SEQUENCE_ID);
+        if self.first {
+            // SEQ_INCREMENTAL_STATE_CLEARED
+            varint_field(&mut self.packet, 13, 1);
+            self.first = false;
+        }
+        bytes_field(&mut self.packet, field, payload);
+
+        let mut header = Vec::new();
+        write_varint(&mut header, 1 << 3 | WIRE_LENGTH_DELIMITED);
+        write_varint(&mut header, self.packet.len() as u64);
+        self.out.write_all(&header)?;
+        self.out.write_all(&self.packet)
+    }
+}
+
+/// A `TrackEvent` of `kind` on `track`
+fn track_event(kind: u64, track: u64, event: Option<&Event>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    varint_field(&mut buf, 9, kind);
+    varint_field(&mut buf, 11, track);
+    if let Some(event) = event {
+        bytes_field(&mut buf, 23, event.name.as_bytes());
+        if !event.category.is_empty() {
+            bytes_field(&mut buf, 22, event.category.as_bytes());
+        }
+        for (name, value) in event.args.iter() {
+            let mut annotation = Vec::new();
+            varint_field(&mut annotation, 3, *value);
+            bytes_field(&mut annotation, 10, name.as_bytes());
+            bytes_field(&mut buf, 4, &annotation);
+        }
+        for flow in event.flows.iter() {
+            fixed64_field(&mut buf, 47, *flow);
+        }
+        for flow in event.terminating_flows.iter() {
+            fixed64_field(&mut buf, 48, *flow);
+        }
+    }
+    buf
+}
+
+fn varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    write_varint(buf, field << 3 | WIRE_VARINT);
+    write_varint(buf, value);
+}
+
+fn fixed64_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    write_varint(buf, field << 3 | WIRE_FIXED64);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_varint(buf, field << 3 | WIRE_LENGTH_DELIMITED);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+#[test]
+fn packets() {
+    use crate::varint::read_varint;
+
+    let mut writer = PerfettoWriter::new(Vec::new());
+    writer.thread_track(7, 1, 0, "worker 0").unwrap();
+    writer.slice(7, 100, 250, &Event { name: "Processing", flows: vec![3], ..Default::default() }).unwrap();
+    let trace = writer.finish().unwrap();
+
+    // three packets: the track descriptor, and the slice's begin and end
+    let mut bytes = &trace[..];
+    let mut packets = Vec::new();
+    while !bytes.is_empty() {
+        assert_eq!(read_varint(&mut bytes).unwrap(), 1 << 3 | WIRE_LENGTH_DELIMITED);
+        let length = read_varint(&mut bytes).unwrap() as usize;
+        packets.push(&bytes[.. length]);
+        bytes = &bytes[length ..];
+    }
+    assert_eq!(packets.len(), 3);
+
+    // the end of the slice: timestamp, sequence, and a `TrackEvent` of type `TYPE_SLICE_END` on track 7
+    let mut end = Vec::new();
+    varint_field(&mut end, 8, 250);
+    varint_field(&mut end, 10, SEQUENCE_ID);
+    bytes_field(&mut end, 11, &[9 << 3, TYPE_SLICE_END as u8, 11 << 3, 7]);
+    assert_eq!(packets[2], &end[..]);
+
+    // the first packet clears the sequence's state
+    assert!(packets[0].windows(2).any(|field| field == &[13 << 3, 1][..]));
+}
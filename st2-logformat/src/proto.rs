@@ -0,0 +1,298 @@
+//! Protobuf wire encoding of `LogRecord`s, as defined in `proto/logrecord.proto`.
+//! This lets non-Rust producers emit ST2-compatible traces with their
+//! language's protobuf tooling.
+
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+use std::convert::TryInto;
+
+use crate::{ActivityType, EventType, LogRecord, Origin};
+use crate::tagged::{TaggedField, Value};
+use crate::varint::{read_varint, write_varint};
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LENGTH_DELIMITED: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+/// Appends the `LogRecord` message for `record` to `buf`.
+pub fn encode_record(record: &LogRecord, buf: &mut Vec<u8>) {
+    // proto3 omits implicit fields with default values
+    let implicit = [
+        (1, record.seq_no),
+        (2, record.epoch),
+        (3, record.timestamp.as_nanos() as u64),
+        (4, record.local_worker),
+        (5, record.activity_type as u64),
+        (6, record.event_type as u64),
+    ];
+    for (field, value) in implicit.iter().filter(|(_, value)| *value != 0) {
+        write_varint(buf, field << 3 | WIRE_VARINT);
+        write_varint(buf, *value);
+    }
+
+    let optional = [
+        (7, record.remote_worker),
+        (8, record.operator_id),
+        (9, record.channel_id),
+        (10, record.correlator_id),
+        (11, record.length.map(|l| l as u64)),
+    ];
+    for (field, value) in optional.iter() {
+        if let Some(value) = value {
+            write_varint(buf, field << 3 | WIRE_VARINT);
+            write_varint(buf, *value);
+        }
+    }
+
+    if let Some(origin) = record.origin {
+        let mut origin_buf = Vec::new();
+        for (field, value) in [(1, origin.host as u64), (2, origin.process_id as u64), (3, origin.thread_id)].iter() {
+            write_varint(&mut origin_buf, field << 3 | WIRE_VARINT);
+            write_varint(&mut origin_buf, *value);
+        }
+        write_varint(buf, 12 << 3 | WIRE_LENGTH_DELIMITED);
+        write_varint(buf, origin_buf.len() as u64);
+        buf.extend_from_slice(&origin_buf);
+    }
+
+    let mut field_buf = Vec::new();
+    for field in record.tagged.iter() {
+        field_buf.clear();
+        write_varint(&mut field_buf, 1 << 3 | WIRE_VARINT);
+        write_varint(&mut field_buf, field.tag as u64);
+        match &field.value {
+            Value::U64(value) => {
+                write_varint(&mut field_buf, 2 << 3 | WIRE_VARINT);
+                write_varint(&mut field_buf, *value);
+            }
+            Value::Bytes(bytes) => {
+                write_varint(&mut field_buf, 3 << 3 | WIRE_LENGTH_DELIMITED);
+                write_varint(&mut field_buf, bytes.len() as u64);
+                field_buf.extend_from_slice(bytes);
+            }
+        }
+        write_varint(buf, 13 << 3 | WIRE_LENGTH_DELIMITED);
+        write_varint(buf, field_buf.len() as u64);
+        buf.extend_from_slice(&field_buf);
+    }
+}
+
+/// Decodes an `Origin` message.
+fn decode_origin(mut bytes: &[u8]) -> Result<Origin> {
+    let mut origin = Origin { host: 0, process_id: 0, thread_id: 0 };
+    while !bytes.is_empty() {
+        let key = read_varint(&mut bytes)?;
+        match (key >> 3, key & 0x7) {
+            (1, WIRE_VARINT) => origin.host = read_varint(&mut bytes)?.try_into()
+                .map_err(|_| invalid("host exceeds u32"))?,
+            (2, WIRE_VARINT) => origin.process_id = read_varint(&mut bytes)?.try_into()
+                .map_err(|_| invalid("process_id exceeds u32"))?,
+            (3, WIRE_VARINT) => origin.thread_id = read_varint(&mut bytes)?,
+            (_, wire_type) => skip_field(wire_type, &mut bytes)?,
+        }
+    }
+    Ok(origin)
+}
+
+/// Decodes a `TaggedField` message.
+fn decode_tagged(mut bytes: &[u8]) -> Result<TaggedField> {
+    let mut tag = 0;
+    let mut value = None;
+    while !bytes.is_empty() {
+        let key = read_varint(&mut bytes)?;
+        match (key >> 3, key & 0x7) {
+            (1, WIRE_VARINT) => tag = read_varint(&mut bytes)?.try_into()
+                .map_err(|_| invalid("tag exceeds u32"))?,
+            (2, WIRE_VARINT) => value = Some(Value::U64(read_varint(&mut bytes)?)),
+            (3, WIRE_LENGTH_DELIMITED) => {
+                let length = read_varint(&mut bytes)?;
+                value = Some(Value::Bytes(take(length, &mut bytes)?.to_vec()));
+            }
+            (_, wire_type) => skip_field(wire_type, &mut bytes)?,
+        }
+    }
+    let value = value.ok_or_else(|| invalid("TaggedField without value"))?;
+    Ok(TaggedField { tag, value })
+}
+
+/// Decodes a `LogRecord` message.
+pub fn decode_record(mut bytes: &[u8]) -> Result<LogRecord> {
+    let mut record = LogRecord {
+        seq_no: 0,
+        epoch: 0,
+        timestamp: Default::default(),
+        local_worker: 0,
+        activity_type: ActivityType::Scheduling,
+        event_type: EventType::Start,
+        remote_worker: None,
+        operator_id: None,
+        channel_id: None,
+        correlator_id: None,
+        length: None,
+        origin: None,
+        tagged: Vec::new(),
+    };
+    let mut has_event_type = false;
+
+    while !bytes.is_empty() {
+        let key = read_varint(&mut bytes)?;
+        match (key >> 3, key & 0x7) {
+            (1, WIRE_VARINT) => record.seq_no = read_varint(&mut bytes)?,
+            (2, WIRE_VARINT) => record.epoch = read_varint(&mut bytes)?,
+            (3, WIRE_VARINT) => record.timestamp = Duration::from_nanos(read_varint(&mut bytes)?),
+            (4, WIRE_VARINT) => record.local_worker = read_varint(&mut bytes)?,
+            (5, WIRE_VARINT) => record.activity_type = activity_type(read_varint(&mut bytes)?)?,
+            (6, WIRE_VARINT) => {
+                record.event_type = event_type(read_varint(&mut bytes)?)?;
+                has_event_type = true;
+            }
+            (7, WIRE_VARINT) => record.remote_worker = Some(read_varint(&mut bytes)?),
+            (8, WIRE_VARINT) => record.operator_id = Some(read_varint(&mut bytes)?),
+            (9, WIRE_VARINT) => record.channel_id = Some(read_varint(&mut bytes)?),
+            (10, WIRE_VARINT) => record.correlator_id = Some(read_varint(&mut bytes)?),
+            (11, WIRE_VARINT) => record.length = Some(read_varint(&mut bytes)?.try_into()
+                .map_err(|_| invalid("length exceeds usize"))?),
+            (12, WIRE_LENGTH_DELIMITED) => {
+                let origin = take(read_varint(&mut bytes)?, &mut bytes)?;
+                record.origin = Some(decode_origin(origin)?);
+            }
+            (13, WIRE_LENGTH_DELIMITED) => {
+                let field = take(read_varint(&mut bytes)?, &mut bytes)?;
+                let field = decode_tagged(field)?;
+                crate::tagged::set(&mut record.tagged, field.tag, field.value);
+            }
+            (_, wire_type) => skip_field(wire_type, &mut bytes)?,
+        }
+    }
+
+    if has_event_type {
+        Ok(record)
+    } else {
+        Err(invalid("LogRecord without event_type"))
+    }
+}
+
+/// Appends the `LogRecordBatch` message for `batch` to `buf`.
+pub fn encode_batch(batch: &[LogRecord], buf: &mut Vec<u8>) {
+    let mut record_buf = Vec::new();
+    for record in batch.iter() {
+        record_buf.clear();
+        encode_record(record, &mut record_buf);
+        write_varint(buf, 1 << 3 | WIRE_LENGTH_DELIMITED);
+        write_varint(buf, record_buf.len() as u64);
+        buf.extend_from_slice(&record_buf);
+    }
+}
+
+/// Decodes a `LogRecordBatch` message.
+pub fn decode_batch(mut bytes: &[u8]) -> Result<Vec<LogRecord>> {
+    let mut batch = Vec::new();
+    while !bytes.is_empty() {
+        let key = read_varint(&mut bytes)?;
+        match (key >> 3, key & 0x7) {
+            (1, WIRE_LENGTH_DELIMITED) => {
+                let record = take(read_varint(&mut bytes)?, &mut bytes)?;
+                batch.push(decode_record(record)?);
+            }
+            (_, wire_type) => skip_field(wire_type, &mut bytes)?,
+        }
+    }
+    Ok(batch)
+}
+
+/// Skips an unknown field, so that newer producers stay readable.
+fn skip_field(wire_type: u64, bytes: &mut &[u8]) -> Result<()> {
+    match wire_type {
+        WIRE_VARINT => read_varint(bytes).map(|_| ()),
+        WIRE_FIXED64 => take(8, bytes).map(|_| ()),
+        WIRE_LENGTH_DELIMITED => {
+            let length = read_varint(bytes)?;
+            take(length, bytes).map(|_| ())
+        }
+        WIRE_FIXED32 => take(4, bytes).map(|_| ()),
+        _ => Err(invalid(&format!("unsupported wire type {}", wire_type))),
+    }
+}
+
+/// Splits `length` bytes off the front of `bytes`.
+fn take<'a>(length: u64, bytes: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let length = length as usize;
+    if length > bytes.len() {
+        return Err(invalid("truncated protobuf message"));
+    }
+    let (taken, rest) = bytes.split_at(length);
+    *bytes = rest;
+    Ok(taken)
+}
+
+/// Maps an `ActivityType` discriminant back to its variant.
+pub(crate) fn activity_type(value: u64) -> Result<ActivityType> {
+    match value {
+        0 => Ok(ActivityType::Scheduling),
+        1 => Ok(ActivityType::Spinning),
+        2 => Ok(ActivityType::Processing),
+        3 => Ok(ActivityType::Serialization),
+        4 => Ok(ActivityType::Deserialization),
+        5 => Ok(ActivityType::ControlMessage),
+        6 => Ok(ActivityType::DataMessage),
+        8 => Ok(ActivityType::Waiting),
+        9 => Ok(ActivityType::Busy),
+        _ => Err(invalid(&format!("unknown activity type {}", value))),
+    }
+}
+
+/// Maps an `EventType` discriminant back to its variant.
+pub(crate) fn event_type(value: u64) -> Result<EventType> {
+    match value {
+        1 => Ok(EventType::Start),
+        2 => Ok(EventType::End),
+        3 => Ok(EventType::Sent),
+        4 => Ok(EventType::Received),
+        _ => Err(invalid(&format!("unknown event type {}", value))),
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, reason.to_string())
+}
+
+#[test]
+fn roundtrip_protobuf() {
+    let batch = vec![
+        LogRecord {
+            timestamp: Duration::from_nanos(1_570_000_000_123),
+            local_worker: 3,
+            event_type: EventType::End,
+            operator_id: Some(0),
+            length: Some(42),
+            ..crate::test_util::record(300, 0)
+        },
+        LogRecord {
+            timestamp: Duration::from_nanos(1_570_000_000_456),
+            local_worker: 3,
+            activity_type: ActivityType::ControlMessage,
+            event_type: EventType::Received,
+            remote_worker: Some(0),
+            operator_id: None,
+            channel_id: Some(2),
+            correlator_id: Some(7),
+            origin: Some(Origin { host: 1, process_id: 4711, thread_id: 0 }),
+            tagged: vec![
+                TaggedField { tag: crate::tagged::MESSAGE_BYTES, value: Value::U64(0) },
+                TaggedField { tag: crate::tagged::USER_TAGS, value: Value::Bytes(b"warmup".to_vec()) },
+            ],
+            ..crate::test_util::record(301, 1)
+        },
+    ];
+
+    let mut buf = Vec::new();
+    encode_batch(&batch, &mut buf);
+    assert_eq!(decode_batch(&buf).unwrap(), batch);
+
+    // an unknown trailing field (e.g. from a newer producer) is skipped
+    let mut record = Vec::new();
+    encode_record(&batch[1], &mut record);
+    record.extend_from_slice(&[15 << 3 | 2, 2, 0xff, 0xff]);
+    assert_eq!(decode_record(&record).unwrap(), batch[1]);
+}
@@ -0,0 +1,172 @@
+//! Rotation and retention of trace files for long captures.
+//!
+//! A `RotatingWriter` splits a trace into a sequence of trace files
+//! `<prefix>.<sequence number>.st2`, starting a new file once the current one exceeds
+//! the size or age given by its `Rotation`. Every file is a self-contained trace with
+//! its own header, index, and names frames: names interned before a rotation keep their
+//! ids in later files. Only the newest `Rotation::retain` files are kept.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::LogRecord;
+use crate::names::NameId;
+use crate::trace::{TraceHeader, TraceWriter};
+
+/// When to rotate trace files, and how many of them to keep
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Rotation {
+    /// Rotate once a file holds at least this many (uncompressed) bytes
+    pub max_bytes: Option<u64>,
+    /// Rotate once a file has been written to for this long
+    pub max_age: Option<Duration>,
+    /// Delete the oldest files once there are more than this many
+    pub retain: Option<usize>,
+}
+
+/// Writes `LogRecord` batches to a sequence of rotated trace files.
+pub struct RotatingWriter {
+    header: TraceHeader,
+    prefix: PathBuf,
+    rotation: Rotation,
+    writer: Option<TraceWriter>,
+    /// when the current file was opened
+    opened: Instant,
+    /// whether the current file holds no batches yet
+    empty: bool,
+    sequence: u64,
+    /// retained files, oldest first
+    files: VecDeque<PathBuf>,
+}
+
+impl RotatingWriter {
+    /// Creates the first trace file of `prefix`, with `header`.
+    pub fn new<P: AsRef<Path>>(header: TraceHeader, prefix: P, rotation: Rotation) -> Result<Self> {
+        if rotation.retain == Some(0) {
+            return Err(Error::new(ErrorKind::InvalidInput, "can't retain zero trace files"));
+        }
+
+        let mut writer = RotatingWriter {
+            header,
+            prefix: prefix.as_ref().to_path_buf(),
+            rotation,
+            writer: None,
+            opened: Instant::now(),
+            empty: true,
+            sequence: 0,
+            files: VecDeque::new(),
+        };
+        writer.open()?;
+        Ok(writer)
+    }
+
+    /// Returns the id of `name`, interning it if necessary (cf. `TraceWriter::intern`).
+    pub fn intern(&mut self, name: &str) -> NameId {
+        self.writer.as_mut().expect("no open trace file").intern(name)
+    }
+
+    /// Writes `batch` as a single frame, rotating beforehand if necessary.
+    /// Batches are never split across files.
+    pub fn write_batch(&mut self, batch: &Vec<LogRecord>) -> Result<()> {
+        if !self.empty && self.is_due() {
+            self.rotate()?;
+        }
+        self.empty = false;
+        self.writer.as_mut().expect("no open trace file").write_batch(batch)
+    }
+
+    /// The retained trace files, oldest first
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|path| path.as_path())
+    }
+
+    /// Finishes the current trace file.
+    pub fn finish(mut self) -> Result<()> {
+        self.writer.take().expect("no open trace file").finish()
+    }
+
+    /// Whether the current file exceeds its size or age
+    fn is_due(&self) -> bool {
+        let writer = self.writer.as_ref().expect("no open trace file");
+        self.rotation.max_bytes.map_or(false, |max| writer.bytes_written() >= max)
+            || self.rotation.max_age.map_or(false, |max| self.opened.elapsed() >= max)
+    }
+
+    /// Finishes the current file and continues in a new one.
+    fn rotate(&mut self) -> Result<()> {
+        let previous = self.writer.take().expect("no open trace file");
+        let names = previous.names().clone();
+        previous.finish()?;
+
+        self.open()?;
+        let writer = self.writer.as_mut().expect("no open trace file");
+        for id in 0 .. names.len() as NameId {
+            writer.intern(names.resolve(id).expect("names are consecutive"));
+        }
+        Ok(())
+    }
+
+    /// Opens the next file and applies retention.
+    fn open(&mut self) -> Result<()> {
+        let mut path = self.prefix.clone().into_os_string();
+        path.push(format!(".{:06}.st2", self.sequence));
+        let path = PathBuf::from(path);
+
+        let mut header = self.header.clone();
+        header.start_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let file = BufWriter::new(File::create(&path)?);
+        self.writer = Some(TraceWriter::new(&header, file)?);
+        self.opened = Instant::now();
+        self.empty = true;
+        self.sequence += 1;
+        self.files.push_back(path);
+
+        if let Some(retain) = self.rotation.retain {
+            while self.files.len() > retain {
+                let oldest = self.files.pop_front().expect("more files than retained");
+                // others (e.g. `st2 record`'s size and age retention) may have deleted it already
+                match fs::remove_file(oldest) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn rotate_and_retain() {
+    use crate::encoding::Encoding;
+    use crate::trace::{Compression, TraceReader};
+
+    let batch = |epoch| vec![crate::test_util::record(epoch, epoch)];
+
+    let dir = std::env::temp_dir().join(format!("st2-rotation-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let header = TraceHeader::new(1, "test".to_string(), Encoding::Compact, Compression::None);
+    // rotate after every batch, keep three files
+    let rotation = Rotation { max_bytes: Some(1), max_age: None, retain: Some(3) };
+    let mut writer = RotatingWriter::new(header, dir.join("trace"), rotation).unwrap();
+    let operator = writer.intern("Map");
+    for epoch in 0 .. 5 {
+        writer.write_batch(&batch(epoch)).unwrap();
+    }
+    let files: Vec<PathBuf> = writer.files().map(Path::to_path_buf).collect();
+    writer.finish().unwrap();
+
+    assert_eq!(files, (2 .. 5).map(|n| dir.join(format!("trace.{:06}.st2", n))).collect::<Vec<_>>());
+    assert!(!dir.join("trace.000001.st2").exists());
+    for (epoch, file) in (2 .. 5).zip(files.iter()) {
+        let mut reader = TraceReader::new(File::open(file).unwrap()).unwrap();
+        assert_eq!(reader.next_batch().unwrap(), Some(batch(epoch)));
+        assert_eq!(reader.next_batch().unwrap(), None);
+        // names carry over to later files
+        assert_eq!(reader.names().resolve(operator), Some("Map"));
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,68 @@
+//! Tagged fields: an extensible set of optional `LogRecord` fields
+//! (since trace format version 5).
+//!
+//! Every tagged field is identified by a `Tag`. New fields get a new tag instead of
+//! changing the layout of `LogRecord`, so they can be added without breaking readers
+//! of older traces or producers that don't know about them: readers keep (and
+//! pass through) tags they don't understand, and producers simply omit tags they
+//! don't set. Tags below `USER_TAGS` are reserved for ST2, the rest are free for
+//! user-defined annotations.
+
+use serde::{Deserialize, Serialize};
+
+/// Identifies a tagged field
+pub type Tag = u32;
+
+/// Size of a message in bytes (`Value::U64`)
+pub const MESSAGE_BYTES: Tag = 1;
+/// Time spent in garbage collection during the event, in nanoseconds (`Value::U64`)
+pub const GC_NANOS: Tag = 2;
+/// Name of the record's operator (`Value::U64` of its `names::NameId`).
+/// Only needs to be set on one record per operator and worker.
+pub const OPERATOR_NAME: Tag = 3;
+/// First tag available for user-defined fields
+pub const USER_TAGS: Tag = 1 << 16;
+
+/// Value of a tagged field
+#[derive(Abomonation, PartialEq, Eq, Hash, Clone, Debug, Deserialize, Serialize)]
+pub enum Value {
+    /// An unsigned integer
+    U64(u64),
+    /// Arbitrary bytes, e.g. UTF-8 text
+    Bytes(Vec<u8>),
+}
+
+/// A tagged field of a `LogRecord`
+#[derive(Abomonation, PartialEq, Eq, Hash, Clone, Debug, Deserialize, Serialize)]
+pub struct TaggedField {
+    /// What the field denotes
+    pub tag: Tag,
+    /// The field's value
+    pub value: Value,
+}
+
+/// Returns the value of `tag` in `fields`, if present.
+pub fn get(fields: &[TaggedField], tag: Tag) -> Option<&Value> {
+    fields.iter().find(|f| f.tag == tag).map(|f| &f.value)
+}
+
+/// Sets `tag` to `value` in `fields`, replacing a previous value.
+/// Keeps `fields` ordered by tag.
+pub fn set(fields: &mut Vec<TaggedField>, tag: Tag, value: Value) {
+    match fields.binary_search_by_key(&tag, |f| f.tag) {
+        Ok(i) => fields[i].value = value,
+        Err(i) => fields.insert(i, TaggedField { tag, value }),
+    }
+}
+
+#[test]
+fn set_and_get() {
+    let mut fields = Vec::new();
+    set(&mut fields, USER_TAGS, Value::Bytes(b"warmup".to_vec()));
+    set(&mut fields, MESSAGE_BYTES, Value::U64(10));
+    set(&mut fields, MESSAGE_BYTES, Value::U64(20));
+
+    assert_eq!(fields.iter().map(|f| f.tag).collect::<Vec<_>>(), vec![MESSAGE_BYTES, USER_TAGS]);
+    assert_eq!(get(&fields, MESSAGE_BYTES), Some(&Value::U64(20)));
+    assert_eq!(get(&fields, GC_NANOS), None);
+}
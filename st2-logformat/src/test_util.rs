@@ -0,0 +1,25 @@
+//! Fixtures of the unit tests
+
+use std::time::Duration;
+
+use crate::{ActivityType, EventType, LogRecord};
+
+/// The start of operator 1 on worker 0 in `epoch`, `seq_no` ns into the trace.
+/// Tests change the fields they care about with struct update syntax.
+pub fn record(seq_no: u64, epoch: u64) -> LogRecord {
+    LogRecord {
+        seq_no,
+        epoch,
+        timestamp: Duration::from_nanos(seq_no),
+        local_worker: 0,
+        activity_type: ActivityType::Scheduling,
+        event_type: EventType::Start,
+        remote_worker: None,
+        operator_id: Some(1),
+        channel_id: None,
+        correlator_id: None,
+        length: None,
+        origin: None,
+        tagged: Vec::new(),
+    }
+}
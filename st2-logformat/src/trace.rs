@@ -0,0 +1,718 @@
+//! Self-describing trace files.
+//!
+//! A trace file starts with a `TraceHeader`, followed by the (optionally compressed)
+//! frames of `LogRecord` batches described in the `encoding` module.
+//!
+//! Header layout (integers in little endian):
+//! `[magic: "ST2TRACE"][version: u16][body length: u32][body]`, with body
+//! `[workers: u64][start time: u64 ns since UNIX epoch][encoding: u8][compression: u8]
+//! [source length: u16][source: UTF-8]`. Readers skip unknown trailing body bytes.
+//!
+//! Since version 2, uncompressed traces written by a `TraceWriter` end in a
+//! `TraceIndex` that maps epochs to the frames containing them, so readers can seek
+//! to an epoch without replaying the trace up to it (cf. `TraceReader::seek_epoch`).
+//!
+//! Since version 3, every frame is wrapped in a checksummed block (cf. `block`), so
+//! readers skip damaged frames instead of failing (cf. `TraceReader::damage`). Recovery
+//! requires uncompressed traces, as a damaged compressed stream can't be resynchronized.
+//!
+//! Traces whose producer was killed while writing usually end in an incomplete frame.
+//! Readers return the valid prefix of such traces and report where they were cut off
+//! (cf. `TraceReader::truncation`).
+//!
+//! Version 4 added `LogRecord::origin`. Abomonation and bincode batches of older
+//! traces are decoded with their previous layout (cf. `legacy`).
+//!
+//! Version 5 added `LogRecord::tagged` fields, which future optional fields are added as
+//! (cf. `tagged`), so that they don't require new record layouts.
+
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+
+use crate::{legacy, LogRecord};
+use crate::block::{self, BlockReader, Damage};
+use crate::encoding::{self, Encoding, Frame};
+use crate::names::{NameId, NameTable};
+
+/// Magic bytes every trace file starts with
+pub const MAGIC: &[u8; 8] = b"ST2TRACE";
+/// Current trace format version
+pub const FORMAT_VERSION: u16 = 5;
+/// Magic bytes every epoch index ends with
+pub const INDEX_MAGIC: &[u8; 8] = b"ST2INDEX";
+
+/// Compression codecs for the frames following the header
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum Compression {
+    /// Uncompressed
+    None,
+    /// gzip (requires the `flate2` feature)
+    Gzip,
+}
+
+impl Compression {
+    /// Wraps `reader` to decompress its contents.
+    pub fn wrap_reader<R: Read + Send + 'static>(self, reader: R) -> Result<Box<dyn Read + Send>> {
+        match self {
+            Compression::None => Ok(Box::new(reader)),
+            #[cfg(feature = "flate2")]
+            Compression::Gzip => Ok(Box::new(flate2::read::GzDecoder::new(reader))),
+            #[cfg(not(feature = "flate2"))]
+            Compression::Gzip => Err(Error::new(ErrorKind::InvalidInput, "gzip compression requires the flate2 feature")),
+        }
+    }
+
+    /// Wraps `writer` to compress everything written to it.
+    pub fn wrap_writer<W: Write + Send + 'static>(self, writer: W) -> Result<Box<dyn Write + Send>> {
+        match self {
+            Compression::None => Ok(Box::new(writer)),
+            #[cfg(feature = "flate2")]
+            Compression::Gzip => Ok(Box::new(flate2::write::GzEncoder::new(writer, flate2::Compression::default()))),
+            #[cfg(not(feature = "flate2"))]
+            Compression::Gzip => Err(Error::new(ErrorKind::InvalidInput, "gzip compression requires the flate2 feature")),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            _ => Err(format!("unsupported compression: {}", s)),
+        }
+    }
+}
+
+/// Metadata describing a trace file
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct TraceHeader {
+    /// Format version the trace was written with
+    pub version: u16,
+    /// Number of source peers whose events are contained in the trace
+    pub workers: u64,
+    /// Wall-clock start time of the capture (since the UNIX epoch)
+    pub start_time: Duration,
+    /// The traced source system, e.g. `timely 0.10`
+    pub source: String,
+    /// Encoding of the trace's batches
+    pub encoding: Encoding,
+    /// Compression of everything following the header
+    pub compression: Compression,
+}
+
+impl TraceHeader {
+    /// Creates a header for a capture starting now.
+    pub fn new(workers: u64, source: String, encoding: Encoding, compression: Compression) -> Self {
+        TraceHeader {
+            version: FORMAT_VERSION,
+            workers,
+            start_time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+            source,
+            encoding,
+            compression,
+        }
+    }
+
+    /// Writes the header to `writer`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let source = self.source.as_bytes();
+        let source_len: u16 = source.len().try_into()
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "source name too long"))?;
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&self.workers.to_le_bytes());
+        body.extend_from_slice(&(self.start_time.as_nanos() as u64).to_le_bytes());
+        body.push(encoding_id(self.encoding));
+        body.push(compression_id(self.compression));
+        body.extend_from_slice(&source_len.to_le_bytes());
+        body.extend_from_slice(source);
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&self.version.to_le_bytes())?;
+        writer.write_all(&(body.len() as u32).to_le_bytes())?;
+        writer.write_all(&body)
+    }
+
+    /// Reads and validates a header from `reader`.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)
+            .map_err(|_| invalid("not an ST2 trace: missing header"))?;
+        if &magic != MAGIC {
+            return Err(invalid("not an ST2 trace: wrong magic bytes"));
+        }
+
+        let mut version = [0u8; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(invalid(&format!("unsupported trace format version {} (supported: <= {})", version, FORMAT_VERSION)));
+        }
+
+        let mut body_len = [0u8; 4];
+        reader.read_exact(&mut body_len)?;
+        let body = encoding::read_payload(reader, u32::from_le_bytes(body_len) as u64)?;
+
+        if body.len() < 20 {
+            return Err(invalid("truncated trace header"));
+        }
+        let workers = u64::from_le_bytes(body[0 .. 8].try_into().unwrap());
+        let start_time = Duration::from_nanos(u64::from_le_bytes(body[8 .. 16].try_into().unwrap()));
+        let encoding = encoding_from_id(body[16])?;
+        let compression = compression_from_id(body[17])?;
+        let source_len = u16::from_le_bytes(body[18 .. 20].try_into().unwrap()) as usize;
+        let source = body.get(20 .. 20 + source_len)
+            .ok_or_else(|| invalid("truncated trace header"))?;
+        let source = String::from_utf8(source.to_vec())
+            .map_err(|_| invalid("malformed source name in trace header"))?;
+
+        Ok(TraceHeader { version, workers, start_time, source, encoding, compression })
+    }
+}
+
+/// Maps epochs to the byte offset of the first frame containing records of that epoch.
+///
+/// Layout (integers in little endian), following the last frame:
+/// `[END_OF_FRAMES: u64][entries: u64]([epoch: u64][offset: u64])*[end: u64][magic: "ST2INDEX"]`,
+/// where `end` is the offset of the `END_OF_FRAMES` marker.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct TraceIndex {
+    epochs: BTreeMap<u64, u64>,
+    end: u64,
+}
+
+impl TraceIndex {
+    /// Records that the frame at `offset` contains records of `epoch`.
+    /// Only the first frame of every epoch is kept.
+    pub fn insert(&mut self, epoch: u64, offset: u64) {
+        self.epochs.entry(epoch).or_insert(offset);
+    }
+
+    /// The offset of the first frame that might contain records of `epoch` or later epochs.
+    /// Frames from this offset on can still contain records of earlier epochs.
+    pub fn offset_of(&self, epoch: u64) -> u64 {
+        self.epochs.range(epoch ..).map(|(_, offset)| *offset).min().unwrap_or(self.end)
+    }
+
+    /// The indexed epochs, in order
+    pub fn epochs(&self) -> impl Iterator<Item = &u64> {
+        self.epochs.keys()
+    }
+
+    /// Ends the frames at `end` and appends the index to `writer`.
+    fn write_to<W: Write>(&mut self, end: u64, writer: &mut W) -> Result<()> {
+        self.end = end;
+        writer.write_all(&encoding::END_OF_FRAMES.to_le_bytes())?;
+        writer.write_all(&(self.epochs.len() as u64).to_le_bytes())?;
+        for (epoch, offset) in self.epochs.iter() {
+            writer.write_all(&epoch.to_le_bytes())?;
+            writer.write_all(&offset.to_le_bytes())?;
+        }
+        writer.write_all(&self.end.to_le_bytes())?;
+        writer.write_all(INDEX_MAGIC)
+    }
+
+    /// Reads the index at the end of `reader`.
+    /// Returns `None` if the trace has no index.
+    fn read_from<R: Read + Seek>(reader: &mut R) -> Result<Option<Self>> {
+        let mut trailer = [0u8; 16];
+        if reader.seek(SeekFrom::End(-16)).is_err() {
+            return Ok(None);
+        }
+        reader.read_exact(&mut trailer)?;
+        if &trailer[8 ..] != INDEX_MAGIC {
+            return Ok(None);
+        }
+        let end = u64::from_le_bytes(trailer[.. 8].try_into().unwrap());
+
+        reader.seek(SeekFrom::Start(end))?;
+        let mut marker = [0u8; 8];
+        reader.read_exact(&mut marker)?;
+        if u64::from_le_bytes(marker) != encoding::END_OF_FRAMES {
+            return Err(invalid("malformed trace index"));
+        }
+
+        let entries = read_u64(reader)?;
+        let mut epochs = BTreeMap::new();
+        for _ in 0 .. entries {
+            let epoch = read_u64(reader)?;
+            let offset = read_u64(reader)?;
+            if offset > end {
+                return Err(invalid("trace index points past the end of the trace"));
+            }
+            epochs.insert(epoch, offset);
+        }
+
+        Ok(Some(TraceIndex { epochs, end }))
+    }
+}
+
+/// Writes `LogRecord` batches to a trace file, preceded by its header.
+/// Uncompressed traces are indexed by epoch (cf. `TraceIndex`).
+/// Names referenced by records are interned per file (cf. `NameTable`).
+pub struct TraceWriter {
+    encoding: Encoding,
+    writer: Box<dyn Write + Send>,
+    /// whether frames are wrapped in checksummed blocks
+    blocks: bool,
+    names: NameTable,
+    /// `None` for compressed traces
+    index: Option<TraceIndex>,
+    /// uncompressed bytes written so far
+    offset: u64,
+}
+
+impl TraceWriter {
+    /// Writes `header` to `writer` and prepares writing batches.
+    pub fn new<W: Write + Send + 'static>(header: &TraceHeader, mut writer: W) -> Result<Self> {
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes)?;
+        writer.write_all(&bytes)?;
+
+        let index = if header.compression == Compression::None {
+            Some(TraceIndex::default())
+        } else {
+            None
+        };
+
+        Ok(TraceWriter {
+            encoding: header.encoding,
+            writer: header.compression.wrap_writer(writer)?,
+            blocks: header.version >= 3,
+            names: NameTable::default(),
+            index,
+            offset: bytes.len() as u64,
+        })
+    }
+
+    /// Returns the id of `name` in this trace, interning it if necessary.
+    /// The name is written to the trace before the next batch.
+    pub fn intern(&mut self, name: &str) -> NameId {
+        self.names.intern(name)
+    }
+
+    /// Names interned in this trace so far
+    pub fn names(&self) -> &NameTable {
+        &self.names
+    }
+
+    /// Number of (uncompressed) bytes written so far, including the header
+    pub fn bytes_written(&self) -> u64 {
+        self.offset
+    }
+
+    /// Writes `batch` as a single frame.
+    pub fn write_batch(&mut self, batch: &Vec<LogRecord>) -> Result<()> {
+        self.write_names()?;
+        if let Some(index) = &mut self.index {
+            for record in batch.iter() {
+                index.insert(record.epoch, self.offset);
+            }
+        }
+        let mut frame = Vec::new();
+        encoding::write_batch(self.encoding, batch, &mut frame)?;
+        self.write_frame(&frame)
+    }
+
+    /// Appends the epoch index (if any) and flushes the trace.
+    pub fn finish(mut self) -> Result<()> {
+        self.write_names()?;
+        if let Some(index) = &mut self.index {
+            index.write_to(self.offset, &mut self.writer)?;
+        }
+        self.writer.flush()
+    }
+
+    /// Writes names interned since the last names frame.
+    fn write_names(&mut self) -> Result<()> {
+        if let Some(payload) = self.names.encode_new() {
+            let mut frame = Vec::new();
+            encoding::write_names(&payload, &mut frame)?;
+            self.write_frame(&frame)?;
+        }
+        Ok(())
+    }
+
+    /// Writes an encoded frame, wrapped in a block if necessary.
+    fn write_frame(&mut self, frame: &[u8]) -> Result<()> {
+        if self.blocks {
+            self.offset += block::write_block(frame, &mut self.writer)?;
+        } else {
+            self.writer.write_all(frame)?;
+            self.offset += frame.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Where a trace ended prematurely, e.g. because its producer was killed
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Truncation {
+    /// Length of the trace's valid prefix in bytes, including the header
+    /// (of the decompressed trace, for compressed traces)
+    pub valid_bytes: u64,
+    /// Latest epoch of the records in the valid prefix
+    pub last_epoch: Option<u64>,
+}
+
+impl fmt::Display for Truncation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "truncated after {} bytes", self.valid_bytes)?;
+        match self.last_epoch {
+            Some(epoch) => write!(f, " / epoch {}", epoch),
+            None => write!(f, " / before the first epoch"),
+        }
+    }
+}
+
+/// A reader that counts the bytes read through it
+struct Counting<R> {
+    reader: R,
+    count: u64,
+}
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+/// Reads `LogRecord` batches from a trace file, validating its header.
+pub struct TraceReader {
+    header: TraceHeader,
+    reader: Counting<Box<dyn Read + Send>>,
+    names: NameTable,
+    damage: Arc<Mutex<Damage>>,
+    /// offset of the first frame read
+    start: u64,
+    /// frames read completely so far
+    frames: u64,
+    /// bytes of the frames read completely so far
+    valid: u64,
+    last_epoch: Option<u64>,
+    truncated: bool,
+}
+
+impl TraceReader {
+    /// Reads the header of the trace in `reader` and prepares reading its batches.
+    pub fn new<R: Read + Send + 'static>(reader: R) -> Result<Self> {
+        let mut reader = Counting { reader, count: 0 };
+        let header = TraceHeader::read_from(&mut reader)?;
+        let start = reader.count;
+        let reader = header.compression.wrap_reader(reader.reader)?;
+        Ok(Self::with_reader(header, reader, NameTable::default(), start))
+    }
+
+    /// Reads the indexed trace in `reader`, starting at the first frame that
+    /// might contain records of `epoch` or later (cf. `TraceIndex::offset_of`).
+    /// Records of earlier epochs have to be skipped by the caller.
+    pub fn seek_epoch<R: Read + Seek + Send + 'static>(mut reader: R, epoch: u64) -> Result<Self> {
+        let header = TraceHeader::read_from(&mut reader)?;
+        if header.compression != Compression::None {
+            return Err(Error::new(ErrorKind::InvalidInput, "compressed traces can't be seeked"));
+        }
+        let start = reader.seek(SeekFrom::Current(0))?;
+        let index = TraceIndex::read_from(&mut reader)?
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "trace has no epoch index"))?;
+
+        // collect the names of all skipped frames without decoding their batches
+        let target = index.offset_of(epoch);
+        let mut names = NameTable::default();
+        let mut offset = reader.seek(SeekFrom::Start(start))?;
+        while offset < target {
+            if header.version >= 3 {
+                // skip the block header, damaged blocks fail to decode later on
+                reader.seek(SeekFrom::Current(block::HEADER_SIZE as i64))?;
+            }
+            let length = read_u64(&mut reader)?;
+            if length == encoding::NAMES_FRAME {
+                let length = read_u64(&mut reader)?;
+                let payload = encoding::read_payload(&mut reader, length)?;
+                names.decode(&payload)?;
+            } else {
+                reader.seek(SeekFrom::Current(length as i64))?;
+            }
+            offset = reader.seek(SeekFrom::Current(0))?;
+        }
+        if offset != target {
+            return Err(invalid("trace index doesn't point to a frame"));
+        }
+
+        Ok(Self::with_reader(header, Box::new(reader), names, target))
+    }
+
+    fn with_reader(header: TraceHeader, reader: Box<dyn Read + Send>, names: NameTable, start: u64) -> Self {
+        let damage = Arc::new(Mutex::new(Damage::default()));
+        let reader: Box<dyn Read + Send> = if header.version >= 3 {
+            Box::new(BlockReader::new(reader, Arc::clone(&damage)))
+        } else {
+            reader
+        };
+        TraceReader {
+            header,
+            reader: Counting { reader, count: 0 },
+            names,
+            damage,
+            start,
+            frames: 0,
+            valid: 0,
+            last_epoch: None,
+            truncated: false,
+        }
+    }
+
+    /// The trace's header
+    pub fn header(&self) -> &TraceHeader {
+        &self.header
+    }
+
+    /// Damaged data skipped so far. Damaged blocks lose the frames they contain.
+    pub fn damage(&self) -> Damage {
+        *self.damage.lock().expect("couldn't lock damage")
+    }
+
+    /// Offset of the first byte after the frames read completely so far, e.g.
+    /// of a frame `next_batch` failed to decode. Offsets in compressed traces
+    /// are offsets in their uncompressed frames.
+    pub fn offset(&self) -> u64 {
+        let block_headers = if self.header.version >= 3 {
+            self.frames * block::HEADER_SIZE as u64
+        } else {
+            0
+        };
+        self.start + self.valid + block_headers + self.damage().bytes
+    }
+
+    /// Where the trace was cut off, if it ended in an incomplete frame.
+    /// Only meaningful once `next_batch` returned `None`.
+    pub fn truncation(&self) -> Option<Truncation> {
+        let damage = self.damage();
+        if !self.truncated && damage.truncated == 0 {
+            return None;
+        }
+        Some(Truncation {
+            valid_bytes: self.offset(),
+            last_epoch: self.last_epoch,
+        })
+    }
+
+    /// Names interned by the batches read so far
+    pub fn names(&self) -> &NameTable {
+        &self.names
+    }
+
+    /// Reads the next batch. Returns `None` at the end of the trace, including
+    /// the end of a truncated trace's valid prefix (cf. `truncation`).
+    pub fn next_batch(&mut self) -> Result<Option<Vec<LogRecord>>> {
+        let encoding = self.header.encoding;
+        let version = self.header.version;
+        loop {
+            let frame = if version < FORMAT_VERSION {
+                encoding::read_frame_using(&mut self.reader, |payload| legacy::decode_batch(version, encoding, payload))
+            } else {
+                encoding::read_frame(encoding, &mut self.reader)
+            };
+            let frame = match frame {
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    self.truncated = true;
+                    return Ok(None);
+                }
+                frame => frame?,
+            };
+            if frame.is_some() {
+                self.frames += 1;
+                self.valid = self.reader.count;
+            }
+            match frame {
+                Some(Frame::Batch(batch)) => {
+                    let last_epoch = batch.iter().map(|r| r.epoch).max();
+                    self.last_epoch = std::cmp::max(self.last_epoch, last_epoch);
+                    return Ok(Some(batch));
+                }
+                Some(Frame::Names(payload)) => self.names.decode(&payload)?,
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, reason.to_string())
+}
+
+fn encoding_id(encoding: Encoding) -> u8 {
+    match encoding {
+        Encoding::Abomonation => 0,
+        #[cfg(feature = "bincode")]
+        Encoding::Bincode => 1,
+        Encoding::Protobuf => 2,
+        Encoding::Compact => 3,
+    }
+}
+
+fn encoding_from_id(id: u8) -> Result<Encoding> {
+    match id {
+        0 => Ok(Encoding::Abomonation),
+        #[cfg(feature = "bincode")]
+        1 => Ok(Encoding::Bincode),
+        #[cfg(not(feature = "bincode"))]
+        1 => Err(invalid("trace is bincode-encoded, which requires the bincode feature")),
+        2 => Ok(Encoding::Protobuf),
+        3 => Ok(Encoding::Compact),
+        _ => Err(invalid(&format!("unknown trace encoding {}", id))),
+    }
+}
+
+fn compression_id(compression: Compression) -> u8 {
+    match compression {
+        Compression::None => 0,
+        Compression::Gzip => 1,
+    }
+}
+
+fn compression_from_id(id: u8) -> Result<Compression> {
+    match id {
+        0 => Ok(Compression::None),
+        1 => Ok(Compression::Gzip),
+        _ => Err(invalid(&format!("unknown trace compression {}", id))),
+    }
+}
+
+#[test]
+fn header_validation() {
+    let header = TraceHeader::new(4, "timely 0.10".to_string(), Encoding::Abomonation, Compression::None);
+    let mut bytes = Vec::new();
+    header.write_to(&mut bytes).unwrap();
+    let mut frame = Vec::new();
+    encoding::write_batch(Encoding::Abomonation, &Vec::new(), &mut frame).unwrap();
+    block::write_block(&frame, &mut bytes).unwrap();
+
+    let mut reader = TraceReader::new(std::io::Cursor::new(bytes.clone())).unwrap();
+    assert_eq!(reader.header(), &header);
+    assert_eq!(reader.next_batch().unwrap(), Some(Vec::new()));
+    assert_eq!(reader.next_batch().unwrap(), None);
+
+    // future versions and mystery files are rejected
+    bytes[8] = 42;
+    assert!(TraceReader::new(std::io::Cursor::new(bytes)).is_err());
+    assert!(TraceReader::new(&b"garbage data, not a trace"[..]).is_err());
+}
+
+#[test]
+fn seek_epoch() {
+    use std::sync::{Arc, Mutex};
+
+    /// A `Write` whose contents remain accessible after the writer is consumed
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> { self.0.lock().unwrap().write(buf) }
+        fn flush(&mut self) -> Result<()> { Ok(()) }
+    }
+
+    let record = |epoch| crate::test_util::record(epoch, epoch);
+
+    let bytes = Shared(Arc::new(Mutex::new(Vec::new())));
+    let header = TraceHeader::new(1, "test".to_string(), Encoding::Abomonation, Compression::None);
+    let mut writer = TraceWriter::new(&header, bytes.clone()).unwrap();
+    for epoch in 1 .. 5 {
+        writer.intern(&format!("operator {}", epoch));
+        writer.write_batch(&vec![record(epoch), record(epoch)]).unwrap();
+    }
+    writer.finish().unwrap();
+    let bytes = bytes.0.lock().unwrap().clone();
+
+    let mut reader = TraceReader::seek_epoch(std::io::Cursor::new(bytes.clone()), 3).unwrap();
+    assert_eq!(reader.next_batch().unwrap(), Some(vec![record(3), record(3)]));
+    // names of skipped frames are available
+    assert_eq!(reader.names().resolve(2), Some("operator 3"));
+    assert_eq!(reader.next_batch().unwrap(), Some(vec![record(4), record(4)]));
+    assert_eq!(reader.next_batch().unwrap(), None);
+
+    // seeking past the last epoch yields an empty trace, sequential reads still work
+    let mut reader = TraceReader::seek_epoch(std::io::Cursor::new(bytes.clone()), 10).unwrap();
+    assert_eq!(reader.next_batch().unwrap(), None);
+    let mut reader = TraceReader::new(std::io::Cursor::new(bytes)).unwrap();
+    for _ in 1 .. 5 {
+        assert!(reader.next_batch().unwrap().is_some());
+    }
+    assert_eq!(reader.next_batch().unwrap(), None);
+}
+
+#[test]
+fn skip_damaged_frames() {
+    let batch = |seq_no| vec![crate::test_util::record(seq_no, 1)];
+
+    let header = TraceHeader::new(1, "test".to_string(), Encoding::Abomonation, Compression::None);
+    let mut bytes = Vec::new();
+    header.write_to(&mut bytes).unwrap();
+    let mut frames = Vec::new();
+    for seq_no in 0 .. 3 {
+        let mut frame = Vec::new();
+        encoding::write_batch(Encoding::Abomonation, &batch(seq_no), &mut frame).unwrap();
+        frames.push(bytes.len());
+        block::write_block(&frame, &mut bytes).unwrap();
+    }
+
+    // corrupt the second frame's payload
+    bytes[frames[1] + 30] ^= 0xff;
+
+    let end = bytes.len() as u64;
+    let mut reader = TraceReader::new(std::io::Cursor::new(bytes)).unwrap();
+    assert_eq!(reader.next_batch().unwrap(), Some(batch(0)));
+    assert_eq!(reader.offset(), frames[1] as u64);
+    assert_eq!(reader.next_batch().unwrap(), Some(batch(2)));
+    assert_eq!(reader.offset(), end);
+    assert_eq!(reader.next_batch().unwrap(), None);
+    assert_eq!(reader.damage(), Damage { regions: 1, bytes: (frames[2] - frames[1]) as u64, truncated: 0 });
+}
+
+#[test]
+fn truncated_trace() {
+    let batch = |epoch| vec![crate::test_util::record(epoch, epoch)];
+
+    // with and without blocks
+    for &version in [FORMAT_VERSION, 2].iter() {
+        let mut header = TraceHeader::new(1, "test".to_string(), Encoding::Protobuf, Compression::None);
+        header.version = version;
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes).unwrap();
+        let mut frames = Vec::new();
+        for epoch in 0 .. 3 {
+            let mut frame = Vec::new();
+            encoding::write_batch(Encoding::Protobuf, &batch(epoch), &mut frame).unwrap();
+            frames.push(bytes.len());
+            if version >= 3 {
+                block::write_block(&frame, &mut bytes).unwrap();
+            } else {
+                bytes.extend_from_slice(&frame);
+            }
+        }
+        bytes.truncate(bytes.len() - 5);
+
+        let mut reader = TraceReader::new(std::io::Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.next_batch().unwrap(), Some(batch(0)));
+        assert_eq!(reader.truncation(), None);
+        assert_eq!(reader.next_batch().unwrap(), Some(batch(1)));
+        assert_eq!(reader.next_batch().unwrap(), None);
+        let truncation = reader.truncation().unwrap();
+        assert_eq!(truncation, Truncation { valid_bytes: frames[2] as u64, last_epoch: Some(1) });
+        assert_eq!(truncation.to_string(), format!("truncated after {} bytes / epoch 1", frames[2]));
+    }
+}
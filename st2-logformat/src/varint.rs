@@ -0,0 +1,60 @@
+//! LEB128 variable-length integers, as used by protobuf.
+
+use std::io::{Error, ErrorKind, Result};
+
+/// Appends `value` to `buf` in 1-10 bytes.
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Reads a value from the front of `bytes`, advancing it.
+pub(crate) fn read_varint(bytes: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0 .. 64).step_by(7) {
+        let (&byte, rest) = bytes.split_first()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "truncated varint"))?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::new(ErrorKind::InvalidData, "varint exceeds 64 bits"))
+}
+
+/// Appends the signed `value` to `buf`, zigzag-encoded so that
+/// small negative values stay short.
+pub(crate) fn write_signed_varint(buf: &mut Vec<u8>, value: i64) {
+    write_varint(buf, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+/// Reads a zigzag-encoded signed value from the front of `bytes`, advancing it.
+pub(crate) fn read_signed_varint(bytes: &mut &[u8]) -> Result<i64> {
+    let value = read_varint(bytes)?;
+    Ok(((value >> 1) as i64) ^ -((value & 1) as i64))
+}
+
+#[test]
+fn roundtrip_varints() {
+    let mut buf = Vec::new();
+    for value in [0, 1, 127, 128, 300, u64::max_value()].iter() {
+        write_varint(&mut buf, *value);
+    }
+    for value in [0, -1, 1, -64, 64, i64::min_value(), i64::max_value()].iter() {
+        write_signed_varint(&mut buf, *value);
+    }
+
+    let mut bytes = &buf[..];
+    for value in [0, 1, 127, 128, 300, u64::max_value()].iter() {
+        assert_eq!(read_varint(&mut bytes).unwrap(), *value);
+    }
+    for value in [0, -1, 1, -64, 64, i64::min_value(), i64::max_value()].iter() {
+        assert_eq!(read_signed_varint(&mut bytes).unwrap(), *value);
+    }
+    assert!(bytes.is_empty());
+    assert!(read_varint(&mut &[0x80][..]).is_err());
+}
@@ -0,0 +1,134 @@
+//! Conformance tests pinning ST2's interchange formats.
+//! They only use the public API, as third-party producers and consumers would.
+//! A failing test here means a breaking change to the wire format.
+
+use std::io::{Cursor, Write};
+use std::time::Duration;
+
+use st2_logformat::{ActivityType, EventType, LogRecord};
+use st2_logformat::encoding::{self, Encoding};
+use st2_logformat::tagged::{TaggedField, Value, USER_TAGS};
+use st2_logformat::trace::{Compression, TraceHeader, TraceReader, TraceWriter, FORMAT_VERSION};
+
+fn record() -> LogRecord {
+    LogRecord {
+        seq_no: 1,
+        epoch: 2,
+        timestamp: Duration::from_nanos(3),
+        local_worker: 0,
+        activity_type: ActivityType::Scheduling,
+        event_type: EventType::End,
+        remote_worker: None,
+        operator_id: Some(5),
+        channel_id: None,
+        correlator_id: None,
+        length: Some(7),
+        origin: None,
+        tagged: Vec::new(),
+    }
+}
+
+fn encodings() -> Vec<Encoding> {
+    vec![
+        Encoding::Abomonation,
+        #[cfg(feature = "bincode")]
+        Encoding::Bincode,
+        Encoding::Protobuf,
+        Encoding::Compact,
+    ]
+}
+
+#[test]
+fn protobuf_wire_format() {
+    let bytes = encoding::encode_batch(Encoding::Protobuf, &vec![record()]).unwrap();
+    assert_eq!(bytes, vec![
+        0x0a, 12,   // LogRecordBatch.records
+        0x08, 1,    // seq_no
+        0x10, 2,    // epoch
+        0x18, 3,    // timestamp_ns
+        0x30, 2,    // event_type: END
+        0x40, 5,    // operator_id
+        0x58, 7,    // length
+    ]);
+}
+
+#[test]
+fn compact_wire_format() {
+    let bytes = encoding::encode_batch(Encoding::Compact, &vec![record()]).unwrap();
+    assert_eq!(bytes, vec![
+        1,          // records
+        0,          // local_worker
+        2, 4, 6,    // zigzag Δseq_no, Δepoch, Δtimestamp
+        2,          // Scheduling (0) << 3 | End (2)
+        0b10010,    // operator_id and length present
+        5, 7,
+    ]);
+}
+
+#[test]
+fn header_format() {
+    let mut header = TraceHeader::new(1, "t".to_string(), Encoding::Protobuf, Compression::None);
+    header.start_time = Duration::from_nanos(0x0102);
+    let mut bytes = Vec::new();
+    header.write_to(&mut bytes).unwrap();
+
+    let mut expected = b"ST2TRACE".to_vec();
+    expected.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    expected.extend_from_slice(&21u32.to_le_bytes());
+    expected.extend_from_slice(&1u64.to_le_bytes());
+    expected.extend_from_slice(&0x0102u64.to_le_bytes());
+    expected.extend_from_slice(&[2, 0, 1, 0, b't']);
+    assert_eq!(bytes, expected);
+}
+
+#[test]
+fn traces_roundtrip_in_every_encoding() {
+    let mut tagged = record();
+    tagged.tagged.push(TaggedField { tag: USER_TAGS, value: Value::Bytes(b"conformance".to_vec()) });
+    let batch = vec![record(), tagged];
+
+    for encoding in encodings() {
+        let bytes = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let header = TraceHeader::new(1, "conformance".to_string(), encoding, Compression::None);
+        let mut writer = TraceWriter::new(&header, Shared(bytes.clone())).unwrap();
+        let host = writer.intern("host-1");
+        writer.write_batch(&batch).unwrap();
+        writer.finish().unwrap();
+
+        let bytes = bytes.lock().unwrap().clone();
+        let mut reader = TraceReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.header(), &header);
+        assert_eq!(reader.next_batch().unwrap(), Some(batch.clone()), "{:?}", encoding);
+        assert_eq!(reader.next_batch().unwrap(), None);
+        assert_eq!(reader.names().resolve(host), Some("host-1"));
+        assert_eq!(reader.truncation(), None);
+    }
+}
+
+#[test]
+fn older_versions_stay_readable() {
+    // a version 2 trace: frames without blocks, no epoch index
+    let mut header = TraceHeader::new(1, "conformance".to_string(), Encoding::Protobuf, Compression::None);
+    header.version = 2;
+    let mut bytes = Vec::new();
+    header.write_to(&mut bytes).unwrap();
+    encoding::write_batch(Encoding::Protobuf, &vec![record()], &mut bytes).unwrap();
+
+    let mut reader = TraceReader::new(Cursor::new(bytes)).unwrap();
+    assert_eq!(reader.header().version, 2);
+    assert_eq!(reader.next_batch().unwrap(), Some(vec![record()]));
+    assert_eq!(reader.next_batch().unwrap(), None);
+}
+
+/// A `Write` whose contents remain accessible after the writer is consumed
+struct Shared(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+//! Python bindings of SnailTrail's analyses, as the module `snailtrail`.
+//!
+//! Traces are loaded into a `Pag`, whose edges, critical paths, and per-epoch
+//! metrics are returned as lists of dicts, or as pandas DataFrames (`*_frame`),
+//! so analyses can be scripted in notebooks:
+//!
+//! ```python
+//! import snailtrail
+//!
+//! pag = snailtrail.load("traces/run-1", source_peers=4, operator_names={3: "Map"})
+//! cp = pag.critical_paths_frame()
+//! cp.groupby("operator_name").duration_ns.sum().sort_values(ascending=False)
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, atomic::AtomicBool};
+
+use pyo3::exceptions::{PyOSError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use pyo3::wrap_pyfunction;
+
+use st2::STError;
+use st2::pag::PagEdge;
+use st2::commands::query::{Query, Value};
+use st2::commands::snapshot::critical_path;
+use st2::store::epoch_samples;
+use st2_timely::filter::Filter;
+use st2_timely::replay_throttled::ReplaySpeed;
+use tdiag_connect::receive::ReplaySource;
+
+/// Columns of edge dicts and frames
+const EDGE_COLUMNS: &[&str] = &[
+    "epoch", "worker", "dst_worker", "operator", "operator_name", "activity", "traverse", "start_ns", "end_ns", "duration_ns", "records",
+];
+
+/// Columns of summary dicts and frames
+const SUMMARY_COLUMNS: &[&str] = &["epoch", "edges", "epoch_latency_ns", "critical_path_ns", "skew"];
+
+/// Columns of metric frames
+const METRIC_COLUMNS: &[&str] = &["epoch", "name", "operator", "activity", "worker", "value"];
+
+/// The PAG of a trace
+#[pyclass(module = "snailtrail")]
+pub struct Pag {
+    /// Edges, ordered by source timestamp
+    edges: Vec<PagEdge>,
+    /// epoch -> its edges' indices
+    epochs: BTreeMap<u64, Vec<usize>>,
+    operator_names: BTreeMap<u64, String>,
+}
+
+impl Pag {
+    fn new(mut edges: Vec<PagEdge>, operator_names: BTreeMap<u64, String>) -> Self {
+        edges.sort_by_key(|edge| (edge.source.timestamp, edge.source.worker_id, edge.destination.timestamp));
+        let mut epochs: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+        for (i, edge) in edges.iter().enumerate() {
+            epochs.entry(edge.source.epoch).or_insert_with(Vec::new).push(i);
+        }
+        Pag { edges, epochs, operator_names }
+    }
+
+    fn epoch_edges(&self, epoch: u64) -> PyResult<Vec<PagEdge>> {
+        self.epochs.get(&epoch)
+            .map(|indices| indices.iter().map(|i| self.edges[*i].clone()).collect())
+            .ok_or_else(|| PyRuntimeError::new_err(format!("no edges in epoch {}", epoch)))
+    }
+
+    fn edge_dict(&self, py: Python, edge: &PagEdge) -> PyResult<PyObject> {
+        let dict = PyDict::new(py);
+        dict.set_item("epoch", edge.source.epoch)?;
+        dict.set_item("worker", edge.source.worker_id)?;
+        dict.set_item("dst_worker", edge.destination.worker_id)?;
+        dict.set_item("operator", edge.operator_id)?;
+        dict.set_item("operator_name", edge.operator_id.and_then(|id| self.operator_names.get(&id)))?;
+        dict.set_item("activity", format!("{:?}", edge.edge_type))?;
+        dict.set_item("traverse", format!("{:?}", edge.traverse))?;
+        dict.set_item("start_ns", edge.source.timestamp.as_nanos() as u64)?;
+        dict.set_item("end_ns", edge.destination.timestamp.as_nanos() as u64)?;
+        dict.set_item("duration_ns", edge.duration())?;
+        dict.set_item("records", edge.length)?;
+        Ok(dict.to_object(py))
+    }
+
+    fn summary_dict(&self, py: Python, epoch: u64, edges: &[PagEdge]) -> PyResult<PyObject> {
+        let dict = PyDict::new(py);
+        dict.set_item("epoch", epoch)?;
+        dict.set_item("edges", edges.len())?;
+        for sample in epoch_samples(epoch, edges, 0, &self.operator_names).iter().filter(|sample| sample.labels.is_empty()) {
+            if SUMMARY_COLUMNS.contains(&sample.name) {
+                dict.set_item(sample.name, sample.value)?;
+            }
+        }
+        Ok(dict.to_object(py))
+    }
+}
+
+#[pymethods]
+impl Pag {
+    /// The epochs of the PAG, in order
+    fn epochs(&self) -> Vec<u64> {
+        self.epochs.keys().cloned().collect()
+    }
+
+    /// The PAG's edges as dicts, ordered by start time; only those of `epoch`, if given
+    #[args(epoch = "None")]
+    fn edges(&self, py: Python, epoch: Option<u64>) -> PyResult<Vec<PyObject>> {
+        match epoch {
+            Some(epoch) => self.epoch_edges(epoch)?.iter().map(|edge| self.edge_dict(py, edge)).collect(),
+            None => self.edges.iter().map(|edge| self.edge_dict(py, edge)).collect(),
+        }
+    }
+
+    /// The critical path of `epoch` as edge dicts, in order
+    fn critical_path(&self, py: Python, epoch: u64) -> PyResult<Vec<PyObject>> {
+        critical_path(&self.epoch_edges(epoch)?).iter().map(|edge| self.edge_dict(py, edge)).collect()
+    }
+
+    /// Per-epoch summaries as dicts: the number of edges, latency, critical path
+    /// length, and skew of every epoch
+    fn summaries(&self, py: Python) -> PyResult<Vec<PyObject>> {
+        let mut summaries = Vec::new();
+        for epoch in self.epochs.keys() {
+            summaries.push(self.summary_dict(py, *epoch, &self.epoch_edges(*epoch)?)?);
+        }
+        Ok(summaries)
+    }
+
+    /// Evaluates a declarative query (cf. `st2 query --help`), returning its rows as dicts
+    fn query(&self, py: Python, query: &str) -> PyResult<Vec<PyObject>> {
+        let query: Query = query.parse().map_err(error)?;
+        let table = query.eval(&self.edges, &self.operator_names).map_err(error)?;
+        table.rows.iter()
+            .map(|row| {
+                let dict = PyDict::new(py);
+                for (column, value) in table.columns.iter().zip(row.iter()) {
+                    match value {
+                        Value::Null => dict.set_item(column, py.None())?,
+                        Value::Int(x) | Value::Time(x) => dict.set_item(column, x)?,
+                        Value::Float(x) => dict.set_item(column, x)?,
+                        Value::Text(s) => dict.set_item(column, s)?,
+                    }
+                }
+                Ok(dict.to_object(py))
+            })
+            .collect()
+    }
+
+    /// The PAG's edges as a pandas DataFrame; only those of `epoch`, if given
+    #[args(epoch = "None")]
+    fn edges_frame(&self, py: Python, epoch: Option<u64>) -> PyResult<PyObject> {
+        frame(py, self.edges(py, epoch)?, EDGE_COLUMNS)
+    }
+
+    /// The critical paths of all epochs as a pandas DataFrame of edges
+    fn critical_paths_frame(&self, py: Python) -> PyResult<PyObject> {
+        let mut rows = Vec::new();
+        for epoch in self.epochs.keys() {
+            rows.extend(self.critical_path(py, *epoch)?);
+        }
+        frame(py, rows, EDGE_COLUMNS)
+    }
+
+    /// The per-epoch summaries as a pandas DataFrame
+    fn summaries_frame(&self, py: Python) -> PyResult<PyObject> {
+        frame(py, self.summaries(py)?, SUMMARY_COLUMNS)
+    }
+
+    /// All per-epoch metrics (cf. `st2 grafana`) as a pandas DataFrame, one row per
+    /// sample, with its labels as columns
+    fn metrics_frame(&self, py: Python) -> PyResult<PyObject> {
+        let mut rows = Vec::new();
+        for epoch in self.epochs.keys() {
+            for sample in epoch_samples(*epoch, &self.epoch_edges(*epoch)?, 0, &self.operator_names) {
+                let dict = PyDict::new(py);
+                dict.set_item("epoch", sample.epoch)?;
+                dict.set_item("name", sample.name)?;
+                for (key, value) in sample.labels.iter() {
+                    dict.set_item(key, value)?;
+                }
+                dict.set_item("value", sample.value)?;
+                rows.push(dict.to_object(py));
+            }
+        }
+        frame(py, rows, METRIC_COLUMNS)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<snailtrail.Pag: {} edges of {} epochs>", self.edges.len(), self.epochs.len())
+    }
+}
+
+/// Loads the PAG of the trace in `path`, i.e. its `*.dump` files written by
+/// `source_peers` peers of the source computation, constructing it with `workers`
+/// threads. Operators are named by `operator_names` (id -> name), if given.
+#[pyfunction(source_peers = "1", workers = "1", operator_names = "None")]
+fn load(py: Python, path: &str, source_peers: usize, workers: usize, operator_names: Option<BTreeMap<u64, String>>) -> PyResult<Pag> {
+    let paths: Vec<PathBuf> = (0 .. source_peers).map(|idx| PathBuf::from(format!("{}/{}.dump", path, idx))).collect();
+    if let Some(missing) = paths.iter().find(|path| !path.exists()) {
+        return Err(PyRuntimeError::new_err(format!("{} doesn't exist", missing.display())));
+    }
+    let replay_source = ReplaySource::Files(Arc::new(Mutex::new(paths.into_iter().map(Some).collect())));
+
+    // the GIL isn't needed while the PAG is constructed
+    let edges = py.allow_threads(|| st2::commands::repl::load_pag(
+        timely::Configuration::Process(workers.max(1)),
+        replay_source,
+        Arc::new(AtomicBool::new(true)),
+        ReplaySpeed::Unbounded,
+        Filter::default()))
+        .map_err(error)?;
+    Ok(Pag::new(edges, operator_names.unwrap_or_default()))
+}
+
+/// Loads the PAG of a single epoch saved by `st2 snapshot` to `path`
+#[pyfunction(operator_names = "None")]
+fn load_snapshot(path: &str, operator_names: Option<BTreeMap<u64, String>>) -> PyResult<Pag> {
+    let edges = st2::commands::repl::load_snapshot(Path::new(path)).map_err(error)?;
+    Ok(Pag::new(edges, operator_names.unwrap_or_default()))
+}
+
+/// A pandas DataFrame of `rows` (dicts) with `columns`, in this order; columns
+/// only some rows have are appended
+fn frame(py: Python, rows: Vec<PyObject>, columns: &[&str]) -> PyResult<PyObject> {
+    let pandas = py.import("pandas")
+        .map_err(|_| PyRuntimeError::new_err("DataFrames require pandas, e.g. `pip install pandas`"))?;
+    let frame = pandas.getattr("DataFrame")?.call1((rows,))?;
+    let present: Vec<String> = frame.getattr("columns")?.call_method0("tolist")?.extract()?;
+    let mut order: Vec<String> = columns.iter().map(|column| column.to_string()).collect();
+    order.extend(present.into_iter().filter(|column| !columns.contains(&column.as_str())));
+
+    let kwargs = PyDict::new(py);
+    kwargs.set_item("columns", order)?;
+    Ok(frame.call_method("reindex", (), Some(kwargs))?.to_object(py))
+}
+
+fn error(e: STError) -> PyErr {
+    match e {
+        STError::Io { .. } => PyOSError::new_err(e.to_string()),
+        STError::Config(_) => PyValueError::new_err(e.to_string()),
+        _ => PyRuntimeError::new_err(e.to_string()),
+    }
+}
+
+/// SnailTrail's analyses of timely dataflow traces
+#[pymodule]
+fn snailtrail(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Pag>()?;
+    m.add_wrapped(wrap_pyfunction!(load))?;
+    m.add_wrapped(wrap_pyfunction!(load_snapshot))?;
+    Ok(())
+}
@@ -2,13 +2,14 @@
 //!
 //! To log a computation, see `Adapter`'s docstring. If `SNAILTRAIL_ADDR=<IP>:<Port>`
 //! is set as env variable, the computation will be logged online via TCP.
+//! For a clustered ST2, provide a comma-separated list with one address per ST2 process.
 //!
 //! Replay a log trace with `replay_into` or `replay_throttled`.
 
 use std::{
     error::Error,
     fs::File,
-    io::Write,
+    io::{Read, Write},
     net::TcpStream,
     path::Path,
     time::Duration,
@@ -19,7 +20,7 @@ use std::cell::RefCell;
 
 use timely::{
     communication::allocator::Generic,
-    dataflow::operators::capture::{event::EventPusher, Event, EventReader, EventWriter},
+    dataflow::operators::capture::{event::{EventIterator, EventPusher}, Event, EventReader, EventWriter},
     logging::{TimelyEvent, WorkerIdentifier, StartStop, Logger},
     worker::Worker,
 };
@@ -31,6 +32,10 @@ use TimelyEvent::{Messages, Operates, Channels, Progress, Schedule, Text};
 
 use st2_logformat::pair::Pair;
 
+use crate::mmap::MmapReplayer;
+use crate::prefetch::Prefetcher;
+use crate::faults::FaultyReplayer;
+
 
 /// A prepared computation event: (epoch, seq_no, Option<event_length>, event)
 /// The seq_no is a worker-unique identifier of the message and given
@@ -43,6 +48,35 @@ pub type Replayer<T, R> = EventReader<T, CompEvent, R>;
 /// A ReplayWriter that writes data to be streamed into timely
 pub type ReplayWriter<T, R> = EventWriter<T, CompEvent, R>;
 
+/// An event of a `*.dump` file or logging stream
+pub type TraceEvent = Event<Pair<u64, Duration>, CompEvent>;
+
+/// Replays a `*.dump` file or logging stream: read as a stream (e.g. from a
+/// socket or a file that's still growing), memory-mapped (cf. `mmap`), or
+/// decoded ahead on an I/O thread (cf. `prefetch`), optionally with injected
+/// faults (cf. `faults`)
+pub enum TraceReplayer<R> {
+    /// Events read from `R`
+    Stream(Replayer<Pair<u64, Duration>, R>),
+    /// Events decoded in place from a memory map
+    Mapped(MmapReplayer),
+    /// Events decoded by an I/O thread
+    Prefetched(Prefetcher),
+    /// Events of another replayer, with injected faults
+    Faulty(Box<FaultyReplayer<TraceReplayer<R>>>),
+}
+
+impl<R: Read> EventIterator<Pair<u64, Duration>, CompEvent> for TraceReplayer<R> {
+    fn next(&mut self) -> Option<&TraceEvent> {
+        match self {
+            TraceReplayer::Stream(replayer) => replayer.next(),
+            TraceReplayer::Mapped(replayer) => replayer.next(),
+            TraceReplayer::Prefetched(replayer) => replayer.next(),
+            TraceReplayer::Faulty(replayer) => replayer.next(),
+        }
+    }
+}
+
 /// Wrapper around a `Vec` of `(Duration, usize, DifferentialEvent|TimelyEvent)`
 pub enum DataflowEvents <'a> {
     /// A `TimelyEvent` batch
@@ -102,6 +136,14 @@ impl Adapter {
         Adapter { logger }
     }
 
+    /// Creates a `PAGLogger` instance that logs to `writers` regardless of
+    /// `SNAILTRAIL_ADDR` (cf. `PAGLogger::with_writers`) and attaches it to the computation.
+    pub fn attach_writers(worker: &Worker<Generic>, writers: Vec<ReplayWriter<Pair<u64, Duration>, TcpStreamOrFile>>, max_fuel: Option<usize>) -> Self {
+        PAGLogger::with_writers(worker, writers, max_fuel.unwrap_or(4096)).attach(worker);
+        let logger = worker.log_register().get::<TimelyEvent>("timely").expect("timely logger not found");
+        Adapter { logger }
+    }
+
     /// Communicates epoch completion to the underlying `PAGLogger`.
     pub fn tick_epoch(&self) {
         self.logger.log(TimelyEvent::Text(Default::default()));
@@ -181,8 +223,11 @@ impl PAGLogger {
 
         let writers = if let Ok(addr) = ::std::env::var("SNAILTRAIL_ADDR") {
             info!("w{} registers logger @{:?}: lbf{}, fuel{}", worker.index(), &addr, load_balance_factor, max_fuel);
+            // Multiple ST2 processes shard the source peers by index.
+            let addrs = addr.split(',').collect::<Vec<_>>();
             (0 .. load_balance_factor)
-                .map(|_| TcpStream::connect(&addr).expect("could not connect to logging stream"))
+                .map(|i| addrs[(worker.index() + i * worker.peers()) % addrs.len()])
+                .map(|addr| TcpStream::connect(addr).expect("could not connect to logging stream"))
                 .map(|stream| {
                     // SnailTrail should be able to keep up with an online computation.
                     // If batch sizes are too large, they should be buffered. Blocking the
@@ -207,6 +252,18 @@ impl PAGLogger {
             }).collect::<Vec<_>>()
         };
 
+        Self::with_writers(worker, writers, max_fuel)
+    }
+
+    /// Creates a new PAGLogger that logs to `writers`, e.g. files of a chosen
+    /// path regardless of `SNAILTRAIL_ADDR`.
+    /// Every writer is told the worker's origin first (cf. `origins`).
+    pub fn with_writers(worker: &Worker<Generic>, mut writers: Vec<ReplayWriter<Pair<u64, Duration>, TcpStreamOrFile>>, max_fuel: usize) -> Self {
+        let origin = (0, 0, None, (Default::default(), worker.index(), Text(crate::origins::announcement())));
+        for writer in writers.iter_mut() {
+            writer.push(Event::Messages(Default::default(), vec![origin.clone()]));
+        }
+
         PAGLogger {
             writers,
             curr_writer: 0,
@@ -0,0 +1,94 @@
+//! Structured diagnostics of SnailTrail itself, emitted as `tracing` spans and
+//! events: ingest rates and frontier progress of replay, and the time every
+//! pipeline stage spent working. They're reported to whichever `tracing`
+//! subscriber the embedding program installs (e.g. `st2 --log-format json`).
+
+use std::time::{Duration, Instant};
+
+use tracing::Span;
+
+/// How often replay reports its ingest rate
+pub const INGEST_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time spent in a stage (i.e., the logic of an operator) of a worker's
+/// pipeline. Work done while `time`d runs in the stage's span; once the stage
+/// is dropped with its dataflow, its totals are reported.
+pub struct StageTimer {
+    name: String,
+    worker: usize,
+    span: Span,
+    busy: Duration,
+    invocations: u64,
+    records: u64,
+}
+
+impl StageTimer {
+    /// A timer of stage `name` at `worker`
+    pub fn new(name: &str, worker: usize) -> Self {
+        StageTimer {
+            name: name.to_string(),
+            worker,
+            span: tracing::debug_span!("stage", stage = name, worker),
+            busy: Duration::default(),
+            invocations: 0,
+            records: 0,
+        }
+    }
+
+    /// Runs `logic`, which processed `records` records, in the stage's span.
+    pub fn time<R>(&mut self, records: usize, logic: impl FnOnce() -> R) -> R {
+        let _entered = self.span.enter();
+        let started = Instant::now();
+        let result = logic();
+        self.busy += started.elapsed();
+        self.invocations += 1;
+        self.records += records as u64;
+        result
+    }
+}
+
+impl Drop for StageTimer {
+    fn drop(&mut self) {
+        tracing::info!(
+            stage = self.name.as_str(),
+            worker = self.worker,
+            busy_ms = self.busy.as_millis() as u64,
+            invocations = self.invocations,
+            records = self.records,
+            "stage finished");
+    }
+}
+
+/// Reports the rate of events and bytes a replay operator ingests
+pub struct IngestRate {
+    worker: usize,
+    since: Instant,
+    events: u64,
+    bytes: u64,
+}
+
+impl IngestRate {
+    /// The rate of replay at `worker`
+    pub fn new(worker: usize) -> Self {
+        IngestRate { worker, since: Instant::now(), events: 0, bytes: 0 }
+    }
+
+    /// Counts `events` replayed events of `bytes` bytes, reporting the rate
+    /// every `INGEST_REPORT_INTERVAL`.
+    pub fn add(&mut self, events: u64, bytes: u64) {
+        self.events += events;
+        self.bytes += bytes;
+        let elapsed = self.since.elapsed();
+        if elapsed >= INGEST_REPORT_INTERVAL {
+            let secs = elapsed.as_secs_f64();
+            tracing::info!(
+                worker = self.worker,
+                events = self.events,
+                bytes = self.bytes,
+                events_per_sec = self.events as f64 / secs,
+                bytes_per_sec = self.bytes as f64 / secs,
+                "ingest");
+            *self = IngestRate::new(self.worker);
+        }
+    }
+}
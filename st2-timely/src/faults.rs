@@ -0,0 +1,165 @@
+//! Fault injection into the ingest path, to validate that an ST2 deployment
+//! degrades gracefully (e.g. keeps serving, reports stalled sources, skips
+//! damaged data) before relying on it during incidents. A `FaultyReplayer`
+//! wraps a source's replayer and, as configured by `Faults`:
+//!
+//! - drops the source's connection after a number of events: the source ends
+//!   without releasing its capabilities, like a crashed source computation,
+//! - delays event batches, like a congested network,
+//! - reorders the records within batches, and
+//! - corrupts batches, which are then lost like blocks that fail their checksum.
+//!
+//! Faults are drawn from a seeded generator, so a run can be repeated.
+
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use timely::dataflow::operators::capture::event::{Event, EventIterator};
+
+use st2_logformat::pair::Pair;
+
+use crate::connect::{CompEvent, TraceEvent};
+
+/// Which faults to inject, and how often
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Faults {
+    /// Drop the connection after this many events
+    pub drop_after: Option<u64>,
+    /// Probability that a batch is delayed
+    pub delay: f64,
+    /// How long delayed batches are held back
+    pub delay_time: Duration,
+    /// Probability that the records of a batch are reordered
+    pub reorder: f64,
+    /// Probability that a batch is corrupted
+    pub corrupt: f64,
+    /// Seed of the generator faults are drawn from
+    pub seed: u64,
+}
+
+impl FromStr for Faults {
+    type Err = String;
+
+    /// Parses comma-separated faults: `drop=<EVENTS>`, `delay=<PROB>:<MS>`,
+    /// `reorder=<PROB>`, `corrupt=<PROB>`, and `seed=<N>`, e.g.
+    /// `delay=0.01:200,reorder=0.1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn probability(value: &str) -> Result<f64, String> {
+            value.parse().ok().filter(|p| (0.0 ..= 1.0).contains(p))
+                .ok_or_else(|| format!("{}: expected a probability between 0 and 1", value))
+        }
+
+        let mut faults = Faults::default();
+        for fault in s.split(',').filter(|fault| !fault.is_empty()) {
+            let mut parts = fault.splitn(2, '=');
+            let (name, value) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+            match name {
+                "drop" => faults.drop_after = Some(value.parse().map_err(|e| format!("{}: {}", fault, e))?),
+                "delay" => {
+                    let mut parts = value.splitn(2, ':');
+                    faults.delay = probability(parts.next().unwrap_or_default())?;
+                    let ms = parts.next().ok_or_else(|| format!("{}: expected delay=<PROB>:<MS>", fault))?;
+                    faults.delay_time = Duration::from_millis(ms.parse().map_err(|e| format!("{}: {}", fault, e))?);
+                }
+                "reorder" => faults.reorder = probability(value)?,
+                "corrupt" => faults.corrupt = probability(value)?,
+                "seed" => faults.seed = value.parse().map_err(|e| format!("{}: {}", fault, e))?,
+                _ => return Err(format!("{}: expected drop, delay, reorder, corrupt, or seed", fault)),
+            }
+        }
+        Ok(faults)
+    }
+}
+
+/// Replays the events of `I`, injecting `Faults`
+pub struct FaultyReplayer<I> {
+    replayer: I,
+    faults: Faults,
+    /// Name of the source, for logs
+    name: String,
+    /// State of the xorshift generator faults are drawn from
+    state: u64,
+    /// Events replayed so far
+    events: u64,
+    /// A delayed event, and when it's due
+    held: Option<(Instant, TraceEvent)>,
+    /// The event `next` returned last
+    current: Option<TraceEvent>,
+    dropped: bool,
+}
+
+impl<I: EventIterator<Pair<u64, Duration>, CompEvent>> FaultyReplayer<I> {
+    /// Injects `faults` into `replayer`, which replays the source `name`. Every
+    /// source should get its own `source` number, so sources see different faults.
+    pub fn new(replayer: I, faults: Faults, name: String, source: u64) -> Self {
+        // xorshift doesn't leave 0
+        let state = (faults.seed ^ source.wrapping_mul(0x9E37_79B9_7F4A_7C15)).max(1);
+        FaultyReplayer { replayer, faults, name, state, events: 0, held: None, current: None, dropped: false }
+    }
+
+    /// Whether a fault of probability `p` happens
+    fn happens(&mut self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 53) as f64 <= p
+    }
+
+    /// A random index below `n`
+    fn index(&mut self, n: usize) -> usize {
+        self.happens(1.0);
+        (self.state % n as u64) as usize
+    }
+}
+
+impl<I: EventIterator<Pair<u64, Duration>, CompEvent>> EventIterator<Pair<u64, Duration>, CompEvent> for FaultyReplayer<I> {
+    fn next(&mut self) -> Option<&TraceEvent> {
+        if self.dropped {
+            return None;
+        }
+
+        if let Some((due, _)) = &self.held {
+            if Instant::now() < *due {
+                return None;
+            }
+            self.current = self.held.take().map(|(_, event)| event);
+            return self.current.as_ref();
+        }
+
+        loop {
+            let mut event = self.replayer.next()?.clone();
+            self.events += 1;
+            if self.faults.drop_after.map_or(false, |after| self.events > after) {
+                warn!("{}: injected fault: dropping the connection after {} events", self.name, self.events - 1);
+                self.dropped = true;
+                return None;
+            }
+
+            // progress updates carry the capabilities, so only batches of data are faulty
+            if let Event::Messages(_, records) = &mut event {
+                if self.happens(self.faults.corrupt) {
+                    warn!("{}: injected fault: corrupted a batch of {} events", self.name, records.len());
+                    continue;
+                }
+                if self.happens(self.faults.reorder) {
+                    debug!("{}: injected fault: reordering a batch of {} events", self.name, records.len());
+                    for i in (1 .. records.len()).rev() {
+                        let j = self.index(i + 1);
+                        records.swap(i, j);
+                    }
+                }
+                if self.happens(self.faults.delay) {
+                    debug!("{}: injected fault: delaying a batch by {:?}", self.name, self.faults.delay_time);
+                    self.held = Some((Instant::now() + self.faults.delay_time, event));
+                    return None;
+                }
+            }
+
+            self.current = Some(event);
+            return self.current.as_ref();
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Filters on the replayed events, applied before `LogRecord`s are constructed
+//! from them, so filtered-out events never pay for PAG construction.
+
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Selects operators by id, name, or address
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OperatorSelector {
+    /// The operator with this id, e.g. `3`
+    Id(usize),
+    /// Operators with this name, e.g. `Map`
+    Name(String),
+    /// Operators whose address matches this glob: address segments separated by `.`,
+    /// where `*` matches any single segment, e.g. `0.2.*`
+    Address(Vec<Option<usize>>),
+}
+
+impl OperatorSelector {
+    /// Whether the operator with `id`, `name`, and `addr` is selected
+    pub fn matches(&self, id: usize, name: &str, addr: &[usize]) -> bool {
+        match self {
+            OperatorSelector::Id(selected) => *selected == id,
+            OperatorSelector::Name(selected) => selected == name,
+            OperatorSelector::Address(glob) => glob.len() == addr.len()
+                && glob.iter().zip(addr.iter()).all(|(segment, x)| segment.map_or(true, |segment| segment == *x)),
+        }
+    }
+}
+
+impl FromStr for OperatorSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = s.parse() {
+            return Ok(OperatorSelector::Id(id));
+        }
+
+        let is_glob = s.contains(|c| c == '.' || c == '*')
+            && s.chars().all(|c| c.is_ascii_digit() || c == '.' || c == '*');
+        if is_glob {
+            s.split('.')
+                .map(|segment| match segment {
+                    "*" => Ok(None),
+                    segment => segment.parse().map(Some).map_err(|_| format!("invalid address glob: {}", s)),
+                })
+                .collect::<Result<_, _>>()
+                .map(OperatorSelector::Address)
+        } else if s.is_empty() {
+            Err("empty operator".to_string())
+        } else {
+            Ok(OperatorSelector::Name(s.to_string()))
+        }
+    }
+}
+
+/// Which events of the source computation to analyze
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    /// Only events of these workers, if set
+    pub workers: Option<BTreeSet<usize>>,
+    /// Only scheduling events of operators matching any of these selectors, if set
+    pub operators: Option<Vec<OperatorSelector>>,
+    /// Only events of these epochs
+    pub epochs: Range<u64>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            workers: None,
+            operators: None,
+            epochs: 0 .. std::u64::MAX,
+        }
+    }
+}
+
+impl Filter {
+    /// Whether events of `worker` in `epoch` are analyzed
+    pub fn keeps(&self, epoch: u64, worker: usize) -> bool {
+        self.epochs.contains(&epoch) && self.workers.as_ref().map_or(true, |workers| workers.contains(&worker))
+    }
+
+    /// Whether the operator with `id`, `name`, and `addr` is analyzed
+    pub fn keeps_operator(&self, id: usize, name: &str, addr: &[usize]) -> bool {
+        self.operators.as_ref().map_or(true, |operators| operators.iter().any(|o| o.matches(id, name, addr)))
+    }
+}
@@ -1,126 +1,242 @@
 //! Replays event streams from Timely / Differential
-//! and constructs a stream of LogRecords from them.
+//! and constructs a stream of LogRecords (or columnar batches of them) from them.
 #![deny(missing_docs)]
 
 #[macro_use]
 extern crate log;
 
 pub mod connect;
-use crate::connect::{Replayer, CompEvent};
+use crate::connect::CompEvent;
 pub mod replay_throttled;
-use crate::replay_throttled::ReplayThrottled;
+use crate::replay_throttled::{ReplayThrottled, ReplaySpeed, PaceEvents};
+pub mod filter;
+use crate::filter::Filter;
+pub mod diagnostics;
+pub mod operators;
+pub mod origins;
+pub mod mmap;
+pub mod prefetch;
+pub mod synthetic;
+pub mod faults;
+use crate::diagnostics::StageTimer;
 
 use st2_logformat::{ActivityType, EventType, LogRecord};
+use st2_logformat::batch::LogRecordBatch;
 use st2_logformat::pair::Pair;
 
-use std::io::Read;
 use std::time::Duration;
+use std::sync::{Arc, atomic::AtomicBool};
 
 use timely::{
     dataflow::{
         channels::pact::Pipeline,
+        operators::capture::event::EventIterator,
         operators::generic::operator::Operator,
         Scope, Stream,
     },
     logging::{
         StartStop,
-        TimelyEvent::{Messages, Operates, Progress, Schedule},
+        TimelyEvent::{Channels, Messages, Operates, Progress, Schedule, Text},
     },
 };
 
 /// Returns a `Stream` of `LogRecord`s that can be used for PAG construction.
-pub fn create_lrs<S, R>(
+/// Replay stops early once `is_running` is unset and is paced according to `speed`.
+/// Only events selected by `filter` are converted to `LogRecord`s.
+pub fn create_lrs<S, I>(
     scope: &mut S,
-    replayers: Vec<Replayer<S::Timestamp, R>>,
+    replayers: Vec<I>,
     index: usize,
+    is_running: Option<Arc<AtomicBool>>,
     throttle: u64,
+    speed: ReplaySpeed,
+    filter: Filter,
 ) -> Stream<S, LogRecord>
 where
     S: Scope<Timestamp = Pair<u64, Duration>>,
-    R: Read + 'static,
+    I: EventIterator<Pair<u64, Duration>, CompEvent> + 'static,
 {
-    replayers
-        .replay_throttled_into(index, scope, None, throttle)
-        .construct_lrs(index)
+    replay(scope, replayers, index, is_running, throttle, speed).construct_lrs(index, filter)
+}
+
+/// Like `create_lrs`, but returns the `LogRecord`s in columnar batches, one per
+/// batch of replayed events (cf. `LogRecordBatch`).
+pub fn create_lr_batches<S, I>(
+    scope: &mut S,
+    replayers: Vec<I>,
+    index: usize,
+    is_running: Option<Arc<AtomicBool>>,
+    throttle: u64,
+    speed: ReplaySpeed,
+    filter: Filter,
+) -> Stream<S, LogRecordBatch>
+where
+    S: Scope<Timestamp = Pair<u64, Duration>>,
+    I: EventIterator<Pair<u64, Duration>, CompEvent> + 'static,
+{
+    replay(scope, replayers, index, is_running, throttle, speed).construct_lr_batches(index, filter)
+}
+
+/// Replays the events of `replayers`, cf. `create_lrs`.
+fn replay<S, I>(
+    scope: &mut S,
+    replayers: Vec<I>,
+    index: usize,
+    is_running: Option<Arc<AtomicBool>>,
+    throttle: u64,
+    speed: ReplaySpeed,
+) -> Stream<S, CompEvent>
+where
+    S: Scope<Timestamp = Pair<u64, Duration>>,
+    I: EventIterator<Pair<u64, Duration>, CompEvent> + 'static,
+{
+    let events = replayers.replay_throttled_into(index, scope, is_running, throttle, speed);
+
+    if let ReplaySpeed::Original(factor) = speed {
+        events.pace_events(factor)
+    } else {
+        events
+    }
 }
 
 /// Operator that converts a Stream of TimelyEvents to their LogRecord representation
 pub trait ConstructLRs<S: Scope<Timestamp = Pair<u64, Duration>>> {
-    /// Constructs a stream of log records to be used in PAG construction from an event stream.
-    fn construct_lrs(&self, index: usize) -> Stream<S, LogRecord>;
+    /// Constructs a stream of log records to be used in PAG construction from
+    /// the events of an event stream selected by `filter`.
+    fn construct_lrs(&self, index: usize, filter: Filter) -> Stream<S, LogRecord>;
+    /// Like `construct_lrs`, but in columnar batches of log records.
+    fn construct_lr_batches(&self, index: usize, filter: Filter) -> Stream<S, LogRecordBatch>;
     /// Strips an event `Stream` of encompassing operators
     /// (e.g. the dataflow operator for every direct child,
-    /// the surrounding iterate operators for loops).
-    fn peel_ops(&self, index: usize) -> Stream<S, CompEvent>;
+    /// the surrounding iterate operators for loops),
+    /// and of all events not selected by `filter`.
+    fn peel_ops(&self, index: usize, filter: Filter) -> Stream<S, CompEvent>;
     /// Makes a stream of log records from an event stream.
     fn make_lrs(&self, index: usize) -> Stream<S, LogRecord>;
+    /// Makes a stream of columnar batches of log records from an event stream.
+    fn make_lr_batches(&self, index: usize) -> Stream<S, LogRecordBatch>;
     /// Builds a log record at differential time `time` from the supplied computation event.
     fn build_lr(comp_event: CompEvent) -> Option<LogRecord>;
 }
 
 impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructLRs<S> for Stream<S, CompEvent>
 {
-    fn construct_lrs(&self, index: usize) -> Stream<S, LogRecord> {
-        self.peel_ops(index)
+    fn construct_lrs(&self, index: usize, filter: Filter) -> Stream<S, LogRecord> {
+        self.peel_ops(index, filter)
             .make_lrs(index)
     }
 
-    fn peel_ops(&self, _index: usize) -> Stream<S, CompEvent> {
+    fn construct_lr_batches(&self, index: usize, filter: Filter) -> Stream<S, LogRecordBatch> {
+        self.peel_ops(index, filter)
+            .make_lr_batches(index)
+    }
+
+    fn peel_ops(&self, index: usize, filter: Filter) -> Stream<S, CompEvent> {
         let mut vector = Vec::new();
-        let mut outer_operates = std::collections::BTreeSet::new();
-        let mut ids_to_addrs = std::collections::HashMap::new();
+        // the kept events of a batch, given to the output at once; the output
+        // hands back a recycled buffer (cf. `Session::give_vec`)
+        let mut kept = Vec::new();
+        // Operator metadata (names, addresses) is shared through `operators`; all
+        // `Schedule` events need is whether their operator is kept: it isn't if it's
+        // a scope (i.e., has children), or not selected by `filter`.
+        let mut kept_ops: std::collections::HashMap<usize, bool> = std::collections::HashMap::new();
+        // address -> id of the operators seen so far, and addresses of scopes
+        let mut addrs_to_ids = std::collections::HashMap::new();
+        let mut outer_operates = std::collections::HashSet::new();
 
+        let mut timer = StageTimer::new("Peel", index);
         self.unary(Pipeline, "Peel", move |_, _| { move |input, output| {
-            input.for_each(|cap, data| {
+            input.for_each(|cap, data| timer.time(data.len(), || {
                 data.swap(&mut vector);
                 for (epoch, seq_no, length, (t, wid, x)) in vector.drain(..) {
+                    if let Channels(ref e) = x {
+                        crate::operators::record_channel(e.id, &e.scope_addr, e.source, e.target);
+                    }
                     match x {
+                        // the worker's origin, announced at epoch 0 (cf. `origins`)
+                        Text(ref text) if crate::origins::record(wid as u64, text) => {}
                         Operates(e) => {
                             if wid == 0 {
                                 // Dataflow structure logging
                                 info!("{:?}", e);
                             }
+                            crate::operators::record(e.id, &e.name, &e.addr);
+
+                            // scopes are logged after their children
                             let mut addr = e.addr.clone();
                             addr.pop();
+                            if let Some(parent) = addrs_to_ids.get(&addr) {
+                                kept_ops.insert(*parent, false);
+                            }
                             outer_operates.insert(addr);
 
-                            ids_to_addrs.insert(e.id, e.addr);
+                            let keeps = !outer_operates.contains(&e.addr) && filter.keeps_operator(e.id, &e.name, &e.addr);
+                            kept_ops.insert(e.id, keeps);
+                            addrs_to_ids.insert(e.addr, e.id);
                         }
                         Schedule(ref e) => {
                             assert!(cap.time() > &Pair::new(0, Default::default()));
 
                             // @TODO: For LBF > 1, we might not have seen all `Operates` events
                             // at all workers, so this fails.
-                            let addr = ids_to_addrs.get(&e.id).expect("operates went wrong");
-                            if !outer_operates.contains(addr) {
-                                output.session(&cap).give((epoch, seq_no, length, (t, wid, x)));
+                            let keeps = *kept_ops.get(&e.id).expect("operates went wrong");
+                            if keeps && filter.keeps(epoch, wid) {
+                                kept.push((epoch, seq_no, length, (t, wid, x)));
                             }
                         }
                         _ => {
                             assert!(cap.time() > &Pair::new(0, Default::default()));
 
-                            output.session(&cap).give((epoch, seq_no, length, (t, wid, x)));
+                            if filter.keeps(epoch, wid) {
+                                kept.push((epoch, seq_no, length, (t, wid, x)));
+                            }
                         }
                     }
                 }
-            });
+                output.session(&cap).give_vec(&mut kept);
+            }));
         }})
     }
 
-    fn make_lrs(&self, _index: usize) -> Stream<S, LogRecord> {
+    fn make_lrs(&self, index: usize) -> Stream<S, LogRecord> {
         let mut vector = Vec::new();
+        // the records of a batch, given to the output at once (cf. `peel_ops`)
+        let mut records = Vec::new();
 
+        let mut timer = StageTimer::new("LogRecordConstruct", index);
         self.unary(Pipeline, "LogRecordConstruct", move |_, _| { move |input, output| {
-            input.for_each(|cap, data| {
+            input.for_each(|cap, data| timer.time(data.len(), || {
                 data.swap(&mut vector);
-                output.session(&cap).give_iterator(vector.drain(..).flat_map(|x| Self::build_lr(x).into_iter()));
-            });
+                records.reserve(vector.len());
+                for event in vector.drain(..) {
+                    if let Some(record) = Self::build_lr(event) {
+                        records.push(record);
+                    }
+                }
+                output.session(&cap).give_vec(&mut records);
+            }));
+        }})
+    }
+
+    fn make_lr_batches(&self, index: usize) -> Stream<S, LogRecordBatch> {
+        let mut vector = Vec::new();
+
+        let mut timer = StageTimer::new("LogRecordConstruct", index);
+        self.unary(Pipeline, "LogRecordConstruct", move |_, _| { move |input, output| {
+            input.for_each(|cap, data| timer.time(data.len(), || {
+                data.swap(&mut vector);
+                let batch: LogRecordBatch = vector.drain(..).filter_map(Self::build_lr).collect();
+                if !batch.is_empty() {
+                    output.session(&cap).give(batch);
+                }
+            }));
         }})
     }
 
     fn build_lr(comp_event: CompEvent) -> Option<LogRecord> {
         let (epoch, seq_no, length, (timestamp, wid, x)) = comp_event;
         let local_worker = wid as u64;
+        let origin = crate::origins::get(local_worker);
 
         match x {
             // Scheduling & Processing
@@ -143,6 +259,8 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructLRs<S> for Stream<S, Co
                     channel_id: None,
                     correlator_id: None,
                     length,
+                    origin,
+                    tagged: Vec::new(),
                 })
             }
             // remote data messages
@@ -172,7 +290,9 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructLRs<S> for Stream<S, Co
                     operator_id: None,
                     channel_id: Some(event.channel as u64),
                     correlator_id: Some(event.seq_no as u64),
-                    length
+                    length,
+                    origin,
+                    tagged: Vec::new(),
                 })
             }
             // Control Messages
@@ -203,6 +323,8 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructLRs<S> for Stream<S, Co
                     channel_id: Some(event.channel as u64),
                     correlator_id: Some(event.seq_no as u64),
                     length: None,
+                    origin,
+                    tagged: Vec::new(),
                 })
             }
             // Channels / Operates events
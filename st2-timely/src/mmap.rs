@@ -0,0 +1,87 @@
+//! Zero-copy offline replay: reads `*.dump` files through a memory map and
+//! decodes their abomonated events in place, instead of copying them into a
+//! read buffer first (cf. `timely`'s `EventReader`). This pays off for
+//! multi-GB traces, where the copies dominate replay.
+//!
+//! Files are mapped copy-on-write: decoding patches the events' pointers in
+//! the mapping, which copies only the touched pages and never writes to the
+//! file. Decoding only checks that the events' lengths fit into the file, and
+//! replay of a file stops at the first event that doesn't, like it does at a
+//! truncated one; events whose progress updates are empty are rejected, too.
+//!
+//! This is not validation: like `EventReader`, decoding trusts the bytes to be
+//! abomonated events (valid enum discriminants, UTF-8 strings), and anything
+//! else is undefined behavior. Only map complete files written by a trusted
+//! source computation's `EventWriter`; they can't be followed while the source
+//! computation is still writing them.
+
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use memmap::{MmapMut, MmapOptions};
+use timely::dataflow::operators::capture::event::{Event, EventIterator};
+
+use st2_logformat::pair::Pair;
+
+use crate::connect::{CompEvent, TraceEvent};
+
+/// Replays the events of a memory-mapped `*.dump` file
+pub struct MmapReplayer {
+    /// Path of the file, for diagnostics
+    path: PathBuf,
+    /// The file's contents; `None` for empty files, which can't be mapped
+    mmap: Option<MmapMut>,
+    /// Offset of the next event to decode
+    offset: usize,
+}
+
+impl MmapReplayer {
+    /// Maps the `*.dump` file at `path`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let mmap = if file.metadata()?.len() > 0 {
+            // Safety: the mapping is private, and trace files aren't modified once complete
+            Some(unsafe { MmapOptions::new().map_copy(&file)? })
+        } else {
+            None
+        };
+        Ok(MmapReplayer { path: path.to_path_buf(), mmap, offset: 0 })
+    }
+}
+
+impl EventIterator<Pair<u64, Duration>, CompEvent> for MmapReplayer {
+    fn next(&mut self) -> Option<&TraceEvent> {
+        let mmap = self.mmap.as_mut()?;
+        let len = mmap.len();
+        if self.offset >= len {
+            return None;
+        }
+
+        let bytes = &mut mmap[self.offset ..];
+        let available = bytes.len();
+        // Safety: `decode` checks that all lengths fit into `bytes`; the
+        // contents are trusted to be an abomonated event (cf. the module docs)
+        match unsafe { abomonation::decode::<TraceEvent>(bytes) } {
+            Some((event, rest)) if is_valid(event) => {
+                self.offset += available - rest.len();
+                Some(event)
+            }
+            _ => {
+                warn!("{}: invalid event at byte {}, skipping the remaining {} bytes", self.path.display(), self.offset, len - self.offset);
+                self.offset = len;
+                None
+            }
+        }
+    }
+}
+
+/// Whether a decoded `event` can be replayed; a sanity check, not validation
+fn is_valid(event: &TraceEvent) -> bool {
+    match event {
+        // replay orders events by their progress updates' first time
+        Event::Progress(updates) => !updates.is_empty(),
+        Event::Messages(_, _) => true,
+    }
+}
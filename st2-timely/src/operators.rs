@@ -0,0 +1,81 @@
+//! The operators and channels of the source computation as replay has seen them:
+//! operators' names and addresses by id, so analyses can show and group operators
+//! by more than their ids (e.g. to apply `st2 --labels` entries selected by name
+//! or address), and the channels between them, to reconstruct the logical
+//! dataflow graph (e.g. `st2 graph`).
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+
+/// An operator of the source computation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Operator {
+    /// Name of the operator, e.g. `Map`
+    pub name: String,
+    /// Address of the operator in its dataflow, e.g. `[0, 2, 1]`
+    pub addr: Vec<usize>,
+}
+
+/// A channel between two operators of the source computation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Channel {
+    /// Id of the channel
+    pub id: usize,
+    /// Address of the scope the channel is in
+    pub scope_addr: Vec<usize>,
+    /// (operator index in the scope, output port) of the channel's source;
+    /// index 0 is the scope's own inputs
+    pub source: (usize, usize),
+    /// (operator index in the scope, input port) of the channel's target;
+    /// index 0 is the scope's own outputs
+    pub target: (usize, usize),
+}
+
+/// Operators seen by replay in this process, by id
+static OPERATORS: Lazy<RwLock<HashMap<usize, Operator>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+/// Channels seen by replay in this process, by id
+static CHANNELS: Lazy<RwLock<HashMap<usize, Channel>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Records the operator with `id`, `name`, and `addr`, unless it's known already.
+pub fn record(id: usize, name: &str, addr: &[usize]) {
+    if OPERATORS.read().expect("operators poisoned").contains_key(&id) {
+        return;
+    }
+    OPERATORS.write().expect("operators poisoned")
+        .entry(id)
+        .or_insert_with(|| Operator { name: name.to_string(), addr: addr.to_vec() });
+}
+
+/// The operator with `id`, if replay has seen it
+pub fn get(id: usize) -> Option<Operator> {
+    OPERATORS.read().expect("operators poisoned").get(&id).cloned()
+}
+
+/// All operators replay has seen, by id
+pub fn all() -> Vec<(usize, Operator)> {
+    let mut operators: Vec<_> = OPERATORS.read().expect("operators poisoned").iter()
+        .map(|(id, operator)| (*id, operator.clone()))
+        .collect();
+    operators.sort_by_key(|(id, _)| *id);
+    operators
+}
+
+/// Records the channel with `id` in the scope at `scope_addr` from `source` to
+/// `target`, unless it's known already.
+pub fn record_channel(id: usize, scope_addr: &[usize], source: (usize, usize), target: (usize, usize)) {
+    if CHANNELS.read().expect("channels poisoned").contains_key(&id) {
+        return;
+    }
+    CHANNELS.write().expect("channels poisoned")
+        .entry(id)
+        .or_insert_with(|| Channel { id, scope_addr: scope_addr.to_vec(), source, target });
+}
+
+/// All channels replay has seen, by id
+pub fn channels() -> Vec<Channel> {
+    let mut channels: Vec<_> = CHANNELS.read().expect("channels poisoned").values().cloned().collect();
+    channels.sort_by_key(|channel| channel.id);
+    channels
+}
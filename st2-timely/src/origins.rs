@@ -0,0 +1,72 @@
+//! Where the workers of the source computation run, so that their `LogRecord`s can
+//! be given an `Origin` (cf. `st2_logformat::Origin`): `PAGLogger` announces every
+//! worker's host, process, and thread as a `Text` event at epoch 0, and replay
+//! records the announcements it sees. Hosts are interned in a name table shared by
+//! all replays of this process (cf. `host_name`).
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use once_cell::sync::Lazy;
+
+use st2_logformat::Origin;
+use st2_logformat::names::{NameId, NameTable};
+
+/// Prefix of the `Text` events that announce a worker's origin
+const ANNOUNCEMENT: &str = "st2:origin ";
+
+/// Origins announced to replay in this process, by worker
+static ORIGINS: Lazy<RwLock<HashMap<u64, Origin>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+/// Hosts of `ORIGINS`
+static HOSTS: Lazy<RwLock<NameTable>> = Lazy::new(|| RwLock::new(NameTable::default()));
+
+/// Ids handed out to threads by `announcement`
+static THREADS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Process-unique id of the current thread
+    static THREAD_ID: u64 = THREADS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The text announcing the origin of the current thread's worker:
+/// `st2:origin <process id> <thread id> <host>`
+pub fn announcement() -> String {
+    let host = hostname::get()
+        .map(|host| host.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "localhost".to_string());
+    THREAD_ID.with(|thread| format!("{}{} {} {}", ANNOUNCEMENT, std::process::id(), thread, host))
+}
+
+/// Records the origin of `worker` if `text` is an `announcement`, interning
+/// its host. Returns whether it was one.
+pub fn record(worker: u64, text: &str) -> bool {
+    let parse = || {
+        if !text.starts_with(ANNOUNCEMENT) {
+            return None;
+        }
+        let mut fields = text[ANNOUNCEMENT.len() ..].splitn(3, ' ');
+        let process_id = fields.next()?.parse().ok()?;
+        let thread_id = fields.next()?.parse().ok()?;
+        let host = fields.next().filter(|host| !host.is_empty())?;
+        Some((host, process_id, thread_id))
+    };
+    match parse() {
+        Some((host, process_id, thread_id)) => {
+            let host = HOSTS.write().expect("hosts poisoned").intern(host);
+            ORIGINS.write().expect("origins poisoned").insert(worker, Origin { host, process_id, thread_id });
+            true
+        }
+        None => false,
+    }
+}
+
+/// The origin of `worker`, if it was announced
+pub fn get(worker: u64) -> Option<Origin> {
+    ORIGINS.read().expect("origins poisoned").get(&worker).copied()
+}
+
+/// The name of `host`, as interned by `record`
+pub fn host_name(host: NameId) -> Option<String> {
+    HOSTS.read().expect("hosts poisoned").resolve(host).map(str::to_string)
+}
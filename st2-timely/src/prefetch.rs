@@ -0,0 +1,114 @@
+//! Parallel reading of multi-file traces: every `*.dump` file gets an I/O
+//! thread that reads and decodes its events ahead of replay and hands them to
+//! the replay operator through a bounded queue. Files are thus decoded in
+//! parallel, also when a replay worker reads several of them, and offline
+//! analysis is bounded by analysis throughput rather than by decoding.
+//!
+//! Every file's progress is tracked separately, cf. `files`.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use timely::dataflow::operators::capture::event::{Event, EventIterator};
+
+use st2_logformat::pair::Pair;
+
+use crate::connect::{CompEvent, TraceEvent};
+
+/// How long an I/O thread waits before reading its file again if it had no new
+/// events, e.g. because the file is still being written
+const IDLE_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Progress of the files being read by I/O threads of this process
+static FILES: Lazy<Mutex<Vec<Arc<FileProgress>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// How far an I/O thread has read its file
+#[derive(Debug)]
+pub struct FileProgress {
+    /// Name of the file, e.g. its path
+    pub name: String,
+    /// Size of the file in bytes when reading started
+    pub size: u64,
+    bytes: AtomicU64,
+    events: AtomicU64,
+}
+
+impl FileProgress {
+    /// Bytes decoded so far, i.e. the size of the decoded events as encoded in the file
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Events decoded so far
+    pub fn events(&self) -> u64 {
+        self.events.load(Ordering::Relaxed)
+    }
+}
+
+/// The progress of all files read by I/O threads of this process, in the order
+/// reading started
+pub fn files() -> Vec<Arc<FileProgress>> {
+    FILES.lock().expect("file progress poisoned").clone()
+}
+
+/// Replays the events an I/O thread decodes
+pub struct Prefetcher {
+    events: Receiver<TraceEvent>,
+    /// The event `next` returned last
+    current: Option<TraceEvent>,
+    /// Tells the I/O thread to stop, once replay is done with the file
+    closed: Arc<AtomicBool>,
+}
+
+impl Prefetcher {
+    /// Spawns an I/O thread reading `source`, the file `name` of `size` bytes,
+    /// that decodes up to `capacity` events ahead of replay.
+    pub fn spawn<I>(name: String, size: u64, mut source: I, capacity: usize) -> std::io::Result<Self>
+    where I: EventIterator<Pair<u64, Duration>, CompEvent> + Send + 'static {
+        let progress = Arc::new(FileProgress { name: name.clone(), size, bytes: AtomicU64::new(0), events: AtomicU64::new(0) });
+        FILES.lock().expect("file progress poisoned").push(Arc::clone(&progress));
+
+        let (sender, events) = mpsc::sync_channel(capacity);
+        let closed = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&closed);
+        std::thread::Builder::new()
+            .name(format!("st2-read {}", name))
+            .spawn(move || {
+                while !stop.load(Ordering::Acquire) {
+                    match source.next() {
+                        Some(event) => {
+                            progress.bytes.fetch_add(abomonation::measure(event) as u64, Ordering::Relaxed);
+                            if let Event::Messages(_, data) = event {
+                                progress.events.fetch_add(data.len() as u64, Ordering::Relaxed);
+                            }
+                            if sender.send(event.clone()).is_err() {
+                                // replay is done with the file
+                                return;
+                            }
+                        }
+                        // the end of the file, for now
+                        None => std::thread::sleep(IDLE_BACKOFF),
+                    }
+                }
+            })?;
+
+        Ok(Prefetcher { events, current: None, closed })
+    }
+}
+
+impl EventIterator<Pair<u64, Duration>, CompEvent> for Prefetcher {
+    fn next(&mut self) -> Option<&TraceEvent> {
+        // nothing decoded yet, or the I/O thread failed
+        self.current = Some(self.events.try_recv().ok()?);
+        self.current.as_ref()
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Release);
+    }
+}
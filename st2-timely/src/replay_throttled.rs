@@ -4,31 +4,211 @@
 //! and throttling the number of epochs in flight that are introduced by it.
 //! It also provides events in order from multiple files. For this to work
 //! properly, all events of one epoch have to be written to the same file.
+//! Optionally, replay is paced according to the recorded times of the trace,
+//! either per progress update or per event (cf. `PaceEvents`).
 
-use std::sync::{Arc, atomic::AtomicBool, atomic::Ordering};
+use std::sync::{Arc, atomic::AtomicBool, atomic::AtomicU64, atomic::AtomicUsize, atomic::Ordering};
+use std::str::FromStr;
+use std::time::Instant;
+use std::collections::HashSet;
 
 use timely::{Data, dataflow::{Scope, Stream}};
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::Capability;
+use timely::dataflow::operators::generic::operator::Operator;
 use timely::dataflow::channels::pushers::{Counter as PushCounter, buffer::Buffer as PushBuffer};
 use timely::dataflow::operators::generic::builder_raw::OperatorBuilder;
 use timely::progress::frontier::MutableAntichain;
 
 use timely::dataflow::operators::capture::event::{Event, EventIterator};
 
+use abomonation::Abomonation;
+
 use st2_logformat::pair::Pair;
 use std::time::Duration;
 
+use crate::connect::CompEvent;
+use crate::diagnostics::IngestRate;
+
+/// How long replay waits before polling its sources again if none of them had new
+/// events, e.g. while following a trace file that is still being written.
+const IDLE_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Replay progress of all replay operators of this process, e.g. to report the
+/// progress of long offline analyses.
+pub static PROGRESS: ReplayProgress = ReplayProgress {
+    input_bytes: AtomicU64::new(0),
+    bytes: AtomicU64::new(0),
+    events: AtomicU64::new(0),
+    epoch: AtomicU64::new(0),
+    started: AtomicUsize::new(0),
+    finished: AtomicUsize::new(0),
+    truncated: AtomicU64::new(std::u64::MAX),
+};
+
+/// Counters of replayed data, cf. `PROGRESS`
+pub struct ReplayProgress {
+    input_bytes: AtomicU64,
+    bytes: AtomicU64,
+    events: AtomicU64,
+    epoch: AtomicU64,
+    started: AtomicUsize,
+    finished: AtomicUsize,
+    /// The first epoch cut short, `u64::MAX` if none
+    truncated: AtomicU64,
+}
+
+/// The state of a `ReplayProgress` at some point in time
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProgressSnapshot {
+    /// Total size of the replayed inputs in bytes, as far as known
+    pub input_bytes: u64,
+    /// Bytes replayed so far, i.e. the size of all replayed events as encoded in `*.dump` files
+    pub bytes: u64,
+    /// Events replayed so far
+    pub events: u64,
+    /// The highest epoch replay has advanced to
+    pub epoch: u64,
+    /// Whether replay has started
+    pub started: bool,
+    /// Whether all replay operators have finished
+    pub done: bool,
+    /// The first epoch replay was stopped in before all its events were
+    /// replayed, if it was stopped (cf. `ReplayProgress::is_truncated`)
+    pub truncated: Option<u64>,
+}
+
+impl ReplayProgress {
+    /// Adds an input of `bytes` bytes to be replayed.
+    pub fn add_input(&self, bytes: u64) {
+        self.input_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// The current progress
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let started = self.started.load(Ordering::Acquire);
+        ProgressSnapshot {
+            input_bytes: self.input_bytes.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            events: self.events.load(Ordering::Relaxed),
+            epoch: self.epoch.load(Ordering::Relaxed),
+            started: started > 0,
+            done: started > 0 && self.finished.load(Ordering::Acquire) == started,
+            truncated: Some(self.truncated.load(Ordering::Acquire)).filter(|epoch| *epoch != std::u64::MAX),
+        }
+    }
+
+    /// Whether replay was stopped before all events of `epoch` were replayed:
+    /// some of them may have been replayed, so results of the epoch would be
+    /// partial. Epochs from the first one cut short on are truncated.
+    pub fn is_truncated(&self, epoch: u64) -> bool {
+        epoch >= self.truncated.load(Ordering::Acquire)
+    }
+
+    /// Forgets truncated epochs, e.g. before replaying another trace whose
+    /// epochs are numbered anew.
+    pub fn reset_truncated(&self) {
+        self.truncated.store(std::u64::MAX, Ordering::Release);
+    }
+
+    fn truncate_at(&self, epoch: u64) {
+        let mut current = self.truncated.load(Ordering::Acquire);
+        while current > epoch {
+            match self.truncated.compare_exchange_weak(current, epoch, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    fn advance_to(&self, epoch: u64) {
+        let mut current = self.epoch.load(Ordering::Relaxed);
+        while current < epoch {
+            match self.epoch.compare_exchange_weak(current, epoch, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+/// How fast a trace is replayed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplaySpeed {
+    /// Replay as fast as possible
+    Unbounded,
+    /// Replay `n` times as fast as the trace was recorded (`1.0` is real time).
+    /// Progress is only made once the recorded time of the progress update is
+    /// reached, so epochs complete at the (scaled) rate they completed originally.
+    Factor(f64),
+    /// Like `Factor`, but additionally re-paces individual events according to
+    /// their recorded inter-arrival gaps (cf. `PaceEvents`).
+    Original(f64),
+}
+
+impl ReplaySpeed {
+    /// Whether a trace event recorded at `time` is due for replay,
+    /// given replay started at `origin = (recorded time, wall-clock time)`.
+    pub fn is_due(&self, time: Duration, origin: Option<(Duration, Instant)>) -> bool {
+        match (self, origin) {
+            (ReplaySpeed::Factor(factor), Some((recorded, started))) |
+            (ReplaySpeed::Original(factor), Some((recorded, started))) =>
+                time <= recorded + started.elapsed().mul_f64(*factor),
+            _ => true,
+        }
+    }
+
+    /// When a trace event recorded at `time` is due for replay (cf. `is_due`),
+    /// `None` if it's due right away.
+    pub fn due(&self, time: Duration, origin: Option<(Duration, Instant)>) -> Option<Instant> {
+        match (self, origin) {
+            (ReplaySpeed::Factor(factor), Some((recorded, started))) |
+            (ReplaySpeed::Original(factor), Some((recorded, started))) =>
+                Some(started + time.checked_sub(recorded).unwrap_or_default().div_f64(*factor)),
+            _ => None,
+        }
+    }
+}
+
+impl Default for ReplaySpeed {
+    fn default() -> Self {
+        ReplaySpeed::Unbounded
+    }
+}
+
+impl FromStr for ReplaySpeed {
+    type Err = String;
+
+    /// Parses `max` (as fast as possible), `realtime`, or `<n>x` (e.g. `2x`, `0.5x`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max" => Ok(ReplaySpeed::Unbounded),
+            "realtime" => Ok(ReplaySpeed::Factor(1.0)),
+            _ if s.ends_with('x') => {
+                let factor: f64 = s[.. s.len() - 1].parse().map_err(|e| format!("{}: {}", s, e))?;
+                if factor > 0.0 && factor.is_finite() {
+                    Ok(ReplaySpeed::Factor(factor))
+                } else {
+                    Err(format!("{}: speed has to be positive", s))
+                }
+            }
+            _ => Err(format!("{}: expected max, realtime, or <n>x", s)),
+        }
+    }
+}
+
 /// Replay a capture stream into a scope with the same timestamp.
 /// This replay operator preserves ordering across an arbitrary amount of files,
 /// and can control how many epochs should be put into flight simultaneously.
 pub trait ReplayThrottled<D: Data + std::fmt::Debug> {
     /// Replays `self` into the provided scope, as a `Stream<S, D>`.
-    fn replay_throttled_into<S: Scope<Timestamp=Pair<u64, Duration>>>(self, worker: usize, scope: &mut S, is_running: Option<Arc<AtomicBool>>, epochs_in_flight: u64) -> Stream<S, D>;
+    fn replay_throttled_into<S: Scope<Timestamp=Pair<u64, Duration>>>(self, worker: usize, scope: &mut S, is_running: Option<Arc<AtomicBool>>, epochs_in_flight: u64, speed: ReplaySpeed) -> Stream<S, D>;
 }
 
-impl<D: Data + std::fmt::Debug, I> ReplayThrottled<D> for I
+impl<D: Data + std::fmt::Debug + Abomonation, I> ReplayThrottled<D> for I
 where I : IntoIterator,
       <I as IntoIterator>::Item: EventIterator<Pair<u64, Duration>, D>+'static {
-    fn replay_throttled_into<S: Scope<Timestamp=Pair<u64, Duration>>>(self, worker: usize, scope: &mut S, is_running: Option<Arc<AtomicBool>>, epochs_in_flight: u64) -> Stream<S, D> {
+    fn replay_throttled_into<S: Scope<Timestamp=Pair<u64, Duration>>>(self, worker: usize, scope: &mut S, is_running: Option<Arc<AtomicBool>>, epochs_in_flight: u64, speed: ReplaySpeed) -> Stream<S, D> {
         let mut builder = OperatorBuilder::new("ReplayThrottled".to_owned(), scope.clone());
 
         let address = builder.operator_info().address;
@@ -45,10 +225,16 @@ where I : IntoIterator,
         let mut antichain: MutableAntichain<Pair<u64, Duration>> = MutableAntichain::new();
 
         let mut started = false;
+        // (recorded time, wall-clock time) of the first progress update
+        let mut pace_origin: Option<(Duration, Instant)> = None;
 
         let mut total_events = 0;
         let mut total_time = 0;
+        let mut ingest = IngestRate::new(worker);
+        let mut frontier_epoch = 0;
         let mut done = false;
+        let mut finished = false;
+        PROGRESS.started.fetch_add(1, Ordering::AcqRel);
 
         builder.build(
             move |_frontier| { },
@@ -65,6 +251,9 @@ where I : IntoIterator,
                     started = true;
                 }
 
+                // whether no sources had new events
+                let mut idle = true;
+
                 let running = if let Some(x) = &is_running {
                     x.load(Ordering::Acquire)
                 } else {
@@ -77,20 +266,31 @@ where I : IntoIterator,
 
                     if let Some(f) = frontier {
                         // apply future progress where possible
-                        future_progress.iter().for_each(|vec| {
-                            if vec[0].0.first <= f.first + epochs_in_flight {
-                                antichain.update_iter(vec.iter().cloned());
-                                internal[0].extend(vec.iter().cloned());
-                            }
-                        });
-                        future_progress.retain(|vec| vec[0].0.first > f.first + epochs_in_flight);
+                        let (due, pending): (Vec<_>, Vec<_>) = future_progress
+                            .drain(..)
+                            .partition(|vec| vec[0].0.first <= f.first + epochs_in_flight && speed.is_due(vec[0].0.second, pace_origin));
+                        future_progress = pending;
+                        idle = due.is_empty();
+
+                        for vec in due.iter() {
+                            antichain.update_iter(vec.iter().cloned());
+                            internal[0].extend(vec.iter().cloned());
+                        }
 
                         // consume new events
                         for event_stream in event_streams.iter_mut() {
                             while let Some(event) = event_stream.next() {
+                                idle = false;
+                                let bytes = abomonation::measure(event) as u64;
+                                PROGRESS.bytes.fetch_add(bytes, Ordering::Relaxed);
+                                ingest.add(if let Event::Messages(_, data) = event { data.len() as u64 } else { 0 }, bytes);
                                 match event {
                                     Event::Progress(ref vec) => {
-                                        if vec[0].0.first <= f.first + epochs_in_flight {
+                                        if vec[0].0.first <= f.first + epochs_in_flight && speed.is_due(vec[0].0.second, pace_origin) {
+                                            if pace_origin.is_none() && vec[0].0.second > Default::default() {
+                                                pace_origin = Some((vec[0].0.second, Instant::now()));
+                                            }
+
                                             antichain.update_iter(vec.iter().cloned());
                                             internal[0].extend(vec.iter().cloned());
                                         } else {
@@ -98,7 +298,10 @@ where I : IntoIterator,
                                             break;
                                         }
                                     },
-                                    Event::Messages(time, data) => { buffer.push((time.clone(), data.clone())); }
+                                    Event::Messages(time, data) => {
+                                        PROGRESS.events.fetch_add(data.len() as u64, Ordering::Relaxed);
+                                        buffer.push((time.clone(), data.clone()));
+                                    }
                                 }
                             }
                         }
@@ -108,6 +311,12 @@ where I : IntoIterator,
                         let curr_f = curr_f.get(0);
 
                         if let Some(curr_f) = curr_f {
+                            PROGRESS.advance_to(curr_f.first);
+                            if curr_f.first > frontier_epoch {
+                                frontier_epoch = curr_f.first;
+                                tracing::debug!(worker, epoch = frontier_epoch, "frontier advanced");
+                            }
+
                             // sort buffered events by time
                             buffer.sort_by_key(|(time, _data)| time.clone());
 
@@ -131,18 +340,39 @@ where I : IntoIterator,
                     } else {
                         if !done {
                             total_time += timer.elapsed().as_nanos();
-                            info!("w{} replay_throttled: total {}ms", worker, total_time / 1_000_000);
-                            info!("w{} replayed {} messages", worker, total_events);
+                            tracing::info!(worker, total_ms = (total_time / 1_000_000) as u64, events = total_events as u64, "replay finished");
                             done = true;
                         }
+                        if !finished {
+                            PROGRESS.finished.fetch_add(1, Ordering::AcqRel);
+                            finished = true;
+                        }
                     }
 
-                    // Always reschedule `replay`.
-                    activator.activate();
+                    // Always reschedule `replay`, polling idle sources less often.
+                    if idle {
+                        activator.activate_after(IDLE_BACKOFF);
+                    } else {
+                        activator.activate();
+                    }
 
                     output.cease();
                     output.inner().produced().borrow_mut().drain_into(&mut produced[0]);
                 } else {
+                    // close sources
+                    event_streams.clear();
+                    if !finished {
+                        PROGRESS.finished.fetch_add(1, Ordering::AcqRel);
+                        finished = true;
+                    }
+
+                    // the epoch of the frontier is incomplete, and so are
+                    // those after it; they're marked truncated before their
+                    // capabilities are released, so they don't appear complete
+                    if let Some(frontier) = antichain.frontier().first() {
+                        tracing::info!(worker, epoch = frontier.first, "replay stopped, epoch cut short");
+                        PROGRESS.truncate_at(frontier.first);
+                    }
                     while !antichain.is_empty() {
                         let elements = antichain.frontier().iter().map(|t| (t.clone(), -1)).collect::<Vec<_>>();
                         for (t, c) in elements.iter() {
@@ -161,3 +391,71 @@ where I : IntoIterator,
         stream
     }
 }
+
+
+/// Re-paces a replayed `CompEvent` stream according to the events' recorded times.
+pub trait PaceEvents<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Holds back every event until `factor` times its recorded offset to the
+    /// first event has passed. Events of the same source peer stay in order.
+    fn pace_events(&self, factor: f64) -> Stream<S, CompEvent>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> PaceEvents<S> for Stream<S, CompEvent> {
+    fn pace_events(&self, factor: f64) -> Stream<S, CompEvent> {
+        let scope = self.scope();
+        let speed = ReplaySpeed::Factor(factor);
+
+        let mut vector = Vec::new();
+        let mut pending: Vec<(Capability<S::Timestamp>, Vec<CompEvent>)> = Vec::new();
+        // (recorded time, wall-clock time) of the first timed event
+        let mut pace_origin: Option<(Duration, Instant)> = None;
+
+        self.unary_frontier(Pipeline, "PaceEvents", move |_, info| {
+            let activator = scope.activator_for(&info.address[..]);
+
+            move |input, output| {
+                input.for_each(|cap, data| {
+                    data.swap(&mut vector);
+                    if pace_origin.is_none() {
+                        if let Some((_, _, _, (t, _, _))) = vector.iter().find(|(_, _, _, (t, _, _))| *t > Default::default()) {
+                            pace_origin = Some((*t, Instant::now()));
+                        }
+                    }
+                    pending.push((cap.retain(), vector.drain(..).collect()));
+                });
+
+                // source peers that have an event waiting
+                let mut blocked = HashSet::new();
+                // when the earliest event that isn't due yet is
+                let mut next_due: Option<Instant> = None;
+                for (cap, events) in pending.iter_mut() {
+                    let mut session = output.session(cap);
+                    let mut held = Vec::new();
+                    for event in events.drain(..) {
+                        let (t, wid, _) = &event.3;
+                        if blocked.contains(wid) {
+                            held.push(event);
+                        } else if !speed.is_due(*t, pace_origin) {
+                            if let Some(due) = speed.due(*t, pace_origin) {
+                                next_due = Some(next_due.map_or(due, |next| next.min(due)));
+                            }
+                            blocked.insert(*wid);
+                            held.push(event);
+                        } else {
+                            session.give(event);
+                        }
+                    }
+                    *events = held;
+                }
+                pending.retain(|(_, events)| !events.is_empty());
+
+                // sleep until the next event is due rather than spinning
+                match next_due {
+                    Some(due) => activator.activate_after(due.saturating_duration_since(Instant::now())),
+                    None if !pending.is_empty() => activator.activate(),
+                    None => (),
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,234 @@
+//! Synthetic traces, e.g. to benchmark ST2 (cf. `st2 bench`): a `Workload`
+//! describes a source computation (its workers and operators, how often epochs
+//! start, which workers exchange data, and how skewed their load is) and
+//! generates the events its `PAGLogger`s would have written, without running
+//! it. The events can be replayed from memory (cf. `SyntheticReplayer`) or
+//! written to `*.dump` files (cf. `Workload::write`).
+//!
+//! Every epoch, each worker schedules every operator once. While it's
+//! scheduled, an operator sends a data message to the workers its
+//! `MessagePattern` selects and receives theirs. After the last operator, every
+//! worker broadcasts a progress message and receives those of all others.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use timely::dataflow::operators::capture::event::{Event, EventIterator, EventPusher};
+use timely::dataflow::operators::capture::EventWriter;
+use timely::logging::{MessagesEvent, OperatesEvent, ProgressEvent, ScheduleEvent, StartStop, TimelyEvent};
+
+use st2_logformat::pair::Pair;
+
+use crate::connect::{CompEvent, TraceEvent};
+
+/// Which workers an operator sends data messages to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessagePattern {
+    /// No data messages
+    Silent,
+    /// To the next worker (by index)
+    Ring,
+    /// To all other workers
+    AllToAll,
+}
+
+impl MessagePattern {
+    /// The workers `worker` (of `workers`) sends to
+    fn targets(self, worker: usize, workers: usize) -> Vec<usize> {
+        match self {
+            _ if workers < 2 => Vec::new(),
+            MessagePattern::Silent => Vec::new(),
+            MessagePattern::Ring => vec![(worker + 1) % workers],
+            MessagePattern::AllToAll => (0 .. workers).filter(|other| *other != worker).collect(),
+        }
+    }
+
+    /// The workers that send to `worker` (of `workers`)
+    fn sources(self, worker: usize, workers: usize) -> Vec<usize> {
+        (0 .. workers).filter(|other| self.targets(*other, workers).contains(&worker)).collect()
+    }
+}
+
+impl FromStr for MessagePattern {
+    type Err = String;
+
+    /// Parses `none`, `ring`, or `all-to-all`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(MessagePattern::Silent),
+            "ring" => Ok(MessagePattern::Ring),
+            "all-to-all" => Ok(MessagePattern::AllToAll),
+            _ => Err(format!("{}: expected none, ring, or all-to-all", s)),
+        }
+    }
+}
+
+/// A synthetic source computation
+#[derive(Clone, Debug, PartialEq)]
+pub struct Workload {
+    /// Number of workers
+    pub workers: usize,
+    /// Number of operators, all scheduled once per epoch
+    pub operators: usize,
+    /// Number of epochs
+    pub epochs: u64,
+    /// Time between the starts of consecutive epochs; epochs that take longer
+    /// delay the next one
+    pub epoch_interval: Duration,
+    /// How long the least busy worker schedules an operator
+    pub operator_time: Duration,
+    /// Which workers exchange data messages
+    pub messages: MessagePattern,
+    /// Records per data message
+    pub records: usize,
+    /// How much longer the busiest worker schedules an operator than the least
+    /// busy one (at least 1); workers in between are spread evenly
+    pub skew: f64,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Workload {
+            workers: 4,
+            operators: 8,
+            epochs: 100,
+            epoch_interval: Duration::from_millis(10),
+            operator_time: Duration::from_micros(100),
+            messages: MessagePattern::AllToAll,
+            records: 100,
+            skew: 1.0,
+        }
+    }
+}
+
+impl Workload {
+    /// How long `worker` schedules an operator
+    fn busy(&self, worker: usize) -> Duration {
+        let share = if self.workers > 1 { worker as f64 / (self.workers - 1) as f64 } else { 0.0 };
+        self.operator_time.mul_f64(1.0 + (self.skew.max(1.0) - 1.0) * share)
+    }
+
+    /// Time between consecutive events within a schedule, so that all sends
+    /// of an operator happen before all receives
+    fn step(&self) -> Duration {
+        std::cmp::max(self.operator_time / (4 * (self.workers as u32 + 1)), Duration::from_nanos(1))
+    }
+
+    /// Time an operator takes in an epoch, on the busiest worker
+    fn slot(&self) -> Duration {
+        self.busy(self.workers.saturating_sub(1)) + self.step()
+    }
+
+    /// When `epoch` (starting at 1) starts
+    fn epoch_start(&self, epoch: u64) -> Duration {
+        let length = self.slot() * self.operators as u32 + self.step() * (self.workers as u32 + 2);
+        self.step() + std::cmp::max(self.epoch_interval, length) * (epoch - 1) as u32
+    }
+
+    /// The number of `LogRecord`s the workload's events convert to
+    pub fn records(&self) -> u64 {
+        let messages: usize = (0 .. self.workers).map(|worker| self.messages.targets(worker, self.workers).len()).sum();
+        let per_epoch = self.workers * self.operators * 2 + 2 * self.operators * messages + self.workers * self.workers;
+        per_epoch as u64 * self.epochs
+    }
+
+    /// The events `worker` would have logged, as `PAGLogger` writes them
+    pub fn events(&self, worker: usize) -> Vec<TraceEvent> {
+        let mut seq_no = 0;
+        let mut event = |epoch: u64, length: Option<usize>, timestamp: Duration, x: TimelyEvent| -> CompEvent {
+            seq_no += 1;
+            (epoch, seq_no, length, (timestamp, worker, x))
+        };
+
+        // the dataflow's structure, scopes logged after their children
+        let mut operates: Vec<_> = (1 ..= self.operators)
+            .map(|id| event(0, None, Default::default(), TimelyEvent::Operates(OperatesEvent { id, addr: vec![0, id], name: format!("Op{}", id) })))
+            .collect();
+        operates.push(event(0, None, Default::default(), TimelyEvent::Operates(OperatesEvent { id: 0, addr: vec![0], name: "Dataflow".to_string() })));
+
+        let mut events = vec![Event::Messages(Default::default(), operates)];
+        let targets = self.messages.targets(worker, self.workers);
+        let sources = self.messages.sources(worker, self.workers);
+        let (busy, step) = (self.busy(worker), self.step());
+        let records = self.records;
+
+        let mut cap: Pair<u64, Duration> = Default::default();
+        for epoch in 1 ..= self.epochs {
+            let start = self.epoch_start(epoch);
+            let mut batch = Vec::new();
+            for id in 1 ..= self.operators {
+                let scheduled = start + self.slot() * (id - 1) as u32;
+                let correlator = (epoch as usize - 1) * self.operators + id - 1;
+                batch.push(event(epoch, None, scheduled, TimelyEvent::Schedule(ScheduleEvent { id, start_stop: StartStop::Start })));
+                for (k, target) in targets.iter().enumerate() {
+                    let sent = MessagesEvent { is_send: true, channel: id, source: worker, target: *target, seq_no: correlator, length: records };
+                    batch.push(event(epoch, Some(records), scheduled + step * (k + 1) as u32, TimelyEvent::Messages(sent)));
+                }
+                for (k, source) in sources.iter().enumerate() {
+                    let received = MessagesEvent { is_send: false, channel: id, source: *source, target: worker, seq_no: correlator, length: records };
+                    batch.push(event(epoch, Some(records), scheduled + busy - step * (sources.len() - k) as u32, TimelyEvent::Messages(received)));
+                }
+                let length = Some(sources.len() * records).filter(|length| *length > 0);
+                batch.push(event(epoch, length, scheduled + busy, TimelyEvent::Schedule(ScheduleEvent { id, start_stop: StartStop::Stop })));
+            }
+
+            let progress = start + self.slot() * self.operators as u32;
+            let sent = ProgressEvent { is_send: true, source: worker, channel: 0, seq_no: epoch as usize, addr: vec![0], messages: Vec::new(), internal: Vec::new() };
+            batch.push(event(epoch, None, progress + step, TimelyEvent::Progress(sent)));
+            for (k, source) in (0 .. self.workers).filter(|other| *other != worker).enumerate() {
+                let received = ProgressEvent { is_send: false, source, channel: 0, seq_no: epoch as usize, addr: vec![0], messages: Vec::new(), internal: Vec::new() };
+                batch.push(event(epoch, None, progress + step * (k + 2) as u32, TimelyEvent::Progress(received)));
+            }
+
+            let next = Pair::new(epoch, start);
+            events.push(Event::Progress(vec![(next.clone(), 1), (cap, -1)]));
+            events.push(Event::Messages(next.clone(), batch));
+            cap = next;
+        }
+        events.push(Event::Progress(vec![(cap, -1)]));
+        events
+    }
+
+    /// Replays the events of `worker` from memory.
+    pub fn replayer(&self, worker: usize) -> SyntheticReplayer {
+        SyntheticReplayer::new(self.events(worker))
+    }
+
+    /// Writes the events of every worker to `<worker>.dump` in `dir`.
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        for worker in 0 .. self.workers {
+            let file = File::create(dir.join(format!("{}.dump", worker)))?;
+            let mut writer = EventWriter::<Pair<u64, Duration>, CompEvent, _>::new(file);
+            for event in self.events(worker) {
+                writer.push(event);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replays events from memory, e.g. those of a `Workload`
+pub struct SyntheticReplayer {
+    events: Vec<TraceEvent>,
+    /// Index of the event `next` returns
+    next: usize,
+}
+
+impl SyntheticReplayer {
+    /// Replays `events`, in order.
+    pub fn new(events: Vec<TraceEvent>) -> Self {
+        SyntheticReplayer { events, next: 0 }
+    }
+}
+
+impl EventIterator<Pair<u64, Duration>, CompEvent> for SyntheticReplayer {
+    fn next(&mut self) -> Option<&TraceEvent> {
+        let event = self.events.get(self.next)?;
+        self.next += 1;
+        Some(event)
+    }
+}
@@ -0,0 +1,205 @@
+//! SnailTrail's PAG analyses for the browser, compiled to WebAssembly.
+//!
+//! A viewer loads a PAG exported by SnailTrail — a snapshot of `st2 snapshot`,
+//! or the edges of `st2 export --edges --format json` — and computes its
+//! epochs' critical paths client-side:
+//!
+//! ```js
+//! import init, { Pag } from "./pkg/st2_wasm.js";
+//!
+//! await init();
+//! const pag = Pag.fromJson(await (await fetch("epoch-42.json")).text());
+//! for (const epoch of pag.epochs()) {
+//!     console.log(epoch, pag.criticalPath(epoch));
+//! }
+//! ```
+//!
+//! JS numbers can't represent nanosecond timestamps since the Unix epoch
+//! exactly, so edges' `start_ns` and `end_ns` are relative to the PAG's first
+//! event, `Pag.start()`.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use st2_logformat::ActivityType;
+use st2_logformat::pag::{critical_path, PagEdge, PagNode, TraversalType};
+
+/// The PAG of a trace or snapshot
+#[wasm_bindgen]
+pub struct Pag {
+    /// Edges, ordered by source timestamp
+    edges: Vec<PagEdge>,
+    /// epoch -> its edges' indices
+    epochs: BTreeMap<u64, Vec<usize>>,
+    /// Timestamp of the first event
+    start: Duration,
+}
+
+/// An edge as handed to JS
+#[derive(Serialize)]
+struct Edge {
+    epoch: u64,
+    worker: u64,
+    dst_worker: u64,
+    operator: Option<u64>,
+    activity: String,
+    traverse: String,
+    start_ns: u64,
+    end_ns: u64,
+    duration_ns: u64,
+    records: Option<usize>,
+}
+
+/// The summary of an epoch as handed to JS
+#[derive(Serialize)]
+struct Summary {
+    epoch: u64,
+    edges: usize,
+    latency_ns: u64,
+    critical_path_ns: u64,
+}
+
+/// A snapshot of `st2 snapshot`; only its edges are needed
+#[derive(Deserialize)]
+struct Snapshot {
+    edges: Vec<PagEdge>,
+}
+
+/// A row of `st2 export --edges --format json`
+#[derive(Deserialize)]
+struct EdgeRow {
+    epoch: u64,
+    src_worker: u64,
+    src_timestamp: u64,
+    dst_worker: u64,
+    dst_timestamp: u64,
+    activity_type: ActivityType,
+    operator_id: Option<u64>,
+    length: Option<usize>,
+}
+
+impl From<EdgeRow> for PagEdge {
+    fn from(row: EdgeRow) -> Self {
+        let node = |worker_id, timestamp| PagNode {
+            timestamp: Duration::from_nanos(timestamp),
+            worker_id,
+            epoch: row.epoch,
+            seq_no: 0,
+        };
+        PagEdge {
+            source: node(row.src_worker, row.src_timestamp),
+            destination: node(row.dst_worker, row.dst_timestamp),
+            edge_type: row.activity_type,
+            operator_id: row.operator_id,
+            // as in the PAG construction, waiting is the only blocked activity
+            traverse: if row.activity_type == ActivityType::Waiting { TraversalType::Block } else { TraversalType::Unbounded },
+            length: row.length,
+            link: None,
+        }
+    }
+}
+
+impl Pag {
+    fn new(mut edges: Vec<PagEdge>) -> Self {
+        edges.sort_by_key(|edge| (edge.source.timestamp, edge.source.worker_id, edge.destination.timestamp));
+        let mut epochs: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+        for (i, edge) in edges.iter().enumerate() {
+            epochs.entry(edge.source.epoch).or_insert_with(Vec::new).push(i);
+        }
+        let start = edges.first().map(|edge| edge.source.timestamp).unwrap_or_default();
+        Pag { edges, epochs, start }
+    }
+
+    fn epoch_edges(&self, epoch: u64) -> Result<Vec<PagEdge>, JsValue> {
+        self.epochs.get(&epoch)
+            .map(|indices| indices.iter().map(|i| self.edges[*i].clone()).collect())
+            .ok_or_else(|| JsValue::from_str(&format!("no edges in epoch {}", epoch)))
+    }
+
+    fn edge(&self, edge: &PagEdge) -> Edge {
+        let relative = |timestamp: Duration| timestamp.checked_sub(self.start).unwrap_or_default().as_nanos() as u64;
+        Edge {
+            epoch: edge.source.epoch,
+            worker: edge.source.worker_id,
+            dst_worker: edge.destination.worker_id,
+            operator: edge.operator_id,
+            activity: format!("{:?}", edge.edge_type),
+            traverse: format!("{:?}", edge.traverse),
+            start_ns: relative(edge.source.timestamp),
+            end_ns: relative(edge.destination.timestamp),
+            duration_ns: edge.duration(),
+            records: edge.length,
+        }
+    }
+
+    fn to_js(&self, edges: &[PagEdge]) -> Result<JsValue, JsValue> {
+        let edges: Vec<Edge> = edges.iter().map(|edge| self.edge(edge)).collect();
+        JsValue::from_serde(&edges).map_err(error)
+    }
+}
+
+#[wasm_bindgen]
+impl Pag {
+    /// Loads a PAG from JSON: a snapshot of `st2 snapshot`, or the edges of
+    /// `st2 export --edges --format json`.
+    #[wasm_bindgen(js_name = fromJson)]
+    pub fn from_json(json: &str) -> Result<Pag, JsValue> {
+        let edges = match serde_json::from_str::<Snapshot>(json) {
+            Ok(snapshot) => snapshot.edges,
+            Err(_) => serde_json::from_str::<Vec<EdgeRow>>(json)
+                .map_err(|e| JsValue::from_str(&format!("neither a snapshot nor exported edges: {}", e)))?
+                .into_iter()
+                .map(PagEdge::from)
+                .collect(),
+        };
+        Ok(Pag::new(edges))
+    }
+
+    /// Timestamp of the PAG's first event in ns since the Unix epoch, as a
+    /// decimal string
+    pub fn start(&self) -> String {
+        self.start.as_nanos().to_string()
+    }
+
+    /// The epochs of the PAG, in order
+    pub fn epochs(&self) -> Result<JsValue, JsValue> {
+        let epochs: Vec<u64> = self.epochs.keys().cloned().collect();
+        JsValue::from_serde(&epochs).map_err(error)
+    }
+
+    /// The edges of `epoch`, ordered by start time
+    pub fn edges(&self, epoch: f64) -> Result<JsValue, JsValue> {
+        self.to_js(&self.epoch_edges(epoch as u64)?)
+    }
+
+    /// The critical path of `epoch`, in order
+    #[wasm_bindgen(js_name = criticalPath)]
+    pub fn critical_path(&self, epoch: f64) -> Result<JsValue, JsValue> {
+        self.to_js(&critical_path(&self.epoch_edges(epoch as u64)?))
+    }
+
+    /// Per-epoch summaries: the number of edges, latency, and critical path
+    /// length of every epoch
+    pub fn summaries(&self) -> Result<JsValue, JsValue> {
+        let mut summaries = Vec::new();
+        for epoch in self.epochs.keys() {
+            let edges = self.epoch_edges(*epoch)?;
+            let first = edges.iter().map(|edge| edge.source.timestamp).min().unwrap_or_default();
+            let last = edges.iter().map(|edge| edge.destination.timestamp).max().unwrap_or_default();
+            summaries.push(Summary {
+                epoch: *epoch,
+                edges: edges.len(),
+                latency_ns: last.checked_sub(first).unwrap_or_default().as_nanos() as u64,
+                critical_path_ns: critical_path(&edges).iter().map(|edge| edge.duration()).sum(),
+            });
+        }
+        JsValue::from_serde(&summaries).map_err(error)
+    }
+}
+
+fn error(e: serde_json::Error) -> JsValue {
+    JsValue::from_str(&e.to_string())
+}
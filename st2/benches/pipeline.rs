@@ -0,0 +1,60 @@
+//! Benchmarks of ST2's pipeline on synthetic traces (cf. `st2 bench`):
+//! conversion of timely events to `LogRecord`s, PAG construction, and
+//! critical path extraction. Run with `cargo bench -p st2`.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use st2::commands::bench::{self, Stage};
+use st2_timely::synthetic::{MessagePattern, Workload};
+
+/// Workloads small enough for criterion's repetitions
+fn workloads() -> Vec<(&'static str, Workload)> {
+    let base = Workload { epochs: 20, ..Workload::default() };
+    vec![
+        ("all-to-all", base.clone()),
+        ("ring", Workload { messages: MessagePattern::Ring, ..base.clone() }),
+        ("skewed", Workload { skew: 4.0, ..base }),
+    ]
+}
+
+fn adapter_conversion(c: &mut Criterion) {
+    let mut group = c.benchmark_group("adapter_conversion");
+    for (name, workload) in workloads() {
+        let events = bench::generate(&workload);
+        group.throughput(Throughput::Elements(workload.records()));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &events, |b, events| {
+            b.iter(|| bench::dataflow(1, events, Stage::LogRecords, None).expect("log record construction failed"))
+        });
+    }
+    group.finish();
+}
+
+fn pag_construction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pag_construction");
+    for (name, workload) in workloads() {
+        let events = bench::generate(&workload);
+        group.throughput(Throughput::Elements(workload.records()));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &events, |b, events| {
+            b.iter(|| bench::dataflow(1, events, Stage::Pag, None).expect("PAG construction failed"))
+        });
+    }
+    group.finish();
+}
+
+fn critical_path(c: &mut Criterion) {
+    let mut group = c.benchmark_group("critical_path");
+    for (name, workload) in workloads() {
+        let pag = Arc::new(Mutex::new(BTreeMap::new()));
+        bench::dataflow(1, &bench::generate(&workload), Stage::Pag, Some(Arc::clone(&pag))).expect("PAG construction failed");
+        let pag = pag.lock().expect("bench pag poisoned").clone();
+        group.throughput(Throughput::Elements(pag.values().map(|edges| edges.len() as u64).sum()));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &pag, |b, pag| b.iter(|| bench::critical_paths(pag)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, adapter_conversion, pag_construction, critical_path);
+criterion_main!(benches);
@@ -0,0 +1,7 @@
+//! Generates the gRPC service of `st2 grpc` from `proto/analysis.proto`, if the
+//! `grpc` feature is enabled.
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/analysis.proto").expect("couldn't compile proto/analysis.proto");
+}
@@ -0,0 +1,34 @@
+-- Tables of `st2 publish --sink clickhouse:URL`, created if they don't exist.
+-- Rows are partitioned by the month they were published in, so old history
+-- can be dropped with `ALTER TABLE ... DROP PARTITION` or a TTL.
+
+-- PAG edges, as exported by `st2 export --edges`
+CREATE TABLE IF NOT EXISTS st2_edges
+(
+    epoch UInt64,
+    src_worker UInt64,
+    src_timestamp UInt64,
+    dst_worker UInt64,
+    dst_timestamp UInt64,
+    activity_type LowCardinality(String),
+    operator_id Nullable(UInt64),
+    length Nullable(UInt64),
+    published DateTime DEFAULT now()
+)
+ENGINE = MergeTree
+PARTITION BY toYYYYMM(published)
+ORDER BY (epoch, src_worker, src_timestamp);
+
+-- Per-epoch metrics, cf. `st2 grafana`
+CREATE TABLE IF NOT EXISTS st2_metrics
+(
+    name LowCardinality(String),
+    labels Map(String, String),
+    epoch UInt64,
+    timestamp UInt64,
+    value Float64,
+    published DateTime DEFAULT now()
+)
+ENGINE = MergeTree
+PARTITION BY toYYYYMM(published)
+ORDER BY (name, epoch);
@@ -0,0 +1,217 @@
+//! Authentication and per-endpoint authorization of served endpoints (`api`,
+//! `grafana`, `grpc`, and `dashboard`).
+//!
+//! Clients authenticate with a bearer token (`Authorization: Bearer <TOKEN>`) or,
+//! for `grpc`, with a TLS client certificate. An `--auth` file lists the
+//! credentials and the endpoints each of them may access:
+//!
+//! ```toml
+//! # tokens are given by their SHA-256 hash, e.g. `printf %s "$TOKEN" | sha256sum`
+//! [[token]]
+//! sha256 = "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8"
+//! endpoints = ["/epochs*", "/metrics", "GetEpochSummaries"]
+//!
+//! # client certificates are given by the SHA-256 fingerprint of their DER encoding,
+//! # e.g. `openssl x509 -in client.pem -noout -fingerprint -sha256`
+//! [[client]]
+//! fingerprint = "AB:12:..."
+//! endpoints = ["*"]
+//! ```
+//!
+//! Endpoints are the paths of HTTP requests (without the query) and the method
+//! names of gRPC requests, e.g. `GetCriticalPath`; a trailing `*` matches any
+//! suffix. HTTP clients that can't set headers may pass their token as the
+//! (percent-encoded) query parameter `access_token` instead. Tokens may also be given in plain text
+//! (`token = "..."`), but then the file is as sensitive as the tokens.
+
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use toml::Value;
+
+use crate::STError;
+
+/// A credential presented by a client
+#[derive(Clone, Copy, Debug)]
+pub enum Credential<'a> {
+    /// A bearer token
+    Token(&'a str),
+    /// The DER encoding of a verified TLS client certificate
+    Certificate(&'a [u8]),
+}
+
+/// Why a request was denied
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Denied {
+    /// No known credential was presented (cf. HTTP 401)
+    Unauthenticated,
+    /// The credentials don't grant access to the endpoint (cf. HTTP 403)
+    Forbidden,
+}
+
+impl Denied {
+    /// The HTTP status code of the denial
+    pub fn status(self) -> u16 {
+        match self {
+            Denied::Unauthenticated => 401,
+            Denied::Forbidden => 403,
+        }
+    }
+}
+
+impl std::fmt::Display for Denied {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Denied::Unauthenticated => write!(f, "missing or unknown credentials"),
+            Denied::Forbidden => write!(f, "not authorized for this endpoint"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Principal {
+    /// Hex SHA-256 hash of a token
+    Token(String),
+    /// Hex SHA-256 fingerprint of a certificate
+    Certificate(String),
+}
+
+/// Credentials and the endpoints they grant access to
+#[derive(Debug, Default)]
+pub struct Auth {
+    grants: Vec<(Principal, Vec<String>)>,
+}
+
+impl Auth {
+    /// Reads the `--auth` file at `path`.
+    pub fn load(path: &Path) -> Result<Self, STError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| STError::Config(format!("Invalid --auth: {}: {}", path.display(), e)))?;
+        contents.parse()
+    }
+
+    /// Whether the `credentials` grant access to `endpoint`. Unknown credentials are ignored.
+    pub fn authorize(&self, credentials: &[Credential], endpoint: &str) -> Result<(), Denied> {
+        let principals: Vec<Principal> = credentials.iter()
+            .map(|credential| match credential {
+                Credential::Token(token) => Principal::Token(sha256_hex(token.as_bytes())),
+                Credential::Certificate(der) => Principal::Certificate(sha256_hex(der)),
+            })
+            .collect();
+
+        let mut authenticated = false;
+        for (principal, endpoints) in self.grants.iter().filter(|(principal, _)| principals.contains(principal)) {
+            authenticated = true;
+            if endpoints.iter().any(|pattern| matches(pattern, endpoint)) {
+                debug!("authorized {:?} for {}", principal, endpoint);
+                return Ok(());
+            }
+        }
+        Err(if authenticated { Denied::Forbidden } else { Denied::Unauthenticated })
+    }
+
+    /// Whether the HTTP `request` may access its path. The token is taken from its
+    /// `Authorization` header or, for clients that can't set headers (e.g. a
+    /// browser's `EventSource`), its `access_token` query parameter.
+    pub fn authorize_http(&self, request: &tiny_http::Request) -> Result<(), Denied> {
+        let authorization = request.headers().iter()
+            .find(|header| header.field.equiv("Authorization"))
+            .map(|header| header.value.as_str());
+        self.authorize_url(request.url(), authorization)
+    }
+
+    /// Whether the WebSocket handshake `request` may access its path, cf. `authorize_http`.
+    pub fn authorize_ws(&self, request: &ws::Request) -> Result<(), Denied> {
+        let authorization = request.header("Authorization").map(|value| String::from_utf8_lossy(value).into_owned());
+        self.authorize_url(request.resource(), authorization.as_ref().map(String::as_str))
+    }
+
+    /// Whether a request of `url` (a path and an optional query) with the
+    /// `Authorization` header `authorization` may access the path, cf. `authorize_http`.
+    pub fn authorize_url(&self, url: &str, authorization: Option<&str>) -> Result<(), Denied> {
+        let mut parts = url.splitn(2, '?');
+        let path = parts.next().unwrap_or("");
+        let parameter = access_token(parts.next().unwrap_or(""));
+        let credentials: Vec<Credential> = authorization.and_then(bearer).into_iter()
+            .chain(parameter.as_ref().map(String::as_str))
+            .map(Credential::Token)
+            .collect();
+        self.authorize(&credentials, path)
+    }
+}
+
+impl std::str::FromStr for Auth {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |message: String| STError::Config(format!("Invalid --auth: {}", message));
+        let table = match s.parse::<Value>() {
+            Ok(Value::Table(table)) => table,
+            Ok(_) => unreachable!("TOML documents are tables"),
+            Err(e) => return Err(invalid(e.to_string())),
+        };
+
+        let mut auth = Auth::default();
+        for (key, value) in table {
+            let entries = match value {
+                Value::Array(entries) if key == "token" || key == "client" => entries,
+                _ => return Err(invalid(format!("unknown key {} (expected [[token]] or [[client]])", key))),
+            };
+            for entry in entries {
+                let string = |name: &str| entry.get(name).and_then(|value| value.as_str());
+                let principal = match (key.as_str(), string("sha256"), string("token"), string("fingerprint")) {
+                    ("token", Some(hash), None, _) => Principal::Token(normalize_hex(hash)),
+                    ("token", None, Some(token), _) => Principal::Token(sha256_hex(token.as_bytes())),
+                    ("token", _, _, _) => return Err(invalid("a [[token]] needs either sha256 or token".to_string())),
+                    (_, _, _, Some(fingerprint)) => Principal::Certificate(normalize_hex(fingerprint)),
+                    (_, _, _, None) => return Err(invalid("a [[client]] needs a fingerprint".to_string())),
+                };
+                let endpoints = match entry.get("endpoints").and_then(|value| value.as_array()) {
+                    Some(endpoints) => endpoints.iter()
+                        .map(|endpoint| endpoint.as_str().map(|endpoint| endpoint.to_string())
+                             .ok_or_else(|| invalid("endpoints have to be strings".to_string())))
+                        .collect::<Result<Vec<_>, _>>()?,
+                    None => return Err(invalid(format!("a [[{}]] needs a list of endpoints", key))),
+                };
+                auth.grants.push((principal, endpoints));
+            }
+        }
+        Ok(auth)
+    }
+}
+
+/// Extracts the token of an `Authorization` header's value `Bearer <TOKEN>`
+pub fn bearer(authorization: &str) -> Option<&str> {
+    let authorization = authorization.trim();
+    match authorization.get(.. 7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("bearer ") => Some(authorization[7 ..].trim()),
+        _ => None,
+    }
+}
+
+/// The percent-decoded `access_token` parameter of the URL query `query`, e.g.
+/// `a+b/=` of `access_token=a%2Bb%2F%3D`
+pub fn access_token(query: &str) -> Option<String> {
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "access_token")
+        .map(|(_, value)| value.into_owned())
+}
+
+fn matches(pattern: &str, endpoint: &str) -> bool {
+    if pattern.ends_with('*') {
+        endpoint.starts_with(&pattern[.. pattern.len() - 1])
+    } else {
+        endpoint == pattern
+    }
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(bytes);
+    hasher.result().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Lowercase hex without separators, e.g. of `AB:CD:...` fingerprints
+fn normalize_hex(hex: &str) -> String {
+    hex.chars().filter(|c| c.is_ascii_hexdigit()).map(|c| c.to_ascii_lowercase()).collect()
+}
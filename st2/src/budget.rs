@@ -0,0 +1,215 @@
+//! A memory budget for the PAG edges that analyses hold until their epochs (or
+//! windows of epochs) complete. Once `enable`d, all `PendingEpochs` of this
+//! process share the budget; when it's exceeded, the epochs that are needed
+//! last (the highest ones) are spilled to disk and read back once they
+//! complete, or, with `OverBudget::Sample` (or if spilling fails), they are
+//! dropped, so that only a sample of the epochs is analyzed. Either way, ST2
+//! doesn't get OOM-killed in the middle of an incident.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use once_cell::sync::OnceCell;
+
+use crate::pag::PagEdge;
+use crate::STError;
+
+/// What to do with epochs that don't fit into the budget
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OverBudget {
+    /// Spill them to files in this directory
+    Spill(PathBuf),
+    /// Drop them
+    Sample,
+}
+
+/// How much memory pending PAG edges may take up
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Budget {
+    /// Budget in bytes
+    pub max_bytes: u64,
+    /// What to do once the budget is exceeded
+    pub over: OverBudget,
+}
+
+/// The enabled budget, cf. `enable`
+static BUDGET: OnceCell<Budget> = OnceCell::new();
+/// Bytes of PAG edges pending in memory in all `PendingEpochs`
+static IN_USE: AtomicU64 = AtomicU64::new(0);
+/// Distinguishes the spill files of the `PendingEpochs` of this process
+static STORES: AtomicUsize = AtomicUsize::new(0);
+
+/// Makes all `PendingEpochs` keep to `budget`.
+pub fn enable(budget: Budget) {
+    BUDGET.set(budget).expect("memory budget already enabled");
+}
+
+/// The estimated size of `edges` in memory
+fn size_of(edges: usize) -> u64 {
+    (edges * std::mem::size_of::<PagEdge>()) as u64
+}
+
+/// PAG edges by epoch, waiting for their epochs to complete, that keep to the
+/// enabled budget (cf. `enable`)
+pub struct PendingEpochs {
+    /// Name of the analysis, for logs and spill files
+    name: String,
+    /// Edges in memory, by epoch
+    memory: BTreeMap<u64, Vec<PagEdge>>,
+    /// Spill files of epochs, whose further edges are collected in `memory`
+    spilled: BTreeMap<u64, PathBuf>,
+    /// Epochs that were dropped, cf. `OverBudget::Sample`
+    dropped: BTreeSet<u64>,
+    /// Distinguishes this store's spill files
+    id: usize,
+}
+
+impl PendingEpochs {
+    /// An empty store for the analysis `name`
+    pub fn new(name: &str) -> Self {
+        PendingEpochs {
+            name: name.to_string(),
+            memory: BTreeMap::new(),
+            spilled: BTreeMap::new(),
+            dropped: BTreeSet::new(),
+            id: STORES.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Adds `edge` to its epoch, spilling or dropping epochs if this exceeds the budget.
+    pub fn push(&mut self, edge: PagEdge) {
+        let epoch = edge.source.epoch;
+        if self.dropped.contains(&epoch) {
+            return;
+        }
+        self.memory.entry(epoch).or_insert_with(Vec::new).push(edge);
+        let in_use = IN_USE.fetch_add(size_of(1), Ordering::Relaxed) + size_of(1);
+
+        if let Some(budget) = BUDGET.get() {
+            if in_use > budget.max_bytes {
+                self.relieve(budget);
+            }
+        }
+    }
+
+    /// The lowest pending epoch
+    pub fn first(&self) -> Option<u64> {
+        self.memory.keys().chain(self.spilled.keys()).min().cloned()
+    }
+
+    /// Removes all epochs below `end` and returns the ones that weren't dropped,
+    /// reading spilled epochs back.
+    pub fn take_until(&mut self, end: u64) -> BTreeMap<u64, Vec<PagEdge>> {
+        let mut epochs = BTreeMap::new();
+        while let Some(epoch) = self.first().filter(|epoch| *epoch < end) {
+            if let Some(edges) = self.take(epoch) {
+                epochs.insert(epoch, edges);
+            }
+        }
+        self.dropped = self.dropped.split_off(&end);
+        epochs
+    }
+
+    /// Removes `epoch` and returns its edges, unless it was dropped.
+    fn take(&mut self, epoch: u64) -> Option<Vec<PagEdge>> {
+        let mut edges = Vec::new();
+        if let Some(path) = self.spilled.remove(&epoch) {
+            match read_spilled(&path) {
+                Ok(spilled) => edges = spilled,
+                Err(e) => {
+                    error!("{}: couldn't read epoch {} back, dropping it: {}", self.name, epoch, e);
+                    self.dropped.insert(epoch);
+                }
+            }
+            let _ = fs::remove_file(&path);
+        }
+        if let Some(remaining) = self.memory.remove(&epoch) {
+            IN_USE.fetch_sub(size_of(remaining.len()), Ordering::Relaxed);
+            edges.extend(remaining);
+        }
+        Some(edges).filter(|_| !self.dropped.contains(&epoch))
+    }
+
+    /// Spills or drops this store's epochs that are needed last, until it keeps
+    /// to `budget` or has nothing left in memory. The lowest epoch is kept, as
+    /// it's needed next.
+    fn relieve(&mut self, budget: &Budget) {
+        while IN_USE.load(Ordering::Relaxed) > budget.max_bytes && self.memory.len() > 1 {
+            let (epoch, edges) = {
+                let epoch = *self.memory.keys().next_back().expect("epoch in memory");
+                (epoch, self.memory.remove(&epoch).expect("epoch in memory"))
+            };
+            IN_USE.fetch_sub(size_of(edges.len()), Ordering::Relaxed);
+
+            let spilled = match &budget.over {
+                OverBudget::Spill(dir) => self.spill(dir, epoch, edges)
+                    .map_err(|e| warn!("{}: couldn't spill epoch {}, dropping it instead: {}", self.name, epoch, e))
+                    .is_ok(),
+                OverBudget::Sample => false,
+            };
+            if !spilled {
+                if let Some(path) = self.spilled.remove(&epoch) {
+                    let _ = fs::remove_file(path);
+                }
+                self.dropped.insert(epoch);
+                warn!("{}: over the memory budget, dropped epoch {}", self.name, epoch);
+            } else {
+                debug!("{}: over the memory budget, spilled epoch {}", self.name, epoch);
+            }
+        }
+    }
+
+    /// Appends `edges` of `epoch` to its spill file in `dir`.
+    fn spill(&mut self, dir: &Path, epoch: u64, edges: Vec<PagEdge>) -> Result<(), STError> {
+        let path = match self.spilled.get(&epoch) {
+            Some(path) => path.clone(),
+            None => {
+                fs::create_dir_all(dir).map_err(|e| STError::io(format!("couldn't create {}", dir.display()), e))?;
+                dir.join(format!("st2-{}-{}-{}.spill", std::process::id(), self.id, epoch))
+            }
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path)
+            .map_err(|e| STError::io(format!("couldn't open {}", path.display()), e))?;
+        let mut writer = BufWriter::new(file);
+        unsafe { abomonation::encode(&edges, &mut writer) }
+            .and_then(|()| std::io::Write::flush(&mut writer))
+            .map_err(|e| STError::io(format!("couldn't write {}", path.display()), e))?;
+        self.spilled.insert(epoch, path);
+        Ok(())
+    }
+}
+
+/// Reads the edges of the spill file at `path`, a sequence of abomonated `Vec<PagEdge>`s
+fn read_spilled(path: &Path) -> Result<Vec<PagEdge>, STError> {
+    let mut bytes = Vec::new();
+    File::open(path).and_then(|mut file| file.read_to_end(&mut bytes))
+        .map_err(|e| STError::io(format!("couldn't read {}", path.display()), e))?;
+
+    let mut edges = Vec::new();
+    let len = bytes.len();
+    let mut rest = &mut bytes[..];
+    while !rest.is_empty() {
+        let offset = len - rest.len();
+        match unsafe { abomonation::decode::<Vec<PagEdge>>(rest) } {
+            Some((chunk, remaining)) => {
+                edges.extend(chunk.iter().cloned());
+                rest = remaining;
+            }
+            None => return Err(STError::Decode { offset: offset as u64, reason: format!("{} is truncated", path.display()) }),
+        }
+    }
+    Ok(edges)
+}
+
+impl Drop for PendingEpochs {
+    fn drop(&mut self) {
+        let in_memory: usize = self.memory.values().map(|edges| edges.len()).sum();
+        IN_USE.fetch_sub(size_of(in_memory), Ordering::Relaxed);
+        for path in self.spilled.values() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
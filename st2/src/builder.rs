@@ -0,0 +1,446 @@
+//! Analysis pipelines embedded in other programs.
+//!
+//! An `STBuilder` assembles what the CLI commands otherwise hard-wire: a source,
+//! the windows epochs are grouped into, the analyses to run, and where their
+//! results go, i.e., metric and alert sinks, callbacks into the embedding
+//! program, or custom checks (cf. `hooks`):
+//!
+//! ```no_run
+//! use st2::builder::{Analysis, STBuilder};
+//!
+//! let summary = STBuilder::new()
+//!     .files("traces/run-1", 4)
+//!     .workers(2)
+//!     .window(10)
+//!     .analysis(Analysis::CriticalPath)
+//!     .analysis(Analysis::Alerts(vec!["latency > 50ms".parse()?]))
+//!     .metric_sink("influx:http://localhost:8086/write?db=st2".parse()?)
+//!     .on_epoch(|report| println!("epoch {}: {} ns", report.epoch, report.latency))
+//!     .on_alert(|alert| eprintln!("{}", alert.message))
+//!     .run()?;
+//! println!("analyzed {} epochs", summary.epochs);
+//! # Ok::<(), st2::STError>(())
+//! ```
+//!
+//! `run` blocks until the source is exhausted, while `spawn` runs the pipeline in
+//! a background thread that can be stopped.
+
+use crate::pag;
+use crate::budget::PendingEpochs;
+use crate::pag::PagEdge;
+use crate::store::{epoch_samples, Sample};
+use crate::commands::alerts::{evaluate, Alert, Rule, SinkConfig as AlertSinkConfig, Sink, Window};
+use crate::commands::publish::{Publisher, SinkConfig as MetricSinkConfig, SinkOptions};
+use crate::hooks::{EpochContext, EpochHook, Hooks};
+
+use timely::dataflow::Stream;
+use timely::dataflow::channels::pact::Exchange;
+use timely::dataflow::operators::generic::operator::Operator;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive as connect;
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::{ReplaySpeed, PROGRESS};
+use st2_timely::filter::Filter;
+
+use crate::{OutputFormat, STError};
+
+/// An analysis of a pipeline's epochs
+#[derive(Clone, Debug)]
+pub enum Analysis {
+    /// Samples of all `store::METRICS`, published to the metric sinks
+    Metrics,
+    /// The epochs' critical paths
+    CriticalPath,
+    /// Alerting rules (cf. `st2 alerts`), evaluated on every window; fired
+    /// alerts are delivered to the alert sinks
+    Alerts(Vec<Rule>),
+}
+
+/// The results of a completed epoch
+#[derive(Clone, Debug)]
+pub struct EpochReport {
+    /// The epoch
+    pub epoch: u64,
+    /// The epoch's PAG edges
+    pub edges: Vec<PagEdge>,
+    /// Time from the epoch's first to its last event, in ns
+    pub latency: u64,
+    /// Number of epochs the source computation is ahead of the epoch's window
+    pub ahead: u64,
+    /// The epoch's critical path, with `Analysis::CriticalPath`
+    pub critical_path: Option<Vec<PagEdge>>,
+    /// The epoch's samples, with `Analysis::Metrics`
+    pub samples: Vec<Sample>,
+}
+
+/// What a pipeline analyzed
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    /// Number of completed epochs
+    pub epochs: u64,
+    /// Number of fired alerts
+    pub alerts: u64,
+    /// Number of failed hook invocations
+    pub hook_failures: u64,
+}
+
+enum Source {
+    /// `*.dump` files of a number of source peers in a directory
+    Files(PathBuf, usize),
+    /// TCP connections of a number of source peers
+    Tcp(SocketAddr, usize),
+    Replay(ReplaySource),
+}
+
+type Callback<T> = Arc<Mutex<Box<dyn FnMut(&T) + Send>>>;
+
+/// Assembles an analysis pipeline (cf. the module documentation)
+pub struct STBuilder {
+    source: Option<Source>,
+    timely_configuration: timely::Configuration,
+    speed: ReplaySpeed,
+    filter: Filter,
+    window: u64,
+    analyses: Vec<Analysis>,
+    metric_sinks: Vec<MetricSinkConfig>,
+    sink_options: SinkOptions,
+    alert_sinks: Vec<AlertSinkConfig>,
+    on_epoch: Vec<Callback<EpochReport>>,
+    on_alert: Vec<Callback<Alert>>,
+    hooks: Hooks,
+    operator_names: BTreeMap<u64, String>,
+    is_running: Arc<AtomicBool>,
+}
+
+impl Default for STBuilder {
+    fn default() -> Self {
+        STBuilder {
+            source: None,
+            timely_configuration: timely::Configuration::Thread,
+            speed: ReplaySpeed::Unbounded,
+            filter: Filter::default(),
+            window: 1,
+            analyses: Vec::new(),
+            metric_sinks: Vec::new(),
+            sink_options: SinkOptions::default(),
+            alert_sinks: Vec::new(),
+            on_epoch: Vec::new(),
+            on_alert: Vec::new(),
+            hooks: Hooks::new(),
+            operator_names: BTreeMap::new(),
+            is_running: Arc::new(AtomicBool::new(true)),
+        }
+    }
+}
+
+impl STBuilder {
+    /// A pipeline without a source, analyzing with a single thread
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replays the `*.dump` files written by `peers` source peers to `path`.
+    pub fn files(mut self, path: impl Into<PathBuf>, peers: usize) -> Self {
+        self.source = Some(Source::Files(path.into(), peers));
+        self
+    }
+
+    /// Listens on `address` for `peers` source peers to connect, once the pipeline runs.
+    pub fn tcp(mut self, address: SocketAddr, peers: usize) -> Self {
+        self.source = Some(Source::Tcp(address, peers));
+        self
+    }
+
+    /// Replays an already opened `source`.
+    pub fn source(mut self, source: ReplaySource) -> Self {
+        self.source = Some(Source::Replay(source));
+        self
+    }
+
+    /// Analyzes with `workers` threads (default: 1).
+    pub fn workers(self, workers: usize) -> Self {
+        self.timely_configuration(timely::Configuration::Process(workers.max(1)))
+    }
+
+    /// Analyzes with an arbitrary timely configuration, e.g. a cluster.
+    pub fn timely_configuration(mut self, timely_configuration: timely::Configuration) -> Self {
+        self.timely_configuration = timely_configuration;
+        self
+    }
+
+    /// Paces the replay (default: as fast as possible).
+    pub fn speed(mut self, speed: ReplaySpeed) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// Only analyzes events selected by `filter`.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Groups `epochs` consecutive epochs into a window (default: 1). Alerting
+    /// rules are evaluated per window, and all edges of a window are kept until
+    /// it's complete, so windows should stay small.
+    pub fn window(mut self, epochs: u64) -> Self {
+        self.window = epochs;
+        self
+    }
+
+    /// Adds an analysis.
+    pub fn analysis(mut self, analysis: Analysis) -> Self {
+        self.analyses.push(analysis);
+        self
+    }
+
+    /// Publishes the samples of `Analysis::Metrics` to `sink`.
+    pub fn metric_sink(mut self, sink: MetricSinkConfig) -> Self {
+        self.metric_sinks.push(sink);
+        self
+    }
+
+    /// Sets the options of all metric sinks.
+    pub fn sink_options(mut self, options: SinkOptions) -> Self {
+        self.sink_options = options;
+        self
+    }
+
+    /// Delivers the alerts of `Analysis::Alerts` to `sink`.
+    pub fn alert_sink(mut self, sink: AlertSinkConfig) -> Self {
+        self.alert_sinks.push(sink);
+        self
+    }
+
+    /// Calls `callback` with the report of every completed epoch, in order.
+    pub fn on_epoch(mut self, callback: impl FnMut(&EpochReport) + Send + 'static) -> Self {
+        self.on_epoch.push(Arc::new(Mutex::new(Box::new(callback))));
+        self
+    }
+
+    /// Calls `callback` with every fired alert.
+    pub fn on_alert(mut self, callback: impl FnMut(&Alert) + Send + 'static) -> Self {
+        self.on_alert.push(Arc::new(Mutex::new(Box::new(callback))));
+        self
+    }
+
+    /// Invokes `hook` with every completed epoch, its edges, critical path, and
+    /// samples, in order (cf. `hooks`). Its failures are logged with `name`.
+    pub fn hook(mut self, name: impl Into<String>, hook: impl EpochHook + 'static) -> Self {
+        self.hooks.register(name, hook);
+        self
+    }
+
+    /// Names operators in samples and alerts.
+    pub fn operator_names(mut self, operator_names: BTreeMap<u64, String>) -> Self {
+        self.operator_names = operator_names;
+        self
+    }
+
+    /// Runs the pipeline in a background thread.
+    pub fn spawn(self) -> Handle {
+        let is_running = Arc::clone(&self.is_running);
+        let thread = std::thread::spawn(move || self.run());
+        Handle { is_running, thread }
+    }
+
+    /// Runs the pipeline until its source is exhausted. Fails without replaying
+    /// anything if a sink can't be opened, and once the source can't be read or
+    /// a worker failed.
+    pub fn run(self) -> Result<Summary, STError> {
+        if self.window == 0 {
+            return Err(STError::Config("a pipeline's window has to span at least one epoch".to_string()));
+        }
+        let replay_source = match self.source {
+            Some(Source::Files(path, peers)) => {
+                let paths: Vec<PathBuf> = (0 .. peers).map(|idx| path.join(format!("{}.dump", idx))).collect();
+                if let Some(missing) = paths.iter().find(|path| !path.exists()) {
+                    return Err(STError::Connect { reason: format!("{} doesn't exist", missing.display()), source: None });
+                }
+                ReplaySource::Files(Arc::new(Mutex::new(paths.into_iter().map(Some).collect())))
+            }
+            Some(Source::Tcp(address, peers)) => {
+                let sockets = connect::open_sockets(address.ip(), address.port(), peers)?;
+                ReplaySource::Tcp(Arc::new(Mutex::new(sockets)))
+            }
+            Some(Source::Replay(source)) => source,
+            None => return Err(STError::Config("a pipeline needs a source".to_string())),
+        };
+
+        let metrics = self.analyses.iter().any(|analysis| if let Analysis::Metrics = analysis { true } else { false });
+        let critical_paths = self.analyses.iter().any(|analysis| if let Analysis::CriticalPath = analysis { true } else { false });
+        let rules: Vec<Rule> = self.analyses.iter()
+            .flat_map(|analysis| match analysis {
+                Analysis::Alerts(rules) => rules.clone(),
+                _ => Vec::new(),
+            })
+            .collect();
+        let metric_sinks = if metrics { self.metric_sinks } else { Vec::new() };
+        let (window, speed, filter, sink_options, alert_sinks) = (self.window, self.speed, self.filter, self.sink_options, self.alert_sinks);
+        let (on_epoch, on_alert, operator_names, is_running) = (self.on_epoch, self.on_alert, self.operator_names, self.is_running);
+        let hook_failures = self.hooks.failures();
+        // hooks are invoked by the first peer only
+        let hooks = Arc::new(Mutex::new(Some(self.hooks)));
+
+        // only the first peer delivers results, to sinks opened up front so that
+        // unreachable sinks fail the pipeline before it replays anything
+        let sinks: Vec<(Box<dyn Sink + Send>, AlertSinkConfig)> = alert_sinks.iter()
+            .map(|sink| sink.spec.open(OutputFormat::Text, sink_options.retries).map(|opened| (opened, sink.clone())))
+            .collect::<Result<_, _>>()?;
+        let publisher = Publisher::open(&metric_sinks, &sink_options, &operator_names)?;
+        let delivery = Arc::new(Mutex::new(Some((sinks, publisher))));
+        // epochs cut short by an earlier pipeline of this process don't concern
+        // this one's source, whose epochs are numbered anew
+        PROGRESS.reset_truncated();
+        // the first error of a worker that couldn't create its readers
+        let failure: Arc<Mutex<Option<STError>>> = Arc::new(Mutex::new(None));
+        let failed = Arc::clone(&failure);
+
+        let epochs = Arc::new(AtomicU64::new(0));
+        let alerts = Arc::new(AtomicU64::new(0));
+        let (completed, fired) = (Arc::clone(&epochs), Arc::clone(&alerts));
+
+        let local_peers = crate::local_peers(&self.timely_configuration);
+
+        let guards = timely::execute(self.timely_configuration, move |worker| {
+            crate::self_profile::attach(worker);
+            let index = worker.index();
+            let rules = rules.clone();
+            let operator_names = operator_names.clone();
+            let on_epoch = on_epoch.clone();
+            let on_alert = on_alert.clone();
+            let (completed, fired) = (Arc::clone(&completed), Arc::clone(&fired));
+            let mut hooks = if index == 0 { hooks.lock().unwrap().take().unwrap_or_default() } else { Hooks::new() };
+            let delivered = if index == 0 { delivery.lock().unwrap().take() } else { None };
+            let (mut sinks, mut publisher) = match delivered {
+                Some((sinks, publisher)) => (sinks, Some(publisher)),
+                None => (Vec::new(), None),
+            };
+
+            // read replayers from file (offline) or TCP stream (online); a
+            // worker without readers still joins the dataflow, so that the
+            // others don't wait for it, but stops the replay
+            let readers = match crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers) {
+                Ok(readers) => readers,
+                Err(e) => {
+                    is_running.store(false, Ordering::Release);
+                    failed.lock().unwrap().get_or_insert(e);
+                    Vec::new()
+                }
+            };
+
+            worker.dataflow(|scope| {
+                let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
+
+                let mut vector = Vec::new();
+                let mut pending = PendingEpochs::new("Pipeline");
+                let mut latest = 0;
+                pag.sink(Exchange::new(|_: &(PagEdge, Pair<u64, Duration>, isize)| 0), "Pipeline", move |input| {
+                    input.for_each(|_cap, data| {
+                        data.swap(&mut vector);
+                        for (edge, _t, _diff) in vector.drain(..) {
+                            latest = std::cmp::max(latest, edge.source.epoch);
+                            pending.push(edge);
+                        }
+                    });
+
+                    // edges of epoch `e` are produced at `Pair(e, _)`
+                    let frontier = input.frontier().frontier();
+                    while let Some(key) = pending.first().map(|epoch| epoch / window) {
+                        if frontier.iter().any(|t| t.first < (key + 1) * window) {
+                            break;
+                        }
+                        let epochs = pending.take_until((key + 1) * window);
+                        // all epochs of the window were dropped for the memory budget
+                        let last = match epochs.keys().next_back() {
+                            Some(last) => *last,
+                            None => continue,
+                        };
+                        let complete = Window::new(&epochs, latest - last);
+
+                        for (epoch, edges) in epochs.into_iter() {
+                            let stats = &complete.epochs[&epoch];
+                            let samples = if metrics || !hooks.is_empty() { epoch_samples(epoch, &edges, latest - epoch, &operator_names) } else { Vec::new() };
+                            if let Some(publisher) = publisher.as_mut() {
+                                publisher.publish(epoch, &edges, &samples);
+                            }
+                            hooks.call(&EpochContext {
+                                epoch,
+                                edges: &edges,
+                                critical_path: &stats.critical_path,
+                                latency: stats.latency,
+                                samples: &samples,
+                                ahead: latest - epoch,
+                                operator_names: &operator_names,
+                            });
+                            let report = EpochReport {
+                                epoch,
+                                latency: stats.latency,
+                                ahead: complete.ahead,
+                                critical_path: if critical_paths { Some(stats.critical_path.clone()) } else { None },
+                                samples: if metrics { samples } else { Vec::new() },
+                                edges,
+                            };
+                            for callback in on_epoch.iter() {
+                                (callback.lock().unwrap())(&report);
+                            }
+                            completed.fetch_add(1, Ordering::Relaxed);
+                        }
+
+                        for alert in evaluate(&rules, (key * window, (key + 1) * window - 1), &complete, &operator_names) {
+                            fired.fetch_add(1, Ordering::Relaxed);
+                            for (sink, _) in sinks.iter_mut().filter(|(_, config)| config.accepts(&alert)) {
+                                if let Err(e) = sink.emit(&alert) {
+                                    error!("couldn't deliver alert: {}", e);
+                                }
+                            }
+                            for callback in on_alert.iter() {
+                                (callback.lock().unwrap())(&alert);
+                            }
+                        }
+                    }
+                });
+            });
+        })
+            .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+        for result in guards.join() {
+            result.map_err(|x| STError::Analysis(format!("a worker of the timely computation failed: {}", x)))?;
+        }
+        if let Some(e) = failure.lock().unwrap().take() {
+            return Err(e);
+        }
+
+        Ok(Summary {
+            epochs: epochs.load(Ordering::Acquire),
+            alerts: alerts.load(Ordering::Acquire),
+            hook_failures: hook_failures.load(Ordering::Acquire),
+        })
+    }
+}
+
+/// A pipeline running in a background thread
+pub struct Handle {
+    is_running: Arc<AtomicBool>,
+    thread: JoinHandle<Result<Summary, STError>>,
+}
+
+impl Handle {
+    /// Stops replaying the source; the epochs in flight are still completed.
+    pub fn stop(&self) {
+        self.is_running.store(false, Ordering::Release);
+    }
+
+    /// Waits until the pipeline finished.
+    pub fn join(self) -> Result<Summary, STError> {
+        self.thread.join().map_err(|_| STError::Analysis("the pipeline panicked".to_string()))?
+    }
+}
@@ -0,0 +1,186 @@
+use crate::pag::PagEdge;
+use crate::commands::{calculate_hash, expect_write};
+use crate::commands::export::{csv_field, edge_row, EDGE_COLUMNS};
+use crate::commands::metrics::MetricsSummary;
+use crate::commands::snapshot::critical_path;
+
+use timely::dataflow::Scope;
+use timely::dataflow::Stream;
+use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::aggregation::aggregate::Aggregate;
+
+use std::time::Duration;
+use std::sync::{Arc, Mutex, atomic::AtomicBool};
+use std::io::Write;
+use std::path::PathBuf;
+
+use st2_logformat::pair::Pair;
+use st2_timely::replay_throttled::{ReplayThrottled, ReplaySpeed};
+
+use tdiag_connect::receive as connect;
+use tdiag_connect::receive::ReplaySource;
+
+use crate::STError;
+use crate::config::Args;
+
+/// What leaf ST2 instances forward to an aggregating instance (`metrics --forward`),
+/// at the time of the epoch's summaries, i.e. `Pair(epoch + 1, 0)`
+#[derive(Abomonation, Clone, Debug)]
+pub enum Forwarded {
+    /// A per-epoch metrics summary
+    Summary(MetricsSummary),
+    /// An edge of the leaf's partial PAG (`metrics --forward-pag`)
+    Edge(PagEdge),
+}
+
+/// The `aggregate` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("aggregate")
+        .about("Merge metrics forwarded by leaf ST2 instances into global metrics. \
+                Set --source-peers to the total number of leaf ST2 peers.")
+        .arg(clap::Arg::with_name("output_path")
+            .short("o")
+            .long("out")
+            .value_name("PATH")
+            .help("The output path for the generated CSV file (don't forget the .CSV extension)")
+            .default_value("metrics.csv"))
+        .arg(clap::Arg::with_name("critical_paths")
+            .long("critical-paths")
+            .value_name("PATH")
+            .help("Additionally write every epoch's critical path through the PAG edges forwarded by leaves \
+                   (metrics --forward-pag) to a CSV file")
+            .takes_value(true))
+}
+
+/// The arguments of `aggregate`
+pub struct Options {
+    /// The CSV file of global metrics
+    pub output_path: PathBuf,
+    /// The CSV file of global critical paths
+    pub critical_paths_path: Option<PathBuf>,
+}
+
+/// Parses the arguments of `aggregate`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        output_path: PathBuf::from(args.value_of("output_path").expect("error parsing aggregate output args")),
+        critical_paths_path: args.value_of("critical_paths").map(PathBuf::from),
+    })
+}
+
+/// Merges per-epoch metrics summaries forwarded by leaf ST2 instances
+/// (`metrics --forward`) in `replay_source` into global metrics.
+/// Every ST2 peer of every leaf instance provides a separate summary stream.
+/// If `critical_paths_path` is set, the edges of every epoch's critical path
+/// through the leaves' partial PAGs (`metrics --forward-pag`) are written there.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    output_path: &std::path::Path,
+    critical_paths_path: Option<&std::path::Path>) -> Result<(), STError> {
+
+    let file = Arc::new(Mutex::new(std::fs::File::create(output_path).map_err(STError::from)?));
+    let cp_file = if let Some(path) = critical_paths_path {
+        Some(Arc::new(Mutex::new(std::fs::File::create(path).map_err(STError::from)?)))
+    } else {
+        None
+    };
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+
+        // read summaries from file (offline) or TCP stream (online)
+        let readers = connect::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+            let file = Arc::clone(&file);
+
+            if index == 0 {
+                expect_write(writeln!(*file.lock().unwrap(), "epoch,from_worker,to_worker,activity_type,#(activities),t(activities),#(records)"));
+            }
+
+            let forwarded: Stream<_, Forwarded> = readers.replay_throttled_into(index, scope, Some(Arc::clone(&is_running)), 1, ReplaySpeed::Unbounded);
+
+            forwarded
+                .flat_map(|x| match x {
+                    Forwarded::Summary(summary) => Some(summary),
+                    Forwarded::Edge(_) => None,
+                })
+                .merge_metrics()
+                .inspect_time(move |t,x| expect_write(
+                    writeln!(*file.lock().unwrap(),
+                             "{:?},{},{},{:?},{},{},{}",
+                             t.first - 1, x.0, x.1, x.2, x.3, x.4, x.5)
+                ));
+
+            if let Some(cp_file) = cp_file.clone() {
+                if index == 0 {
+                    let columns: Vec<_> = EDGE_COLUMNS.iter().map(|(name, _)| *name).collect();
+                    expect_write(writeln!(*cp_file.lock().unwrap(), "{}", columns.join(",")));
+                }
+
+                forwarded
+                    .flat_map(|x| match x {
+                        Forwarded::Edge(edge) => Some(edge),
+                        Forwarded::Summary(_) => None,
+                    })
+                    .merge_critical_paths()
+                    .inspect_time(move |t, path| {
+                        let mut cp_file = cp_file.lock().unwrap();
+                        for edge in path.iter() {
+                            let fields: Vec<_> = edge_row(t.first - 1, edge).fields.iter().map(csv_field).collect();
+                            expect_write(writeln!(*cp_file, "{}", fields.join(",")));
+                        }
+                    });
+            }
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    Ok(())
+}
+
+/// Merges partial metrics summaries of the same epoch.
+pub trait MergeMetrics<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Sums up activity counts, durations, and record counts
+    /// per epoch, worker pair, and activity type.
+    fn merge_metrics(&self) -> Stream<S, MetricsSummary>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> MergeMetrics<S> for Stream<S, MetricsSummary> {
+    fn merge_metrics(&self) -> Stream<S, MetricsSummary> {
+        self
+            .map(|(wf, wt, a, ac, at, rc)| ((wf, wt, a), (ac, at, rc)))
+            .aggregate::<_,(u64, u64, u64),_,_,_>(
+                |_key, (ac, at, rc), acc| {
+                    *acc = (acc.0 + ac, acc.1 + at, acc.2 + rc);
+                },
+                |key, acc| (key.0, key.1, key.2, acc.0, acc.1, acc.2),
+                |key| calculate_hash(key))
+    }
+}
+
+/// Merges partial PAGs of the same epoch.
+pub trait MergeCriticalPaths<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// The critical path (cf. `snapshot::critical_path`) through the union of
+    /// every epoch's partial PAGs, in order. Edges between source peers
+    /// analyzed by different leaves aren't part of any partial PAG, so the
+    /// path may be shorter than the epoch's actual critical path.
+    fn merge_critical_paths(&self) -> Stream<S, Vec<PagEdge>>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> MergeCriticalPaths<S> for Stream<S, PagEdge> {
+    fn merge_critical_paths(&self) -> Stream<S, Vec<PagEdge>> {
+        self
+            .map(|edge| (edge.source.epoch, edge))
+            .aggregate::<_,Vec<PagEdge>,_,_,_>(
+                |_epoch, edge, edges| edges.push(edge),
+                |_epoch, edges| critical_path(&edges),
+                |epoch| calculate_hash(epoch))
+    }
+}
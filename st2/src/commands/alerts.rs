@@ -0,0 +1,1024 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::budget::PendingEpochs;
+use crate::commands::snapshot::{critical_path, Snapshot};
+use crate::history::History;
+use crate::commands::publish::{Publisher, SinkConfig as MetricSinkConfig, SinkOptions};
+use crate::store::{epoch_samples, Sample};
+use crate::scripting::{self, Scripts};
+
+use timely::dataflow::Stream;
+use timely::dataflow::channels::pact::Exchange;
+use timely::dataflow::operators::generic::operator::Operator;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::sync::mpsc;
+use std::sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use st2_logformat::pair::Pair;
+use st2_logformat::ActivityType;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use crate::commands::query::parse_time;
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+/// What a rule measures of a window of epochs
+#[derive(Clone, Debug, PartialEq)]
+pub enum Metric {
+    /// The highest epoch latency, in ns
+    Latency,
+    /// The share of an operator (given by id or name), or of a team's or tag's
+    /// operators (`team:<TEAM>`, `tag:<TAG>`, cf. `labels`), in the duration of
+    /// the window's critical paths, from 0 to 1
+    CpShare(String),
+    /// The number of epochs the source computation is ahead of the window,
+    /// i.e., how far the analysis lags behind
+    Backlog,
+    /// The highest ratio of the busiest worker's busy time to the workers'
+    /// average busy time in an epoch; 1 if the load is perfectly balanced
+    Skew,
+}
+
+/// An alerting rule, e.g. `latency > 500ms`, `cp_share(Map) > 40%`,
+/// `backlog > 10`, or `skew > 2`
+#[derive(Clone, Debug)]
+pub struct Rule {
+    /// The rule as given
+    pub text: String,
+    /// The measured metric
+    pub metric: Metric,
+    /// Whether the rule fires below (`<`) rather than above (`>`) the threshold
+    pub below: bool,
+    /// The threshold, in the metric's unit
+    pub threshold: f64,
+}
+
+impl std::str::FromStr for Rule {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s.find(|c| c == '<' || c == '>')
+            .ok_or_else(|| STError::Config(format!("{}: expected METRIC > THRESHOLD or METRIC < THRESHOLD", s)))?;
+        let (metric, threshold) = (s[.. split].trim(), s[split + 1 ..].trim());
+
+        let metric = match metric {
+            "latency" => Metric::Latency,
+            "backlog" => Metric::Backlog,
+            "skew" => Metric::Skew,
+            metric if metric.starts_with("cp_share(") && metric.ends_with(')') => {
+                let operator = metric["cp_share(".len() .. metric.len() - 1].trim();
+                if operator.is_empty() {
+                    return Err(STError::Config(format!("{}: cp_share needs an operator id or name, team:<TEAM>, or tag:<TAG>", s)));
+                }
+                Metric::CpShare(operator.to_string())
+            }
+            metric => return Err(STError::Config(format!("{}: unknown metric {} (expected latency, cp_share(OPERATOR), backlog, or skew)", s, metric))),
+        };
+
+        let number = |threshold: &str| threshold.parse::<f64>().ok().filter(|x| x.is_finite());
+        let threshold = match metric {
+            Metric::Latency => parse_time(threshold)?.as_nanos() as f64,
+            Metric::CpShare(_) if threshold.ends_with('%') => number(&threshold[.. threshold.len() - 1]).map(|x| x / 100.0)
+                .ok_or_else(|| STError::Config(format!("{}: invalid share {}", s, threshold)))?,
+            _ => number(threshold).ok_or_else(|| STError::Config(format!("{}: invalid threshold {}", s, threshold)))?,
+        };
+
+        Ok(Rule { text: s.trim().to_string(), metric, below: s[split ..].starts_with('<'), threshold })
+    }
+}
+
+impl Rule {
+    /// The rule's value for `window` and the epoch that determined it
+    fn measure(&self, window: &Window, operator_names: &BTreeMap<u64, String>) -> Option<(f64, u64)> {
+        match &self.metric {
+            Metric::Latency => window.epochs.iter()
+                .max_by_key(|(_, epoch)| epoch.latency)
+                .map(|(e, epoch)| (epoch.latency as f64, *e)),
+            Metric::Skew => window.epochs.iter()
+                .max_by(|(_, a), (_, b)| a.skew.partial_cmp(&b.skew).expect("skew is finite"))
+                .map(|(e, epoch)| (epoch.skew, *e)),
+            Metric::Backlog => window.epochs.keys().next_back()
+                .map(|e| (window.ahead as f64, *e)),
+            Metric::CpShare(operator) => {
+                let matches = |id: u64| crate::labels::selects(operator, id, operator_names);
+                let shares: Vec<(u64, u64, u64)> = window.epochs.iter()
+                    .map(|(e, epoch)| {
+                        let total = epoch.critical_path.iter().map(|edge| edge.duration()).sum::<u64>();
+                        let share = epoch.critical_path.iter()
+                            .filter(|edge| edge.operator_id.map_or(false, matches))
+                            .map(|edge| edge.duration())
+                            .sum::<u64>();
+                        (*e, share, total)
+                    })
+                    .collect();
+
+                let total = shares.iter().map(|(_, _, total)| total).sum::<u64>();
+                if total == 0 {
+                    return None;
+                }
+                let share = shares.iter().map(|(_, share, _)| share).sum::<u64>();
+                let (epoch, _, _) = shares.iter()
+                    .filter(|(_, _, total)| *total > 0)
+                    .max_by(|(_, a, x), (_, b, y)| (*a as f64 / *x as f64).partial_cmp(&(*b as f64 / *y as f64)).expect("share is finite"))
+                    .expect("an epoch with a critical path");
+                Some((share as f64 / total as f64, *epoch))
+            }
+        }
+    }
+
+    fn fires(&self, value: f64) -> bool {
+        if self.below { value < self.threshold } else { value > self.threshold }
+    }
+
+    /// `value` in the metric's unit
+    fn format(&self, value: f64) -> String {
+        match self.metric {
+            Metric::Latency => format!("{:.1}ms", value / 1_000_000.0),
+            Metric::CpShare(_) => format!("{:.1}%", value * 100.0),
+            Metric::Backlog => format!("{} epochs", value),
+            Metric::Skew => format!("{:.2}x", value),
+        }
+    }
+}
+
+/// A fired rule
+#[derive(Clone, Debug, Serialize)]
+pub struct Alert {
+    /// The rule as given, or the name of a scripted alert (cf. `scripting`)
+    pub rule: String,
+    /// Human-readable description of the alert
+    pub message: String,
+    /// The first and last epoch of the window the rule fired for
+    pub window: (u64, u64),
+    /// The rule's value for the window
+    pub value: f64,
+    /// The rule's threshold
+    pub threshold: f64,
+    /// The epoch that determined the value, e.g. the slowest one for `latency`
+    pub epoch: u64,
+    /// The latency of `epoch`, in ns
+    pub latency: u64,
+    /// The critical path of `epoch` (cf. `snapshot::critical_path`)
+    pub critical_path: Vec<PagEdge>,
+}
+
+/// Timeout of a single HTTP request to a sink
+pub(crate) const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before the first retry of a failed HTTP request, doubled for every further retry
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Maximum wait between retries
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// PagerDuty's Events API v2
+const PAGERDUTY_URL: &str = "https://events.pagerduty.com/v2/enqueue";
+/// Number of critical path edges shown in chat messages
+const SUMMARY_EDGES: usize = 3;
+/// Number of recent epochs whose metrics evidence bundles include
+const HISTORY: usize = 100;
+
+/// Where alerts are delivered to, e.g. `stdout`, `file:alerts.jsonl`, or
+/// `slack:https://hooks.slack.com/services/...`
+#[derive(Clone, Debug, PartialEq)]
+pub enum SinkSpec {
+    /// Prints alerts to stdout, in the `--output` format
+    Stdout,
+    /// Appends alerts as JSON lines to a file
+    File(PathBuf),
+    /// POSTs alerts to a URL: the alert's JSON, or the rendered template
+    Webhook(String, Option<Template>),
+    /// POSTs alerts as messages to a Slack incoming webhook URL
+    Slack(String),
+    /// Triggers PagerDuty incidents with an integration's routing key
+    PagerDuty(String),
+    /// Appends alerts to a local SQLite database (cf. `history`; requires the `sqlite` feature)
+    Sqlite(PathBuf),
+}
+
+impl std::str::FromStr for SinkSpec {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdout" => Ok(SinkSpec::Stdout),
+            s if s.starts_with("file:") && s.len() > "file:".len() => Ok(SinkSpec::File(PathBuf::from(&s["file:".len() ..]))),
+            s if s.starts_with("webhook:") && s.len() > "webhook:".len() => Ok(SinkSpec::Webhook(s["webhook:".len() ..].to_string(), None)),
+            s if s.starts_with("slack:") && s.len() > "slack:".len() => Ok(SinkSpec::Slack(s["slack:".len() ..].to_string())),
+            s if s.starts_with("pagerduty:") && s.len() > "pagerduty:".len() => Ok(SinkSpec::PagerDuty(s["pagerduty:".len() ..].to_string())),
+            s if s.starts_with("sqlite:") && s.len() > "sqlite:".len() => Ok(SinkSpec::Sqlite(PathBuf::from(&s["sqlite:".len() ..]))),
+            s => Err(STError::Config(format!("{}: expected stdout, file:PATH, webhook:URL, slack:URL, pagerduty:ROUTING_KEY, or sqlite:PATH", s))),
+        }
+    }
+}
+
+/// An alert sink with its filter, given as the sink followed by whitespace-separated
+/// options, e.g. `slack:https://hooks.slack.com/... rules=latency,skew`:
+///
+/// - `rules=METRIC,...`: only alerts of rules on these metrics, e.g. `latency` or `cp_share`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SinkConfig {
+    /// The sink
+    pub spec: SinkSpec,
+    /// Metrics of the rules whose alerts the sink gets; empty for all rules
+    pub rules: Vec<String>,
+}
+
+impl std::str::FromStr for SinkConfig {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = s.split_whitespace();
+        let spec = tokens.next().unwrap_or("").parse()?;
+        let mut rules = Vec::new();
+        for option in tokens {
+            let mut parts = option.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("rules"), Some(metrics)) => rules = metrics.split(',').filter(|metric| !metric.is_empty()).map(|metric| metric.to_string()).collect(),
+                _ => return Err(STError::Config(format!("invalid option {} (expected rules=METRIC,...)", option))),
+            }
+        }
+        Ok(SinkConfig { spec, rules })
+    }
+}
+
+impl SinkConfig {
+    /// Uses `template` for the payload of a webhook sink that doesn't have a template yet.
+    pub fn with_template(self, template: Option<&Template>) -> Self {
+        SinkConfig { spec: self.spec.with_template(template), rules: self.rules }
+    }
+
+    /// Whether the sink gets `alert`
+    pub(crate) fn accepts(&self, alert: &Alert) -> bool {
+        self.rules.is_empty() || self.rules.iter().any(|metric| alert.rule.trim_start().starts_with(metric.as_str()))
+    }
+}
+
+impl SinkSpec {
+    /// Uses `template` for the payload of a webhook sink that doesn't have a template yet.
+    pub fn with_template(self, template: Option<&Template>) -> Self {
+        match self {
+            SinkSpec::Webhook(url, None) => SinkSpec::Webhook(url, template.cloned()),
+            sink => sink,
+        }
+    }
+
+    /// Opens the sink. HTTP requests are retried up to `retries` times with
+    /// exponential backoff.
+    pub fn open(&self, output_format: OutputFormat, retries: u32) -> Result<Box<dyn Sink + Send>, STError> {
+        match self {
+            SinkSpec::Stdout => Ok(Box::new(StdoutSink { output_format })),
+            SinkSpec::File(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)
+                    .map_err(|e| STError::io(format!("couldn't open alert sink {}", path.display()), e))?;
+                Ok(Box::new(FileSink { file }))
+            }
+            SinkSpec::Webhook(url, template) => {
+                let template = template.clone();
+                Ok(Box::new(HttpSink::new(url.clone(), retries, move |alert| match &template {
+                    Some(template) => template.render(alert),
+                    None => serde_json::to_value(alert).expect("alerts are serializable"),
+                })))
+            }
+            SinkSpec::Slack(url) => Ok(Box::new(HttpSink::new(url.clone(), retries, slack_payload))),
+            SinkSpec::PagerDuty(routing_key) => {
+                let routing_key = routing_key.clone();
+                Ok(Box::new(HttpSink::new(PAGERDUTY_URL.to_string(), retries, move |alert| pagerduty_payload(&routing_key, alert))))
+            }
+            SinkSpec::Sqlite(path) => Ok(Box::new(History::open(path)?)),
+        }
+    }
+}
+
+/// A destination of alerts
+pub trait Sink {
+    /// Delivers `alert`.
+    fn emit(&mut self, alert: &Alert) -> Result<(), STError>;
+}
+
+struct StdoutSink {
+    output_format: OutputFormat,
+}
+
+impl Sink for StdoutSink {
+    fn emit(&mut self, alert: &Alert) -> Result<(), STError> {
+        let json = serde_json::to_value(alert).map_err(|e| STError::Analysis(format!("couldn't serialize alert: {}", e)))?;
+        self.output_format.print(format_args!("ALERT {}", alert.message), json);
+        Ok(())
+    }
+}
+
+struct FileSink {
+    file: File,
+}
+
+impl Sink for FileSink {
+    fn emit(&mut self, alert: &Alert) -> Result<(), STError> {
+        let mut line = serde_json::to_vec(alert).map_err(|e| STError::Analysis(format!("couldn't serialize alert: {}", e)))?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+impl Sink for History {
+    fn emit(&mut self, alert: &Alert) -> Result<(), STError> {
+        let fired_at = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        self.insert_alert(alert, fired_at)
+    }
+}
+
+/// Delivers alerts by HTTP POST. Requests are sent by a background thread, so
+/// slow or unavailable endpoints don't stall the analysis; pending alerts are
+/// still delivered when the sink is dropped.
+struct HttpSink {
+    send: Option<mpsc::Sender<Alert>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HttpSink {
+    /// A sink POSTing the `payload` of alerts to `url`
+    fn new(url: String, retries: u32, payload: impl Fn(&Alert) -> Value + Send + 'static) -> Self {
+        let (send, recv) = mpsc::channel::<Alert>();
+        let thread = std::thread::spawn(move || {
+            for alert in recv {
+                if let Err(e) = post(&url, &payload(&alert), retries) {
+                    error!("couldn't deliver alert `{}`: {}", alert.rule, e);
+                }
+            }
+        });
+        HttpSink { send: Some(send), thread: Some(thread) }
+    }
+}
+
+impl Sink for HttpSink {
+    fn emit(&mut self, alert: &Alert) -> Result<(), STError> {
+        self.send.as_ref().expect("sink is open").send(alert.clone())
+            .map_err(|_| STError::Analysis("HTTP sink stopped".to_string()))
+    }
+}
+
+impl Drop for HttpSink {
+    fn drop(&mut self) {
+        self.send.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// POSTs `body` to `url`, cf. `send`.
+fn post(url: &str, body: &Value, retries: u32) -> Result<(), STError> {
+    send("POST", url, retries, || ureq::post(url).timeout(HTTP_TIMEOUT).send_json(body.clone()))
+}
+
+/// Sends an HTTP request with `request`. Connection errors, rate limiting (429),
+/// and server errors (5xx) are retried up to `retries` times with exponential backoff.
+pub(crate) fn send(method: &str, url: &str, retries: u32, request: impl Fn() -> ureq::Response) -> Result<(), STError> {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 0 ..= retries {
+        let response = request();
+        if response.ok() {
+            return Ok(());
+        }
+
+        let status = response.status();
+        let error = format!("{} {}: {}", method, url, response.status_line());
+        if !(response.synthetic() || status == 429 || status >= 500) || attempt == retries {
+            return Err(STError::Protocol(error));
+        }
+        warn!("{}, retrying in {:?}", error, backoff);
+        std::thread::sleep(backoff);
+        backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+    unreachable!("the last attempt returns")
+}
+
+/// A Slack message for `alert`: its description and the longest edges of
+/// the offending epoch's critical path
+fn slack_payload(alert: &Alert) -> Value {
+    let mut text = format!(":rotating_light: *ST2 alert* {}", alert.message);
+    for edge in longest_edges(alert) {
+        text.push_str(&format!("\n• {}", describe(edge)));
+    }
+    json!({ "text": text })
+}
+
+/// A PagerDuty event triggering an incident for `alert`. Alerts of the same rule
+/// for the same window are deduplicated.
+fn pagerduty_payload(routing_key: &str, alert: &Alert) -> Value {
+    // PagerDuty truncates longer summaries
+    let summary: String = alert.message.chars().take(1024).collect();
+    json!({
+        "routing_key": routing_key,
+        "event_action": "trigger",
+        "dedup_key": format!("st2/{}/{}", alert.rule, alert.window.0),
+        "payload": {
+            "summary": summary,
+            "source": "st2",
+            "severity": "error",
+            "component": format!("epoch {}", alert.epoch),
+            "custom_details": {
+                "rule": alert.rule,
+                "window": alert.window,
+                "value": alert.value,
+                "threshold": alert.threshold,
+                "latency_ns": alert.latency,
+                "critical_path": longest_edges(alert).into_iter().map(describe).collect::<Vec<_>>(),
+            },
+        },
+    })
+}
+
+/// The `SUMMARY_EDGES` longest edges of the alert's critical path
+fn longest_edges(alert: &Alert) -> Vec<&PagEdge> {
+    let mut edges: Vec<_> = alert.critical_path.iter().collect();
+    edges.sort_by_key(|edge| std::cmp::Reverse(edge.duration()));
+    edges.truncate(SUMMARY_EDGES);
+    edges
+}
+
+fn describe(edge: &PagEdge) -> String {
+    let operator = edge.operator_id.map(|id| format!(" of operator {}", id)).unwrap_or_default();
+    format!("{:?}{} on worker {}: {:.1}ms", edge.edge_type, operator, edge.source.worker_id, edge.duration() as f64 / 1_000_000.0)
+}
+
+/// A JSON payload template for webhooks. Strings in the template may contain
+/// placeholders, `{{rule}}`, `{{message}}`, `{{window_from}}`, `{{window_to}}`,
+/// `{{value}}`, `{{threshold}}`, `{{epoch}}`, `{{latency}}`, `{{critical_path}}`,
+/// and `{{alert}}` (all of the above), which are replaced by the alert's values.
+/// A string consisting of a single placeholder is replaced by the value itself,
+/// e.g. a number or an array for `{{critical_path}}`, rather than its text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template(Value);
+
+/// Names of the placeholders of `Template`s
+const PLACEHOLDERS: [&str; 10] = ["rule", "message", "window_from", "window_to", "value", "threshold", "epoch", "latency", "critical_path", "alert"];
+
+impl std::str::FromStr for Template {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let template: Value = serde_json::from_str(s).map_err(|e| STError::Config(format!("invalid JSON: {}", e)))?;
+
+        fn check(value: &Value) -> Result<(), STError> {
+            match value {
+                Value::String(s) => {
+                    let mut rest = s.as_str();
+                    while let Some(start) = rest.find("{{") {
+                        let end = rest[start ..].find("}}").ok_or_else(|| STError::Config(format!("unclosed placeholder in {:?}", s)))?;
+                        let name = &rest[start + 2 .. start + end];
+                        if !PLACEHOLDERS.contains(&name) {
+                            return Err(STError::Config(format!("unknown placeholder {{{{{}}}}} (expected one of {})", name, PLACEHOLDERS.join(", "))));
+                        }
+                        rest = &rest[start + end + 2 ..];
+                    }
+                    Ok(())
+                }
+                Value::Array(values) => values.iter().map(check).collect(),
+                Value::Object(values) => values.values().map(check).collect(),
+                _ => Ok(()),
+            }
+        }
+        check(&template)?;
+
+        Ok(Template(template))
+    }
+}
+
+impl Template {
+    /// The payload for `alert`
+    pub fn render(&self, alert: &Alert) -> Value {
+        let alert_json = serde_json::to_value(alert).expect("alerts are serializable");
+        let field = |name: &str| match name {
+            "rule" => json!(alert.rule),
+            "message" => json!(alert.message),
+            "window_from" => json!(alert.window.0),
+            "window_to" => json!(alert.window.1),
+            "value" => json!(alert.value),
+            "threshold" => json!(alert.threshold),
+            "epoch" => json!(alert.epoch),
+            "latency" => json!(alert.latency),
+            "critical_path" => alert_json["critical_path"].clone(),
+            "alert" => alert_json.clone(),
+            _ => unreachable!("placeholders are checked when parsing"),
+        };
+
+        fn render(value: &Value, field: &dyn Fn(&str) -> Value) -> Value {
+            match value {
+                Value::String(s) if s.starts_with("{{") && s.ends_with("}}") && s.matches("{{").count() == 1 => field(&s[2 .. s.len() - 2]),
+                Value::String(s) => {
+                    let mut text = String::new();
+                    let mut rest = s.as_str();
+                    while let Some(start) = rest.find("{{") {
+                        let end = start + rest[start ..].find("}}").expect("placeholders are closed");
+                        text.push_str(&rest[.. start]);
+                        match field(&rest[start + 2 .. end]) {
+                            Value::String(s) => text.push_str(&s),
+                            value => text.push_str(&value.to_string()),
+                        }
+                        rest = &rest[end + 2 ..];
+                    }
+                    text.push_str(rest);
+                    Value::String(text)
+                }
+                Value::Array(values) => Value::Array(values.iter().map(|value| render(value, field)).collect()),
+                Value::Object(values) => Value::Object(values.iter().map(|(key, value)| (key.clone(), render(value, field))).collect()),
+                value => value.clone(),
+            }
+        }
+        render(&self.0, &field)
+    }
+}
+
+/// Where evidence bundles are stored: a directory, or the URL of an object store
+/// prefix that bundle files are PUT under, e.g. `https://bucket.s3.amazonaws.com/st2`
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvidenceStore {
+    /// A local directory
+    Dir(PathBuf),
+    /// An HTTP(S) URL prefix
+    Url(String),
+}
+
+impl std::str::FromStr for EvidenceStore {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            Err(STError::Config("expected a directory or an http(s):// URL".to_string()))
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(EvidenceStore::Url(s.trim_end_matches('/').to_string()))
+        } else {
+            Ok(EvidenceStore::Dir(PathBuf::from(s)))
+        }
+    }
+}
+
+impl EvidenceStore {
+    /// Stores the `files` of the bundle `name`, retrying failed uploads up to `retries` times.
+    fn put(&self, name: &str, files: &[(String, Vec<u8>)], retries: u32) -> Result<(), STError> {
+        match self {
+            EvidenceStore::Dir(dir) => {
+                let dir = dir.join(name);
+                std::fs::create_dir_all(&dir)?;
+                for (file, contents) in files {
+                    std::fs::write(dir.join(file), contents)?;
+                }
+            }
+            EvidenceStore::Url(prefix) => {
+                for (file, contents) in files {
+                    let url = format!("{}/{}/{}", prefix, name, file);
+                    send("PUT", &url, retries, || ureq::put(&url).timeout(HTTP_TIMEOUT).set("Content-Type", "application/json").send_bytes(contents))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Captures evidence bundles of alerts: the alert, a snapshot (cf. `snapshot`) of
+/// every epoch of its window, the metrics of the `HISTORY` most recent epochs, and
+/// the alerting configuration. Bundles are stored by a background thread, and
+/// pending bundles are still stored when the capture is dropped.
+struct Evidence {
+    send: Option<mpsc::Sender<(String, Vec<(String, Vec<u8>)>)>>,
+    thread: Option<JoinHandle<()>>,
+    /// Metrics of the most recent epochs
+    history: VecDeque<Value>,
+    settings: Value,
+}
+
+impl Evidence {
+    /// Captures bundles to `store`, including the alerting configuration `settings`.
+    fn new(store: EvidenceStore, settings: Value, retries: u32) -> Self {
+        let (send, recv) = mpsc::channel::<(String, Vec<(String, Vec<u8>)>)>();
+        let thread = std::thread::spawn(move || {
+            for (name, files) in recv {
+                match store.put(&name, &files, retries) {
+                    Ok(()) => info!("captured evidence bundle {}", name),
+                    Err(e) => error!("couldn't capture evidence bundle {}: {}", name, e),
+                }
+            }
+        });
+        Evidence { send: Some(send), thread: Some(thread), history: VecDeque::new(), settings }
+    }
+
+    /// Records the metrics of the completed `window`.
+    fn observe(&mut self, window: &Window) {
+        for (epoch, stats) in window.epochs.iter() {
+            self.history.push_back(json!({
+                "epoch": epoch,
+                "latency_ns": stats.latency,
+                "skew": stats.skew,
+                "critical_path_ns": stats.critical_path.iter().map(|edge| edge.duration()).sum::<u64>(),
+            }));
+            if self.history.len() > HISTORY {
+                self.history.pop_front();
+            }
+        }
+    }
+
+    /// Captures the bundle of `alert`, which fired for `window` with the PAG `edges`.
+    fn capture(&self, alert: &Alert, window: &Window, edges: &BTreeMap<u64, Vec<PagEdge>>) -> Result<(), STError> {
+        let mut files = vec![
+            ("alert.json".to_string(), to_json(alert)?),
+            ("history.json".to_string(), to_json(&self.history)?),
+            ("config.json".to_string(), to_json(&self.settings)?),
+        ];
+        for (epoch, edges) in edges.iter() {
+            let mut edges = edges.clone();
+            edges.sort_by_key(|edge| (edge.source.timestamp, edge.source.worker_id, edge.destination.timestamp));
+            let stats = &window.epochs[epoch];
+            let snapshot = Snapshot { epoch: *epoch, latency: stats.latency, edges, critical_path: stats.critical_path.clone() };
+            files.push((format!("snapshot-{}.json", epoch), to_json(&snapshot)?));
+        }
+
+        let since_epoch = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        let rule: String = alert.rule.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+        let name = format!("alert-{}-epoch-{}-{}", since_epoch.as_secs(), alert.epoch, rule);
+
+        self.send.as_ref().expect("capture is open").send((name, files))
+            .map_err(|_| STError::Analysis("evidence capture stopped".to_string()))
+    }
+}
+
+impl Drop for Evidence {
+    fn drop(&mut self) {
+        self.send.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn to_json(value: &impl Serialize) -> Result<Vec<u8>, STError> {
+    serde_json::to_vec_pretty(value).map_err(|e| STError::Analysis(format!("couldn't serialize evidence: {}", e)))
+}
+
+/// Per-epoch results the rules are evaluated on
+pub(crate) struct EpochStats {
+    /// Time from the epoch's first to its last event, in ns
+    pub(crate) latency: u64,
+    pub(crate) critical_path: Vec<PagEdge>,
+    /// cf. `Metric::Skew`
+    pub(crate) skew: f64,
+    /// Busy time of every worker's local activities, in ns
+    pub(crate) busy: HashMap<u64, u64>,
+}
+
+impl EpochStats {
+    pub(crate) fn new(edges: &[PagEdge]) -> Self {
+        let first = edges.iter().map(|edge| edge.source.timestamp).min().unwrap_or_default();
+        let last = edges.iter().map(|edge| edge.destination.timestamp).max().unwrap_or_default();
+
+        // busy time of workers' local activities, as in `top`
+        let mut busy: HashMap<u64, u64> = HashMap::new();
+        for edge in edges.iter().filter(|edge| edge.source.worker_id == edge.destination.worker_id) {
+            let time = match edge.edge_type {
+                ActivityType::Waiting | ActivityType::Spinning => 0,
+                _ => edge.duration(),
+            };
+            *busy.entry(edge.source.worker_id).or_insert(0) += time;
+        }
+        let mean = busy.values().sum::<u64>() as f64 / busy.len().max(1) as f64;
+        let max = busy.values().cloned().max().unwrap_or(0) as f64;
+
+        EpochStats {
+            latency: last.checked_sub(first).unwrap_or_default().as_nanos() as u64,
+            critical_path: critical_path(edges),
+            skew: if mean > 0.0 { max / mean } else { 1.0 },
+            busy,
+        }
+    }
+}
+
+/// A completed window of epochs
+pub struct Window {
+    pub(crate) epochs: BTreeMap<u64, EpochStats>,
+    /// cf. `Metric::Backlog`
+    pub(crate) ahead: u64,
+}
+
+impl Window {
+    /// The window of the epochs of `edges`, `ahead` epochs behind the source computation
+    pub fn new(edges: &BTreeMap<u64, Vec<PagEdge>>, ahead: u64) -> Self {
+        Window {
+            epochs: edges.iter().map(|(epoch, edges)| (*epoch, EpochStats::new(edges))).collect(),
+            ahead,
+        }
+    }
+}
+
+/// The `alerts` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("alerts")
+        .about("Evaluate alerting rules on every completed window of epochs and deliver alerts with the offending epoch's critical path")
+        .after_help("RULES:
+    rule   := metric ('>' | '<') threshold
+    metric := 'latency'              highest epoch latency, e.g. `latency > 500ms`
+            | 'cp_share(' OP ')'     share of operator OP (id or name) in the critical paths, e.g. `cp_share(Map) > 40%`
+            | 'backlog'              epochs the source is ahead of the window, e.g. `backlog > 10`
+            | 'skew'                 busiest worker's busy time / workers' average in an epoch, e.g. `skew > 2`
+
+    Alerts can also be scripted in the --config file's `scripts` table.
+
+SINKS:
+    stdout                  print alerts (in the --output format)
+    file:PATH               append alerts as JSON lines to PATH
+    webhook:URL             POST alerts as JSON (or the --template) to URL
+    slack:URL               post alerts to a Slack incoming webhook
+    pagerduty:ROUTING_KEY   trigger PagerDuty incidents (Events API v2)
+    sqlite:PATH             append to a SQLite database (requires the `sqlite` feature)
+
+    A sink may be followed by options, e.g. \"slack:URL rules=latency,skew\":
+    rules=METRIC,...        only deliver alerts of rules on these metrics, or of scripted alerts with these names
+
+TEMPLATES:
+    JSON payloads for webhooks, in which strings may contain the placeholders {{rule}}, {{message}},
+    {{window_from}}, {{window_to}}, {{value}}, {{threshold}}, {{epoch}}, {{latency}}, {{critical_path}},
+    and {{alert}}. A string that is a single placeholder is replaced by the value itself, e.g.
+    {\"summary\": \"{{message}}\", \"path\": \"{{critical_path}}\"}")
+        .arg(clap::Arg::with_name("rule")
+            .short("r")
+            .long("rule")
+            .value_name("RULE")
+            .help("An alerting rule; can be given several times")
+            .multiple(true)
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("sink")
+            .long("sink")
+            .value_name("SINK")
+            .help("Where to deliver alerts; can be given several times")
+            .multiple(true)
+            .number_of_values(1)
+            .default_value("stdout"))
+        .arg(clap::Arg::with_name("template")
+            .long("template")
+            .value_name("PATH")
+            .help("JSON file with the payload template of webhook sinks (default: the alert's JSON)")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("retries")
+            .long("retries")
+            .value_name("N")
+            .help("Number of retries, with exponential backoff, of failed deliveries to HTTP sinks")
+            .default_value("3"))
+        .arg(clap::Arg::with_name("evidence")
+            .long("evidence")
+            .value_name("DIR|URL")
+            .help("Capture an evidence bundle for every alert to a directory, or PUT its files under an object store URL")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("window")
+            .short("w")
+            .long("window")
+            .value_name("EPOCHS")
+            .help("Number of epochs per evaluated window")
+            .default_value("1"))
+        .arg(clap::Arg::with_name("publish")
+            .long("publish")
+            .value_name("SINK")
+            .help("Also publish every epoch's metrics to a sink of `publish`; can be given several times")
+            .multiple(true)
+            .number_of_values(1))
+}
+
+/// The arguments of `alerts`
+pub struct Options {
+    /// The alerting rules
+    pub rules: Vec<Rule>,
+    /// Where to send alerts to
+    pub sinks: Vec<SinkConfig>,
+    /// The number of retries of failed deliveries
+    pub retries: u32,
+    /// The number of epochs rules are evaluated on
+    pub window: u64,
+    /// Where to store the evidence of alerts
+    pub evidence: Option<EvidenceStore>,
+    /// Where to publish metrics to, e.g. for the context of alerts
+    pub publish: Vec<MetricSinkConfig>,
+    /// How to publish metrics
+    pub publish_options: SinkOptions,
+}
+
+/// Parses the arguments of `alerts`. Unless alerts are scripted, there has to
+/// be a rule.
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    let rules = args.all_values_of("rule").into_iter()
+        .map(|rule| rule.parse::<Rule>().map_err(|e| e.context("Invalid --rule")))
+        .collect::<Result<Vec<_>, _>>()?;
+    if rules.is_empty() && !scripting::installed().map_or(false, |scripts| scripts.has_alerts()) {
+        Err(STError::Config("Invalid --rule: no rules given (nor alerts scripted in the --config)".to_string()))?
+    }
+    let template = match args.value_of("template") {
+        Some(path) => Some(std::fs::read_to_string(path)
+            .map_err(|e| STError::io(path, e))
+            .and_then(|template| template.parse::<Template>())
+            .map_err(|e| e.context("Invalid --template"))?),
+        None => None,
+    };
+    let sinks = args.all_values_of("sink").into_iter()
+        .map(|sink| sink.parse::<SinkConfig>().map_err(|e| e.context("Invalid --sink")))
+        .map(|sink| sink.map(|sink| sink.with_template(template.as_ref())))
+        .collect::<Result<Vec<_>, _>>()?;
+    let retries: u32 = args.value_of("retries").expect("error parsing alerts retries args")
+        .parse().map_err(|e| STError::Config(format!("Invalid --retries: {}", e)))?;
+    let window: u64 = args.value_of("window").expect("error parsing alerts window args")
+        .parse().map_err(|e| STError::Config(format!("Invalid --window: {}", e)))?;
+    if window == 0 {
+        Err(STError::Config("Invalid --window: has to be at least 1".to_string()))?
+    }
+    let evidence = match args.value_of("evidence") {
+        Some(store) => Some(store.parse::<EvidenceStore>().map_err(|e| e.context("Invalid --evidence"))?),
+        None => None,
+    };
+    let publish = args.all_values_of("publish").into_iter()
+        .map(|sink| sink.parse::<MetricSinkConfig>().map_err(|e| e.context("Invalid --publish")))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Options {
+        rules,
+        sinks,
+        retries,
+        window,
+        evidence,
+        publish,
+        publish_options: SinkOptions { retries, ..Default::default() },
+    })
+}
+
+/// Evaluates `rules` on every completed window of `window` epochs of
+/// `replay_source` and delivers the fired rules' alerts to `sinks`, retrying
+/// failed HTTP deliveries up to `retries` times. If an `evidence` store is given,
+/// an evidence bundle (cf. `Evidence`) including the alerting configuration is
+/// captured for every alert. The metrics of every epoch are also published to the
+/// `publish` sinks, so a single analysis can feed dashboards and page people.
+/// Returns the number of alerts.
+///
+/// To compute the epochs' critical paths, all PAG edges of a window are
+/// collected at the first ST2 peer until the window is complete, so windows
+/// should stay small.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    rules: Vec<Rule>,
+    window: u64,
+    sinks: Vec<SinkConfig>,
+    retries: u32,
+    evidence: Option<(EvidenceStore, Value)>,
+    publish: Vec<MetricSinkConfig>,
+    publish_options: SinkOptions,
+    operator_names: &BTreeMap<u64, String>,
+    output_format: OutputFormat) -> Result<u64, STError> {
+
+    let alerts = Arc::new(AtomicU64::new(0));
+    let fired = Arc::clone(&alerts);
+    let operator_names = operator_names.clone();
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let rules = rules.clone();
+        let operator_names = operator_names.clone();
+        let fired = Arc::clone(&fired);
+
+        // only the first peer evaluates rules
+        let mut sinks: Vec<(Box<dyn Sink + Send>, SinkConfig)> = if index == 0 {
+            sinks.iter().map(|sink| sink.spec.open(output_format, retries).map(|opened| (opened, sink.clone())))
+                .collect::<Result<_, _>>().expect("couldn't open alert sinks")
+        } else {
+            Vec::new()
+        };
+        let mut publisher = Publisher::open(if index == 0 { &publish[..] } else { &[] }, &publish_options, &operator_names)
+            .expect("couldn't open metric sinks");
+        let mut evidence = evidence.clone().filter(|_| index == 0)
+            .map(|(store, settings)| Evidence::new(store, settings, retries));
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
+
+            let mut vector = Vec::new();
+            let mut pending = PendingEpochs::new("Alerts");
+            let mut latest = 0;
+            pag.sink(Exchange::new(|_: &(PagEdge, Pair<u64, Duration>, isize)| 0), "Alerts", move |input| {
+                input.for_each(|_cap, data| {
+                    data.swap(&mut vector);
+                    for (edge, _t, _diff) in vector.drain(..) {
+                        latest = std::cmp::max(latest, edge.source.epoch);
+                        pending.push(edge);
+                    }
+                });
+
+                // edges of epoch `e` are produced at `Pair(e, _)`
+                let frontier = input.frontier().frontier();
+                while let Some(key) = pending.first().map(|epoch| epoch / window) {
+                    if frontier.iter().any(|t| t.first < (key + 1) * window) {
+                        break;
+                    }
+                    let epochs = pending.take_until((key + 1) * window);
+                    // all epochs of the window were dropped for the memory budget
+                    let last = match epochs.keys().next_back() {
+                        Some(last) => *last,
+                        None => continue,
+                    };
+                    let complete = Window::new(&epochs, latest - last);
+                    if let Some(evidence) = evidence.as_mut() {
+                        evidence.observe(&complete);
+                    }
+                    let samples: BTreeMap<u64, Vec<Sample>> = epochs.iter()
+                        .map(|(epoch, edges)| (*epoch, epoch_samples(*epoch, edges, latest - epoch, &operator_names)))
+                        .collect();
+                    for (epoch, edges) in epochs.iter() {
+                        publisher.publish(*epoch, edges, &samples[epoch]);
+                    }
+
+                    let span = (key * window, (key + 1) * window - 1);
+                    let mut alerts = evaluate(&rules, span, &complete, &operator_names);
+                    if let Some(scripts) = scripting::installed() {
+                        alerts.extend(evaluate_scripts(scripts, span, &complete, &epochs, &samples));
+                    }
+                    for alert in alerts {
+                        fired.fetch_add(1, Ordering::Relaxed);
+                        for (sink, _) in sinks.iter_mut().filter(|(_, config)| config.accepts(&alert)) {
+                            if let Err(e) = sink.emit(&alert) {
+                                error!("couldn't deliver alert: {}", e);
+                            }
+                        }
+                        if let Some(evidence) = evidence.as_ref() {
+                            if let Err(e) = evidence.capture(&alert, &complete, &epochs) {
+                                error!("couldn't capture evidence: {}", e);
+                            }
+                        }
+                    }
+                }
+            });
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    Ok(alerts.load(Ordering::Acquire))
+}
+
+/// The alerts of `rules` that fire for `window`, which spans the `epochs` (inclusive)
+pub fn evaluate(rules: &[Rule], epochs: (u64, u64), window: &Window, operator_names: &BTreeMap<u64, String>) -> Vec<Alert> {
+    rules.iter()
+        .filter_map(|rule| {
+            let (value, epoch) = rule.measure(window, operator_names)?;
+            if !rule.fires(value) {
+                return None;
+            }
+            let stats = &window.epochs[&epoch];
+            Some(Alert {
+                rule: rule.text.clone(),
+                message: format!("{}: {} in epoch {} (window {}..={}, threshold {})",
+                                 rule.text, rule.format(value), epoch, epochs.0, epochs.1, rule.format(rule.threshold)),
+                window: epochs,
+                value,
+                threshold: rule.threshold,
+                epoch,
+                latency: stats.latency,
+                critical_path: stats.critical_path.clone(),
+            })
+        })
+        .collect()
+}
+
+/// The alerts of `scripts` (cf. `scripting`) that fire for an epoch of `window`,
+/// which spans the `epochs` (inclusive), given every epoch's `edges` and `samples`
+pub(crate) fn evaluate_scripts(scripts: &Scripts, epochs: (u64, u64), window: &Window, edges: &BTreeMap<u64, Vec<PagEdge>>, samples: &BTreeMap<u64, Vec<Sample>>) -> Vec<Alert> {
+    let mut alerts = Vec::new();
+    for (epoch, stats) in window.epochs.iter() {
+        for (name, message) in scripts.alerts(*epoch, &edges[epoch], &samples[epoch]) {
+            alerts.push(Alert {
+                rule: name.to_string(),
+                message: format!("{}: {} in epoch {} (window {}..={})", name, message, epoch, epochs.0, epochs.1),
+                window: epochs,
+                value: 1.0,
+                threshold: 0.0,
+                epoch: *epoch,
+                latency: stats.latency,
+                critical_path: stats.critical_path.clone(),
+            });
+        }
+    }
+    alerts
+}
+
@@ -1,5 +1,6 @@
 use crate::pag;
 use crate::pag::PagEdge;
+use crate::commands::calculate_hash;
 use crate::STError;
 
 use timely::dataflow::Scope;
@@ -15,32 +16,43 @@ use timely::dataflow::channels::pact::Exchange;
 use timely::dataflow::operators::aggregation::aggregate::Aggregate;
 
 use std::time::Duration;
+use std::sync::{Arc, atomic::AtomicBool};
 use std::collections::BTreeSet;
 use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
-use std::collections::hash_map::DefaultHasher;
 
 use st2_logformat::pair::Pair;
 use st2_logformat::ActivityType;
 
-use tdiag_connect::receive as connect;
 use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
 
 
 
+/// The `algo` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("algo")
+        .about("run ST2 graph algorithms")
+}
+
 /// Runs graph algorithms on ST2.
 pub fn run(
     timely_configuration: timely::Configuration,
-    replay_source: ReplaySource) -> Result<(), STError> {
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: st2_timely::filter::Filter) -> Result<(), STError> {
+
+    let local_peers = crate::local_peers(&timely_configuration);
 
     timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
         let index = worker.index();
 
         // read replayers from file (offline) or TCP stream (online)
-        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
 
         worker.dataflow(|scope| {
-            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = pag::create_pag(scope, readers, index, 1);
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
 
             pag
                 .khops()
@@ -48,7 +60,7 @@ pub fn run(
                 .inspect_time(|t, x| println!("{}: {:?}", t.first, x));
         });
     })
-        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
 
     Ok(())
 }
@@ -73,12 +85,6 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> KHopsSummary<S> for Stream<S, ((
     }
 }
 
-fn calculate_hash<T: Hash>(t: &T) -> u64 {
-    let mut s = DefaultHasher::new();
-    t.hash(&mut s);
-    s.finish()
-}
-
 /// Run khops on provided `Stream`.
 pub trait KHops<S: Scope<Timestamp = Pair<u64, Duration>>> {
     /// Run khops algorithm on provided `Stream`.
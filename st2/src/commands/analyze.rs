@@ -0,0 +1,132 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::hooks::{attach, EpochContext, Hooks};
+use crate::plugins;
+
+use timely::dataflow::Stream;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}};
+use std::time::Duration;
+
+use serde_json::json;
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+/// Prints the registered analyses and their descriptions.
+pub fn list(output_format: OutputFormat) {
+    for (name, plugin) in plugins::registry() {
+        output_format.print(
+            format_args!("{:<20} {}", name, plugin.description),
+            json!({ "name": name, "description": plugin.description }));
+    }
+}
+
+/// The `analyze` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("analyze")
+        .about("Run registered analyses, e.g. of plugins, on every completed epoch and print their metrics")
+        .arg(clap::Arg::with_name("analyses")
+            .long("analyses")
+            .value_name("NAMES")
+            .help("Comma-separated names of the analyses to run (see --list)")
+            .required_unless("list"))
+        .arg(clap::Arg::with_name("list")
+            .long("list")
+            .help("List the registered analyses and exit")
+            .conflicts_with("analyses"))
+}
+
+/// The arguments of `analyze`, unless it lists the analyses (`--list`)
+pub struct Options {
+    /// The names of the analyses to run
+    pub analyses: Vec<String>,
+}
+
+/// Parses the arguments of `analyze`. The analyses have to be registered.
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    let analyses: Vec<String> = args.value_of("analyses").expect("error parsing analyze analyses args")
+        .split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect();
+    if analyses.is_empty() {
+        return Err(STError::Config("Invalid --analyses: expected at least one analysis".to_string()));
+    }
+    plugins::create(&analyses).map_err(|e| e.context("Invalid --analyses"))?;
+
+    Ok(Options { analyses })
+}
+
+/// Runs the `analyses` (cf. `plugins::registry`) on every completed epoch of
+/// `replay_source` and prints every value of their metric collections, e.g.
+/// `epoch 3 operator_busy_ns{operator="Map"} 12345` or, as JSON, an object with
+/// the epoch, analysis, metric, labels, and value. Returns the number of epochs.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    analyses: Vec<String>,
+    operator_names: &BTreeMap<u64, String>,
+    output_format: OutputFormat) -> Result<u64, STError> {
+
+    // fail on unknown analyses before the analysis rather than in a worker
+    plugins::create(&analyses)?;
+
+    let analyzed = Arc::new(AtomicU64::new(0));
+    let counted = Arc::clone(&analyzed);
+    let operator_names = operator_names.clone();
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+
+        // epochs are completed at the first peer only
+        let mut hooks = Hooks::new();
+        if index == 0 {
+            let counted = Arc::clone(&counted);
+            hooks.register("count", move |_: &EpochContext| {
+                counted.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            });
+            for (name, mut analysis) in plugins::create(&analyses).expect("analyses were validated") {
+                hooks.register(name, move |epoch: &EpochContext| {
+                    for collection in analysis.analyze(epoch) {
+                        for (labels, value) in collection.values {
+                            let series: Vec<String> = labels.iter().map(|(key, value)| format!("{}=\"{}\"", key, value)).collect();
+                            output_format.print(
+                                format_args!("epoch {} {}{{{}}} {}", epoch.epoch, collection.name, series.join(","), value),
+                                json!({
+                                    "epoch": epoch.epoch,
+                                    "analysis": name,
+                                    "metric": collection.name,
+                                    "labels": labels,
+                                    "value": value,
+                                }));
+                        }
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
+            attach(&pag, hooks, operator_names.clone());
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    Ok(analyzed.load(Ordering::Acquire))
+}
@@ -0,0 +1,174 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use st2_logformat::LogRecord;
+use st2_logformat::names::{NameId, NameTable};
+use st2_logformat::tagged::{self, Value};
+use st2_logformat::trace::{TraceHeader, TraceReader, TraceWriter};
+
+use serde_json::json;
+
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+/// Number of hex digits of a pseudonym's hash
+const HASH_DIGITS: usize = 12;
+
+/// The `anonymize` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("anonymize")
+        .about("Replace hostnames, operator names, and user labels of a trace file with pseudonyms, e.g. to share it")
+        .arg(clap::Arg::with_name("input")
+            .value_name("IN")
+            .help("Trace file to anonymize")
+            .required(true))
+        .arg(clap::Arg::with_name("output")
+            .value_name("OUT")
+            .help("Path of the anonymized trace file")
+            .required(true))
+        .arg(clap::Arg::with_name("salt")
+            .long("salt")
+            .value_name("SALT")
+            .help("Secret mixed into the pseudonyms; use the same salt for all traces of a computation (default: random)")
+            .takes_value(true))
+}
+
+/// The arguments of `anonymize`
+pub struct Options {
+    /// The anonymized trace file
+    pub input: PathBuf,
+    /// The anonymized file
+    pub output: PathBuf,
+    /// The salt of the pseudonyms, random unless given
+    pub salt: String,
+}
+
+/// Parses the arguments of `anonymize`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    let salt = match args.value_of("salt") {
+        Some(salt) => salt.to_string(),
+        None if crate::deterministic::enabled() => Err(STError::Config("--deterministic requires a --salt".to_string()))?,
+        None => {
+            let salt = random_salt();
+            eprintln!("Using salt {} (pass it as --salt to anonymize further traces of this computation consistently)", salt);
+            salt
+        }
+    };
+
+    Ok(Options {
+        input: PathBuf::from(args.value_of("input").expect("error parsing anonymize input args")),
+        output: PathBuf::from(args.value_of("output").expect("error parsing anonymize output args")),
+        salt,
+    })
+}
+
+/// Copies the trace file `input` to `output`, replacing identifying data with
+/// pseudonyms: hostnames become `host-<hash>`, operator names `op-<hash>`, and the
+/// text of user-defined tagged fields (e.g. span labels) `label-<hash>`.
+///
+/// Pseudonyms are derived from the original names and `salt` by SHA-256, so the
+/// same name always gets the same pseudonym: traces of one computation anonymized
+/// with the same salt remain consistent with each other, but names can't be
+/// recovered (or guessed, as long as the salt is kept secret). Everything else,
+/// i.e. the trace's structure, timestamps, epochs, workers, and operator ids, is
+/// kept, as are the encoding and compression of `input`.
+pub fn run(input: &Path, output: &Path, salt: &str, output_format: OutputFormat) -> Result<(), STError> {
+    let mut reader = TraceReader::new(BufReader::new(File::open(input)?)).map_err(|e| STError::decode(0, e))?;
+    let mut header = TraceHeader::new(reader.header().workers, reader.header().source.clone(), reader.header().encoding, reader.header().compression);
+    header.start_time = reader.header().start_time;
+    let mut writer = TraceWriter::new(&header, BufWriter::new(File::create(output)?))?;
+
+    let mut anonymizer = Anonymizer::new(salt);
+    let mut records = 0;
+    while let Some(batch) = reader.next_batch().map_err(|e| STError::decode(reader.offset(), e))? {
+        records += batch.len();
+        let batch: Vec<_> = batch.into_iter()
+            .map(|record| anonymizer.anonymize(record, reader.names(), &mut writer))
+            .collect();
+        writer.write_batch(&batch)?;
+    }
+    writer.finish()?;
+
+    let (hosts, operators, labels) = (anonymizer.count("host"), anonymizer.count("op"), anonymizer.count("label"));
+    output_format.print(
+        format_args!("Anonymized {} records from {} in {}: {} hostnames, {} operator names, {} labels",
+                     records, input.display(), output.display(), hosts, operators, labels),
+        json!({ "input": input, "output": output, "records": records, "hostnames": hosts, "operator_names": operators, "labels": labels }));
+    Ok(())
+}
+
+/// Replaces names with pseudonyms
+struct Anonymizer<'a> {
+    salt: &'a str,
+    /// (kind, name id in the input) -> name id of the pseudonym in the output
+    renamed: HashMap<(&'static str, NameId), NameId>,
+    /// (kind, pseudonym) of all replaced names
+    pseudonyms: HashSet<(&'static str, String)>,
+}
+
+impl<'a> Anonymizer<'a> {
+    fn new(salt: &'a str) -> Self {
+        Anonymizer { salt, renamed: HashMap::new(), pseudonyms: HashSet::new() }
+    }
+
+    fn anonymize(&mut self, mut record: LogRecord, names: &NameTable, writer: &mut TraceWriter) -> LogRecord {
+        if let Some(origin) = record.origin.as_mut() {
+            origin.host = self.rename("host", origin.host, names, writer);
+        }
+        for field in record.tagged.iter_mut() {
+            match (field.tag, &field.value) {
+                (tagged::OPERATOR_NAME, Value::U64(id)) => {
+                    let name = self.rename("op", *id as NameId, names, writer);
+                    field.value = Value::U64(name as u64);
+                }
+                (tag, Value::Bytes(bytes)) if tag >= tagged::USER_TAGS => {
+                    let label = self.pseudonym("label", bytes);
+                    field.value = Value::Bytes(label.into_bytes());
+                }
+                _ => (),
+            }
+        }
+        record
+    }
+
+    /// Interns the pseudonym of the name `id` in `writer`.
+    fn rename(&mut self, kind: &'static str, id: NameId, names: &NameTable, writer: &mut TraceWriter) -> NameId {
+        if let Some(renamed) = self.renamed.get(&(kind, id)) {
+            return *renamed;
+        }
+        let pseudonym = match names.resolve(id) {
+            Some(name) => self.pseudonym(kind, name.as_bytes()),
+            // dangling ids stay dangling, but don't refer to another name
+            None => format!("{}-unknown", kind),
+        };
+        let renamed = writer.intern(&pseudonym);
+        self.renamed.insert((kind, id), renamed);
+        renamed
+    }
+
+    fn pseudonym(&mut self, kind: &'static str, name: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(self.salt.as_bytes());
+        hasher.input(&[0]);
+        hasher.input(name);
+        let hash: String = hasher.result().iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        let pseudonym = format!("{}-{}", kind, &hash[.. HASH_DIGITS]);
+        self.pseudonyms.insert((kind, pseudonym.clone()));
+        pseudonym
+    }
+
+    /// Number of distinct names of `kind` replaced so far
+    fn count(&self, kind: &str) -> usize {
+        self.pseudonyms.iter().filter(|(k, _)| *k == kind).count()
+    }
+}
+
+/// A random salt, for when none is given
+pub fn random_salt() -> String {
+    (0 .. 16).map(|_| format!("{:02x}", rand::random::<u8>())).collect()
+}
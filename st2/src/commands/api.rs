@@ -0,0 +1,434 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::auth::Auth;
+use crate::commands::alerts::EpochStats;
+use crate::commands::snapshot::Snapshot;
+use crate::store::{completed_epochs, epoch_samples, Labels, MetricsStore, Sample};
+
+use timely::dataflow::Stream;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, mpsc, atomic::{AtomicBool, Ordering}};
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use crate::STError;
+use crate::config::Args;
+
+/// Origins of the browser pages that may use the API (`--allow-origin`), cf.
+/// CORS. Requests without an `Origin`, i.e. not made by browsers, are served
+/// regardless; browsers are denied the responses to other origins' requests,
+/// and WebSocket handshakes from other origins are rejected.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AllowedOrigins(pub Vec<String>);
+
+impl AllowedOrigins {
+    /// The `Access-Control-Allow-Origin` of responses to requests from `origin`,
+    /// unless it isn't allowed. `*` allows all origins.
+    pub fn allow<'a>(&'a self, origin: &'a str) -> Option<&'a str> {
+        self.0.iter()
+            .find(|allowed| *allowed == "*" || *allowed == origin)
+            .map(|allowed| if allowed == "*" { "*" } else { origin })
+    }
+}
+
+/// Summary of a completed epoch, as listed by `GET /epochs` and pushed to subscribers
+#[derive(Serialize)]
+pub struct EpochSummary {
+    /// The epoch
+    pub epoch: u64,
+    /// Time of the epoch's last event, in ns since the Unix epoch
+    pub timestamp: u64,
+    /// Time from the epoch's first to its last event, in ns
+    pub latency_ns: u64,
+    /// Duration of the epoch's critical path, in ns
+    pub critical_path_ns: u64,
+    /// Busiest worker's busy time / the workers' average busy time
+    pub skew: f64,
+    /// Number of epochs the source computation was ahead of the analysis
+    pub backlog_epochs: u64,
+    /// Number of the epoch's PAG edges
+    pub edges: usize,
+}
+
+impl EpochSummary {
+    fn new(epoch: u64, samples: &[Sample], edges: usize) -> Self {
+        let value = |name| samples.iter()
+            .find(|sample| sample.name == name && sample.labels.is_empty())
+            .map(|sample| sample.value)
+            .unwrap_or(0.0);
+        EpochSummary {
+            epoch,
+            timestamp: samples.first().map(|sample| sample.timestamp).unwrap_or(0),
+            latency_ns: value("epoch_latency_ns") as u64,
+            critical_path_ns: value("critical_path_ns") as u64,
+            skew: value("skew"),
+            backlog_epochs: value("backlog_epochs") as u64,
+            edges,
+        }
+    }
+}
+
+/// The metrics and PAGs of the most recent epochs
+struct Retained {
+    metrics: MetricsStore,
+    /// Oldest first
+    pags: VecDeque<(u64, Vec<PagEdge>)>,
+    retention: usize,
+}
+
+impl Retained {
+    fn insert(&mut self, epoch: u64, edges: Vec<PagEdge>, samples: Vec<Sample>) {
+        self.metrics.insert(epoch, samples);
+        self.pags.push_back((epoch, edges));
+        while self.pags.len() > self.retention {
+            self.pags.pop_front();
+        }
+    }
+
+    fn summary(&self, epoch: u64) -> Option<EpochSummary> {
+        let edges = self.pags.iter().find(|(e, _)| *e == epoch).map(|(_, edges)| edges.len())?;
+        Some(EpochSummary::new(epoch, self.metrics.samples(epoch)?, edges))
+    }
+}
+
+/// A client subscribed to completed epochs, which is sent their summaries as JSON
+type Subscriber = Box<dyn FnMut(&str) -> std::io::Result<()> + Send>;
+
+/// The `api` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("api")
+        .about("Serve per-epoch results as an HTTP/JSON API with live subscriptions over SSE and WebSockets")
+        .arg(clap::Arg::with_name("listen")
+            .short("l")
+            .long("listen")
+            .value_name("ADDR")
+            .default_value("127.0.0.1:3002")
+            .help("Address to serve the API on"))
+        .arg(clap::Arg::with_name("ws-listen")
+            .long("ws-listen")
+            .value_name("ADDR")
+            .default_value("127.0.0.1:3003")
+            .help("Address to serve WebSocket subscriptions (/ws) on"))
+        .arg(clap::Arg::with_name("retention")
+            .long("retention")
+            .value_name("EPOCHS")
+            .default_value("1000")
+            .help("Number of most recent epochs to keep metrics and PAGs of"))
+        .arg(clap::Arg::with_name("auth")
+            .long("auth")
+            .value_name("PATH")
+            .help("TOML file of the tokens allowed to access endpoints, and which ones (cf. README)"))
+        .arg(clap::Arg::with_name("allow-origin")
+            .long("allow-origin")
+            .value_name("ORIGIN")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Origin of web UIs that may use the API (CORS), e.g. http://localhost:8080; * allows any, but not with --auth"))
+}
+
+/// The arguments of `api`
+pub struct Options {
+    /// The address to serve the HTTP API at
+    pub listen: String,
+    /// The number of most recent epochs to keep
+    pub retention: usize,
+    /// The address to serve live subscriptions at
+    pub ws_listen: String,
+    /// Who may access the API
+    pub auth: Option<Auth>,
+    /// The web UIs that may use the API
+    pub origins: AllowedOrigins,
+}
+
+/// Parses the arguments of `api`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        listen: args.value_of("listen").expect("error parsing api listen args").to_string(),
+        retention: args.value_of("retention").expect("error parsing api retention args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --retention: {}", e)))?,
+        ws_listen: args.value_of("ws-listen").expect("error parsing api ws-listen args").to_string(),
+        auth: args.value_of("auth").map(|path| Auth::load(std::path::Path::new(path))).transpose()?,
+        origins: AllowedOrigins(args.values_of("allow-origin").into_iter().flatten().map(String::from).collect()),
+    })
+}
+
+/// Serves the results of the `retention` most recent epochs of `replay_source` as
+/// JSON over HTTP at `listen`, for custom UIs:
+///
+/// - `GET /epochs`: summaries of the retained epochs, optionally `?from=E&to=E`
+///   (epochs from `from` up to, but excluding, `to`)
+/// - `GET /epochs/<E>`: the PAG of epoch `E` with its critical path, like a `snapshot`
+/// - `GET /metrics`: the samples (cf. `store::METRICS`) of the retained epochs,
+///   optionally of a metric (`?name=NAME`), epochs (`from`, `to`), and with labels
+///   (any other parameter, e.g. `operator=Map`)
+/// - `GET /subscribe`: server-sent events (`event: epoch`) with the summary of every
+///   epoch completed from then on
+///
+/// and WebSockets at `ws_listen` (`ws://<ws_listen>/ws`) that push the same
+/// summaries as text messages. If `auth` is given, requests and WebSocket
+/// handshakes have to authenticate and be authorized for their path (cf.
+/// `auth`); browsers may only use the API from `origins`. Once the analysis is
+/// complete, the results are served until ST2 is interrupted.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    listen: &str,
+    ws_listen: &str,
+    retention: usize,
+    auth: Option<Auth>,
+    origins: AllowedOrigins,
+    operator_names: &BTreeMap<u64, String>) -> Result<(), STError> {
+
+    if auth.is_some() && origins.0.iter().any(|origin| origin == "*") {
+        return Err(STError::Config("Invalid --allow-origin: * can't be combined with --auth, list the origins of the UIs instead".to_string()));
+    }
+    let server = tiny_http::Server::http(listen).map_err(|e| STError::Config(format!("Invalid --listen: {}", e)))?;
+    let auth = auth.map(Arc::new);
+    let (websockets, ws_addr) = serve_websockets(ws_listen, auth.clone(), origins.clone())?;
+    let retained = Arc::new(Mutex::new(Retained { metrics: MetricsStore::new(retention), pags: VecDeque::new(), retention }));
+    let subscribers: Arc<Mutex<Vec<Subscriber>>> = Arc::new(Mutex::new(Vec::new()));
+
+    let served = Arc::clone(&retained);
+    let subscribing = Arc::clone(&subscribers);
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            serve(request, &served, &subscribing, auth.as_ref().map(|auth| &**auth), &origins);
+        }
+    });
+
+    // push summaries of completed epochs to all subscribers, dropping disconnected ones
+    let (summary_send, summary_recv) = mpsc::channel::<String>();
+    std::thread::spawn(move || {
+        for summary in summary_recv {
+            if let Err(e) = websockets.send(summary.as_str()) {
+                error!("couldn't push epoch summary to WebSockets: {}", e);
+            }
+            let mut subscribers = subscribers.lock().unwrap();
+            let mut connected = Vec::with_capacity(subscribers.len());
+            for mut subscriber in subscribers.drain(..) {
+                if subscriber(&summary).is_ok() {
+                    connected.push(subscriber);
+                }
+            }
+            *subscribers = connected;
+        }
+    });
+    eprintln!("Serving API at http://{} and WebSockets at ws://{}/ws", listen, ws_addr);
+
+    let operator_names = operator_names.clone();
+    let summary_send = Arc::new(Mutex::new(summary_send));
+    let workers_running = Arc::clone(&is_running);
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let operator_names = operator_names.clone();
+        let retained = Arc::clone(&retained);
+        let summary_send = summary_send.lock().unwrap().clone();
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&workers_running)), 1, speed, filter.clone());
+
+            completed_epochs(&pag, "Api", move |epoch, edges, ahead| {
+                let samples = epoch_samples(epoch, &edges, ahead, &operator_names);
+                let summary = EpochSummary::new(epoch, &samples, edges.len());
+                retained.lock().unwrap().insert(epoch, edges, samples);
+                summary_send.send(json!(summary).to_string()).expect("couldn't push epoch summary");
+            });
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    // keep serving the retained results unless we've been interrupted
+    while is_running.load(Ordering::Acquire) {
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    Ok(())
+}
+
+/// Responds to a request of the API
+fn serve(request: tiny_http::Request, retained: &Mutex<Retained>, subscribers: &Mutex<Vec<Subscriber>>, auth: Option<&Auth>, origins: &AllowedOrigins) {
+    let origin = request.headers().iter()
+        .find(|header| header.field.equiv("Origin"))
+        .and_then(|header| origins.allow(header.value.as_str()))
+        .map(str::to_string);
+    if *request.method() != tiny_http::Method::Get {
+        return respond(request, origin, Err((405, "only GET is supported".to_string())));
+    }
+    if let Err(denied) = auth.map_or(Ok(()), |auth| auth.authorize_http(&request)) {
+        return respond(request, origin, Err((denied.status(), denied.to_string())));
+    }
+    let (path, parameters) = parse_url(request.url());
+    let number = |name: &str| match parameters.iter().find(|(key, _)| key == name) {
+        Some((_, value)) => value.parse::<u64>().map(Some).map_err(|e| (400, format!("invalid {}: {}", name, e))),
+        None => Ok(None),
+    };
+
+    let response = match path.as_str() {
+        "/subscribe" => return subscribe_events(request, origin, subscribers),
+        "/epochs" => number("from").and_then(|from| Ok((from, number("to")?))).map(|(from, to)| {
+            let retained = retained.lock().unwrap();
+            let summaries: Vec<EpochSummary> = retained.pags.iter()
+                .map(|(epoch, _)| *epoch)
+                .filter(|epoch| *epoch >= from.unwrap_or(0) && *epoch < to.unwrap_or(std::u64::MAX))
+                .filter_map(|epoch| retained.summary(epoch))
+                .collect();
+            json!(summaries)
+        }),
+        "/metrics" => number("from").and_then(|from| Ok((from, number("to")?))).map(|(from, to)| {
+            let name = parameters.iter().find(|(key, _)| key == "name").map(|(_, value)| value.as_str());
+            let labels: Labels = parameters.iter()
+                .filter(|(key, _)| key != "name" && key != "from" && key != "to" && key != "access_token")
+                .cloned()
+                .collect();
+            let retained = retained.lock().unwrap();
+            let samples: Vec<&Sample> = retained.metrics.epochs()
+                .filter(|epoch| *epoch >= from.unwrap_or(0) && *epoch < to.unwrap_or(std::u64::MAX))
+                .flat_map(|epoch| retained.metrics.samples(epoch).unwrap_or(&[]).iter())
+                .filter(|sample| name.map_or(true, |name| sample.name == name))
+                .filter(|sample| labels.iter().all(|(key, value)| sample.labels.get(key) == Some(value)))
+                .collect();
+            json!(samples)
+        }),
+        path if path.starts_with("/epochs/") => match path["/epochs/".len() ..].parse::<u64>() {
+            Ok(epoch) => match retained.lock().unwrap().pags.iter().find(|(e, _)| *e == epoch) {
+                Some((_, edges)) => {
+                    let mut edges = edges.clone();
+                    edges.sort_by_key(|edge| (edge.source.timestamp, edge.source.worker_id, edge.destination.timestamp));
+                    let stats = EpochStats::new(&edges);
+                    Ok(json!(Snapshot { epoch, latency: stats.latency, edges, critical_path: stats.critical_path }))
+                }
+                None => Err((404, format!("epoch {} isn't retained", epoch))),
+            },
+            Err(e) => Err((400, format!("invalid epoch: {}", e))),
+        },
+        _ => Err((404, format!("not found: {}", path))),
+    };
+    respond(request, origin, response);
+}
+
+/// Responds with `response` as JSON, or with an error `{"error": message}`,
+/// readable by browser pages of the allowed `origin`
+fn respond(request: tiny_http::Request, origin: Option<String>, response: Result<Value, (u16, String)>) {
+    let (status, body) = match response {
+        Ok(body) => (200, body),
+        Err((status, message)) => (status, json!({ "error": message })),
+    };
+    let mut response = tiny_http::Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+        .with_header(header("Vary", "Origin"));
+    if let Some(origin) = origin {
+        response.add_header(header("Access-Control-Allow-Origin", &origin));
+    }
+    if status == 401 {
+        response.add_header(header("WWW-Authenticate", "Bearer"));
+    }
+    if let Err(e) = request.respond(response) {
+        error!("couldn't respond to API request: {}", e);
+    }
+}
+
+fn header(name: &str, value: &str) -> tiny_http::Header {
+    tiny_http::Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+/// Subscribes the client of `request` to server-sent events
+fn subscribe_events(request: tiny_http::Request, origin: Option<String>, subscribers: &Mutex<Vec<Subscriber>>) {
+    // the response is written directly, as `tiny_http` would buffer a streamed body
+    let mut stream = request.into_writer();
+    let cors = origin.map(|origin| format!("Access-Control-Allow-Origin: {}\r\n", origin)).unwrap_or_default();
+    let head = format!("HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nVary: Origin\r\n{}Connection: close\r\n\r\n", cors);
+    if let Err(e) = stream.write_all(head.as_bytes()).and_then(|_| stream.flush()) {
+        error!("couldn't subscribe API client: {}", e);
+        return;
+    }
+    subscribers.lock().unwrap().push(Box::new(move |summary| {
+        stream.write_all(event("epoch", summary).as_bytes())?;
+        stream.flush()
+    }));
+}
+
+/// A server-sent event of type `name` with `data`, which is split into one
+/// `data` field per line
+pub fn event(name: &str, data: &str) -> String {
+    let mut event = format!("event: {}\n", name);
+    for line in data.lines() {
+        event.push_str(&format!("data: {}\n", line));
+    }
+    event.push('\n');
+    event
+}
+
+/// Serves WebSockets at `ws://<listen>/ws`, for handshakes from `origins`
+/// authorized by `auth` (for the path `/ws`). Returns a sender whose messages
+/// are pushed to every connected client, and the address served.
+pub fn serve_websockets(listen: &str, auth: Option<Arc<Auth>>, origins: AllowedOrigins) -> Result<(ws::Sender, SocketAddr), STError> {
+    let server = ws::WebSocket::new(move |_| Subscription { auth: auth.clone(), origins: origins.clone() })
+        .and_then(|server| server.bind(listen))
+        .map_err(|e| STError::Config(format!("Invalid --ws-listen: {}", e)))?;
+    let addr = server.local_addr().map_err(|e| STError::Config(format!("Invalid --ws-listen: {}", e)))?;
+    let broadcaster = server.broadcaster();
+    std::thread::spawn(move || {
+        if let Err(e) = server.run() {
+            error!("couldn't serve WebSockets: {}", e);
+        }
+    });
+    Ok((broadcaster, addr))
+}
+
+/// A WebSocket client subscribed to completed epochs, cf. `serve_websockets`
+struct Subscription {
+    auth: Option<Arc<Auth>>,
+    origins: AllowedOrigins,
+}
+
+impl ws::Handler for Subscription {
+    fn on_request(&mut self, request: &ws::Request) -> ws::Result<ws::Response> {
+        let path = request.resource().splitn(2, '?').next().unwrap_or("");
+        if path != "/ws" {
+            return Ok(ws::Response::new(404, "Not Found", format!("not found: {}", path).into_bytes()));
+        }
+        if let Some(origin) = request.origin()? {
+            if self.origins.allow(origin).is_none() {
+                return Ok(ws::Response::new(403, "Forbidden", format!("origin {} isn't allowed", origin).into_bytes()));
+            }
+        }
+        if let Err(denied) = self.auth.as_ref().map_or(Ok(()), |auth| auth.authorize_ws(request)) {
+            let mut response = ws::Response::new(denied.status(), if denied.status() == 401 { "Unauthorized" } else { "Forbidden" }, denied.to_string().into_bytes());
+            if denied.status() == 401 {
+                response.headers_mut().push(("WWW-Authenticate".to_string(), b"Bearer".to_vec()));
+            }
+            return Ok(response);
+        }
+        ws::Response::from_request(request)
+    }
+}
+
+/// Splits `url` into its path and its percent-decoded query parameters
+pub fn parse_url(url: &str) -> (String, Vec<(String, String)>) {
+    let mut parts = url.splitn(2, '?');
+    let path = parts.next().unwrap_or("").to_string();
+    let parameters = form_urlencoded::parse(parts.next().unwrap_or("").as_bytes())
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    (path, parameters)
+}
@@ -0,0 +1,253 @@
+use crate::pag::{self, PagEdge};
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+use timely::dataflow::ProbeHandle;
+use timely::dataflow::operators::probe::Probe;
+use timely::dataflow::operators::inspect::Inspect;
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}};
+use std::time::{Duration, Instant};
+
+use st2_logformat::pair::Pair;
+use st2_timely::connect::TraceEvent;
+use st2_timely::filter::Filter;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::synthetic::{SyntheticReplayer, Workload};
+
+use serde_json::json;
+
+/// Epochs replay lets into the dataflow at a time, as in the analysis commands
+const EPOCHS_IN_FLIGHT: u64 = 1;
+
+/// A dataflow stage of ST2's analysis pipeline
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Replay and conversion of events to `LogRecord`s
+    LogRecords,
+    /// Replay, conversion, and PAG construction
+    Pag,
+}
+
+/// How long a stage took to process a workload
+#[derive(Clone, Debug, PartialEq)]
+pub struct Measurement {
+    /// Name of the stage
+    pub stage: &'static str,
+    /// Time the stage took, on the slowest worker
+    pub elapsed: Duration,
+    /// Items the stage produced, e.g. PAG edges
+    pub items: u64,
+    /// What the items are
+    pub unit: &'static str,
+}
+
+impl Measurement {
+    /// Items per second
+    pub fn rate(&self) -> f64 {
+        self.items as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
+
+/// The events of every worker of `workload`, generated once for all stages
+pub fn generate(workload: &Workload) -> Arc<Vec<Vec<TraceEvent>>> {
+    Arc::new((0 .. workload.workers).map(|worker| workload.events(worker)).collect())
+}
+
+/// Runs `stage` on `workers` ST2 workers over `events` (cf. `generate`). With
+/// `collect`, the PAG edges are collected there by epoch, e.g. for `critical_paths`.
+pub fn dataflow(workers: usize,
+                events: &Arc<Vec<Vec<TraceEvent>>>,
+                stage: Stage,
+                collect: Option<Arc<Mutex<BTreeMap<u64, Vec<PagEdge>>>>>) -> Result<Measurement, STError> {
+    let configuration = match workers {
+        1 => timely::Configuration::Thread,
+        n => timely::Configuration::Process(n),
+    };
+    let events = Arc::clone(events);
+    let items = Arc::new(AtomicU64::new(0));
+    let elapsed = Arc::new(Mutex::new(Duration::default()));
+
+    let (items_out, elapsed_out) = (Arc::clone(&items), Arc::clone(&elapsed));
+    timely::execute(configuration, move |worker| {
+        let index = worker.index();
+        let replayers: Vec<_> = events.iter()
+            .enumerate()
+            .filter(|(source, _)| source % worker.peers() == index)
+            .map(|(_, events)| SyntheticReplayer::new(events.clone()))
+            .collect();
+
+        let items = Arc::clone(&items);
+        let collect = collect.clone();
+        let probe: ProbeHandle<Pair<u64, Duration>> = worker.dataflow(|scope| match stage {
+            Stage::LogRecords => st2_timely::create_lrs(scope, replayers, index, None, EPOCHS_IN_FLIGHT, ReplaySpeed::Unbounded, Filter::default())
+                .inspect_batch(move |_, records| { items.fetch_add(records.len() as u64, Ordering::Relaxed); })
+                .probe(),
+            Stage::Pag => pag::create_pag(scope, replayers, index, None, EPOCHS_IN_FLIGHT, ReplaySpeed::Unbounded, Filter::default())
+                .inspect_batch(move |t, edges| {
+                    items.fetch_add(edges.len() as u64, Ordering::Relaxed);
+                    if let Some(collect) = &collect {
+                        collect.lock().expect("bench pag poisoned").entry(t.first).or_insert_with(Vec::new)
+                            .extend(edges.iter().map(|(edge, _, _)| edge.clone()));
+                    }
+                })
+                .probe(),
+        });
+
+        let started = Instant::now();
+        while !probe.done() { worker.step(); }
+        let mut elapsed = elapsed.lock().expect("bench timer poisoned");
+        *elapsed = std::cmp::max(*elapsed, started.elapsed());
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    let elapsed = *elapsed_out.lock().expect("bench timer poisoned");
+    let (stage, unit) = match stage {
+        Stage::LogRecords => ("log records", "records"),
+        Stage::Pag => ("pag", "edges"),
+    };
+    Ok(Measurement { stage, elapsed, items: items_out.load(Ordering::Relaxed), unit })
+}
+
+/// Extracts the critical path of every epoch of `pag`.
+pub fn critical_paths(pag: &BTreeMap<u64, Vec<PagEdge>>) -> Measurement {
+    let started = Instant::now();
+    let items = pag.values().map(|edges| st2_logformat::pag::critical_path(edges).len() as u64).sum();
+    Measurement { stage: "critical paths", elapsed: started.elapsed(), items, unit: "edges" }
+}
+
+/// The `bench` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("bench")
+        .about("Benchmark ST2's pipeline (log record and PAG construction, critical paths) on a synthetic trace, or generate one")
+        .arg(clap::Arg::with_name("peers")
+            .long("peers")
+            .value_name("PEERS")
+            .help("Number of workers of the synthetic source computation")
+            .default_value("4"))
+        .arg(clap::Arg::with_name("operators")
+            .long("operators")
+            .value_name("OPERATORS")
+            .help("Number of operators, each scheduled once per epoch and worker")
+            .default_value("8"))
+        .arg(clap::Arg::with_name("epochs")
+            .long("epochs")
+            .value_name("EPOCHS")
+            .help("Number of epochs")
+            .default_value("100"))
+        .arg(clap::Arg::with_name("epoch_interval")
+            .long("epoch-interval")
+            .value_name("MS")
+            .help("Time between the starts of consecutive epochs")
+            .default_value("10"))
+        .arg(clap::Arg::with_name("operator_time")
+            .long("operator-time")
+            .value_name("US")
+            .help("Time the least busy worker schedules an operator for")
+            .default_value("100"))
+        .arg(clap::Arg::with_name("messages")
+            .long("messages")
+            .value_name("PATTERN")
+            .possible_values(&["none", "ring", "all-to-all"])
+            .help("Workers every operator sends a data message to")
+            .default_value("all-to-all"))
+        .arg(clap::Arg::with_name("records")
+            .long("records")
+            .value_name("RECORDS")
+            .help("Records per data message")
+            .default_value("100"))
+        .arg(clap::Arg::with_name("skew")
+            .long("skew")
+            .value_name("FACTOR")
+            .help("How much longer the busiest worker schedules operators than the least busy one (at least 1)")
+            .default_value("1"))
+        .arg(clap::Arg::with_name("generate")
+            .long("generate")
+            .value_name("DIR")
+            .help("Write the synthetic trace as *.dump files to DIR instead of benchmarking"))
+}
+
+/// The arguments of `bench`
+pub struct Options {
+    /// The synthetic workload
+    pub workload: Workload,
+    /// The directory to write the workload's `*.dump` files to instead of
+    /// benchmarking
+    pub generate: Option<PathBuf>,
+}
+
+/// Parses the arguments of `bench`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    fn parse<T: std::str::FromStr>(args: &Args, name: &str, flag: &str) -> Result<T, STError>
+    where T::Err: std::fmt::Display {
+        args.value_of(name).expect("error parsing bench args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --{}: {}", flag, e)))
+    }
+
+    let workload = Workload {
+        workers: parse(args, "peers", "peers")?,
+        operators: parse(args, "operators", "operators")?,
+        epochs: parse(args, "epochs", "epochs")?,
+        epoch_interval: Duration::from_millis(parse(args, "epoch_interval", "epoch-interval")?),
+        operator_time: Duration::from_micros(parse(args, "operator_time", "operator-time")?),
+        messages: parse(args, "messages", "messages")?,
+        records: parse(args, "records", "records")?,
+        skew: parse(args, "skew", "skew")?,
+    };
+    if workload.workers == 0 || workload.operators == 0 {
+        Err(STError::Config("Invalid --peers or --operators: expected at least 1".to_string()))?
+    }
+    if !(workload.skew >= 1.0 && workload.skew.is_finite()) {
+        Err(STError::Config(format!("Invalid --skew: {} is not at least 1", workload.skew)))?
+    }
+    if workload.operator_time < Duration::from_micros(1) {
+        Err(STError::Config("Invalid --operator-time: expected at least 1us".to_string()))?
+    }
+
+    Ok(Options {
+        workload,
+        generate: args.value_of("generate").map(PathBuf::from),
+    })
+}
+
+/// Benchmarks ST2's pipeline on `workers` ST2 workers over the synthetic
+/// `workload`, stage by stage, and prints the time and throughput of each stage.
+pub fn run(workers: usize, workload: &Workload, output_format: OutputFormat) -> Result<(), STError> {
+    let events = generate(workload);
+    let pag = Arc::new(Mutex::new(BTreeMap::new()));
+
+    let mut measurements = vec![
+        dataflow(workers, &events, Stage::LogRecords, None)?,
+        dataflow(workers, &events, Stage::Pag, Some(Arc::clone(&pag)))?,
+    ];
+    measurements.push(critical_paths(&pag.lock().expect("bench pag poisoned")));
+
+    let text = measurements.iter()
+        .map(|m| format!("{:<16}{:>12.3?}{:>12} {:<8}{:>14.0} {}/s", m.stage, m.elapsed, m.items, m.unit, m.rate(), m.unit))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let json = json!({
+        "workload": {
+            "peers": workload.workers,
+            "operators": workload.operators,
+            "epochs": workload.epochs,
+            "epoch_interval_ns": workload.epoch_interval.as_nanos() as u64,
+            "operator_time_ns": workload.operator_time.as_nanos() as u64,
+            "messages": format!("{:?}", workload.messages),
+            "records": workload.records,
+            "skew": workload.skew,
+        },
+        "workers": workers,
+        "stages": measurements.iter().map(|m| json!({
+            "stage": m.stage,
+            "elapsed_ns": m.elapsed.as_nanos() as u64,
+            "items": m.items,
+            "unit": m.unit,
+            "per_sec": m.rate(),
+        })).collect::<Vec<_>>(),
+    });
+    output_format.print(text, json);
+    Ok(())
+}
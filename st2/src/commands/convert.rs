@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use st2_logformat::convert::transcode;
+use st2_logformat::csv::CsvWriter;
+use st2_logformat::encoding::Encoding;
+use st2_logformat::trace::{Compression, TraceReader};
+
+use serde_json::json;
+
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+/// The `convert` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("convert")
+        .about("Convert a trace file to another encoding and/or compression, from/to Parquet (*.parquet), or to CSV/TSV (*.csv, *.tsv)")
+        .arg(clap::Arg::with_name("input")
+            .value_name("IN")
+            .help("Trace file to convert")
+            .required(true))
+        .arg(clap::Arg::with_name("output")
+            .value_name("OUT")
+            .help("Path of the converted trace file (ending in .parquet for a Parquet file, .csv or .tsv for a table of records)")
+            .required(true))
+        .arg(clap::Arg::with_name("encoding")
+            .long("encoding")
+            .value_name("ENCODING")
+            .help("Encoding of the converted trace file (abomonation, bincode, protobuf, compact)")
+            .default_value("compact"))
+        .arg(clap::Arg::with_name("compression")
+            .long("compression")
+            .value_name("COMPRESSION")
+            .possible_values(&["none", "gzip"])
+            .help("Compression of the converted trace file")
+            .default_value("none"))
+}
+
+/// The arguments of `convert`
+pub struct Options {
+    /// The converted trace file
+    pub input: PathBuf,
+    /// The converted file
+    pub output: PathBuf,
+    /// The encoding of the converted file
+    pub encoding: Encoding,
+    /// The compression of the converted file
+    pub compression: Compression,
+}
+
+/// Parses the arguments of `convert`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        input: PathBuf::from(args.value_of("input").expect("error parsing convert input args")),
+        output: PathBuf::from(args.value_of("output").expect("error parsing convert output args")),
+        encoding: args.value_of("encoding").expect("error parsing convert encoding args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --encoding: {}", e)))?,
+        compression: args.value_of("compression").expect("error parsing convert compression args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --compression: {}", e)))?,
+    })
+}
+
+/// Converts the trace file `input` to `output` with `encoding` and `compression`,
+/// preserving its header metadata and names. Paths ending in `.parquet` are read
+/// resp. written as Parquet files (requires the `parquet` feature). Outputs ending
+/// in `.csv` or `.tsv` get one row per record with names resolved (cf. `st2_logformat::csv`);
+/// operators without names in the trace are named by `operator_names`.
+pub fn run(input: &Path, output: &Path, encoding: Encoding, compression: Compression, operator_names: &BTreeMap<u64, String>, output_format: OutputFormat) -> Result<(), STError> {
+    let count = match (is_parquet(input), is_parquet(output)) {
+        (false, false) if delimiter(output).is_some() => to_csv(input, output, operator_names)?,
+        (true, false) if delimiter(output).is_some() => return Err(STError::Config("Invalid convert: Parquet traces can't be converted to CSV; convert them to a trace file first".to_string())),
+        (false, false) => transcode(BufReader::new(File::open(input)?), BufWriter::new(File::create(output)?), encoding, compression)?,
+        (false, true) => to_parquet(input, output)?,
+        (true, false) => from_parquet(input, output, encoding, compression)?,
+        (true, true) => return Err(STError::Config("Invalid convert: input and output are both Parquet files".to_string())),
+    };
+
+    output_format.print(
+        format_args!("Converted {} records from {} to {}", count, input.display(), output.display()),
+        json!({ "input": input, "output": output, "records": count }));
+    Ok(())
+}
+
+fn is_parquet(path: &Path) -> bool {
+    path.extension().map_or(false, |extension| extension == "parquet")
+}
+
+/// The delimiter of `path`, if it's a CSV or TSV file
+fn delimiter(path: &Path) -> Option<char> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("csv") => Some(','),
+        Some("tsv") => Some('\t'),
+        _ => None,
+    }
+}
+
+/// Writes the records of the trace file `input` as CSV or TSV file `output`.
+fn to_csv(input: &Path, output: &Path, operator_names: &BTreeMap<u64, String>) -> Result<usize, STError> {
+    let delimiter = delimiter(output).expect("CSV or TSV output");
+    let mut writer = CsvWriter::new(BufWriter::new(File::create(output)?), delimiter)?;
+    for (id, name) in operator_names.iter() {
+        writer.name_operator(*id, name);
+    }
+
+    let mut reader = TraceReader::new(BufReader::new(File::open(input)?)).map_err(|e| STError::decode(0, e))?;
+    let mut count = 0;
+    while let Some(batch) = reader.next_batch().map_err(|e| STError::decode(reader.offset(), e))? {
+        for record in batch.iter() {
+            writer.write(record, reader.names())?;
+        }
+        count += batch.len();
+    }
+    writer.finish()?;
+    Ok(count)
+}
+
+/// Writes the trace file `input` as Parquet file `output`.
+#[cfg(feature = "parquet")]
+fn to_parquet(input: &Path, output: &Path) -> Result<usize, STError> {
+    // the Parquet metadata is written first, so collect all names up front
+    let mut reader = TraceReader::new(BufReader::new(File::open(input)?)).map_err(|e| STError::decode(0, e))?;
+    while reader.next_batch().map_err(|e| STError::decode(reader.offset(), e))?.is_some() {}
+    let names = reader.names().clone();
+
+    let mut reader = TraceReader::new(BufReader::new(File::open(input)?))?;
+    let header = reader.header().clone();
+    let batches = std::iter::from_fn(|| reader.next_batch().transpose());
+    Ok(st2_logformat::parquet::write_trace(&header, &names, batches, File::create(output)?)?)
+}
+
+/// Writes the Parquet file `input` as trace file `output`.
+#[cfg(feature = "parquet")]
+fn from_parquet(input: &Path, output: &Path, encoding: Encoding, compression: Compression) -> Result<usize, STError> {
+    use st2_logformat::names::NameId;
+    use st2_logformat::parquet::ParquetReader;
+    use st2_logformat::trace::{TraceHeader, TraceWriter};
+
+    let mut reader = ParquetReader::new(File::open(input)?)?;
+    let mut header = TraceHeader::new(reader.header().workers, reader.header().source.clone(), encoding, compression);
+    header.start_time = reader.header().start_time;
+    let mut writer = TraceWriter::new(&header, BufWriter::new(File::create(output)?))?;
+
+    // names keep their ids, as they are interned in the same order
+    let names = reader.names();
+    for id in 0 .. names.len() {
+        writer.intern(names.resolve(id as NameId).expect("names are consecutive"));
+    }
+
+    let mut count = 0;
+    while let Some(batch) = reader.next_batch()? {
+        count += batch.len();
+        writer.write_batch(&batch)?;
+    }
+    writer.finish()?;
+    Ok(count)
+}
+
+#[cfg(not(feature = "parquet"))]
+fn to_parquet(_input: &Path, _output: &Path) -> Result<usize, STError> {
+    Err(STError::Config("Invalid convert: Parquet traces require st2 to be built with the `parquet` feature".to_string()))
+}
+
+#[cfg(not(feature = "parquet"))]
+fn from_parquet(_input: &Path, _output: &Path, _encoding: Encoding, _compression: Compression) -> Result<usize, STError> {
+    Err(STError::Config("Invalid convert: Parquet traces require st2 to be built with the `parquet` feature".to_string()))
+}
@@ -0,0 +1,565 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::budget::PendingEpochs;
+use crate::commands::alerts::{evaluate, evaluate_scripts, Rule, Sink, SinkConfig, Template, Window};
+use crate::commands::publish::{Publisher, SinkConfig as MetricSinkConfig, SinkOptions};
+use crate::scripting;
+use crate::store::{epoch_samples, Sample};
+
+use timely::dataflow::Stream;
+use timely::dataflow::channels::pact::Exchange;
+use timely::dataflow::operators::generic::operator::Operator;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::{ReplaySpeed, PROGRESS};
+use st2_timely::filter::Filter;
+
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+/// How often the daemon checks for reload requests
+const RELOAD_INTERVAL: Duration = Duration::from_millis(200);
+
+/// What the daemon analyzes and where results go; reloaded on SIGHUP
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// Alerting rules, cf. `alerts`
+    pub rules: Vec<Rule>,
+    /// Where alerts are delivered
+    pub sinks: Vec<SinkConfig>,
+    /// Where every epoch's metrics are published, cf. `publish`
+    pub publish: Vec<MetricSinkConfig>,
+    /// Options of all sinks, e.g. retries of HTTP deliveries
+    pub options: SinkOptions,
+    /// Names of operators in metrics and alerts
+    pub operator_names: BTreeMap<u64, String>,
+}
+
+/// Running statistics of a metric's values (Welford's algorithm)
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Baseline {
+    /// Number of values
+    pub count: u64,
+    /// Mean of the values
+    pub mean: f64,
+    /// Sum of squared differences from the mean
+    m2: f64,
+}
+
+impl Baseline {
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Standard deviation of the values
+    pub fn stddev(&self) -> f64 {
+        if self.count < 2 { 0.0 } else { (self.m2 / (self.count - 1) as f64).sqrt() }
+    }
+}
+
+/// What the daemon has analyzed and delivered so far, checkpointed to disk so
+/// a restarted daemon resumes where it stopped
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The results of all epochs before this one have been delivered to the sinks
+    pub next_epoch: u64,
+    /// Number of completed epochs
+    pub epochs: u64,
+    /// Number of fired alerts
+    pub alerts: u64,
+    /// Baselines of the unlabeled metrics, by name
+    pub baselines: BTreeMap<String, Baseline>,
+}
+
+impl Checkpoint {
+    /// Reads the checkpoint at `path`, if it exists.
+    pub fn load(path: &Path) -> Result<Option<Self>, STError> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map(Some)
+                .map_err(|e| STError::Config(format!("Invalid --checkpoint: {}: {}", path.display(), e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(STError::io(format!("Couldn't read {}", path.display()), e)),
+        }
+    }
+
+    /// Writes the checkpoint to `path`, replacing the previous one atomically.
+    pub fn save(&self, path: &Path) -> Result<(), STError> {
+        let partial = path.with_extension("partial");
+        let contents = serde_json::to_string_pretty(self).expect("checkpoints serialize to JSON");
+        std::fs::write(&partial, contents).map_err(|e| STError::io(format!("Couldn't write {}", partial.display()), e))?;
+        std::fs::rename(&partial, path).map_err(|e| STError::io(format!("Couldn't replace {}", path.display()), e))
+    }
+
+    /// Records the delivered window of `epochs` ending before `next_epoch`.
+    fn record(&mut self, next_epoch: u64, samples: &BTreeMap<u64, Vec<Sample>>, alerts: u64) {
+        self.next_epoch = next_epoch;
+        self.epochs += samples.len() as u64;
+        self.alerts += alerts;
+        for sample in samples.values().flatten().filter(|sample| sample.labels.is_empty()) {
+            self.baselines.entry(sample.name.to_string()).or_insert_with(Baseline::default).add(sample.value);
+        }
+    }
+}
+
+/// Where and how often the daemon checkpoints its progress
+#[derive(Clone, Debug)]
+pub struct Checkpointing {
+    /// The checkpoint file
+    pub path: PathBuf,
+    /// Time between checkpoints
+    pub interval: Duration,
+}
+
+/// State of the daemon, as reported by its health endpoints
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Waiting for the source computation to connect
+    Starting,
+    /// Analyzing the source computation
+    Ready,
+    /// Shutting down, completing the epochs in flight
+    Stopping,
+    /// The analysis failed
+    Failed,
+}
+
+/// Health of the daemon, served at `/healthz` and `/readyz`
+pub struct Health {
+    status: Mutex<Status>,
+    started: Instant,
+    progress: Mutex<Checkpoint>,
+    reloads: AtomicU64,
+}
+
+impl Health {
+    /// Serves the daemon's health over HTTP at `listen`, starting as `Status::Starting`:
+    ///
+    /// - `GET /healthz`: 200 unless the analysis failed (liveness)
+    /// - `GET /readyz`: 200 while the source computation is analyzed (readiness)
+    ///
+    /// Both return the daemon's status, uptime, numbers of completed epochs,
+    /// fired alerts, and config reloads, and the metrics' baselines as JSON.
+    /// Progress continues from `resumed`, e.g. a loaded checkpoint.
+    pub fn serve(listen: &str, resumed: Checkpoint) -> Result<Arc<Health>, STError> {
+        let server = tiny_http::Server::http(listen).map_err(|e| STError::Config(format!("Invalid --listen: {}", e)))?;
+        let health = Arc::new(Health {
+            status: Mutex::new(Status::Starting),
+            started: Instant::now(),
+            progress: Mutex::new(resumed),
+            reloads: AtomicU64::new(0),
+        });
+
+        let served = Arc::clone(&health);
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let status = served.status();
+                let (code, body) = match (request.method(), request.url()) {
+                    (tiny_http::Method::Get, "/healthz") => (if status == Status::Failed { 503 } else { 200 }, served.report()),
+                    (tiny_http::Method::Get, "/readyz") => (if status == Status::Ready { 200 } else { 503 }, served.report()),
+                    _ => (404, json!({ "error": "not found (expected GET /healthz or /readyz)" })),
+                };
+                let response = tiny_http::Response::from_string(body.to_string())
+                    .with_status_code(code)
+                    .with_header(tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).expect("valid header"));
+                if let Err(e) = request.respond(response) {
+                    warn!("couldn't respond to health check: {}", e);
+                }
+            }
+        });
+        tracing::info!(listen, "health endpoints listening");
+        Ok(health)
+    }
+
+    /// The daemon's status
+    pub fn status(&self) -> Status {
+        *self.status.lock().unwrap()
+    }
+
+    /// Moves the daemon to `status`, unless it failed.
+    pub fn set(&self, status: Status) {
+        let mut current = self.status.lock().unwrap();
+        if *current != Status::Failed && *current != status {
+            tracing::info!(from = ?*current, to = ?status, "daemon status changed");
+            *current = status;
+        }
+    }
+
+    fn fail(&self) {
+        *self.status.lock().unwrap() = Status::Failed;
+    }
+
+    fn report(&self) -> Value {
+        let progress = self.progress.lock().unwrap();
+        let baselines: BTreeMap<&str, Value> = progress.baselines.iter()
+            .map(|(name, baseline)| (name.as_str(), json!({ "count": baseline.count, "mean": baseline.mean, "stddev": baseline.stddev() })))
+            .collect();
+        json!({
+            "status": self.status(),
+            "uptime_s": self.started.elapsed().as_secs(),
+            "epochs": progress.epochs,
+            "next_epoch": progress.next_epoch,
+            "alerts": progress.alerts,
+            "reloads": self.reloads.load(Ordering::Acquire),
+            "baselines": baselines,
+        })
+    }
+}
+
+/// Marks the daemon as failed if a worker panics.
+struct PanicGuard(Arc<Health>);
+
+impl Drop for PanicGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            tracing::error!("an analysis worker panicked");
+            self.0.fail();
+        }
+    }
+}
+
+/// The `daemon` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("daemon")
+        .about("Run the online pipeline of `alerts` and `publish` as a long-lived service with health endpoints, e.g. under systemd or Kubernetes")
+        .after_help("ENDPOINTS:
+    GET /healthz    200 unless the analysis failed (liveness)
+    GET /readyz     200 once the source computation is connected, until shutdown (readiness)
+
+    Both return the daemon's status and its numbers of epochs, alerts, and reloads as JSON.
+
+    On SIGHUP, the --config file is reloaded: rules, sinks, publish sinks, and operator names
+    take effect from the next window on. Life cycle events are logged at `info` (cf. --log-format).
+    See `st2 alerts --help` for rules and sinks.")
+        .arg(clap::Arg::with_name("listen")
+            .long("listen")
+            .value_name("ADDR")
+            .help("Address to serve the health endpoints at")
+            .default_value("127.0.0.1:3003"))
+        .arg(clap::Arg::with_name("rule")
+            .short("r")
+            .long("rule")
+            .value_name("RULE")
+            .help("An alerting rule; can be given several times")
+            .multiple(true)
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("sink")
+            .long("sink")
+            .value_name("SINK")
+            .help("Where to deliver alerts; can be given several times")
+            .multiple(true)
+            .number_of_values(1)
+            .default_value("stdout"))
+        .arg(clap::Arg::with_name("template")
+            .long("template")
+            .value_name("PATH")
+            .help("JSON file with the payload template of webhook sinks (default: the alert's JSON)")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("publish")
+            .long("publish")
+            .value_name("SINK")
+            .help("Publish every epoch's metrics to a sink of `publish`; can be given several times")
+            .multiple(true)
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("retries")
+            .long("retries")
+            .value_name("N")
+            .help("Number of retries, with exponential backoff, of failed deliveries to HTTP sinks")
+            .default_value("3"))
+        .arg(clap::Arg::with_name("window")
+            .short("w")
+            .long("window")
+            .value_name("EPOCHS")
+            .help("Number of epochs per evaluated window")
+            .default_value("1"))
+        .arg(clap::Arg::with_name("checkpoint")
+            .long("checkpoint")
+            .value_name("PATH")
+            .help("Checkpoint the daemon's progress and baselines to PATH, and resume from it after a restart")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("checkpoint_interval")
+            .long("checkpoint-interval")
+            .value_name("SECS")
+            .help("Seconds between checkpoints")
+            .default_value("10"))
+}
+
+/// The arguments of `daemon`
+pub struct Options {
+    /// The number of epochs rules are evaluated on
+    pub window: u64,
+    /// The reloadable settings
+    pub settings: Settings,
+    /// Where and how often to checkpoint the analysis
+    pub checkpointing: Option<Checkpointing>,
+    /// The address of the health endpoints
+    pub listen: String,
+}
+
+/// Parses the arguments of `daemon`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    let window: u64 = args.value_of("window").expect("error parsing daemon window args")
+        .parse().map_err(|e| STError::Config(format!("Invalid --window: {}", e)))?;
+    if window == 0 {
+        Err(STError::Config("Invalid --window: has to be at least 1".to_string()))?
+    }
+    let checkpointing = match args.value_of("checkpoint") {
+        Some(path) => {
+            let interval: f64 = args.value_of("checkpoint_interval").expect("error parsing daemon checkpoint interval args")
+                .parse().ok().filter(|secs: &f64| *secs >= 0.0 && secs.is_finite())
+                .ok_or_else(|| STError::Config("Invalid --checkpoint-interval: expected a number of seconds".to_string()))?;
+            Some(Checkpointing { path: PathBuf::from(path), interval: Duration::from_secs_f64(interval) })
+        }
+        None => None,
+    };
+
+    Ok(Options {
+        window,
+        settings: settings(args)?,
+        checkpointing,
+        listen: args.value_of("listen").expect("error parsing daemon listen args").to_string(),
+    })
+}
+
+/// The reloadable settings of `daemon` from `args`, e.g. of a reloaded config
+pub fn settings(args: &Args) -> Result<Settings, STError> {
+    let rules = args.all_values_of("rule").into_iter()
+        .map(|rule| rule.parse::<Rule>().map_err(|e| e.context("Invalid --rule")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let template = match args.value_of("template") {
+        Some(path) => Some(std::fs::read_to_string(path)
+            .map_err(|e| STError::io(path, e))
+            .and_then(|template| template.parse::<Template>())
+            .map_err(|e| e.context("Invalid --template"))?),
+        None => None,
+    };
+    let sinks = args.all_values_of("sink").into_iter()
+        .map(|sink| sink.parse::<SinkConfig>().map_err(|e| e.context("Invalid --sink")))
+        .map(|sink| sink.map(|sink| sink.with_template(template.as_ref())))
+        .collect::<Result<Vec<_>, _>>()?;
+    let publish = args.all_values_of("publish").into_iter()
+        .map(|sink| sink.parse::<MetricSinkConfig>().map_err(|e| e.context("Invalid --publish")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let retries: u32 = args.value_of("retries").expect("error parsing daemon retries args")
+        .parse().map_err(|e| STError::Config(format!("Invalid --retries: {}", e)))?;
+
+    Ok(Settings {
+        rules,
+        sinks,
+        publish,
+        options: SinkOptions { retries, ..Default::default() },
+        operator_names: args.config.operator_names().clone(),
+    })
+}
+
+/// Runs the online pipeline of `alerts` and `publish` as a long-lived service:
+/// evaluates the rules of `settings` on every completed window of `window`
+/// epochs of `replay_source`, delivers alerts to its sinks, and publishes every
+/// epoch's metrics. Whenever `hangup` is set (on SIGHUP), the settings are
+/// replaced by those `reload` returns; if reloading fails, the current settings
+/// are kept. Progress is reported to `health`, continuing from its resumed
+/// checkpoint: windows before its `next_epoch` were delivered before a restart
+/// and are skipped. With `checkpointing`, the progress is checkpointed
+/// periodically and on shutdown. Runs until the source computation disconnects
+/// or ST2 is shut down, and returns the number of alerts, including those before
+/// the resumed checkpoint.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    window: u64,
+    settings: Settings,
+    mut reload: impl FnMut() -> Result<Settings, STError>,
+    hangup: Arc<AtomicBool>,
+    health: Arc<Health>,
+    checkpointing: Option<Checkpointing>,
+    output_format: OutputFormat) -> Result<u64, STError> {
+
+    // epochs cut short by an earlier pipeline of this process don't concern this one
+    PROGRESS.reset_truncated();
+
+    // the settings and their generation, which the first peer applies between windows
+    let current = Arc::new(Mutex::new((0, settings)));
+    let generation = Arc::new(AtomicU64::new(0));
+
+    let local_peers = crate::local_peers(&timely_configuration);
+    let running = Arc::new(AtomicUsize::new(local_peers));
+
+    let (shared, changed, workers_health, workers_running) = (Arc::clone(&current), Arc::clone(&generation), Arc::clone(&health), Arc::clone(&running));
+    let workers_checkpointing = checkpointing.clone();
+    let guards = timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let _guard = PanicGuard(Arc::clone(&workers_health));
+        let index = worker.index();
+        let health = Arc::clone(&workers_health);
+        let shared = Arc::clone(&shared);
+        let changed = Arc::clone(&changed);
+        let checkpointing = workers_checkpointing.clone();
+        let resumed = health.progress.lock().unwrap().next_epoch;
+        let mut checkpointed = Instant::now();
+
+        // only the first peer delivers results
+        let (mut applied, mut settings) = shared.lock().unwrap().clone();
+        let mut sinks = if index == 0 { open_sinks(&settings, output_format).expect("couldn't open sinks") } else { (Vec::new(), None) };
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
+
+            let mut vector = Vec::new();
+            let mut pending = PendingEpochs::new("Daemon");
+            let mut latest = 0;
+            pag.sink(Exchange::new(|_: &(PagEdge, Pair<u64, Duration>, isize)| 0), "Daemon", move |input| {
+                input.for_each(|_cap, data| {
+                    data.swap(&mut vector);
+                    for (edge, _t, _diff) in vector.drain(..) {
+                        latest = std::cmp::max(latest, edge.source.epoch);
+                        pending.push(edge);
+                    }
+                });
+
+                if changed.load(Ordering::Acquire) != applied {
+                    let (generation, reloaded) = shared.lock().unwrap().clone();
+                    match open_sinks(&reloaded, output_format) {
+                        Ok(opened) => {
+                            sinks = opened;
+                            settings = reloaded;
+                            tracing::info!(generation, rules = settings.rules.len() as u64, "applied reloaded config");
+                        }
+                        Err(e) => tracing::error!(generation, error = %e, "couldn't open the sinks of the reloaded config, keeping the current ones"),
+                    }
+                    applied = generation;
+                }
+
+                // edges of epoch `e` are produced at `Pair(e, _)`
+                let frontier = input.frontier().frontier();
+                while let Some(key) = pending.first().map(|epoch| epoch / window) {
+                    if frontier.iter().any(|t| t.first < (key + 1) * window) {
+                        break;
+                    }
+                    let epochs = pending.take_until((key + 1) * window);
+                    let next_epoch = (key + 1) * window;
+                    if next_epoch <= resumed {
+                        // delivered before the restart
+                        continue;
+                    }
+                    // all epochs of the window were dropped for the memory budget
+                    let last = match epochs.keys().next_back() {
+                        Some(last) => *last,
+                        None => continue,
+                    };
+                    let complete = Window::new(&epochs, latest - last);
+                    let samples: BTreeMap<u64, Vec<Sample>> = epochs.iter()
+                        .map(|(epoch, edges)| (*epoch, epoch_samples(*epoch, edges, latest - epoch, &settings.operator_names)))
+                        .collect();
+                    let (alert_sinks, publisher) = &mut sinks;
+                    if let Some(publisher) = publisher.as_mut() {
+                        for (epoch, edges) in epochs.iter() {
+                            publisher.publish(*epoch, edges, &samples[epoch]);
+                        }
+                    }
+
+                    let span = (key * window, (key + 1) * window - 1);
+                    let mut alerts = evaluate(&settings.rules, span, &complete, &settings.operator_names);
+                    if let Some(scripts) = scripting::installed() {
+                        alerts.extend(evaluate_scripts(scripts, span, &complete, &epochs, &samples));
+                    }
+                    let fired = alerts.len() as u64;
+                    for alert in alerts {
+                        for (sink, _) in alert_sinks.iter_mut().filter(|(_, config)| config.accepts(&alert)) {
+                            if let Err(e) = sink.emit(&alert) {
+                                tracing::error!(error = %e, "couldn't deliver alert");
+                            }
+                        }
+                    }
+
+                    let mut progress = health.progress.lock().unwrap();
+                    progress.record(next_epoch, &samples, fired);
+                    if let Some(checkpointing) = checkpointing.as_ref().filter(|c| checkpointed.elapsed() >= c.interval) {
+                        if let Err(e) = progress.save(&checkpointing.path) {
+                            tracing::error!(error = %e, "couldn't checkpoint");
+                        }
+                        checkpointed = Instant::now();
+                    }
+                }
+            });
+        });
+
+        health.set(Status::Ready);
+        while worker.step_or_park(None) {}
+        workers_running.fetch_sub(1, Ordering::AcqRel);
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    while running.load(Ordering::Acquire) > 0 && health.status() != Status::Failed {
+        if !is_running.load(Ordering::Acquire) {
+            health.set(Status::Stopping);
+        }
+        if hangup.swap(false, Ordering::AcqRel) {
+            match reload() {
+                Ok(settings) => {
+                    // only an interrupt cuts epochs short; unless ST2 is shutting
+                    // down, no marker of an earlier run may outlive the reload
+                    if is_running.load(Ordering::Acquire) {
+                        PROGRESS.reset_truncated();
+                    }
+                    let next = generation.load(Ordering::Acquire) + 1;
+                    *current.lock().unwrap() = (next, settings);
+                    generation.store(next, Ordering::Release);
+                    health.reloads.fetch_add(1, Ordering::Relaxed);
+                    tracing::info!(generation = next, "config reloaded");
+                }
+                Err(e) => tracing::error!(error = %e, "couldn't reload config, keeping the current one"),
+            }
+        }
+        std::thread::sleep(RELOAD_INTERVAL);
+    }
+
+    let checkpoint = || match checkpointing.as_ref() {
+        Some(checkpointing) => {
+            let progress = health.progress.lock().unwrap();
+            tracing::info!(next_epoch = progress.next_epoch, path = %checkpointing.path.display(), "checkpointed");
+            progress.save(&checkpointing.path)
+        }
+        None => Ok(()),
+    };
+    if health.status() == Status::Failed {
+        checkpoint()?;
+        // the other workers may wait for the failed one forever, so don't join them
+        std::mem::forget(guards);
+        return Err(STError::Analysis("an analysis worker panicked".to_string()));
+    }
+    health.set(Status::Stopping);
+    guards.join().into_iter().collect::<Result<Vec<_>, _>>()
+        .map_err(|e| STError::Analysis(format!("error in the timely computation: {}", e)))?;
+    checkpoint()?;
+    let alerts = health.progress.lock().unwrap().alerts;
+    Ok(alerts)
+}
+
+/// The opened alert sinks and metric publisher of `settings`
+fn open_sinks(settings: &Settings, output_format: OutputFormat) -> Result<(Vec<(Box<dyn Sink + Send>, SinkConfig)>, Option<Publisher>), STError> {
+    let sinks = settings.sinks.iter()
+        .map(|sink| sink.spec.open(output_format, settings.options.retries).map(|opened| (opened, sink.clone())))
+        .collect::<Result<_, _>>()?;
+    let publisher = Publisher::open(&settings.publish, &settings.options, &settings.operator_names)?;
+    Ok((sinks, Some(publisher)))
+}
@@ -1,39 +1,128 @@
 use crate::pag;
 use crate::pag::PagEdge;
 use crate::STError;
+use crate::auth::Auth;
+use crate::config::Args;
 use crate::PagData;
 use crate::commands::algo::{KHops, KHopsSummary};
-use crate::{MetricsData, KHopSummaryData};
+use crate::{MetricsData, KHopSummaryData, LatencyData};
 use crate::commands::metrics::Metrics;
 use crate::InvariantData;
 use crate::commands::invariants::Invariants;
 use crate::{EpochData, OperatorData, MessageData};
 
 use timely::dataflow::Stream;
+use timely::dataflow::channels::pact::Pipeline;
 use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::concat::Concat;
+use timely::dataflow::operators::delay::Delay;
+use timely::dataflow::operators::aggregation::aggregate::Aggregate;
+use timely::dataflow::operators::generic::operator::Operator;
 
 use std::time::Duration;
 use std::sync::mpsc;
-use std::sync::{Mutex, Arc};
+use std::sync::{Mutex, Arc, atomic::AtomicBool};
 use std::convert::TryInto;
+use std::collections::BTreeMap;
 
 use st2_logformat::pair::Pair;
 
-use tdiag_connect::receive as connect;
 use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
 
 
+/// The `dashboard` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("dashboard")
+        .about("run ST2 live dashboard")
+        .arg(clap::Arg::with_name("listen")
+            .short("l")
+            .long("listen")
+            .value_name("ADDR")
+            .default_value("127.0.0.1:3012")
+            .help("Address to serve the dashboard's web UI on"))
+        .arg(clap::Arg::with_name("auth")
+            .long("auth")
+            .value_name("PATH")
+            .help("TOML file of the tokens allowed to access the dashboard's endpoints (/, /charts.js, /ws), and which ones (cf. README)"))
+        .arg(clap::Arg::with_name("epoch_max")
+            .short("e")
+            .long("epoch-max")
+            .value_name("MS")
+            .help("Temporal invariant: the maximum milliseconds an epoch is allowed to take"))
+        .arg(clap::Arg::with_name("operator_max")
+            .short("o")
+            .long("operator-max")
+            .value_name("MS")
+            .help("Temporal invariant: the maximum milliseconds an operator is allowed to take"))
+        .arg(clap::Arg::with_name("message_max")
+            .short("m")
+            .long("message-max")
+            .value_name("MS")
+            .help("Temporal invariant: the maximum milliseconds a control or data message is allowed to take"))
+}
+
+/// The arguments of `dashboard`
+pub struct Options {
+    /// Temporal invariant of epochs (ms)
+    pub epoch_max: Option<u64>,
+    /// Temporal invariant of operators (ms)
+    pub operator_max: Option<u64>,
+    /// Temporal invariant of messages (ms)
+    pub message_max: Option<u64>,
+    /// Who may access the dashboard
+    pub auth: Option<Auth>,
+    /// The address to serve the dashboard at
+    pub listen: String,
+}
+
+/// Parses the arguments of `dashboard`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    let epoch_max: Option<u64> = if let Some(t) = args.value_of("epoch_max") {
+        eprintln!("epoch max given");
+        Some(t.parse().map_err(|e| STError::Config(format!("Invalid --epoch-max: {}", e)))?)
+    } else {
+        None
+    };
+    let operator_max: Option<u64> = if let Some(t) = args.value_of("operator_max") {
+        Some(t.parse().map_err(|e| STError::Config(format!("Invalid --operator-max: {}", e)))?)
+    } else {
+        None
+    };
+    let message_max: Option<u64> = if let Some(t) = args.value_of("message_max") {
+        Some(t.parse().map_err(|e| STError::Config(format!("Invalid --message-max: {}", e)))?)
+    } else {
+        None
+    };
+
+    Ok(Options {
+        epoch_max,
+        operator_max,
+        message_max,
+        auth: args.value_of("auth").map(|path| Auth::load(std::path::Path::new(path))).transpose()?,
+        listen: args.value_of("listen").expect("error parsing listen args").to_string(),
+    })
+}
+
 /// Creates an online dashboard for ST2.
 pub fn run(
     timely_configuration: timely::Configuration,
     replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
     pag_send: Arc<Mutex<mpsc::Sender<(u64, PagData)>>>,
     epoch_max: Option<u64>,
     operator_max: Option<u64>,
     message_max: Option<u64>,
 ) -> Result<(), STError> {
 
+    let local_peers = crate::local_peers(&timely_configuration);
+
     timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
         let index = worker.index();
 
         let pag_send1 = pag_send.lock().expect("cannot lock pag_send").clone();
@@ -43,12 +132,13 @@ pub fn run(
         let pag_send6 = pag_send.lock().expect("cannot lock pag_send").clone();
         let pag_send7 = pag_send.lock().expect("cannot lock pag_send").clone();
         let pag_send8 = pag_send.lock().expect("cannot lock pag_send").clone();
+        let pag_send9 = pag_send.lock().expect("cannot lock pag_send").clone();
 
         // read replayers from file (offline) or TCP stream (online)
-        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
 
         worker.dataflow(|scope| {
-            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = pag::create_pag(scope, readers, index, 1);
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
 
             // log PAG to socket
             pag.inspect(move |(x, t, _)| {
@@ -94,6 +184,53 @@ pub fn run(
             });
 
 
+            let latency = pag
+                .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+                .map(|(edge, _t, _diff)| (edge.source.epoch, edge))
+                .aggregate::<_,(u64, u64),_,_,_>(
+                    |_key, edge, acc| {
+                        let from: u64 = edge.source.timestamp.as_nanos().try_into().unwrap();
+                        let to: u64 = edge.destination.timestamp.as_nanos().try_into().unwrap();
+                        *acc = if *acc == (0, 0) {
+                            (from, to)
+                        } else {
+                            (std::cmp::min(acc.0, from), std::cmp::max(acc.1, to))
+                        };
+                    },
+                    |key, acc| LatencyData { e: key, s: acc.0, l: acc.1 - acc.0 },
+                    |key| *key);
+
+            // log epoch latencies to socket once all other per-epoch data has been sent,
+            // i.e., once none of the per-epoch streams can produce data for the epoch anymore.
+            // The dashboard is pushed an update for every completed epoch.
+            let mut vector = Vec::new();
+            let mut pending = BTreeMap::new();
+            latency
+                .map(Some)
+                .concat(&khops.map(|_| None))
+                .concat(&khops_summary.map(|_| None))
+                .concat(&metrics.map(|_| None))
+                .sink(Pipeline, "EpochLatency", move |input| {
+                    input.for_each(|_cap, data| {
+                        data.swap(&mut vector);
+                        for latency in vector.drain(..).flatten() {
+                            pending.insert(latency.e, latency);
+                        }
+                    });
+
+                    // per-epoch data of epoch `e` is produced at `Pair(e + 1, _)`
+                    let frontier = input.frontier().frontier();
+                    while let Some(epoch) = pending.keys().next().cloned() {
+                        if frontier.iter().any(|t| t.first <= epoch + 1) {
+                            break;
+                        }
+                        let latency = pending.remove(&epoch).expect("pending epoch");
+                        pag_send9
+                            .send((epoch, PagData::Lat(latency)))
+                            .expect("latency")
+                    }
+                });
+
             if let Some(epoch_max) = epoch_max {
                 let max = Duration::from_millis(epoch_max);
                 let max_nanos: u64 = max.as_nanos().try_into().unwrap();
@@ -139,7 +276,7 @@ pub fn run(
             }
         });
     })
-        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
 
     Ok(())
 }
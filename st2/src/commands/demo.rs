@@ -0,0 +1,297 @@
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+use timely::dataflow::InputHandle;
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::{Accumulate, Exchange, Input, Probe};
+use timely::dataflow::operators::generic::operator::Operator;
+use timely::dataflow::operators::capture::EventWriter;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, atomic::AtomicBool};
+use std::time::{Duration, Instant};
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::connect::{Adapter, TcpStreamOrFile};
+use st2_timely::filter::Filter;
+use st2_timely::replay_throttled::ReplaySpeed;
+
+/// The built-in demo computation: every epoch, each worker feeds records into
+/// a `Work` operator, which takes longer on higher workers (`skew`), exchanges
+/// them to a `Stall` operator, which stalls the last worker now and then
+/// (`stall_every`), and counts them. Worker 0's input comes in bursts
+/// (`burst_every`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Demo {
+    /// Number of workers
+    pub workers: usize,
+    /// Number of epochs
+    pub epochs: u64,
+    /// Records per worker and epoch
+    pub records: u64,
+    /// Time `Work` takes per record, on worker 0
+    pub record_time: Duration,
+    /// How much longer the last worker takes per record than worker 0 (at
+    /// least 1); workers in between are spread evenly
+    pub skew: f64,
+    /// Every this many epochs, the last worker stalls; `0` never stalls
+    pub stall_every: u64,
+    /// How long a stall takes
+    pub stall: Duration,
+    /// Every this many epochs, worker 0's input is a burst; `0` never bursts
+    pub burst_every: u64,
+    /// How many times the usual records a burst has
+    pub burst: u64,
+}
+
+impl Default for Demo {
+    fn default() -> Self {
+        Demo {
+            workers: 4,
+            epochs: 50,
+            records: 1000,
+            record_time: Duration::from_micros(2),
+            skew: 2.0,
+            stall_every: 10,
+            stall: Duration::from_millis(20),
+            burst_every: 7,
+            burst: 10,
+        }
+    }
+}
+
+impl Demo {
+    /// Time `Work` takes per record on `worker`
+    fn record_time(&self, worker: usize) -> Duration {
+        let share = if self.workers > 1 { worker as f64 / (self.workers - 1) as f64 } else { 0.0 };
+        self.record_time.mul_f64(1.0 + (self.skew.max(1.0) - 1.0) * share)
+    }
+
+    /// Whether the last worker stalls in `epoch`
+    fn stalls(&self, epoch: u64) -> bool {
+        self.stall_every > 0 && epoch % self.stall_every == self.stall_every - 1
+    }
+
+    /// Records `worker` feeds in in `epoch`
+    fn input(&self, worker: usize, epoch: u64) -> u64 {
+        if worker == 0 && self.burst_every > 0 && epoch % self.burst_every == self.burst_every - 1 {
+            self.records * self.burst
+        } else {
+            self.records
+        }
+    }
+
+    /// Runs the computation, writing its trace to `<worker>.dump` in `dir`.
+    pub fn run(&self, dir: &Path) -> Result<(), STError> {
+        let configuration = match self.workers {
+            1 => timely::Configuration::Thread,
+            n => timely::Configuration::Process(n),
+        };
+        let demo = self.clone();
+        let dir = dir.to_path_buf();
+
+        timely::execute(configuration, move |worker| {
+            let index = worker.index();
+            let path = dir.join(format!("{}.dump", index));
+            let file = File::create(&path).unwrap_or_else(|e| panic!("couldn't create {}: {}", path.display(), e));
+            let adapter = Adapter::attach_writers(worker, vec![EventWriter::new(TcpStreamOrFile::File(file))], None);
+
+            let (record_time, stall) = (demo.record_time(index), demo.stall);
+            let stalls = { let demo = demo.clone(); move |epoch| demo.stalls(epoch) };
+            let last = index + 1 == demo.workers;
+
+            let mut input = InputHandle::new();
+            let probe = worker.dataflow(|scope| {
+                scope.input_from(&mut input)
+                    .unary(Pipeline, "Work", move |_capability, _info| {
+                        let mut vector = Vec::new();
+                        move |input, output| {
+                            input.for_each(|time, data| {
+                                data.swap(&mut vector);
+                                spin(record_time * vector.len() as u32);
+                                output.session(&time).give_vec(&mut vector);
+                            });
+                        }
+                    })
+                    .exchange(|x: &u64| *x)
+                    .unary(Pipeline, "Stall", move |_capability, _info| {
+                        let mut vector = Vec::new();
+                        let mut stalled = None;
+                        move |input, output| {
+                            input.for_each(|time, data| {
+                                data.swap(&mut vector);
+                                let epoch = *time.time();
+                                if last && stalls(epoch) && stalled != Some(epoch) {
+                                    std::thread::sleep(stall);
+                                    stalled = Some(epoch);
+                                }
+                                output.session(&time).give_vec(&mut vector);
+                            });
+                        }
+                    })
+                    .count()
+                    .probe()
+            });
+
+            for epoch in 0 .. demo.epochs {
+                let records = demo.input(index, epoch);
+                for i in 0 .. records {
+                    input.send((epoch * records + i) * demo.workers as u64 + index as u64);
+                }
+                input.advance_to(epoch + 1);
+                while probe.less_than(input.time()) { worker.step(); }
+
+                adapter.tick_epoch();
+            }
+        })
+            .map_err(|x| STError::Analysis(format!("error in the demo computation: {}", x)))?;
+
+        Ok(())
+    }
+}
+
+/// Keeps the worker busy for `duration`.
+fn spin(duration: Duration) {
+    let started = Instant::now();
+    while started.elapsed() < duration {}
+}
+
+/// The `demo` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("demo")
+        .about("Run a built-in instrumented timely computation and analyze it right away, to see ST2 at work without a dataflow of your own")
+        .arg(clap::Arg::with_name("peers")
+            .long("peers")
+            .value_name("PEERS")
+            .help("Number of workers of the demo computation")
+            .default_value("4"))
+        .arg(clap::Arg::with_name("epochs")
+            .long("epochs")
+            .value_name("EPOCHS")
+            .help("Number of epochs")
+            .default_value("50"))
+        .arg(clap::Arg::with_name("records")
+            .long("records")
+            .value_name("RECORDS")
+            .help("Records every worker feeds in per epoch")
+            .default_value("1000"))
+        .arg(clap::Arg::with_name("skew")
+            .long("skew")
+            .value_name("FACTOR")
+            .help("How much longer the last worker processes a record than worker 0 (at least 1)")
+            .default_value("2"))
+        .arg(clap::Arg::with_name("stall_every")
+            .long("stall-every")
+            .value_name("EPOCHS")
+            .help("Stall the last worker every EPOCHS epochs; 0 never stalls")
+            .default_value("10"))
+        .arg(clap::Arg::with_name("stall")
+            .long("stall")
+            .value_name("MS")
+            .help("How long a stall takes")
+            .default_value("20"))
+        .arg(clap::Arg::with_name("burst_every")
+            .long("burst-every")
+            .value_name("EPOCHS")
+            .help("Burst worker 0's input every EPOCHS epochs; 0 never bursts")
+            .default_value("7"))
+        .arg(clap::Arg::with_name("burst")
+            .long("burst")
+            .value_name("FACTOR")
+            .help("How many times the usual records a burst has")
+            .default_value("10"))
+        .arg(clap::Arg::with_name("out")
+            .long("out")
+            .value_name("DIR")
+            .help("Keep the demo's trace (*.dump files) and per-epoch metrics in DIR [default: a temporary directory, removed afterwards]"))
+}
+
+/// The arguments of `demo`
+pub struct Options {
+    /// The demo computation
+    pub demo: Demo,
+    /// The directory to keep the computation's `*.dump` files in
+    pub out: Option<PathBuf>,
+}
+
+/// Parses the arguments of `demo`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    fn parse<T: std::str::FromStr>(args: &Args, name: &str, flag: &str) -> Result<T, STError>
+    where T::Err: std::fmt::Display {
+        args.value_of(name).expect("error parsing demo args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --{}: {}", flag, e)))
+    }
+
+    let demo = Demo {
+        workers: parse(args, "peers", "peers")?,
+        epochs: parse(args, "epochs", "epochs")?,
+        records: parse(args, "records", "records")?,
+        skew: parse(args, "skew", "skew")?,
+        stall_every: parse(args, "stall_every", "stall-every")?,
+        stall: Duration::from_millis(parse(args, "stall", "stall")?),
+        burst_every: parse(args, "burst_every", "burst-every")?,
+        burst: parse(args, "burst", "burst")?,
+        ..Default::default()
+    };
+    if demo.workers == 0 {
+        Err(STError::Config("Invalid --peers: expected at least 1".to_string()))?
+    }
+    if !(demo.skew >= 1.0 && demo.skew.is_finite()) {
+        Err(STError::Config(format!("Invalid --skew: {} is not at least 1", demo.skew)))?
+    }
+
+    Ok(Options {
+        demo,
+        out: args.value_of("out").map(PathBuf::from),
+    })
+}
+
+/// Runs the `demo` computation, writing its trace to `dir` (or a temporary
+/// directory), and analyzes the trace right away: prints per-worker,
+/// per-operator, and per-activity aggregates, and writes per-epoch metrics to
+/// `metrics.csv` next to the trace.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    demo: &Demo,
+    dir: Option<&Path>,
+    operator_names: &BTreeMap<u64, String>,
+    output_format: OutputFormat) -> Result<(), STError> {
+
+    let (dir, keep) = match dir {
+        Some(dir) => (dir.to_path_buf(), true),
+        None => (std::env::temp_dir().join(format!("st2-demo-{}", std::process::id())), false),
+    };
+    std::fs::create_dir_all(&dir).map_err(|e| STError::io(format!("couldn't create {}", dir.display()), e))?;
+
+    eprintln!("Running the demo computation ({} workers, {} epochs)", demo.workers, demo.epochs);
+    demo.run(&dir)?;
+
+    eprintln!("Analyzing its trace");
+    let files: Vec<Option<PathBuf>> = (0 .. demo.workers).map(|worker| Some(dir.join(format!("{}.dump", worker)))).collect();
+    let replay_source = ReplaySource::Files(Arc::new(Mutex::new(files)));
+    let metrics_path = dir.join("metrics.csv");
+    super::metrics::run(
+        timely_configuration,
+        replay_source,
+        Arc::new(AtomicBool::new(true)),
+        ReplaySpeed::Unbounded,
+        Filter::default(),
+        &metrics_path,
+        None,
+        false,
+        None,
+        true,
+        operator_names,
+        output_format)?;
+
+    if keep {
+        eprintln!("\nThe trace is in {}, per-epoch metrics in {}. Explore it further with e.g. `st2 -f {} -s {} top` or `report`.",
+                  dir.display(), metrics_path.display(), dir.display(), demo.workers);
+    } else {
+        std::fs::remove_dir_all(&dir).map_err(|e| STError::io(format!("couldn't remove {}", dir.display()), e))?;
+        eprintln!("\nRun with --out <DIR> to keep the trace and explore it further, e.g. with `st2 -f <DIR> -s {} top`.", demo.workers);
+    }
+    Ok(())
+}
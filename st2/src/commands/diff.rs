@@ -0,0 +1,538 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::commands::alerts::EpochStats;
+use crate::commands::export::escape_xml;
+use crate::commands::metrics::{self, Metrics, BreakdownKey};
+use crate::store::completed_epochs;
+
+use timely::dataflow::Stream;
+use timely::dataflow::operators::inspect::Inspect;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, atomic::AtomicBool};
+use std::time::Duration;
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use serde_json::json;
+
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+/// Total time spent per operator and activity type over a whole trace, in ns
+pub type Totals = BTreeMap<BreakdownKey, u64>;
+
+/// A change in the time spent on an operator or activity type between two traces
+pub struct Change {
+    /// The operator or activity type
+    pub key: BreakdownKey,
+    /// Time spent in the first trace, in ns
+    pub a: u64,
+    /// Time spent in the second trace, in ns
+    pub b: u64,
+    /// Share of the time spent on all operators resp. activity types in the first trace
+    pub share_a: f64,
+    /// Share of the time spent on all operators resp. activity types in the second trace
+    pub share_b: f64,
+}
+
+impl Change {
+    /// Change of the time spent, in ns
+    pub fn delta(&self) -> i128 {
+        self.b as i128 - self.a as i128
+    }
+}
+
+/// Results of a single epoch, for the significance of changes between traces
+#[derive(Default)]
+pub struct EpochSample {
+    /// Time from the epoch's first to its last event, in ns
+    pub latency: u64,
+    /// Time spent per operator, in ns
+    pub operators: HashMap<u64, u64>,
+    /// Time on the critical path per operator, in ns
+    pub critical_path_operators: HashMap<u64, u64>,
+    /// Time on the critical path per activity type, in ns
+    pub critical_path_activities: BTreeMap<String, u64>,
+}
+
+impl EpochSample {
+    fn new(edges: &[PagEdge]) -> Self {
+        let stats = EpochStats::new(edges);
+        let mut sample = EpochSample { latency: stats.latency, ..Default::default() };
+        for edge in edges {
+            if let Some(operator) = edge.operator_id {
+                *sample.operators.entry(operator).or_insert(0) += edge.duration();
+            }
+        }
+        for edge in stats.critical_path.iter() {
+            if let Some(operator) = edge.operator_id {
+                *sample.critical_path_operators.entry(operator).or_insert(0) += edge.duration();
+            }
+            *sample.critical_path_activities.entry(format!("{:?}", edge.edge_type)).or_insert(0) += edge.duration();
+        }
+        sample
+    }
+}
+
+/// Format of comparison reports
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReportFormat {
+    /// A self-contained HTML page
+    Html,
+    /// GitHub-flavored Markdown, e.g. for pull request comments
+    Markdown,
+}
+
+impl ReportFormat {
+    /// `Html` for paths ending in `.html` or `.htm`, otherwise `Markdown`
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("html") | Some("htm") => ReportFormat::Html,
+            _ => ReportFormat::Markdown,
+        }
+    }
+}
+
+/// The `diff` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("diff")
+        .about("Compare the activities of two offline traces of the same computation, e.g. before and after an optimization")
+        .arg(clap::Arg::with_name("trace_a")
+            .value_name("TRACE_A")
+            .help("Path to the *.dump files of the baseline trace (without trailing /)")
+            .required(true))
+        .arg(clap::Arg::with_name("trace_b")
+            .value_name("TRACE_B")
+            .help("Path to the *.dump files of the trace to compare (without trailing /)")
+            .required(true))
+        .arg(clap::Arg::with_name("top")
+            .long("top")
+            .value_name("N")
+            .help("Number of operators and activity types to report")
+            .default_value("20"))
+        .arg(clap::Arg::with_name("report")
+            .long("report")
+            .value_name("PATH")
+            .help("Also write a comparison report with significance annotations to PATH, as HTML (*.html) or Markdown")
+            .takes_value(true))
+}
+
+/// The arguments of `diff`
+pub struct Options {
+    /// The number of largest changes to list
+    pub top: usize,
+    /// The directory of the first trace's `*.dump` files
+    pub trace_a: String,
+    /// The directory of the second trace's `*.dump` files
+    pub trace_b: String,
+    /// The HTML report
+    pub report: Option<PathBuf>,
+}
+
+/// Parses the arguments of `diff`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        top: args.value_of("top").expect("error parsing diff top args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --top: {}", e)))?,
+        trace_a: args.value_of("trace_a").expect("error parsing diff trace args").to_string(),
+        trace_b: args.value_of("trace_b").expect("error parsing diff trace args").to_string(),
+        report: args.value_of("report").map(PathBuf::from),
+    })
+}
+
+/// Compares the traces in `source_a` and `source_b`: constructs the PAG of both in
+/// the same computation and prints the `top` operators and activity types whose
+/// latency contribution changed most from `source_a` to `source_b`, showing operators
+/// with their `operator_names`, if known, as a table or JSON object.
+///
+/// If a `report` path is given, also writes a comparison report to it (cf.
+/// `write_report`), e.g. to attach to a performance PR. The report needs every
+/// epoch's critical path, so all PAG edges are collected at the first ST2 peer.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    source_a: ReplaySource,
+    source_b: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    top: usize,
+    operator_names: &BTreeMap<u64, String>,
+    report: Option<(&Path, &str, &str)>,
+    output_format: OutputFormat) -> Result<(), STError> {
+
+    let mut report_out = match report {
+        Some((path, _, _)) => Some(BufWriter::new(File::create(path)
+            .map_err(|e| STError::io(format!("couldn't create {}", path.display()), e))?)),
+        None => None,
+    };
+    let with_report = report.is_some();
+
+    let totals_a = Arc::new(Mutex::new(Totals::new()));
+    let totals_b = Arc::new(Mutex::new(Totals::new()));
+    let samples_a = Arc::new(Mutex::new(Vec::new()));
+    let samples_b = Arc::new(Mutex::new(Vec::new()));
+    let totals = vec![
+        (source_a, Arc::clone(&totals_a), Arc::clone(&samples_a)),
+        (source_b, Arc::clone(&totals_b), Arc::clone(&samples_b)),
+    ];
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+
+        for (replay_source, totals, samples) in totals.iter() {
+            // read replayers from file
+            let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+            let totals = Arc::clone(totals);
+            let samples = Arc::clone(samples);
+
+            worker.dataflow(|scope| {
+                let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
+                pag.breakdown()
+                    .inspect(move |(key, _count, t, _records)| {
+                        if let BreakdownKey::Worker(_) = key {
+                            return;
+                        }
+                        *totals.lock().unwrap().entry(*key).or_insert(0) += t;
+                    });
+
+                if with_report {
+                    completed_epochs(&pag, "DiffEpochs", move |_epoch, edges, _ahead| {
+                        samples.lock().unwrap().push(EpochSample::new(&edges));
+                    });
+                }
+            });
+        }
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    let changes = changes(&totals_a.lock().unwrap(), &totals_b.lock().unwrap());
+    match output_format {
+        OutputFormat::Text => print_changes(&changes, top, operator_names),
+        OutputFormat::Json => println!("{}", changes_json(&changes, top, operator_names)),
+    }
+
+    if let (Some((path, name_a, name_b)), Some(out)) = (report, report_out.as_mut()) {
+        let (samples_a, samples_b) = (samples_a.lock().unwrap(), samples_b.lock().unwrap());
+        write_report(out, ReportFormat::from_path(path), &changes, [(name_a, &samples_a[..]), (name_b, &samples_b[..])], top, operator_names)?;
+        out.flush()?;
+        eprintln!("Wrote comparison report to {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Significance level of changes marked `*`
+const SIGNIFICANT: f64 = 0.05;
+/// Significance level of changes marked `**`
+const HIGHLY_SIGNIFICANT: f64 = 0.01;
+
+/// Writes a comparison of the traces `a` and `b` (their names and epoch samples) in
+/// `format`: epoch latency percentiles, the `top` operator changes with their mean
+/// time per epoch, and the critical path composition of both traces. Changes are
+/// annotated with the p-value of a two-sided Mann-Whitney U test of the traces'
+/// per-epoch values, as `**` (p < 0.01), `*` (p < 0.05), or `n.s.`, so changes
+/// within the noise of epoch-to-epoch variation don't look like regressions.
+pub fn write_report<W: Write>(
+    out: &mut W,
+    format: ReportFormat,
+    changes: &[Change],
+    traces: [(&str, &[EpochSample]); 2],
+    top: usize,
+    operator_names: &BTreeMap<u64, String>) -> std::io::Result<()> {
+
+    let [(name_a, a), (name_b, b)] = traces;
+    let ms = |ns: f64| format!("{:.3}", ns / 1_000_000.0);
+    let significance = |p: Option<f64>| match p {
+        Some(p) if p < HIGHLY_SIGNIFICANT => format!("** (p={:.4})", p),
+        Some(p) if p < SIGNIFICANT => format!("* (p={:.3})", p),
+        Some(p) => format!("n.s. (p={:.2})", p),
+        None => "-".to_string(),
+    };
+    let relative = |a: f64, b: f64| if a > 0.0 { format!("{:+.1}%", 100.0 * (b - a) / a) } else { "-".to_string() };
+    let mut document = Document { out, format };
+
+    document.heading(1, "ST2 comparison")?;
+    document.paragraph(&format!("A: {} ({} epochs), B: {} ({} epochs)", name_a, a.len(), name_b, b.len()))?;
+
+    // latency distribution
+    document.heading(2, "Epoch latency (ms)")?;
+    let latencies = |samples: &[EpochSample]| {
+        let mut latencies: Vec<f64> = samples.iter().map(|sample| sample.latency as f64).collect();
+        latencies.sort_by(|x, y| x.partial_cmp(y).expect("latencies are finite"));
+        latencies
+    };
+    let (latencies_a, latencies_b) = (latencies(a), latencies(b));
+    let p = mann_whitney(&latencies_a, &latencies_b);
+    let mut rows = Vec::new();
+    for (label, q) in &[("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.0)] {
+        let (x, y) = (percentile(&latencies_a, *q), percentile(&latencies_b, *q));
+        rows.push(vec![label.to_string(), ms(x), ms(y), relative(x, y)]);
+    }
+    let (mean_a, mean_b) = (mean(&latencies_a), mean(&latencies_b));
+    rows.push(vec!["mean".to_string(), ms(mean_a), ms(mean_b), relative(mean_a, mean_b)]);
+    document.table(&["", "A", "B", "Δ"], &rows)?;
+    document.paragraph(&format!("Latency distribution change: {}", significance(p)))?;
+
+    // per-operator deltas
+    document.heading(2, "Operators (mean ms per epoch)")?;
+    let per_epoch = |samples: &[EpochSample], operator: u64| -> Vec<f64> {
+        samples.iter().map(|sample| sample.operators.get(&operator).cloned().unwrap_or(0) as f64).collect()
+    };
+    let rows: Vec<Vec<String>> = changes.iter()
+        .filter_map(|change| match change.key {
+            BreakdownKey::Operator(operator) => Some(operator),
+            _ => None,
+        })
+        .take(top)
+        .map(|operator| {
+            let (x, y) = (per_epoch(a, operator), per_epoch(b, operator));
+            let (mean_x, mean_y) = (mean(&x), mean(&y));
+            vec![metrics::operator_label(operator, operator_names), ms(mean_x), ms(mean_y), ms(mean_y - mean_x), relative(mean_x, mean_y), significance(mann_whitney(&x, &y))]
+        })
+        .collect();
+    document.table(&["Operator", "A", "B", "Δ", "Δ %", "Significance"], &rows)?;
+
+    // critical path composition
+    document.heading(2, "Critical path composition")?;
+    let shares = |samples: &[EpochSample], key: &dyn Fn(&EpochSample) -> Vec<(String, u64)>| -> BTreeMap<String, f64> {
+        let mut totals: BTreeMap<String, u64> = BTreeMap::new();
+        for sample in samples {
+            for (name, ns) in key(sample) {
+                *totals.entry(name).or_insert(0) += ns;
+            }
+        }
+        let total = totals.values().sum::<u64>().max(1) as f64;
+        totals.into_iter().map(|(name, ns)| (name, ns as f64 / total)).collect()
+    };
+    let activities = |sample: &EpochSample| -> Vec<(String, u64)> { sample.critical_path_activities.iter().map(|(activity, ns)| (activity.clone(), *ns)).collect() };
+    let operators = |sample: &EpochSample| -> Vec<(String, u64)> { sample.critical_path_operators.iter().map(|(operator, ns)| (metrics::operator_label(*operator, operator_names), *ns)).collect() };
+    for (label, key) in &[("Activity type", &activities as &dyn Fn(&EpochSample) -> Vec<(String, u64)>), ("Operator", &operators)] {
+        let (x, y) = (shares(a, *key), shares(b, *key));
+        let mut names: Vec<&String> = x.keys().chain(y.keys()).collect::<std::collections::BTreeSet<_>>().into_iter().collect();
+        let delta = |name: &String| y.get(name).cloned().unwrap_or(0.0) - x.get(name).cloned().unwrap_or(0.0);
+        names.sort_by(|m, n| delta(n).abs().partial_cmp(&delta(m).abs()).expect("shares are finite"));
+        let rows: Vec<Vec<String>> = names.into_iter().take(top)
+            .map(|name| vec![
+                name.clone(),
+                format!("{:.1}%", 100.0 * x.get(name).cloned().unwrap_or(0.0)),
+                format!("{:.1}%", 100.0 * y.get(name).cloned().unwrap_or(0.0)),
+                format!("{:+.1} pp", 100.0 * delta(name)),
+            ])
+            .collect();
+        document.table(&[*label, "A", "B", "Δ"], &rows)?;
+    }
+
+    document.paragraph("Significance: two-sided Mann-Whitney U test of per-epoch values; ** p < 0.01, * p < 0.05, n.s. not significant.")?;
+    document.finish()
+}
+
+/// A report being written in HTML or Markdown
+struct Document<'a, W: Write> {
+    out: &'a mut W,
+    format: ReportFormat,
+}
+
+impl<'a, W: Write> Document<'a, W> {
+    fn heading(&mut self, level: usize, text: &str) -> std::io::Result<()> {
+        match self.format {
+            ReportFormat::Html if level == 1 => {
+                writeln!(self.out, "<!DOCTYPE html>")?;
+                writeln!(self.out, r#"<html><head><meta charset="utf-8"><title>{}</title>"#, escape_xml(text))?;
+                writeln!(self.out, "<style>body {{ font-family: sans-serif; margin: 2em; }} table {{ border-collapse: collapse; }} th, td {{ border: 1px solid #ddd; padding: 4px 8px; text-align: right; }} th:first-child, td:first-child {{ text-align: left; }}</style>")?;
+                writeln!(self.out, "</head><body><h1>{}</h1>", escape_xml(text))
+            }
+            ReportFormat::Html => writeln!(self.out, "<h{0}>{1}</h{0}>", level, escape_xml(text)),
+            ReportFormat::Markdown => writeln!(self.out, "{} {}\n", "#".repeat(level), text),
+        }
+    }
+
+    fn paragraph(&mut self, text: &str) -> std::io::Result<()> {
+        match self.format {
+            ReportFormat::Html => writeln!(self.out, "<p>{}</p>", escape_xml(text)),
+            ReportFormat::Markdown => writeln!(self.out, "{}\n", text.replace('*', "\\*")),
+        }
+    }
+
+    fn table(&mut self, header: &[&str], rows: &[Vec<String>]) -> std::io::Result<()> {
+        match self.format {
+            ReportFormat::Html => {
+                write!(self.out, "<table><tr>")?;
+                for cell in header {
+                    write!(self.out, "<th>{}</th>", escape_xml(cell))?;
+                }
+                writeln!(self.out, "</tr>")?;
+                for row in rows {
+                    write!(self.out, "<tr>")?;
+                    for cell in row {
+                        write!(self.out, "<td>{}</td>", escape_xml(cell))?;
+                    }
+                    writeln!(self.out, "</tr>")?;
+                }
+                writeln!(self.out, "</table>")
+            }
+            ReportFormat::Markdown => {
+                let line = |cells: Vec<String>| format!("| {} |", cells.join(" | "));
+                let escape = |cell: &str| cell.replace('|', "\\|").replace('*', "\\*");
+                writeln!(self.out, "{}", line(header.iter().map(|cell| escape(cell)).collect()))?;
+                writeln!(self.out, "{}", line(header.iter().enumerate().map(|(i, _)| if i == 0 { "---".to_string() } else { "---:".to_string() }).collect()))?;
+                for row in rows {
+                    writeln!(self.out, "{}", line(row.iter().map(|cell| escape(cell)).collect()))?;
+                }
+                writeln!(self.out)
+            }
+        }
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        match self.format {
+            ReportFormat::Html => writeln!(self.out, "</body></html>"),
+            ReportFormat::Markdown => Ok(()),
+        }
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() { 0.0 } else { values.iter().sum::<f64>() / values.len() as f64 }
+}
+
+/// The `q`-quantile of the sorted `values` (nearest rank)
+fn percentile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    sorted[((sorted.len() - 1) as f64 * q).round() as usize]
+}
+
+/// The two-sided p-value of a Mann-Whitney U test of whether `a` and `b` come from
+/// the same distribution, using the normal approximation with tie correction.
+/// `None` if either sample has fewer than two values.
+fn mann_whitney(a: &[f64], b: &[f64]) -> Option<f64> {
+    if a.len() < 2 || b.len() < 2 {
+        return None;
+    }
+    let mut values: Vec<(f64, bool)> = a.iter().map(|x| (*x, true)).chain(b.iter().map(|y| (*y, false))).collect();
+    values.sort_by(|x, y| x.0.partial_cmp(&y.0).expect("values are finite"));
+
+    // average ranks of ties
+    let (n_a, n_b, n) = (a.len() as f64, b.len() as f64, values.len() as f64);
+    let mut rank_sum_a = 0.0;
+    let mut ties = 0.0;
+    let mut i = 0;
+    while i < values.len() {
+        let mut j = i;
+        while j + 1 < values.len() && values[j + 1].0 == values[i].0 {
+            j += 1;
+        }
+        let rank = (i + j) as f64 / 2.0 + 1.0;
+        let t = (j - i + 1) as f64;
+        ties += t * t * t - t;
+        rank_sum_a += values[i ..= j].iter().filter(|(_, in_a)| *in_a).count() as f64 * rank;
+        i = j + 1;
+    }
+
+    let u = rank_sum_a - n_a * (n_a + 1.0) / 2.0;
+    let mean = n_a * n_b / 2.0;
+    let variance = n_a * n_b / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)));
+    if variance <= 0.0 {
+        return Some(1.0);
+    }
+    let z = ((u - mean).abs() - 0.5).max(0.0) / variance.sqrt();
+    Some((2.0 * (1.0 - normal_cdf(z))).min(1.0))
+}
+
+/// The standard normal distribution's CDF, cf. Abramowitz & Stegun 7.1.26
+fn normal_cdf(z: f64) -> f64 {
+    let x = z.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.327_591_1 * x);
+    let erf = 1.0 - (((((1.061_405_429 * t - 1.453_152_027) * t) + 1.421_413_741) * t - 0.284_496_736) * t + 0.254_829_592) * t * (-x * x).exp();
+    if z >= 0.0 { 0.5 * (1.0 + erf) } else { 0.5 * (1.0 - erf) }
+}
+
+/// Changes between `a` and `b`, ranked by the absolute change of time spent
+pub fn changes(a: &Totals, b: &Totals) -> Vec<Change> {
+    let total = |totals: &Totals, operator: bool| -> u64 {
+        totals.iter().filter(|(key, _)| is_operator(key) == operator).map(|(_, t)| t).sum()
+    };
+    let share = |t: u64, total: u64| if total == 0 { 0.0 } else { t as f64 / total as f64 };
+
+    let mut changes: Vec<_> = a.keys().chain(b.keys())
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .map(|key| {
+            let t_a = a.get(key).cloned().unwrap_or(0);
+            let t_b = b.get(key).cloned().unwrap_or(0);
+            Change {
+                key: *key,
+                a: t_a,
+                b: t_b,
+                share_a: share(t_a, total(a, is_operator(key))),
+                share_b: share(t_b, total(b, is_operator(key))),
+            }
+        })
+        .collect();
+    changes.sort_by_key(|change| std::cmp::Reverse(change.delta().abs()));
+    changes
+}
+
+fn is_operator(key: &BreakdownKey) -> bool {
+    match key {
+        BreakdownKey::Operator(_) => true,
+        _ => false,
+    }
+}
+
+/// The `top` changes as a JSON object
+fn changes_json(changes: &[Change], top: usize, operator_names: &BTreeMap<u64, String>) -> serde_json::Value {
+    let changes: Vec<_> = changes.iter().take(top)
+        .map(|change| {
+            let mut object = json!({
+                "a_ns": change.a,
+                "b_ns": change.b,
+                "delta_ns": change.delta() as i64,
+                "share_a": change.share_a,
+                "share_b": change.share_b,
+            });
+            match change.key {
+                BreakdownKey::Operator(o) => {
+                    object["operator"] = json!(o);
+                    object["name"] = json!(crate::labels::name(o, operator_names));
+                    object["source"] = json!(crate::sources::of(o, operator_names).map(|location| location.to_string()));
+                }
+                BreakdownKey::Activity(a) => object["activity"] = json!(format!("{:?}", a)),
+                BreakdownKey::Worker(w) => object["worker"] = json!(w),
+            }
+            object
+        })
+        .collect();
+    json!({ "changes": changes })
+}
+
+/// Prints the `top` changes as a table.
+fn print_changes(changes: &[Change], top: usize, operator_names: &BTreeMap<u64, String>) {
+    println!("{:<24}{:>14}{:>14}{:>14}{:>10}{:>10}", "operator / activity", "a (ms)", "b (ms)", "Δ (ms)", "a (%)", "b (%)");
+    for change in changes.iter().take(top) {
+        let key = match change.key {
+            BreakdownKey::Operator(o) => format!("operator {}", metrics::operator_label(o, operator_names)),
+            BreakdownKey::Activity(a) => format!("{:?}", a),
+            BreakdownKey::Worker(w) => format!("worker {}", w),
+        };
+        println!("{:<24}{:>14.3}{:>14.3}{:>+14.3}{:>10.1}{:>10.1}",
+                 key,
+                 change.a as f64 / 1_000_000.0,
+                 change.b as f64 / 1_000_000.0,
+                 change.delta() as f64 / 1_000_000.0,
+                 change.share_a * 100.0,
+                 change.share_b * 100.0);
+    }
+}
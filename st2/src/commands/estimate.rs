@@ -0,0 +1,330 @@
+//! Estimates what full monitoring of a source computation takes: ST2 analyzes a
+//! sample of its trace, the first epochs of `*.dump` files or a short live
+//! capture, measures its event rate, PAG size, and analysis time, and projects
+//! them to the memory, CPU, and ST2 workers needed to keep up with the
+//! computation.
+
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::replay::Recorder;
+use crate::store::completed_epochs;
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::time::{Duration, Instant};
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::filter::Filter;
+use st2_timely::replay_throttled::{ReplaySpeed, PROGRESS};
+
+use serde_json::json;
+
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+/// Share of their time ST2 workers should be busy, leaving headroom for bursts
+const TARGET_UTILIZATION: f64 = 0.7;
+
+/// What the analysis of a sample measured
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Measurement {
+    /// Sampled epochs
+    pub epochs: u64,
+    /// Events replayed
+    pub events: u64,
+    /// Size of the replayed events, as encoded in `*.dump` files
+    pub bytes: u64,
+    /// PAG edges of the sampled epochs
+    pub edges: u64,
+    /// PAG edges of the largest sampled epoch
+    pub max_edges: u64,
+    /// Earliest and latest time of the sampled epochs' edges, in the source
+    /// computation's time
+    pub span: Option<(Duration, Duration)>,
+    /// Wall-clock time of the analysis
+    pub analysis_time: Duration,
+    /// ST2 workers of the analysis
+    pub workers: usize,
+    /// Sources of the sample
+    pub sources: usize,
+}
+
+impl Measurement {
+    /// Adds the `edges` of a completed epoch
+    fn add(&mut self, edges: &[PagEdge]) {
+        self.epochs += 1;
+        self.edges += edges.len() as u64;
+        self.max_edges = self.max_edges.max(edges.len() as u64);
+        for edge in edges {
+            let (from, to) = (edge.source.timestamp, edge.destination.timestamp);
+            self.span = Some(match self.span {
+                Some((first, last)) => (first.min(from), last.max(to)),
+                None => (from, to),
+            });
+        }
+    }
+
+    /// Time of the source computation the sample covers
+    pub fn trace_time(&self) -> Duration {
+        self.span.map(|(first, last)| last - first).unwrap_or_default()
+    }
+
+    /// Projects the sample to full monitoring, with `in_flight` epochs pending
+    /// at once (i.e., the analysis lags behind the computation by that much).
+    pub fn project(&self, in_flight: u64) -> Estimate {
+        let secs = self.trace_time().as_secs_f64();
+        let rate = |n: u64| if secs > 0.0 { n as f64 / secs } else { 0.0 };
+
+        let memory_bytes = self.max_edges * in_flight * std::mem::size_of::<PagEdge>() as u64;
+        // every ST2 worker counts as busy throughout the analysis
+        let cores = if secs > 0.0 { self.analysis_time.as_secs_f64() * self.workers as f64 / secs } else { 0.0 };
+        let workers = ((cores / TARGET_UTILIZATION).ceil() as usize).max(1);
+
+        Estimate {
+            event_rate: rate(self.events),
+            byte_rate: rate(self.bytes),
+            edge_rate: rate(self.edges),
+            edges_per_epoch: if self.epochs > 0 { self.edges as f64 / self.epochs as f64 } else { 0.0 },
+            memory_bytes,
+            cores,
+            workers,
+        }
+    }
+}
+
+/// Requirements of full monitoring, projected from a `Measurement`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Estimate {
+    /// Events per second of the source computation's time
+    pub event_rate: f64,
+    /// Bytes of events per second
+    pub byte_rate: f64,
+    /// PAG edges per second
+    pub edge_rate: f64,
+    /// PAG edges per epoch, on average
+    pub edges_per_epoch: f64,
+    /// Memory of the PAG edges pending at once
+    pub memory_bytes: u64,
+    /// CPU cores the analysis needs to keep up
+    pub cores: f64,
+    /// Recommended number of ST2 workers
+    pub workers: usize,
+}
+
+/// The `estimate` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("estimate")
+        .about("estimate the memory, CPU, and ST2 workers full monitoring takes from a sample of the trace")
+        .arg(clap::Arg::with_name("sample_epochs")
+            .long("sample-epochs")
+            .value_name("EPOCHS")
+            .help("Number of epochs to sample; a live source is disconnected afterwards")
+            .default_value("20"))
+        .arg(clap::Arg::with_name("in_flight")
+            .long("in-flight")
+            .value_name("EPOCHS")
+            .help("Number of epochs pending at once to project memory for, i.e., how far the analysis may lag behind")
+            .default_value("4"))
+}
+
+/// The arguments of `estimate`
+pub struct Options {
+    /// The number of epochs sampled
+    pub sample_epochs: u64,
+    /// The number of epochs in flight to estimate for
+    pub in_flight: u64,
+}
+
+/// Parses the arguments of `estimate`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    let parse_epochs = |arg: &str| args.value_of(arg).expect("error parsing estimate args")
+        .parse::<u64>().ok().filter(|epochs| *epochs > 0)
+        .ok_or_else(|| STError::Config(format!("Invalid --{}: expected a positive number of epochs", arg.replace('_', "-"))));
+
+    Ok(Options {
+        sample_epochs: parse_epochs("sample_epochs")?,
+        in_flight: parse_epochs("in_flight")?,
+    })
+}
+
+/// Samples `sample_epochs` epochs of `replay_source`, measures them (cf.
+/// `Measurement`), and prints the projected requirements of full monitoring
+/// with `in_flight` epochs pending at once (cf. `Measurement::project`) as
+/// text or JSON.
+///
+/// A live source is captured to `*.dump` files in a temporary directory and
+/// disconnected once the sample completed; the capture is then analyzed as
+/// fast as possible, like files, so the analysis time doesn't depend on the
+/// pace of the source computation.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    filter: Filter,
+    sample_epochs: u64,
+    in_flight: u64,
+    output_format: OutputFormat) -> Result<(), STError> {
+
+    let (sources, capture) = match &replay_source {
+        ReplaySource::Tcp(sockets) => {
+            let sources = sockets.lock().expect("sockets poisoned").len();
+            let dir = std::env::temp_dir().join(format!("st2-estimate-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).map_err(|e| STError::io(format!("couldn't create {}", dir.display()), e))?;
+            (sources, Some(dir))
+        }
+        ReplaySource::Files(files) => (files.lock().expect("replay files poisoned").len(), None),
+    };
+    let paths: Vec<PathBuf> = match &capture {
+        Some(dir) => (0 .. sources).map(|source| dir.join(format!("{}.dump", source))).collect(),
+        None => Vec::new(),
+    };
+    let recording = ReplaySource::Files(Arc::new(Mutex::new(paths.iter().cloned().map(Some).collect())));
+
+    // stops replay once the sample completed, or once ST2 is shut down
+    let sampling = Arc::new(AtomicBool::new(true));
+    {
+        let (sampling, is_running) = (Arc::clone(&sampling), Arc::clone(&is_running));
+        std::thread::spawn(move || {
+            while sampling.load(Ordering::Acquire) {
+                if !is_running.load(Ordering::Acquire) {
+                    sampling.store(false, Ordering::Release);
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        });
+    }
+
+    let measurement = Arc::new(Mutex::new(Measurement {
+        workers: crate::local_peers(&timely_configuration),
+        sources,
+        ..Default::default()
+    }));
+    let measured = Arc::clone(&measurement);
+    let local_peers = crate::local_peers(&timely_configuration);
+    let live = capture.is_some();
+    let stop = Arc::clone(&sampling);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let local_index = index % local_peers;
+
+        let readers = crate::replay::make_readers(replay_source.clone(), local_index, local_peers).expect("couldn't create readers");
+        let readers = if live {
+            // capture the sample; a worker's captures are the files it analyzes
+            let readers: Vec<_> = readers.into_iter()
+                .enumerate()
+                .map(|(i, reader)| Recorder::create(reader, &paths[i * local_peers + local_index]).expect("couldn't capture source"))
+                .collect();
+            let stop = Arc::clone(&stop);
+            worker.dataflow(|scope| {
+                let pag = pag::create_pag(scope, readers, index, Some(Arc::clone(&stop)), 1, ReplaySpeed::Unbounded, filter.clone());
+                let mut epochs = 0;
+                completed_epochs(&pag, "EstimateCapture", move |_epoch, _edges, _ahead| {
+                    epochs += 1;
+                    if epochs >= sample_epochs {
+                        stop.store(false, Ordering::Release);
+                    }
+                });
+            });
+            while worker.step_or_park(None) {}
+
+            crate::replay::make_readers(recording.clone(), local_index, local_peers).expect("couldn't create readers")
+        } else {
+            readers
+        };
+
+        // replay counters are per process; other workers may have started on
+        // the capture already, by a few batches at most
+        let events = PROGRESS.snapshot();
+        let measured = Arc::clone(&measured);
+        let stop = Arc::clone(&stop);
+        worker.dataflow(|scope| {
+            // the capture is the sample, files are cut off after it
+            let is_running = if live { None } else { Some(Arc::clone(&stop)) };
+            let pag = pag::create_pag(scope, readers, index, is_running, 1, ReplaySpeed::Unbounded, filter.clone());
+            let measured = Arc::clone(&measured);
+            completed_epochs(&pag, "Estimate", move |_epoch, edges, _ahead| {
+                let mut measured = measured.lock().unwrap();
+                if measured.epochs < sample_epochs {
+                    measured.add(&edges);
+                }
+                if measured.epochs >= sample_epochs {
+                    stop.store(false, Ordering::Release);
+                }
+            });
+        });
+        let started = Instant::now();
+        while worker.step_or_park(None) {}
+
+        if index == 0 {
+            let progress = PROGRESS.snapshot();
+            let mut measured = measured.lock().unwrap();
+            measured.events = progress.events.saturating_sub(events.events);
+            measured.bytes = progress.bytes.saturating_sub(events.bytes);
+            measured.analysis_time = started.elapsed();
+        }
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+    sampling.store(false, Ordering::Release);
+
+    if let Some(dir) = capture {
+        std::fs::remove_dir_all(&dir).map_err(|e| STError::io(format!("couldn't remove {}", dir.display()), e))?;
+    }
+
+    let measurement = measurement.lock().unwrap();
+    if measurement.epochs == 0 || measurement.trace_time() == Duration::from_secs(0) {
+        return Err(STError::Analysis("the sample has no completed epochs to estimate from".to_string()));
+    }
+    let estimate = measurement.project(in_flight);
+    print(&measurement, &estimate, in_flight, output_format);
+    Ok(())
+}
+
+/// Prints `measurement` and `estimate` as text or JSON
+fn print(measurement: &Measurement, estimate: &Estimate, in_flight: u64, output_format: OutputFormat) {
+    use crate::progress::bytes;
+
+    let budget_mb = (estimate.memory_bytes as f64 / (1024.0 * 1024.0)).ceil().max(1.0) as u64;
+    let mut text = format!(
+        "Sampled {} epochs of {} sources: {:.2}s of the source computation, {} events ({}), {} PAG edges\n",
+        measurement.epochs, measurement.sources, measurement.trace_time().as_secs_f64(),
+        measurement.events, bytes(measurement.bytes as f64), measurement.edges);
+    text.push_str(&format!("  event rate:   {:.0} events/s ({}/s)\n", estimate.event_rate, bytes(estimate.byte_rate)));
+    text.push_str(&format!("  PAG size:     {:.0} edges/epoch on average, {} at most, {:.0} edges/s\n",
+                           estimate.edges_per_epoch, measurement.max_edges, estimate.edge_rate));
+    text.push_str(&format!("  analysis:     {:.2}s on {} ST2 workers\n", measurement.analysis_time.as_secs_f64(), measurement.workers));
+    text.push_str("Projected for full monitoring:\n");
+    text.push_str(&format!("  memory:       {} of pending PAG edges ({} epochs in flight), e.g. --memory-budget {}\n",
+                           bytes(estimate.memory_bytes as f64), in_flight, budget_mb));
+    text.push_str(&format!("  CPU:          {:.2} cores\n", estimate.cores));
+    text.push_str(&format!("  ST2 workers:  {} (-w {})", estimate.workers, estimate.workers));
+    if estimate.workers > measurement.sources {
+        text.push_str(", with --repartition, as there are fewer sources");
+    }
+
+    output_format.print(text, json!({
+        "sample": {
+            "epochs": measurement.epochs,
+            "sources": measurement.sources,
+            "trace_secs": measurement.trace_time().as_secs_f64(),
+            "events": measurement.events,
+            "bytes": measurement.bytes,
+            "edges": measurement.edges,
+            "max_edges_per_epoch": measurement.max_edges,
+            "analysis_secs": measurement.analysis_time.as_secs_f64(),
+            "workers": measurement.workers,
+        },
+        "projection": {
+            "events_per_sec": estimate.event_rate,
+            "bytes_per_sec": estimate.byte_rate,
+            "edges_per_sec": estimate.edge_rate,
+            "edges_per_epoch": estimate.edges_per_epoch,
+            "in_flight_epochs": in_flight,
+            "memory_bytes": estimate.memory_bytes,
+            "memory_budget_mb": budget_mb,
+            "cores": estimate.cores,
+            "workers": estimate.workers,
+        },
+    }));
+}
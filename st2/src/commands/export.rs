@@ -0,0 +1,1180 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::commands::metrics::{Metrics, MetricsSummary};
+use crate::commands::snapshot::critical_path;
+
+use timely::dataflow::operators::inspect::Inspect;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, atomic::AtomicBool};
+use std::time::Duration;
+
+use st2_logformat::ActivityType;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use st2_logformat::perfetto::{CounterUnit, Event, PerfettoWriter};
+
+use crate::STError;
+use crate::commands::parse_epochs;
+use crate::commands::query::parse_time;
+use crate::config::Args;
+
+
+/// Output formats of `export`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// A JSON array of objects, one per row
+    Json,
+    /// A Graphviz digraph, one edge per row
+    Dot,
+    /// Comma-separated values with a header line
+    Csv,
+    /// An Apache Parquet file (requires the `parquet` feature)
+    Parquet,
+    /// A GraphML graph, one edge per row
+    GraphMl,
+    /// A Chrome trace (cf. chrome://tracing) of PAG edges
+    Chrome,
+    /// A Perfetto trace (cf. https://ui.perfetto.dev) of PAG edges
+    Perfetto,
+    /// A GEXF graph (e.g. for Gephi), one edge per row
+    Gexf,
+    /// A self-contained HTML timeline of PAG edges
+    Html,
+    /// A Cypher script loading PAG edges into Neo4j
+    Cypher,
+}
+
+impl FromStr for Format {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, STError> {
+        match s {
+            "json" => Ok(Format::Json),
+            "dot" => Ok(Format::Dot),
+            "csv" => Ok(Format::Csv),
+            "parquet" => Ok(Format::Parquet),
+            "graphml" => Ok(Format::GraphMl),
+            "chrome" => Ok(Format::Chrome),
+            "perfetto" => Ok(Format::Perfetto),
+            "gexf" => Ok(Format::Gexf),
+            "html" => Ok(Format::Html),
+            "cypher" => Ok(Format::Cypher),
+            _ => Err(STError::Config(format!("Invalid --format: {} (expected json, dot, csv, parquet, graphml, gexf, chrome, perfetto, html, or cypher)", s))),
+        }
+    }
+}
+
+/// Type of an exported column
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// Unsigned integers
+    U64,
+    /// Text
+    Str,
+}
+
+/// An exported column: its name and type
+pub type Column = (&'static str, Kind);
+
+/// A value of an exported row
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Field {
+    /// An unsigned integer
+    U64(u64),
+    /// Text
+    Str(String),
+    /// An absent value
+    Null,
+}
+
+/// An exported row. In graph formats, it's an edge between the nodes `from` and `to`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Row {
+    /// Source node of the row
+    pub from: String,
+    /// Destination node of the row
+    pub to: String,
+    /// Values of the row, in the order of the sink's columns
+    pub fields: Vec<Field>,
+}
+
+/// Columns of exported PAG edges
+pub const EDGE_COLUMNS: &[Column] = &[
+    ("epoch", Kind::U64),
+    ("src_worker", Kind::U64),
+    ("src_timestamp", Kind::U64),
+    ("dst_worker", Kind::U64),
+    ("dst_timestamp", Kind::U64),
+    ("activity_type", Kind::Str),
+    ("operator_id", Kind::U64),
+    ("length", Kind::U64),
+];
+
+/// Columns of exported metrics summaries
+pub const METRICS_COLUMNS: &[Column] = &[
+    ("epoch", Kind::U64),
+    ("from_worker", Kind::U64),
+    ("to_worker", Kind::U64),
+    ("activity_type", Kind::Str),
+    ("activities", Kind::U64),
+    ("duration", Kind::U64),
+    ("records", Kind::U64),
+];
+
+/// Converts a PAG edge of `epoch` to a row of `EDGE_COLUMNS`.
+/// PAG nodes are identified by `<worker>@<timestamp>`.
+pub fn edge_row(epoch: u64, edge: &PagEdge) -> Row {
+    let src_t: u64 = edge.source.timestamp.as_nanos().try_into().unwrap();
+    let dst_t: u64 = edge.destination.timestamp.as_nanos().try_into().unwrap();
+    Row {
+        from: format!("{}@{}", edge.source.worker_id, src_t),
+        to: format!("{}@{}", edge.destination.worker_id, dst_t),
+        fields: vec![
+            Field::U64(epoch),
+            Field::U64(edge.source.worker_id),
+            Field::U64(src_t),
+            Field::U64(edge.destination.worker_id),
+            Field::U64(dst_t),
+            Field::Str(format!("{:?}", edge.edge_type)),
+            edge.operator_id.map_or(Field::Null, Field::U64),
+            edge.length.map_or(Field::Null, |l| Field::U64(l as u64)),
+        ],
+    }
+}
+
+/// Converts a metrics summary of `epoch` to a row of `METRICS_COLUMNS`.
+/// Workers are identified by `w<worker>`.
+pub fn metrics_row(epoch: u64, summary: &MetricsSummary) -> Row {
+    Row {
+        from: format!("w{}", summary.0),
+        to: format!("w{}", summary.1),
+        fields: vec![
+            Field::U64(epoch),
+            Field::U64(summary.0),
+            Field::U64(summary.1),
+            Field::Str(format!("{:?}", summary.2)),
+            Field::U64(summary.3),
+            Field::U64(summary.4),
+            Field::U64(summary.5),
+        ],
+    }
+}
+
+/// A destination for exported rows, implemented once per `Format`
+pub trait Sink {
+    /// Writes `row`
+    fn write(&mut self, row: &Row) -> std::io::Result<()>;
+    /// Completes the output. No rows may be written afterwards.
+    fn finish(&mut self) -> std::io::Result<()>;
+}
+
+/// Creates a sink writing rows of `columns` to `path` in `format`.
+pub fn create_sink(format: Format, path: &Path, columns: &'static [Column]) -> Result<Box<dyn Sink + Send>, STError> {
+    if format == Format::Parquet {
+        return parquet_sink(path, columns);
+    }
+    if (format == Format::Chrome || format == Format::Perfetto || format == Format::Html) && columns != EDGE_COLUMNS {
+        return Err(STError::Config("Invalid --format: chrome, perfetto, and html timelines can only be exported from --edges".to_string()));
+    }
+    if format == Format::Cypher && columns != EDGE_COLUMNS {
+        return Err(STError::Config("Invalid --format: cypher scripts can only be exported from --edges".to_string()));
+    }
+
+    let out = BufWriter::new(File::create(path)?);
+    Ok(match format {
+        Format::Json => Box::new(JsonSink { out, columns, rows: 0 }),
+        Format::Dot => Box::new(DotSink { out, columns, started: false }),
+        Format::Csv => Box::new(CsvSink { out, columns, started: false }),
+        Format::GraphMl => Box::new(GraphMlSink { out, columns, nodes: HashSet::new(), rows: 0 }),
+        Format::Chrome => Box::new(ChromeSink { out, columns, rows: Vec::new() }),
+        Format::Perfetto => Box::new(PerfettoSink { out, columns, rows: Vec::new() }),
+        Format::Gexf => Box::new(GexfSink { out, columns, rows: Vec::new() }),
+        Format::Html => Box::new(HtmlSink { out, columns, rows: Vec::new() }),
+        Format::Cypher => Box::new(CypherSink { out, columns, rows: Vec::new() }),
+        Format::Parquet => unreachable!(),
+    })
+}
+
+/// The `export` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("export")
+        .about("Export PAG edges and/or metrics summaries to file")
+        .arg(clap::Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .possible_values(&["json", "dot", "csv", "parquet", "graphml", "gexf", "chrome", "perfetto", "html", "cypher"])
+            .help("The output format")
+            .default_value("csv"))
+        .arg(clap::Arg::with_name("epochs")
+            .long("epochs")
+            .value_name("FROM..TO")
+            .help("Only export epochs FROM (inclusive) to TO (exclusive); either bound may be omitted")
+            .default_value(".."))
+        .arg(clap::Arg::with_name("edges")
+            .long("edges")
+            .value_name("PATH")
+            .help("The output path for PAG edges")
+            .required_unless("metrics")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("metrics")
+            .long("metrics")
+            .value_name("PATH")
+            .help("The output path for per-epoch metrics summaries")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("min_weight")
+            .long("min-weight")
+            .value_name("TIME")
+            .help("With --format dot, prune PAG edges shorter than this (e.g. 1ms); critical path edges are kept")
+            .default_value("0"))
+}
+
+/// The arguments of `export`
+pub struct Options {
+    /// The output format
+    pub format: Format,
+    /// The exported epochs
+    pub epochs: Range<u64>,
+    /// The file of PAG edges
+    pub edges_path: Option<PathBuf>,
+    /// The file of per-epoch metrics summaries
+    pub metrics_path: Option<PathBuf>,
+    /// The shortest PAG edge kept in DOT output
+    pub min_weight: Duration,
+}
+
+/// Parses the arguments of `export`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        format: args.value_of("format").expect("error parsing export format args").parse()?,
+        epochs: parse_epochs(args.value_of("epochs").expect("error parsing export epochs args"))?,
+        edges_path: args.value_of("edges").map(PathBuf::from),
+        metrics_path: args.value_of("metrics").map(PathBuf::from),
+        min_weight: parse_time(args.value_of("min_weight").expect("error parsing export min weight args"))
+            .map_err(|e| e.context("Invalid --min-weight"))?,
+    })
+}
+
+/// Exports the PAG edges and/or metrics summaries of `epochs` from `replay_source`
+/// to `edges_path` and `metrics_path`, respectively. PAG edges in the `Dot` format
+/// are written as styled per-epoch PAGs (cf. `write_dot`), without edges shorter
+/// than `min_weight` ns.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    format: Format,
+    epochs: Range<u64>,
+    edges_path: Option<&Path>,
+    metrics_path: Option<&Path>,
+    min_weight: u64) -> Result<(), STError> {
+
+    // styled PAGs need all edges of an epoch to find its critical path
+    let mut dot_out = match edges_path {
+        Some(path) if format == Format::Dot => Some(BufWriter::new(File::create(path)?)),
+        _ => None,
+    };
+    let dot_edges = Arc::new(Mutex::new(BTreeMap::new()));
+    let collected = Arc::clone(&dot_edges);
+    let styled = dot_out.is_some();
+
+    let edges_sink = match edges_path {
+        Some(path) if !styled => Some(Arc::new(Mutex::new(create_sink(format, path, EDGE_COLUMNS)?))),
+        _ => None,
+    };
+    let metrics_sink = match metrics_path {
+        Some(path) => Some(Arc::new(Mutex::new(create_sink(format, path, METRICS_COLUMNS)?))),
+        None => None,
+    };
+    let sinks: Vec<_> = edges_sink.iter().chain(metrics_sink.iter()).cloned().collect();
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
+
+            if styled {
+                let epochs = epochs.clone();
+                let collected = Arc::clone(&collected);
+                pag.inspect_time(move |t, (edge, _t, _diff)| {
+                    if epochs.contains(&t.first) {
+                        collected.lock().unwrap().entry(t.first).or_insert_with(Vec::new).push(edge.clone());
+                    }
+                });
+            }
+
+            if let Some(sink) = edges_sink.clone() {
+                let epochs = epochs.clone();
+                pag.inspect_time(move |t, (edge, _t, _diff)| {
+                    if epochs.contains(&t.first) {
+                        sink.lock().unwrap().write(&edge_row(t.first, edge)).expect("write failed");
+                    }
+                });
+            }
+
+            if let Some(sink) = metrics_sink.clone() {
+                let epochs = epochs.clone();
+                pag.metrics().inspect_time(move |t, x| {
+                    if epochs.contains(&(t.first - 1)) {
+                        sink.lock().unwrap().write(&metrics_row(t.first - 1, x)).expect("write failed");
+                    }
+                });
+            }
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    for sink in sinks {
+        sink.lock().unwrap().finish()?;
+    }
+    if let Some(out) = dot_out.as_mut() {
+        write_dot(out, &dot_edges.lock().unwrap(), min_weight)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the PAGs of `epochs` as a Graphviz digraph with a cluster per epoch.
+/// Edges are colored by activity type and as thick as their share of the epoch's
+/// longest edge; critical path edges (cf. `snapshot::critical_path`) are red and
+/// bold. To prune large graphs, edges shorter than `min_weight` ns are left out,
+/// unless they're on the critical path.
+pub fn write_dot<W: Write>(out: &mut W, epochs: &BTreeMap<u64, Vec<PagEdge>>, min_weight: u64) -> std::io::Result<()> {
+    writeln!(out, "digraph st2 {{")?;
+    writeln!(out, "  rankdir=LR;")?;
+    writeln!(out, "  node [shape=box, fontsize=8, height=0.2];")?;
+    for (epoch, edges) in epochs.iter() {
+        let critical: HashSet<PagEdge> = critical_path(edges).into_iter().collect();
+        let longest = edges.iter().map(|edge| edge.duration()).max().unwrap_or(0).max(1);
+
+        writeln!(out, "  subgraph cluster_epoch_{} {{", epoch)?;
+        writeln!(out, "    label=\"epoch {}\";", epoch)?;
+        for edge in edges.iter() {
+            let on_path = critical.contains(edge);
+            if !on_path && edge.duration() < min_weight {
+                continue;
+            }
+
+            let src_t: u64 = edge.source.timestamp.as_nanos().try_into().unwrap();
+            let dst_t: u64 = edge.destination.timestamp.as_nanos().try_into().unwrap();
+            let width = 1.0 + 4.0 * edge.duration() as f64 / longest as f64;
+            let (color, style) = if on_path {
+                ("red", "bold")
+            } else {
+                activity_style(edge.edge_type)
+            };
+            let operator = edge.operator_id.map(|id| format!(" of operator {}", id)).unwrap_or_default();
+            writeln!(out, "    \"{}@{}\" -> \"{}@{}\" [label=\"{:?}\\n{:.3}ms\", tooltip=\"{:?}{}\", color=\"{}\", style={}, penwidth={:.2}];",
+                     edge.source.worker_id, src_t, edge.destination.worker_id, dst_t,
+                     edge.edge_type, edge.duration() as f64 / 1_000_000.0, edge.edge_type, operator,
+                     color, style, if on_path { width + 2.0 } else { width })?;
+        }
+        writeln!(out, "  }}")?;
+    }
+    writeln!(out, "}}")?;
+    out.flush()
+}
+
+/// Color and line style of edges of `activity` in DOT PAGs
+fn activity_style(activity: ActivityType) -> (&'static str, &'static str) {
+    let color = activity_color(&format!("{:?}", activity));
+    match activity {
+        ActivityType::ControlMessage | ActivityType::DataMessage => (color, "dashed"),
+        ActivityType::Waiting => (color, "dotted"),
+        _ => (color, "solid"),
+    }
+}
+
+/// Color of the activity type named `activity` in graphs and timelines
+pub(crate) fn activity_color(activity: &str) -> &'static str {
+    match activity {
+        "Processing" => "#1f77b4",
+        "Spinning" => "#7f7f7f",
+        "Serialization" | "Deserialization" => "#ff7f0e",
+        "ControlMessage" => "#9467bd",
+        "DataMessage" => "#2ca02c",
+        "Waiting" => "#c7c7c7",
+        "Busy" => "#8c564b",
+        _ => "#000000",
+    }
+}
+
+/// Writes rows as a JSON array of objects
+struct JsonSink<W: Write> {
+    out: W,
+    columns: &'static [Column],
+    rows: usize,
+}
+
+impl<W: Write> Sink for JsonSink<W> {
+    fn write(&mut self, row: &Row) -> std::io::Result<()> {
+        let object: serde_json::Map<_, _> = self.columns.iter().zip(row.fields.iter())
+            .map(|((name, _), field)| (name.to_string(), match field {
+                Field::U64(x) => serde_json::Value::from(*x),
+                Field::Str(x) => serde_json::Value::from(x.as_str()),
+                Field::Null => serde_json::Value::Null,
+            }))
+            .collect();
+        write!(self.out, "{}\n  {}", if self.rows == 0 { "[" } else { "," }, serde_json::Value::Object(object))?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        writeln!(self.out, "{}]", if self.rows == 0 { "[" } else { "\n" })?;
+        self.out.flush()
+    }
+}
+
+/// `field` as a comma-separated value, quoted if necessary
+pub(crate) fn csv_field(field: &Field) -> String {
+    match field {
+        Field::U64(x) => x.to_string(),
+        Field::Str(x) if x.contains(|c| c == ',' || c == '"' || c == '\n') => format!("\"{}\"", x.replace('"', "\"\"")),
+        Field::Str(x) => x.clone(),
+        Field::Null => String::new(),
+    }
+}
+
+/// Writes rows as comma-separated values
+struct CsvSink<W: Write> {
+    out: W,
+    columns: &'static [Column],
+    started: bool,
+}
+
+impl<W: Write> CsvSink<W> {
+    fn header(&mut self) -> std::io::Result<()> {
+        if !self.started {
+            self.started = true;
+            let names: Vec<_> = self.columns.iter().map(|(name, _)| *name).collect();
+            writeln!(self.out, "{}", names.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Sink for CsvSink<W> {
+    fn write(&mut self, row: &Row) -> std::io::Result<()> {
+        self.header()?;
+        let values: Vec<_> = row.fields.iter().map(csv_field).collect();
+        writeln!(self.out, "{}", values.join(","))
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.header()?;
+        self.out.flush()
+    }
+}
+
+/// Writes rows as edges of a Graphviz digraph, labeled with their activity type
+struct DotSink<W: Write> {
+    out: W,
+    columns: &'static [Column],
+    started: bool,
+}
+
+impl<W: Write> DotSink<W> {
+    fn header(&mut self) -> std::io::Result<()> {
+        if !self.started {
+            self.started = true;
+            writeln!(self.out, "digraph st2 {{")?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Sink for DotSink<W> {
+    fn write(&mut self, row: &Row) -> std::io::Result<()> {
+        self.header()?;
+        let mut attributes = Vec::new();
+        for ((name, _), field) in self.columns.iter().zip(row.fields.iter()) {
+            match field {
+                Field::U64(x) => attributes.push(format!("{}={}", name, x)),
+                Field::Str(x) => {
+                    if *name == "activity_type" {
+                        attributes.push(format!("label={:?}", x));
+                    }
+                    attributes.push(format!("{}={:?}", name, x));
+                }
+                Field::Null => (),
+            }
+        }
+        writeln!(self.out, "  {:?} -> {:?} [{}];", row.from, row.to, attributes.join(", "))
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.header()?;
+        writeln!(self.out, "}}")?;
+        self.out.flush()
+    }
+}
+
+/// Writes rows as edges of a directed GraphML graph, with one data key per column
+struct GraphMlSink<W: Write> {
+    out: W,
+    columns: &'static [Column],
+    /// nodes declared so far
+    nodes: HashSet<String>,
+    rows: usize,
+}
+
+impl<W: Write> GraphMlSink<W> {
+    fn header(&mut self) -> std::io::Result<()> {
+        writeln!(self.out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(self.out, r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#)?;
+        for (name, kind) in self.columns {
+            let kind = match kind {
+                Kind::U64 => "long",
+                Kind::Str => "string",
+            };
+            writeln!(self.out, r#"  <key id="{0}" for="edge" attr.name="{0}" attr.type="{1}"/>"#, name, kind)?;
+        }
+        writeln!(self.out, r#"  <graph id="st2" edgedefault="directed">"#)
+    }
+
+    fn node(&mut self, id: &str) -> std::io::Result<()> {
+        if self.nodes.insert(id.to_string()) {
+            writeln!(self.out, r#"    <node id="{}"/>"#, escape_xml(id))?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Sink for GraphMlSink<W> {
+    fn write(&mut self, row: &Row) -> std::io::Result<()> {
+        if self.rows == 0 {
+            self.header()?;
+        }
+        self.rows += 1;
+
+        self.node(&row.from)?;
+        self.node(&row.to)?;
+        writeln!(self.out, r#"    <edge source="{}" target="{}">"#, escape_xml(&row.from), escape_xml(&row.to))?;
+        for ((name, _), field) in self.columns.iter().zip(row.fields.iter()) {
+            match field {
+                Field::U64(x) => writeln!(self.out, r#"      <data key="{}">{}</data>"#, name, x)?,
+                Field::Str(x) => writeln!(self.out, r#"      <data key="{}">{}</data>"#, name, escape_xml(x))?,
+                Field::Null => (),
+            }
+        }
+        writeln!(self.out, "    </edge>")
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        if self.rows == 0 {
+            self.header()?;
+        }
+        writeln!(self.out, "  </graph>")?;
+        writeln!(self.out, "</graphml>")?;
+        self.out.flush()
+    }
+}
+
+/// A row of `EDGE_COLUMNS`, for formats that interpret the exported edges
+pub(crate) struct EdgeRow {
+    epoch: u64,
+    src_worker: u64,
+    src_timestamp: u64,
+    dst_worker: u64,
+    dst_timestamp: u64,
+    activity_type: String,
+    operator_id: Option<u64>,
+    length: Option<u64>,
+}
+
+impl EdgeRow {
+    fn new(columns: &[Column], row: &Row) -> Self {
+        let field = |name: &str| {
+            let column = columns.iter().position(|(column, _)| *column == name).expect("edge column");
+            &row.fields[column]
+        };
+        let number = |name: &str| match field(name) {
+            Field::U64(x) => Some(*x),
+            _ => None,
+        };
+        EdgeRow {
+            epoch: number("epoch").unwrap_or(0),
+            src_worker: number("src_worker").unwrap_or(0),
+            src_timestamp: number("src_timestamp").unwrap_or(0),
+            dst_worker: number("dst_worker").unwrap_or(0),
+            dst_timestamp: number("dst_timestamp").unwrap_or(0),
+            activity_type: match field("activity_type") {
+                Field::Str(activity) => activity.clone(),
+                _ => String::new(),
+            },
+            operator_id: number("operator_id"),
+            length: number("length"),
+        }
+    }
+
+    /// The row of a PAG edge of `epoch`
+    pub(crate) fn from_edge(epoch: u64, edge: &PagEdge) -> Self {
+        EdgeRow::new(EDGE_COLUMNS, &edge_row(epoch, edge))
+    }
+
+    /// Whether the edge is an activity of a single worker rather than a message between workers
+    fn is_local(&self) -> bool {
+        self.src_worker == self.dst_worker
+    }
+}
+
+/// Buffers rows of `EDGE_COLUMNS` and writes them as a Chrome trace on `finish`:
+/// one track per worker, duration events for activities, and flow events for
+/// messages between workers. Timestamps are relative to the earliest edge.
+struct ChromeSink<W: Write> {
+    out: W,
+    columns: &'static [Column],
+    rows: Vec<Row>,
+}
+
+impl<W: Write> Sink for ChromeSink<W> {
+    fn write(&mut self, row: &Row) -> std::io::Result<()> {
+        self.rows.push(row.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        let edges: Vec<_> = self.rows.iter().map(|row| EdgeRow::new(self.columns, row)).collect();
+        let start = edges.iter().map(|edge| edge.src_timestamp).min().unwrap_or(0);
+        let micros = |ns: u64| (ns - start) as f64 / 1000.0;
+
+        let mut workers = std::collections::BTreeSet::new();
+        let mut events = Vec::new();
+        for (id, edge) in edges.iter().enumerate() {
+            let name = match edge.operator_id {
+                Some(operator) => format!("{} (operator {})", edge.activity_type, operator),
+                None => edge.activity_type.clone(),
+            };
+            let args = serde_json::json!({ "epoch": edge.epoch, "operator_id": edge.operator_id, "length": edge.length });
+            workers.insert(edge.src_worker);
+            workers.insert(edge.dst_worker);
+
+            if edge.is_local() {
+                events.push(serde_json::json!({
+                    "name": name, "cat": edge.activity_type, "ph": "X", "pid": 0, "tid": edge.src_worker,
+                    "ts": micros(edge.src_timestamp), "dur": micros(edge.dst_timestamp) - micros(edge.src_timestamp), "args": args,
+                }));
+            } else {
+                events.push(serde_json::json!({
+                    "name": name, "cat": edge.activity_type, "ph": "s", "id": id, "pid": 0, "tid": edge.src_worker,
+                    "ts": micros(edge.src_timestamp), "args": args,
+                }));
+                events.push(serde_json::json!({
+                    "name": name, "cat": edge.activity_type, "ph": "f", "bp": "e", "id": id, "pid": 0, "tid": edge.dst_worker,
+                    "ts": micros(edge.dst_timestamp),
+                }));
+            }
+        }
+        for worker in workers {
+            events.push(serde_json::json!({
+                "name": "thread_name", "ph": "M", "pid": 0, "tid": worker, "args": { "name": format!("worker {}", worker) },
+            }));
+        }
+
+        serde_json::to_writer(&mut self.out, &serde_json::json!({ "traceEvents": events, "displayTimeUnit": "ms" }))?;
+        writeln!(self.out)?;
+        self.out.flush()
+    }
+}
+
+/// Tracks of a Perfetto trace
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Track {
+    /// Activities of a worker that don't belong to an operator, and the worker's messages
+    Worker(u64),
+    /// Activities of an operator on a worker
+    Operator(u64, u64),
+    /// Per-epoch busy time of a worker
+    Busy(u64),
+    /// Per-epoch records processed by a worker
+    Records(u64),
+}
+
+/// Pid of the source computation in Perfetto traces
+const PROCESS_ID: u64 = 1;
+/// Track id of the source computation's process track
+const PROCESS_TRACK: u64 = 1;
+/// Track id of the per-epoch latency counter
+const LATENCY_TRACK: u64 = 2;
+
+/// Buffers rows of `EDGE_COLUMNS` and writes them as a Perfetto trace on `finish`:
+/// a track per worker with nested tracks per operator, slices for activities, flows
+/// for messages between workers, and counters of per-epoch latency and per-worker
+/// busy time and records.
+struct PerfettoSink<W: Write> {
+    out: W,
+    columns: &'static [Column],
+    rows: Vec<Row>,
+}
+
+impl<W: Write> PerfettoSink<W> {
+    /// The id of `track`, which is declared to `trace` on first use.
+    fn track<V: Write>(trace: &mut PerfettoWriter<V>, tracks: &mut HashMap<Track, u64>, track: Track) -> std::io::Result<u64> {
+        if let Some(uuid) = tracks.get(&track) {
+            return Ok(*uuid);
+        }
+        // nested tracks declare their parents first
+        let parent = match track {
+            Track::Worker(_) => PROCESS_TRACK,
+            Track::Operator(worker, _) | Track::Busy(worker) | Track::Records(worker) => Self::track(trace, tracks, Track::Worker(worker))?,
+        };
+        let uuid = LATENCY_TRACK + 1 + tracks.len() as u64;
+        match track {
+            Track::Worker(worker) => trace.thread_track(uuid, PROCESS_ID, worker, &format!("worker {}", worker))?,
+            Track::Operator(_, operator) => trace.track(uuid, parent, &format!("operator {}", operator))?,
+            Track::Busy(worker) => trace.counter_track(uuid, parent, &format!("worker {} busy", worker), CounterUnit::TimeNs)?,
+            Track::Records(worker) => trace.counter_track(uuid, parent, &format!("worker {} records", worker), CounterUnit::Count)?,
+        }
+        tracks.insert(track, uuid);
+        Ok(uuid)
+    }
+}
+
+impl<W: Write> Sink for PerfettoSink<W> {
+    fn write(&mut self, row: &Row) -> std::io::Result<()> {
+        self.rows.push(row.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        let mut edges: Vec<_> = self.rows.iter().map(|row| EdgeRow::new(self.columns, row)).collect();
+        edges.sort_by_key(|edge| (edge.src_timestamp, edge.dst_timestamp));
+
+        let mut trace = PerfettoWriter::new(&mut self.out);
+        trace.process_track(PROCESS_TRACK, PROCESS_ID, "source computation")?;
+        trace.counter_track(LATENCY_TRACK, PROCESS_TRACK, "epoch latency", CounterUnit::TimeNs)?;
+
+        let mut tracks = HashMap::new();
+        // epoch -> (first, last timestamp)
+        let mut epochs: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+        // (epoch, worker) -> (busy time, records)
+        let mut workers: BTreeMap<(u64, u64), (u64, u64)> = BTreeMap::new();
+        for (id, edge) in edges.iter().enumerate() {
+            let span = epochs.entry(edge.epoch).or_insert((edge.src_timestamp, edge.dst_timestamp));
+            *span = (std::cmp::min(span.0, edge.src_timestamp), std::cmp::max(span.1, edge.dst_timestamp));
+
+            let mut event = Event { name: &edge.activity_type, category: &edge.activity_type, ..Default::default() };
+            event.args.push(("epoch", edge.epoch));
+            if let Some(length) = edge.length {
+                event.args.push(("length", length));
+            }
+
+            if edge.is_local() {
+                let track = match edge.operator_id {
+                    Some(operator) => Track::Operator(edge.src_worker, operator),
+                    None => Track::Worker(edge.src_worker),
+                };
+                let track = Self::track(&mut trace, &mut tracks, track)?;
+                trace.slice(track, edge.src_timestamp, edge.dst_timestamp, &event)?;
+
+                let totals = workers.entry((edge.epoch, edge.src_worker)).or_insert((0, 0));
+                if edge.activity_type != "Waiting" && edge.activity_type != "Spinning" {
+                    totals.0 += edge.dst_timestamp - edge.src_timestamp;
+                }
+                totals.1 += edge.length.unwrap_or(0);
+            } else {
+                let flow = id as u64 + 1;
+                let track = Self::track(&mut trace, &mut tracks, Track::Worker(edge.src_worker))?;
+                trace.instant(track, edge.src_timestamp, &Event { flows: vec![flow], ..event.clone() })?;
+                let track = Self::track(&mut trace, &mut tracks, Track::Worker(edge.dst_worker))?;
+                trace.instant(track, edge.dst_timestamp, &Event { terminating_flows: vec![flow], ..event })?;
+            }
+        }
+
+        // per-epoch counters change at the epoch's start
+        for (epoch, (first, last)) in epochs.iter() {
+            trace.counter(LATENCY_TRACK, *first, (last - first) as i64)?;
+            for ((_, worker), (busy, records)) in workers.range((*epoch, 0) ..= (*epoch, u64::max_value())) {
+                let track = Self::track(&mut trace, &mut tracks, Track::Busy(*worker))?;
+                trace.counter(track, *first, *busy as i64)?;
+                let track = Self::track(&mut trace, &mut tracks, Track::Records(*worker))?;
+                trace.counter(track, *first, *records as i64)?;
+            }
+        }
+
+        trace.finish()?;
+        Ok(())
+    }
+}
+
+/// Buffers rows and writes them as edges of a directed GEXF 1.3 graph on `finish`,
+/// with one edge attribute per column. Edges are weighted by their duration.
+struct GexfSink<W: Write> {
+    out: W,
+    columns: &'static [Column],
+    rows: Vec<Row>,
+}
+
+impl<W: Write> GexfSink<W> {
+    /// The duration of `row`: its `duration` column, or the time between its timestamps
+    fn weight(&self, row: &Row) -> Option<u64> {
+        let field = |name: &str| self.columns.iter().position(|(column, _)| *column == name).map(|column| &row.fields[column]);
+        match (field("duration"), field("src_timestamp"), field("dst_timestamp")) {
+            (Some(Field::U64(duration)), _, _) => Some(*duration),
+            (_, Some(Field::U64(from)), Some(Field::U64(to))) => Some(to.saturating_sub(*from)),
+            _ => None,
+        }
+    }
+}
+
+impl<W: Write> Sink for GexfSink<W> {
+    fn write(&mut self, row: &Row) -> std::io::Result<()> {
+        self.rows.push(row.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        writeln!(self.out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(self.out, r#"<gexf xmlns="http://gexf.net/1.3" version="1.3">"#)?;
+        writeln!(self.out, r#"  <graph defaultedgetype="directed">"#)?;
+        writeln!(self.out, r#"    <attributes class="edge">"#)?;
+        for (id, (name, kind)) in self.columns.iter().enumerate() {
+            let kind = match kind {
+                Kind::U64 => "long",
+                Kind::Str => "string",
+            };
+            writeln!(self.out, r#"      <attribute id="{}" title="{}" type="{}"/>"#, id, name, kind)?;
+        }
+        writeln!(self.out, "    </attributes>")?;
+
+        let mut nodes = std::collections::BTreeSet::new();
+        for row in self.rows.iter() {
+            nodes.insert(row.from.as_str());
+            nodes.insert(row.to.as_str());
+        }
+        writeln!(self.out, "    <nodes>")?;
+        for node in nodes {
+            writeln!(self.out, r#"      <node id="{0}" label="{0}"/>"#, escape_xml(node))?;
+        }
+        writeln!(self.out, "    </nodes>")?;
+
+        writeln!(self.out, "    <edges>")?;
+        for (id, row) in self.rows.iter().enumerate() {
+            let weight = self.weight(row).map(|weight| format!(r#" weight="{}""#, weight)).unwrap_or_default();
+            writeln!(self.out, r#"      <edge id="{}" source="{}" target="{}"{}>"#, id, escape_xml(&row.from), escape_xml(&row.to), weight)?;
+            writeln!(self.out, "        <attvalues>")?;
+            for (column, field) in row.fields.iter().enumerate() {
+                match field {
+                    Field::U64(x) => writeln!(self.out, r#"          <attvalue for="{}" value="{}"/>"#, column, x)?,
+                    Field::Str(x) => writeln!(self.out, r#"          <attvalue for="{}" value="{}"/>"#, column, escape_xml(x))?,
+                    Field::Null => (),
+                }
+            }
+            writeln!(self.out, "        </attvalues>")?;
+            writeln!(self.out, "      </edge>")?;
+        }
+        writeln!(self.out, "    </edges>")?;
+        writeln!(self.out, "  </graph>")?;
+        writeln!(self.out, "</gexf>")?;
+        self.out.flush()
+    }
+}
+
+/// Number of activities or dependencies per `UNWIND` statement of Cypher scripts
+const CYPHER_BATCH: usize = 1000;
+
+/// Buffers rows of `EDGE_COLUMNS` and writes them as a Cypher script for Neo4j on
+/// `finish` (e.g. for `cypher-shell -f`): every PAG edge becomes an `:Activity`
+/// node with the edge's columns and duration as properties, and every PAG node
+/// becomes `:PRECEDES` relationships from the activities ending at it to the
+/// activities starting at it. Activities are merged on their `id` (source and
+/// destination node and activity type), so loading a script again doesn't
+/// duplicate them.
+struct CypherSink<W: Write> {
+    out: W,
+    columns: &'static [Column],
+    rows: Vec<Row>,
+}
+
+impl<W: Write> Sink for CypherSink<W> {
+    fn write(&mut self, row: &Row) -> std::io::Result<()> {
+        self.rows.push(row.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        let ids: Vec<String> = self.rows.iter()
+            .map(|row| format!("{}->{}:{}", row.from, row.to, EdgeRow::new(self.columns, row).activity_type))
+            .collect();
+
+        writeln!(self.out, "CREATE INDEX st2_activity_id IF NOT EXISTS FOR (a:Activity) ON (a.id);")?;
+        for (rows, ids) in self.rows.chunks(CYPHER_BATCH).zip(ids.chunks(CYPHER_BATCH)) {
+            writeln!(self.out, "UNWIND [")?;
+            for (i, (row, id)) in rows.iter().zip(ids.iter()).enumerate() {
+                let edge = EdgeRow::new(self.columns, row);
+                let mut properties = vec![
+                    format!("id: {}", cypher_string(id)),
+                    format!("epoch: {}", edge.epoch),
+                    format!("src_worker: {}", edge.src_worker),
+                    format!("src_timestamp: {}", edge.src_timestamp),
+                    format!("dst_worker: {}", edge.dst_worker),
+                    format!("dst_timestamp: {}", edge.dst_timestamp),
+                    format!("duration: {}", edge.dst_timestamp.saturating_sub(edge.src_timestamp)),
+                    format!("activity_type: {}", cypher_string(&edge.activity_type)),
+                ];
+                if let Some(operator) = edge.operator_id {
+                    properties.push(format!("operator_id: {}", operator));
+                }
+                if let Some(length) = edge.length {
+                    properties.push(format!("length: {}", length));
+                }
+                let separator = if i + 1 < rows.len() { "," } else { "" };
+                writeln!(self.out, "  {{{}}}{}", properties.join(", "), separator)?;
+            }
+            writeln!(self.out, "] AS activity")?;
+            writeln!(self.out, "MERGE (a:Activity {{id: activity.id}}) SET a += activity;")?;
+        }
+
+        // PAG node -> (activities ending at it, activities starting at it)
+        let mut nodes: BTreeMap<&str, (Vec<usize>, Vec<usize>)> = BTreeMap::new();
+        for (i, row) in self.rows.iter().enumerate() {
+            nodes.entry(row.to.as_str()).or_default().0.push(i);
+            nodes.entry(row.from.as_str()).or_default().1.push(i);
+        }
+        let dependencies: Vec<(usize, usize)> = nodes.values()
+            .flat_map(|(ending, starting)| ending.iter().flat_map(move |from| starting.iter().map(move |to| (*from, *to))))
+            .collect();
+        for batch in dependencies.chunks(CYPHER_BATCH) {
+            writeln!(self.out, "UNWIND [")?;
+            for (i, (from, to)) in batch.iter().enumerate() {
+                let separator = if i + 1 < batch.len() { "," } else { "" };
+                writeln!(self.out, "  [{}, {}]{}", cypher_string(&ids[*from]), cypher_string(&ids[*to]), separator)?;
+            }
+            writeln!(self.out, "] AS dependency")?;
+            writeln!(self.out, "MATCH (a:Activity {{id: dependency[0]}}), (b:Activity {{id: dependency[1]}})")?;
+            writeln!(self.out, "MERGE (a)-[:PRECEDES]->(b);")?;
+        }
+        self.out.flush()
+    }
+}
+
+/// `s` as a Cypher string literal
+fn cypher_string(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Width of the timeline of HTML exports, in px
+const TIMELINE_WIDTH: f64 = 1200.0;
+/// Height of a worker's swimlane in HTML exports, in px
+const LANE_HEIGHT: f64 = 28.0;
+/// Width of the worker labels left of the timeline, in px
+const LABEL_WIDTH: f64 = 80.0;
+
+/// Buffers rows of `EDGE_COLUMNS` and writes them as a self-contained HTML page on
+/// `finish`: an SVG timeline with one swimlane per worker, blocks for activities
+/// colored by type, and arrows for messages between workers. Hovering shows an
+/// edge's details; no scripts or external resources are used, so the page can be
+/// embedded in documents.
+struct HtmlSink<W: Write> {
+    out: W,
+    columns: &'static [Column],
+    rows: Vec<Row>,
+}
+
+impl<W: Write> Sink for HtmlSink<W> {
+    fn write(&mut self, row: &Row) -> std::io::Result<()> {
+        self.rows.push(row.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        let edges: Vec<_> = self.rows.iter().map(|row| EdgeRow::new(self.columns, row)).collect();
+        let start = edges.iter().map(|edge| edge.src_timestamp).min().unwrap_or(0);
+        let end = edges.iter().map(|edge| edge.dst_timestamp).max().unwrap_or(0);
+        let epochs = (edges.iter().map(|edge| edge.epoch).min(), edges.iter().map(|edge| edge.epoch).max());
+        let title = match epochs {
+            (Some(first), Some(last)) if first == last => format!("ST2 timeline of epoch {}", first),
+            (Some(first), Some(last)) => format!("ST2 timeline of epochs {} to {}", first, last),
+            _ => "ST2 timeline".to_string(),
+        };
+
+        writeln!(self.out, "<!DOCTYPE html>")?;
+        writeln!(self.out, r#"<html><head><meta charset="utf-8"><title>{}</title>"#, escape_xml(&title))?;
+        writeln!(self.out, "<style>body {{ font-family: sans-serif; }} {}</style>", TIMELINE_STYLE)?;
+        writeln!(self.out, "</head><body>")?;
+        writeln!(self.out, "<h3>{} ({:.3}ms)</h3>", escape_xml(&title), (end - start) as f64 / 1_000_000.0)?;
+        write_timeline(&mut self.out, &edges)?;
+        writeln!(self.out, "</body></html>")?;
+        self.out.flush()
+    }
+}
+
+/// Style of `write_timeline`'s legend and labels
+pub(crate) const TIMELINE_STYLE: &str = "text { font-size: 11px; } .legend span { display: inline-block; width: 12px; height: 12px; margin: 0 4px 0 12px; vertical-align: middle; }";
+
+/// Writes `edges` as a legend of activity types and an SVG timeline with one swimlane
+/// per worker, blocks for activities, and arrows for messages, for HTML pages styled
+/// with `TIMELINE_STYLE`.
+pub(crate) fn write_timeline<W: Write>(out: &mut W, edges: &[EdgeRow]) -> std::io::Result<()> {
+    let start = edges.iter().map(|edge| edge.src_timestamp).min().unwrap_or(0);
+    let end = edges.iter().map(|edge| edge.dst_timestamp).max().unwrap_or(0);
+    let span = std::cmp::max(end - start, 1) as f64;
+    let x = |ns: u64| LABEL_WIDTH + (ns - start) as f64 / span * TIMELINE_WIDTH;
+
+    let workers: std::collections::BTreeSet<u64> = edges.iter().flat_map(|edge| vec![edge.src_worker, edge.dst_worker]).collect();
+    let lanes: HashMap<u64, usize> = workers.iter().enumerate().map(|(lane, worker)| (*worker, lane)).collect();
+    let y = |worker: u64| 20.0 + lanes[&worker] as f64 * LANE_HEIGHT;
+    let height = 20.0 + workers.len() as f64 * LANE_HEIGHT + 30.0;
+
+    let mut activities: Vec<&str> = edges.iter().map(|edge| edge.activity_type.as_str()).collect();
+    activities.sort();
+    activities.dedup();
+    write!(out, r#"<div class="legend">"#)?;
+    for activity in activities {
+        write!(out, r#"<span style="background: {}"></span>{}"#, activity_color(activity), escape_xml(activity))?;
+    }
+    writeln!(out, "</div>")?;
+
+    writeln!(out, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}">"#, LABEL_WIDTH + TIMELINE_WIDTH + 20.0, height)?;
+    writeln!(out, r#"<defs><marker id="arrow" viewBox="0 0 10 10" refX="10" refY="5" markerWidth="6" markerHeight="6" orient="auto"><path d="M 0 0 L 10 5 L 0 10 z"/></marker></defs>"#)?;
+    for worker in workers.iter() {
+        writeln!(out, r#"<text x="4" y="{:.1}">worker {}</text>"#, y(*worker) + LANE_HEIGHT / 2.0 + 4.0, worker)?;
+        writeln!(out, r##"<line x1="{}" x2="{}" y1="{:.1}" y2="{:.1}" stroke="#eee"/>"##, LABEL_WIDTH, LABEL_WIDTH + TIMELINE_WIDTH, y(*worker) + LANE_HEIGHT, y(*worker) + LANE_HEIGHT)?;
+    }
+
+    // ticks every tenth of the timeline, relative to its start
+    let axis = 20.0 + workers.len() as f64 * LANE_HEIGHT;
+    for tick in 0 ..= 10 {
+        let tick_x = LABEL_WIDTH + tick as f64 * TIMELINE_WIDTH / 10.0;
+        writeln!(out, r##"<line x1="{0:.1}" x2="{0:.1}" y1="20" y2="{1:.1}" stroke="#ddd"/><text x="{0:.1}" y="{2:.1}" text-anchor="middle">{3:.3}ms</text>"##,
+                 tick_x, axis, axis + 16.0, span * tick as f64 / 10.0 / 1_000_000.0)?;
+    }
+
+    for edge in edges.iter().filter(|edge| edge.is_local()) {
+        let (from, to) = (x(edge.src_timestamp), x(edge.dst_timestamp));
+        writeln!(out, r#"<rect x="{:.2}" y="{:.1}" width="{:.2}" height="{:.1}" fill="{}"><title>{}</title></rect>"#,
+                 from, y(edge.src_worker) + 4.0, (to - from).max(0.5), LANE_HEIGHT - 8.0, activity_color(&edge.activity_type), escape_xml(&tooltip(edge)))?;
+    }
+    for edge in edges.iter().filter(|edge| !edge.is_local()) {
+        writeln!(out, r#"<line x1="{:.2}" y1="{:.1}" x2="{:.2}" y2="{:.1}" stroke="{}" marker-end="url(#arrow)"><title>{}</title></line>"#,
+                 x(edge.src_timestamp), y(edge.src_worker) + LANE_HEIGHT / 2.0, x(edge.dst_timestamp), y(edge.dst_worker) + LANE_HEIGHT / 2.0,
+                 activity_color(&edge.activity_type), escape_xml(&tooltip(edge)))?;
+    }
+    writeln!(out, "</svg>")
+}
+
+/// Details of `edge` for tooltips
+fn tooltip(edge: &EdgeRow) -> String {
+    let mut text = format!("{} in epoch {}: {:.3}ms", edge.activity_type, edge.epoch, (edge.dst_timestamp - edge.src_timestamp) as f64 / 1_000_000.0);
+    if let Some(operator) = edge.operator_id {
+        text.push_str(&format!(", operator {}", operator));
+    }
+    if !edge.is_local() {
+        text.push_str(&format!(", worker {} to {}", edge.src_worker, edge.dst_worker));
+    }
+    if let Some(length) = edge.length {
+        text.push_str(&format!(", {} records", length));
+    }
+    text
+}
+
+/// Escapes `s` for XML attributes and text
+pub(crate) fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(feature = "parquet")]
+fn parquet_sink(path: &Path, columns: &'static [Column]) -> Result<Box<dyn Sink + Send>, STError> {
+    Ok(Box::new(ParquetSink { file: Some(File::create(path)?), columns, rows: Vec::new() }))
+}
+
+#[cfg(not(feature = "parquet"))]
+fn parquet_sink(_path: &Path, _columns: &'static [Column]) -> Result<Box<dyn Sink + Send>, STError> {
+    Err(STError::Config("Invalid --format: parquet export requires st2 to be built with the `parquet` feature".to_string()))
+}
+
+/// Version of the schema of Parquet exports, stored as `st2.schema_version` in
+/// their key-value metadata. Bumped when columns change meaning or are removed.
+pub const PARQUET_SCHEMA_VERSION: u32 = 1;
+
+/// Maximum number of rows per row group of Parquet exports
+pub const PARQUET_ROW_GROUP_ROWS: usize = 1 << 16;
+
+/// Buffers rows and writes them as a Parquet file on `finish`, in row groups of up to
+/// `PARQUET_ROW_GROUP_ROWS` rows, so query engines can skip row groups by their
+/// statistics. The key-value metadata names the table (`st2.table`: `edges` or
+/// `metrics`) and `st2.schema_version`.
+#[cfg(feature = "parquet")]
+struct ParquetSink {
+    file: Option<File>,
+    columns: &'static [Column],
+    rows: Vec<Row>,
+}
+
+#[cfg(feature = "parquet")]
+impl Sink for ParquetSink {
+    fn write(&mut self, row: &Row) -> std::io::Result<()> {
+        self.rows.push(row.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        use std::rc::Rc;
+        use parquet_rs::column::writer::ColumnWriter;
+        use parquet_rs::data_type::ByteArray;
+        use parquet_rs::file::metadata::KeyValue;
+        use parquet_rs::file::properties::WriterProperties;
+        use parquet_rs::file::writer::{FileWriter, RowGroupWriter, SerializedFileWriter};
+        use parquet_rs::schema::parser::parse_message_type;
+
+        let to_io = |e: parquet_rs::errors::ParquetError| std::io::Error::new(std::io::ErrorKind::Other, e.to_string());
+
+        let fields: Vec<_> = self.columns.iter().map(|(name, kind)| match kind {
+            Kind::U64 => format!("OPTIONAL INT64 {} (UINT_64);", name),
+            Kind::Str => format!("OPTIONAL BYTE_ARRAY {} (UTF8);", name),
+        }).collect();
+        let schema = Rc::new(parse_message_type(&format!("message st2 {{ {} }}", fields.join(" "))).map_err(to_io)?);
+        let table = if self.columns == EDGE_COLUMNS { "edges" } else { "metrics" };
+        let metadata = vec![
+            KeyValue { key: "st2.table".to_string(), value: Some(table.to_string()) },
+            KeyValue { key: "st2.schema_version".to_string(), value: Some(PARQUET_SCHEMA_VERSION.to_string()) },
+        ];
+        let properties = Rc::new(WriterProperties::builder().set_key_value_metadata(Some(metadata)).build());
+        let file = self.file.take().expect("parquet sink already finished");
+        let mut writer = SerializedFileWriter::new(file, schema, properties).map_err(to_io)?;
+
+        for rows in self.rows.chunks(PARQUET_ROW_GROUP_ROWS) {
+            let mut row_group = writer.next_row_group().map_err(to_io)?;
+            let mut index = 0;
+            while let Some(mut column) = row_group.next_column().map_err(to_io)? {
+                let present: Vec<_> = rows.iter().map(|row| &row.fields[index]).filter(|f| **f != Field::Null).collect();
+                let levels: Vec<i16> = rows.iter().map(|row| if row.fields[index] == Field::Null { 0 } else { 1 }).collect();
+                match column {
+                    ColumnWriter::Int64ColumnWriter(ref mut typed) => {
+                        let values: Vec<i64> = present.iter().map(|f| match f { Field::U64(x) => *x as i64, _ => unreachable!() }).collect();
+                        typed.write_batch(&values, Some(&levels), None).map_err(to_io)?;
+                    }
+                    ColumnWriter::ByteArrayColumnWriter(ref mut typed) => {
+                        let values: Vec<ByteArray> = present.iter().map(|f| match f { Field::Str(x) => ByteArray::from(x.as_str()), _ => unreachable!() }).collect();
+                        typed.write_batch(&values, Some(&levels), None).map_err(to_io)?;
+                    }
+                    _ => unreachable!(),
+                }
+                row_group.close_column(column).map_err(to_io)?;
+                index += 1;
+            }
+            writer.close_row_group(row_group).map_err(to_io)?;
+        }
+        self.rows.clear();
+        writer.close().map_err(to_io)
+    }
+}
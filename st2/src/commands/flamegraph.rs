@@ -0,0 +1,237 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::commands::snapshot::critical_path;
+
+use timely::dataflow::Stream;
+use timely::dataflow::channels::pact::Exchange;
+use timely::dataflow::operators::generic::operator::Operator;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, atomic::AtomicBool};
+use std::time::Duration;
+
+use serde_json::json;
+
+use st2_logformat::pair::Pair;
+use st2_logformat::ActivityType;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use crate::{OutputFormat, STError};
+use crate::commands::parse_epochs;
+use crate::config::Args;
+
+/// The `flamegraph` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("flamegraph")
+        .about("Fold critical paths into collapsed stacks (scope;operator;activity) for flamegraphs")
+        .arg(clap::Arg::with_name("output_path")
+            .short("o")
+            .long("out")
+            .value_name("PATH")
+            .help("The output path for the collapsed stacks")
+            .default_value("critical-path.folded"))
+        .arg(clap::Arg::with_name("svg")
+            .long("svg")
+            .value_name("PATH")
+            .help("Also render an SVG flamegraph to PATH (requires the `flamegraph` feature)")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("epochs")
+            .long("epochs")
+            .value_name("FROM..TO")
+            .help("Only fold epochs FROM (inclusive) to TO (exclusive); either bound may be omitted")
+            .default_value(".."))
+        .arg(clap::Arg::with_name("window")
+            .long("window")
+            .value_name("EPOCHS")
+            .help("Give every window of EPOCHS epochs its own root frame")
+            .takes_value(true))
+}
+
+/// The arguments of `flamegraph`
+pub struct Options {
+    /// The file of collapsed stacks
+    pub output_path: PathBuf,
+    /// The SVG flamegraph
+    pub svg_path: Option<PathBuf>,
+    /// The folded epochs
+    pub epochs: Range<u64>,
+    /// The number of epochs per root frame
+    pub window: Option<u64>,
+}
+
+/// Parses the arguments of `flamegraph`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    let window: Option<u64> = match args.value_of("window") {
+        Some(window) => match window.parse() {
+            Ok(0) => Err(STError::Config("Invalid --window: has to be at least 1".to_string()))?,
+            Ok(window) => Some(window),
+            Err(e) => Err(STError::Config(format!("Invalid --window: {}", e)))?,
+        },
+        None => None,
+    };
+
+    Ok(Options {
+        output_path: PathBuf::from(args.value_of("output_path").expect("error parsing flamegraph output args")),
+        svg_path: args.value_of("svg").map(PathBuf::from),
+        epochs: parse_epochs(args.value_of("epochs").expect("error parsing flamegraph epochs args"))?,
+        window,
+    })
+}
+
+/// Folds the critical paths of `epochs` of `replay_source` into collapsed stacks
+/// (`scope;operator;activity <ns>`, one line per stack) written to `output_path`,
+/// the input format of flamegraph tools. With `window`, every window of that many
+/// epochs gets its own root frame. If `svg_path` is given, the stacks are also
+/// rendered as an SVG flamegraph (requires the `flamegraph` feature).
+///
+/// Dataflow scopes aren't part of traces, so scope frames are taken from operator
+/// names in `operator_names` that are paths, e.g. `Iterate/Join`. Operators with
+/// plain names are in the `dataflow` scope.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    epochs: Range<u64>,
+    window: Option<u64>,
+    operator_names: &BTreeMap<u64, String>,
+    output_path: &Path,
+    svg_path: Option<&Path>,
+    output_format: OutputFormat) -> Result<(), STError> {
+
+    // fail before the analysis rather than after it
+    if svg_path.is_some() && !cfg!(feature = "flamegraph") {
+        return Err(STError::Config("Invalid --svg: SVG flamegraphs require st2 to be built with the `flamegraph` feature".to_string()));
+    }
+    let mut out = BufWriter::new(File::create(output_path)?);
+    let svg = match svg_path {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+
+    let stacks = Arc::new(Mutex::new(BTreeMap::new()));
+    let folded = Arc::clone(&stacks);
+    let operator_names = operator_names.clone();
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let stacks = Arc::clone(&folded);
+        let operator_names = operator_names.clone();
+        let epochs = epochs.clone();
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
+
+            // collect every epoch's edges at a single worker to find its critical path
+            let mut vector = Vec::new();
+            let mut pending: BTreeMap<u64, Vec<PagEdge>> = BTreeMap::new();
+            pag.sink(Exchange::new(|(edge, _t, _diff): &(PagEdge, Pair<u64, Duration>, isize)| edge.source.epoch), "Flamegraph", move |input| {
+                input.for_each(|_cap, data| {
+                    data.swap(&mut vector);
+                    for (edge, _t, _diff) in vector.drain(..) {
+                        if epochs.contains(&edge.source.epoch) {
+                            pending.entry(edge.source.epoch).or_insert_with(Vec::new).push(edge);
+                        }
+                    }
+                });
+
+                // edges of epoch `e` are produced at `Pair(e, _)`
+                let frontier = input.frontier().frontier();
+                while let Some(epoch) = pending.keys().next().cloned() {
+                    if frontier.iter().any(|t| t.first <= epoch) {
+                        break;
+                    }
+                    let edges = pending.remove(&epoch).expect("pending epoch");
+                    let mut stacks = stacks.lock().unwrap();
+                    for edge in critical_path(&edges) {
+                        *stacks.entry(stack(&edge, window, &operator_names)).or_insert(0) += edge.duration();
+                    }
+                }
+            });
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    let stacks = std::mem::replace(&mut *stacks.lock().unwrap(), BTreeMap::new());
+    let lines: Vec<String> = stacks.iter().map(|(stack, ns)| format!("{} {}", stack, ns)).collect();
+    for line in lines.iter() {
+        writeln!(out, "{}", line)?;
+    }
+    out.flush()?;
+    if let Some(svg) = svg {
+        render_svg(&lines, svg)?;
+    }
+
+    let total: u64 = stacks.values().sum();
+    output_format.print(
+        format_args!("Folded {:.1}ms of critical paths into {} stacks in {}", total as f64 / 1_000_000.0, stacks.len(), output_path.display()),
+        json!({ "output": output_path, "svg": svg_path, "stacks": stacks.len(), "critical_path_ns": total }));
+    Ok(())
+}
+
+/// The collapsed stack of `edge`: its window (if any), scopes, operator, and activity
+pub(crate) fn stack(edge: &PagEdge, window: Option<u64>, operator_names: &BTreeMap<u64, String>) -> String {
+    let mut frames = Vec::new();
+    if let Some(window) = window {
+        let from = edge.source.epoch / window * window;
+        frames.push(format!("epochs {}..{}", from, from + window));
+    }
+    match edge.operator_id {
+        Some(id) => {
+            // operators of a team (cf. `labels`) are grouped under it
+            let root = crate::labels::of(id).and_then(|label| label.team).unwrap_or_else(|| "dataflow".to_string());
+            // operators with a source location (cf. `sources`) show it like in profilers
+            let location = crate::sources::of(id, operator_names).map(|location| format!(" ({})", location)).unwrap_or_default();
+            match crate::labels::name(id, operator_names) {
+                Some(name) => {
+                    let name = crate::sources::split(&name).0;
+                    if name.contains('/') {
+                        frames.extend(name.split('/').map(|frame| frame.to_string()));
+                        if let Some(operator) = frames.last_mut() {
+                            operator.push_str(&location);
+                        }
+                    } else {
+                        frames.extend(vec![root, format!("{}{}", name, location)]);
+                    }
+                }
+                None => frames.extend(vec![root, format!("operator {}{}", id, location)]),
+            }
+        }
+        None => match edge.edge_type {
+            ActivityType::ControlMessage | ActivityType::DataMessage => frames.extend(vec!["dataflow".to_string(), "(messages)".to_string()]),
+            _ => frames.extend(vec!["dataflow".to_string(), "(no operator)".to_string()]),
+        },
+    }
+    frames.push(format!("{:?}", edge.edge_type));
+
+    // `;` separates frames
+    frames.iter().map(|frame| frame.replace(';', ":")).collect::<Vec<_>>().join(";")
+}
+
+#[cfg(feature = "flamegraph")]
+fn render_svg<W: Write>(lines: &[String], out: W) -> Result<(), STError> {
+    let mut options = inferno::flamegraph::Options::default();
+    options.title = "ST2 critical path composition".to_string();
+    options.count_name = "ns".to_string();
+    inferno::flamegraph::from_lines(&mut options, lines.iter().map(|line| line.as_str()), out)
+        .map_err(|e| STError::Analysis(format!("couldn't render flamegraph: {}", e)))
+}
+
+#[cfg(not(feature = "flamegraph"))]
+fn render_svg<W: Write>(_lines: &[String], _out: W) -> Result<(), STError> {
+    unreachable!("--svg is rejected without the `flamegraph` feature")
+}
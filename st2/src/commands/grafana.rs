@@ -0,0 +1,292 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::auth::Auth;
+use crate::store::{completed_epochs, epoch_samples, Labels, MetricsStore};
+
+use timely::dataflow::Stream;
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use crate::STError;
+use crate::config::Args;
+
+/// The `grafana` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("grafana")
+        .about("Serve per-epoch metrics to Grafana as a JSON datasource")
+        .arg(clap::Arg::with_name("listen")
+            .short("l")
+            .long("listen")
+            .value_name("ADDR")
+            .default_value("127.0.0.1:3001")
+            .help("Address to serve the datasource API on"))
+        .arg(clap::Arg::with_name("retention")
+            .long("retention")
+            .value_name("EPOCHS")
+            .default_value("10000")
+            .help("Number of most recent epochs to keep metrics of"))
+        .arg(clap::Arg::with_name("auth")
+            .long("auth")
+            .value_name("PATH")
+            .help("TOML file of the tokens allowed to access endpoints, and which ones (cf. README)"))
+}
+
+/// The arguments of `grafana`
+pub struct Options {
+    /// The address to serve the datasource at
+    pub listen: String,
+    /// The number of most recent epochs to keep
+    pub retention: usize,
+    /// Who may access the datasource
+    pub auth: Option<Auth>,
+}
+
+/// Parses the arguments of `grafana`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        listen: args.value_of("listen").expect("error parsing grafana listen args").to_string(),
+        retention: args.value_of("retention").expect("error parsing grafana retention args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --retention: {}", e)))?,
+        auth: args.value_of("auth").map(|path| Auth::load(std::path::Path::new(path))).transpose()?,
+    })
+}
+
+/// Serves the metrics of the `retention` most recent epochs of `replay_source`
+/// (cf. `store::METRICS`) at `listen`, implementing the API of Grafana's JSON
+/// datasource plugins:
+///
+/// - `GET /`: health check
+/// - `POST /search`, `POST /metrics`: metric names
+/// - `POST /query`: series of targets, e.g. `operator_critical_path_ns` or
+///   `operator_critical_path_ns{operator="Map"}`, in the query's time range.
+///   Labels may also be given as the target's payload or as ad hoc filters.
+/// - `POST /tag-keys`, `POST /tag-values`: label keys and values, for ad hoc filters
+///
+/// If `auth` is given, requests have to authenticate and be authorized for their
+/// path (cf. `auth`). Once the analysis is complete, the metrics are served until
+/// ST2 is interrupted.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    listen: &str,
+    retention: usize,
+    auth: Option<Auth>,
+    operator_names: &BTreeMap<u64, String>) -> Result<(), STError> {
+
+    let server = tiny_http::Server::http(listen).map_err(|e| STError::Config(format!("Invalid --listen: {}", e)))?;
+    let store = Arc::new(Mutex::new(MetricsStore::new(retention)));
+    let served = Arc::clone(&store);
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            serve(request, &served, auth.as_ref());
+        }
+    });
+    eprintln!("Serving Grafana JSON datasource at http://{}", listen);
+
+    let operator_names = operator_names.clone();
+    let workers_running = Arc::clone(&is_running);
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let operator_names = operator_names.clone();
+        let store = Arc::clone(&store);
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&workers_running)), 1, speed, filter.clone());
+
+            completed_epochs(&pag, "MetricsStore", move |epoch, edges, ahead| {
+                let samples = epoch_samples(epoch, &edges, ahead, &operator_names);
+                store.lock().unwrap().insert(epoch, samples);
+            });
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    // keep serving the stored metrics unless we've been interrupted
+    while is_running.load(Ordering::Acquire) {
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    Ok(())
+}
+
+/// Responds to a request of Grafana's JSON datasource API
+fn serve(mut request: tiny_http::Request, store: &Mutex<MetricsStore>, auth: Option<&Auth>) {
+    let mut body = String::new();
+    let authorized = auth.map_or(Ok(()), |auth| auth.authorize_http(&request));
+    let response = match authorized.and_then(|_| Ok(request.as_reader().read_to_string(&mut body))) {
+        Err(denied) => Err((denied.status(), denied.to_string())),
+        Ok(Ok(_)) => {
+            let body: Value = serde_json::from_str(&body).unwrap_or(Value::Null);
+            let store = store.lock().unwrap();
+            match (request.method(), request.url()) {
+                (tiny_http::Method::Get, "/") => Ok(json!({ "status": "ok" })),
+                (tiny_http::Method::Post, "/search") => Ok(search(&body)),
+                (tiny_http::Method::Post, "/metrics") => Ok(Value::Array(crate::store::metrics().iter()
+                    .map(|(name, description)| json!({ "label": name, "value": name, "description": description }))
+                    .collect())),
+                (tiny_http::Method::Post, "/query") => query(&body, &store),
+                (tiny_http::Method::Post, "/tag-keys") => Ok(Value::Array(store.label_keys().into_iter()
+                    .map(|key| json!({ "type": "string", "text": key }))
+                    .collect())),
+                (tiny_http::Method::Post, "/tag-values") => Ok(Value::Array(store.label_values(body["key"].as_str().unwrap_or(""))
+                    .into_iter()
+                    .map(|value| json!({ "text": value }))
+                    .collect())),
+                (_, url) => Err((404, format!("not found: {}", url))),
+            }
+        }
+        Ok(Err(e)) => Err((400, format!("couldn't read request: {}", e))),
+    };
+
+    let (status, body) = match response {
+        Ok(body) => (200, body),
+        Err((status, message)) => (status, json!({ "error": message })),
+    };
+    let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).expect("valid header");
+    let mut response = tiny_http::Response::from_string(body.to_string()).with_status_code(status).with_header(header);
+    if status == 401 {
+        response.add_header(tiny_http::Header::from_bytes(&b"WWW-Authenticate"[..], &b"Bearer"[..]).expect("valid header"));
+    }
+    if let Err(e) = request.respond(response) {
+        error!("couldn't respond to Grafana: {}", e);
+    }
+}
+
+/// Metric names containing the requested `target`
+fn search(body: &Value) -> Value {
+    let target = body["target"].as_str().unwrap_or("");
+    Value::Array(crate::store::metrics().iter()
+        .filter(|(name, _)| name.contains(target))
+        .map(|(name, _)| json!(name))
+        .collect())
+}
+
+/// The series of all targets of the query `body`, as `[{target, datapoints: [[value, ms]]}]`
+fn query(body: &Value, store: &MetricsStore) -> Result<Value, (u16, String)> {
+    let time = |bound: &str| {
+        let value = &body["range"][bound];
+        value.as_str()
+            .map(|s| parse_rfc3339(s).ok_or_else(|| (400, format!("invalid range.{}: {}", bound, s))))
+            .unwrap_or_else(|| Err((400, format!("missing range.{}", bound))))
+    };
+    let (from, to) = (time("from")?, time("to")?);
+    let max_points = body["maxDataPoints"].as_u64().unwrap_or(std::u64::MAX).max(1) as usize;
+
+    // ad hoc filters apply to all targets
+    let mut filters = Labels::new();
+    for filter in body["adhocFilters"].as_array().into_iter().flatten() {
+        if let (Some(key), Some(value)) = (filter["key"].as_str(), filter["value"].as_str()) {
+            filters.insert(key.to_string(), value.to_string());
+        }
+    }
+
+    let mut results = Vec::new();
+    for target in body["targets"].as_array().into_iter().flatten() {
+        if target["hide"].as_bool() == Some(true) {
+            continue;
+        }
+        let (name, mut labels) = parse_target(target["target"].as_str().unwrap_or(""))
+            .map_err(|e| (400, e))?;
+        labels.extend(filters.clone());
+        if let Some(payload) = target["payload"].as_object() {
+            labels.extend(payload.iter().filter_map(|(key, value)| value.as_str().map(|value| (key.clone(), value.to_string()))));
+        }
+
+        for (labels, points) in store.query(&name, &labels, from, to) {
+            // thin out series with more points than the panel can show
+            let stride = if points.len() > max_points { (points.len() + max_points - 1) / max_points } else { 1 };
+            let datapoints: Vec<Value> = points.iter().step_by(stride)
+                .map(|(timestamp, value)| json!([value, timestamp / 1_000_000]))
+                .collect();
+            results.push(json!({ "target": series_name(&name, &labels), "datapoints": datapoints }));
+        }
+    }
+    Ok(Value::Array(results))
+}
+
+/// Splits a target like `name{key="value",...}` into its metric name and labels
+fn parse_target(target: &str) -> Result<(String, Labels), String> {
+    let target = target.trim();
+    let (name, selector) = match target.find('{') {
+        Some(i) if target.ends_with('}') => (&target[.. i], &target[i + 1 .. target.len() - 1]),
+        Some(_) => return Err(format!("invalid target: {} (expected e.g. name{{key=\"value\"}})", target)),
+        None => (target, ""),
+    };
+    let mut labels = Labels::new();
+    for label in selector.split(',').map(str::trim).filter(|label| !label.is_empty()) {
+        let mut parts = label.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => { labels.insert(key.trim().to_string(), value.trim().trim_matches('"').to_string()); }
+            _ => return Err(format!("invalid label: {} (expected key=\"value\")", label)),
+        }
+    }
+    Ok((name.trim().to_string(), labels))
+}
+
+/// `name{key="value",...}`, or just `name` without labels
+fn series_name(name: &str, labels: &Labels) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+    let labels: Vec<String> = labels.iter().map(|(key, value)| format!("{}=\"{}\"", key, value)).collect();
+    format!("{}{{{}}}", name, labels.join(","))
+}
+
+/// Parses an RFC 3339 time like `2020-01-31T06:33:44.866Z` into ns since the Unix epoch.
+fn parse_rfc3339(s: &str) -> Option<u64> {
+    let number = |range: std::ops::Range<usize>| s.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (number(0 .. 4)?, number(5 .. 7)?, number(8 .. 10)?);
+    let (hour, minute, second) = (number(11 .. 13)?, number(14 .. 16)?, number(17 .. 19)?);
+
+    // fractional seconds, then `Z` or a `+HH:MM` / `-HH:MM` offset
+    let rest = s.get(19 ..)?;
+    let fraction_len = if rest.starts_with('.') { rest[1 ..].find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len() - 1) } else { 0 };
+    let nanos = if fraction_len > 0 {
+        let digits = &rest[1 .. 1 + fraction_len.min(9)];
+        digits.parse::<i64>().ok()? * 10i64.pow(9 - digits.len() as u32)
+    } else {
+        0
+    };
+    let zone = &rest[if fraction_len > 0 { 1 + fraction_len } else { 0 } ..];
+    let offset = match zone {
+        "Z" | "z" => 0,
+        zone if zone.len() == 6 && (zone.starts_with('+') || zone.starts_with('-')) => {
+            let minutes = zone[1 .. 3].parse::<i64>().ok()? * 60 + zone[4 .. 6].parse::<i64>().ok()?;
+            if zone.starts_with('-') { -minutes * 60 } else { minutes * 60 }
+        }
+        _ => return None,
+    };
+
+    // days since 1970-01-01 of the (proleptic Gregorian) date, cf. H. Hinnant's `days_from_civil`
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = days * 86_400 + hour * 3_600 + minute * 60 + second - offset;
+    if seconds < 0 {
+        return None;
+    }
+    Some(seconds as u64 * 1_000_000_000 + nanos as u64)
+}
@@ -0,0 +1,352 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::commands::alerts::EpochStats;
+use crate::sources::SourceLocation;
+use crate::store::completed_epochs;
+
+use timely::dataflow::Stream;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, atomic::AtomicBool};
+use std::time::Duration;
+
+use serde_json::json;
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::operators::{Channel, Operator};
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use crate::{OutputFormat, STError};
+use crate::commands::parse_epochs;
+use crate::config::Args;
+
+/// Format of the logical dataflow graph
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Graphviz, with scopes as clusters and operators colored by their share
+    /// of the critical paths
+    Dot,
+    /// Operators and channels as JSON
+    Json,
+}
+
+impl std::str::FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(GraphFormat::Dot),
+            "json" => Ok(GraphFormat::Json),
+            _ => Err(format!("unknown graph format {} (expected dot or json)", s)),
+        }
+    }
+}
+
+/// The `graph` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("graph")
+        .about("Reconstruct the logical dataflow graph with every operator's time, critical path share, and throughput")
+        .arg(clap::Arg::with_name("output_path")
+            .short("o")
+            .long("out")
+            .value_name("PATH")
+            .help("The output path for the graph")
+            .default_value("dataflow.dot"))
+        .arg(clap::Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .possible_values(&["dot", "json"])
+            .help("Format of the graph: Graphviz DOT or JSON")
+            .default_value("dot"))
+        .arg(clap::Arg::with_name("epochs")
+            .long("epochs")
+            .value_name("FROM..TO")
+            .help("Only sum the metrics of epochs FROM (inclusive) to TO (exclusive); either bound may be omitted")
+            .default_value(".."))
+}
+
+/// The arguments of `graph`
+pub struct Options {
+    /// The file of the graph
+    pub output_path: PathBuf,
+    /// The format of the graph
+    pub format: GraphFormat,
+    /// The analyzed epochs
+    pub epochs: Range<u64>,
+}
+
+/// Parses the arguments of `graph`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        output_path: PathBuf::from(args.value_of("output_path").expect("error parsing graph output args")),
+        format: args.value_of("format").expect("error parsing graph format args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --format: {}", e)))?,
+        epochs: parse_epochs(args.value_of("epochs").expect("error parsing graph epochs args"))?,
+    })
+}
+
+/// Reconstructs the logical dataflow graph of `replay_source` from its
+/// `Operates` and `Channels` events and writes it to `output_path`, with the
+/// metrics of every operator during `epochs` overlaid: its total busy time, its
+/// time on and share of the critical paths, and the records it processed (also
+/// per second of busy time).
+///
+/// Scopes (e.g. iterations) contain the operators whose addresses they prefix;
+/// channels from or to a scope's own inputs and outputs attach to the scope.
+/// Only operators and channels replayed by this ST2 process are known.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    epochs: Range<u64>,
+    operator_names: &BTreeMap<u64, String>,
+    output_path: &Path,
+    format: GraphFormat,
+    output_format: OutputFormat) -> Result<(), STError> {
+
+    let mut out = BufWriter::new(File::create(output_path)?);
+
+    // operator id -> metrics
+    let metrics = Arc::new(Mutex::new(BTreeMap::new()));
+    let summed = Arc::clone(&metrics);
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let metrics = Arc::clone(&summed);
+        let epochs = epochs.clone();
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
+
+            completed_epochs(&pag, "Graph", move |epoch, edges, _ahead| {
+                if !epochs.contains(&epoch) {
+                    return;
+                }
+                let stats = EpochStats::new(&edges);
+                let mut metrics = metrics.lock().unwrap();
+                for edge in edges.iter().filter(|edge| edge.source.worker_id == edge.destination.worker_id) {
+                    if let Some(operator) = edge.operator_id {
+                        let operator_metrics: &mut OperatorMetrics = metrics.entry(operator).or_default();
+                        operator_metrics.busy_ns += edge.duration();
+                        operator_metrics.records += edge.length.unwrap_or(0) as u64;
+                    }
+                }
+                for edge in stats.critical_path.iter() {
+                    if let Some(operator) = edge.operator_id {
+                        metrics.entry(operator).or_default().cp_ns += edge.duration();
+                    }
+                }
+            });
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    let metrics = std::mem::replace(&mut *metrics.lock().unwrap(), BTreeMap::new());
+    let graph = Graph::new(st2_timely::operators::all(), st2_timely::operators::channels(), metrics, operator_names);
+    if graph.nodes.is_empty() {
+        return Err(STError::Analysis("no Operates events were replayed, so there's no dataflow graph".to_string()));
+    }
+
+    match format {
+        GraphFormat::Dot => graph.write_dot(&mut out)?,
+        GraphFormat::Json => serde_json::to_writer_pretty(&mut out, &graph.to_json())
+            .map_err(|e| STError::io(format!("couldn't write {}", output_path.display()), e.into()))?,
+    }
+    out.flush()?;
+
+    output_format.print(
+        format_args!("Wrote the dataflow graph of {} operators and {} channels to {}",
+                     graph.nodes.len(), graph.edges.len(), output_path.display()),
+        json!({
+            "output": output_path,
+            "operators": graph.nodes.len(),
+            "channels": graph.edges.len(),
+        }));
+    Ok(())
+}
+
+/// What an operator did during the analyzed epochs
+#[derive(Clone, Copy, Debug, Default)]
+struct OperatorMetrics {
+    /// Time of its activities on all workers, in ns
+    busy_ns: u64,
+    /// Time on the critical paths, in ns
+    cp_ns: u64,
+    /// Records it processed
+    records: u64,
+}
+
+/// An operator of the logical dataflow graph
+struct Node {
+    id: u64,
+    name: String,
+    source: Option<SourceLocation>,
+    addr: Vec<usize>,
+    /// Whether other operators are nested in it
+    is_scope: bool,
+    metrics: OperatorMetrics,
+}
+
+/// The logical dataflow graph: operators and the channels between them
+struct Graph {
+    /// Operators, ordered by address
+    nodes: Vec<Node>,
+    /// (channel, source operator id, target operator id)
+    edges: Vec<(Channel, u64, u64)>,
+    /// Total time of all critical paths, in ns
+    cp_ns: u64,
+}
+
+impl Graph {
+    fn new(operators: Vec<(usize, Operator)>, channels: Vec<Channel>, mut metrics: BTreeMap<u64, OperatorMetrics>, operator_names: &BTreeMap<u64, String>) -> Self {
+        let ids: HashMap<Vec<usize>, u64> = operators.iter().map(|(id, operator)| (operator.addr.clone(), *id as u64)).collect();
+        let mut nodes: Vec<Node> = operators.iter()
+            .map(|(id, operator)| Node {
+                id: *id as u64,
+                name: crate::sources::split(&crate::labels::name(*id as u64, operator_names).unwrap_or_else(|| operator.name.clone())).0.to_string(),
+                source: crate::sources::of(*id as u64, operator_names),
+                addr: operator.addr.clone(),
+                is_scope: operators.iter().any(|(_, other)| other.addr.len() > operator.addr.len() && other.addr.starts_with(&operator.addr)),
+                metrics: metrics.remove(&(*id as u64)).unwrap_or_default(),
+            })
+            .collect();
+        nodes.sort_by(|a, b| a.addr.cmp(&b.addr));
+
+        // index 0 of a scope is the scope itself
+        let endpoint = |scope_addr: &[usize], index: usize| {
+            let mut addr = scope_addr.to_vec();
+            if index > 0 {
+                addr.push(index);
+            }
+            ids.get(&addr).cloned()
+        };
+        let edges = channels.into_iter()
+            .filter_map(|channel| {
+                let source = endpoint(&channel.scope_addr, channel.source.0)?;
+                let target = endpoint(&channel.scope_addr, channel.target.0)?;
+                Some((channel, source, target))
+            })
+            .collect();
+
+        let cp_ns = nodes.iter().map(|node| node.metrics.cp_ns).sum();
+        Graph { nodes, edges, cp_ns }
+    }
+
+    fn cp_share(&self, node: &Node) -> f64 {
+        if self.cp_ns > 0 { node.metrics.cp_ns as f64 / self.cp_ns as f64 } else { 0.0 }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let throughput = |metrics: &OperatorMetrics| if metrics.busy_ns > 0 {
+            Some(metrics.records as f64 / (metrics.busy_ns as f64 / 1_000_000_000.0))
+        } else {
+            None
+        };
+        json!({
+            "operators": self.nodes.iter().map(|node| {
+                let label = crate::labels::of(node.id).unwrap_or_default();
+                json!({
+                    "id": node.id,
+                    "name": node.name,
+                    "address": node.addr,
+                    "source": node.source.as_ref().map(|location| location.to_string()),
+                    "source_url": node.source.as_ref().and_then(|location| location.url()),
+                    "scope": node.is_scope,
+                    "team": label.team,
+                    "tags": label.tags,
+                    "busy_ns": node.metrics.busy_ns,
+                    "critical_path_ns": node.metrics.cp_ns,
+                    "critical_path_share": self.cp_share(node),
+                    "records": node.metrics.records,
+                    "records_per_sec": throughput(&node.metrics),
+                })
+            }).collect::<Vec<_>>(),
+            "channels": self.edges.iter().map(|(channel, source, target)| json!({
+                "id": channel.id,
+                "source": source,
+                "source_port": channel.source.1,
+                "target": target,
+                "target_port": channel.target.1,
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    /// Writes the graph in Graphviz's DOT language: scopes are clusters, and
+    /// operators are shaded by their share of the critical paths.
+    fn write_dot<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        writeln!(out, "digraph dataflow {{")?;
+        writeln!(out, "  node [shape=box, style=filled, fontname=\"sans-serif\", fontsize=10];")?;
+        writeln!(out, "  edge [fontname=\"sans-serif\", fontsize=8];")?;
+        let roots: Vec<&Node> = self.nodes.iter()
+            .filter(|node| !self.nodes.iter().any(|parent| is_child(parent, node)))
+            .collect();
+        for node in roots {
+            self.write_dot_node(out, node, 1)?;
+        }
+        for (channel, source, target) in self.edges.iter() {
+            writeln!(out, "  n{} -> n{} [tooltip=\"channel {}\"];", source, target, channel.id)?;
+        }
+        writeln!(out, "}}")
+    }
+
+    fn write_dot_node<W: Write>(&self, out: &mut W, node: &Node, depth: usize) -> std::io::Result<()> {
+        let indent = "  ".repeat(depth);
+        let ms = |ns: u64| ns as f64 / 1_000_000.0;
+        let share = self.cp_share(node);
+        let mut label = format!("{} ({})", escape_dot(&node.name), node.id);
+        if let Some(location) = node.source.as_ref() {
+            label.push_str(&format!("\\n{}", escape_dot(&location.to_string())));
+        }
+        label.push_str(&format!("\\nbusy {:.3}ms\\ncritical path {:.1}%", ms(node.metrics.busy_ns), 100.0 * share));
+        if node.metrics.records > 0 && node.metrics.busy_ns > 0 {
+            label.push_str(&format!("\\n{:.0} records/s", node.metrics.records as f64 / (node.metrics.busy_ns as f64 / 1_000_000_000.0)));
+        }
+        // white to red with the share of the critical paths
+        let color = format!("0.000 {:.3} 1.000", share);
+        let tooltip = format!("address {:?}", node.addr);
+        // operators link to their code, cf. `--source-url`
+        let url = node.source.as_ref().and_then(|location| location.url()).map(|url| escape_dot(&url));
+
+        if node.is_scope {
+            writeln!(out, "{}subgraph cluster_{} {{", indent, node.id)?;
+            writeln!(out, "{}  label=\"{}\"; style=filled; fillcolor=\"{}\";", indent, label, color)?;
+            if let Some(url) = url {
+                writeln!(out, "{}  URL=\"{}\";", indent, url)?;
+            }
+            // the scope's own inputs and outputs
+            writeln!(out, "{}  n{} [shape=point, tooltip=\"{}\"];", indent, node.id, tooltip)?;
+            for child in self.nodes.iter().filter(|child| is_child(node, child)) {
+                self.write_dot_node(out, child, depth + 1)?;
+            }
+            writeln!(out, "{}}}", indent)
+        } else {
+            let url = url.map(|url| format!(", URL=\"{}\"", url)).unwrap_or_default();
+            writeln!(out, "{}n{} [label=\"{}\", fillcolor=\"{}\", tooltip=\"{}\"{}];", indent, node.id, label, color, tooltip, url)
+        }
+    }
+}
+
+/// Whether `child` is nested directly in `parent`
+fn is_child(parent: &Node, child: &Node) -> bool {
+    child.addr.len() == parent.addr.len() + 1 && child.addr.starts_with(&parent.addr)
+}
+
+/// Escapes `s` for a quoted DOT string
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,443 @@
+use crate::auth::Auth;
+use crate::STError;
+use crate::config::Args;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, atomic::AtomicBool};
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+/// TLS configuration of the gRPC server
+pub struct Tls {
+    /// PEM file of the server's certificate (chain)
+    pub certificate: PathBuf,
+    /// PEM file of the server's private key
+    pub key: PathBuf,
+    /// PEM file of the CA certificates client certificates are verified with. If
+    /// given, clients have to present a certificate (mTLS).
+    pub client_ca: Option<PathBuf>,
+}
+
+/// The `grpc` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("grpc")
+        .about("Serve analysis results over gRPC, cf. st2/proto/analysis.proto")
+        .arg(clap::Arg::with_name("listen")
+            .short("l")
+            .long("listen")
+            .value_name("ADDR")
+            .default_value("127.0.0.1:50051")
+            .help("Address to serve the gRPC service on"))
+        .arg(clap::Arg::with_name("retention")
+            .long("retention")
+            .value_name("EPOCHS")
+            .default_value("1000")
+            .help("Number of most recent epochs to keep metrics and PAGs of"))
+        .arg(clap::Arg::with_name("snapshot_dir")
+            .long("snapshot-dir")
+            .value_name("DIR")
+            .default_value("snapshots")
+            .help("Directory to write snapshots triggered by clients to"))
+        .arg(clap::Arg::with_name("auth")
+            .long("auth")
+            .value_name("PATH")
+            .help("TOML file of the tokens and client certificates allowed to access endpoints, and which ones (cf. README)"))
+        .arg(clap::Arg::with_name("tls_cert")
+            .long("tls-cert")
+            .value_name("PATH")
+            .requires("tls_key")
+            .help("PEM file of the server's TLS certificate; serves over TLS if given"))
+        .arg(clap::Arg::with_name("tls_key")
+            .long("tls-key")
+            .value_name("PATH")
+            .requires("tls_cert")
+            .help("PEM file of the server's TLS private key"))
+        .arg(clap::Arg::with_name("client_ca")
+            .long("client-ca")
+            .value_name("PATH")
+            .requires("tls_cert")
+            .help("PEM file of the CA certificates to verify client certificates with (mTLS)"))
+}
+
+/// The arguments of `grpc`
+pub struct Options {
+    /// The address to serve the gRPC service at
+    pub listen: String,
+    /// The number of most recent epochs to keep
+    pub retention: usize,
+    /// The directory of snapshots triggered by clients
+    pub snapshot_dir: PathBuf,
+    /// Who may access the service
+    pub auth: Option<Auth>,
+    /// The TLS configuration of the server
+    pub tls: Option<Tls>,
+}
+
+/// Parses the arguments of `grpc`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    let tls = match (args.value_of("tls_cert"), args.value_of("tls_key")) {
+        (Some(certificate), Some(key)) => Some(Tls {
+            certificate: certificate.into(),
+            key: key.into(),
+            client_ca: args.value_of("client_ca").map(|path| path.into()),
+        }),
+        (None, None) if args.value_of("client_ca").is_none() => None,
+        _ => return Err(STError::Config("--tls-cert and --tls-key have to be given together, and --client-ca requires them".to_string())),
+    };
+
+    Ok(Options {
+        listen: args.value_of("listen").expect("error parsing grpc listen args").to_string(),
+        retention: args.value_of("retention").expect("error parsing grpc retention args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --retention: {}", e)))?,
+        snapshot_dir: PathBuf::from(args.value_of("snapshot_dir").expect("error parsing grpc snapshot dir args")),
+        auth: args.value_of("auth").map(|path| Auth::load(Path::new(path))).transpose()?,
+        tls,
+    })
+}
+
+/// Serves the results of analyzing `replay_source` over gRPC at `listen`, with the
+/// service `Analysis` of `proto/analysis.proto`:
+///
+/// - `GetEpochSummaries`: the summaries of retained epochs
+/// - `StreamMetrics`: the samples (cf. `store::METRICS`) of every epoch completed
+///   from then on, filtered like the sinks of `publish`
+/// - `GetCriticalPath`: the critical path of a retained epoch
+/// - `TriggerSnapshot`: writes a snapshot (cf. `snapshot`) of a retained epoch to
+///   `snapshot_dir`, named like `epoch-42.json`; with a retention enabled (cf.
+///   `crate::retention`), old snapshots are deleted in the background
+///
+/// The PAG edges and samples of the `retention` most recent epochs are retained.
+/// Once the analysis is complete, they're served until ST2 is interrupted.
+///
+/// With `tls`, the server only accepts TLS connections. If `auth` is given, every
+/// call has to authenticate with a bearer token (metadata `authorization`) or a
+/// client certificate, and be authorized for its method (cf. `auth`).
+#[cfg(feature = "grpc")]
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    listen: &str,
+    retention: usize,
+    snapshot_dir: &Path,
+    auth: Option<Auth>,
+    tls: Option<Tls>,
+    operator_names: &BTreeMap<u64, String>) -> Result<(), STError> {
+
+    use crate::pag;
+    use crate::pag::PagEdge;
+    use crate::store::{completed_epochs, epoch_samples};
+
+    use timely::dataflow::Stream;
+
+    use std::sync::{Mutex, atomic::Ordering};
+    use std::time::Duration;
+
+    use st2_logformat::pair::Pair;
+
+    use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+    let address = listen.parse().map_err(|e| STError::Config(format!("Invalid --listen: {}", e)))?;
+    std::fs::create_dir_all(snapshot_dir)
+        .map_err(|e| STError::io(format!("couldn't create {}", snapshot_dir.display()), e))?;
+    let compacted = snapshot_dir.to_path_buf();
+    crate::retention::spawn(format!("snapshots in {}", snapshot_dir.display()), move |retention| {
+        retention.apply(crate::retention::files_in(&compacted, |name| name.starts_with("epoch-") && name.ends_with(".json"))?)
+    });
+
+    let mut builder = tonic::transport::Server::builder();
+    if let Some(tls) = tls {
+        let read = |path: &Path| std::fs::read(path).map_err(|e| STError::io(format!("couldn't read {}", path.display()), e));
+        let mut config = ServerTlsConfig::new()
+            .identity(Identity::from_pem(read(&tls.certificate)?, read(&tls.key)?));
+        if let Some(client_ca) = tls.client_ca.as_ref() {
+            config = config.client_ca_root(Certificate::from_pem(read(client_ca)?));
+        }
+        builder = builder.tls_config(config);
+    }
+
+    let state = Arc::new(Mutex::new(service::State::new(retention)));
+    let service = service::Service {
+        state: Arc::clone(&state),
+        operator_names: operator_names.clone(),
+        snapshot_dir: snapshot_dir.to_path_buf(),
+        auth,
+    };
+    let mut runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| STError::io("couldn't start the gRPC runtime", e))?;
+    std::thread::spawn(move || {
+        let server = builder
+            .add_service(service::proto::analysis_server::AnalysisServer::new(service))
+            .serve(address);
+        if let Err(e) = runtime.block_on(server) {
+            error!("gRPC server failed: {}", e);
+        }
+    });
+    eprintln!("Serving gRPC at {}", listen);
+
+    let operator_names = operator_names.clone();
+    let workers_running = Arc::clone(&is_running);
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let operator_names = operator_names.clone();
+        let state = Arc::clone(&state);
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&workers_running)), 1, speed, filter.clone());
+
+            completed_epochs(&pag, "Grpc", move |epoch, edges, ahead| {
+                let samples = epoch_samples(epoch, &edges, ahead, &operator_names);
+                state.lock().unwrap().insert(epoch, edges, samples);
+            });
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    // keep serving the retained epochs unless we've been interrupted
+    while is_running.load(Ordering::Acquire) {
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    Ok(())
+}
+
+/// Serves the results of analyzing `replay_source` over gRPC (requires the `grpc` feature).
+#[cfg(not(feature = "grpc"))]
+pub fn run(
+    _timely_configuration: timely::Configuration,
+    _replay_source: ReplaySource,
+    _is_running: Arc<AtomicBool>,
+    _speed: ReplaySpeed,
+    _filter: Filter,
+    _listen: &str,
+    _retention: usize,
+    _snapshot_dir: &Path,
+    _auth: Option<Auth>,
+    _tls: Option<Tls>,
+    _operator_names: &BTreeMap<u64, String>) -> Result<(), STError> {
+    Err(STError::Config("The gRPC server requires building with `--features grpc`".to_string()))
+}
+
+#[cfg(feature = "grpc")]
+mod service {
+    use crate::pag::PagEdge;
+    use crate::auth::{bearer, Auth, Credential, Denied};
+    use crate::commands::alerts::EpochStats;
+    use crate::commands::publish::{Level, SampleFilter};
+    use crate::commands::snapshot::Snapshot;
+    use crate::store::Sample;
+
+    use std::collections::{BTreeMap, VecDeque};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use tokio::sync::mpsc;
+    use tokio::sync::mpsc::error::TrySendError;
+    use tonic::{Request, Response, Status};
+
+    /// Code generated from `proto/analysis.proto`
+    #[allow(missing_docs)]
+    pub mod proto {
+        tonic::include_proto!("st2.analysis");
+    }
+
+    /// Number of samples buffered per `StreamMetrics` client; samples are dropped
+    /// for clients that fall further behind
+    const STREAM_BUFFER: usize = 10_000;
+
+    /// A retained epoch
+    struct Epoch {
+        epoch: u64,
+        edges: Vec<PagEdge>,
+        samples: Vec<Sample>,
+    }
+
+    /// The retained epochs and the clients streaming metrics
+    pub struct State {
+        retention: usize,
+        /// Oldest first
+        epochs: VecDeque<Epoch>,
+        subscribers: Vec<(SampleFilter, mpsc::Sender<Result<proto::Sample, Status>>)>,
+    }
+
+    impl State {
+        pub fn new(retention: usize) -> Self {
+            State { retention, epochs: VecDeque::new(), subscribers: Vec::new() }
+        }
+
+        /// Retains the completed `epoch`, dropping the oldest epoch if necessary, and
+        /// sends its `samples` to the clients streaming metrics.
+        pub fn insert(&mut self, epoch: u64, edges: Vec<PagEdge>, samples: Vec<Sample>) {
+            let mut subscribers = Vec::with_capacity(self.subscribers.len());
+            for (filter, mut sender) in self.subscribers.drain(..) {
+                if !filter.accepts_epoch(epoch) || send(&mut sender, &filter, &samples) {
+                    subscribers.push((filter, sender));
+                }
+            }
+            self.subscribers = subscribers;
+
+            self.epochs.push_back(Epoch { epoch, edges, samples });
+            while self.epochs.len() > self.retention {
+                self.epochs.pop_front();
+            }
+        }
+
+        fn edges(&self, epoch: u64) -> Result<&[PagEdge], Status> {
+            self.epochs.iter().find(|e| e.epoch == epoch).map(|e| &e.edges[..])
+                .ok_or_else(|| Status::not_found(format!("epoch {} isn't retained", epoch)))
+        }
+    }
+
+    /// Sends the `samples` accepted by `filter`. Returns whether the client is still connected.
+    fn send(sender: &mut mpsc::Sender<Result<proto::Sample, Status>>, filter: &SampleFilter, samples: &[Sample]) -> bool {
+        // streams are never aggregated to scopes, which need operator names
+        for sample in filter.apply(samples, &BTreeMap::new()) {
+            let sample = proto::Sample {
+                name: sample.name.to_string(),
+                labels: sample.labels.into_iter().collect(),
+                epoch: sample.epoch,
+                timestamp_ns: sample.timestamp,
+                value: sample.value,
+            };
+            match sender.try_send(Ok(sample)) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => {
+                    warn!("dropping metrics of a slow gRPC client");
+                    return true;
+                }
+                Err(TrySendError::Closed(_)) => return false,
+            }
+        }
+        true
+    }
+
+    pub struct Service {
+        pub state: Arc<Mutex<State>>,
+        pub operator_names: BTreeMap<u64, String>,
+        pub snapshot_dir: PathBuf,
+        pub auth: Option<Auth>,
+    }
+
+    impl Service {
+        /// Whether the caller of `request` may call `method`
+        fn authorize<T>(&self, request: &Request<T>, method: &str) -> Result<(), Status> {
+            let auth = match self.auth.as_ref() {
+                Some(auth) => auth,
+                None => return Ok(()),
+            };
+            let token = request.metadata().get("authorization")
+                .and_then(|value| value.to_str().ok())
+                .and_then(bearer);
+            let certificates = request.peer_certs();
+            let mut credentials: Vec<Credential> = token.into_iter().map(Credential::Token).collect();
+            if let Some(certificates) = certificates.as_ref() {
+                credentials.extend(certificates.iter().map(|certificate| Credential::Certificate(certificate.get_ref())));
+            }
+            auth.authorize(&credentials, method).map_err(|denied| match denied {
+                Denied::Unauthenticated => Status::unauthenticated(denied.to_string()),
+                Denied::Forbidden => Status::permission_denied(denied.to_string()),
+            })
+        }
+
+        fn edge(&self, edge: &PagEdge) -> proto::Edge {
+            proto::Edge {
+                worker: edge.source.worker_id,
+                dst_worker: edge.destination.worker_id,
+                start_ns: edge.source.timestamp.as_nanos() as u64,
+                end_ns: edge.destination.timestamp.as_nanos() as u64,
+                activity: format!("{:?}", edge.edge_type),
+                traverse: format!("{:?}", edge.traverse),
+                operator_id: edge.operator_id,
+                operator_name: edge.operator_id.and_then(|id| crate::labels::name(id, &self.operator_names)).unwrap_or_default(),
+                records: edge.length.map(|l| l as u64),
+            }
+        }
+    }
+
+    fn summary(epoch: &Epoch) -> proto::EpochSummary {
+        let value = |name| epoch.samples.iter()
+            .find(|sample| sample.name == name && sample.labels.is_empty())
+            .map(|sample| sample.value)
+            .unwrap_or(0.0);
+        proto::EpochSummary {
+            epoch: epoch.epoch,
+            timestamp_ns: epoch.samples.first().map(|sample| sample.timestamp).unwrap_or(0),
+            latency_ns: value("epoch_latency_ns") as u64,
+            critical_path_ns: value("critical_path_ns") as u64,
+            skew: value("skew"),
+            backlog_epochs: value("backlog_epochs") as u64,
+            edges: epoch.edges.len() as u64,
+        }
+    }
+
+    #[tonic::async_trait]
+    impl proto::analysis_server::Analysis for Service {
+        async fn get_epoch_summaries(&self, request: Request<proto::EpochRange>) -> Result<Response<proto::EpochSummaries>, Status> {
+            self.authorize(&request, "GetEpochSummaries")?;
+            let range = request.into_inner();
+            let to = if range.to == 0 { std::u64::MAX } else { range.to };
+            let state = self.state.lock().unwrap();
+            let epochs = state.epochs.iter()
+                .filter(|epoch| epoch.epoch >= range.from && epoch.epoch < to)
+                .map(summary)
+                .collect();
+            Ok(Response::new(proto::EpochSummaries { epochs }))
+        }
+
+        type StreamMetricsStream = mpsc::Receiver<Result<proto::Sample, Status>>;
+
+        async fn stream_metrics(&self, request: Request<proto::MetricsRequest>) -> Result<Response<Self::StreamMetricsStream>, Status> {
+            self.authorize(&request, "StreamMetrics")?;
+            let request = request.into_inner();
+            let level = if request.summary { Level::Summary } else { Level::Operator };
+            let filter = SampleFilter { metrics: request.metrics, level, every: request.every.max(1) };
+            let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+            self.state.lock().unwrap().subscribers.push((filter, sender));
+            Ok(Response::new(receiver))
+        }
+
+        async fn get_critical_path(&self, request: Request<proto::EpochRequest>) -> Result<Response<proto::CriticalPath>, Status> {
+            self.authorize(&request, "GetCriticalPath")?;
+            let epoch = request.into_inner().epoch;
+            let state = self.state.lock().unwrap();
+            let stats = EpochStats::new(state.edges(epoch)?);
+            Ok(Response::new(proto::CriticalPath {
+                epoch,
+                latency_ns: stats.latency,
+                edges: stats.critical_path.iter().map(|edge| self.edge(edge)).collect(),
+            }))
+        }
+
+        async fn trigger_snapshot(&self, request: Request<proto::EpochRequest>) -> Result<Response<proto::SnapshotReply>, Status> {
+            self.authorize(&request, "TriggerSnapshot")?;
+            let epoch = request.into_inner().epoch;
+            let mut edges = self.state.lock().unwrap().edges(epoch)?.to_vec();
+            edges.sort_by_key(|edge| (edge.source.timestamp, edge.source.worker_id, edge.destination.timestamp));
+            let stats = EpochStats::new(&edges);
+            let snapshot = Snapshot { epoch, latency: stats.latency, edges, critical_path: stats.critical_path };
+
+            let path = self.snapshot_dir.join(format!("epoch-{}.json", epoch));
+            let file = std::fs::File::create(&path)
+                .map_err(|e| Status::internal(format!("couldn't create {}: {}", path.display(), e)))?;
+            serde_json::to_writer_pretty(std::io::BufWriter::new(file), &snapshot)
+                .map_err(|e| Status::internal(format!("couldn't write snapshot: {}", e)))?;
+            info!("wrote snapshot of epoch {} to {}", epoch, path.display());
+
+            Ok(Response::new(proto::SnapshotReply {
+                path: path.display().to_string(),
+                edges: snapshot.edges.len() as u64,
+                critical_path_edges: snapshot.critical_path.len() as u64,
+            }))
+        }
+    }
+}
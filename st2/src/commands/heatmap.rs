@@ -0,0 +1,249 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::commands::export::escape_xml;
+
+use timely::dataflow::Stream;
+use timely::dataflow::operators::inspect::Inspect;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, atomic::AtomicBool};
+use std::time::Duration;
+
+use serde_json::json;
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use crate::{OutputFormat, STError};
+use crate::commands::parse_epochs;
+use crate::config::Args;
+
+/// Width of a worker's column in the SVG heatmap, in px
+const CELL_WIDTH: u64 = 48;
+/// Height of an operator's row in the SVG heatmap, in px
+const CELL_HEIGHT: u64 = 22;
+/// Width of the operator labels left of the heatmap, in px
+const LABEL_WIDTH: u64 = 220;
+
+/// The `heatmap` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("heatmap")
+        .about("Sum the time spent per worker and operator into a heatmap, e.g. to spot skewed partitioning")
+        .arg(clap::Arg::with_name("output_path")
+            .short("o")
+            .long("out")
+            .value_name("PATH")
+            .help("The output path for the heatmap as CSV")
+            .default_value("heatmap.csv"))
+        .arg(clap::Arg::with_name("svg")
+            .long("svg")
+            .value_name("PATH")
+            .help("Also render the heatmap as SVG to PATH")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("epochs")
+            .long("epochs")
+            .value_name("FROM..TO")
+            .help("Only sum epochs FROM (inclusive) to TO (exclusive); either bound may be omitted")
+            .default_value(".."))
+}
+
+/// The arguments of `heatmap`
+pub struct Options {
+    /// The CSV file of the heatmap
+    pub output_path: PathBuf,
+    /// The SVG heatmap
+    pub svg_path: Option<PathBuf>,
+    /// The summed epochs
+    pub epochs: Range<u64>,
+}
+
+/// Parses the arguments of `heatmap`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        output_path: PathBuf::from(args.value_of("output_path").expect("error parsing heatmap output args")),
+        svg_path: args.value_of("svg").map(PathBuf::from),
+        epochs: parse_epochs(args.value_of("epochs").expect("error parsing heatmap epochs args"))?,
+    })
+}
+
+/// Sums the time each worker spent in each operator during `epochs` of
+/// `replay_source` and writes the resulting heatmap as CSV to `output_path`:
+/// one row per operator (sorted by total time), one column per worker, in ns.
+/// If `svg_path` is given, the heatmap is also rendered as SVG.
+///
+/// The row of an operator whose partitioning is skewed stands out, as some of
+/// its workers are much busier than others. The operator with the largest
+/// skew (busiest worker / mean) is reported.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    epochs: Range<u64>,
+    operator_names: &BTreeMap<u64, String>,
+    output_path: &Path,
+    svg_path: Option<&Path>,
+    output_format: OutputFormat) -> Result<(), STError> {
+
+    let mut out = BufWriter::new(File::create(output_path)?);
+    let svg = match svg_path {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+
+    // (operator, worker) -> ns
+    let cells = Arc::new(Mutex::new(BTreeMap::new()));
+    let summed = Arc::clone(&cells);
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let cells = Arc::clone(&summed);
+        let epochs = epochs.clone();
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
+
+            pag.inspect(move |(edge, _t, _diff)| {
+                if !epochs.contains(&edge.source.epoch) || edge.source.worker_id != edge.destination.worker_id {
+                    return;
+                }
+                if let Some(operator) = edge.operator_id {
+                    *cells.lock().unwrap().entry((operator, edge.source.worker_id)).or_insert(0) += edge.duration();
+                }
+            });
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    let cells = std::mem::replace(&mut *cells.lock().unwrap(), BTreeMap::new());
+    let heatmap = Heatmap::new(cells, operator_names);
+
+    heatmap.write_csv(&mut out)?;
+    out.flush()?;
+    if let Some(mut svg) = svg {
+        heatmap.write_svg(&mut svg)?;
+        svg.flush()?;
+    }
+
+    let skewed = heatmap.most_skewed();
+    output_format.print(
+        format_args!("Wrote time of {} operators on {} workers to {}{}",
+                     heatmap.operators.len(), heatmap.workers.len(), output_path.display(),
+                     match skewed {
+                         Some((row, skew)) => format!("; most skewed: {} ({:.2}x the mean on its busiest worker)", row.name, skew),
+                         None => String::new(),
+                     }),
+        json!({
+            "output": output_path,
+            "svg": svg_path,
+            "operators": heatmap.operators.len(),
+            "workers": heatmap.workers.len(),
+            "most_skewed": skewed.map(|(row, skew)| json!({ "operator_id": row.id, "operator": row.name, "skew": skew })),
+        }));
+    Ok(())
+}
+
+/// An operator's row of the heatmap
+pub(crate) struct OperatorRow {
+    pub(crate) id: u64,
+    pub(crate) name: String,
+    /// ns per worker, in the order of `Heatmap::workers`
+    pub(crate) cells: Vec<u64>,
+}
+
+impl OperatorRow {
+    fn total(&self) -> u64 {
+        self.cells.iter().sum()
+    }
+
+    /// Busiest worker / mean
+    fn skew(&self) -> f64 {
+        let mean = self.total() as f64 / self.cells.len() as f64;
+        let max = self.cells.iter().cloned().max().unwrap_or(0) as f64;
+        if mean > 0.0 { max / mean } else { 1.0 }
+    }
+}
+
+/// Time of operators (rows) on workers (columns)
+pub(crate) struct Heatmap {
+    pub(crate) workers: Vec<u64>,
+    /// Sorted by total time, descending
+    pub(crate) operators: Vec<OperatorRow>,
+}
+
+impl Heatmap {
+    /// The heatmap of `cells`, i.e., `(operator, worker)` → ns
+    pub(crate) fn new(cells: BTreeMap<(u64, u64), u64>, operator_names: &BTreeMap<u64, String>) -> Self {
+        let workers: Vec<u64> = cells.keys().map(|(_, worker)| *worker).collect::<BTreeSet<_>>().into_iter().collect();
+        let mut operators: Vec<OperatorRow> = cells.keys().map(|(operator, _)| *operator).collect::<BTreeSet<_>>().into_iter()
+            .map(|id| OperatorRow {
+                id,
+                name: crate::labels::name(id, operator_names).unwrap_or_else(|| format!("operator {}", id)),
+                cells: workers.iter().map(|worker| cells.get(&(id, *worker)).cloned().unwrap_or(0)).collect(),
+            })
+            .collect();
+        operators.sort_by_key(|row| std::cmp::Reverse(row.total()));
+        Heatmap { workers, operators }
+    }
+
+    pub(crate) fn most_skewed(&self) -> Option<(&OperatorRow, f64)> {
+        self.operators.iter()
+            .map(|row| (row, row.skew()))
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).expect("skew is finite"))
+    }
+
+    /// `operator_id,operator,w0,w1,...`
+    fn write_csv<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        write!(out, "operator_id,operator")?;
+        for worker in self.workers.iter() {
+            write!(out, ",w{}", worker)?;
+        }
+        writeln!(out)?;
+        for row in self.operators.iter() {
+            write!(out, "{},\"{}\"", row.id, row.name.replace('"', "\"\""))?;
+            for ns in row.cells.iter() {
+                write!(out, ",{}", ns)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    /// A grid of cells shaded from white (idle) to red (the busiest cell)
+    pub(crate) fn write_svg<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        let max = self.operators.iter().flat_map(|row| row.cells.iter()).cloned().max().unwrap_or(0).max(1);
+        let width = LABEL_WIDTH + self.workers.len() as u64 * CELL_WIDTH + 10;
+        let height = 30 + self.operators.len() as u64 * CELL_HEIGHT + 10;
+
+        writeln!(out, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="sans-serif" font-size="11">"#, width, height)?;
+        for (column, worker) in self.workers.iter().enumerate() {
+            writeln!(out, r#"<text x="{}" y="20" text-anchor="middle">w{}</text>"#, LABEL_WIDTH + column as u64 * CELL_WIDTH + CELL_WIDTH / 2, worker)?;
+        }
+        for (line, row) in self.operators.iter().enumerate() {
+            let y = 30 + line as u64 * CELL_HEIGHT;
+            writeln!(out, r#"<text x="{}" y="{}" text-anchor="end">{}</text>"#, LABEL_WIDTH - 6, y + CELL_HEIGHT / 2 + 4, escape_xml(&row.name))?;
+            for ((column, worker), ns) in self.workers.iter().enumerate().zip(row.cells.iter()) {
+                let heat = *ns as f64 / max as f64;
+                let shade = (255.0 * (1.0 - heat)).round() as u8;
+                writeln!(out, r##"<rect x="{}" y="{}" width="{}" height="{}" fill="#ff{:02x}{:02x}" stroke="#fff"><title>{} on w{}: {:.3}ms</title></rect>"##,
+                         LABEL_WIDTH + column as u64 * CELL_WIDTH, y, CELL_WIDTH, CELL_HEIGHT, shade, shade,
+                         escape_xml(&row.name), worker, *ns as f64 / 1_000_000.0)?;
+            }
+        }
+        writeln!(out, "</svg>")
+    }
+}
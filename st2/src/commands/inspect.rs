@@ -10,25 +10,66 @@ use timely::dataflow::Stream;
 use timely::Data;
 
 use std::time::Duration;
+use std::sync::{Arc, atomic::AtomicBool};
 use std::time::Instant;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::io::Read;
+use std::path::PathBuf;
 
 use st2_logformat::pair::Pair;
+use st2_logformat::{ActivityType, EventType};
+use st2_logformat::block::Damage;
+use st2_logformat::tagged::{self, Value};
+use st2_logformat::trace::{TraceHeader, TraceReader, Truncation};
 
-use tdiag_connect::receive as connect;
 use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
 
-use crate::STError;
+use serde_json::json;
+
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+/// The `inspect` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("inspect")
+        .about("run ST2 inspector, or summarize a trace file")
+        .arg(clap::Arg::with_name("trace")
+            .value_name("TRACE")
+            .help("Trace file to summarize (worker, epoch & event counts, operators, anomalies) without constructing a PAG"))
+}
+
+/// The arguments of `inspect`
+pub struct Options {
+    /// The trace file to summarize instead of inspecting ST2 itself
+    pub trace: Option<PathBuf>,
+}
+
+/// Parses the arguments of `inspect`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        trace: args.value_of("trace").map(PathBuf::from),
+    })
+}
 
 /// Inspects a running SnailTrail computation, e.g. for benchmarking of SnailTrail itself.
 pub fn run(
     timely_configuration: timely::Configuration,
-    replay_source: ReplaySource) -> Result<(), STError> {
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter) -> Result<(), STError> {
+
+    let local_peers = crate::local_peers(&timely_configuration);
 
     timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
         let index = worker.index();
 
         // read replayers from file (offline) or TCP stream (online)
-        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
 
         let probe: ProbeHandle<Pair<u64, Duration>> = worker.dataflow(|scope| {
             // use timely::dataflow::operators::inspect::Inspect;
@@ -40,7 +81,7 @@ pub fn run(
             //     .inspect(|x| println!("{:?}", x))
             //     .probe()
 
-            pag::create_pag(scope, readers, index, 1)
+            pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone())
                 // .bench(index)
                 .probe()
         });
@@ -53,11 +94,178 @@ pub fn run(
         // use std::io::stdin;
         // stdin().read_line(&mut String::new()).unwrap();
     })
-        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
 
     Ok(())
 }
 
+/// A summary of a trace file, gathered in a single pass over its records
+/// (cf. `summarize`)
+pub struct TraceSummary {
+    /// The trace's header
+    pub header: TraceHeader,
+    /// Number of records
+    pub records: u64,
+    /// Distinct workers that logged records
+    pub workers: BTreeSet<u64>,
+    /// Distinct epochs of the records
+    pub epochs: BTreeSet<u64>,
+    /// Timestamps of the earliest and latest record
+    pub span: Option<(Duration, Duration)>,
+    /// Record counts per activity type and event type
+    pub activities: BTreeMap<(ActivityType, EventType), u64>,
+    /// Operators by id: name (if known) and record count
+    pub operators: BTreeMap<u64, (Option<String>, u64)>,
+    /// Per worker: number of gaps in its `seq_no`s and number of records missing in them
+    pub gaps: BTreeMap<u64, (u64, u64)>,
+    /// Damaged data skipped while reading
+    pub damage: Damage,
+    /// Where the trace was cut off, if it was
+    pub truncation: Option<Truncation>,
+}
+
+/// Summarizes the trace in `reader`, without constructing a PAG.
+pub fn summarize<R: Read + Send + 'static>(reader: R) -> Result<TraceSummary, STError> {
+    let mut reader = TraceReader::new(reader).map_err(|e| STError::decode(0, e))?;
+    let mut summary = TraceSummary {
+        header: reader.header().clone(),
+        records: 0,
+        workers: BTreeSet::new(),
+        epochs: BTreeSet::new(),
+        span: None,
+        activities: BTreeMap::new(),
+        operators: BTreeMap::new(),
+        gaps: BTreeMap::new(),
+        damage: Damage::default(),
+        truncation: None,
+    };
+
+    // worker -> last seq_no
+    let mut last_seq_no = HashMap::new();
+    while let Some(batch) = reader.next_batch().map_err(|e| STError::decode(reader.offset(), e))? {
+        for record in batch {
+            summary.records += 1;
+            summary.workers.insert(record.local_worker);
+            summary.epochs.insert(record.epoch);
+            summary.span = Some(match summary.span {
+                Some((first, last)) => (std::cmp::min(first, record.timestamp), std::cmp::max(last, record.timestamp)),
+                None => (record.timestamp, record.timestamp),
+            });
+            *summary.activities.entry((record.activity_type, record.event_type)).or_insert(0) += 1;
+
+            if let Some(operator_id) = record.operator_id {
+                let operator = summary.operators.entry(operator_id).or_insert((None, 0));
+                operator.1 += 1;
+                if let Some(Value::U64(name)) = tagged::get(&record.tagged, tagged::OPERATOR_NAME) {
+                    operator.0 = reader.names().resolve(*name as u32).map(|name| name.to_string());
+                }
+            }
+
+            // seq_nos restart at 0 when the source computation is restarted
+            if let Some(last) = last_seq_no.insert(record.local_worker, record.seq_no) {
+                if record.seq_no > last + 1 {
+                    let gaps = summary.gaps.entry(record.local_worker).or_insert((0, 0));
+                    *gaps = (gaps.0 + 1, gaps.1 + record.seq_no - last - 1);
+                }
+            }
+        }
+    }
+    summary.damage = reader.damage();
+    summary.truncation = reader.truncation();
+
+    Ok(summary)
+}
+
+impl fmt::Display for TraceSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "format      v{}, {:?}, {:?}", self.header.version, self.header.encoding, self.header.compression)?;
+        writeln!(f, "source      {}", self.header.source)?;
+        writeln!(f, "workers     {} (of {})", self.workers.len(), self.header.workers)?;
+        match (self.epochs.iter().next(), self.epochs.iter().next_back()) {
+            (Some(first), Some(last)) => writeln!(f, "epochs      {} ({} to {})", self.epochs.len(), first, last)?,
+            _ => writeln!(f, "epochs      0")?,
+        }
+        if let Some((first, last)) = self.span {
+            writeln!(f, "duration    {:?}", last - first)?;
+        }
+        writeln!(f, "records     {}", self.records)?;
+
+        writeln!(f, "\n{:<24}{:<12}{:>14}", "activity", "event", "#(records)")?;
+        for ((activity, event), count) in self.activities.iter() {
+            writeln!(f, "{:<24}{:<12}{:>14}", format!("{:?}", activity), format!("{:?}", event), count)?;
+        }
+
+        writeln!(f, "\n{:<12}{:<36}{:>14}", "operator", "name", "#(records)")?;
+        for (id, (name, count)) in self.operators.iter() {
+            writeln!(f, "{:<12}{:<36}{:>14}", id, name.as_ref().map_or("?", |name| name.as_str()), count)?;
+        }
+
+        writeln!(f, "\nanomalies")?;
+        let mut anomalies = 0;
+        for (worker, (gaps, missing)) in self.gaps.iter() {
+            writeln!(f, "  worker {}: {} gaps in seq_no, {} records missing", worker, gaps, missing)?;
+            anomalies += 1;
+        }
+        let missing_workers = (0 .. self.header.workers).filter(|w| !self.workers.contains(w)).count();
+        if missing_workers > 0 {
+            writeln!(f, "  {} workers without records", missing_workers)?;
+            anomalies += 1;
+        }
+        if self.damage.regions > 0 {
+            writeln!(f, "  {} damaged regions skipped ({} bytes)", self.damage.regions, self.damage.bytes)?;
+            anomalies += 1;
+        }
+        if let Some(truncation) = self.truncation {
+            writeln!(f, "  {}", truncation)?;
+            anomalies += 1;
+        }
+        if anomalies == 0 {
+            writeln!(f, "  none")?;
+        }
+        Ok(())
+    }
+}
+
+impl TraceSummary {
+    /// The summary as a JSON object
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "version": self.header.version,
+            "encoding": format!("{:?}", self.header.encoding),
+            "compression": format!("{:?}", self.header.compression),
+            "source": self.header.source,
+            "workers": self.workers,
+            "source_workers": self.header.workers,
+            "epochs": self.epochs.len(),
+            "first_epoch": self.epochs.iter().next(),
+            "last_epoch": self.epochs.iter().next_back(),
+            "duration_ns": self.span.map(|(first, last)| (last - first).as_nanos() as u64),
+            "records": self.records,
+            "activities": self.activities.iter()
+                .map(|((activity, event), count)| json!({ "activity": format!("{:?}", activity), "event": format!("{:?}", event), "records": count }))
+                .collect::<Vec<_>>(),
+            "operators": self.operators.iter()
+                .map(|(id, (name, count))| json!({ "operator": id, "name": name, "records": count }))
+                .collect::<Vec<_>>(),
+            "gaps": self.gaps.iter()
+                .map(|(worker, (gaps, missing))| json!({ "worker": worker, "gaps": gaps, "missing_records": missing }))
+                .collect::<Vec<_>>(),
+            "damage": { "regions": self.damage.regions, "bytes": self.damage.bytes },
+            "truncation": self.truncation.map(|t| json!({ "valid_bytes": t.valid_bytes, "last_epoch": t.last_epoch })),
+        })
+    }
+}
+
+/// Prints a summary of the trace file at `path` (cf. `summarize`).
+pub fn run_summary(path: &std::path::Path, output_format: OutputFormat) -> Result<(), STError> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let summary = summarize(file)?;
+    match output_format {
+        OutputFormat::Text => print!("{}", summary),
+        OutputFormat::Json => println!("{}", summary.to_json()),
+    }
+    Ok(())
+}
 
 /// Benchmarks epoch duration & # of events passing through
 trait Benchmark<S: Scope<Timestamp = Pair<u64, Duration>>, D: Data> {
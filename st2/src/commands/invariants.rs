@@ -1,6 +1,7 @@
 use crate::pag::PagEdge;
 use crate::pag;
-use crate::STError;
+use crate::{OutputFormat, STError};
+use crate::config::Args;
 use crate::pag::PagNode;
 
 use timely::dataflow::Stream;
@@ -14,90 +15,233 @@ use timely::dataflow::operators::generic::operator::Operator;
 use timely::dataflow::operators::filter::Filter;
 
 use std::time::Duration;
+use std::sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}};
 
 use st2_logformat::pair::Pair;
 use st2_logformat::ActivityType;
 
 use tdiag_connect::receive as connect;
 use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+
+use serde_json::json;
+
+
+/// The `invariants` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("invariants")
+        .about("run invariants checker")
+        .arg(clap::Arg::with_name("epoch_max")
+            .short("e")
+            .long("epoch-max")
+            .value_name("MS")
+            .help("Temporal invariant: the maximum milliseconds an epoch is allowed to take"))
+        .arg(clap::Arg::with_name("operator_max")
+            .short("o")
+            .long("operator-max")
+            .value_name("MS")
+            .help("Temporal invariant: the maximum milliseconds an operator is allowed to take"))
+        .arg(clap::Arg::with_name("message_max")
+            .short("m")
+            .long("message-max")
+            .value_name("MS")
+            .help("Temporal invariant: the maximum milliseconds a control or data message is allowed to take"))
+        .arg(clap::Arg::with_name("progress_max")
+            .short("p")
+            .long("progress-max")
+            .value_name("MS")
+            .help("Progress invariant: the maximum milliseconds between two progress messages per worker"))
+}
+
+/// The arguments of `invariants`
+pub struct Options {
+    /// Temporal invariant of epochs (ms)
+    pub epoch_max: Option<u64>,
+    /// Temporal invariant of operators (ms)
+    pub operator_max: Option<u64>,
+    /// Temporal invariant of messages (ms)
+    pub message_max: Option<u64>,
+    /// Progress invariant (ms)
+    pub progress_max: Option<u64>,
+}
 
+/// Parses the arguments of `invariants`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    let progress_max: Option<u64> = if let Some(t) = args.value_of("progress_max") {
+        Some(t.parse().map_err(|e| STError::Config(format!("Invalid --progress-max: {}", e)))?)
+    } else {
+        None
+    };
+    let epoch_max: Option<u64> = if let Some(t) = args.value_of("epoch_max") {
+        eprintln!("epoch max given");
+        Some(t.parse().map_err(|e| STError::Config(format!("Invalid --epoch-max: {}", e)))?)
+    } else {
+        None
+    };
+    let operator_max: Option<u64> = if let Some(t) = args.value_of("operator_max") {
+        Some(t.parse().map_err(|e| STError::Config(format!("Invalid --operator-max: {}", e)))?)
+    } else {
+        None
+    };
+    let message_max: Option<u64> = if let Some(t) = args.value_of("message_max") {
+        Some(t.parse().map_err(|e| STError::Config(format!("Invalid --message-max: {}", e)))?)
+    } else {
+        None
+    };
+
+    Ok(Options { epoch_max, operator_max, message_max, progress_max })
+}
 
-/// Checks invariants on the log traces provided by `replay_source`.
+/// Checks invariants on the log traces provided by `replay_source` and prints every
+/// violation as it is found, as text or a JSON object per line. Returns the number
+/// of violations.
 pub fn run(timely_configuration: timely::Configuration,
            replay_source: ReplaySource,
+           is_running: Arc<AtomicBool>,
+           speed: ReplaySpeed,
+           filter: st2_timely::filter::Filter,
+           source_peers: usize,
            temporal_epoch: Option<u64>,
            temporal_operator: Option<u64>,
            temporal_message: Option<u64>,
-           progress_max: Option<u64>) -> Result<(), STError> {
+           progress_max: Option<u64>,
+           output_format: OutputFormat) -> Result<u64, STError> {
+
+    let violations = Arc::new(AtomicU64::new(0));
+    let violations_out = Arc::clone(&violations);
+
+    let local_peers = crate::local_peers(&timely_configuration);
 
     timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
         let index = worker.index();
-        let peers = worker.peers();
+        // the source computation's peers, which needn't match ST2's
+        let peers = source_peers;
 
         // read replayers from file (offline) or TCP stream (online)
-        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        // counts and prints a violation
+        let report = {
+            let violations = Arc::clone(&violations);
+            move |text: String, json: serde_json::Value| {
+                violations.fetch_add(1, Ordering::Relaxed);
+                output_format.print(text, json);
+            }
+        };
 
         worker.dataflow(|scope| {
-            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = pag::create_pag(scope, readers, index, 1);
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
 
+            let report_progress = report.clone();
             pag.some_progress(peers)
                 .inspect_time(move |t, x| if x.1 < (peers as u64 - 1) {
-                    println!("Progress Issue: w{}@e{} Sent progress to {} of {} other peers", x.0, t.first - 1, x.1, peers - 1)
+                    report_progress(
+                        format!("Progress Issue: w{}@e{} Sent progress to {} of {} other peers", x.0, t.first - 1, x.1, peers - 1),
+                        json!({ "type": "progress", "worker": x.0, "epoch": t.first - 1, "peers_notified": x.1, "peers": peers - 1 }))
                 });
 
             if let Some(progress_max) = progress_max {
                 let max = Duration::from_millis(progress_max);
+                let report = report.clone();
                 pag.max_progress(max)
-                    .inspect(move |(x, y)| println!("Progress Issue: No progress message sent by w{} since {:?}. Maximum allowed is {:?}.", x.worker_id, (y.timestamp - x.timestamp), max));
+                    .inspect(move |(x, y)| report(
+                        format!("Progress Issue: No progress message sent by w{} since {:?}. Maximum allowed is {:?}.", x.worker_id, (y.timestamp - x.timestamp), max),
+                        json!({
+                            "type": "progress_pause",
+                            "worker": x.worker_id,
+                            "pause_ns": (y.timestamp - x.timestamp).as_nanos() as u64,
+                            "max_ns": max.as_nanos() as u64,
+                        })));
             }
 
             if let Some(temporal_epoch) = temporal_epoch {
                 let max = Duration::from_millis(temporal_epoch);
+                let report = report.clone();
                 pag.max_epoch(max)
-                    .inspect(move |(x, y)|
-                             println!("Temporal Issue: Epoch {} ran from {:?} to {:?}, taking {:?}. Maximum allowed is {:?}.",
-                                      x.epoch, x.timestamp, y.timestamp, (y.timestamp - x.timestamp), max));
+                    .inspect(move |(x, y)| report(
+                        format!("Temporal Issue: Epoch {} ran from {:?} to {:?}, taking {:?}. Maximum allowed is {:?}.",
+                                x.epoch, x.timestamp, y.timestamp, (y.timestamp - x.timestamp), max),
+                        json!({
+                            "type": "epoch",
+                            "epoch": x.epoch,
+                            "from_ns": x.timestamp.as_nanos() as u64,
+                            "to_ns": y.timestamp.as_nanos() as u64,
+                            "duration_ns": (y.timestamp - x.timestamp).as_nanos() as u64,
+                            "max_ns": max.as_nanos() as u64,
+                        })));
             }
 
             if let Some(temporal_operator) = temporal_operator {
                 let max = Duration::from_millis(temporal_operator);
+                let report = report.clone();
                 pag.max_operator(max)
                     .inspect(move |(first_edge, last_edge)| {
-                        println!("Temporal Issue: Operator {} in w{}@e{} ({:?}, {} records processed) ran from {:?} to {:?}, taking {:?}. \
-                                  Maximum allowed is {:?}.",
-                                 first_edge.operator_id.expect("not an operator?"),
-                                 first_edge.source.worker_id,
-                                 first_edge.source.epoch,
-                                 first_edge.edge_type,
-                                 last_edge.length.unwrap_or(0),
-                                 first_edge.source.timestamp,
-                                 last_edge.destination.timestamp,
-                                 (last_edge.destination.timestamp - first_edge.source.timestamp),
-                                 max)
+                        let operator = first_edge.operator_id.expect("not an operator?");
+                        let duration = last_edge.destination.timestamp - first_edge.source.timestamp;
+                        report(
+                            format!("Temporal Issue: Operator {} in w{}@e{} ({:?}, {} records processed) ran from {:?} to {:?}, taking {:?}. \
+                                     Maximum allowed is {:?}.",
+                                    operator,
+                                    first_edge.source.worker_id,
+                                    first_edge.source.epoch,
+                                    first_edge.edge_type,
+                                    last_edge.length.unwrap_or(0),
+                                    first_edge.source.timestamp,
+                                    last_edge.destination.timestamp,
+                                    duration,
+                                    max),
+                            json!({
+                                "type": "operator",
+                                "operator": operator,
+                                "worker": first_edge.source.worker_id,
+                                "epoch": first_edge.source.epoch,
+                                "activity": format!("{:?}", first_edge.edge_type),
+                                "records": last_edge.length.unwrap_or(0),
+                                "from_ns": first_edge.source.timestamp.as_nanos() as u64,
+                                "to_ns": last_edge.destination.timestamp.as_nanos() as u64,
+                                "duration_ns": duration.as_nanos() as u64,
+                                "max_ns": max.as_nanos() as u64,
+                            }))
                     });
             }
 
             if let Some(temporal_message) = temporal_message {
                 let max = Duration::from_millis(temporal_message);
+                let report = report.clone();
                 pag.max_message(max)
                     .inspect(move |edge| {
-                        println!("Temporal Issue: {:?} (payload: {:?}) in e{}, w{} to w{} ran from {:?} to {:?}, taking {:?}. \
-                                  Maximum allowed is {:?}.",
-                                 edge.edge_type,
-                                 edge.length,
-                                 edge.source.epoch,
-                                 edge.source.worker_id,
-                                 edge.destination.worker_id,
-                                 edge.source.timestamp,
-                                 edge.destination.timestamp,
-                                 (edge.destination.timestamp - edge.source.timestamp),
-                                 max)
+                        let duration = edge.destination.timestamp - edge.source.timestamp;
+                        report(
+                            format!("Temporal Issue: {:?} (payload: {:?}) in e{}, w{} to w{} ran from {:?} to {:?}, taking {:?}. \
+                                     Maximum allowed is {:?}.",
+                                    edge.edge_type,
+                                    edge.length,
+                                    edge.source.epoch,
+                                    edge.source.worker_id,
+                                    edge.destination.worker_id,
+                                    edge.source.timestamp,
+                                    edge.destination.timestamp,
+                                    duration,
+                                    max),
+                            json!({
+                                "type": "message",
+                                "activity": format!("{:?}", edge.edge_type),
+                                "records": edge.length,
+                                "epoch": edge.source.epoch,
+                                "from_worker": edge.source.worker_id,
+                                "to_worker": edge.destination.worker_id,
+                                "from_ns": edge.source.timestamp.as_nanos() as u64,
+                                "to_ns": edge.destination.timestamp.as_nanos() as u64,
+                                "duration_ns": duration.as_nanos() as u64,
+                                "max_ns": max.as_nanos() as u64,
+                            }))
                     });
             }
         });
-    }).map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+    }).map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
 
-    Ok(())
+    Ok(violations_out.load(Ordering::Acquire))
 }
 
 
@@ -105,7 +249,7 @@ pub fn run(timely_configuration: timely::Configuration,
 pub trait Invariants<S: Scope<Timestamp = Pair<u64, Duration>>> {
     /// Ensure the reference computation is making progress.
     /// Every worker should send at least one progress message per epoch
-    /// to each other peer.
+    /// to each other peer of the source computation (`peers` in total).
     /// Returns `(worker_id, count)` pairs where `count` of progress
     /// messages per epoch is smaller than `peers`.
     fn some_progress(&self, peers: usize) -> Stream<S, (u64, u64)>;
@@ -0,0 +1,313 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use st2_logformat::{ActivityType, EventType, LogRecord};
+use st2_logformat::encoding::Encoding;
+use st2_logformat::names::NameId;
+use st2_logformat::tagged::{self, Value};
+use st2_logformat::trace::{Compression, TraceHeader, TraceReader, TraceWriter};
+
+use serde_json::json;
+
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+/// Number of records per written batch
+const BATCH_SIZE: usize = 1024;
+
+/// Clock offset of every worker relative to the reference worker (the lowest
+/// worker id among its connected workers), in ns: `local clock - reference clock`
+pub type Offsets = BTreeMap<u64, i64>;
+
+/// The `merge` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("merge")
+        .about("Merge independently captured trace files (e.g. per worker or host) into one trace file, correcting clock offsets")
+        .arg(clap::Arg::with_name("output")
+            .short("o")
+            .long("out")
+            .value_name("OUT")
+            .help("Path of the merged trace file")
+            .required(true))
+        .arg(clap::Arg::with_name("inputs")
+            .value_name("TRACE")
+            .help("Trace files to merge")
+            .multiple(true)
+            .required(true))
+        .arg(clap::Arg::with_name("encoding")
+            .long("encoding")
+            .value_name("ENCODING")
+            .help("Encoding of the merged trace file (abomonation, bincode, protobuf, compact)")
+            .default_value("compact"))
+        .arg(clap::Arg::with_name("compression")
+            .long("compression")
+            .value_name("COMPRESSION")
+            .possible_values(&["none", "gzip"])
+            .help("Compression of the merged trace file")
+            .default_value("none"))
+}
+
+/// The arguments of `merge`
+pub struct Options {
+    /// The merged trace files
+    pub inputs: Vec<PathBuf>,
+    /// The merged file
+    pub output: PathBuf,
+    /// The encoding of the merged file
+    pub encoding: Encoding,
+    /// The compression of the merged file
+    pub compression: Compression,
+}
+
+/// Parses the arguments of `merge`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        inputs: args.values_of("inputs").expect("error parsing merge input args")
+            .map(PathBuf::from)
+            .collect(),
+        output: PathBuf::from(args.value_of("output").expect("error parsing merge output args")),
+        encoding: args.value_of("encoding").expect("error parsing merge encoding args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --encoding: {}", e)))?,
+        compression: args.value_of("compression").expect("error parsing merge compression args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --compression: {}", e)))?,
+    })
+}
+
+/// Combines the independently captured trace files `inputs` (e.g. one per worker
+/// or host) into the single trace file `output`.
+///
+/// As the inputs may have been captured on hosts with different clocks, every
+/// worker's timestamps are corrected by its estimated clock offset (cf. `estimate_offsets`)
+/// before the records are merged in timestamp order. Every input has to be in
+/// timestamp order per worker, as written by ST2. Names are re-interned, so
+/// records of different inputs can refer to the same names.
+pub fn run(inputs: &[&Path], output: &Path, encoding: Encoding, compression: Compression, output_format: OutputFormat) -> Result<(), STError> {
+    let mut pairs = MessagePairs::default();
+    let mut workers = BTreeSet::new();
+    let mut start_time = None;
+    let mut source = None;
+    for input in inputs {
+        let mut reader = TraceReader::new(BufReader::new(File::open(input)?)).map_err(|e| STError::decode(0, e))?;
+        let input_start = reader.header().start_time;
+        start_time = Some(start_time.map_or(input_start, |start| std::cmp::min(start, input_start)));
+        source = source.or_else(|| Some(reader.header().source.clone()));
+        while let Some(batch) = reader.next_batch().map_err(|e| STError::decode(reader.offset(), e))? {
+            for record in batch.iter() {
+                workers.insert(record.local_worker);
+                pairs.record(record);
+            }
+        }
+    }
+
+    let offsets = estimate_offsets(&pairs.delays);
+    for (worker, offset) in offsets.iter().filter(|(_, offset)| **offset != 0 && output_format == OutputFormat::Text) {
+        println!("worker {}: correcting clock offset of {:.3}ms", worker, *offset as f64 / 1_000_000.0);
+    }
+
+    let mut header = TraceHeader::new(workers.len() as u64, source.unwrap_or_default(), encoding, compression);
+    header.start_time = start_time.unwrap_or_default();
+    let mut writer = TraceWriter::new(&header, BufWriter::new(File::create(output)?))?;
+
+    let mut inputs = inputs.iter()
+        .map(|input| Input::open(input, &offsets))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // k-way merge by corrected timestamp, preserving every input's order
+    let mut heads = BinaryHeap::new();
+    for (index, input) in inputs.iter_mut().enumerate() {
+        if let Some(timestamp) = input.peek()? {
+            heads.push(Reverse((timestamp, index)));
+        }
+    }
+
+    let mut count = 0;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while let Some(Reverse((_, index))) = heads.pop() {
+        let input = &mut inputs[index];
+        let record = input.next().expect("peeked record");
+        batch.push(input.rename(record, &mut writer));
+        if let Some(timestamp) = input.peek()? {
+            heads.push(Reverse((timestamp, index)));
+        }
+
+        if batch.len() == BATCH_SIZE {
+            count += batch.len();
+            writer.write_batch(&batch)?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        count += batch.len();
+        writer.write_batch(&batch)?;
+    }
+    writer.finish()?;
+
+    output_format.print(
+        format_args!("Merged {} records of {} workers into {}", count, workers.len(), output.display()),
+        json!({
+            "inputs": inputs,
+            "output": output,
+            "records": count,
+            "workers": workers.len(),
+            "clock_offsets_ns": offsets.iter().map(|(worker, offset)| (worker.to_string(), *offset)).collect::<BTreeMap<_, _>>(),
+        }));
+    Ok(())
+}
+
+/// An input trace file, with skew-corrected timestamps
+struct Input<'a> {
+    reader: TraceReader,
+    offsets: &'a Offsets,
+    /// Records of the current batch, in reverse order
+    pending: Vec<LogRecord>,
+}
+
+impl<'a> Input<'a> {
+    fn open(path: &Path, offsets: &'a Offsets) -> Result<Self, STError> {
+        let reader = TraceReader::new(BufReader::new(File::open(path)?)).map_err(|e| STError::decode(0, e))?;
+        Ok(Input { reader, offsets, pending: Vec::new() })
+    }
+
+    /// Corrected timestamp of the next record, if any
+    fn peek(&mut self) -> Result<Option<Duration>, STError> {
+        while self.pending.is_empty() {
+            match self.reader.next_batch().map_err(|e| STError::decode(self.reader.offset(), e))? {
+                Some(mut batch) => {
+                    batch.reverse();
+                    for record in batch.iter_mut() {
+                        let offset = self.offsets.get(&record.local_worker).cloned().unwrap_or(0);
+                        record.timestamp = correct(record.timestamp, offset);
+                    }
+                    self.pending = batch;
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(self.pending.last().map(|record| record.timestamp))
+    }
+
+    fn next(&mut self) -> Option<LogRecord> {
+        self.pending.pop()
+    }
+
+    /// Re-interns the names `record` refers to in `writer`.
+    fn rename(&self, mut record: LogRecord, writer: &mut TraceWriter) -> LogRecord {
+        let names = self.reader.names();
+        let rename = |id: NameId, writer: &mut TraceWriter| names.resolve(id).map(|name| writer.intern(name));
+
+        if let Some(origin) = record.origin.as_mut() {
+            if let Some(host) = rename(origin.host, writer) {
+                origin.host = host;
+            }
+        }
+        if let Some(Value::U64(id)) = tagged::get(&record.tagged, tagged::OPERATOR_NAME) {
+            if let Some(name) = rename(*id as NameId, writer) {
+                tagged::set(&mut record.tagged, tagged::OPERATOR_NAME, Value::U64(name as u64));
+            }
+        }
+        record
+    }
+}
+
+fn correct(timestamp: Duration, offset: i64) -> Duration {
+    if offset >= 0 {
+        timestamp.checked_sub(Duration::from_nanos(offset as u64)).unwrap_or_default()
+    } else {
+        timestamp + Duration::from_nanos(offset.wrapping_neg() as u64)
+    }
+}
+
+/// Matches sent to received messages in order, per sender, receiver, and channel
+#[derive(Default)]
+struct MessagePairs {
+    /// (sender, receiver, activity type, channel, correlator) -> timestamps of unmatched sends resp. receives
+    unmatched: HashMap<(u64, u64, ActivityType, Option<u64>, Option<u64>), (VecDeque<Duration>, VecDeque<Duration>)>,
+    /// (sender, receiver) -> minimum observed `receive - send`, in ns
+    delays: BTreeMap<(u64, u64), i64>,
+}
+
+impl MessagePairs {
+    fn record(&mut self, record: &LogRecord) {
+        let remote = match record.remote_worker {
+            Some(remote) => remote,
+            None => return,
+        };
+        let (sender, receiver) = match record.event_type {
+            EventType::Sent => (record.local_worker, remote),
+            EventType::Received => (remote, record.local_worker),
+            _ => return,
+        };
+        if sender == receiver {
+            return;
+        }
+
+        let key = (sender, receiver, record.activity_type, record.channel_id, record.correlator_id);
+        let (sends, receives) = self.unmatched.entry(key).or_insert_with(Default::default);
+        let matched = if record.event_type == EventType::Sent {
+            receives.pop_front().map(|received| (record.timestamp, received))
+        } else {
+            sends.pop_front().map(|sent| (sent, record.timestamp))
+        };
+        let (sent, received) = match matched {
+            Some(matched) => matched,
+            None => {
+                if record.event_type == EventType::Sent {
+                    sends.push_back(record.timestamp);
+                } else {
+                    receives.push_back(record.timestamp);
+                }
+                return;
+            }
+        };
+
+        let delay = received.as_nanos() as i64 - sent.as_nanos() as i64;
+        let min = self.delays.entry((sender, receiver)).or_insert(delay);
+        *min = std::cmp::min(*min, delay);
+    }
+}
+
+/// Estimates every worker's clock offset from the minimum observed message `delays`
+/// between pairs of workers, assuming symmetric minimum network delays: for workers
+/// `a` and `b` with minimum delays `d(a, b)` and `d(b, a)`, `b`'s clock is ahead of
+/// `a`'s by `(d(a, b) - d(b, a)) / 2`. Offsets propagate from the lowest worker id
+/// along pairs that exchanged messages in both directions. If they only exchanged
+/// messages in one direction, `b` is only corrected if the one-way delay is negative,
+/// i.e. if messages appear to be received before they were sent.
+pub fn estimate_offsets(delays: &BTreeMap<(u64, u64), i64>) -> Offsets {
+    let workers: BTreeSet<u64> = delays.keys().flat_map(|(a, b)| vec![*a, *b]).collect();
+
+    // a -> [(b, offset of b relative to a)]
+    let mut edges: BTreeMap<u64, Vec<(u64, i64)>> = BTreeMap::new();
+    for ((a, b), d_ab) in delays.iter() {
+        let offset = match delays.get(&(*b, *a)) {
+            Some(d_ba) => (d_ab - d_ba) / 2,
+            None if *d_ab < 0 => *d_ab,
+            None => 0,
+        };
+        edges.entry(*a).or_insert_with(Vec::new).push((*b, offset));
+        edges.entry(*b).or_insert_with(Vec::new).push((*a, -offset));
+    }
+
+    let mut offsets = Offsets::new();
+    for root in workers.iter() {
+        if offsets.contains_key(root) {
+            continue;
+        }
+        offsets.insert(*root, 0);
+        let mut queue = VecDeque::new();
+        queue.push_back(*root);
+        while let Some(a) = queue.pop_front() {
+            for (b, offset) in edges.get(&a).into_iter().flatten() {
+                if !offsets.contains_key(b) {
+                    offsets.insert(*b, offsets[&a] + offset);
+                    queue.push_back(*b);
+                }
+            }
+        }
+    }
+    offsets
+}
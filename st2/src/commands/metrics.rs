@@ -1,5 +1,7 @@
 use crate::pag;
 use crate::pag::PagEdge;
+use crate::commands::{calculate_hash, expect_write};
+use crate::commands::aggregate::Forwarded;
 
 use timely::dataflow::Scope;
 use timely::dataflow::Stream;
@@ -7,38 +9,162 @@ use timely::dataflow::operators::inspect::Inspect;
 use timely::dataflow::operators::map::Map;
 use timely::dataflow::operators::aggregation::aggregate::Aggregate;
 use timely::dataflow::operators::delay::Delay;
+use timely::dataflow::operators::capture::{Capture, EventWriter};
+use timely::dataflow::operators::concat::Concat;
 
 use std::time::Duration;
-use std::sync::{Arc, Mutex};
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, atomic::AtomicBool};
+use std::collections::BTreeMap;
 use std::io::Write;
 use std::convert::TryInto;
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
 
 use st2_logformat::pair::Pair;
 use st2_logformat::ActivityType;
 
-use tdiag_connect::receive as connect;
 use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
 
-use crate::STError;
+use serde_json::json;
 
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+use crate::deterministic::Deterministic;
+
+
+/// A per-epoch metrics summary:
+/// `(from_worker, to_worker, activity_type, #(activities), t(activities), #(records))`
+pub type MetricsSummary = (u64, u64, ActivityType, u64, u64, u64);
+
+/// What a `Breakdown` aggregates activities by
+#[derive(Abomonation, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum BreakdownKey {
+    /// Activities of a worker (by the worker they start at)
+    Worker(u64),
+    /// Activities of an operator
+    Operator(u64),
+    /// Activities of a type
+    Activity(ActivityType),
+}
+
+impl std::fmt::Display for BreakdownKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BreakdownKey::Worker(w) => write!(f, "worker,{}", w),
+            BreakdownKey::Operator(o) => write!(f, "operator,{}", o),
+            BreakdownKey::Activity(a) => write!(f, "activity,{:?}", a),
+        }
+    }
+}
+
+/// A per-epoch breakdown of activities:
+/// `(key, #(activities), t(activities), #(records))`
+pub type Breakdown = (BreakdownKey, u64, u64, u64);
+
+/// The `metrics` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("metrics")
+        .about("Write dataflow metrics to file")
+        .arg(clap::Arg::with_name("output_path")
+            .short("o")
+            .long("out")
+            .value_name("PATH")
+            .help("The output path for the generated CSV file (don't forget the .CSV extension)")
+            .default_value("metrics.csv"))
+        .arg(clap::Arg::with_name("forward")
+            .long("forward")
+            .value_name("ADDR")
+            .help("Additionally forward per-epoch summaries to an aggregating ST2 instance (<IP>:<Port>)")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("forward_pag")
+            .long("forward-pag")
+            .requires("forward")
+            .help("Also forward PAG edges, so the aggregating instance can compute global critical paths"))
+        .arg(clap::Arg::with_name("breakdown")
+            .long("breakdown")
+            .value_name("PATH")
+            .help("Additionally write per-epoch aggregates per worker, operator, and activity type to a CSV file")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("summary")
+            .long("summary")
+            .help("Print aggregates per worker, operator, and activity type over the whole trace when done"))
+}
+
+/// The arguments of `metrics`
+pub struct Options {
+    /// The CSV file of per-epoch metrics
+    pub output_path: PathBuf,
+    /// The aggregating ST2 instance to forward summaries to
+    pub forward: Option<SocketAddr>,
+    /// Whether to forward PAG edges, too
+    pub forward_pag: bool,
+    /// The CSV file of per-epoch breakdowns
+    pub breakdown_path: Option<PathBuf>,
+    /// Whether to print aggregates over the whole trace when done
+    pub summary: bool,
+}
+
+/// Parses the arguments of `metrics`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    let forward = if let Some(addr) = args.value_of("forward") {
+        Some(addr.parse().map_err(|e| STError::Config(format!("Invalid --forward: {}", e)))?)
+    } else {
+        None
+    };
+
+    Ok(Options {
+        output_path: PathBuf::from(args.value_of("output_path").expect("error parsing metrics output args")),
+        forward,
+        forward_pag: args.is_present("forward_pag"),
+        breakdown_path: args.value_of("breakdown").map(PathBuf::from),
+        summary: args.is_present("summary"),
+    })
+}
 
 /// Computes aggregate metrics for the computation traces in `replay_source`.
+/// If `forward` is set, the per-epoch summaries are additionally forwarded
+/// to an aggregating ST2 instance (cf. `commands::aggregate`), and if
+/// `forward_pag` is set, so are the PAG edges, for global critical paths.
+/// If `breakdown_path` is set, per-worker, per-operator, and per-activity aggregates
+/// are written there per epoch. If `summary` is set, these aggregates are printed
+/// for the whole trace once it has been processed, showing operators with their
+/// `operator_names`, if known.
 pub fn run(
     timely_configuration: timely::Configuration,
     replay_source: ReplaySource,
-    output_path: &std::path::Path) -> Result<(), STError> {
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    output_path: &std::path::Path,
+    forward: Option<SocketAddr>,
+    forward_pag: bool,
+    breakdown_path: Option<&std::path::Path>,
+    summary: bool,
+    operator_names: &BTreeMap<u64, String>,
+    output_format: OutputFormat) -> Result<(), STError> {
 
     let throttle = 1;
 
-    let file = Arc::new(Mutex::new(std::fs::File::create(output_path).map_err(|e| STError(format!("io error: {}", e)))?));
+    let file = Arc::new(Mutex::new(std::fs::File::create(output_path).map_err(STError::from)?));
+    let breakdown_file = if let Some(path) = breakdown_path {
+        Some(Arc::new(Mutex::new(std::fs::File::create(path).map_err(STError::from)?)))
+    } else {
+        None
+    };
+    // key -> (#(activities), t(activities), #(records)) over all epochs
+    let totals: Arc<Mutex<BTreeMap<BreakdownKey, (u64, u64, u64)>>> = Arc::new(Mutex::new(BTreeMap::new()));
+    let totals_out = Arc::clone(&totals);
+
+    let local_peers = crate::local_peers(&timely_configuration);
 
     timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
         let index = worker.index();
 
         // read replayers from file (offline) or TCP stream (online)
-        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
 
         worker.dataflow(|scope| {
             let file = Arc::clone(&file);
@@ -47,36 +173,136 @@ pub fn run(
                 expect_write(writeln!(*file.lock().unwrap(), "epoch,from_worker,to_worker,activity_type,#(activities),t(activities),#(records)"));
             }
 
-            let pag = pag::create_pag(scope, readers, index, throttle);
+            let pag = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), throttle, speed, filter.clone());
+            let metrics = pag.metrics().deterministic();
 
-            pag
-                .metrics()
+            metrics
                 .inspect_time(move |t,x| expect_write(
                     writeln!(*file.lock().unwrap(),
                              "{:?},{},{},{:?},{},{},{}",
                              t.first - 1, x.0, x.1, x.2, x.3, x.4, x.5)
                 ));
+
+            // every ST2 peer forwards its share of summaries on a separate connection
+            if let Some(addr) = forward {
+                let stream = TcpStream::connect(addr).expect("couldn't connect to aggregator");
+                let mut forwarded = metrics.map(Forwarded::Summary);
+                if forward_pag {
+                    // at the time of the epoch's summaries, as `Forwarded` promises
+                    forwarded = forwarded.concat(&pag
+                        .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+                        .map(|(edge, _t, _diff)| Forwarded::Edge(edge)));
+                }
+                forwarded.capture_into(EventWriter::new(stream));
+            }
+
+            if breakdown_file.is_some() || summary {
+                let breakdown_file = breakdown_file.clone();
+                let totals = Arc::clone(&totals);
+
+                if index == 0 {
+                    if let Some(breakdown_file) = &breakdown_file {
+                        expect_write(writeln!(*breakdown_file.lock().unwrap(), "epoch,dimension,key,#(activities),t(activities),#(records)"));
+                    }
+                }
+
+                pag.breakdown()
+                    .deterministic()
+                    .inspect_time(move |t, x| {
+                        if let Some(breakdown_file) = &breakdown_file {
+                            expect_write(writeln!(*breakdown_file.lock().unwrap(),
+                                                  "{:?},{},{},{},{}",
+                                                  t.first - 1, x.0, x.1, x.2, x.3));
+                        }
+                        let mut totals = totals.lock().unwrap();
+                        let total = totals.entry(x.0).or_insert((0, 0, 0));
+                        *total = (total.0 + x.1, total.1 + x.2, total.2 + x.3);
+                    });
+            }
         });
     })
-        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    if summary {
+        match output_format {
+            OutputFormat::Text => print_summary(&totals_out.lock().unwrap(), operator_names),
+            OutputFormat::Json => println!("{}", summary_json(&totals_out.lock().unwrap(), operator_names)),
+        }
+    }
 
     Ok(())
 }
 
-fn calculate_hash<T: Hash>(t: &T) -> u64 {
-    let mut s = DefaultHasher::new();
-    t.hash(&mut s);
-    s.finish()
+/// Prints aggregates over the whole trace as a table, one section per dimension.
+fn print_summary(totals: &BTreeMap<BreakdownKey, (u64, u64, u64)>, operator_names: &BTreeMap<u64, String>) {
+    let mut dimension = None;
+    for (key, (count, t, records)) in totals.iter() {
+        let key_dimension = std::mem::discriminant(key);
+        if dimension != Some(key_dimension) {
+            let title = match key {
+                BreakdownKey::Worker(_) => "worker",
+                BreakdownKey::Operator(_) => "operator",
+                BreakdownKey::Activity(_) => "activity",
+            };
+            println!("\n{:<24}{:>14}{:>18}{:>14}", title, "#(activities)", "t(activities) ms", "#(records)");
+            dimension = Some(key_dimension);
+        }
+        let key = match key {
+            BreakdownKey::Worker(w) => w.to_string(),
+            BreakdownKey::Operator(o) => operator_label(*o, operator_names),
+            BreakdownKey::Activity(a) => format!("{:?}", a),
+        };
+        println!("{:<24}{:>14}{:>18.3}{:>14}", key, count, *t as f64 / 1_000_000.0, records);
+    }
+}
+
+/// Aggregates over the whole trace as a JSON object, one array per dimension
+fn summary_json(totals: &BTreeMap<BreakdownKey, (u64, u64, u64)>, operator_names: &BTreeMap<u64, String>) -> serde_json::Value {
+    let (mut workers, mut operators, mut activities) = (Vec::new(), Vec::new(), Vec::new());
+    for (key, (count, t, records)) in totals.iter() {
+        let mut object = json!({ "activities": count, "t_ns": t, "records": records });
+        match key {
+            BreakdownKey::Worker(w) => {
+                object["worker"] = json!(w);
+                workers.push(object);
+            }
+            BreakdownKey::Operator(o) => {
+                object["operator"] = json!(o);
+                object["name"] = json!(operator_names.get(o));
+                operators.push(object);
+            }
+            BreakdownKey::Activity(a) => {
+                object["activity"] = json!(format!("{:?}", a));
+                activities.push(object);
+            }
+        }
+    }
+    json!({ "workers": workers, "operators": operators, "activities": activities })
+}
+
+/// Shows operator `id` with its label (cf. `labels`) or name from `operator_names`,
+/// if known, and its source location (cf. `sources`), e.g. `Map (3) at src/main.rs:42`
+pub fn operator_label(id: u64, operator_names: &BTreeMap<u64, String>) -> String {
+    let label = match crate::labels::name(id, operator_names) {
+        Some(name) => format!("{} ({})", crate::sources::split(&name).0, id),
+        None => id.to_string(),
+    };
+    match crate::sources::of(id, operator_names) {
+        Some(location) => format!("{} at {}", label, location),
+        None => label,
+    }
 }
 
 /// Benchmarks epoch duration & # of events passing through
 pub trait Metrics<S: Scope<Timestamp = Pair<u64, Duration>>> {
     /// Reports activity type & duration per epoch per worker
-    fn metrics(&self) -> Stream<S, (u64, u64, ActivityType, u64, u64, u64)>;
+    fn metrics(&self) -> Stream<S, MetricsSummary>;
+    /// Reports activity count & duration per epoch, per worker, operator, and activity type
+    fn breakdown(&self) -> Stream<S, Breakdown>;
 }
 
 impl<S: Scope<Timestamp = Pair<u64, Duration>>> Metrics<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
-    fn metrics(&self) -> Stream<S, (u64, u64, ActivityType, u64, u64, u64)> {
+    fn metrics(&self) -> Stream<S, MetricsSummary> {
 
         self
             .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
@@ -91,9 +317,24 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> Metrics<S> for Stream<S, (PagEdg
                 |key, acc| (key.0, key.1, key.2, acc.0, acc.1, acc.2),
                 |key| calculate_hash(key))
     }
-}
 
-/// Unwraps a write.
-fn expect_write(e: Result<(), std::io::Error>) {
-    e.expect("write failed");
+    fn breakdown(&self) -> Stream<S, Breakdown> {
+        self
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .flat_map(|(edge, _t, _diff)| {
+                let mut keys = vec![BreakdownKey::Worker(edge.source.worker_id), BreakdownKey::Activity(edge.edge_type)];
+                if let Some(operator_id) = edge.operator_id {
+                    keys.push(BreakdownKey::Operator(operator_id));
+                }
+                keys.into_iter().map(move |key| (key, edge.clone()))
+            })
+            .aggregate::<_,(u64, u64, u64),_,_,_>(
+                |_key, edge, acc| {
+                    *acc = (acc.0 + 1,
+                            acc.1 + edge.duration(),
+                            acc.2 + edge.length.unwrap_or(0) as u64);
+                },
+                |key, acc| (key, acc.0, acc.1, acc.2),
+                |key| calculate_hash(key))
+    }
 }
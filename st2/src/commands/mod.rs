@@ -1,14 +1,107 @@
 //! Subcommand modules.
 //!
-//! Each of the program's subcommand logic is in a separate module here.
+//! Each of the program's subcommand logic is in a separate module here,
+//! along with its command-line arguments (`subcommand`) and their parsing
+//! (`from_args`).
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+
+use crate::STError;
 
 /// Aggregate metrics export
 pub mod metrics;
+/// Export of PAG edges and metrics to various file formats
+pub mod export;
+/// Flamegraphs of critical path composition
+pub mod flamegraph;
+/// Worker × operator heatmaps
+pub mod heatmap;
+/// Logical dataflow graphs with operator metrics
+pub mod graph;
+/// Self-contained HTML reports
+pub mod report;
+/// Comparison of two traces
+pub mod diff;
+/// Capture of traces without analysis
+pub mod record;
+/// Trace health checks
+pub mod validate;
+/// Conversion of trace files between encodings and formats
+pub mod convert;
+/// Extraction of time and epoch ranges from trace files
+pub mod trim;
+/// Merging of trace files with clock skew correction
+pub mod merge;
+/// Pseudonymization of trace files
+pub mod anonymize;
+/// Hierarchical aggregation of metrics from multiple ST2 instances
+pub mod aggregate;
 /// ST2 inspector
 pub mod inspect;
+/// Benchmarks of ST2 on synthetic traces
+pub mod bench;
+/// Built-in demo computation, analyzed right away
+pub mod demo;
+/// Verification of online against offline analysis
+pub mod verify;
+/// Resource estimation from a sample of a trace
+pub mod estimate;
 /// ST2 graph algorithms
 pub mod algo;
 /// Invariants checker
 pub mod invariants;
+/// Single-epoch PAG snapshots
+pub mod snapshot;
+/// Interactive queries over a loaded PAG
+pub mod repl;
+/// Declarative PAG queries
+pub mod query;
+/// SQL queries over PAGs
+pub mod sql;
+/// Threshold-based alerting
+pub mod alerts;
+/// Registered analyses, e.g. of plugins
+pub mod analyze;
+/// JSON lines of per-epoch results
+pub mod stream;
+/// Live terminal UI
+pub mod top;
 /// Online dashboard
 pub mod dashboard;
+/// Grafana JSON datasource
+pub mod grafana;
+/// gRPC service exposing analysis results
+pub mod grpc;
+/// HTTP/JSON API with live subscriptions
+pub mod api;
+/// Publishing of per-epoch metrics to external systems
+pub mod publish;
+/// Long-lived online analysis service with health endpoints
+pub mod daemon;
+
+/// Hash of `t`, which distributes keys among the workers of timely's `aggregate`
+pub(crate) fn calculate_hash<T: Hash>(t: &T) -> u64 {
+    let mut s = DefaultHasher::new();
+    t.hash(&mut s);
+    s.finish()
+}
+
+/// Unwraps a write.
+pub(crate) fn expect_write(e: Result<(), std::io::Error>) {
+    e.expect("write failed");
+}
+
+/// Parses an epoch range `FROM..TO`, where either bound may be omitted
+pub fn parse_epochs(range: &str) -> Result<Range<u64>, STError> {
+    let mut bounds = range.splitn(2, "..");
+    let parse = |bound: Option<&str>, default| match bound {
+        Some("") => Ok(default),
+        Some(bound) => bound.parse().map_err(|e| STError::Config(format!("Invalid --epochs: {}", e))),
+        None => Err(STError::Config(format!("Invalid --epochs: {} (expected FROM..TO)", range))),
+    };
+    let from = parse(bounds.next(), 0)?;
+    let to = parse(bounds.next(), std::u64::MAX)?;
+    Ok(from .. to)
+}
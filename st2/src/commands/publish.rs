@@ -0,0 +1,826 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::store::{completed_epochs, epoch_samples, Sample};
+use crate::history::History;
+use crate::commands::alerts::{send, HTTP_TIMEOUT};
+use crate::commands::export::{edge_row, Field, EDGE_COLUMNS};
+
+use timely::dataflow::Stream;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::UdpSocket;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use serde_json::{json, Map, Value};
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+/// Largest statsd packet; fits into the MTU of most networks
+const STATSD_PACKET_BYTES: usize = 1432;
+
+/// Tables of the ClickHouse sink, created when it's opened
+pub const CLICKHOUSE_DDL: &str = include_str!("../../clickhouse.sql");
+
+/// Where metrics are published to, e.g.
+/// `influx:http://localhost:8086/api/v2/write?org=ops&bucket=st2`
+#[derive(Clone, Debug, PartialEq)]
+pub enum SinkSpec {
+    /// POSTs batches of InfluxDB line protocol to a write URL, e.g. of InfluxDB
+    /// (`/write` or `/api/v2/write`) or VictoriaMetrics (`/write`)
+    Influx(String),
+    /// Sends statsd metrics over UDP to an address, e.g. `localhost:8125`.
+    /// Labels are appended to metric names.
+    Statsd(String),
+    /// Sends DogStatsD metrics over UDP to an address, e.g. `localhost:8125`.
+    /// Labels and configured tags become tags.
+    DogStatsd(String),
+    /// Inserts PAG edges and metrics into the tables of `CLICKHOUSE_DDL` through
+    /// ClickHouse's HTTP interface, e.g. `http://localhost:8123/?database=st2`
+    ClickHouse(String),
+    /// Appends per-epoch summaries and samples to a local SQLite database
+    /// (cf. `history`; requires the `sqlite` feature)
+    Sqlite(PathBuf),
+    /// Appends a JSON line per epoch with its samples to a file, or writes it to
+    /// stdout for `-`
+    JsonLines(PathBuf),
+}
+
+impl std::str::FromStr for SinkSpec {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, target) = match s.find(':') {
+            Some(i) if i + 1 < s.len() => (&s[.. i], s[i + 1 ..].to_string()),
+            _ => ("", String::new()),
+        };
+        match scheme {
+            "influx" => Ok(SinkSpec::Influx(target)),
+            "statsd" => Ok(SinkSpec::Statsd(target)),
+            "dogstatsd" => Ok(SinkSpec::DogStatsd(target)),
+            "clickhouse" => Ok(SinkSpec::ClickHouse(target)),
+            "sqlite" => Ok(SinkSpec::Sqlite(PathBuf::from(target))),
+            "jsonl" => Ok(SinkSpec::JsonLines(PathBuf::from(target))),
+            _ => Err(STError::Config(format!("{}: expected influx:URL, statsd:HOST:PORT, dogstatsd:HOST:PORT, clickhouse:URL, sqlite:PATH, or jsonl:PATH", s))),
+        }
+    }
+}
+
+/// How finely a sink's samples are broken down, to bound the number of series
+/// it gets, e.g. for dataflows with many operators
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    /// All samples: per operator, team, tag, activity type, and worker
+    Operator,
+    /// Like `Operator`, but samples labeled with an `operator` are summed per
+    /// scope of the operator instead (label `scope`, cf. `Scopes`)
+    Scope,
+    /// Only the summary metrics and the samples per activity type
+    Activity,
+    /// Only the summary metrics, without labels
+    Summary,
+}
+
+impl std::str::FromStr for Level {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            // `detail` is what `operator` used to be called
+            "operator" | "detail" => Ok(Level::Operator),
+            "scope" => Ok(Level::Scope),
+            "activity" => Ok(Level::Activity),
+            "summary" => Ok(Level::Summary),
+            _ => Err(STError::Config(format!("invalid level={} (expected operator, scope, activity, or summary)", s))),
+        }
+    }
+}
+
+/// Scopes of the source computation's operators by the labels samples carry
+/// for them (cf. `store::epoch_samples`). An operator's scope is the path of
+/// the scope operators its recorded address is nested in (cf.
+/// `st2_timely::operators`), e.g. `Dataflow/Iterate` for an operator at
+/// `[0, 3, 2]` if the operators at `[0]` and `[0, 3]` are named `Dataflow`
+/// and `Iterate`. Operators that share a label get the scope of the first one.
+pub struct Scopes {
+    scopes: HashMap<String, String>,
+}
+
+impl Scopes {
+    /// The scopes of the operators replay has seen so far, labeled by their
+    /// names in `operator_names` (cf. `labels::name`) or their ids
+    pub fn new(operator_names: &BTreeMap<u64, String>) -> Self {
+        let operators = st2_timely::operators::all();
+        let name = |id: usize, operator: &st2_timely::operators::Operator| {
+            crate::labels::name(id as u64, operator_names).unwrap_or_else(|| operator.name.clone())
+        };
+        let by_addr: HashMap<&[usize], (usize, &st2_timely::operators::Operator)> = operators.iter()
+            .map(|(id, operator)| (&operator.addr[..], (*id, operator)))
+            .collect();
+
+        let mut scopes = HashMap::new();
+        for (id, operator) in operators.iter() {
+            let path: Vec<String> = (1 .. operator.addr.len())
+                .filter_map(|len| by_addr.get(&operator.addr[.. len]).map(|(id, scope)| name(*id, scope)))
+                .collect();
+            let label = crate::labels::name(*id as u64, operator_names).unwrap_or_else(|| id.to_string());
+            let scope = if path.is_empty() { "dataflow".to_string() } else { path.join("/") };
+            scopes.entry(label).or_insert(scope);
+        }
+        Scopes { scopes }
+    }
+
+    /// The scope of the operator labeled `operator`. Labels of no known
+    /// operator fall back to their name up to the last `/`, e.g. `Iterate`
+    /// for `Iterate/Join` (cf. `flamegraph`), or `dataflow` for plain names.
+    pub fn of(&self, operator: &str) -> String {
+        if let Some(scope) = self.scopes.get(operator) {
+            return scope.clone();
+        }
+        let name = crate::sources::split(operator).0;
+        name.rfind('/').map_or("dataflow", |i| &name[.. i]).to_string()
+    }
+}
+
+impl Level {
+    /// Whether `sample` is kept at this level
+    fn keeps(self, sample: &Sample) -> bool {
+        match self {
+            Level::Operator | Level::Scope => true,
+            Level::Activity => sample.labels.keys().all(|key| key == "activity"),
+            Level::Summary => sample.labels.is_empty(),
+        }
+    }
+
+    /// Aggregates the kept `samples` of an epoch to this level, with operators
+    /// labeled by their names in `operator_names`
+    fn aggregate(self, samples: impl Iterator<Item = Sample>, operator_names: &BTreeMap<u64, String>) -> Vec<Sample> {
+        if self != Level::Scope {
+            return samples.collect();
+        }
+
+        // replay may have seen further operators since the last epoch
+        let operator_scopes = Scopes::new(operator_names);
+        let mut aggregated: Vec<Sample> = Vec::new();
+        let mut scopes = BTreeMap::new();
+        for mut sample in samples {
+            let operator = match sample.labels.remove("operator") {
+                Some(operator) => operator,
+                None => {
+                    aggregated.push(sample);
+                    continue;
+                }
+            };
+            sample.labels.insert("scope".to_string(), operator_scopes.of(&operator));
+            match scopes.get(&(sample.name, sample.labels.clone())) {
+                Some(&i) => aggregated[i].value += sample.value,
+                None => {
+                    scopes.insert((sample.name, sample.labels.clone()), aggregated.len());
+                    aggregated.push(sample);
+                }
+            }
+        }
+        aggregated
+    }
+}
+
+/// Which samples a sink gets
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleFilter {
+    /// Names of the published metrics; a trailing `*` matches any suffix. Empty for all metrics.
+    pub metrics: Vec<String>,
+    /// How finely the published samples are broken down
+    pub level: Level,
+    /// Only every `every`th epoch is published
+    pub every: u64,
+}
+
+impl Default for SampleFilter {
+    fn default() -> Self {
+        SampleFilter { metrics: Vec::new(), level: Level::Operator, every: 1 }
+    }
+}
+
+impl SampleFilter {
+    /// Whether the samples of `epoch` are published
+    pub fn accepts_epoch(&self, epoch: u64) -> bool {
+        epoch % self.every == 0
+    }
+
+    /// Whether `sample` is published, before it's aggregated to the `level`
+    pub fn accepts(&self, sample: &Sample) -> bool {
+        let metric = self.metrics.is_empty() || self.metrics.iter().any(|metric| if metric.ends_with('*') {
+            sample.name.starts_with(&metric[.. metric.len() - 1])
+        } else {
+            sample.name == metric
+        });
+        metric && self.level.keeps(sample)
+    }
+
+    /// The published ones of an epoch's `samples`, aggregated to the `level`,
+    /// with operators labeled by their names in `operator_names`
+    pub fn apply(&self, samples: &[Sample], operator_names: &BTreeMap<u64, String>) -> Vec<Sample> {
+        self.level.aggregate(samples.iter().filter(|sample| self.accepts(sample)).cloned(), operator_names)
+    }
+}
+
+/// A sink with its filter, given as the sink followed by whitespace-separated options,
+/// e.g. `statsd:localhost:8125 metrics=epoch_latency_ns,operator_* every=10`:
+///
+/// - `metrics=NAME,...`: only these metrics; a trailing `*` matches any suffix
+/// - `level=operator|scope|activity|summary`: how finely samples are broken
+///   down (cf. `Level`); the default, `operator`, publishes all
+/// - `every=N`: only every `N`th epoch
+#[derive(Clone, Debug, PartialEq)]
+pub struct SinkConfig {
+    /// The sink
+    pub spec: SinkSpec,
+    /// Which samples it gets
+    pub filter: SampleFilter,
+}
+
+impl std::str::FromStr for SinkConfig {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = s.split_whitespace();
+        let spec = tokens.next().unwrap_or("").parse()?;
+        let mut filter = SampleFilter::default();
+        for option in tokens {
+            let mut parts = option.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("metrics"), Some(metrics)) => {
+                    filter.metrics = metrics.split(',').filter(|metric| !metric.is_empty()).map(|metric| metric.to_string()).collect();
+                }
+                (Some("level"), Some(level)) => filter.level = level.parse()?,
+                (Some("every"), Some(every)) => match every.parse() {
+                    Ok(every) if every > 0 => filter.every = every,
+                    _ => return Err(STError::Config(format!("invalid every={} (expected a positive number)", every))),
+                },
+                _ => return Err(STError::Config(format!("invalid option {} (expected metrics=NAME,..., level=operator|scope|activity|summary, or every=N)", option))),
+            }
+        }
+        Ok(SinkConfig { spec, filter })
+    }
+}
+
+/// The opened sinks of `SinkConfig`s, which publish completed epochs
+pub(crate) struct Publisher {
+    sinks: Vec<(Box<dyn MetricSink + Send>, SampleFilter)>,
+    /// Names operators are labeled with, cf. `Scopes`
+    operator_names: BTreeMap<u64, String>,
+}
+
+impl Publisher {
+    /// Opens all `sinks`, which label operators by their names in `operator_names`.
+    pub(crate) fn open(sinks: &[SinkConfig], options: &SinkOptions, operator_names: &BTreeMap<u64, String>) -> Result<Self, STError> {
+        let sinks = sinks.iter()
+            .map(|sink| sink.spec.open(options).map(|opened| (opened, sink.filter.clone())))
+            .collect::<Result<_, _>>()?;
+        Ok(Publisher { sinks, operator_names: operator_names.clone() })
+    }
+
+    /// Publishes `epoch`, i.e., its PAG `edges` and its `samples`, to every sink
+    /// whose filter accepts it. Failures are logged.
+    pub(crate) fn publish(&mut self, epoch: u64, edges: &[PagEdge], samples: &[Sample]) {
+        for (sink, filter) in self.sinks.iter_mut() {
+            if !filter.accepts_epoch(epoch) {
+                continue;
+            }
+            let samples = filter.apply(samples, &self.operator_names);
+            if let Err(e) = sink.publish_edges(epoch, edges).and_then(|_| sink.publish(&samples)) {
+                error!("couldn't publish metrics of epoch {}: {}", epoch, e);
+            }
+        }
+    }
+}
+
+/// Options of all sinks
+#[derive(Clone, Debug)]
+pub struct SinkOptions {
+    /// Maximum number of samples (or edges) per request
+    pub batch: usize,
+    /// Number of retries, with exponential backoff, of failed requests
+    pub retries: u32,
+    /// Headers of HTTP requests, e.g. `Authorization: Token ...`
+    pub headers: Vec<(String, String)>,
+    /// Tags added to all DogStatsD metrics, e.g. `env` → `prod`
+    pub tags: Vec<(String, String)>,
+}
+
+impl Default for SinkOptions {
+    fn default() -> Self {
+        SinkOptions { batch: 5000, retries: 3, headers: Vec::new(), tags: Vec::new() }
+    }
+}
+
+impl SinkSpec {
+    /// Opens the sink.
+    pub fn open(&self, options: &SinkOptions) -> Result<Box<dyn MetricSink + Send>, STError> {
+        match self {
+            SinkSpec::Influx(url) => Ok(Box::new(InfluxSink::new(url.clone(), options))),
+            SinkSpec::Statsd(address) => Ok(Box::new(StatsdSink::new(address, None)?)),
+            SinkSpec::DogStatsd(address) => Ok(Box::new(StatsdSink::new(address, Some(options.tags.clone()))?)),
+            SinkSpec::ClickHouse(url) => Ok(Box::new(ClickHouseSink::new(url, options)?)),
+            SinkSpec::Sqlite(path) => Ok(Box::new(History::open(path)?)),
+            SinkSpec::JsonLines(path) if path == Path::new("-") => Ok(Box::new(JsonLinesSink { out: Box::new(std::io::stdout()) })),
+            SinkSpec::JsonLines(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)
+                    .map_err(|e| STError::io(format!("couldn't open {}", path.display()), e))?;
+                Ok(Box::new(JsonLinesSink { out: Box::new(file) }))
+            }
+        }
+    }
+}
+
+/// A destination of per-epoch metrics
+pub trait MetricSink {
+    /// Publishes the samples of a completed epoch. Sinks may batch samples; they
+    /// are published at the latest when the sink is dropped.
+    fn publish(&mut self, samples: &[Sample]) -> Result<(), STError>;
+
+    /// Publishes the PAG edges of a completed epoch, before its samples. Most sinks
+    /// only publish metrics and ignore edges.
+    fn publish_edges(&mut self, _epoch: u64, _edges: &[PagEdge]) -> Result<(), STError> {
+        Ok(())
+    }
+}
+
+/// POSTs request bodies from a background thread, so slow or unavailable endpoints
+/// don't stall the analysis. Queued requests are sent before the poster is dropped.
+struct Poster {
+    send: Option<mpsc::Sender<(String, String, usize)>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Poster {
+    fn new(options: &SinkOptions) -> Self {
+        let (sender, receiver) = mpsc::channel::<(String, String, usize)>();
+        let options = options.clone();
+        let thread = std::thread::spawn(move || {
+            for (url, body, rows) in receiver {
+                if let Err(e) = post(&url, &body, &options) {
+                    error!("couldn't publish {} rows: {}", rows, e);
+                }
+            }
+        });
+        Poster { send: Some(sender), thread: Some(thread) }
+    }
+
+    /// Queues `body`, holding `rows` rows, to be POSTed to `url`.
+    fn post(&self, url: &str, body: String, rows: usize) -> Result<(), STError> {
+        self.send.as_ref().expect("poster is open").send((url.to_string(), body, rows))
+            .map_err(|_| STError::Analysis("publishing thread stopped".to_string()))
+    }
+}
+
+impl Drop for Poster {
+    fn drop(&mut self) {
+        self.send.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// POSTs `body` to `url` with the configured headers and retries.
+fn post(url: &str, body: &str, options: &SinkOptions) -> Result<(), STError> {
+    send("POST", url, options.retries, || {
+        let mut request = ureq::post(url);
+        request.timeout(HTTP_TIMEOUT).set("Content-Type", "text/plain; charset=utf-8");
+        for (name, value) in options.headers.iter() {
+            request.set(name, value);
+        }
+        request.send_string(body)
+    })
+}
+
+/// Publishes samples as InfluxDB line protocol: a measurement per metric, with the
+/// sample's labels as tags and its value and epoch as fields, e.g.
+/// `operator_critical_path_ns,operator=Map value=1200,epoch=7i 1588000000000000000`.
+struct InfluxSink {
+    url: String,
+    batch: usize,
+    lines: Vec<String>,
+    poster: Poster,
+}
+
+impl InfluxSink {
+    fn new(url: String, options: &SinkOptions) -> Self {
+        InfluxSink { url, batch: options.batch, lines: Vec::new(), poster: Poster::new(options) }
+    }
+
+    fn flush(&mut self) -> Result<(), STError> {
+        if self.lines.is_empty() {
+            return Ok(());
+        }
+        let lines = std::mem::replace(&mut self.lines, Vec::new());
+        self.poster.post(&self.url, lines.join("\n"), lines.len())
+    }
+}
+
+impl MetricSink for InfluxSink {
+    fn publish(&mut self, samples: &[Sample]) -> Result<(), STError> {
+        self.lines.extend(samples.iter().map(line_protocol));
+        if self.lines.len() >= self.batch {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for InfluxSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("couldn't publish samples: {}", e);
+        }
+    }
+}
+
+/// Inserts edges into `st2_edges` and samples into `st2_metrics` (cf. `CLICKHOUSE_DDL`)
+/// as `JSONEachRow`, in batches of each table.
+struct ClickHouseSink {
+    url: String,
+    batch: usize,
+    edges: Vec<String>,
+    samples: Vec<String>,
+    poster: Poster,
+}
+
+impl ClickHouseSink {
+    /// Creates the sink's tables, unless they exist.
+    fn new(url: &str, options: &SinkOptions) -> Result<Self, STError> {
+        let statements = CLICKHOUSE_DDL.split(';')
+            .map(|statement| statement.lines().filter(|line| !line.trim_start().starts_with("--")).collect::<Vec<_>>().join("\n"))
+            .filter(|statement| !statement.trim().is_empty());
+        for statement in statements {
+            post(url, &statement, options)
+                .map_err(|e| e.context("couldn't create ClickHouse tables"))?;
+        }
+        Ok(ClickHouseSink { url: url.to_string(), batch: options.batch, edges: Vec::new(), samples: Vec::new(), poster: Poster::new(options) })
+    }
+
+    /// The URL inserting `JSONEachRow` rows into `table`
+    fn insert_url(&self, table: &str) -> String {
+        let query = format!("INSERT INTO {} FORMAT JSONEachRow", table).replace(' ', "%20");
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        format!("{}{}query={}", self.url, separator, query)
+    }
+
+    fn flush_edges(&mut self) -> Result<(), STError> {
+        if self.edges.is_empty() {
+            return Ok(());
+        }
+        let rows = std::mem::replace(&mut self.edges, Vec::new());
+        self.poster.post(&self.insert_url("st2_edges"), rows.join("\n"), rows.len())
+    }
+
+    fn flush_samples(&mut self) -> Result<(), STError> {
+        if self.samples.is_empty() {
+            return Ok(());
+        }
+        let rows = std::mem::replace(&mut self.samples, Vec::new());
+        self.poster.post(&self.insert_url("st2_metrics"), rows.join("\n"), rows.len())
+    }
+}
+
+impl MetricSink for ClickHouseSink {
+    fn publish(&mut self, samples: &[Sample]) -> Result<(), STError> {
+        self.samples.extend(samples.iter().map(|sample| serde_json::to_string(sample).expect("samples are serializable")));
+        if self.samples.len() >= self.batch {
+            self.flush_samples()?;
+        }
+        Ok(())
+    }
+
+    fn publish_edges(&mut self, epoch: u64, edges: &[PagEdge]) -> Result<(), STError> {
+        for edge in edges {
+            let row = edge_row(epoch, edge);
+            let object: Map<String, Value> = EDGE_COLUMNS.iter().zip(row.fields.into_iter())
+                .map(|((name, _), field)| (name.to_string(), match field {
+                    Field::U64(value) => json!(value),
+                    Field::Str(value) => json!(value),
+                    Field::Null => Value::Null,
+                }))
+                .collect();
+            self.edges.push(Value::Object(object).to_string());
+        }
+        if self.edges.len() >= self.batch {
+            self.flush_edges()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ClickHouseSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_edges().and_then(|_| self.flush_samples()) {
+            error!("couldn't publish to ClickHouse: {}", e);
+        }
+    }
+}
+
+/// Writes a JSON line `{"epoch": .., "samples": [..]}` per epoch, flushed right away
+struct JsonLinesSink {
+    out: Box<dyn Write + Send>,
+}
+
+impl MetricSink for JsonLinesSink {
+    fn publish(&mut self, samples: &[Sample]) -> Result<(), STError> {
+        let epoch = match samples.first() {
+            Some(sample) => sample.epoch,
+            None => return Ok(()),
+        };
+        let mut line = serde_json::to_vec(&json!({ "epoch": epoch, "samples": samples })).expect("samples are serializable");
+        line.push(b'\n');
+        self.out.write_all(&line).and_then(|_| self.out.flush())
+            .map_err(|e| STError::io("couldn't write samples", e))
+    }
+}
+
+impl MetricSink for History {
+    fn publish(&mut self, samples: &[Sample]) -> Result<(), STError> {
+        match samples.first() {
+            Some(sample) => self.insert_epoch(sample.epoch, samples),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `sample` as a line of InfluxDB line protocol
+fn line_protocol(sample: &Sample) -> String {
+    let escape = |s: &str, special: &[char]| {
+        let mut escaped = String::with_capacity(s.len());
+        for c in s.chars() {
+            if special.contains(&c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    };
+
+    let mut line = escape(sample.name, &[',', ' ']);
+    for (key, value) in sample.labels.iter() {
+        line.push_str(&format!(",{}={}", escape(key, &[',', '=', ' ']), escape(value, &[',', '=', ' '])));
+    }
+    line.push_str(&format!(" value={},epoch={}i {}", sample.value, sample.epoch, sample.timestamp));
+    line
+}
+
+/// Publishes samples as statsd metrics, prefixed with `st2.`: durations (`*_ns`) as
+/// timers in ms, e.g. `st2.epoch_latency:1.2|ms`, other metrics as gauges, each
+/// operator's share of the critical path as the gauge `st2.operator_critical_path_share`,
+/// and the counter `st2.epochs`. DogStatsD metrics carry their labels and the
+/// configured tags as tags, e.g. `st2.operator_critical_path:0.8|ms|#operator:Map,env:prod`;
+/// plain statsd metrics carry their labels' values in their name, e.g.
+/// `st2.operator_critical_path.Map:0.8|ms`. Metrics are packed into as few UDP
+/// packets as possible; lost packets aren't detected.
+struct StatsdSink {
+    socket: UdpSocket,
+    /// `None` for plain statsd
+    tags: Option<Vec<(String, String)>>,
+}
+
+impl StatsdSink {
+    fn new(address: &str, tags: Option<Vec<(String, String)>>) -> Result<Self, STError> {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect(address).map(|_| socket))
+            .map_err(|e| STError::io(format!("couldn't connect to statsd at {}", address), e))?;
+        Ok(StatsdSink { socket, tags })
+    }
+
+    /// `name` with `labels` as a statsd metric of `kind` (e.g. `ms` or `g`)
+    fn metric(&self, name: &str, labels: &BTreeMap<String, String>, value: f64, kind: &str) -> String {
+        let sanitize = |s: &str| s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect::<String>();
+        match self.tags {
+            Some(ref tags) => {
+                let tags: Vec<String> = labels.iter().chain(tags.iter().map(|(key, value)| (key, value)))
+                    .map(|(key, value)| format!("{}:{}", sanitize(key), value.replace(|c: char| c == ',' || c == '|' || c == '\n', "_")))
+                    .collect();
+                if tags.is_empty() {
+                    format!("st2.{}:{}|{}", name, value, kind)
+                } else {
+                    format!("st2.{}:{}|{}|#{}", name, value, kind, tags.join(","))
+                }
+            }
+            None => {
+                let mut metric = format!("st2.{}", name);
+                for value in labels.values() {
+                    metric.push('.');
+                    metric.push_str(&sanitize(value));
+                }
+                format!("{}:{}|{}", metric, value, kind)
+            }
+        }
+    }
+
+    fn send(&self, packet: &str) -> Result<(), STError> {
+        self.socket.send(packet.as_bytes()).map(|_| ())
+            .map_err(|e| STError::io("couldn't send to statsd", e))
+    }
+}
+
+impl MetricSink for StatsdSink {
+    fn publish(&mut self, samples: &[Sample]) -> Result<(), STError> {
+        let critical_path = samples.iter().find(|sample| sample.name == "critical_path_ns").map_or(0.0, |sample| sample.value);
+
+        let mut metrics = vec![self.metric("epochs", &BTreeMap::new(), 1.0, "c")];
+        for sample in samples {
+            if sample.name.ends_with("_ns") {
+                let name = &sample.name[.. sample.name.len() - "_ns".len()];
+                metrics.push(self.metric(name, &sample.labels, sample.value / 1_000_000.0, "ms"));
+            } else {
+                metrics.push(self.metric(sample.name, &sample.labels, sample.value, "g"));
+            }
+            if sample.name == "operator_critical_path_ns" && critical_path > 0.0 {
+                metrics.push(self.metric("operator_critical_path_share", &sample.labels, sample.value / critical_path, "g"));
+            }
+        }
+
+        let mut packet = String::new();
+        for metric in metrics {
+            if !packet.is_empty() && packet.len() + 1 + metric.len() > STATSD_PACKET_BYTES {
+                self.send(&packet)?;
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&metric);
+        }
+        if !packet.is_empty() {
+            self.send(&packet)?;
+        }
+        Ok(())
+    }
+}
+
+/// The `publish` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("publish")
+        .about("Publish per-epoch and per-operator metrics to external systems, e.g. InfluxDB or statsd")
+        .after_help("SINKS:
+    influx:URL    POST InfluxDB line protocol to a write URL, e.g.
+                  influx:http://localhost:8086/api/v2/write?org=ops&bucket=st2&precision=ns
+                  (InfluxDB 2), influx:http://localhost:8086/write?db=st2 (InfluxDB 1),
+                  or influx:http://localhost:8428/write (VictoriaMetrics)
+    statsd:HOST:PORT
+                  Send statsd timers and gauges over UDP, e.g. statsd:localhost:8125
+    dogstatsd:HOST:PORT
+                  Send DogStatsD timers and gauges with tags over UDP,
+                  e.g. dogstatsd:localhost:8125
+    clickhouse:URL
+                  Insert PAG edges and metrics into ClickHouse over HTTP, e.g.
+                  clickhouse:http://localhost:8123/?database=st2; creates the
+                  tables st2_edges and st2_metrics (see st2/clickhouse.sql)
+    sqlite:PATH   Append per-epoch summaries and metrics to a SQLite database,
+                  created if necessary (requires the `sqlite` feature)
+    jsonl:PATH    Append a JSON line with the samples of every epoch to PATH,
+                  or write it to stdout for jsonl:-
+
+    A sink may be followed by options that filter what it gets, e.g.
+    \"statsd:localhost:8125 metrics=epoch_latency_ns,operator_* every=10\":
+    metrics=NAME,...     only these metrics; a trailing * matches any suffix
+    level=LEVEL          how finely samples are broken down: operator (all,
+                         the default), scope (per-operator samples summed per
+                         scope, e.g. Dataflow/Iterate), activity (only
+                         summary and per-activity-type metrics), or summary
+                         (only the epochs' summary metrics, without labels)
+    every=N              only every Nth epoch")
+        .arg(clap::Arg::with_name("sink")
+            .long("sink")
+            .value_name("SINK")
+            .help("Where to publish metrics to (see SINKS below); may be given multiple times")
+            .multiple(true)
+            .number_of_values(1)
+            .required(true))
+        .arg(clap::Arg::with_name("batch")
+            .long("batch")
+            .value_name("SAMPLES")
+            .help("Maximum number of samples (or edges) per request")
+            .default_value("5000"))
+        .arg(clap::Arg::with_name("retries")
+            .long("retries")
+            .value_name("N")
+            .help("Number of retries, with exponential backoff, of failed requests")
+            .default_value("3"))
+        .arg(clap::Arg::with_name("header")
+            .long("header")
+            .value_name("NAME:VALUE")
+            .help("Header of HTTP requests, e.g. \"Authorization: Token ...\"; may be given multiple times")
+            .multiple(true)
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("tag")
+            .long("tag")
+            .value_name("KEY:VALUE")
+            .help("Tag of all DogStatsD metrics, e.g. env:prod; may be given multiple times")
+            .multiple(true)
+            .number_of_values(1))
+}
+
+/// The arguments of `publish`
+pub struct Options {
+    /// Where to publish metrics to
+    pub sinks: Vec<SinkConfig>,
+    /// How to publish them
+    pub sink_options: SinkOptions,
+}
+
+/// Parses the arguments of `publish`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    let sinks = args.all_values_of("sink").into_iter()
+        .map(|sink| sink.parse::<SinkConfig>().map_err(|e| e.context("Invalid --sink")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let batch: usize = match args.value_of("batch").expect("error parsing publish batch args").parse() {
+        Ok(0) => Err(STError::Config("Invalid --batch: has to be at least 1".to_string()))?,
+        Ok(batch) => batch,
+        Err(e) => Err(STError::Config(format!("Invalid --batch: {}", e)))?,
+    };
+    let retries: u32 = args.value_of("retries").expect("error parsing publish retries args")
+        .parse().map_err(|e| STError::Config(format!("Invalid --retries: {}", e)))?;
+    let headers = args.all_values_of("header").into_iter()
+        .map(|header| match header.find(':') {
+            Some(i) => Ok((header[.. i].trim().to_string(), header[i + 1 ..].trim().to_string())),
+            None => Err(STError::Config(format!("Invalid --header: {} (expected NAME:VALUE)", header))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let tags = args.all_values_of("tag").into_iter()
+        .map(|tag| match tag.find(':') {
+            Some(i) => Ok((tag[.. i].to_string(), tag[i + 1 ..].to_string())),
+            None => Err(STError::Config(format!("Invalid --tag: {} (expected KEY:VALUE)", tag))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Options {
+        sinks,
+        sink_options: SinkOptions { batch, retries, headers, tags },
+    })
+}
+
+/// Publishes the metrics of every completed epoch of `replay_source`
+/// (cf. `store::METRICS`) to all `sinks` at once, each getting the samples its
+/// filter accepts. Returns the number of published epochs.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    sinks: Vec<SinkConfig>,
+    options: SinkOptions,
+    operator_names: &BTreeMap<u64, String>,
+    output_format: OutputFormat) -> Result<u64, STError> {
+
+    let published = Arc::new(AtomicU64::new(0));
+    let counted = Arc::clone(&published);
+    let operator_names = operator_names.clone();
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let operator_names = operator_names.clone();
+        let counted = Arc::clone(&counted);
+
+        // only the first peer publishes metrics
+        let mut publisher = if index == 0 {
+            Publisher::open(&sinks, &options, &operator_names).expect("couldn't open metric sinks")
+        } else {
+            Publisher::open(&[], &options, &operator_names).expect("no sinks to open")
+        };
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
+
+            completed_epochs(&pag, "Publish", move |epoch, edges, ahead| {
+                let samples = epoch_samples(epoch, &edges, ahead, &operator_names);
+                publisher.publish(epoch, &edges, &samples);
+                counted.fetch_add(1, Ordering::Relaxed);
+            });
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    let epochs = published.load(Ordering::Acquire);
+    output_format.print(
+        format_args!("Published the metrics of {} epochs", epochs),
+        json!({ "epochs": epochs }));
+    Ok(epochs)
+}
@@ -0,0 +1,680 @@
+use crate::pag::PagEdge;
+use crate::commands::metrics::operator_label;
+use crate::commands::snapshot::critical_path;
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+/// Columns of the tables `from edges` and `from cp` start with
+const EDGE_COLUMNS: [&str; 10] = ["epoch", "worker", "dst_worker", "operator", "type", "traverse", "start", "end", "duration", "records"];
+
+/// The `query` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("query")
+        .about("Evaluate a PAG query, e.g. `from cp | group by operator | sum(duration) | sort sum(duration) desc`")
+        .after_help("QUERIES:
+    query     := 'from' ('edges' | 'cp') ('|' stage)*
+    stage     := 'where' condition ('and' condition)*
+               | 'group' 'by' column (',' column)*
+               | aggregate (',' aggregate)*
+               | 'sort' column ['asc' | 'desc']
+               | 'limit' N
+               | 'select' column (',' column)*
+    condition := column ('=' | '!=' | '<' | '<=' | '>' | '>=') literal
+    aggregate := 'count' | ('sum' | 'avg' | 'min' | 'max') '(' column ')'
+
+    `edges` are all PAG edges, `cp` the edges of every epoch's critical path, with the columns
+    epoch, worker, dst_worker, operator, type, traverse, start, end, duration, and records.
+
+SQL:
+    With --sql (requires building with `--features sql`), `edges` and `cp` are tables with the columns
+    epoch, worker, dst_worker, operator, operator_name, activity, traverse, start_ns, end_ns,
+    duration_ns, and records, e.g. `SELECT operator_name, SUM(duration_ns) FROM cp GROUP BY operator_name`.")
+        .arg(clap::Arg::with_name("expr")
+            .short("e")
+            .long("expr")
+            .value_name("QUERY")
+            .help("The query to evaluate")
+            .required_unless("sql")
+            .conflicts_with("sql"))
+        .arg(clap::Arg::with_name("sql")
+            .long("sql")
+            .value_name("SQL")
+            .help("An SQL query to evaluate instead, over the tables `edges` and `cp`"))
+        .arg(clap::Arg::with_name("pag")
+            .value_name("PAG")
+            .help("Path to the *.dump files of a trace (without trailing /), or a JSON file written by `snapshot`")
+            .required(true))
+}
+
+/// The arguments of `query`
+pub struct Options {
+    /// The query (`--expr`)
+    pub query: Option<Query>,
+    /// The SQL query, unless `query` is given
+    pub sql: Option<String>,
+    /// A snapshot (`*.json`) or a directory of `*.dump` files
+    pub pag: String,
+}
+
+/// Parses the arguments of `query`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        query: args.value_of("expr")
+            .map(|expr| expr.parse::<Query>().map_err(|e| e.context("Invalid --expr")))
+            .transpose()?,
+        sql: args.value_of("sql").map(String::from),
+        pag: args.value_of("pag").expect("error parsing query pag args").to_string(),
+    })
+}
+
+/// Evaluates `query` over `edges` and prints the resulting table to stdout,
+/// showing operators with their `operator_names`, if known. As JSON, the table
+/// is an array of objects, one per row.
+pub fn run(query: &Query, edges: &[PagEdge], operator_names: &BTreeMap<u64, String>, output_format: OutputFormat) -> Result<(), STError> {
+    print(&query.eval(edges, operator_names)?, operator_names, output_format)
+}
+
+/// Prints `table` to stdout, as text or as a JSON array of objects.
+pub fn print(table: &Table, operator_names: &BTreeMap<u64, String>, output_format: OutputFormat) -> Result<(), STError> {
+    match output_format {
+        OutputFormat::Text => table.write(&mut std::io::stdout(), operator_names),
+        OutputFormat::Json => {
+            println!("{}", table.to_json());
+            Ok(())
+        }
+    }
+}
+
+/// A query selecting PAG edges and aggregating over them: a source followed by
+/// a pipeline of stages, each transforming the table of the previous one.
+///
+/// ```text
+/// query     := 'from' ('edges' | 'cp') ('|' stage)*
+/// stage     := 'where' condition ('and' condition)*
+///            | 'group' 'by' column (',' column)*
+///            | aggregate (',' aggregate)*
+///            | 'sort' column ['asc' | 'desc']
+///            | 'limit' N
+///            | 'select' column (',' column)*
+/// condition := column ('=' | '!=' | '<' | '<=' | '>' | '>=') literal
+/// aggregate := 'count' | ('sum' | 'avg' | 'min' | 'max') '(' column ')'
+/// ```
+///
+/// `edges` are all edges of the PAG, `cp` the edges of every epoch's critical path
+/// (cf. `snapshot::critical_path`), with the columns `epoch`, `worker`, `dst_worker`,
+/// `operator`, `type`, `traverse`, `start`, `end`, `duration`, and `records`. `start`
+/// and `end` are relative to the start of the trace. Times are written like `1.2s`
+/// or `300ms`, operators by id or name, and text compares case-insensitively.
+///
+/// A `group by` stage is followed by the aggregates computed per group (`count` if
+/// there are none), whose columns are named like `count` or `sum(duration)`. Without
+/// `group by`, aggregates are computed over the whole table. For example,
+///
+/// ```text
+/// from cp | where epoch >= 100 and epoch < 200 | group by operator | sum(duration), count | sort sum(duration) desc | limit 5
+/// ```
+///
+/// ranks the operators by their time on the critical paths of epochs 100..200.
+#[derive(Debug, PartialEq)]
+pub struct Query {
+    source: Source,
+    stages: Vec<Stage>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Source {
+    Edges,
+    CriticalPaths,
+}
+
+#[derive(Debug, PartialEq)]
+enum Stage {
+    Where(Vec<Condition>),
+    Group(Vec<String>),
+    Aggregate(Vec<Aggregate>),
+    Sort(String, bool),
+    Limit(usize),
+    Select(Vec<String>),
+}
+
+#[derive(Debug, PartialEq)]
+struct Condition {
+    column: String,
+    op: Op,
+    literal: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op { Eq, Ne, Lt, Le, Gt, Ge }
+
+impl Op {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Op::Eq => ordering == Ordering::Equal,
+            Op::Ne => ordering != Ordering::Equal,
+            Op::Lt => ordering == Ordering::Less,
+            Op::Le => ordering != Ordering::Greater,
+            Op::Gt => ordering == Ordering::Greater,
+            Op::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Aggregate {
+    function: Function,
+    /// `None` for `count`
+    column: Option<String>,
+}
+
+impl Aggregate {
+    fn name(&self) -> String {
+        match &self.column {
+            Some(column) => format!("{:?}({})", self.function, column).to_lowercase(),
+            None => "count".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Function { Count, Sum, Avg, Min, Max }
+
+/// A cell of a query result
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub enum Value {
+    /// No value, e.g. the operator of a message edge
+    Null,
+    /// A count or id
+    Int(u64),
+    /// A time or duration in ns
+    Time(u64),
+    /// An average
+    Float(f64),
+    /// A name
+    Text(String),
+}
+
+impl Value {
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(x) | Value::Time(x) => Some(*x as f64),
+            Value::Float(x) => Some(*x),
+            Value::Null | Value::Text(_) => None,
+        }
+    }
+}
+
+/// The result of a query
+#[derive(Debug, PartialEq)]
+pub struct Table {
+    /// Column names
+    pub columns: Vec<String>,
+    /// Rows, with one value per column
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+    fn column(&self, name: &str) -> Result<usize, STError> {
+        self.columns.iter().position(|c| c == name)
+            .ok_or_else(|| STError::Config(format!("unknown column: {} (available: {})", name, self.columns.join(", "))))
+    }
+
+    /// The table as a JSON array of objects, one per row. Times are in ns.
+    pub fn to_json(&self) -> serde_json::Value {
+        let rows: Vec<_> = self.rows.iter()
+            .map(|row| row.iter().zip(self.columns.iter())
+                 .map(|(value, column)| (column.clone(), match value {
+                     Value::Null => serde_json::Value::Null,
+                     Value::Int(x) | Value::Time(x) => json!(x),
+                     Value::Float(x) => json!(x),
+                     Value::Text(s) => json!(s),
+                 }))
+                 .collect::<serde_json::Map<_, _>>())
+            .collect();
+        json!(rows)
+    }
+
+    /// Writes the table with aligned columns to `out`.
+    pub fn write<W: Write>(&self, out: &mut W, operator_names: &BTreeMap<u64, String>) -> Result<(), STError> {
+        let cells: Vec<Vec<String>> = self.rows.iter()
+            .map(|row| row.iter().zip(self.columns.iter()).map(|(value, column)| match value {
+                Value::Null => "-".to_string(),
+                Value::Int(x) if column == "operator" => operator_label(*x, operator_names),
+                Value::Int(x) => x.to_string(),
+                Value::Time(x) => format!("{:.3}ms", *x as f64 / 1_000_000.0),
+                Value::Float(x) => format!("{:.3}", x),
+                Value::Text(s) => s.clone(),
+            }).collect())
+            .collect();
+
+        let widths: Vec<usize> = self.columns.iter().enumerate()
+            .map(|(i, column)| cells.iter().map(|row| row[i].chars().count()).chain(Some(column.len())).max().unwrap_or(0))
+            .collect();
+        let line = |row: &[String]| row.iter().zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+
+        writeln!(out, "{}", line(&self.columns).trim_end())?;
+        for row in cells.iter() {
+            writeln!(out, "{}", line(row).trim_end())?;
+        }
+        writeln!(out, "({} rows)", self.rows.len())?;
+        Ok(())
+    }
+}
+
+impl Query {
+    /// Evaluates the query over `edges`. Operators may be referred to by their `operator_names`.
+    pub fn eval(&self, edges: &[PagEdge], operator_names: &BTreeMap<u64, String>) -> Result<Table, STError> {
+        let start = edges.iter().map(|edge| edge.source.timestamp).min().unwrap_or_default();
+
+        let rows = match self.source {
+            Source::Edges => edges.iter().map(|edge| edge_row(edge, start)).collect(),
+            Source::CriticalPaths => {
+                let mut epochs: BTreeMap<u64, Vec<PagEdge>> = BTreeMap::new();
+                for edge in edges.iter() {
+                    epochs.entry(edge.source.epoch).or_insert_with(Vec::new).push(edge.clone());
+                }
+                epochs.values()
+                    .flat_map(|edges| critical_path(edges))
+                    .map(|edge| edge_row(&edge, start))
+                    .collect()
+            }
+        };
+        let mut table = Table { columns: EDGE_COLUMNS.iter().map(|c| c.to_string()).collect(), rows };
+
+        let mut group: Option<&[String]> = None;
+        for stage in self.stages.iter() {
+            // a `group by` without aggregates counts
+            if let (Some(keys), false) = (group, matches!(stage, Stage::Aggregate(_))) {
+                table = aggregate(table, keys, &[Aggregate { function: Function::Count, column: None }])?;
+                group = None;
+            }
+
+            match stage {
+                Stage::Where(conditions) => {
+                    let predicates = conditions.iter()
+                        .map(|condition| predicate(&table, condition, operator_names))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    table.rows.retain(|row| predicates.iter().all(|p| p(row)));
+                }
+                Stage::Group(keys) => group = Some(keys.as_slice()),
+                Stage::Aggregate(aggregates) => {
+                    table = aggregate(table, group.unwrap_or(&[]), aggregates)?;
+                    group = None;
+                }
+                Stage::Sort(column, descending) => {
+                    let i = table.column(column)?;
+                    table.rows.sort_by(|a, b| {
+                        let ordering = a[i].partial_cmp(&b[i]).unwrap_or(Ordering::Equal);
+                        if *descending { ordering.reverse() } else { ordering }
+                    });
+                }
+                Stage::Limit(n) => table.rows.truncate(*n),
+                Stage::Select(columns) => {
+                    let indices = columns.iter().map(|c| table.column(c)).collect::<Result<Vec<_>, _>>()?;
+                    table = Table {
+                        columns: columns.clone(),
+                        rows: table.rows.into_iter().map(|row| indices.iter().map(|i| row[*i].clone()).collect()).collect(),
+                    };
+                }
+            }
+        }
+        if let Some(keys) = group {
+            table = aggregate(table, keys, &[Aggregate { function: Function::Count, column: None }])?;
+        }
+
+        Ok(table)
+    }
+}
+
+fn edge_row(edge: &PagEdge, start: Duration) -> Vec<Value> {
+    let relative = |t: Duration| Value::Time(t.checked_sub(start).unwrap_or_default().as_nanos() as u64);
+    vec![
+        Value::Int(edge.source.epoch),
+        Value::Int(edge.source.worker_id),
+        Value::Int(edge.destination.worker_id),
+        edge.operator_id.map(Value::Int).unwrap_or(Value::Null),
+        Value::Text(format!("{:?}", edge.edge_type)),
+        Value::Text(format!("{:?}", edge.traverse)),
+        relative(edge.source.timestamp),
+        relative(edge.destination.timestamp),
+        Value::Time(edge.duration()),
+        edge.length.map(|l| Value::Int(l as u64)).unwrap_or(Value::Null),
+    ]
+}
+
+/// Whether a row satisfies `condition`. The literal is interpreted according to
+/// the column's values; rows without a value never do.
+fn predicate<'a>(table: &Table, condition: &Condition, operator_names: &'a BTreeMap<u64, String>)
+    -> Result<Box<dyn Fn(&[Value]) -> bool + 'a>, STError> {
+
+    let i = table.column(&condition.column)?;
+    let op = condition.op;
+    let sample = table.rows.iter().map(|row| &row[i]).find(|value| **value != Value::Null);
+
+    let literal = match sample {
+        // operators by name
+        Some(Value::Int(_)) if condition.column == "operator" && condition.literal.parse::<u64>().is_err() => {
+            let name = condition.literal.to_lowercase();
+            return Ok(Box::new(move |row: &[Value]| match &row[i] {
+                Value::Int(id) => crate::labels::name(*id, operator_names)
+                    .map_or(false, |n| op.holds(n.to_lowercase().cmp(&name))),
+                _ => false,
+            }));
+        }
+        Some(Value::Int(_)) => Value::Int(parse_literal(condition)?),
+        Some(Value::Time(_)) => Value::Time(parse_time(&condition.literal)?.as_nanos() as u64),
+        Some(Value::Float(_)) => Value::Float(parse_literal(condition)?),
+        Some(Value::Text(_)) => Value::Text(condition.literal.to_lowercase()),
+        // no rows to filter
+        Some(Value::Null) | None => return Ok(Box::new(|_: &[Value]| false)),
+    };
+
+    Ok(Box::new(move |row: &[Value]| {
+        let ordering = match (&row[i], &literal) {
+            (Value::Null, _) => None,
+            (Value::Text(s), Value::Text(literal)) => Some(s.to_lowercase().cmp(literal)),
+            (value, literal) => value.partial_cmp(literal),
+        };
+        ordering.map_or(false, |ordering| op.holds(ordering))
+    }))
+}
+
+fn parse_literal<T: std::str::FromStr>(condition: &Condition) -> Result<T, STError> {
+    condition.literal.parse()
+        .map_err(|_| STError::Config(format!("invalid value of {}: {}", condition.column, condition.literal)))
+}
+
+/// Groups the rows of `table` by the `keys` columns and computes `aggregates` per group.
+fn aggregate(mut table: Table, keys: &[String], aggregates: &[Aggregate]) -> Result<Table, STError> {
+    let keys_at = keys.iter().map(|k| table.column(k)).collect::<Result<Vec<_>, _>>()?;
+    let columns_at = aggregates.iter()
+        .map(|a| a.column.as_ref().map(|c| table.column(c)).transpose())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let key = |row: &Vec<Value>| keys_at.iter().map(|i| row[*i].clone()).collect::<Vec<_>>();
+    table.rows.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap_or(Ordering::Equal));
+
+    let mut rows = Vec::new();
+    let mut group_start = 0;
+    while group_start < table.rows.len() || (rows.is_empty() && keys.is_empty()) {
+        let group_key = table.rows.get(group_start).map(&key).unwrap_or_default();
+        let group_end = table.rows[group_start..].iter().position(|row| key(row) != group_key)
+            .map_or(table.rows.len(), |n| group_start + n);
+        let group = &table.rows[group_start .. group_end];
+
+        let mut row = group_key;
+        for (aggregate, column) in aggregates.iter().zip(columns_at.iter()) {
+            let values = || group.iter().map(|r| &r[column.expect("aggregate without column")]).filter(|v| **v != Value::Null);
+            let value = match aggregate.function {
+                Function::Count => Value::Int(group.len() as u64),
+                Function::Min => values().min_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal)).cloned().unwrap_or(Value::Null),
+                Function::Max => values().max_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal)).cloned().unwrap_or(Value::Null),
+                Function::Sum | Function::Avg => {
+                    let numbers = values().map(|v| v.as_f64()
+                        .ok_or_else(|| STError::Config(format!("cannot compute {} of text", aggregate.name()))))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let sum: f64 = numbers.iter().sum();
+                    let result = if aggregate.function == Function::Sum { sum } else { sum / numbers.len() as f64 };
+                    match values().next() {
+                        _ if numbers.is_empty() => Value::Null,
+                        Some(Value::Time(_)) => Value::Time(result.round() as u64),
+                        Some(Value::Int(_)) if aggregate.function == Function::Sum => Value::Int(result as u64),
+                        _ => Value::Float(result),
+                    }
+                }
+            };
+            row.push(value);
+        }
+        rows.push(row);
+        group_start = group_end;
+    }
+
+    Ok(Table {
+        columns: keys.iter().cloned().chain(aggregates.iter().map(|a| a.name())).collect(),
+        rows,
+    })
+}
+
+/// Parses a time such as `1.2s`, `300ms`, `50us`, or `1.5` (seconds).
+pub fn parse_time(s: &str) -> Result<Duration, STError> {
+    let (number, unit) = match s.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let scale = match unit {
+        "s" => 1.0,
+        "ms" => 1e-3,
+        "us" => 1e-6,
+        "ns" => 1e-9,
+        _ => return Err(STError::Config(format!("invalid time: {} (expected e.g. 1.2s or 300ms)", s))),
+    };
+    match number.parse::<f64>() {
+        Ok(value) if value >= 0.0 && value.is_finite() => Ok(Duration::from_secs_f64(value * scale)),
+        _ => Err(STError::Config(format!("invalid time: {}", s))),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    /// A quoted string
+    Text(String),
+    Symbol(&'static str),
+}
+
+/// The tokens of `s`, with their columns (1-based, in characters), followed by
+/// the column of the end of `s`
+fn tokenize(s: &str) -> Result<(Vec<Token>, Vec<usize>), STError> {
+    const SYMBOLS: [&str; 10] = ["!=", "<=", ">=", "|", ",", "(", ")", "=", "<", ">"];
+
+    let column = |rest: &str| s[.. s.len() - rest.len()].chars().count() + 1;
+    let mut tokens = Vec::new();
+    let mut columns = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        columns.push(column(rest));
+        if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            tokens.push(Token::Symbol(*symbol));
+            rest = &rest[symbol.len() ..];
+        } else if rest.starts_with('"') {
+            let end = rest[1..].find('"')
+                .ok_or_else(|| STError::Config(format!("unterminated string (at column {})", column(rest))))?;
+            tokens.push(Token::Text(rest[1 .. end + 1].to_string()));
+            rest = &rest[end + 2 ..];
+        } else {
+            let end = rest.find(|c: char| c.is_whitespace() || "!<>=|,()\"".contains(c)).unwrap_or(rest.len());
+            if end == 0 {
+                return Err(STError::Config(format!("unexpected character: {} (at column {})", &rest[.. 1], column(rest))));
+            }
+            tokens.push(Token::Word(rest[.. end].to_string()));
+            rest = &rest[end ..];
+        }
+        rest = rest.trim_start();
+    }
+    columns.push(column(rest));
+    Ok((tokens, columns))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    /// Column of every token, and of the end of the query (cf. `tokenize`)
+    columns: Vec<usize>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    /// An error about the token at `pos` (or the end of the query)
+    fn error(&self, pos: usize, message: String) -> STError {
+        STError::Config(format!("{} (at column {})", message, self.columns[pos.min(self.tokens.len())]))
+    }
+
+    fn next(&mut self, expected: &str) -> Result<Token, STError> {
+        let token = self.tokens.get(self.pos).cloned()
+            .ok_or_else(|| self.error(self.pos, format!("unexpected end of query, expected {}", expected)))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    /// Consumes the next token if it is `symbol`.
+    fn eat(&mut self, symbol: &str) -> bool {
+        match self.peek() {
+            Some(Token::Symbol(s)) if *s == symbol => { self.pos += 1; true }
+            Some(Token::Word(w)) if w == symbol => { self.pos += 1; true }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), STError> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            match self.peek() {
+                Some(token) => Err(self.error(self.pos, format!("expected `{}`, found {}", symbol, show(token)))),
+                None => Err(self.error(self.pos, format!("unexpected end of query, expected `{}`", symbol))),
+            }
+        }
+    }
+
+    fn word(&mut self, expected: &str) -> Result<String, STError> {
+        match self.next(expected)? {
+            Token::Word(w) => Ok(w),
+            token => Err(self.error(self.pos - 1, format!("expected {}, found {}", expected, show(&token)))),
+        }
+    }
+
+    /// A column name, including those of aggregates like `sum(duration)`
+    fn column(&mut self) -> Result<String, STError> {
+        let name = self.word("a column")?;
+        if self.eat("(") {
+            let column = self.word("a column")?;
+            self.expect(")")?;
+            Ok(format!("{}({})", name, column))
+        } else {
+            Ok(name)
+        }
+    }
+
+    fn columns(&mut self) -> Result<Vec<String>, STError> {
+        let mut columns = vec![self.column()?];
+        while self.eat(",") {
+            columns.push(self.column()?);
+        }
+        Ok(columns)
+    }
+
+    fn stage(&mut self) -> Result<Stage, STError> {
+        let keyword = self.word("a stage")?;
+        match keyword.as_str() {
+            "where" => {
+                let mut conditions = vec![self.condition()?];
+                while self.eat("and") {
+                    conditions.push(self.condition()?);
+                }
+                Ok(Stage::Where(conditions))
+            }
+            "group" => {
+                self.expect("by")?;
+                Ok(Stage::Group(self.columns()?))
+            }
+            "count" | "sum" | "avg" | "min" | "max" => {
+                self.pos -= 1;
+                let mut aggregates = vec![self.aggregate()?];
+                while self.eat(",") {
+                    aggregates.push(self.aggregate()?);
+                }
+                Ok(Stage::Aggregate(aggregates))
+            }
+            "sort" => {
+                let column = self.column()?;
+                let descending = if self.eat("desc") { true } else { self.eat("asc"); false };
+                Ok(Stage::Sort(column, descending))
+            }
+            "limit" => {
+                let n = self.word("a number")?;
+                n.parse().map(Stage::Limit).map_err(|_| self.error(self.pos - 1, format!("invalid limit: {}", n)))
+            }
+            "select" => Ok(Stage::Select(self.columns()?)),
+            _ => Err(self.error(self.pos - 1, format!("unknown stage: {}, expected where, group by, count, sum, avg, min, max, sort, limit, or select", keyword))),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, STError> {
+        let column = self.column()?;
+        let op = match self.next("a comparison")? {
+            Token::Symbol("=") => Op::Eq,
+            Token::Symbol("!=") => Op::Ne,
+            Token::Symbol("<") => Op::Lt,
+            Token::Symbol("<=") => Op::Le,
+            Token::Symbol(">") => Op::Gt,
+            Token::Symbol(">=") => Op::Ge,
+            token => return Err(self.error(self.pos - 1, format!("expected a comparison, found {}", show(&token)))),
+        };
+        let literal = match self.next("a value")? {
+            Token::Word(w) | Token::Text(w) => w,
+            token => return Err(self.error(self.pos - 1, format!("expected a value, found {}", show(&token)))),
+        };
+        Ok(Condition { column, op, literal })
+    }
+
+    fn aggregate(&mut self) -> Result<Aggregate, STError> {
+        let function = match self.word("an aggregate")?.as_str() {
+            "count" => return Ok(Aggregate { function: Function::Count, column: None }),
+            "sum" => Function::Sum,
+            "avg" => Function::Avg,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            other => return Err(self.error(self.pos - 1, format!("unknown aggregate: {}", other))),
+        };
+        self.expect("(")?;
+        let column = self.word("a column")?;
+        self.expect(")")?;
+        Ok(Aggregate { function, column: Some(column) })
+    }
+}
+
+fn show(token: &Token) -> String {
+    match token {
+        Token::Word(w) => format!("`{}`", w),
+        Token::Text(s) => format!("\"{}\"", s),
+        Token::Symbol(s) => format!("`{}`", s),
+    }
+}
+
+impl std::str::FromStr for Query {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tokens, columns) = tokenize(s)?;
+        let mut parser = Parser { tokens, columns, pos: 0 };
+
+        parser.expect("from")?;
+        let source = match parser.word("`edges` or `cp`")?.as_str() {
+            "edges" => Source::Edges,
+            "cp" => Source::CriticalPaths,
+            other => return Err(parser.error(parser.pos - 1, format!("unknown source: {}, expected edges or cp", other))),
+        };
+
+        let mut stages = Vec::new();
+        while parser.peek().is_some() {
+            parser.expect("|")?;
+            stages.push(parser.stage()?);
+        }
+
+        Ok(Query { source, stages })
+    }
+}
@@ -0,0 +1,272 @@
+use timely::dataflow::{ProbeHandle, Stream};
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::probe::Probe;
+use timely::dataflow::operators::generic::OutputHandle;
+use timely::dataflow::operators::generic::operator::Operator;
+
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+use std::sync::{Arc, atomic::AtomicBool};
+
+use st2_logformat::pair::Pair;
+use st2_logformat::encoding::Encoding;
+use st2_logformat::rotation::{Rotation, RotatingWriter};
+use st2_logformat::trace::{Compression, TraceHeader};
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::connect::CompEvent;
+use st2_timely::replay_throttled::{ReplaySpeed, ReplayThrottled};
+use st2_timely::filter::Filter;
+
+use crate::replay::Recorder;
+use crate::STError;
+use crate::config::Args;
+
+/// What `record` writes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// The source computation's events, one `<source peer>.dump` file per
+    /// source peer, which every analysis reads like the source itself (`-f`)
+    Dump,
+    /// `LogRecord`s, in rotated trace files of every ST2 peer (cf. `record_traces`),
+    /// for `inspect`, `validate`, `convert`, `trim`, `merge`, and `anonymize`
+    Trace {
+        /// Encoding of the trace files
+        encoding: Encoding,
+        /// Compression of the trace files
+        compression: Compression,
+        /// When to start a new trace file, and how many to keep
+        rotation: Rotation,
+    },
+}
+
+/// The `record` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("record")
+        .about("Capture the source computation's events without analyzing them, to analyze them later with -f")
+        .arg(clap::Arg::with_name("out_dir")
+            .short("o")
+            .long("out")
+            .value_name("DIR")
+            .help("Directory to write the recording to")
+            .required(true))
+        .arg(clap::Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .possible_values(&["dump", "st2"])
+            .help("Write *.dump files, which all commands read with -f, or rotated *.st2 trace files of log records, for inspect, validate, convert, trim, and merge")
+            .default_value("dump"))
+        .arg(clap::Arg::with_name("encoding")
+            .long("encoding")
+            .value_name("ENCODING")
+            .help("Encoding of the trace files (abomonation, protobuf, compact), with --format st2")
+            .default_value("compact"))
+        .arg(clap::Arg::with_name("compression")
+            .long("compression")
+            .value_name("COMPRESSION")
+            .possible_values(&["none", "gzip"])
+            .help("Compression of the trace files, with --format st2")
+            .default_value("gzip"))
+        .arg(clap::Arg::with_name("rotate_size")
+            .long("rotate-size")
+            .value_name("MB")
+            .help("Start a new trace file once the current one holds this many (uncompressed) megabytes, with --format st2")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("rotate_age")
+            .long("rotate-age")
+            .value_name("SECS")
+            .help("Start a new trace file once the current one has been written to for this many seconds, with --format st2")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("retain")
+            .long("retain")
+            .value_name("FILES")
+            .help("Delete the oldest trace files of every ST2 peer once it has written more than this many, with --format st2")
+            .takes_value(true))
+}
+
+/// The arguments of `record`
+pub struct Options {
+    /// The directory to record to
+    pub out_dir: PathBuf,
+    /// What to record
+    pub format: Format,
+}
+
+/// Parses the arguments of `record`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    let encoding: Encoding = args.value_of("encoding").expect("error parsing record encoding args")
+        .parse().map_err(|e| STError::Config(format!("Invalid --encoding: {}", e)))?;
+    let compression: Compression = args.value_of("compression").expect("error parsing record compression args")
+        .parse().map_err(|e| STError::Config(format!("Invalid --compression: {}", e)))?;
+    let rotation = Rotation {
+        max_bytes: if let Some(mb) = args.value_of("rotate_size") {
+            Some(mb.parse::<u64>().map_err(|e| STError::Config(format!("Invalid --rotate-size: {}", e)))? * 1024 * 1024)
+        } else {
+            None
+        },
+        max_age: if let Some(secs) = args.value_of("rotate_age") {
+            Some(Duration::from_secs(secs.parse().map_err(|e| STError::Config(format!("Invalid --rotate-age: {}", e)))?))
+        } else {
+            None
+        },
+        retain: if let Some(files) = args.value_of("retain") {
+            Some(files.parse().map_err(|e| STError::Config(format!("Invalid --retain: {}", e)))?)
+        } else {
+            None
+        },
+    };
+
+    Ok(Options {
+        out_dir: PathBuf::from(args.value_of("out_dir").expect("error parsing record output args")),
+        format: match args.value_of("format") {
+            Some("st2") => Format::Trace { encoding, compression, rotation },
+            _ => Format::Dump,
+        },
+    })
+}
+
+/// Captures `replay_source` to files in `out_dir` as `format` without
+/// analyzing it, e.g. to analyze it elsewhere later on.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    out_dir: &Path,
+    format: Format) -> Result<(), STError> {
+
+    std::fs::create_dir_all(out_dir)?;
+    match format {
+        Format::Dump => record_dumps(timely_configuration, replay_source, is_running, out_dir),
+        Format::Trace { encoding, compression, rotation } =>
+            record_traces(timely_configuration, replay_source, is_running, out_dir, encoding, compression, rotation),
+    }
+}
+
+/// Writes the events of every source peer of `replay_source` to
+/// `<out_dir>/<source peer>.dump`, as the source computation would have
+/// written them, so that `-f <out_dir>` replays the recording.
+fn record_dumps(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    out_dir: &Path) -> Result<(), STError> {
+
+    let sources = match &replay_source {
+        ReplaySource::Tcp(sockets) => sockets.lock().expect("sockets poisoned").len(),
+        ReplaySource::Files(files) => files.lock().expect("replay files poisoned").len(),
+    };
+    let paths: Vec<PathBuf> = (0 .. sources).map(|source| out_dir.join(format!("{}.dump", source))).collect();
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let local_index = index % local_peers;
+
+        let readers: Vec<_> = crate::replay::make_readers(replay_source.clone(), local_index, local_peers).expect("couldn't create readers")
+            .into_iter()
+            .enumerate()
+            .map(|(i, reader)| Recorder::create(reader, &paths[i * local_peers + local_index]).expect("couldn't record source"))
+            .collect();
+
+        // the events are only replayed to be recorded, not converted to log records
+        let probe: ProbeHandle<Pair<u64, Duration>> = worker.dataflow(|scope| {
+            let events: Stream<_, CompEvent> = readers.replay_throttled_into(index, scope, Some(Arc::clone(&is_running)), 1, ReplaySpeed::Unbounded);
+            events.probe()
+        });
+
+        while !probe.done() { worker.step_or_park(None); };
+        info!("w{} done", index);
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    Ok(())
+}
+
+/// Writes the `LogRecord`s of `replay_source` to trace files in `out_dir`.
+/// Every ST2 peer writes the records of its share of source peers to its own
+/// sequence of rotated trace files `<out_dir>/<peer>.<sequence number>.st2`.
+/// With a retention enabled (cf. `retention`), the oldest of these files are
+/// deleted in the background, never the ones still being written.
+fn record_traces(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    out_dir: &Path,
+    encoding: Encoding,
+    compression: Compression,
+    rotation: Rotation) -> Result<(), STError> {
+
+    let out_dir = out_dir.to_path_buf();
+
+    let compacted = out_dir.clone();
+    crate::retention::spawn(format!("trace files in {}", out_dir.display()), move |retention| {
+        retention.apply(finished_trace_files(&compacted)?)
+    });
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+
+        // read replayers from TCP stream (online) or file (offline)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        let header = TraceHeader::new(readers.len() as u64, "timely 0.10".to_string(), encoding, compression);
+        let writer = RotatingWriter::new(header, out_dir.join(index.to_string()), rotation).expect("couldn't create trace file");
+        let writer = Rc::new(RefCell::new(Some(writer)));
+
+        let probe: ProbeHandle<Pair<u64, Duration>> = worker.dataflow(|scope| {
+            let writer = Rc::clone(&writer);
+
+            st2_timely::create_lrs(scope, readers, index, Some(Arc::clone(&is_running)), 1, ReplaySpeed::Unbounded, Filter::default())
+                .unary(Pipeline, "Record", move |_, _| {
+                    let mut vector = Vec::new();
+
+                    move |input, _output: &mut OutputHandle<_, (), _>| {
+                        input.for_each(|_cap, data| {
+                            data.swap(&mut vector);
+                            let mut writer = writer.borrow_mut();
+                            let writer = writer.as_mut().expect("trace already finished");
+                            // hosts are interned per trace file rather than per replay
+                            for origin in vector.iter_mut().filter_map(|record| record.origin.as_mut()) {
+                                let host = st2_timely::origins::host_name(origin.host).expect("host of origin not interned");
+                                origin.host = writer.intern(&host);
+                            }
+                            writer.write_batch(&vector).expect("couldn't write trace file");
+                        });
+                    }
+                })
+                .probe()
+        });
+
+        while !probe.done() { worker.step_or_park(None); };
+
+        writer.borrow_mut().take().expect("trace already finished").finish().expect("couldn't finish trace file");
+        info!("w{} done", index);
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    Ok(())
+}
+
+/// The trace files in `dir` except for the newest one of every peer, which might
+/// still be written to
+fn finished_trace_files(dir: &Path) -> Result<Vec<PathBuf>, STError> {
+    let mut files = crate::retention::files_in(dir, |name| name.ends_with(".st2"))?;
+    // zero-padded sequence numbers sort like names
+    files.sort();
+    let peer = |file: &Path| file.file_name().and_then(|name| name.to_str()).and_then(|name| name.split('.').next()).map(str::to_string);
+    let mut finished = Vec::with_capacity(files.len());
+    let mut files = files.into_iter().peekable();
+    while let Some(file) = files.next() {
+        if files.peek().map_or(false, |next| peer(next) == peer(&file)) {
+            finished.push(file);
+        }
+    }
+    Ok(finished)
+}
@@ -0,0 +1,317 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::commands::metrics::operator_label;
+use crate::commands::snapshot::{critical_path, Snapshot};
+use crate::commands::query::{parse_time, Query};
+use crate::commands::sql;
+
+use timely::dataflow::operators::inspect::Inspect;
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex, atomic::AtomicBool};
+use std::time::Duration;
+
+use st2_logformat::ActivityType;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use crate::STError;
+use crate::config::Args;
+
+/// Maximum number of edges printed by `edges`
+const MAX_EDGES: usize = 50;
+
+const HELP: &str = "\
+commands:
+  epochs                                  list epochs and their latencies
+  cp epoch <E>                            critical path of epoch E
+  edges [worker <W>] [epoch <E>] [between <T1> <T2>]
+                                          edges matching all given conditions; times are
+                                          relative to the start of the trace, e.g. 1.2s or 300ms
+  rank <operators|workers|activities> [window <FROM>..<TO>]
+                                          time spent, ranked, over all epochs or epochs FROM..TO
+  from <edges|cp> | <stage> | ...         a query, e.g. `from cp | group by operator | count`;
+                                          cf. `st2 query --help`
+  select ...                              an SQL query over the tables `edges` and `cp`, e.g.
+                                          `select epoch, count(*) from cp group by epoch`
+  help                                    show this help
+  quit                                    leave the REPL";
+
+/// Constructs the PAG of `replay_source` in memory.
+pub fn load_pag(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter) -> Result<Vec<PagEdge>, STError> {
+
+    let edges = Arc::new(Mutex::new(Vec::new()));
+    let collected = Arc::clone(&edges);
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let edges = Arc::clone(&collected);
+
+        // read replayers from file
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone())
+                .inspect(move |(edge, _t, _diff)| edges.lock().unwrap().push(edge.clone()));
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    let edges = std::mem::replace(&mut *edges.lock().unwrap(), Vec::new());
+    Ok(edges)
+}
+
+/// Reads the PAG saved by `snapshot` to `path`.
+pub fn load_snapshot(path: &Path) -> Result<Vec<PagEdge>, STError> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let snapshot: Snapshot = serde_json::from_reader(file)
+        .map_err(|e| STError::Config(format!("Invalid snapshot {}: {}", path.display(), e)))?;
+    Ok(snapshot.edges)
+}
+
+/// The `repl` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("repl")
+        .about("Interactive queries over a loaded PAG, e.g. `cp epoch 17` or `rank operators window 100..200`")
+        .arg(clap::Arg::with_name("pag")
+            .value_name("PAG")
+            .help("Path to the *.dump files of a trace (without trailing /), or a JSON file written by `snapshot`")
+            .required(true))
+}
+
+/// The arguments of `repl`
+pub struct Options {
+    /// A snapshot (`*.json`) or a directory of `*.dump` files
+    pub pag: String,
+}
+
+/// Parses the arguments of `repl`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        pag: args.value_of("pag").expect("error parsing repl pag args").to_string(),
+    })
+}
+
+/// Runs an interactive prompt over `edges` on `stdin` / `stdout`, showing operators
+/// with their `operator_names`, if known.
+pub fn run(edges: Vec<PagEdge>, operator_names: &BTreeMap<u64, String>) -> Result<(), STError> {
+    let session = Session::new(edges, operator_names);
+    println!("Loaded {} edges of {} epochs. Type `help` for help.", session.edges.len(), session.epochs().len());
+
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("st2> ");
+        std::io::stdout().flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        match session.eval(&line, &mut std::io::stdout()) {
+            Ok(true) => (),
+            Ok(false) => return Ok(()),
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
+
+/// A PAG loaded for interactive queries
+pub struct Session<'a> {
+    /// All edges, ordered by source timestamp
+    pub edges: Vec<PagEdge>,
+    /// Timestamp of the earliest event, to which times are relative
+    pub start: Duration,
+    operator_names: &'a BTreeMap<u64, String>,
+}
+
+impl<'a> Session<'a> {
+    /// Creates a session over `edges`.
+    pub fn new(mut edges: Vec<PagEdge>, operator_names: &'a BTreeMap<u64, String>) -> Self {
+        edges.sort_by_key(|edge| (edge.source.timestamp, edge.source.worker_id, edge.destination.timestamp));
+        let start = edges.first().map(|edge| edge.source.timestamp).unwrap_or_default();
+        Session { edges, start, operator_names }
+    }
+
+    /// Evaluates the command `line`, writing its results to `out`.
+    /// Returns whether the session continues.
+    pub fn eval<W: Write>(&self, line: &str, out: &mut W) -> Result<bool, STError> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => (),
+            ["quit"] | ["exit"] => return Ok(false),
+            ["help"] => writeln!(out, "{}", HELP)?,
+            ["epochs"] => {
+                for (epoch, (first, last)) in self.epochs() {
+                    writeln!(out, "epoch {:<8} {:>12.3}ms latency, starting at {}",
+                             epoch, ms(last.checked_sub(first).unwrap_or_default()), self.time(first))?;
+                }
+            }
+            ["cp", "epoch", epoch] => {
+                let epoch = parse::<u64>("epoch", epoch)?;
+                let edges: Vec<_> = self.edges.iter().filter(|edge| edge.source.epoch == epoch).cloned().collect();
+                if edges.is_empty() {
+                    return Err(STError::Analysis(format!("no edges in epoch {}", epoch)));
+                }
+                let path = critical_path(&edges);
+                let total: u64 = path.iter().map(|edge| edge.duration()).sum();
+                for edge in path.iter() {
+                    writeln!(out, "{}", self.show(edge))?;
+                }
+                writeln!(out, "{} edges, {:.3}ms", path.len(), total as f64 / 1_000_000.0)?;
+            }
+            ["edges", conditions @ ..] => {
+                let predicate = self.edge_predicate(conditions)?;
+                let matching: Vec<_> = self.edges.iter().filter(|edge| predicate(edge)).collect();
+                for edge in matching.iter().take(MAX_EDGES) {
+                    writeln!(out, "{}", self.show(edge))?;
+                }
+                if matching.len() > MAX_EDGES {
+                    writeln!(out, "... {} more", matching.len() - MAX_EDGES)?;
+                }
+                writeln!(out, "{} edges", matching.len())?;
+            }
+            ["from", ..] => {
+                let query: Query = line.parse()?;
+                query.eval(&self.edges, self.operator_names)?.write(out, self.operator_names)?;
+            }
+            [first, ..] if first.eq_ignore_ascii_case("select") => {
+                sql::eval(line, &self.edges, self.operator_names)?.write(out, self.operator_names)?;
+            }
+            ["rank", dimension] => self.rank(dimension, 0 .. std::u64::MAX, out)?,
+            ["rank", dimension, "window", window] => {
+                let window = parse_window(window)?;
+                self.rank(dimension, window, out)?
+            }
+            _ => return Err(STError::Config(format!("unknown command: {} (try `help`)", line.trim()))),
+        }
+        Ok(true)
+    }
+
+    /// epoch -> (first, last) timestamp
+    pub fn epochs(&self) -> BTreeMap<u64, (Duration, Duration)> {
+        let mut epochs = BTreeMap::new();
+        for edge in self.edges.iter() {
+            let bounds = epochs.entry(edge.source.epoch).or_insert((edge.source.timestamp, edge.destination.timestamp));
+            bounds.0 = std::cmp::min(bounds.0, edge.source.timestamp);
+            bounds.1 = std::cmp::max(bounds.1, edge.destination.timestamp);
+        }
+        epochs
+    }
+
+    /// Parses the conditions of `edges`.
+    fn edge_predicate(&self, conditions: &[&str]) -> Result<impl Fn(&PagEdge) -> bool, STError> {
+        let mut worker = None;
+        let mut epoch = None;
+        let mut between = None;
+
+        let mut words = conditions.iter();
+        while let Some(word) = words.next() {
+            let mut next = || words.next().ok_or_else(|| STError::Config(format!("missing value of {}", word)));
+            match *word {
+                "worker" => worker = Some(parse::<u64>("worker", next()?)?),
+                "epoch" => epoch = Some(parse::<u64>("epoch", next()?)?),
+                "between" => {
+                    let from = self.start + parse_time(next()?)?;
+                    let to = self.start + parse_time(next()?)?;
+                    between = Some((from, to));
+                }
+                _ => return Err(STError::Config(format!("unknown condition: {}", word))),
+            }
+        }
+
+        Ok(move |edge: &PagEdge| {
+            worker.map_or(true, |w| edge.source.worker_id == w || edge.destination.worker_id == w)
+                && epoch.map_or(true, |e| edge.source.epoch == e)
+                // edges overlapping the interval
+                && between.map_or(true, |(from, to)| edge.source.timestamp < to && edge.destination.timestamp > from)
+        })
+    }
+
+    /// Prints the time spent per `dimension` over the edges of epochs in `window`, ranked.
+    fn rank<W: Write>(&self, dimension: &str, window: Range<u64>, out: &mut W) -> Result<(), STError> {
+        let key: fn(&PagEdge) -> Option<Key> = match dimension {
+            "operators" => |edge| edge.operator_id.map(Key::Operator),
+            "workers" => |edge| Some(Key::Worker(edge.source.worker_id)),
+            "activities" => |edge| Some(Key::Activity(edge.edge_type)),
+            _ => return Err(STError::Config(format!("unknown dimension: {} (expected operators, workers, or activities)", dimension))),
+        };
+
+        let mut totals: BTreeMap<Key, (u64, u64)> = BTreeMap::new();
+        for edge in self.edges.iter().filter(|edge| window.contains(&edge.source.epoch)) {
+            if let Some(key) = key(edge) {
+                let total = totals.entry(key).or_insert((0, 0));
+                *total = (total.0 + 1, total.1 + edge.duration());
+            }
+        }
+        let sum: u64 = totals.values().map(|(_, t)| t).sum();
+
+        let mut ranked: Vec<_> = totals.into_iter().collect();
+        ranked.sort_by_key(|(_, (_, t))| std::cmp::Reverse(*t));
+        writeln!(out, "{:<24}{:>14}{:>14}{:>10}", dimension.trim_end_matches('s'), "#(activities)", "t (ms)", "%")?;
+        for (key, (count, t)) in ranked {
+            let key = match key {
+                Key::Operator(o) => operator_label(o, self.operator_names),
+                Key::Worker(w) => w.to_string(),
+                Key::Activity(a) => format!("{:?}", a),
+            };
+            let share = if sum == 0 { 0.0 } else { t as f64 / sum as f64 };
+            writeln!(out, "{:<24}{:>14}{:>14.3}{:>10.1}", key, count, t as f64 / 1_000_000.0, share * 100.0)?;
+        }
+        Ok(())
+    }
+
+    fn show(&self, edge: &PagEdge) -> String {
+        let operator = edge.operator_id.map(|o| format!(" operator {}", operator_label(o, self.operator_names))).unwrap_or_default();
+        format!("epoch {} w{} {} -> w{} {}: {:?}{} ({:.3}ms)",
+                edge.source.epoch,
+                edge.source.worker_id, self.time(edge.source.timestamp),
+                edge.destination.worker_id, self.time(edge.destination.timestamp),
+                edge.edge_type, operator, edge.duration() as f64 / 1_000_000.0)
+    }
+
+    /// `timestamp` relative to the start of the trace
+    fn time(&self, timestamp: Duration) -> String {
+        format!("{:.6}s", timestamp.checked_sub(self.start).unwrap_or_default().as_secs_f64())
+    }
+}
+
+/// What `rank` aggregates by
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Operator(u64),
+    Worker(u64),
+    Activity(ActivityType),
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_nanos() as f64 / 1_000_000.0
+}
+
+fn parse<T: std::str::FromStr>(what: &str, s: &str) -> Result<T, STError> where T::Err: std::fmt::Display {
+    s.parse().map_err(|e| STError::Config(format!("invalid {}: {}: {}", what, s, e)))
+}
+
+/// Parses an epoch window `FROM..TO`, where either bound may be omitted.
+fn parse_window(s: &str) -> Result<Range<u64>, STError> {
+    let mut bounds = s.splitn(2, "..");
+    let from = bounds.next().filter(|b| !b.is_empty()).map(|b| parse("window", b)).transpose()?.unwrap_or(0);
+    let to = match bounds.next() {
+        Some(b) => if b.is_empty() { std::u64::MAX } else { parse("window", b)? },
+        None => return Err(STError::Config(format!("invalid window: {} (expected FROM..TO)", s))),
+    };
+    Ok(from .. to)
+}
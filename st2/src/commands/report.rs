@@ -0,0 +1,326 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::commands::alerts::EpochStats;
+use crate::commands::export::{activity_color, escape_xml, write_timeline, EdgeRow, TIMELINE_STYLE};
+use crate::commands::flamegraph::stack;
+use crate::commands::heatmap::Heatmap;
+use crate::store::completed_epochs;
+
+use timely::dataflow::Stream;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, atomic::AtomicBool};
+use std::time::Duration;
+
+use serde_json::json;
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use crate::{OutputFormat, STError};
+use crate::commands::parse_epochs;
+use crate::config::Args;
+
+/// Number of operators in the report's table of critical path time
+const TOP_OPERATORS: usize = 10;
+/// Width of the critical path flamegraph, in px
+const FLAMEGRAPH_WIDTH: f64 = 1200.0;
+/// Height of a frame of the critical path flamegraph, in px
+const FRAME_HEIGHT: f64 = 18.0;
+
+/// Everything the report shows, collected at the first ST2 peer
+#[derive(Default)]
+struct Findings {
+    /// Per epoch: latency, critical path duration (both in ns), and skew
+    epochs: BTreeMap<u64, (u64, u64, f64)>,
+    edges: usize,
+    /// Critical path stacks, cf. `flamegraph::stack`
+    stacks: BTreeMap<String, u64>,
+    /// Critical path time per activity type
+    activities: BTreeMap<String, u64>,
+    /// Critical path time per operator
+    operators: BTreeMap<u64, u64>,
+    /// `(operator, worker)` → ns, cf. `heatmap`
+    cells: BTreeMap<(u64, u64), u64>,
+    /// The slowest epochs with their edges, slowest first
+    worst: Vec<(u64, u64, Vec<PagEdge>)>,
+}
+
+/// The `report` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("report")
+        .about("Analyze a trace into a single self-contained HTML report, e.g. to share results")
+        .arg(clap::Arg::with_name("output_path")
+            .short("o")
+            .long("out")
+            .value_name("PATH")
+            .help("The output path for the HTML report")
+            .default_value("report.html"))
+        .arg(clap::Arg::with_name("epochs")
+            .long("epochs")
+            .value_name("FROM..TO")
+            .help("Only report epochs FROM (inclusive) to TO (exclusive); either bound may be omitted")
+            .default_value(".."))
+        .arg(clap::Arg::with_name("worst")
+            .long("worst")
+            .value_name("N")
+            .help("Number of slowest epochs to show timelines of")
+            .default_value("3"))
+}
+
+/// The arguments of `report`
+pub struct Options {
+    /// The HTML report
+    pub output_path: PathBuf,
+    /// The analyzed epochs
+    pub epochs: Range<u64>,
+    /// The number of slowest epochs to detail
+    pub worst: usize,
+}
+
+/// Parses the arguments of `report`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        output_path: PathBuf::from(args.value_of("output_path").expect("error parsing report output args")),
+        epochs: parse_epochs(args.value_of("epochs").expect("error parsing report epochs args"))?,
+        worst: args.value_of("worst").expect("error parsing report worst args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --worst: {}", e)))?,
+    })
+}
+
+/// Analyzes `epochs` of `replay_source` and writes a single self-contained HTML
+/// report to `output_path`, for people who won't run ST2 themselves: summary
+/// tables, the critical path composition as a flamegraph, the worker × operator
+/// heatmap, and timelines of the `worst` slowest epochs. The page uses no scripts
+/// or external resources.
+///
+/// All PAG edges are collected at the first ST2 peer; only the slowest epochs'
+/// edges are kept once they complete.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    epochs: Range<u64>,
+    worst: usize,
+    operator_names: &BTreeMap<u64, String>,
+    output_path: &Path,
+    output_format: OutputFormat) -> Result<(), STError> {
+
+    let mut out = BufWriter::new(File::create(output_path)?);
+
+    let findings = Arc::new(Mutex::new(Findings::default()));
+    let collected = Arc::clone(&findings);
+    let names = operator_names.clone();
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let findings = Arc::clone(&collected);
+        let operator_names = names.clone();
+        let epochs = epochs.clone();
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
+
+            completed_epochs(&pag, "Report", move |epoch, edges, _ahead| {
+                if epochs.contains(&epoch) {
+                    findings.lock().unwrap().add(epoch, edges, worst, &operator_names);
+                }
+            });
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    let findings = std::mem::replace(&mut *findings.lock().unwrap(), Findings::default());
+    findings.write_html(&mut out, operator_names)?;
+    out.flush()?;
+
+    output_format.print(
+        format_args!("Wrote a report of {} epochs to {}", findings.epochs.len(), output_path.display()),
+        json!({ "output": output_path, "epochs": findings.epochs.len(), "edges": findings.edges }));
+    Ok(())
+}
+
+impl Findings {
+    fn add(&mut self, epoch: u64, edges: Vec<PagEdge>, worst: usize, operator_names: &BTreeMap<u64, String>) {
+        let stats = EpochStats::new(&edges);
+        let critical_path_ns = stats.critical_path.iter().map(|edge| edge.duration()).sum();
+        self.epochs.insert(epoch, (stats.latency, critical_path_ns, stats.skew));
+        self.edges += edges.len();
+
+        for edge in stats.critical_path.iter() {
+            *self.stacks.entry(stack(edge, None, operator_names)).or_insert(0) += edge.duration();
+            *self.activities.entry(format!("{:?}", edge.edge_type)).or_insert(0) += edge.duration();
+            if let Some(operator) = edge.operator_id {
+                *self.operators.entry(operator).or_insert(0) += edge.duration();
+            }
+        }
+        for edge in edges.iter().filter(|edge| edge.source.worker_id == edge.destination.worker_id) {
+            if let Some(operator) = edge.operator_id {
+                *self.cells.entry((operator, edge.source.worker_id)).or_insert(0) += edge.duration();
+            }
+        }
+
+        if worst > 0 && (self.worst.len() < worst || self.worst.last().map_or(true, |(latency, _, _)| stats.latency > *latency)) {
+            self.worst.push((stats.latency, epoch, edges));
+            self.worst.sort_by_key(|(latency, epoch, _)| (std::cmp::Reverse(*latency), *epoch));
+            self.worst.truncate(worst);
+        }
+    }
+
+    fn write_html<W: Write>(&self, out: &mut W, operator_names: &BTreeMap<u64, String>) -> std::io::Result<()> {
+        let ms = |ns: u64| format!("{:.3}ms", ns as f64 / 1_000_000.0);
+        let share = |ns: u64, total: u64| if total > 0 { format!("{:.1}%", 100.0 * ns as f64 / total as f64) } else { "-".to_string() };
+        let operator_name = |id: u64| crate::labels::name(id, operator_names)
+            .map(|name| crate::sources::split(&name).0.to_string())
+            .unwrap_or_else(|| format!("operator {}", id));
+        // the operator's source location, linked if `--source-url` is given
+        let source = |id: u64| match crate::sources::of(id, operator_names) {
+            Some(location) => match location.url() {
+                Some(url) => format!(r#"<a href="{}">{}</a>"#, escape_xml(&url), escape_xml(&location.to_string())),
+                None => escape_xml(&location.to_string()),
+            },
+            None => String::new(),
+        };
+
+        writeln!(out, "<!DOCTYPE html>")?;
+        writeln!(out, r#"<html><head><meta charset="utf-8"><title>ST2 report</title>"#)?;
+        writeln!(out, "<style>body {{ font-family: sans-serif; margin: 2em; }} table {{ border-collapse: collapse; margin-bottom: 1em; }} th, td {{ border: 1px solid #ddd; padding: 4px 8px; text-align: right; }} th:first-child, td:first-child {{ text-align: left; }} {}</style>", TIMELINE_STYLE)?;
+        writeln!(out, "</head><body>")?;
+        writeln!(out, "<h1>ST2 report</h1>")?;
+
+        // summary
+        let mut latencies: Vec<u64> = self.epochs.values().map(|(latency, _, _)| *latency).collect();
+        latencies.sort();
+        let percentile = |p: f64| latencies.get(((latencies.len() as f64 - 1.0) * p).round() as usize).cloned().unwrap_or(0);
+        let count = self.epochs.len().max(1) as u64;
+        let critical_path: u64 = self.epochs.values().map(|(_, cp, _)| *cp).sum();
+        writeln!(out, "<h2>Summary</h2><table>")?;
+        match (self.epochs.keys().next(), self.epochs.keys().last()) {
+            (Some(first), Some(last)) => writeln!(out, "<tr><td>Epochs</td><td>{} ({} to {})</td></tr>", self.epochs.len(), first, last)?,
+            _ => writeln!(out, "<tr><td>Epochs</td><td>0</td></tr>")?,
+        }
+        writeln!(out, "<tr><td>PAG edges</td><td>{}</td></tr>", self.edges)?;
+        writeln!(out, "<tr><td>Mean latency</td><td>{}</td></tr>", ms(latencies.iter().sum::<u64>() / count))?;
+        for (label, p) in &[("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.0)] {
+            writeln!(out, "<tr><td>{} latency</td><td>{}</td></tr>", label, ms(percentile(*p)))?;
+        }
+        writeln!(out, "<tr><td>Mean critical path</td><td>{}</td></tr>", ms(critical_path / count))?;
+        writeln!(out, "<tr><td>Mean skew</td><td>{:.2}</td></tr>", self.epochs.values().map(|(_, _, skew)| skew).sum::<f64>() / count as f64)?;
+        writeln!(out, "</table>")?;
+
+        // critical path composition
+        writeln!(out, "<h2>Critical path</h2>")?;
+        writeln!(out, "<table><tr><th>Activity</th><th>Time</th><th>Share</th></tr>")?;
+        let mut activities: Vec<_> = self.activities.iter().collect();
+        activities.sort_by_key(|(_, ns)| std::cmp::Reverse(**ns));
+        for (activity, ns) in activities {
+            writeln!(out, r#"<tr><td><span class="legend"><span style="background: {}"></span></span>{}</td><td>{}</td><td>{}</td></tr>"#,
+                     activity_color(activity), escape_xml(activity), ms(*ns), share(*ns, critical_path))?;
+        }
+        writeln!(out, "</table>")?;
+        writeln!(out, "<table><tr><th>Operator</th><th>Source</th><th>Time</th><th>Share</th></tr>")?;
+        let mut operators: Vec<_> = self.operators.iter().collect();
+        operators.sort_by_key(|(id, ns)| (std::cmp::Reverse(**ns), **id));
+        for (id, ns) in operators.into_iter().take(TOP_OPERATORS) {
+            writeln!(out, "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>", escape_xml(&operator_name(*id)), source(*id), ms(*ns), share(*ns, critical_path))?;
+        }
+        writeln!(out, "</table>")?;
+        writeln!(out, "<h3>Flamegraph</h3>")?;
+        self.write_flamegraph(out)?;
+
+        // load balance
+        let heatmap = Heatmap::new(self.cells.clone(), operator_names);
+        writeln!(out, "<h2>Operators × workers</h2>")?;
+        if let Some((row, skew)) = heatmap.most_skewed() {
+            writeln!(out, "<p>Most skewed: {} ({:.2}x the mean on its busiest worker)</p>", escape_xml(&row.name), skew)?;
+        }
+        heatmap.write_svg(out)?;
+
+        // slowest epochs
+        writeln!(out, "<h2>Slowest epochs</h2>")?;
+        writeln!(out, "<table><tr><th>Epoch</th><th>Latency</th><th>Critical path</th><th>Skew</th></tr>")?;
+        for (_, epoch, _) in self.worst.iter() {
+            let (latency, cp, skew) = self.epochs[epoch];
+            writeln!(out, "<tr><td>{}</td><td>{}</td><td>{}</td><td>{:.2}</td></tr>", epoch, ms(latency), ms(cp), skew)?;
+        }
+        writeln!(out, "</table>")?;
+        for (latency, epoch, edges) in self.worst.iter() {
+            writeln!(out, "<h3>Epoch {} ({})</h3>", epoch, ms(*latency))?;
+            let rows: Vec<EdgeRow> = edges.iter().map(|edge| EdgeRow::from_edge(*epoch, edge)).collect();
+            write_timeline(out, &rows)?;
+        }
+
+        writeln!(out, "</body></html>")
+    }
+
+    /// Renders the critical path stacks as an SVG icicle graph: the root spans the
+    /// whole width, and every frame is as wide as its share of its parent.
+    fn write_flamegraph<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        #[derive(Default)]
+        struct Frame {
+            ns: u64,
+            children: BTreeMap<String, Frame>,
+        }
+        let mut root = Frame::default();
+        for (stack, ns) in self.stacks.iter() {
+            root.ns += ns;
+            let mut frame = &mut root;
+            for name in stack.split(';') {
+                frame = frame.children.entry(name.to_string()).or_insert_with(Frame::default);
+                frame.ns += ns;
+            }
+        }
+
+        fn depth(frame: &Frame) -> usize {
+            frame.children.values().map(|child| 1 + depth(child)).max().unwrap_or(0)
+        }
+        fn draw<W: Write>(out: &mut W, name: &str, frame: &Frame, x: f64, level: usize, scale: f64) -> std::io::Result<()> {
+            let width = frame.ns as f64 * scale;
+            if width < 0.5 {
+                return Ok(());
+            }
+            // activities in their colors, scopes and operators in warm colors
+            let color = match activity_color(name) {
+                "#000000" => format!("hsl({}, 80%, 65%)", 10 + name.bytes().map(u32::from).sum::<u32>() % 45),
+                color => color.to_string(),
+            };
+            let y = level as f64 * FRAME_HEIGHT;
+            writeln!(out, r##"<g><title>{} ({:.3}ms)</title><rect x="{:.2}" y="{:.1}" width="{:.2}" height="{:.1}" fill="{}" stroke="#fff"/>"##,
+                     escape_xml(name), frame.ns as f64 / 1_000_000.0, x, y, width, FRAME_HEIGHT, color)?;
+            let characters = (width / 7.0) as usize;
+            if characters >= 3 {
+                let label: String = if name.chars().count() > characters { name.chars().take(characters - 2).chain("..".chars()).collect() } else { name.to_string() };
+                writeln!(out, r#"<text x="{:.2}" y="{:.1}">{}</text>"#, x + 3.0, y + FRAME_HEIGHT - 5.0, escape_xml(&label))?;
+            }
+            writeln!(out, "</g>")?;
+            let mut child_x = x;
+            for (child_name, child) in frame.children.iter() {
+                draw(out, child_name, child, child_x, level + 1, scale)?;
+                child_x += child.ns as f64 * scale;
+            }
+            Ok(())
+        }
+
+        let height = (depth(&root) + 1) as f64 * FRAME_HEIGHT;
+        writeln!(out, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="sans-serif" font-size="11">"#, FLAMEGRAPH_WIDTH, height)?;
+        if root.ns > 0 {
+            draw(out, "all", &root, 0.0, 0, FLAMEGRAPH_WIDTH / root.ns as f64)?;
+        }
+        writeln!(out, "</svg>")
+    }
+}
@@ -0,0 +1,152 @@
+use crate::pag;
+use crate::pag::PagEdge;
+
+use timely::dataflow::ProbeHandle;
+use timely::dataflow::operators::probe::Probe;
+use timely::dataflow::operators::filter::Filter;
+use timely::dataflow::operators::inspect::Inspect;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+
+use serde_json::json;
+
+pub use st2_logformat::pag::critical_path;
+
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+/// The PAG of a single epoch
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The epoch
+    pub epoch: u64,
+    /// Time from the epoch's first to its last event, in ns
+    pub latency: u64,
+    /// All edges of the epoch's PAG, ordered by source timestamp
+    pub edges: Vec<PagEdge>,
+    /// The epoch's critical path (cf. `critical_path`), in order
+    pub critical_path: Vec<PagEdge>,
+}
+
+/// The `snapshot` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("snapshot")
+        .about("Wait for an epoch and write its PAG and critical path to a JSON file, e.g. for bug reports")
+        .arg(clap::Arg::with_name("epoch")
+            .long("epoch")
+            .value_name("EPOCH")
+            .help("The epoch to snapshot")
+            .required(true))
+        .arg(clap::Arg::with_name("output_path")
+            .short("o")
+            .long("out")
+            .value_name("PATH")
+            .help("The output path for the snapshot (default: snapshot-<EPOCH>.json)")
+            .takes_value(true))
+}
+
+/// The arguments of `snapshot`
+pub struct Options {
+    /// The snapshotted epoch
+    pub epoch: u64,
+    /// The snapshot file, `snapshot-<epoch>.json` unless given
+    pub output_path: PathBuf,
+}
+
+/// Parses the arguments of `snapshot`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    let epoch: u64 = args.value_of("epoch").expect("error parsing snapshot epoch args")
+        .parse().map_err(|e| STError::Config(format!("Invalid --epoch: {}", e)))?;
+
+    Ok(Options {
+        epoch,
+        output_path: args.value_of("output_path")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(format!("snapshot-{}.json", epoch))),
+    })
+}
+
+/// Waits until `epoch` of `replay_source` has been analyzed, then writes its
+/// PAG and critical path as JSON to `output_path` and stops the analysis.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: st2_timely::filter::Filter,
+    epoch: u64,
+    output_path: &Path,
+    output_format: OutputFormat) -> Result<(), STError> {
+
+    let edges = Arc::new(Mutex::new(Vec::new()));
+    let collected = Arc::clone(&edges);
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let edges = Arc::clone(&collected);
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        let probe: ProbeHandle<Pair<u64, Duration>> = worker.dataflow(|scope| {
+            pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone())
+                .filter(move |(edge, _t, _diff)| edge.source.epoch == epoch)
+                .inspect(move |(edge, _t, _diff)| edges.lock().unwrap().push(edge.clone()))
+                .probe()
+        });
+
+        // once the epoch is complete, stop replaying and finish the epochs in flight
+        while !probe.done() {
+            if is_running.load(Ordering::Acquire) && !probe.less_than(&Pair::new(epoch + 1, Default::default())) {
+                info!("w{} analyzed epoch {}", index, epoch);
+                is_running.store(false, Ordering::Release);
+            }
+            worker.step_or_park(None);
+        }
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    let mut edges = std::mem::replace(&mut *edges.lock().unwrap(), Vec::new());
+    if edges.is_empty() {
+        return Err(STError::Analysis(format!("epoch {} not found in the source", epoch)));
+    }
+    edges.sort_by_key(|edge| (edge.source.timestamp, edge.source.worker_id, edge.destination.timestamp));
+
+    let first = edges.iter().map(|edge| edge.source.timestamp).min().unwrap_or_default();
+    let last = edges.iter().map(|edge| edge.destination.timestamp).max().unwrap_or_default();
+    let snapshot = Snapshot {
+        epoch,
+        latency: last.checked_sub(first).unwrap_or_default().as_nanos() as u64,
+        critical_path: critical_path(&edges),
+        edges,
+    };
+
+    let file = std::io::BufWriter::new(std::fs::File::create(output_path)?);
+    serde_json::to_writer_pretty(file, &snapshot)
+        .map_err(|e| STError::io("couldn't write snapshot", e.into()))?;
+    output_format.print(
+        format_args!("Wrote {} edges of epoch {} ({} on its critical path) to {}",
+                     snapshot.edges.len(), epoch, snapshot.critical_path.len(), output_path.display()),
+        json!({
+            "output": output_path,
+            "epoch": epoch,
+            "latency_ns": snapshot.latency,
+            "edges": snapshot.edges.len(),
+            "critical_path_edges": snapshot.critical_path.len(),
+        }));
+
+    Ok(())
+}
+
@@ -0,0 +1,187 @@
+//! SQL queries over PAGs with DataFusion (requires the `sql` feature).
+//!
+//! A loaded PAG is registered as two in-memory tables:
+//!
+//! - `edges`: all edges of the PAG
+//! - `cp`: the edges of every epoch's critical path (cf. `snapshot::critical_path`)
+//!
+//! Both have the columns `TABLE_COLUMNS`. `start_ns` and `end_ns` are relative to
+//! the start of the trace; `operator` is the operator's id, `operator_name` its
+//! label or name, `team` and `tags` (comma-separated) its team and tags (cf.
+//! `labels`), and `source` its source location (cf. `sources`), if known. For example,
+//!
+//! ```text
+//! SELECT operator_name, SUM(duration_ns) FROM cp WHERE epoch >= 100 GROUP BY operator_name ORDER BY SUM(duration_ns) DESC LIMIT 5
+//! ```
+//!
+//! ranks the operators by their time on the critical paths of epochs from 100.
+//! Results are returned as `query::Table`s, so they're printed like those of
+//! declarative queries: result columns whose names contain `_ns` are times.
+
+use crate::pag::PagEdge;
+use crate::commands::query::Table;
+use crate::STError;
+
+use std::collections::BTreeMap;
+
+/// Columns of the tables `edges` and `cp`
+pub const TABLE_COLUMNS: &[&str] = &[
+    "epoch", "worker", "dst_worker", "operator", "operator_name", "team", "tags", "source", "activity", "traverse", "start_ns", "end_ns", "duration_ns", "records",
+];
+
+/// Evaluates the SQL query `sql` over `edges`, whose operators are named by `operator_names`.
+#[cfg(feature = "sql")]
+pub fn eval(sql: &str, edges: &[PagEdge], operator_names: &BTreeMap<u64, String>) -> Result<Table, STError> {
+    use crate::commands::snapshot::critical_path;
+
+    use datafusion::datasource::MemTable;
+    use datafusion::execution::context::ExecutionContext;
+
+    let start = edges.iter().map(|edge| edge.source.timestamp).min().unwrap_or_default();
+    let mut epochs: BTreeMap<u64, Vec<PagEdge>> = BTreeMap::new();
+    for edge in edges.iter() {
+        epochs.entry(edge.source.epoch).or_insert_with(Vec::new).push(edge.clone());
+    }
+    let critical_paths: Vec<PagEdge> = epochs.values().flat_map(|edges| critical_path(edges)).collect();
+
+    let mut context = ExecutionContext::new();
+    for (name, edges) in vec![("edges", edges), ("cp", &critical_paths[..])] {
+        let batch = record_batch(edges, start, operator_names).map_err(arrow_error)?;
+        let table = MemTable::new(batch.schema(), vec![vec![batch]]).map_err(datafusion_error)?;
+        context.register_table(name, Box::new(table));
+    }
+
+    let batches = context.sql(sql, BATCH_SIZE).map_err(datafusion_error)?;
+    table(&batches)
+}
+
+/// Evaluates the SQL query `sql` over `edges`, whose operators are named by `operator_names`.
+#[cfg(not(feature = "sql"))]
+pub fn eval(_sql: &str, _edges: &[PagEdge], _operator_names: &BTreeMap<u64, String>) -> Result<Table, STError> {
+    Err(STError::Config("SQL queries require building with `--features sql`".to_string()))
+}
+
+/// Number of rows DataFusion processes at a time
+#[cfg(feature = "sql")]
+const BATCH_SIZE: usize = 8192;
+
+/// The `edges` as a record batch of `TABLE_COLUMNS`, with times relative to `start`
+#[cfg(feature = "sql")]
+fn record_batch(edges: &[PagEdge], start: std::time::Duration, operator_names: &BTreeMap<u64, String>)
+    -> arrow::error::Result<arrow::record_batch::RecordBatch> {
+    use arrow::array::{ArrayRef, StringArray, UInt64Array};
+    use arrow::datatypes::{DataType, Field, Schema};
+    use arrow::record_batch::RecordBatch;
+    use std::sync::Arc;
+
+    let schema = Schema::new(vec![
+        Field::new("epoch", DataType::UInt64, false),
+        Field::new("worker", DataType::UInt64, false),
+        Field::new("dst_worker", DataType::UInt64, false),
+        Field::new("operator", DataType::UInt64, true),
+        Field::new("operator_name", DataType::Utf8, true),
+        Field::new("team", DataType::Utf8, true),
+        Field::new("tags", DataType::Utf8, true),
+        Field::new("source", DataType::Utf8, true),
+        Field::new("activity", DataType::Utf8, false),
+        Field::new("traverse", DataType::Utf8, false),
+        Field::new("start_ns", DataType::UInt64, false),
+        Field::new("end_ns", DataType::UInt64, false),
+        Field::new("duration_ns", DataType::UInt64, false),
+        Field::new("records", DataType::UInt64, true),
+    ]);
+
+    let relative = |t: std::time::Duration| t.checked_sub(start).unwrap_or_default().as_nanos() as u64;
+    let ints = |f: &dyn Fn(&PagEdge) -> u64| -> ArrayRef { Arc::new(UInt64Array::from(edges.iter().map(f).collect::<Vec<_>>())) };
+    let optional_ints = |f: &dyn Fn(&PagEdge) -> Option<u64>| -> ArrayRef { Arc::new(UInt64Array::from(edges.iter().map(f).collect::<Vec<_>>())) };
+    let activities: Vec<String> = edges.iter().map(|edge| format!("{:?}", edge.edge_type)).collect();
+    let traversals: Vec<String> = edges.iter().map(|edge| format!("{:?}", edge.traverse)).collect();
+    let names: Vec<Option<String>> = edges.iter()
+        .map(|edge| edge.operator_id.and_then(|id| crate::labels::name(id, operator_names)))
+        .collect();
+    let labels: Vec<Option<crate::labels::Label>> = edges.iter()
+        .map(|edge| edge.operator_id.and_then(crate::labels::of))
+        .collect();
+    let teams: Vec<Option<&str>> = labels.iter().map(|label| label.as_ref().and_then(|label| label.team.as_deref())).collect();
+    let tags: Vec<Option<String>> = labels.iter()
+        .map(|label| label.as_ref().filter(|label| !label.tags.is_empty()).map(|label| label.tags.join(",")))
+        .collect();
+    let sources: Vec<Option<String>> = edges.iter()
+        .map(|edge| edge.operator_id.and_then(|id| crate::sources::of(id, operator_names)).map(|location| location.to_string()))
+        .collect();
+    let strings = |values: &[Option<String>]| -> ArrayRef {
+        Arc::new(StringArray::from(values.iter().map(|value| value.as_deref()).collect::<Vec<_>>()))
+    };
+
+    let columns: Vec<ArrayRef> = vec![
+        ints(&|edge| edge.source.epoch),
+        ints(&|edge| edge.source.worker_id),
+        ints(&|edge| edge.destination.worker_id),
+        optional_ints(&|edge| edge.operator_id),
+        strings(&names),
+        Arc::new(StringArray::from(teams)),
+        strings(&tags),
+        strings(&sources),
+        Arc::new(StringArray::from(activities.iter().map(|s| s.as_str()).collect::<Vec<_>>())),
+        Arc::new(StringArray::from(traversals.iter().map(|s| s.as_str()).collect::<Vec<_>>())),
+        ints(&|edge| relative(edge.source.timestamp)),
+        ints(&|edge| relative(edge.destination.timestamp)),
+        ints(&|edge| edge.duration()),
+        optional_ints(&|edge| edge.length.map(|l| l as u64)),
+    ];
+    RecordBatch::try_new(Arc::new(schema), columns)
+}
+
+/// The query result `batches` as a table
+#[cfg(feature = "sql")]
+fn table(batches: &[arrow::record_batch::RecordBatch]) -> Result<Table, STError> {
+    use crate::commands::query::Value;
+    use arrow::array::{Array, Float32Array, Float64Array, Int32Array, Int64Array, StringArray, UInt32Array, UInt64Array};
+    use arrow::datatypes::DataType;
+
+    let columns: Vec<String> = match batches.first() {
+        Some(batch) => batch.schema().fields().iter().map(|field| field.name().clone()).collect(),
+        None => return Ok(Table { columns: Vec::new(), rows: Vec::new() }),
+    };
+
+    let mut rows = Vec::new();
+    for batch in batches.iter() {
+        let mut batch_rows = vec![Vec::with_capacity(columns.len()); batch.num_rows()];
+        for (i, name) in columns.iter().enumerate() {
+            let column = batch.column(i);
+            let time = name.contains("_ns");
+            let int = |x: u64| if time { Value::Time(x) } else { Value::Int(x) };
+            macro_rules! cells {
+                ($array:ty, $cell:expr) => {{
+                    let array = column.as_any().downcast_ref::<$array>().expect("array of the column's type");
+                    for (row, cells) in batch_rows.iter_mut().enumerate() {
+                        cells.push(if array.is_null(row) { Value::Null } else { $cell(array.value(row)) });
+                    }
+                }};
+            }
+            match column.data_type() {
+                DataType::UInt64 => cells!(UInt64Array, |x: u64| int(x)),
+                DataType::UInt32 => cells!(UInt32Array, |x: u32| int(x as u64)),
+                // negative ints only come from arithmetic, show them as floats
+                DataType::Int64 => cells!(Int64Array, |x: i64| if x >= 0 { int(x as u64) } else { Value::Float(x as f64) }),
+                DataType::Int32 => cells!(Int32Array, |x: i32| if x >= 0 { int(x as u64) } else { Value::Float(x as f64) }),
+                DataType::Float64 => cells!(Float64Array, |x: f64| Value::Float(x)),
+                DataType::Float32 => cells!(Float32Array, |x: f32| Value::Float(x as f64)),
+                DataType::Utf8 => cells!(StringArray, |x: &str| Value::Text(x.to_string())),
+                other => return Err(STError::Config(format!("unsupported type of column {}: {:?}", name, other))),
+            }
+        }
+        rows.extend(batch_rows);
+    }
+    Ok(Table { columns, rows })
+}
+
+#[cfg(feature = "sql")]
+fn arrow_error(e: arrow::error::ArrowError) -> STError {
+    STError::Analysis(format!("Arrow error: {}", e))
+}
+
+#[cfg(feature = "sql")]
+fn datafusion_error(e: datafusion::error::ExecutionError) -> STError {
+    STError::Config(format!("SQL error: {}", e))
+}
@@ -0,0 +1,217 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::commands::alerts::EpochStats;
+use crate::store::completed_epochs;
+
+use timely::dataflow::Stream;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, atomic::AtomicBool};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use crate::STError;
+use crate::config::Args;
+
+/// Number of recent epochs latency spikes are detected against
+const HISTORY: usize = 100;
+/// An epoch is a latency spike if it takes this many times the median latency of recent epochs
+const SPIKE_FACTOR: f64 = 2.0;
+/// An epoch's load is skewed if the busiest worker is busier than this many times the average
+const SKEW_FACTOR: f64 = 2.0;
+
+/// Results of a completed epoch, written as one JSON line
+#[derive(Serialize)]
+pub struct EpochResult {
+    /// The epoch
+    pub epoch: u64,
+    /// Time from the epoch's first to its last event, in ns
+    pub latency_ns: u64,
+    /// Number of the epoch's PAG edges
+    pub edges: usize,
+    /// Duration of the epoch's critical path, in ns
+    pub critical_path_ns: u64,
+    /// Time on the critical path per activity type, in ns
+    pub critical_path: BTreeMap<String, u64>,
+    /// Operators with the most time on the critical path, descending
+    pub top_operators: Vec<OperatorTime>,
+    /// Busiest worker's busy time / the workers' average busy time
+    pub skew: f64,
+    /// Anomalies of the epoch, e.g. `latency_spike` or `skewed_load`
+    pub anomalies: Vec<String>,
+}
+
+/// An operator's time on an epoch's critical path
+#[derive(Serialize)]
+pub struct OperatorTime {
+    /// The operator's id
+    pub id: u64,
+    /// The operator's name, if configured
+    pub name: Option<String>,
+    /// Time on the critical path, in ns
+    pub ns: u64,
+    /// Share of the critical path, from 0 to 1
+    pub share: f64,
+}
+
+/// The `stream` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("stream")
+        .about("Write one JSON object per completed epoch (latency, critical path, top operators, anomalies), e.g. for jq, Vector, or Fluent Bit")
+        .arg(clap::Arg::with_name("output_path")
+            .short("o")
+            .long("out")
+            .value_name("PATH")
+            .help("File to append JSON lines to, or - for stdout")
+            .default_value("-"))
+        .arg(clap::Arg::with_name("top")
+            .long("top")
+            .value_name("N")
+            .help("Number of operators on the critical path to report per epoch")
+            .default_value("5"))
+}
+
+/// The arguments of `stream`
+pub struct Options {
+    /// The file of JSON lines
+    pub output_path: PathBuf,
+    /// The number of operators listed per epoch
+    pub top: usize,
+}
+
+/// Parses the arguments of `stream`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        output_path: PathBuf::from(args.value_of("output_path").expect("error parsing stream output args")),
+        top: args.value_of("top").expect("error parsing stream top args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --top: {}", e)))?,
+    })
+}
+
+/// Writes an `EpochResult` per completed epoch of `replay_source` as a JSON line
+/// to `output_path` (appended to), or to stdout if it's `-`. Lines are flushed
+/// as soon as their epoch completes, so the output can be piped into `jq`,
+/// Vector, or Fluent Bit. Results report the `top` operators on the critical
+/// path.
+///
+/// Epochs are analyzed in order at the first ST2 peer, which collects their PAG
+/// edges until they complete.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    top: usize,
+    operator_names: &BTreeMap<u64, String>,
+    output_path: &Path) -> Result<(), STError> {
+
+    // fail before the analysis rather than in a worker
+    if output_path != Path::new("-") {
+        OpenOptions::new().create(true).append(true).open(output_path)
+            .map_err(|e| STError::io(format!("couldn't open {}", output_path.display()), e))?;
+    }
+    let output_path = output_path.to_path_buf();
+    let operator_names = operator_names.clone();
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let operator_names = operator_names.clone();
+
+        // only the first peer writes results
+        let mut out: Option<Box<dyn Write>> = match index {
+            0 if output_path == Path::new("-") => Some(Box::new(std::io::stdout())),
+            0 => Some(Box::new(OpenOptions::new().create(true).append(true).open(&output_path).expect("couldn't open output"))),
+            _ => None,
+        };
+        let mut latencies: VecDeque<u64> = VecDeque::new();
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
+
+            completed_epochs(&pag, "Stream", move |epoch, edges, _ahead| {
+                let result = epoch_result(epoch, &edges, top, &latencies, &operator_names);
+                latencies.push_back(result.latency_ns);
+                if latencies.len() > HISTORY {
+                    latencies.pop_front();
+                }
+
+                if let Some(out) = out.as_mut() {
+                    let mut line = serde_json::to_vec(&result).expect("results are serializable");
+                    line.push(b'\n');
+                    if let Err(e) = out.write_all(&line).and_then(|_| out.flush()) {
+                        error!("couldn't write results of epoch {}: {}", epoch, e);
+                    }
+                }
+            });
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    Ok(())
+}
+
+/// The results of `epoch` with the PAG `edges`, given the `latencies` of recent epochs
+fn epoch_result(epoch: u64, edges: &[PagEdge], top: usize, latencies: &VecDeque<u64>, operator_names: &BTreeMap<u64, String>) -> EpochResult {
+    let stats = EpochStats::new(edges);
+    let critical_path_ns: u64 = stats.critical_path.iter().map(|edge| edge.duration()).sum();
+
+    let mut activities = BTreeMap::new();
+    let mut operators: HashMap<u64, u64> = HashMap::new();
+    for edge in stats.critical_path.iter() {
+        *activities.entry(format!("{:?}", edge.edge_type)).or_insert(0) += edge.duration();
+        if let Some(id) = edge.operator_id {
+            *operators.entry(id).or_insert(0) += edge.duration();
+        }
+    }
+    let mut top_operators: Vec<OperatorTime> = operators.into_iter()
+        .map(|(id, ns)| OperatorTime {
+            id,
+            name: operator_names.get(&id).cloned(),
+            ns,
+            share: if critical_path_ns > 0 { ns as f64 / critical_path_ns as f64 } else { 0.0 },
+        })
+        .collect();
+    top_operators.sort_by_key(|operator| (std::cmp::Reverse(operator.ns), operator.id));
+    top_operators.truncate(top);
+
+    let mut anomalies = Vec::new();
+    if !latencies.is_empty() {
+        let mut sorted: Vec<u64> = latencies.iter().cloned().collect();
+        sorted.sort();
+        let median = sorted[sorted.len() / 2];
+        if median > 0 && stats.latency as f64 > SPIKE_FACTOR * median as f64 {
+            anomalies.push("latency_spike".to_string());
+        }
+    }
+    if stats.skew > SKEW_FACTOR {
+        anomalies.push("skewed_load".to_string());
+    }
+
+    EpochResult {
+        epoch,
+        latency_ns: stats.latency,
+        edges: edges.len(),
+        critical_path_ns,
+        critical_path: activities,
+        top_operators,
+        skew: stats.skew,
+        anomalies,
+    }
+}
@@ -0,0 +1,325 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::commands::algo::KHops;
+use crate::commands::metrics::Metrics;
+
+use timely::dataflow::Stream;
+use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::delay::Delay;
+use timely::dataflow::operators::aggregation::aggregate::Aggregate;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryInto;
+use std::io::Stdout;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::time::{Duration, Instant};
+
+use crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::Terminal;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, Borders, Paragraph, Row, Sparkline, Table};
+
+use st2_logformat::pair::Pair;
+use st2_logformat::ActivityType;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use crate::STError;
+use crate::config::Args;
+
+/// Per-epoch results sent from the timely workers to the TUI
+enum Update {
+    /// An epoch's latency in ns
+    Latency(u64, u64),
+    /// Weight of an operator's edges on an epoch's critical paths (cf. `KHops`)
+    Critical(u64, u64, u64),
+    /// An epoch's busy and total time of a worker's local activities, in ns
+    Busy(u64, u64, u64, u64),
+}
+
+/// The `top` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("top")
+        .about("Live terminal UI of per-operator critical path participation, worker utilization, and epoch latencies")
+        .arg(clap::Arg::with_name("refresh")
+            .long("refresh")
+            .value_name("MS")
+            .help("Redraw interval in milliseconds")
+            .default_value("250"))
+        .arg(clap::Arg::with_name("history")
+            .long("history")
+            .value_name("EPOCHS")
+            .help("Number of epochs shown in the latency sparkline")
+            .default_value("200"))
+}
+
+/// The arguments of `top`
+pub struct Options {
+    /// Time between redraws
+    pub refresh: Duration,
+    /// The number of epochs shown in sparklines
+    pub history: usize,
+}
+
+/// Parses the arguments of `top`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        refresh: Duration::from_millis(args.value_of("refresh").expect("error parsing top refresh args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --refresh: {}", e)))?),
+        history: args.value_of("history").expect("error parsing top history args")
+            .parse().map_err(|e| STError::Config(format!("Invalid --history: {}", e)))?,
+    })
+}
+
+/// Shows a live terminal UI of the analysis of `replay_source`: per-operator critical
+/// path participation and per-worker busy fractions of the latest completed epoch, and a
+/// sparkline of the latencies of the last `history` epochs, redrawn every `refresh`.
+/// In cluster mode, every ST2 process shows the results of its own workers.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    refresh: Duration,
+    history: usize) -> Result<(), STError> {
+
+    let (send, recv) = mpsc::channel();
+    let send = Arc::new(Mutex::new(send));
+    let workers_running = Arc::clone(&is_running);
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    let guards = timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let send_latency = send.lock().expect("cannot lock send").clone();
+        let send_critical = send.lock().expect("cannot lock send").clone();
+        let send_busy = send.lock().expect("cannot lock send").clone();
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = crate::replay::make_readers(replay_source.clone(), worker.index() % local_peers, local_peers).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, index, Some(Arc::clone(&workers_running)), 1, speed, filter.clone());
+
+            pag
+                .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+                .map(|(edge, _t, _diff)| (edge.source.epoch, edge))
+                .aggregate::<_,(u64, u64),_,_,_>(
+                    |_key, edge, acc| {
+                        let from: u64 = edge.source.timestamp.as_nanos().try_into().unwrap();
+                        let to: u64 = edge.destination.timestamp.as_nanos().try_into().unwrap();
+                        *acc = if *acc == (0, 0) {
+                            (from, to)
+                        } else {
+                            (std::cmp::min(acc.0, from), std::cmp::max(acc.1, to))
+                        };
+                    },
+                    |key, acc| (key, acc.1 - acc.0),
+                    |key| *key)
+                .inspect(move |(epoch, latency)| {
+                    // the TUI might have quit already
+                    let _ = send_latency.send(Update::Latency(*epoch, *latency));
+                });
+
+            pag
+                .khops()
+                .flat_map(|((edge, weight), _hops)| edge.operator_id.map(|operator| (operator, weight)))
+                .aggregate::<_,u64,_,_,_>(
+                    |_key, weight, acc| *acc += weight,
+                    |key, acc| (key, acc),
+                    |key| *key)
+                .inspect_time(move |t, (operator, weight)| {
+                    let _ = send_critical.send(Update::Critical(t.first - 1, *operator, *weight));
+                });
+
+            pag
+                .metrics()
+                .inspect_time(move |t, (wf, wt, a, _ac, at, _rc)| {
+                    if wf != wt {
+                        return;
+                    }
+                    let busy = match a {
+                        ActivityType::Waiting | ActivityType::Spinning => 0,
+                        _ => *at,
+                    };
+                    let _ = send_busy.send(Update::Busy(t.first - 1, *wf, busy, *at));
+                });
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    let result = show(recv, &is_running, refresh, history);
+
+    // stop replaying (if the user quit), then complete the epochs in flight
+    is_running.store(false, Ordering::Release);
+    guards.join().into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    result
+}
+
+/// What the TUI shows
+#[derive(Default)]
+struct State {
+    /// Latencies of the most recent epochs
+    latencies: VecDeque<(u64, u64)>,
+    /// epoch -> operator -> critical path weight
+    critical: BTreeMap<u64, BTreeMap<u64, u64>>,
+    /// epoch -> worker -> (busy, total)
+    busy: BTreeMap<u64, BTreeMap<u64, (u64, u64)>>,
+    /// Whether the analysis has finished
+    done: bool,
+}
+
+impl State {
+    fn update(&mut self, update: Update, history: usize) {
+        match update {
+            Update::Latency(epoch, latency) => {
+                self.latencies.push_back((epoch, latency));
+                while self.latencies.len() > history {
+                    self.latencies.pop_front();
+                }
+                // forget epochs that are no longer shown
+                let oldest = self.latencies.front().map(|(epoch, _)| *epoch).unwrap_or(0);
+                self.critical = self.critical.split_off(&oldest);
+                self.busy = self.busy.split_off(&oldest);
+            }
+            Update::Critical(epoch, operator, weight) => {
+                *self.critical.entry(epoch).or_insert_with(BTreeMap::new).entry(operator).or_insert(0) += weight;
+            }
+            Update::Busy(epoch, worker, busy, total) => {
+                let acc = self.busy.entry(epoch).or_insert_with(BTreeMap::new).entry(worker).or_insert((0, 0));
+                *acc = (acc.0 + busy, acc.1 + total);
+            }
+        }
+    }
+
+    /// The latest epoch whose latency is known
+    fn epoch(&self) -> Option<u64> {
+        self.latencies.iter().map(|(epoch, _)| *epoch).max()
+    }
+}
+
+type Tui = Terminal<CrosstermBackend<Stdout>>;
+
+/// Draws `recv`'s updates until the user quits (`q`, `Esc`, or `Ctrl-C`).
+fn show(recv: mpsc::Receiver<Update>, is_running: &AtomicBool, refresh: Duration, history: usize) -> Result<(), STError> {
+    enable_raw_mode()?;
+    crossterm::execute!(std::io::stdout(), EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
+
+    let result = event_loop(&mut terminal, recv, is_running, refresh, history);
+
+    disable_raw_mode()?;
+    crossterm::execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    result
+}
+
+fn event_loop(terminal: &mut Tui, recv: mpsc::Receiver<Update>, is_running: &AtomicBool, refresh: Duration, history: usize) -> Result<(), STError> {
+    let mut state = State::default();
+
+    loop {
+        let deadline = Instant::now() + refresh;
+        loop {
+            match recv.try_recv() {
+                Ok(update) => state.update(update, history),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    state.done = true;
+                    break;
+                }
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+
+        terminal.draw(|frame| draw(frame, &state, is_running))?;
+
+        if event::poll(deadline.saturating_duration_since(Instant::now()))? {
+            if let Event::Key(key) = event::read()? {
+                let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.code == KeyCode::Char('q') || key.code == KeyCode::Esc || ctrl_c {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn draw(frame: &mut ratatui::Frame, state: &State, is_running: &AtomicBool) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(1), Constraint::Length(8), Constraint::Min(0)])
+        .split(frame.size());
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(rows[2]);
+
+    let status = if state.done {
+        "analysis finished"
+    } else if !is_running.load(Ordering::Acquire) {
+        "shutting down"
+    } else {
+        "running"
+    };
+    let title = match state.epoch() {
+        Some(epoch) => format!(" st2 top | epoch {} | {} | q to quit", epoch, status),
+        None => format!(" st2 top | waiting for the first epoch | {} | q to quit", status),
+    };
+    frame.render_widget(Paragraph::new(title).style(Style::default().add_modifier(Modifier::REVERSED)), rows[0]);
+
+    let latencies: Vec<u64> = state.latencies.iter().map(|(_, latency)| *latency).collect();
+    let last = latencies.last().map(|l| format!("{:.3}ms", *l as f64 / 1_000_000.0)).unwrap_or_default();
+    let width = rows[1].width.saturating_sub(2) as usize;
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::default().borders(Borders::ALL).title(format!(" epoch latency {} ", last)))
+            .data(&latencies[latencies.len().saturating_sub(width) ..]),
+        rows[1]);
+
+    let epoch = state.epoch();
+
+    let critical = epoch.and_then(|epoch| state.critical.get(&epoch));
+    let total: u64 = critical.map(|c| c.values().sum()).unwrap_or(0);
+    let mut operators: Vec<_> = critical.into_iter().flatten().collect();
+    operators.sort_by_key(|(_, weight)| std::cmp::Reverse(**weight));
+    let operator_rows = operators.iter().map(|(operator, weight)| {
+        let share = if total == 0 { 0.0 } else { **weight as f64 / total as f64 };
+        Row::new(vec![operator.to_string(), format!("{:.1}%", share * 100.0), bar(share, 20)])
+    });
+    frame.render_widget(
+        Table::new(operator_rows, [Constraint::Length(10), Constraint::Length(8), Constraint::Min(0)])
+            .header(Row::new(vec!["operator", "CP", ""]).style(Style::default().add_modifier(Modifier::BOLD)))
+            .block(Block::default().borders(Borders::ALL).title(" critical path participation ")),
+        columns[0]);
+
+    let busy = epoch.and_then(|epoch| state.busy.get(&epoch));
+    let worker_rows = busy.into_iter().flatten().map(|(worker, (busy, total))| {
+        let share = if *total == 0 { 0.0 } else { *busy as f64 / *total as f64 };
+        Row::new(vec![worker.to_string(), format!("{:.1}%", share * 100.0), bar(share, 20)])
+    });
+    frame.render_widget(
+        Table::new(worker_rows, [Constraint::Length(10), Constraint::Length(8), Constraint::Min(0)])
+            .header(Row::new(vec!["worker", "busy", ""]).style(Style::default().add_modifier(Modifier::BOLD)))
+            .block(Block::default().borders(Borders::ALL).title(" worker utilization ")),
+        columns[1]);
+}
+
+/// A horizontal bar of `width` cells, filled to `share`
+fn bar(share: f64, width: usize) -> String {
+    let filled = ((share * width as f64).round() as usize).min(width);
+    format!("{}{}", "█".repeat(filled), "·".repeat(width - filled))
+}
@@ -0,0 +1,174 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use st2_logformat::{EventType, LogRecord};
+use st2_logformat::names::NameId;
+use st2_logformat::tagged::{self, Value};
+use st2_logformat::trace::{TraceHeader, TraceReader, TraceWriter};
+
+use serde_json::json;
+
+use crate::{OutputFormat, STError};
+use crate::commands::parse_epochs;
+use crate::config::Args;
+
+/// The `trim` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("trim")
+        .about("Extract a time and/or epoch range of a trace file into a new trace file")
+        .arg(clap::Arg::with_name("input")
+            .value_name("IN")
+            .help("Trace file to trim")
+            .required(true))
+        .arg(clap::Arg::with_name("output")
+            .value_name("OUT")
+            .help("Path of the trimmed trace file")
+            .required(true))
+        .arg(clap::Arg::with_name("from")
+            .long("from")
+            .value_name("SECS")
+            .help("Only keep records logged at least SECS seconds after the trace's earliest record")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("to")
+            .long("to")
+            .value_name("SECS")
+            .help("Only keep records logged less than SECS seconds after the trace's earliest record")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("epochs")
+            .long("epochs")
+            .value_name("FROM..TO")
+            .help("Only keep epochs FROM (inclusive) to TO (exclusive); either bound may be omitted")
+            .default_value(".."))
+}
+
+/// The arguments of `trim`
+pub struct Options {
+    /// The trimmed trace file
+    pub input: PathBuf,
+    /// The trimmed file
+    pub output: PathBuf,
+    /// The kept time range, relative to the trace's first record
+    pub time: Range<Duration>,
+    /// The kept epochs
+    pub epochs: Range<u64>,
+}
+
+/// Parses the arguments of `trim`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    let parse_secs = |arg: &str, default| match args.value_of(arg) {
+        Some(secs) => secs.parse::<f64>()
+            .ok()
+            .filter(|secs| *secs >= 0.0 && secs.is_finite())
+            .map(Duration::from_secs_f64)
+            .ok_or_else(|| STError::Config(format!("Invalid --{}: {} (expected a number of seconds)", arg, secs))),
+        None => Ok(default),
+    };
+
+    Ok(Options {
+        input: PathBuf::from(args.value_of("input").expect("error parsing trim input args")),
+        output: PathBuf::from(args.value_of("output").expect("error parsing trim output args")),
+        time: parse_secs("from", Duration::from_secs(0))? .. parse_secs("to", Duration::from_secs(std::u64::MAX))?,
+        epochs: parse_epochs(args.value_of("epochs").expect("error parsing trim epochs args"))?,
+    })
+}
+
+/// Extracts the records of the trace file `input` within `time` (relative to its
+/// earliest record) and `epochs` to the new trace file `output`.
+///
+/// The result remains a valid trace file (cf. `validate`): it keeps all names and
+/// the encoding and compression of `input`, repeats operator names on the first kept
+/// record of each operator, and keeps activities balanced, i.e. drops `End`s whose
+/// `Start` was cut off and keeps the `End`s of activities still open at the end of
+/// the range. Like any trace file of log records, it's read by the trace file
+/// commands (`inspect`, `validate`, `convert`, ...), not replayed by `-f`, which
+/// reads the source computation's `*.dump` files.
+pub fn run(input: &Path, output: &Path, time: Range<Duration>, epochs: Range<u64>, output_format: OutputFormat) -> Result<(), STError> {
+    let start = earliest_timestamp(input)?;
+    let time = (start + time.start) .. start.checked_add(time.end).unwrap_or(time.end);
+
+    let mut reader = TraceReader::new(BufReader::new(File::open(input)?)).map_err(|e| STError::decode(0, e))?;
+    let mut header = TraceHeader::new(reader.header().workers, reader.header().source.clone(), reader.header().encoding, reader.header().compression);
+    header.start_time = reader.header().start_time;
+    let mut writer = TraceWriter::new(&header, BufWriter::new(File::create(output)?))?;
+
+    let mut trim = Trim::default();
+    let (mut read, mut written) = (0, 0);
+    while let Some(batch) = reader.next_batch().map_err(|e| STError::decode(reader.offset(), e))? {
+        // names keep their ids, as they are interned in the same order
+        let names = reader.names();
+        for id in writer.names().len() .. names.len() {
+            writer.intern(names.resolve(id as NameId).expect("names are consecutive"));
+        }
+
+        read += batch.len();
+        let batch: Vec<_> = batch.into_iter()
+            .filter_map(|record| {
+                let in_range = time.contains(&record.timestamp) && epochs.contains(&record.epoch);
+                trim.keep(record, in_range)
+            })
+            .collect();
+        written += batch.len();
+        if !batch.is_empty() {
+            writer.write_batch(&batch)?;
+        }
+    }
+    writer.finish()?;
+
+    output_format.print(
+        format_args!("Kept {} of {} records from {} in {}", written, read, input.display(), output.display()),
+        json!({ "input": input, "output": output, "records_read": read, "records_written": written }));
+    Ok(())
+}
+
+/// Timestamp of the earliest record in the trace file `path`
+fn earliest_timestamp(path: &Path) -> Result<Duration, STError> {
+    let mut reader = TraceReader::new(BufReader::new(File::open(path)?)).map_err(|e| STError::decode(0, e))?;
+    let mut earliest = None;
+    while let Some(batch) = reader.next_batch().map_err(|e| STError::decode(reader.offset(), e))? {
+        earliest = batch.iter().map(|record| record.timestamp).chain(earliest).min();
+    }
+    Ok(earliest.unwrap_or_default())
+}
+
+/// Decides which records to keep
+#[derive(Default)]
+struct Trim {
+    /// (worker, activity type, operator) of kept `Start`s without `End`
+    open: HashSet<(u64, st2_logformat::ActivityType, Option<u64>)>,
+    /// (worker, operator) -> the operator's name, if seen already
+    names: HashMap<(u64, u64), Value>,
+    /// (worker, operator) whose name has been written
+    named: HashSet<(u64, u64)>,
+}
+
+impl Trim {
+    fn keep(&mut self, mut record: LogRecord, in_range: bool) -> Option<LogRecord> {
+        let operator = record.operator_id.map(|operator| (record.local_worker, operator));
+        if let (Some(operator), Some(name)) = (operator, tagged::get(&record.tagged, tagged::OPERATOR_NAME)) {
+            self.names.insert(operator, name.clone());
+        }
+
+        let activity = (record.local_worker, record.activity_type, record.operator_id);
+        let keep = match record.event_type {
+            EventType::Start => in_range && self.open.insert(activity),
+            EventType::End => self.open.remove(&activity),
+            EventType::Sent | EventType::Received => in_range,
+        };
+        if !keep {
+            return None;
+        }
+
+        if let Some(operator) = operator {
+            if self.named.insert(operator) {
+                if let Some(name) = self.names.get(&operator) {
+                    tagged::set(&mut record.tagged, tagged::OPERATOR_NAME, name.clone());
+                }
+            }
+        }
+        Some(record)
+    }
+}
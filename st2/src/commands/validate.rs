@@ -0,0 +1,234 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use st2_logformat::{ActivityType, EventType, LogRecord};
+use st2_logformat::trace::TraceReader;
+
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+/// Maximum number of examples reported per check
+const MAX_EXAMPLES: usize = 10;
+
+/// A check that failed for a trace
+#[derive(Serialize, Debug)]
+pub struct Problem {
+    /// The failed check: `format`, `monotonic_timestamps`, `balanced_start_end`,
+    /// `matched_messages`, or `consistent_epochs`
+    pub check: &'static str,
+    /// Number of violations
+    pub count: u64,
+    /// Descriptions of the first violations
+    pub examples: Vec<String>,
+}
+
+/// Result of validating a trace
+#[derive(Serialize, Debug)]
+pub struct Report {
+    /// The validated trace files
+    pub traces: Vec<String>,
+    /// Number of records read
+    pub records: u64,
+    /// Whether all checks passed
+    pub valid: bool,
+    /// The failed checks
+    pub problems: Vec<Problem>,
+}
+
+/// The `validate` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("validate")
+        .about("Check trace files for format integrity and consistency, printing a JSON report")
+        .arg(clap::Arg::with_name("traces")
+            .value_name("TRACE")
+            .help("Trace files to validate together (e.g. those of all ST2 peers of a recording)")
+            .multiple(true)
+            .required(true))
+}
+
+/// The arguments of `validate`
+pub struct Options {
+    /// The validated trace files
+    pub traces: Vec<PathBuf>,
+}
+
+/// Parses the arguments of `validate`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        traces: args.values_of("traces").expect("error parsing validate trace args")
+            .map(PathBuf::from)
+            .collect(),
+    })
+}
+
+/// Validates the trace split across the trace files `paths` (e.g. one per ST2 peer
+/// of `record`) and prints a JSON report to `stdout`, pretty-printed unless JSON
+/// output was requested. Returns whether all checks passed.
+pub fn run(paths: &[&Path], output_format: OutputFormat) -> Result<bool, STError> {
+    let report = validate(paths)?;
+    let json = match output_format {
+        OutputFormat::Text => serde_json::to_string_pretty(&report),
+        OutputFormat::Json => serde_json::to_string(&report),
+    };
+    println!("{}", json.expect("couldn't serialize report"));
+
+    if !report.valid {
+        eprintln!("Trace failed {} checks", report.problems.len());
+    }
+    Ok(report.valid)
+}
+
+/// Validates the trace split across the trace files `paths`.
+pub fn validate(paths: &[&Path]) -> Result<Report, STError> {
+    let mut validator = Validator::default();
+
+    for path in paths {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let mut reader = match TraceReader::new(file) {
+            Ok(reader) => reader,
+            Err(e) => {
+                validator.problem("format", format!("{}: invalid header: {}", path.display(), e));
+                continue;
+            }
+        };
+
+        loop {
+            match reader.next_batch() {
+                Ok(Some(batch)) => batch.iter().for_each(|record| validator.record(record)),
+                Ok(None) => break,
+                Err(e) => {
+                    validator.problem("format", format!("{}: {}", path.display(), e));
+                    break;
+                }
+            }
+        }
+
+        let damage = reader.damage();
+        if damage.regions > 0 {
+            validator.problem("format", format!("{}: {} damaged regions ({} bytes)", path.display(), damage.regions, damage.bytes));
+        }
+        if let Some(truncation) = reader.truncation() {
+            validator.problem("format", format!("{}: {}", path.display(), truncation));
+        }
+    }
+
+    validator.finish();
+
+    let problems: Vec<_> = validator.problems.into_iter()
+        .map(|(check, (count, examples))| Problem { check, count, examples })
+        .collect();
+    Ok(Report {
+        traces: paths.iter().map(|path| path.display().to_string()).collect(),
+        records: validator.records,
+        valid: problems.is_empty(),
+        problems,
+    })
+}
+
+/// Checks records as they are read
+#[derive(Default)]
+struct Validator {
+    records: u64,
+    /// check -> (count, examples)
+    problems: BTreeMap<&'static str, (u64, Vec<String>)>,
+    /// worker -> last record's (seq_no, timestamp)
+    last: HashMap<u64, (u64, std::time::Duration)>,
+    /// (worker, activity type, operator) -> seq_no of the open Start
+    open: HashMap<(u64, ActivityType, Option<u64>), u64>,
+    /// (sender, receiver, activity type, channel, correlator) -> #(sent) - #(received)
+    messages: HashMap<(u64, u64, ActivityType, Option<u64>, Option<u64>), i64>,
+    /// worker -> epochs
+    epochs: BTreeMap<u64, BTreeSet<u64>>,
+}
+
+impl Validator {
+    fn problem(&mut self, check: &'static str, example: String) {
+        let problem = self.problems.entry(check).or_insert((0, Vec::new()));
+        problem.0 += 1;
+        if problem.1.len() < MAX_EXAMPLES {
+            problem.1.push(example);
+        }
+    }
+
+    fn record(&mut self, record: &LogRecord) {
+        self.records += 1;
+        let worker = record.local_worker;
+
+        if let Some((seq_no, timestamp)) = self.last.insert(worker, (record.seq_no, record.timestamp)) {
+            // seq_nos restart when the source computation is restarted
+            if record.seq_no > seq_no && record.timestamp < timestamp {
+                self.problem("monotonic_timestamps", format!(
+                    "worker {}: seq_no {} at {:?} precedes seq_no {} at {:?}",
+                    worker, seq_no, timestamp, record.seq_no, record.timestamp));
+            }
+        }
+
+        self.epochs.entry(worker).or_insert_with(BTreeSet::new).insert(record.epoch);
+
+        match record.event_type {
+            EventType::Start => {
+                let key = (worker, record.activity_type, record.operator_id);
+                if let Some(open) = self.open.insert(key, record.seq_no) {
+                    self.problem("balanced_start_end", format!(
+                        "worker {}: {:?} of operator {:?} started at seq_no {} and {} without ending",
+                        worker, record.activity_type, record.operator_id, open, record.seq_no));
+                }
+            }
+            EventType::End => {
+                let key = (worker, record.activity_type, record.operator_id);
+                if self.open.remove(&key).is_none() {
+                    self.problem("balanced_start_end", format!(
+                        "worker {}: {:?} of operator {:?} ended at seq_no {} without starting",
+                        worker, record.activity_type, record.operator_id, record.seq_no));
+                }
+            }
+            EventType::Sent | EventType::Received => {
+                let remote = match record.remote_worker {
+                    Some(remote) => remote,
+                    None => {
+                        self.problem("matched_messages", format!(
+                            "worker {}: {:?} at seq_no {} has no remote worker",
+                            worker, record.activity_type, record.seq_no));
+                        return;
+                    }
+                };
+                let (key, diff) = if record.event_type == EventType::Sent {
+                    ((worker, remote, record.activity_type, record.channel_id, record.correlator_id), 1)
+                } else {
+                    ((remote, worker, record.activity_type, record.channel_id, record.correlator_id), -1)
+                };
+                *self.messages.entry(key).or_insert(0) += diff;
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        let open: Vec<_> = self.open.drain().collect();
+        for ((worker, activity_type, operator_id), seq_no) in open {
+            self.problem("balanced_start_end", format!(
+                "worker {}: {:?} of operator {:?} started at seq_no {} but never ended",
+                worker, activity_type, operator_id, seq_no));
+        }
+
+        let messages: Vec<_> = self.messages.drain().filter(|(_, diff)| *diff != 0).collect();
+        for ((sender, receiver, activity_type, channel_id, correlator_id), diff) in messages {
+            let (count, unmatched) = if diff > 0 { (diff, "sends") } else { (-diff, "receives") };
+            self.problem("matched_messages", format!(
+                "{} unmatched {} of {:?} from worker {} to {} (channel {:?}, correlator {:?})",
+                count, unmatched, activity_type, sender, receiver, channel_id, correlator_id));
+        }
+
+        let all: BTreeSet<u64> = self.epochs.values().flatten().cloned().collect();
+        let missing: Vec<_> = self.epochs.iter()
+            .map(|(worker, epochs)| (*worker, all.difference(epochs).cloned().collect::<Vec<_>>()))
+            .filter(|(_, missing)| !missing.is_empty())
+            .collect();
+        for (worker, missing) in missing {
+            self.problem("consistent_epochs", format!(
+                "worker {}: no records of {} epochs other workers logged, e.g. {:?}",
+                worker, missing.len(), &missing[.. std::cmp::min(missing.len(), MAX_EXAMPLES)]));
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Verifies that online analysis matches offline analysis of the same events:
+//! ST2 analyzes a live session while recording every source's events to
+//! `*.dump` files, then analyzes the recording offline, and diffs the per-epoch
+//! results of both (cf. `store::epoch_samples`). Online, epochs are completed
+//! as the sources stream in, with throttling, budgets, and batches cut by
+//! arrival; the offline analysis of the complete recording is the ground truth.
+
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::replay::Recorder;
+use crate::store::{completed_epochs, epoch_samples, Labels, Sample};
+
+use timely::dataflow::Stream;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, atomic::AtomicBool};
+use std::time::Duration;
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::filter::Filter;
+use st2_timely::replay_throttled::ReplaySpeed;
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{OutputFormat, STError};
+use crate::config::Args;
+
+/// Mismatches printed as text; JSON has all of them
+const PRINTED: usize = 20;
+
+/// What an analysis found in an epoch
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EpochResult {
+    /// Number of PAG edges
+    pub edges: usize,
+    /// Samples of the epoch's metrics, without `backlog_epochs`, which depends
+    /// on how far the sources were ahead
+    pub samples: Vec<Sample>,
+}
+
+/// Results of an analysis, by epoch
+pub type Results = BTreeMap<u64, EpochResult>;
+
+/// A difference between the online and the offline results of an epoch
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Mismatch {
+    /// The epoch
+    pub epoch: u64,
+    /// What differs: `epoch` if only one analysis completed it (the values
+    /// are edge counts), `edges`, or a metric's series
+    pub what: String,
+    /// The online value, `None` if missing online
+    pub online: Option<f64>,
+    /// The offline value, `None` if missing offline
+    pub offline: Option<f64>,
+}
+
+/// Differences between `online` and `offline` results: epochs only one of them
+/// completed, and edge counts and samples that differ by more than `tolerance`
+/// (relative to the larger value).
+pub fn mismatches(online: &Results, offline: &Results, tolerance: f64) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+    let mut mismatch = |epoch: u64, what: String, online: Option<f64>, offline: Option<f64>| {
+        let equal = match (online, offline) {
+            (Some(a), Some(b)) => a == b || (a - b).abs() <= tolerance * f64::max(a.abs(), b.abs()),
+            _ => false,
+        };
+        if !equal {
+            mismatches.push(Mismatch { epoch, what, online, offline });
+        }
+    };
+
+    let epochs: std::collections::BTreeSet<u64> = online.keys().chain(offline.keys()).cloned().collect();
+    for epoch in epochs {
+        let (a, b) = match (online.get(&epoch), offline.get(&epoch)) {
+            (Some(a), Some(b)) => (a, b),
+            (a, b) => {
+                mismatch(epoch, "epoch".to_string(), a.map(|a| a.edges as f64), b.map(|b| b.edges as f64));
+                continue;
+            }
+        };
+        mismatch(epoch, "edges".to_string(), Some(a.edges as f64), Some(b.edges as f64));
+
+        let series = |result: &EpochResult| -> BTreeMap<(&'static str, Labels), f64> {
+            result.samples.iter().map(|sample| ((sample.name, sample.labels.clone()), sample.value)).collect()
+        };
+        let (a, b) = (series(a), series(b));
+        let keys: std::collections::BTreeSet<_> = a.keys().chain(b.keys()).collect();
+        for key in keys {
+            mismatch(epoch, series_name(key.0, &key.1), a.get(key).cloned(), b.get(key).cloned());
+        }
+    }
+    mismatches
+}
+
+/// `name{key="value",...}`, or just `name` without labels
+fn series_name(name: &str, labels: &Labels) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+    let labels: Vec<String> = labels.iter().map(|(key, value)| format!("{}=\"{}\"", key, value)).collect();
+    format!("{}{{{}}}", name, labels.join(","))
+}
+
+/// Collects the results of every completed epoch of `pag` in `results`
+fn collect<G>(pag: &Stream<G, (PagEdge, Pair<u64, Duration>, isize)>, name: &str, results: Arc<Mutex<Results>>, operator_names: BTreeMap<u64, String>)
+where
+    G: timely::dataflow::Scope<Timestamp = Pair<u64, Duration>>,
+{
+    completed_epochs(pag, name, move |epoch, edges, _ahead| {
+        let samples = epoch_samples(epoch, &edges, 0, &operator_names)
+            .into_iter()
+            .filter(|sample| sample.name != "backlog_epochs")
+            .collect();
+        results.lock().unwrap().insert(epoch, EpochResult { edges: edges.len(), samples });
+    });
+}
+
+/// The `verify` subcommand and its arguments
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name("verify")
+        .about("analyze a live session while recording it, then analyze the recording offline and diff both results")
+        .arg(clap::Arg::with_name("record_dir")
+            .long("record-dir")
+            .value_name("DIR")
+            .help("Record the session's *.dump files to DIR")
+            .required(true))
+        .arg(clap::Arg::with_name("tolerance")
+            .long("tolerance")
+            .value_name("FRACTION")
+            .help("Relative difference up to which online and offline values match")
+            .default_value("0"))
+}
+
+/// The arguments of `verify`
+pub struct Options {
+    /// The directory to record the session to
+    pub record_dir: PathBuf,
+    /// The tolerated relative difference of results
+    pub tolerance: f64,
+}
+
+/// Parses the arguments of `verify`
+pub fn from_args(args: &Args) -> Result<Options, STError> {
+    Ok(Options {
+        record_dir: PathBuf::from(args.value_of("record_dir").expect("error parsing verify record dir args")),
+        tolerance: args.value_of("tolerance").expect("error parsing verify tolerance args")
+            .parse().ok().filter(|fraction: &f64| *fraction >= 0.0 && fraction.is_finite())
+            .ok_or_else(|| STError::Config("Invalid --tolerance: expected a non-negative fraction".to_string()))?,
+    })
+}
+
+/// Analyzes the live `replay_source` while recording it to `<source>.dump`
+/// files in `dir`, then analyzes the recording offline, and
+/// prints the mismatches between both results (cf. `mismatches`) as text or
+/// JSON. Returns the number of mismatches.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    is_running: Arc<AtomicBool>,
+    speed: ReplaySpeed,
+    filter: Filter,
+    dir: &Path,
+    tolerance: f64,
+    operator_names: &BTreeMap<u64, String>,
+    output_format: OutputFormat) -> Result<u64, STError> {
+
+    let sources = match &replay_source {
+        ReplaySource::Tcp(sockets) => sockets.lock().expect("sockets poisoned").len(),
+        ReplaySource::Files(files) => files.lock().expect("replay files poisoned").len(),
+    };
+    std::fs::create_dir_all(dir).map_err(|e| STError::io(format!("couldn't create {}", dir.display()), e))?;
+    let paths: Vec<PathBuf> = (0 .. sources).map(|source| dir.join(format!("{}.dump", source))).collect();
+    let recording = ReplaySource::Files(Arc::new(Mutex::new(paths.iter().cloned().map(Some).collect())));
+
+    let online = Arc::new(Mutex::new(Results::new()));
+    let offline = Arc::new(Mutex::new(Results::new()));
+    let (online_results, offline_results) = (Arc::clone(&online), Arc::clone(&offline));
+    let names = operator_names.clone();
+
+    let local_peers = crate::local_peers(&timely_configuration);
+
+    timely::execute(timely_configuration, move |worker| {
+        crate::self_profile::attach(worker);
+        let index = worker.index();
+        let local_index = index % local_peers;
+
+        // online, recording the sources; a worker's recordings are the files
+        // it replays offline
+        let readers: Vec<_> = crate::replay::make_readers(replay_source.clone(), local_index, local_peers).expect("couldn't create readers")
+            .into_iter()
+            .enumerate()
+            .map(|(i, reader)| Recorder::create(reader, &paths[i * local_peers + local_index]).expect("couldn't record source"))
+            .collect();
+        worker.dataflow(|scope| {
+            let pag = pag::create_pag(scope, readers, index, Some(Arc::clone(&is_running)), 1, speed, filter.clone());
+            collect(&pag, "VerifyOnline", Arc::clone(&online_results), names.clone());
+        });
+
+        // the recording is complete, and its files closed, once the online
+        // dataflow finished
+        while worker.step_or_park(None) {}
+
+        // offline, from the recording
+        let readers = crate::replay::make_readers(recording.clone(), local_index, local_peers).expect("couldn't create readers");
+        worker.dataflow(|scope| {
+            let pag = pag::create_pag(scope, readers, index, None, 1, ReplaySpeed::Unbounded, filter.clone());
+            collect(&pag, "VerifyOffline", Arc::clone(&offline_results), names.clone());
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the timely computation: {}", x)))?;
+
+    let (online, offline) = (online.lock().unwrap(), offline.lock().unwrap());
+    let mismatches = mismatches(&online, &offline, tolerance);
+
+    let mut text = format!("Verified {} online against {} offline epochs (recorded to {}): ", online.len(), offline.len(), dir.display());
+    if mismatches.is_empty() {
+        text.push_str("results match");
+    } else {
+        text.push_str(&format!("{} mismatches", mismatches.len()));
+        let value = |value: Option<f64>| value.map_or_else(|| "missing".to_string(), |value| value.to_string());
+        for mismatch in mismatches.iter().take(PRINTED) {
+            text.push_str(&format!("\n  epoch {}: {}: online {}, offline {}", mismatch.epoch, mismatch.what, value(mismatch.online), value(mismatch.offline)));
+        }
+        if mismatches.len() > PRINTED {
+            text.push_str(&format!("\n  ... and {} more (cf. --output json)", mismatches.len() - PRINTED));
+        }
+    }
+    output_format.print(text, json!({
+        "recording": dir.display().to_string(),
+        "online_epochs": online.len(),
+        "offline_epochs": offline.len(),
+        "mismatches": mismatches,
+    }));
+
+    Ok(mismatches.len() as u64)
+}
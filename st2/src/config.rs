@@ -0,0 +1,278 @@
+//! `--config` files: TOML files providing defaults for command-line arguments,
+//! so deployments can be described in a file rather than in shell scripts.
+//!
+//! Top-level keys set global arguments, and tables named after a subcommand set
+//! that subcommand's arguments. Keys are the arguments' long names, values are
+//! strings, numbers, `true` for flags, or arrays of these for arguments that can
+//! be given several times. The `operator-names` table maps operator ids to the
+//! names reports show them with, and the `scripts` table defines custom metrics
+//! and alerts (cf. `scripting`):
+//!
+//! ```toml
+//! from-file = "traces/run-1"
+//! source-peers = 4
+//! replay-speed = "realtime"
+//!
+//! [dashboard]
+//! listen = "0.0.0.0:3012"
+//! epoch-max = 500
+//!
+//! [alerts]
+//! rule = ["latency > 500ms", "skew > 2"]
+//!
+//! [operator-names]
+//! 3 = "Map"
+//!
+//! [[scripts.alert]]
+//! name = "slow_map"
+//! when = 'operators.Map > 10_000_000.0'
+//! ```
+//!
+//! Arguments given on the command line override the config file (cf. `Args`).
+//! Keys that aren't arguments are rejected, and values are checked like those on
+//! the command line, e.g. for conflicting arguments (cf. `Config::check`).
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::path::Path;
+
+use clap::{App, AppSettings, ArgMatches, ErrorKind};
+use toml::Value;
+
+use crate::STError;
+
+/// Name of the table mapping operator ids to names
+const OPERATOR_NAMES: &str = "operator-names";
+/// Name of the table of scripted metrics and alerts
+const SCRIPTS: &str = "scripts";
+
+/// Argument values of a config file
+#[derive(Default, Debug)]
+pub struct Config {
+    /// (subcommand, long name) -> values; `None` for global arguments
+    values: HashMap<(Option<String>, String), Vec<String>>,
+    /// operator id -> name
+    operator_names: BTreeMap<u64, String>,
+    /// the uncompiled `scripts` table
+    scripts: Option<toml::value::Table>,
+}
+
+impl Config {
+    /// Reads the config file at `path`.
+    pub fn load(path: &Path) -> Result<Self, STError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| STError::Config(format!("Invalid --config: {}: {}", path.display(), e)))?;
+        contents.parse()
+    }
+
+    /// The value of the argument with long name `long` of `subcommand` (`None` for
+    /// global arguments), if set. Flags are set to `"true"`.
+    pub fn value(&self, subcommand: Option<&str>, long: &str) -> Option<&str> {
+        self.values(subcommand, long).and_then(|values| values.last()).map(|value| value.as_str())
+    }
+
+    /// All values of an argument that can be given several times, if set.
+    pub fn values(&self, subcommand: Option<&str>, long: &str) -> Option<&[String]> {
+        self.values.get(&(subcommand.map(|s| s.to_string()), long.to_string())).map(|values| values.as_slice())
+    }
+
+    /// Names of operators, by id
+    pub fn operator_names(&self) -> &BTreeMap<u64, String> {
+        &self.operator_names
+    }
+
+    /// The `scripts` table, if any (cf. `scripting::Scripts::compile`)
+    pub fn scripts(&self) -> Option<&toml::value::Table> {
+        self.scripts.as_ref()
+    }
+
+    /// Checks the config against `app`, given the command line `args` (starting
+    /// with the program name) of its `subcommand`: every key has to be an
+    /// argument of `app`, or of the subcommand its table is named after, and the
+    /// values of global arguments and of `subcommand` have to pass `app`'s checks
+    /// of possible values, conflicts, and requirements together with `args`.
+    pub fn check(&self, app: &App, args: &[OsString], subcommand: Option<&str>) -> Result<(), STError> {
+        let mut keys: Vec<_> = self.values.iter().collect();
+        keys.sort();
+
+        let (mut global, mut local) = (Vec::new(), Vec::new());
+        for ((table, long), values) in keys {
+            let table = table.as_ref().map(String::as_str);
+            let flag = match kind(app, table, long) {
+                Some(flag) => flag,
+                None => return Err(STError::Config(match table {
+                    Some(table) => format!("Invalid --config: {} isn't an argument of `{}`", long, table),
+                    None => format!("Invalid --config: {} isn't an argument", long),
+                })),
+            };
+            let config_args = match table {
+                None => &mut global,
+                Some(table) if Some(table) == subcommand => &mut local,
+                Some(_) => continue,
+            };
+            for value in values {
+                config_args.push(OsString::from(if flag && value == "true" { format!("--{}", long) } else { format!("--{}={}", long, value) }));
+            }
+        }
+
+        // global arguments have to precede the subcommand, so the config's go
+        // first, and the subcommand's follow the command line's
+        let merged: Vec<OsString> = args.iter().take(1).cloned()
+            .chain(global)
+            .chain(args.iter().skip(1).cloned())
+            .chain(local)
+            .collect();
+        app.clone()
+            .global_settings(&[AppSettings::AllArgsOverrideSelf, AppSettings::ColorNever])
+            .get_matches_from_safe(merged)
+            .map(|_| ())
+            .map_err(|e| STError::Config(format!("Invalid --config: {}", e.message.lines().next().unwrap_or_default().trim_start_matches("error: "))))
+    }
+}
+
+/// Whether `long` is a flag (`true`) or an option (`false`) of `subcommand` of
+/// `app` (`None` for global arguments); `None` if it's neither.
+fn kind(app: &App, subcommand: Option<&str>, long: &str) -> Option<bool> {
+    let probe: Vec<String> = std::iter::once("st2".to_string())
+        .chain(subcommand.map(|subcommand| subcommand.to_string()))
+        .chain(std::iter::once(format!("--{}", long)))
+        .collect();
+    match app.clone().get_matches_from_safe(probe) {
+        Ok(_) => Some(true),
+        Err(e) => match e.kind {
+            ErrorKind::UnknownArgument | ErrorKind::UnrecognizedSubcommand | ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => None,
+            ErrorKind::EmptyValue => Some(false),
+            // e.g. missing positional arguments of the subcommand
+            _ => Some(true),
+        },
+    }
+}
+
+/// Command-line arguments, falling back to the `--config` file
+/// for arguments not given on the command line
+#[derive(Clone, Copy)]
+pub struct Args<'a> {
+    /// The arguments given on the command line
+    pub matches: &'a ArgMatches<'a>,
+    /// The config file
+    pub config: &'a Config,
+    /// The subcommand the arguments belong to, `None` for global arguments
+    pub subcommand: Option<&'a str>,
+}
+
+impl<'a> Args<'a> {
+    /// The value of argument `name`: given on the command line, set in the
+    /// config file, or its default, in that order
+    pub fn value_of(&self, name: &str) -> Option<&'a str> {
+        if self.matches.occurrences_of(name) > 0 {
+            return self.matches.value_of(name);
+        }
+        self.config.value(self.subcommand, &config_key(name)).or_else(|| self.matches.value_of(name))
+    }
+
+    /// Values of positional arguments, which can only be given on the command line
+    pub fn values_of(&self, name: &str) -> Option<clap::Values<'a>> {
+        self.matches.values_of(name)
+    }
+
+    /// Values of options that can be given several times; those on the command
+    /// line replace those of the config file.
+    pub fn all_values_of(&self, name: &str) -> Vec<&'a str> {
+        match self.matches.values_of(name) {
+            Some(values) if self.matches.occurrences_of(name) > 0 => values.collect(),
+            _ => match self.config.values(self.subcommand, &config_key(name)) {
+                Some(values) => values.iter().map(|value| value.as_str()).collect(),
+                None => self.matches.values_of(name).map(|values| values.collect()).unwrap_or_default(),
+            },
+        }
+    }
+
+    /// Whether flag `name` is given on the command line or set in the config file
+    pub fn is_present(&self, name: &str) -> bool {
+        self.matches.is_present(name) || self.config.value(self.subcommand, &config_key(name)).is_some()
+    }
+
+    /// The name and arguments of the subcommand given on the command line
+    pub fn subcommand(&self) -> (&'a str, Option<Args<'a>>) {
+        let (name, matches) = self.matches.subcommand();
+        (name, matches.map(|matches| Args { matches, config: self.config, subcommand: Some(name) }))
+    }
+}
+
+/// The config file key of argument `name`, i.e., its long name
+fn config_key(name: &str) -> String {
+    match name {
+        "output_path" | "out_dir" | "output" => "out".to_string(),
+        "output_format" => "output".to_string(),
+        name => name.replace('_', "-"),
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let table = match s.parse::<Value>() {
+            Ok(Value::Table(table)) => table,
+            Ok(_) => unreachable!("TOML documents are tables"),
+            Err(e) => return Err(STError::Config(format!("Invalid --config: {}", e))),
+        };
+
+        let mut config = Config::default();
+        for (key, value) in table {
+            match value {
+                Value::Table(table) if key == OPERATOR_NAMES => {
+                    for (id, name) in table {
+                        let id = id.parse().map_err(|_| STError::Config(format!("Invalid --config: operator id {} is not a number", id)))?;
+                        match name {
+                            Value::String(name) => { config.operator_names.insert(id, name); }
+                            _ => return Err(STError::Config(format!("Invalid --config: name of operator {} is not a string", id))),
+                        }
+                    }
+                }
+                Value::Table(table) if key == SCRIPTS => config.scripts = Some(table),
+                Value::Table(table) => {
+                    for (long, value) in table {
+                        let values = values(&format!("{}.{}", key, long), value)?;
+                        if !values.is_empty() {
+                            config.values.insert((Some(key.clone()), long), values);
+                        }
+                    }
+                }
+                value => {
+                    let values = values(&key, value)?;
+                    if !values.is_empty() {
+                        config.values.insert((None, key), values);
+                    }
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// The argument values of `key`: the elements of `value` if it's an array, otherwise `value`.
+fn values(key: &str, value: Value) -> Result<Vec<String>, STError> {
+    match value {
+        Value::Array(array) => Ok(array.into_iter()
+            .map(|value| scalar(key, value))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .collect()),
+        value => Ok(scalar(key, value)?.into_iter().collect()),
+    }
+}
+
+/// The argument value `value` of `key` as it would be given on the command line.
+/// Returns `None` for unset flags.
+fn scalar(key: &str, value: Value) -> Result<Option<String>, STError> {
+    match value {
+        Value::String(s) => Ok(Some(s)),
+        Value::Integer(i) => Ok(Some(i.to_string())),
+        Value::Float(f) => Ok(Some(f.to_string())),
+        Value::Boolean(true) => Ok(Some("true".to_string())),
+        Value::Boolean(false) => Ok(None),
+        _ => Err(STError::Config(format!("Invalid --config: {} has to be a string, number, or boolean", key))),
+    }
+}
@@ -0,0 +1,77 @@
+//! Deterministic analysis (`--deterministic`), so that the same trace always
+//! produces byte-identical outputs, e.g. for golden tests or reproducible
+//! research. Once `enable`d:
+//!
+//! - sources are `*.dump` files, assigned to workers by their index, and read
+//!   on the analysis workers rather than prefetched, so that how far a file was
+//!   decoded doesn't decide which events make it into a batch (cf. `main`),
+//! - streams passed through `Deterministic::deterministic` are gathered at the
+//!   first ST2 worker and every epoch's data is released at once, sorted, after
+//!   the epoch completed, so batch boundaries and the interleaving of workers
+//!   don't show in the outputs, and
+//! - nothing is left to chance: sampling under a memory budget and random salts
+//!   are ruled out (cf. `main`).
+//!
+//! Ties between equally long paths are broken by the paths' edges regardless of
+//! the mode (cf. `st2_logformat::pag::critical_path`).
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use timely::Data;
+use timely::dataflow::{Scope, Stream};
+use timely::dataflow::channels::pact::Exchange;
+use timely::dataflow::operators::generic::operator::Operator;
+
+/// Whether analyses are deterministic, cf. `enable`
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Makes analyses deterministic (`--deterministic`).
+pub fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Whether analyses are deterministic, cf. `enable`
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Fixes the order of a stream's data in deterministic mode
+pub trait Deterministic<S: Scope, D: Data + Ord> {
+    /// In deterministic mode, gathers the stream at the first worker and
+    /// releases every time's data at once, sorted, when the time completes.
+    /// Otherwise, the stream is passed on as is.
+    fn deterministic(&self) -> Stream<S, D>;
+}
+
+impl<S: Scope, D: Data + Ord> Deterministic<S, D> for Stream<S, D> {
+    fn deterministic(&self) -> Stream<S, D> {
+        if !enabled() {
+            return self.clone();
+        }
+
+        self.unary_frontier(Exchange::new(|_| 0), "Deterministic", |_capability, _info| {
+            let mut vector = Vec::new();
+            let mut pending = BTreeMap::new();
+
+            move |input, output| {
+                input.for_each(|cap, data| {
+                    data.swap(&mut vector);
+                    pending.entry(cap.time().clone())
+                        .or_insert_with(|| (cap.retain(), Vec::new()))
+                        .1.extend(vector.drain(..));
+                });
+
+                let complete: Vec<S::Timestamp> = pending.keys()
+                    .filter(|time| !input.frontier().less_equal(time))
+                    .cloned()
+                    .collect();
+                for time in complete {
+                    let (cap, mut data) = pending.remove(&time).expect("pending time vanished");
+                    data.sort();
+                    output.session(&cap).give_iterator(data.into_iter());
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,215 @@
+//! Durable per-epoch history in a local SQLite database (requires the `sqlite` feature).
+//!
+//! Small deployments can keep the summaries of all epochs and all fired alerts
+//! without running a database server, and query them with `sqlite3`. The database
+//! has three tables:
+//!
+//! - `epochs`: one row per epoch with its `epoch_latency_ns`, `critical_path_ns`,
+//!   `skew`, and `backlog_epochs` (cf. `store::METRICS`)
+//! - `metrics`: all samples of the epoch, with their labels as a JSON object
+//! - `alerts`: every fired alert, with its critical path as JSON
+//!
+//! Rows are appended; tables are created if they don't exist. With a retention
+//! enabled (cf. `retention`), a background compactor deletes epochs, samples, and
+//! alerts older than its maximum age, and the oldest ones while the database is
+//! larger than its maximum size (cf. `compact`).
+
+use crate::commands::alerts::Alert;
+use crate::retention::Retention;
+use crate::store::Sample;
+use crate::STError;
+
+use std::path::Path;
+use std::time::Duration;
+
+/// How long to wait for a compactor (or another writer) holding the database
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Compactions delete one in this many rows at a time while the database is too large
+#[cfg(feature = "sqlite")]
+const COMPACTION_STEP: i64 = 10;
+
+/// Tables of the history database
+pub const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS epochs (
+    epoch INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    latency_ns INTEGER,
+    critical_path_ns INTEGER,
+    skew REAL,
+    backlog_epochs INTEGER
+);
+CREATE INDEX IF NOT EXISTS epochs_epoch ON epochs (epoch);
+CREATE TABLE IF NOT EXISTS metrics (
+    epoch INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    labels TEXT NOT NULL,
+    value REAL NOT NULL
+);
+CREATE INDEX IF NOT EXISTS metrics_name_epoch ON metrics (name, epoch);
+CREATE TABLE IF NOT EXISTS alerts (
+    fired_at INTEGER NOT NULL,
+    rule TEXT NOT NULL,
+    message TEXT NOT NULL,
+    window_from INTEGER NOT NULL,
+    window_to INTEGER NOT NULL,
+    value REAL NOT NULL,
+    threshold REAL NOT NULL,
+    epoch INTEGER NOT NULL,
+    latency_ns INTEGER NOT NULL,
+    critical_path TEXT NOT NULL
+);
+";
+
+/// An open history database
+pub struct History {
+    #[cfg(feature = "sqlite")]
+    connection: rusqlite::Connection,
+}
+
+#[cfg(feature = "sqlite")]
+impl History {
+    /// Opens the database at `path`, creating it and its tables if necessary.
+    pub fn open(path: &Path) -> Result<Self, STError> {
+        let connection = rusqlite::Connection::open(path)
+            .and_then(|connection| connection.busy_timeout(BUSY_TIMEOUT).map(|_| connection))
+            .and_then(|connection| connection.execute_batch(SCHEMA).map(|_| connection))
+            .map_err(|e| STError::io(format!("couldn't open {}", path.display()), std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+
+        let compacted = path.to_path_buf();
+        crate::retention::spawn(format!("history {}", path.display()), move |retention| compact(&compacted, retention));
+        Ok(History { connection })
+    }
+
+    /// Appends the summary and `samples` of `epoch`, in a single transaction.
+    pub fn insert_epoch(&mut self, epoch: u64, samples: &[Sample]) -> Result<(), STError> {
+        let value = |name: &str| samples.iter().find(|sample| sample.name == name && sample.labels.is_empty()).map(|sample| sample.value);
+        let timestamp = samples.iter().map(|sample| sample.timestamp).max().unwrap_or_default();
+
+        let transaction = self.connection.transaction().map_err(sqlite_error)?;
+        transaction.execute(
+            "INSERT INTO epochs (epoch, timestamp, latency_ns, critical_path_ns, skew, backlog_epochs) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![
+                epoch as i64,
+                timestamp as i64,
+                value("epoch_latency_ns").map(|ns| ns as i64),
+                value("critical_path_ns").map(|ns| ns as i64),
+                value("skew"),
+                value("backlog_epochs").map(|epochs| epochs as i64),
+            ]).map_err(sqlite_error)?;
+        {
+            let mut insert = transaction.prepare_cached("INSERT INTO metrics (epoch, timestamp, name, labels, value) VALUES (?1, ?2, ?3, ?4, ?5)")
+                .map_err(sqlite_error)?;
+            for sample in samples {
+                let labels = serde_json::to_string(&sample.labels).expect("labels are serializable");
+                insert.execute(rusqlite::params![sample.epoch as i64, sample.timestamp as i64, sample.name, labels, sample.value])
+                    .map_err(sqlite_error)?;
+            }
+        }
+        transaction.commit().map_err(sqlite_error)
+    }
+
+    /// Appends `alert`, fired at `fired_at` (in ns since the Unix epoch).
+    pub fn insert_alert(&mut self, alert: &Alert, fired_at: u64) -> Result<(), STError> {
+        let critical_path = serde_json::to_string(&alert.critical_path).expect("alerts are serializable");
+        self.connection.execute(
+            "INSERT INTO alerts (fired_at, rule, message, window_from, window_to, value, threshold, epoch, latency_ns, critical_path)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            rusqlite::params![
+                fired_at as i64,
+                alert.rule,
+                alert.message,
+                alert.window.0 as i64,
+                alert.window.1 as i64,
+                alert.value,
+                alert.threshold,
+                alert.epoch as i64,
+                alert.latency as i64,
+                critical_path,
+            ]).map(|_| ()).map_err(sqlite_error)
+    }
+}
+
+/// Deletes the epochs, samples, and alerts of the database at `path` that
+/// `retention` doesn't retain, and vacuums it. Rows are older than the maximum age
+/// if their `timestamp` (`fired_at` for alerts) is; while the database is larger
+/// than the maximum size, the oldest tenth of every table is deleted. Returns the
+/// number of deleted epochs and alerts.
+#[cfg(feature = "sqlite")]
+pub fn compact(path: &Path, retention: &Retention) -> Result<usize, STError> {
+    let connection = rusqlite::Connection::open(path).map_err(sqlite_error)?;
+    connection.busy_timeout(BUSY_TIMEOUT).map_err(sqlite_error)?;
+    let size = || std::fs::metadata(path).map(|metadata| metadata.len())
+        .map_err(|e| STError::io(format!("couldn't read {}", path.display()), e));
+
+    let mut deleted = 0;
+    if let Some(max_age) = retention.max_age {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        let cutoff = now.checked_sub(max_age).unwrap_or_default().as_nanos() as i64;
+        for (table, column) in &[("epochs", "timestamp"), ("metrics", "timestamp"), ("alerts", "fired_at")] {
+            let rows = connection.execute(&format!("DELETE FROM {} WHERE {} < ?1", table, column), rusqlite::params![cutoff])
+                .map_err(sqlite_error)?;
+            if *table != "metrics" {
+                deleted += rows;
+            }
+        }
+        if deleted > 0 {
+            connection.execute_batch("VACUUM").map_err(sqlite_error)?;
+        }
+    }
+
+    if let Some(max_bytes) = retention.max_bytes {
+        while size()? > max_bytes {
+            let mut rows = 0;
+            for table in &["epochs", "metrics", "alerts"] {
+                let count: i64 = connection.query_row(&format!("SELECT COUNT(*) FROM {}", table), rusqlite::params![], |row| row.get(0))
+                    .map_err(sqlite_error)?;
+                let step = (count + COMPACTION_STEP - 1) / COMPACTION_STEP;
+                let table_rows = connection.execute(
+                    &format!("DELETE FROM {0} WHERE rowid IN (SELECT rowid FROM {0} ORDER BY rowid LIMIT ?1)", table),
+                    rusqlite::params![step]).map_err(sqlite_error)?;
+                rows += table_rows;
+                if *table != "metrics" {
+                    deleted += table_rows;
+                }
+            }
+            if rows == 0 {
+                // nothing left to delete
+                break;
+            }
+            connection.execute_batch("VACUUM").map_err(sqlite_error)?;
+        }
+    }
+    Ok(deleted)
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_error(e: rusqlite::Error) -> STError {
+    STError::io("SQLite error", std::io::Error::new(std::io::ErrorKind::Other, e))
+}
+
+#[cfg(not(feature = "sqlite"))]
+impl History {
+    /// Opens the database at `path`, creating it and its tables if necessary.
+    pub fn open(_path: &Path) -> Result<Self, STError> {
+        Err(STError::Config("SQLite sinks require building with `--features sqlite`".to_string()))
+    }
+
+    /// Appends the summary and `samples` of `epoch`, in a single transaction.
+    pub fn insert_epoch(&mut self, _epoch: u64, _samples: &[Sample]) -> Result<(), STError> {
+        unreachable!("history databases can't be opened without the `sqlite` feature")
+    }
+
+    /// Appends `alert`, fired at `fired_at` (in ns since the Unix epoch).
+    pub fn insert_alert(&mut self, _alert: &Alert, _fired_at: u64) -> Result<(), STError> {
+        unreachable!("history databases can't be opened without the `sqlite` feature")
+    }
+}
+
+/// Deletes the epochs, samples, and alerts of the database at `path` that
+/// `retention` doesn't retain, and vacuums it.
+#[cfg(not(feature = "sqlite"))]
+pub fn compact(_path: &Path, _retention: &Retention) -> Result<usize, STError> {
+    unreachable!("history databases can't be opened without the `sqlite` feature")
+}
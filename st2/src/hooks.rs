@@ -0,0 +1,143 @@
+//! Per-epoch hooks: custom logic, e.g. business-specific checks, invoked inline
+//! with every completed epoch's PAG and metrics.
+//!
+//! Hooks are closures or implementations of `EpochHook`, registered by name:
+//!
+//! ```no_run
+//! use st2::hooks::Hooks;
+//! use st2::STError;
+//!
+//! let mut hooks = Hooks::new();
+//! hooks.register("checkout latency", |epoch: &st2::hooks::EpochContext| {
+//!     match epoch.sample("operator_critical_path_ns", &[("operator", "Checkout")]) {
+//!         Some(ns) if ns > 20e6 => Err(STError::Analysis(format!("Checkout took {} ms", ns / 1e6))),
+//!         _ => Ok(()),
+//!     }
+//! });
+//! ```
+//!
+//! They run in a pipeline (cf. `builder::STBuilder::hook`), or in a custom
+//! dataflow on a PAG stream (cf. `attach`). A hook's error is logged and counted
+//! as a failure, but doesn't stop the analysis.
+
+use crate::pag::PagEdge;
+use crate::store::{completed_epochs, epoch_samples, Sample};
+use crate::commands::alerts::EpochStats;
+
+use timely::dataflow::{Scope, Stream};
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+use std::time::Duration;
+
+use st2_logformat::pair::Pair;
+
+use crate::STError;
+
+/// A completed epoch, as seen by hooks
+pub struct EpochContext<'a> {
+    /// The epoch
+    pub epoch: u64,
+    /// The epoch's PAG edges
+    pub edges: &'a [PagEdge],
+    /// The epoch's critical path, in order
+    pub critical_path: &'a [PagEdge],
+    /// Time from the epoch's first to its last event, in ns
+    pub latency: u64,
+    /// Samples of all `store::METRICS`
+    pub samples: &'a [Sample],
+    /// Number of epochs the source computation is ahead of the epoch
+    pub ahead: u64,
+    /// Operator id → name, as configured
+    pub operator_names: &'a BTreeMap<u64, String>,
+}
+
+impl<'a> EpochContext<'a> {
+    /// The value of metric `name` in the series with exactly `labels`, e.g.
+    /// `("operator_critical_path_ns", &[("operator", "Map")])`
+    pub fn sample(&self, name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+        self.samples.iter()
+            .find(|sample| sample.name == name
+                  && sample.labels.len() == labels.len()
+                  && labels.iter().all(|(key, value)| sample.labels.get(*key).map(|v| v.as_str()) == Some(*value)))
+            .map(|sample| sample.value)
+    }
+}
+
+/// Custom logic invoked with every completed epoch
+pub trait EpochHook: Send {
+    /// Called with every completed epoch, in order. Errors are logged and counted
+    /// as failures of the hook.
+    fn on_epoch(&mut self, epoch: &EpochContext) -> Result<(), STError>;
+}
+
+impl<F> EpochHook for F where F: FnMut(&EpochContext) -> Result<(), STError> + Send {
+    fn on_epoch(&mut self, epoch: &EpochContext) -> Result<(), STError> {
+        self(epoch)
+    }
+}
+
+/// Named hooks
+#[derive(Default)]
+pub struct Hooks {
+    hooks: Vec<(String, Box<dyn EpochHook>)>,
+    failures: Arc<AtomicU64>,
+}
+
+impl Hooks {
+    /// No hooks
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `hook`, whose failures are logged with `name`.
+    pub fn register(&mut self, name: impl Into<String>, hook: impl EpochHook + 'static) -> &mut Self {
+        self.hooks.push((name.into(), Box::new(hook)));
+        self
+    }
+
+    /// Whether no hooks are registered
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Counter of failed hook invocations, e.g. to read after the analysis
+    pub fn failures(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.failures)
+    }
+
+    /// Invokes all hooks with `epoch`, in the order they were registered.
+    pub fn call(&mut self, epoch: &EpochContext) {
+        for (name, hook) in self.hooks.iter_mut() {
+            if let Err(e) = hook.on_epoch(epoch) {
+                error!("hook {} failed for epoch {}: {}", name, epoch.epoch, e);
+                self.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Invokes `hooks` with every completed epoch of `pag`. As all edges are
+/// collected at the first ST2 peer (cf. `store::completed_epochs`), only its
+/// hooks are invoked.
+pub fn attach<G>(pag: &Stream<G, (PagEdge, Pair<u64, Duration>, isize)>, mut hooks: Hooks, operator_names: BTreeMap<u64, String>)
+where
+    G: Scope<Timestamp = Pair<u64, Duration>>,
+{
+    completed_epochs(pag, "Hooks", move |epoch, edges, ahead| {
+        if hooks.is_empty() {
+            return;
+        }
+        let stats = EpochStats::new(&edges);
+        let samples = epoch_samples(epoch, &edges, ahead, &operator_names);
+        hooks.call(&EpochContext {
+            epoch,
+            edges: &edges,
+            critical_path: &stats.critical_path,
+            latency: stats.latency,
+            samples: &samples,
+            ahead,
+            operator_names: &operator_names,
+        });
+    });
+}
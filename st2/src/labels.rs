@@ -0,0 +1,189 @@
+//! `--labels` files: human-friendly labels, owning teams, and tags of the source
+//! computation's operators. Analyses show operators by their label, metrics and
+//! SQL tables carry their team and tags, and alert rules can select operators by
+//! team or tag (e.g. `cp_share(team:ingest) > 40%`).
+//!
+//! Every `[[operator]]` entry selects operators by `id`, `name`, or `address`
+//! (a glob like those of `--operators`, e.g. `0.2.*`):
+//!
+//! ```toml
+//! [[operator]]
+//! id = 3
+//! label = "parse events"
+//! team = "ingest"
+//! tags = ["hot-path"]
+//! source = "src/ingest.rs:42"
+//!
+//! [[operator]]
+//! address = "0.4.*"
+//! team = "joins"
+//! ```
+//!
+//! An operator gets the label, team, and source location (cf. `sources`) of the
+//! first entry selecting it that has one, and the tags of all entries selecting it. Names and addresses are those
+//! replay has seen (cf. `st2_timely::operators`). Labels take precedence over the
+//! `operator-names` of a `--config` file.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use once_cell::sync::OnceCell;
+use toml::Value;
+
+use st2_timely::filter::OperatorSelector;
+
+use crate::sources::SourceLocation;
+use crate::STError;
+
+/// Prefix of alert rule operators that select a team, e.g. `team:ingest`
+pub const TEAM_PREFIX: &str = "team:";
+/// Prefix of alert rule operators that select a tag, e.g. `tag:hot-path`
+pub const TAG_PREFIX: &str = "tag:";
+
+/// What a `--labels` file says about an operator
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Label {
+    /// Human-friendly name of the operator
+    pub label: Option<String>,
+    /// Team owning the operator
+    pub team: Option<String>,
+    /// Tags of the operator
+    pub tags: Vec<String>,
+    /// Where the operator is defined
+    pub source: Option<SourceLocation>,
+}
+
+/// The entries of a `--labels` file
+#[derive(Debug, Default)]
+pub struct Labels {
+    entries: Vec<(OperatorSelector, Label)>,
+    /// operator id -> its label, once the operator is known
+    resolved: Mutex<HashMap<u64, Label>>,
+}
+
+/// The enabled labels, cf. `enable`
+static LABELS: OnceCell<Labels> = OnceCell::new();
+
+/// Makes analyses, exports, and alerts use `labels`.
+pub fn enable(labels: Labels) {
+    LABELS.set(labels).expect("labels already enabled");
+}
+
+/// What the enabled labels say about operator `id`, if anything
+pub fn of(id: u64) -> Option<Label> {
+    LABELS.get().and_then(|labels| labels.resolve(id))
+}
+
+/// The name to show operator `id` with: its label if it has one, otherwise its
+/// name in `operator_names`, if known
+pub fn name(id: u64, operator_names: &BTreeMap<u64, String>) -> Option<String> {
+    of(id).and_then(|label| label.label).or_else(|| operator_names.get(&id).cloned())
+}
+
+/// Whether operator `id` is selected by `selector`: its id, its name (cf. `name`),
+/// `team:<TEAM>`, or `tag:<TAG>`
+pub fn selects(selector: &str, id: u64, operator_names: &BTreeMap<u64, String>) -> bool {
+    if selector.starts_with(TEAM_PREFIX) {
+        of(id).and_then(|label| label.team).map_or(false, |team| team == selector[TEAM_PREFIX.len() ..])
+    } else if selector.starts_with(TAG_PREFIX) {
+        of(id).map_or(false, |label| label.tags.iter().any(|tag| *tag == selector[TAG_PREFIX.len() ..]))
+    } else {
+        id.to_string() == selector || name(id, operator_names).map_or(false, |name| name == selector)
+    }
+}
+
+impl Labels {
+    /// Reads the labels file at `path`.
+    pub fn load(path: &Path) -> Result<Self, STError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| STError::Config(format!("Invalid --labels: {}: {}", path.display(), e)))?;
+        contents.parse()
+    }
+
+    /// What the entries say about operator `id`, if anything. Operators selected
+    /// by name or address are only resolved once replay has seen them.
+    fn resolve(&self, id: u64) -> Option<Label> {
+        if let Some(label) = self.resolved.lock().expect("labels poisoned").get(&id) {
+            return Some(label.clone()).filter(|label| *label != Label::default());
+        }
+
+        let operator = st2_timely::operators::get(id as usize);
+        let mut label = Label::default();
+        for (selector, entry) in self.entries.iter() {
+            let selected = match (selector, operator.as_ref()) {
+                (OperatorSelector::Id(selected), _) => *selected as u64 == id,
+                (_, Some(operator)) => selector.matches(id as usize, &operator.name, &operator.addr),
+                (_, None) => false,
+            };
+            if selected {
+                label.label = label.label.or_else(|| entry.label.clone());
+                label.team = label.team.or_else(|| entry.team.clone());
+                label.source = label.source.or_else(|| entry.source.clone());
+                for tag in entry.tags.iter() {
+                    if !label.tags.contains(tag) {
+                        label.tags.push(tag.clone());
+                    }
+                }
+            }
+        }
+
+        // operators that replay hasn't seen yet might still be selected by name or address
+        let needs_operator = self.entries.iter().any(|(selector, _)| !matches!(selector, OperatorSelector::Id(_)));
+        if operator.is_some() || !needs_operator {
+            self.resolved.lock().expect("labels poisoned").insert(id, label.clone());
+        }
+        Some(label).filter(|label| *label != Label::default())
+    }
+}
+
+impl FromStr for Labels {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |message: String| STError::Config(format!("Invalid --labels: {}", message));
+        let table = match s.parse::<Value>() {
+            Ok(Value::Table(table)) => table,
+            Ok(_) => unreachable!("TOML documents are tables"),
+            Err(e) => return Err(invalid(e.to_string())),
+        };
+
+        let mut labels = Labels::default();
+        for (key, value) in table {
+            let entries = match value {
+                Value::Array(entries) if key == "operator" => entries,
+                _ => return Err(invalid(format!("unknown key {} (expected [[operator]])", key))),
+            };
+            for entry in entries {
+                let string = |name: &str| match entry.get(name) {
+                    None => Ok(None),
+                    Some(Value::String(s)) => Ok(Some(s.clone())),
+                    Some(_) => Err(invalid(format!("{} has to be a string", name))),
+                };
+                let selector = match (entry.get("id"), string("name")?, string("address")?) {
+                    (Some(Value::Integer(id)), None, None) if *id >= 0 => OperatorSelector::Id(*id as usize),
+                    (Some(_), None, None) => return Err(invalid("id has to be an operator id".to_string())),
+                    (None, Some(name), None) => OperatorSelector::Name(name),
+                    (None, None, Some(address)) => match address.parse() {
+                        Ok(selector @ OperatorSelector::Address(_)) => selector,
+                        _ => return Err(invalid(format!("{} is not an address glob such as 0.2.*", address))),
+                    },
+                    _ => return Err(invalid("an [[operator]] needs exactly one of id, name, and address".to_string())),
+                };
+                let tags = match entry.get("tags") {
+                    None => Vec::new(),
+                    Some(Value::Array(tags)) => tags.iter()
+                        .map(|tag| tag.as_str().map(|tag| tag.to_string()).ok_or_else(|| invalid("tags have to be strings".to_string())))
+                        .collect::<Result<Vec<_>, _>>()?,
+                    Some(_) => return Err(invalid("tags have to be a list".to_string())),
+                };
+                let source = string("source")?
+                    .map(|source| source.parse().map_err(invalid))
+                    .transpose()?;
+                labels.entries.push((selector, Label { label: string("label")?, team: string("team")?, tags, source }));
+            }
+        }
+        Ok(labels)
+    }
+}
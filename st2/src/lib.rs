@@ -22,27 +22,199 @@ extern crate log;
 /// Contains the PAG construction
 pub mod pag;
 
+/// Readers of trace files and streams
+pub mod replay;
+
+/// A memory budget for pending PAG edges, spilling to disk
+pub mod budget;
+
 /// Contains commands to execute ST2
 pub mod commands;
 
-/// A generic ST2 error
-pub struct STError(pub String);
+/// Configuration files
+pub mod config;
+
+/// Progress reports of offline analyses
+pub mod progress;
+
+/// Per-epoch metric samples and their recent history
+pub mod store;
+
+/// Durable per-epoch history in SQLite
+pub mod history;
+
+/// Authentication of served endpoints
+pub mod auth;
+
+/// Analysis pipelines embedded in other programs
+pub mod builder;
+
+/// Custom logic invoked with every completed epoch
+pub mod hooks;
+
+/// Registry of analyses contributed by other crates
+pub mod plugins;
+
+/// User-defined metrics and alerts, scripted in config files
+pub mod scripting;
+
+/// ST2 analyzing its own dataflow
+pub mod self_profile;
+
+/// Size and age limits of trace files, snapshots, and history on disk
+pub mod retention;
+
+/// Labels, teams, and tags of operators
+pub mod labels;
+
+/// Source locations of operators
+pub mod sources;
+
+/// Reproducible analyses, with byte-identical outputs
+pub mod deterministic;
+
+/// An ST2 error, by category
+#[derive(Debug)]
+pub enum STError {
+    /// Reading or writing a file or socket failed
+    Io {
+        /// What ST2 was doing, e.g. `couldn't open <path>`; empty if unknown
+        context: String,
+        /// The underlying error
+        source: std::io::Error,
+    },
+    /// Couldn't connect to or read from the source computation
+    Connect {
+        /// What failed, e.g. the source's address
+        reason: String,
+        /// The underlying error, if any
+        source: Option<std::io::Error>,
+    },
+    /// A trace couldn't be decoded
+    Decode {
+        /// Byte offset of the first frame that couldn't be decoded
+        offset: u64,
+        /// Why it couldn't be decoded
+        reason: String,
+    },
+    /// A sink, client, or other peer violated its protocol, e.g. an HTTP
+    /// endpoint responded with an error
+    Protocol(String),
+    /// Invalid arguments, config files, rules, queries, or other user input
+    Config(String),
+    /// The analysis itself failed, e.g. the timely computation or a hook
+    Analysis(String),
+}
+
+impl STError {
+    /// An error reading or writing while doing `context`
+    pub fn io(context: impl std::fmt::Display, source: std::io::Error) -> Self {
+        STError::Io { context: context.to_string(), source }
+    }
+
+    /// An error reading a trace at `offset`: malformed data (`InvalidData`)
+    /// couldn't be decoded, anything else is an I/O error.
+    pub fn decode(offset: u64, error: std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::InvalidData => STError::Decode { offset, reason: error.to_string() },
+            _ => STError::io(format!("couldn't read trace at byte {}", offset), error),
+        }
+    }
+
+    /// Prefixes the error's message with `context`, keeping its category.
+    pub fn context(self, context: impl std::fmt::Display) -> Self {
+        let prefix = |message: String| if message.is_empty() { context.to_string() } else { format!("{}: {}", context, message) };
+        match self {
+            STError::Io { context, source } => STError::Io { context: prefix(context), source },
+            STError::Connect { reason, source } => STError::Connect { reason: prefix(reason), source },
+            STError::Decode { offset, reason } => STError::Decode { offset, reason: prefix(reason) },
+            STError::Protocol(message) => STError::Protocol(prefix(message)),
+            STError::Config(message) => STError::Config(prefix(message)),
+            STError::Analysis(message) => STError::Analysis(prefix(message)),
+        }
+    }
+}
+
+impl std::fmt::Display for STError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            STError::Io { context, source } if context.is_empty() => write!(f, "io error: {}", source),
+            STError::Io { context, source } => write!(f, "{}: {}", context, source),
+            STError::Connect { reason, source: Some(source) } => write!(f, "{}: {}", reason, source),
+            STError::Connect { reason, source: None } => write!(f, "{}", reason),
+            STError::Decode { offset, reason } => write!(f, "couldn't decode trace at byte {}: {}", offset, reason),
+            STError::Protocol(message) | STError::Config(message) | STError::Analysis(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for STError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            STError::Io { source, .. } => Some(source),
+            STError::Connect { source: Some(source), .. } => Some(source),
+            _ => None,
+        }
+    }
+}
 
 impl From<std::io::Error> for STError {
     fn from(error: std::io::Error) -> Self {
-        STError(format!("io error: {}", error.to_string()))
+        STError::io("", error)
     }
 }
 
 impl From<tdiag_connect::ConnectError> for STError {
     fn from(error: tdiag_connect::ConnectError) -> Self {
         match error {
-            tdiag_connect::ConnectError::IoError(e) => STError(format!("io error: {}", e)),
-            tdiag_connect::ConnectError::Other(e) => STError(e),
+            tdiag_connect::ConnectError::IoError(e) => STError::Connect { reason: "couldn't connect to the source computation".to_string(), source: Some(e) },
+            tdiag_connect::ConnectError::Other(e) => STError::Connect { reason: e, source: None },
         }
     }
 }
 
+/// Format of the results commands print to stdout
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// Human-readable text
+    Text,
+    /// JSON: one document per line, e.g. a command's summary or an invariant violation
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("{}: expected text or json", s)),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Prints a result to stdout: `json` as a single line for `Json`, otherwise `text`.
+    pub fn print(self, text: impl std::fmt::Display, json: serde_json::Value) {
+        match self {
+            OutputFormat::Text => println!("{}", text),
+            OutputFormat::Json => println!("{}", json),
+        }
+    }
+}
+
+/// Number of ST2 peers per process for `timely_configuration`.
+/// In cluster mode, every ST2 process only replays its own shard of the
+/// source peers, which is then distributed among its local ST2 peers.
+pub fn local_peers(timely_configuration: &timely::Configuration) -> usize {
+    match timely_configuration {
+        timely::Configuration::Thread => 1,
+        timely::Configuration::Process(n) => *n,
+        timely::Configuration::Cluster { threads, .. } => *threads,
+    }
+}
+
 
 #[derive(Serialize, Debug)]
 /// Serialization type for socket
@@ -57,6 +229,8 @@ pub enum PagData {
     Met(MetricsData),
     /// invariants
     Inv(InvariantData),
+    /// epoch latency (sent once all other data of the epoch has been sent)
+    Lat(LatencyData),
 }
 
 #[derive(Serialize, Debug)]
@@ -82,6 +256,15 @@ pub struct MetricsData {
     rc: u64,
 }
 
+#[derive(Serialize, Debug, Clone)]
+/// Serialization type for epoch latencies
+/// epoch, start of the epoch, latency (both in ns)
+pub struct LatencyData {
+    e: u64,
+    s: u64,
+    l: u64,
+}
+
 #[derive(Serialize, Debug)]
 /// Types of invariants that are checked
 pub enum InvariantData {
@@ -4,9 +4,10 @@
 //! inspecting & benchmarking SnailTrail itself.
 #![deny(missing_docs)]
 #[macro_use] extern crate log;
-use env_logger;
 
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::path::PathBuf;
 use std::sync::mpsc;
 use std::convert::TryInto;
@@ -14,7 +15,11 @@ use std::convert::TryInto;
 use tdiag_connect::receive as connect;
 use tdiag_connect::receive::ReplaySource;
 
-use st2::STError;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use st2::{OutputFormat, STError};
+use st2::config::{Args, Config};
 use st2::PagData;
 use std::collections::HashMap;
 
@@ -22,23 +27,167 @@ use ws::Handshake;
 use ws::Handler;
 use ws::Sender;
 use ws::Message;
-use ws::listen;
+use ws::Request;
+use ws::Response;
 
 use serde_json::json;
 
+/// Exit code if ST2 failed
+const EXIT_FAILURE: i32 = 1;
+/// Exit code if the command line or config file is invalid
+const EXIT_USAGE: i32 = 2;
+/// Exit code if a check failed, cf. `CHECKS`
+const EXIT_CHECK_FAILED: i32 = 3;
+/// The checks that fail with `EXIT_CHECK_FAILED`, for `--help`
+const CHECKS: &str = "`validate` found problems, `invariants` found violations, `alerts` fired, or `verify` found mismatches";
+/// Exit code if ST2 couldn't connect to or read the source computation
+const EXIT_CONNECT: i32 = 4;
+/// Exit code if a trace couldn't be decoded
+const EXIT_DECODE: i32 = 5;
+/// Exit code if ST2 was interrupted by SIGINT / SIGTERM
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Whether SIGHUP reloads the config rather than shutting down ST2
+static RELOAD_ON_HANGUP: AtomicBool = AtomicBool::new(false);
+
+/// The dashboard's web UI, compiled into the binary
+const DASHBOARD_HTML: &str = include_str!("../../dashboard/index.html");
+/// The dashboard's compiled React components
+const DASHBOARD_JS: &str = include_str!("../../dashboard/charts.js");
+
 fn main() {
-    env_logger::init();
-    info!("running.");
+    let is_running = Arc::new(AtomicBool::new(true));
+    let started = Instant::now();
+
+    let hangup = Arc::new(AtomicBool::new(false));
+
+    let result = install_shutdown_handler(Arc::clone(&is_running), Arc::clone(&hangup))
+        .and_then(|_| run(Arc::clone(&is_running), hangup));
+    let interrupted = !is_running.load(Ordering::Acquire);
+
+    eprintln!("{}", st2::progress::summary(started.elapsed(), interrupted));
 
-    match run() {
-        Ok(()) => (),
-        Err(STError(e)) => eprintln!("Error: {}", e)
+    match result {
+        Ok(_) if interrupted => std::process::exit(EXIT_INTERRUPTED),
+        Ok(true) => (),
+        Ok(false) => std::process::exit(EXIT_CHECK_FAILED),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(match e {
+                STError::Config(_) => EXIT_USAGE,
+                STError::Connect { .. } => EXIT_CONNECT,
+                STError::Decode { .. } => {
+                    eprintln!("Run `st2 validate` on the trace for details.");
+                    EXIT_DECODE
+                }
+                _ => EXIT_FAILURE,
+            });
+        }
     }
 }
 
-fn run() -> Result<(), STError> {
-    let args = clap::App::new("snailtrail")
+/// On SIGINT / SIGTERM, unsets `is_running`: ST2 stops replaying its sources,
+/// closes them, and completes all epochs in flight. A second signal terminates immediately.
+/// SIGHUP sets `hangup` if `RELOAD_ON_HANGUP` is set (cf. `daemon`), and shuts down otherwise.
+fn install_shutdown_handler(is_running: Arc<AtomicBool>, hangup: Arc<AtomicBool>) -> Result<(), STError> {
+    use signal_hook::{SIGHUP, SIGINT, SIGTERM};
+
+    let signals = signal_hook::iterator::Signals::new(&[SIGINT, SIGTERM, SIGHUP])
+        .map_err(|e| STError::io("Couldn't install signal handler", e))?;
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP && RELOAD_ON_HANGUP.load(Ordering::Acquire) {
+                eprintln!("Reloading the config...");
+                hangup.store(true, Ordering::Release);
+            } else if is_running.swap(false, Ordering::AcqRel) {
+                eprintln!("Shutting down, completing epochs in flight (repeat to force)...");
+            } else {
+                std::process::exit(EXIT_INTERRUPTED);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Reports ST2's own logs and diagnostics (cf. `st2_timely::diagnostics`) to
+/// stderr as text or JSON lines, filtered by `RUST_LOG` (default: `default_level`).
+fn init_logging(format: &str, default_level: &str) -> Result<(), STError> {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(default_level));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr);
+    let result = match format {
+        "json" => subscriber.json().try_init(),
+        _ => subscriber.try_init(),
+    };
+    result.map_err(|e| STError::io("Couldn't install logger", std::io::Error::new(std::io::ErrorKind::Other, e)))
+}
+
+/// The command line of ST2: global arguments and subcommands, with the
+/// `exit_codes` after the help
+fn app(exit_codes: &str) -> clap::App<'_, '_> {
+    clap::App::new("snailtrail")
         .about("Online and offline analysis of Timely & Differential dataflows")
+        .after_help(exit_codes)
+        .arg(clap::Arg::with_name("config")
+             .long("config")
+             .value_name("PATH")
+             .help("TOML file providing defaults for all arguments; arguments given on the command line take precedence")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("output_format")
+             .long("output")
+             .value_name("FORMAT")
+             .possible_values(&["text", "json"])
+             .help("Format of the results printed to stdout; status messages always go to stderr")
+             .default_value("text"))
+        .arg(clap::Arg::with_name("log_format")
+             .long("log-format")
+             .value_name("FORMAT")
+             .possible_values(&["text", "json"])
+             .help("Format of ST2's own diagnostics on stderr, filtered by RUST_LOG (e.g. `RUST_LOG=info`)")
+             .default_value("text"))
+        .arg(clap::Arg::with_name("self_profile")
+             .long("self-profile")
+             .value_name("DIR")
+             .help("Log ST2's own dataflow as a trace to DIR and report where ST2 spent its time after the command")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("labels")
+             .long("labels")
+             .value_name("PATH")
+             .help("TOML file of labels, teams, and tags of operators to show and group them by (cf. README)")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("source_url")
+             .long("source-url")
+             .value_name("TEMPLATE")
+             .help("Link operators' source locations in reports to this URL, with {file}, {line}, and {column} filled in")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("retain_size")
+             .long("retain-size")
+             .value_name("MB")
+             .help("Delete the oldest recorded trace files, snapshots, and SQLite history once they take up more than this many megabytes each")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("retain_age")
+             .long("retain-age")
+             .value_name("SECS")
+             .help("Delete recorded trace files, snapshots, and SQLite history older than this many seconds")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("memory_budget")
+             .long("memory-budget")
+             .value_name("MB")
+             .help("Keep the PAG edges of epochs and windows that haven't completed yet within this many megabytes; beyond it, the epochs needed last are spilled to disk (cf. --spill-dir and --over-budget)")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("spill_dir")
+             .long("spill-dir")
+             .value_name("DIR")
+             .requires("memory_budget")
+             .help("Directory for epochs spilled over the --memory-budget [default: the system's temporary directory]")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("over_budget")
+             .long("over-budget")
+             .value_name("POLICY")
+             .requires("memory_budget")
+             .possible_values(&["spill", "sample"])
+             .help("What to do with epochs over the --memory-budget: spill them to disk (default), or sample, i.e. drop them from the analysis")
+             .takes_value(true))
         .arg(clap::Arg::with_name("interface")
              .short("i")
              .long("interface")
@@ -58,226 +207,813 @@ fn run() -> Result<(), STError> {
              .value_name("PATH")
              .help("File path from which to load *.dump files (without trailing /). Set if you want to run offline.")
              .takes_value(true))
+        .arg(clap::Arg::with_name("replay_speed")
+             .long("replay-speed")
+             .value_name("SPEED")
+             .requires("from_file")
+             .help("Offline replay speed: max (as fast as possible, default), realtime, or <n>x (e.g. 2x) the recorded speed")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("respect_timing")
+             .long("respect-timing")
+             .requires("from_file")
+             .help("Offline replay re-paces individual events according to their recorded inter-arrival gaps (at --replay-speed, default realtime)"))
+        .arg(clap::Arg::with_name("follow")
+             .long("follow")
+             .requires("from_file")
+             .help("Offline, tail *.dump files that are still being written (like tail -f): wait for them to appear and analyze new epochs as they are appended, until the source computation finishes"))
+        .arg(clap::Arg::with_name("mmap")
+             .long("mmap")
+             .requires("from_file")
+             .conflicts_with("follow")
+             .help("Offline, replay *.dump files from memory maps, decoding their events in place instead of reading them into buffers (faster for large traces); only map complete files written by a trusted source computation, as their contents aren't validated"))
+        .arg(clap::Arg::with_name("prefetch")
+             .long("prefetch")
+             .value_name("BATCHES")
+             .help("Offline, read and decode every *.dump file on an I/O thread of its own, up to BATCHES event batches ahead of the analysis; 0 reads files on the analysis workers")
+             .default_value("16"))
+        .arg(clap::Arg::with_name("progress")
+             .long("progress")
+             .value_name("SECS")
+             .help("Offline, report replay progress (bytes, events, epochs, rate, and ETA) on stderr every SECS seconds; 0 disables reports")
+             .default_value("10"))
         .arg(clap::Arg::with_name("source_peers")
              .short("s")
              .long("source-peers")
              .value_name("PEERS")
-             .help("Number of workers in the source computation")
-             .required(true))
+             .help("Number of workers in the source computation (required unless inspecting a trace file)")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("workers")
+             .long("workers")
+             .value_name("WORKERS")
+             .help("Only analyze events of these source workers (comma-separated ids)")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("operators")
+             .long("operators")
+             .value_name("OPERATORS")
+             .help("Only analyze scheduling events of these operators (comma-separated ids, names, or address globs such as 0.2.*)")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("epochs")
+             .long("epochs")
+             .value_name("FROM..TO")
+             .help("Only analyze epochs FROM (inclusive) to TO (exclusive); either bound may be omitted")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("repartition")
+             .long("repartition")
+             .value_name("EPOCHS")
+             .help("Repartition log records across SnailTrail workers by blocks of EPOCHS epochs, so all workers are busy even if the source computation has fewer workers or skewed activity")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("inject_faults")
+             .long("inject-faults")
+             .value_name("FAULTS")
+             .conflicts_with("deterministic")
+             .help("Testing: inject faults into every source to check that the deployment degrades gracefully, comma-separated: drop=<EVENTS> (drop the connection after EVENTS events), delay=<PROB>:<MS> (hold batches back), reorder=<PROB> (reorder records within batches), corrupt=<PROB> (lose batches like damaged blocks), seed=<N>")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("deterministic")
+             .long("deterministic")
+             .conflicts_with_all(&["follow", "replay_speed", "respect_timing"])
+             .help("Make outputs byte-identical across runs of the same trace: replay *.dump files without prefetching, release every epoch's results at once and sorted, and rule out sampling and random salts"))
         .arg(clap::Arg::with_name("snailtrail_workers")
              .short("w")
              .long("snailtrail-workers")
              .value_name("WORKERS")
-             .help("Number of worker threads for SnailTrail")
+             .help("Number of worker threads for SnailTrail (per process), independent of the number of source peers")
              .default_value("1"))
-        .subcommand(
-            clap::SubCommand::with_name("metrics")
-                .about("Write dataflow metrics to file")
-                .arg(clap::Arg::with_name("output_path")
-                    .short("o")
-                    .long("out")
-                    .value_name("PATH")
-                    .help("The output path for the generated CSV file (don't forget the .CSV extension)")
-                    .default_value("metrics.csv"))
-        )
-        .subcommand(
-            clap::SubCommand::with_name("inspect")
-                .about("run ST2 inspector")
-        )
-        .subcommand(
-            clap::SubCommand::with_name("algo")
-                .about("run ST2 graph algorithms")
-        )
-        .subcommand(
-            clap::SubCommand::with_name("dashboard")
-                .about("run ST2 live dashboard")
-                .arg(clap::Arg::with_name("epoch_max")
-                    .short("e")
-                    .long("epoch-max")
-                    .value_name("MS")
-                    .help("Temporal invariant: the maximum milliseconds an epoch is allowed to take"))
-                .arg(clap::Arg::with_name("operator_max")
-                    .short("o")
-                    .long("operator-max")
-                    .value_name("MS")
-                    .help("Temporal invariant: the maximum milliseconds an operator is allowed to take"))
-                .arg(clap::Arg::with_name("message_max")
-                    .short("m")
-                    .long("message-max")
-                    .value_name("MS")
-                    .help("Temporal invariant: the maximum milliseconds a control or data message is allowed to take"))
-        )
-        .subcommand(
-            clap::SubCommand::with_name("invariants")
-                .about("run invariants checker")
-                .arg(clap::Arg::with_name("epoch_max")
-                    .short("e")
-                    .long("epoch-max")
-                    .value_name("MS")
-                    .help("Temporal invariant: the maximum milliseconds an epoch is allowed to take"))
-                .arg(clap::Arg::with_name("operator_max")
-                    .short("o")
-                    .long("operator-max")
-                    .value_name("MS")
-                    .help("Temporal invariant: the maximum milliseconds an operator is allowed to take"))
-                .arg(clap::Arg::with_name("message_max")
-                    .short("m")
-                    .long("message-max")
-                    .value_name("MS")
-                    .help("Temporal invariant: the maximum milliseconds a control or data message is allowed to take"))
-                .arg(clap::Arg::with_name("progress_max")
-                    .short("p")
-                    .long("progress-max")
-                    .value_name("MS")
-                    .help("Progress invariant: the maximum milliseconds between two progress messages per worker"))
-        )
-        .get_matches();
+        .arg(clap::Arg::with_name("processes")
+             .long("processes")
+             .value_name("PROCESSES")
+             .help("Number of SnailTrail processes. Source peers are sharded across processes by index.")
+             .default_value("1"))
+        .arg(clap::Arg::with_name("process_id")
+             .long("process-id")
+             .value_name("ID")
+             .help("Identity of this SnailTrail process")
+             .default_value("0"))
+        .arg(clap::Arg::with_name("hostfile")
+             .long("hostfile")
+             .value_name("PATH")
+             .help("Hostfile listing one <host>:<port> per SnailTrail process (defaults to localhost)")
+             .takes_value(true))
+        .subcommand(st2::commands::metrics::subcommand())
+        .subcommand(st2::commands::export::subcommand())
+        .subcommand(st2::commands::flamegraph::subcommand())
+        .subcommand(st2::commands::heatmap::subcommand())
+        .subcommand(st2::commands::graph::subcommand())
+        .subcommand(st2::commands::report::subcommand())
+        .subcommand(st2::commands::diff::subcommand())
+        .subcommand(st2::commands::record::subcommand())
+        .subcommand(st2::commands::validate::subcommand())
+        .subcommand(st2::commands::convert::subcommand())
+        .subcommand(st2::commands::trim::subcommand())
+        .subcommand(st2::commands::merge::subcommand())
+        .subcommand(st2::commands::anonymize::subcommand())
+        .subcommand(st2::commands::top::subcommand())
+        .subcommand(st2::commands::snapshot::subcommand())
+        .subcommand(st2::commands::repl::subcommand())
+        .subcommand(st2::commands::query::subcommand())
+        .subcommand(st2::commands::stream::subcommand())
+        .subcommand(st2::commands::analyze::subcommand())
+        .subcommand(st2::commands::alerts::subcommand())
+        .subcommand(st2::commands::daemon::subcommand())
+        .subcommand(st2::commands::aggregate::subcommand())
+        .subcommand(st2::commands::inspect::subcommand())
+        .subcommand(st2::commands::bench::subcommand())
+        .subcommand(st2::commands::demo::subcommand())
+        .subcommand(st2::commands::estimate::subcommand())
+        .subcommand(st2::commands::verify::subcommand())
+        .subcommand(st2::commands::algo::subcommand())
+        .subcommand(st2::commands::dashboard::subcommand())
+        .subcommand(st2::commands::grafana::subcommand())
+        .subcommand(st2::commands::api::subcommand())
+        .subcommand(st2::commands::grpc::subcommand())
+        .subcommand(st2::commands::publish::subcommand())
+        .subcommand(st2::commands::invariants::subcommand())
+}
+
+/// Enables the global settings of `args` that apply to every subcommand,
+/// e.g. the memory budget or self-profiling
+fn enable_globals(args: &Args) -> Result<(), STError> {
+    if let Some(dir) = args.value_of("self_profile") {
+        st2::self_profile::enable(std::path::Path::new(dir))?;
+    }
+    if let Some(path) = args.value_of("labels") {
+        st2::labels::enable(st2::labels::Labels::load(std::path::Path::new(path))?);
+    }
+    if let Some(template) = args.value_of("source_url") {
+        st2::sources::enable_links(template.to_string());
+    }
+    if args.is_present("mmap") {
+        st2::replay::enable_mmap();
+    }
+    if let Some(faults) = args.value_of("inject_faults") {
+        let faults: st2_timely::faults::Faults = faults.parse().map_err(|e| STError::Config(format!("Invalid --inject-faults: {}", e)))?;
+        eprintln!("Injecting faults into every source: {:?}", faults);
+        st2::replay::enable_faults(faults);
+    }
+    if let Some(mb) = args.value_of("memory_budget") {
+        let mb: u64 = mb.parse().map_err(|e| STError::Config(format!("Invalid --memory-budget: {}", e)))?;
+        let over = match args.value_of("over_budget") {
+            Some("sample") if args.is_present("deterministic") => Err(STError::Config("Invalid --over-budget: sampling isn't deterministic".to_string()))?,
+            Some("sample") => st2::budget::OverBudget::Sample,
+            _ => st2::budget::OverBudget::Spill(args.value_of("spill_dir").map(PathBuf::from).unwrap_or_else(std::env::temp_dir)),
+        };
+        st2::budget::enable(st2::budget::Budget { max_bytes: mb * 1024 * 1024, over });
+    }
+    if let Some(block) = args.value_of("repartition") {
+        let block: u64 = block.parse().ok().filter(|block| *block > 0)
+            .ok_or_else(|| STError::Config("Invalid --repartition: expected a positive number of epochs".to_string()))?;
+        st2::pag::repartition_by_epoch(block);
+    }
+    let prefetch = args.value_of("prefetch").expect("error parsing prefetch args")
+        .parse().map_err(|e| STError::Config(format!("Invalid --prefetch: {}", e)))?;
+    if args.is_present("deterministic") {
+        // how far I/O threads got would decide batch boundaries
+        st2::deterministic::enable();
+        st2::replay::enable_prefetch(0);
+    } else {
+        st2::replay::enable_prefetch(prefetch);
+    }
+    st2::retention::enable(st2::retention::Retention {
+        max_bytes: if let Some(mb) = args.value_of("retain_size") {
+            Some(mb.parse::<u64>().map_err(|e| STError::Config(format!("Invalid --retain-size: {}", e)))? * 1024 * 1024)
+        } else {
+            None
+        },
+        max_age: if let Some(secs) = args.value_of("retain_age") {
+            Some(std::time::Duration::from_secs(secs.parse().map_err(|e| STError::Config(format!("Invalid --retain-age: {}", e)))?))
+        } else {
+            None
+        },
+    });
+    Ok(())
+}
+
+/// Runs the subcommand. Returns whether its checks passed, if it has any.
+fn run(is_running: Arc<AtomicBool>, hangup: Arc<AtomicBool>) -> Result<bool, STError> {
+    let exit_codes = format!("EXIT CODES:
+    {}    success
+    {}    failure
+    {}    invalid command line or config file
+    {}    a check failed: {}
+    {}    couldn't connect to or read the source computation
+    {}    a trace couldn't be decoded
+    {}  interrupted by SIGINT / SIGTERM",
+        0, EXIT_FAILURE, EXIT_USAGE, EXIT_CHECK_FAILED, CHECKS, EXIT_CONNECT, EXIT_DECODE, EXIT_INTERRUPTED);
+    let app = app(&exit_codes);
+    let command_line: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let matches = app.clone().get_matches_from_safe(command_line.iter().cloned())
+        .unwrap_or_else(|e| match e.kind {
+            clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => e.exit(),
+            _ => {
+                eprintln!("{}", e.message);
+                std::process::exit(EXIT_USAGE);
+            }
+        });
+
+    let config = match matches.value_of("config") {
+        Some(path) => Config::load(std::path::Path::new(path))?,
+        None => Config::default(),
+    };
+    config.check(&app, &command_line, matches.subcommand_name())?;
+    if let Some(scripts) = config.scripts() {
+        st2::scripting::install(st2::scripting::Scripts::compile(scripts)?)?;
+    }
+    let args = Args { matches: &matches, config: &config, subcommand: None };
+    // the daemon logs its life cycle by default
+    let default_level = if matches.subcommand_name() == Some("daemon") { "info" } else { "error" };
+    init_logging(args.value_of("log_format").expect("error parsing log format args"), default_level)?;
+    info!("running.");
 
     match args.subcommand() {
-        (_, None) => Err(STError("Invalid subcommand".to_string()))?,
+        (_, None) => Err(STError::Config("Invalid subcommand".to_string()))?,
         _ => (),
     }
 
-    // @TODO: support cluster mode
     let st_workers: usize = args.value_of("snailtrail_workers").expect("error parsing worker args")
-        .parse().map_err(|e| STError(format!("Invalid --diag-workers: {}", e)))?;
-    let timely_configuration = match st_workers {
-        1 => timely::Configuration::Thread,
-        n => timely::Configuration::Process(n),
+        .parse().map_err(|e| STError::Config(format!("Invalid --diag-workers: {}", e)))?;
+    let (processes, process_id) = parse_processes(&args)?;
+    let speed: ReplaySpeed = if let Some(s) = args.value_of("replay_speed") {
+        s.parse().map_err(|e| STError::Config(format!("Invalid --replay-speed: {}", e)))?
+    } else {
+        ReplaySpeed::Unbounded
     };
+    let speed = match speed {
+        ReplaySpeed::Factor(factor) if args.is_present("respect_timing") => ReplaySpeed::Original(factor),
+        _ if args.is_present("respect_timing") => ReplaySpeed::Original(1.0),
+        speed => speed,
+    };
+    let filter = parse_filter(&args)?;
+    let output_format: OutputFormat = args.value_of("output_format").expect("error parsing output args")
+        .parse().map_err(|e| STError::Config(format!("Invalid --output: {}", e)))?;
+    let mut checks_passed = true;
+    let timely_configuration = if processes > 1 {
+        let mut timely_args = vec![
+            "-w".to_string(), st_workers.to_string(),
+            "-n".to_string(), processes.to_string(),
+            "-p".to_string(), process_id.to_string(),
+        ];
+        if let Some(hostfile) = args.value_of("hostfile") {
+            timely_args.push("-h".to_string());
+            timely_args.push(hostfile.to_string());
+        }
+        timely::Configuration::from_args(timely_args.into_iter())
+            .map_err(|e| STError::Config(format!("Invalid cluster configuration: {}", e)))?
+    } else {
+        match st_workers {
+            1 => timely::Configuration::Thread,
+            n => timely::Configuration::Process(n),
+        }
+    };
+
+    enable_globals(&args)?;
+
+    let (command, _) = args.subcommand();
+    let span = tracing::info_span!("command", command);
+    let _entered = span.enter();
+    let started = Instant::now();
 
     match args.subcommand() {
         ("metrics", Some(metrics_args)) => {
-            let output_path = std::path::Path::new(metrics_args.value_of("output_path").expect("error parsing metrics output args"));
+            let st2::commands::metrics::Options { output_path, forward, forward_pag, breakdown_path, summary } = st2::commands::metrics::from_args(&metrics_args)?;
 
-            let replay_source = make_replay_source(&args)?;
-            println!("Connected!");
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
 
-            st2::commands::metrics::run(timely_configuration, replay_source, output_path)
+            st2::commands::metrics::run(timely_configuration, replay_source, is_running, speed, filter, &output_path, forward, forward_pag, breakdown_path.as_deref(), summary, config.operator_names(), output_format)
         }
-        ("inspect", Some(_inspect_args)) => {
-            let replay_source = make_replay_source(&args)?;
-            println!("Connected!");
+        ("export", Some(export_args)) => {
+            let st2::commands::export::Options { format, epochs, edges_path, metrics_path, min_weight } = st2::commands::export::from_args(&export_args)?;
 
-            st2::commands::inspect::run(timely_configuration, replay_source)
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::export::run(timely_configuration, replay_source, is_running, speed, filter, format, epochs, edges_path.as_deref(), metrics_path.as_deref(), min_weight.as_nanos() as u64)
         }
-        ("algo", Some(_algo_args)) => {
-            let replay_source = make_replay_source(&args)?;
-            println!("Connected!");
+        ("flamegraph", Some(flamegraph_args)) => {
+            let st2::commands::flamegraph::Options { output_path, svg_path, epochs, window } = st2::commands::flamegraph::from_args(&flamegraph_args)?;
 
-            st2::commands::algo::run(timely_configuration, replay_source)
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::flamegraph::run(timely_configuration, replay_source, is_running, speed, filter, epochs, window, config.operator_names(), &output_path, svg_path.as_deref(), output_format)
         }
-        ("dashboard", Some(dashboard_args)) => {
-            let epoch_max: Option<u64> = if let Some(t) = dashboard_args.value_of("epoch_max") {
-                println!("epoch max given");
-                Some(t.parse().map_err(|e| STError(format!("Invalid --epoch-max: {}", e)))?)
+        ("heatmap", Some(heatmap_args)) => {
+            let st2::commands::heatmap::Options { output_path, svg_path, epochs } = st2::commands::heatmap::from_args(&heatmap_args)?;
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::heatmap::run(timely_configuration, replay_source, is_running, speed, filter, epochs, config.operator_names(), &output_path, svg_path.as_deref(), output_format)
+        }
+        ("graph", Some(graph_args)) => {
+            let st2::commands::graph::Options { output_path, format, epochs } = st2::commands::graph::from_args(&graph_args)?;
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::graph::run(timely_configuration, replay_source, is_running, speed, filter, epochs, config.operator_names(), &output_path, format, output_format)
+        }
+        ("report", Some(report_args)) => {
+            let st2::commands::report::Options { output_path, epochs, worst } = st2::commands::report::from_args(&report_args)?;
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::report::run(timely_configuration, replay_source, is_running, speed, filter, epochs, worst, config.operator_names(), &output_path, output_format)
+        }
+        ("diff", Some(diff_args)) => {
+            let st2::commands::diff::Options { top, trace_a, trace_b, report } = st2::commands::diff::from_args(&diff_args)?;
+            let report = report.as_deref().map(|path| (path, trace_a.as_str(), trace_b.as_str()));
+            let source_a = make_file_source(&args, &is_running, &trace_a)?;
+            let source_b = make_file_source(&args, &is_running, &trace_b)?;
+
+            st2::commands::diff::run(timely_configuration, source_a, source_b, is_running, speed, filter, top, config.operator_names(), report, output_format)
+        }
+        ("record", Some(record_args)) => {
+            let st2::commands::record::Options { out_dir, format } = st2::commands::record::from_args(&record_args)?;
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected! Recording to {}", out_dir.display());
+
+            st2::commands::record::run(timely_configuration, replay_source, is_running, &out_dir, format)
+        }
+        ("validate", Some(validate_args)) => {
+            let st2::commands::validate::Options { traces } = st2::commands::validate::from_args(&validate_args)?;
+            let traces: Vec<_> = traces.iter().map(PathBuf::as_path).collect();
+
+            st2::commands::validate::run(&traces, output_format).map(|valid| checks_passed = valid)
+        }
+        ("convert", Some(convert_args)) => {
+            let st2::commands::convert::Options { input, output, encoding, compression } = st2::commands::convert::from_args(&convert_args)?;
+
+            st2::commands::convert::run(&input, &output, encoding, compression, config.operator_names(), output_format)
+        }
+        ("trim", Some(trim_args)) => {
+            let st2::commands::trim::Options { input, output, time, epochs } = st2::commands::trim::from_args(&trim_args)?;
+
+            st2::commands::trim::run(&input, &output, time, epochs, output_format)
+        }
+        ("merge", Some(merge_args)) => {
+            let st2::commands::merge::Options { inputs, output, encoding, compression } = st2::commands::merge::from_args(&merge_args)?;
+            let inputs: Vec<_> = inputs.iter().map(PathBuf::as_path).collect();
+
+            st2::commands::merge::run(&inputs, &output, encoding, compression, output_format)
+        }
+        ("anonymize", Some(anonymize_args)) => {
+            let st2::commands::anonymize::Options { input, output, salt } = st2::commands::anonymize::from_args(&anonymize_args)?;
+
+            st2::commands::anonymize::run(&input, &output, &salt, output_format)
+        }
+        ("top", Some(top_args)) => {
+            let st2::commands::top::Options { refresh, history } = st2::commands::top::from_args(&top_args)?;
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+
+            st2::commands::top::run(timely_configuration, replay_source, is_running, speed, filter, refresh, history)
+        }
+        ("snapshot", Some(snapshot_args)) => {
+            let st2::commands::snapshot::Options { epoch, output_path } = st2::commands::snapshot::from_args(&snapshot_args)?;
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected! Waiting for epoch {}", epoch);
+
+            st2::commands::snapshot::run(timely_configuration, replay_source, is_running, speed, filter, epoch, &output_path, output_format)
+        }
+        ("repl", Some(repl_args)) => {
+            let st2::commands::repl::Options { pag } = st2::commands::repl::from_args(&repl_args)?;
+
+            let edges = if pag.ends_with(".json") {
+                st2::commands::repl::load_snapshot(std::path::Path::new(&pag))?
             } else {
-                None
+                let replay_source = make_file_source(&args, &is_running, &pag)?;
+                st2::commands::repl::load_pag(timely_configuration, replay_source, is_running, speed, filter)?
             };
-            let operator_max: Option<u64> = if let Some(t) = dashboard_args.value_of("operator_max") {
-                Some(t.parse().map_err(|e| STError(format!("Invalid --operator-max: {}", e)))?)
+
+            st2::commands::repl::run(edges, config.operator_names())
+        }
+        ("query", Some(query_args)) => {
+            let st2::commands::query::Options { query, sql, pag } = st2::commands::query::from_args(&query_args)?;
+
+            let edges = if pag.ends_with(".json") {
+                st2::commands::repl::load_snapshot(std::path::Path::new(&pag))?
             } else {
-                None
+                let replay_source = make_file_source(&args, &is_running, &pag)?;
+                st2::commands::repl::load_pag(timely_configuration, replay_source, is_running, speed, filter)?
             };
-            let message_max: Option<u64> = if let Some(t) = dashboard_args.value_of("message_max") {
-                Some(t.parse().map_err(|e| STError(format!("Invalid --message-max: {}", e)))?)
-            } else {
-                None
+
+            match (query, sql) {
+                (Some(query), _) => st2::commands::query::run(&query, &edges, config.operator_names(), output_format),
+                (None, Some(sql)) => {
+                    let table = st2::commands::sql::eval(&sql, &edges, config.operator_names())?;
+                    st2::commands::query::print(&table, config.operator_names(), output_format)
+                }
+                (None, None) => unreachable!("clap requires --expr or --sql"),
+            }
+        }
+        ("stream", Some(stream_args)) => {
+            let st2::commands::stream::Options { output_path, top } = st2::commands::stream::from_args(&stream_args)?;
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::stream::run(timely_configuration, replay_source, is_running, speed, filter, top, config.operator_names(), &output_path)
+        }
+        ("analyze", Some(analyze_args)) if analyze_args.is_present("list") => {
+            st2::commands::analyze::list(output_format);
+            Ok(())
+        }
+        ("analyze", Some(analyze_args)) => {
+            let st2::commands::analyze::Options { analyses } = st2::commands::analyze::from_args(&analyze_args)?;
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::analyze::run(timely_configuration, replay_source, is_running, speed, filter, analyses, config.operator_names(), output_format)
+                .map(|_| ())
+        }
+        ("alerts", Some(alerts_args)) => {
+            let st2::commands::alerts::Options { rules, sinks, retries, window, evidence, publish, publish_options } = st2::commands::alerts::from_args(&alerts_args)?;
+            // the settings the evidence of alerts was gathered with
+            let evidence = match evidence {
+                Some(store) => {
+                    let config_file = match matches.value_of("config") {
+                        Some(path) => Some(std::fs::read_to_string(path)?),
+                        None => None,
+                    };
+                    let settings = json!({
+                        "rules": rules.iter().map(|rule| rule.text.clone()).collect::<Vec<_>>(),
+                        "window": window,
+                        "operator_names": config.operator_names(),
+                        "config_file": config_file,
+                    });
+                    Some((store, settings))
+                }
+                None => None,
             };
 
-            println!("Waiting for source computation...");
-            let replay_source = make_replay_source(&args)?;
-            println!("Connected to source computation!");
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::alerts::run(timely_configuration, replay_source, is_running, speed, filter, rules, window, sinks, retries, evidence, publish, publish_options, config.operator_names(), output_format)
+                .map(|alerts| checks_passed = alerts == 0)
+        }
+        ("aggregate", Some(aggregate_args)) => {
+            let st2::commands::aggregate::Options { output_path, critical_paths_path } = st2::commands::aggregate::from_args(&aggregate_args)?;
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected to all leaves!");
+
+            st2::commands::aggregate::run(timely_configuration, replay_source, is_running, &output_path, critical_paths_path.as_deref())
+        }
+        ("inspect", Some(inspect_args)) => {
+            if let Some(trace) = st2::commands::inspect::from_args(&inspect_args)?.trace {
+                return st2::commands::inspect::run_summary(&trace, output_format).map(|_| true);
+            }
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::inspect::run(timely_configuration, replay_source, is_running, speed, filter)
+        }
+        ("bench", Some(bench_args)) => {
+            let st2::commands::bench::Options { workload, generate } = st2::commands::bench::from_args(&bench_args)?;
+            if let Some(dir) = generate {
+                workload.write(&dir).map_err(|e| STError::io(format!("couldn't write the trace to {}", dir.display()), e))?;
+                eprintln!("Wrote {} *.dump files to {}, analyze them with e.g. `st2 -f {} -s {} metrics`", workload.workers, dir.display(), dir.display(), workload.workers);
+                return Ok(true);
+            }
+
+            st2::commands::bench::run(st_workers, &workload, output_format)
+        }
+        ("demo", Some(demo_args)) => {
+            let st2::commands::demo::Options { demo, out } = st2::commands::demo::from_args(&demo_args)?;
+
+            st2::commands::demo::run(timely_configuration, &demo, out.as_deref(), config.operator_names(), output_format)
+        }
+        ("estimate", Some(estimate_args)) => {
+            let st2::commands::estimate::Options { sample_epochs, in_flight } = st2::commands::estimate::from_args(&estimate_args)?;
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::estimate::run(timely_configuration, replay_source, is_running, filter, sample_epochs, in_flight, output_format)
+        }
+        ("verify", Some(verify_args)) => {
+            let st2::commands::verify::Options { record_dir, tolerance } = st2::commands::verify::from_args(&verify_args)?;
+            if args.is_present("from_file") {
+                return Err(STError::Config("verify analyzes a live session, not --from-file".to_string()));
+            }
+            if args.is_present("inject_faults") {
+                // the recording would be replayed with other faults
+                return Err(STError::Config("verify can't be combined with --inject-faults".to_string()));
+            }
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::verify::run(timely_configuration, replay_source, is_running, speed, filter, &record_dir, tolerance, config.operator_names(), output_format)
+                .map(|mismatches| checks_passed = mismatches == 0)
+        }
+        ("algo", Some(_algo_args)) => {
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::algo::run(timely_configuration, replay_source, is_running, speed, filter)
+        }
+        ("dashboard", Some(dashboard_args)) => {
+            let st2::commands::dashboard::Options { epoch_max, operator_max, message_max, auth, listen } = st2::commands::dashboard::from_args(&dashboard_args)?;
+            let auth = auth.map(Arc::new);
+
+            eprintln!("Waiting for source computation...");
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected to source computation!");
 
             let (pag_send, pag_recv) = mpsc::channel();
             let pag_send = Arc::new(Mutex::new(pag_send));
 
-            println!("Waiting for dashboard connection...");
+            let pag_recvd = Arc::new(Mutex::new(HashMap::new()));
+            let server_recvd = Arc::clone(&pag_recvd);
+            let server = ws::WebSocket::new(move |out| Server { out, pag_recvd: Arc::clone(&server_recvd), auth: auth.clone() })
+                .and_then(|server| server.bind(listen.as_str()))
+                .map_err(|e| STError::Config(format!("Invalid --listen: {}", e)))?;
+
+            // push completed epochs to all connected dashboards
+            let broadcaster = server.broadcaster();
+            std::thread::spawn(move || {
+                for (epoch, pag_data) in pag_recv {
+                    let push = match &pag_data {
+                        PagData::Lat(x) => Some(json!({"type": "EPOCH", "payload": x }).to_string()),
+                        _ => None
+                    };
+                    pag_recvd.lock().expect("cannot lock pag_recvd").entry(epoch).or_insert(Vec::new()).push(pag_data);
+                    if let Some(push) = push {
+                        broadcaster.send(push).expect("couldn't push to dashboard");
+                    }
+                }
+            });
+
+            eprintln!("Serving dashboard at http://{}", listen);
             let listener = std::thread::spawn(move || {
-                listen("127.0.0.1:3012", |out| { Server { out, pag_recv: &pag_recv, pag_recvd: HashMap::new() } } ).unwrap();
+                server.run().expect("couldn't serve dashboard");
             });
 
-            st2::commands::dashboard::run(timely_configuration, replay_source, pag_send, epoch_max, operator_max, message_max)?;
+            st2::commands::dashboard::run(timely_configuration, replay_source, Arc::clone(&is_running), speed, filter, pag_send, epoch_max, operator_max, message_max)?;
 
-            listener.join().expect("couldn't join listener");
+            // keep serving the dashboard unless we've been interrupted
+            if is_running.load(Ordering::Acquire) {
+                listener.join().expect("couldn't join listener");
+            }
             Ok(())
         }
-        ("invariants", Some(invariants_args)) => {
-            let progress_max: Option<u64> = if let Some(t) = invariants_args.value_of("progress_max") {
-                Some(t.parse().map_err(|e| STError(format!("Invalid --progress-max: {}", e)))?)
-            } else {
-                None
-            };
-            let epoch_max: Option<u64> = if let Some(t) = invariants_args.value_of("epoch_max") {
-                println!("epoch max given");
-                Some(t.parse().map_err(|e| STError(format!("Invalid --epoch-max: {}", e)))?)
-            } else {
-                None
-            };
-            let operator_max: Option<u64> = if let Some(t) = invariants_args.value_of("operator_max") {
-                Some(t.parse().map_err(|e| STError(format!("Invalid --operator-max: {}", e)))?)
-            } else {
-                None
+        ("grafana", Some(grafana_args)) => {
+            let st2::commands::grafana::Options { listen, retention, auth } = st2::commands::grafana::from_args(&grafana_args)?;
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::grafana::run(timely_configuration, replay_source, is_running, speed, filter, &listen, retention, auth, config.operator_names())
+        }
+        ("api", Some(api_args)) => {
+            let st2::commands::api::Options { listen, retention, ws_listen, auth, origins } = st2::commands::api::from_args(&api_args)?;
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::api::run(timely_configuration, replay_source, is_running, speed, filter, &listen, &ws_listen, retention, auth, origins, config.operator_names())
+        }
+        ("grpc", Some(grpc_args)) => {
+            let st2::commands::grpc::Options { listen, retention, snapshot_dir, auth, tls } = st2::commands::grpc::from_args(&grpc_args)?;
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::grpc::run(timely_configuration, replay_source, is_running, speed, filter, &listen, retention, &snapshot_dir, auth, tls, config.operator_names())
+        }
+        ("daemon", Some(daemon_args)) => {
+            let st2::commands::daemon::Options { window, settings, checkpointing, listen } = st2::commands::daemon::from_args(&daemon_args)?;
+            let reload = || {
+                let config = match matches.value_of("config") {
+                    Some(path) => Config::load(std::path::Path::new(path))?,
+                    None => Config::default(),
+                };
+                config.check(&app, &command_line, Some("daemon"))?;
+                st2::commands::daemon::settings(&Args { matches: daemon_args.matches, config: &config, subcommand: Some("daemon") })
             };
-            let message_max: Option<u64> = if let Some(t) = invariants_args.value_of("message_max") {
-                Some(t.parse().map_err(|e| STError(format!("Invalid --message-max: {}", e)))?)
-            } else {
-                None
+
+            let resumed = match checkpointing.as_ref() {
+                Some(checkpointing) => st2::commands::daemon::Checkpoint::load(&checkpointing.path)?.unwrap_or_default(),
+                None => Default::default(),
             };
 
-            let replay_source = make_replay_source(&args)?;
-            println!("Connected!");
+            let listen = listen.as_str();
+            tracing::info!(listen, window, rules = settings.rules.len() as u64, resumed_at = resumed.next_epoch, "daemon started");
+            let health = st2::commands::daemon::Health::serve(listen, resumed)?;
+            RELOAD_ON_HANGUP.store(true, Ordering::Release);
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            let result = st2::commands::daemon::run(timely_configuration, replay_source, is_running, speed, filter, window, settings, reload, hangup, health, checkpointing, output_format);
+            match &result {
+                Ok(alerts) => tracing::info!(alerts, "daemon stopped"),
+                Err(e) => tracing::error!(error = %e, "daemon failed"),
+            }
+            result.map(|_| ())
+        }
+        ("publish", Some(publish_args)) => {
+            let st2::commands::publish::Options { sinks, sink_options } = st2::commands::publish::from_args(&publish_args)?;
+
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
 
-            st2::commands::invariants::run(timely_configuration, replay_source, epoch_max, operator_max, message_max, progress_max)
+            st2::commands::publish::run(timely_configuration, replay_source, is_running, speed, filter, sinks, sink_options, config.operator_names(), output_format)
+                .map(|_| ())
+        }
+        ("invariants", Some(invariants_args)) => {
+            let st2::commands::invariants::Options { epoch_max, operator_max, message_max, progress_max } = st2::commands::invariants::from_args(&invariants_args)?;
+
+            let source_peers = parse_source_peers(&args)?;
+            let replay_source = make_replay_source(&args, &is_running)?;
+            eprintln!("Connected!");
+
+            st2::commands::invariants::run(timely_configuration, replay_source, is_running, speed, filter, source_peers, epoch_max, operator_max, message_max, progress_max, output_format)
+                .map(|violations| checks_passed = violations == 0)
         }
         _ => panic!("Invalid subcommand"),
     }?;
 
-    Ok(())
+    st2::self_profile::report(output_format)?;
+    tracing::info!(elapsed_ms = started.elapsed().as_millis() as u64, checks_passed, "command finished");
+    Ok(checks_passed)
 }
 
-/// creates one socket per worker in the computation we're examining
-fn make_replay_source(args: &clap::ArgMatches) -> Result<ReplaySource, STError> {
-    let source_peers: usize = args.value_of("source_peers").expect("error parsing source peers args")
-        .parse().map_err(|e| STError(format!("Invalid --source-peers: {}", e)))?;
+/// parses `(processes, process_id)` of a (potentially) clustered ST2
+fn parse_processes(args: &Args) -> Result<(usize, usize), STError> {
+    let processes: usize = args.value_of("processes").expect("error parsing processes args")
+        .parse().map_err(|e| STError::Config(format!("Invalid --processes: {}", e)))?;
+    let process_id: usize = args.value_of("process_id").expect("error parsing process id args")
+        .parse().map_err(|e| STError::Config(format!("Invalid --process-id: {}", e)))?;
 
-    if let Some(path) = args.value_of("from_file") {
-        let path: String = path.parse().map_err(|e| STError(format!("Invalid --from_file: {}", e)))?;
+    if processes == 0 || process_id >= processes {
+        Err(STError::Config(format!("Invalid --process-id: {} is not in 0..{}", process_id, processes)))?
+    }
 
-        println!("Reading from {} *.dump files", source_peers);
+    Ok((processes, process_id))
+}
 
-        let files = (0 .. source_peers)
-            .map(|idx| format!("{}/{}.dump", path, idx))
-            .map(|path| Some(PathBuf::from(path)))
-            .collect::<Vec<_>>();
+/// parses the global filters on the analyzed events
+fn parse_filter(args: &Args) -> Result<Filter, STError> {
+    let mut filter = Filter::default();
+    if let Some(workers) = args.value_of("workers") {
+        filter.workers = Some(workers.split(',')
+            .map(|worker| worker.trim().parse().map_err(|e| STError::Config(format!("Invalid --workers: {}: {}", worker, e))))
+            .collect::<Result<_, _>>()?);
+    }
+    if let Some(operators) = args.value_of("operators") {
+        filter.operators = Some(operators.split(',')
+            .map(|operator| operator.trim().parse().map_err(|e| STError::Config(format!("Invalid --operators: {}", e))))
+            .collect::<Result<_, _>>()?);
+    }
+    if let Some(epochs) = args.value_of("epochs") {
+        filter.epochs = st2::commands::parse_epochs(epochs)?;
+    }
+    Ok(filter)
+}
 
-        Ok(ReplaySource::Files(Arc::new(Mutex::new(files))))
+/// creates one socket per worker in the computation we're examining.
+/// In cluster mode, only the shard of source peers `idx` with
+/// `idx % processes == process_id` is handled by this process.
+fn make_replay_source(args: &Args, is_running: &AtomicBool) -> Result<ReplaySource, STError> {
+    if let Some(path) = args.value_of("from_file") {
+        make_file_source(args, is_running, path)
+    } else if args.is_present("deterministic") {
+        // sources connect in any order
+        Err(STError::Config("--deterministic requires --from-file".to_string()))
     } else {
+        let shard = source_shard(args)?;
         let ip_addr: std::net::IpAddr = args.value_of("interface").expect("error parsing ip addr args")
-            .parse().map_err(|e| STError(format!("Invalid --interface: {}", e)))?;
+            .parse().map_err(|e| STError::Config(format!("Invalid --interface: {}", e)))?;
         let port: u16 = args.value_of("port").expect("error parsing args")
-            .parse().map_err(|e| STError(format!("Invalid --port: {}", e)))?;
+            .parse().map_err(|e| STError::Config(format!("Invalid --port: {}", e)))?;
 
-        println!("Listening for {} connections on {}:{}", source_peers, ip_addr, port);
+        eprintln!("Listening for {} connections on {}:{}", shard.len(), ip_addr, port);
 
-        let sockets = connect::open_sockets(ip_addr, port, source_peers)?;
+        let sockets = connect::open_sockets(ip_addr, port, shard.len())?;
         Ok(ReplaySource::Tcp(Arc::new(Mutex::new(sockets))))
     }
 }
 
+/// reads this process's shard of the `*.dump` files in `path` (without trailing /).
+/// With `--follow`, waits for the files to appear; they are then read as they grow.
+fn make_file_source(args: &Args, is_running: &AtomicBool, path: &str) -> Result<ReplaySource, STError> {
+    let shard = source_shard(args)?;
+
+    let paths = shard.iter()
+        .map(|idx| PathBuf::from(format!("{}/{}.dump", path, idx)))
+        .collect::<Vec<_>>();
+
+    if args.is_present("follow") {
+        eprintln!("Following {} *.dump files in {}", shard.len(), path);
+        wait_for_files(&paths, is_running)?;
+    } else {
+        eprintln!("Reading from {} *.dump files in {}", shard.len(), path);
+    }
+
+    for path in paths.iter() {
+        st2_timely::replay_throttled::PROGRESS.add_input(std::fs::metadata(path).map(|m| m.len()).unwrap_or(0));
+    }
+    let interval: f64 = args.value_of("progress").expect("error parsing progress args")
+        .parse().ok().filter(|secs: &f64| *secs >= 0.0 && secs.is_finite())
+        .ok_or_else(|| STError::Config("Invalid --progress: expected a number of seconds".to_string()))?;
+    if interval > 0.0 {
+        // one report for all sources, e.g. of `diff`
+        static REPORTER: std::sync::Once = std::sync::Once::new();
+        let follow = args.is_present("follow");
+        REPORTER.call_once(|| st2::progress::report(Duration::from_secs_f64(interval), follow));
+    }
+
+    let files = paths.into_iter().map(Some).collect::<Vec<_>>();
+
+    Ok(ReplaySource::Files(Arc::new(Mutex::new(files))))
+}
+
+/// Blocks until all `paths` exist, e.g. because the source computation started
+/// writing them, or until ST2 is shut down.
+fn wait_for_files(paths: &[PathBuf], is_running: &AtomicBool) -> Result<(), STError> {
+    let mut waiting = false;
+    while let Some(missing) = paths.iter().find(|path| !path.exists()) {
+        if !is_running.load(Ordering::Acquire) {
+            return Err(STError::Connect { reason: format!("shut down while waiting for {}", missing.display()), source: None });
+        }
+        if !waiting {
+            eprintln!("Waiting for {} to be created", missing.display());
+            waiting = true;
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    Ok(())
+}
+
+/// the number of workers in the source computation
+fn parse_source_peers(args: &Args) -> Result<usize, STError> {
+    args.value_of("source_peers").ok_or_else(|| STError::Config("--source-peers is required".to_string()))?
+        .parse().ok().filter(|peers| *peers > 0)
+        .ok_or_else(|| STError::Config("Invalid --source-peers: expected a positive number of workers".to_string()))
+}
+
+/// the source peers handled by this process. They are distributed round-robin
+/// across its ST2 workers, whose number is independent of the source peers'.
+fn source_shard(args: &Args) -> Result<Vec<usize>, STError> {
+    let source_peers = parse_source_peers(args)?;
+    let (processes, process_id) = parse_processes(args)?;
+    let shard: Vec<usize> = (0 .. source_peers).filter(|idx| idx % processes == process_id).collect();
+
+    let st_workers: usize = args.value_of("snailtrail_workers").expect("error parsing worker args")
+        .parse().map_err(|e| STError::Config(format!("Invalid --snailtrail-workers: {}", e)))?;
+    if shard.len() < st_workers && !args.is_present("repartition") {
+        eprintln!("{} of {} ST2 workers have no source peer to replay; pass --repartition to share the analysis with them",
+                  st_workers - shard.len(), st_workers);
+    }
+    Ok(shard)
+}
+
+
+/// Serves the dashboard's web UI and answers its queries over a websocket at `/ws`.
+/// With `auth`, every request has to be authorized for its path (cf. `st2::auth`);
+/// the web UI passes its `access_token` on to `/charts.js` and `/ws`.
+struct Server { out: Sender, pag_recvd: Arc<Mutex<HashMap<u64, Vec<PagData>>>>, auth: Option<Arc<st2::auth::Auth>> }
+impl Handler for Server {
+    fn on_request(&mut self, req: &Request) -> ws::Result<Response> {
+        if let Some(auth) = self.auth.as_ref() {
+            if let Err(denied) = auth.authorize_ws(req) {
+                let reason = if denied.status() == 401 { "Unauthorized" } else { "Forbidden" };
+                let mut response = Response::new(denied.status(), reason, denied.to_string().into_bytes());
+                if denied.status() == 401 {
+                    response.headers_mut().push(("WWW-Authenticate".to_string(), b"Bearer".to_vec()));
+                }
+                return Ok(response);
+            }
+        }
+
+        let path = req.resource().splitn(2, '?').next().unwrap_or("");
+        let (body, content_type) = match path {
+            "/ws" => return Response::from_request(req),
+            "/" | "/index.html" => (DASHBOARD_HTML, "text/html; charset=utf-8"),
+            "/charts.js" => (DASHBOARD_JS, "application/javascript; charset=utf-8"),
+            _ => return Ok(Response::new(404, "Not Found", b"404 - Not Found".to_vec())),
+        };
+
+        let mut response = Response::new(200, "OK", body.as_bytes().to_vec());
+        response.headers_mut().push(("Content-Type".to_string(), content_type.as_bytes().to_vec()));
+        Ok(response)
+    }
 
-struct Server<'a> { out: Sender, pag_recv: &'a mpsc::Receiver<(u64, PagData)>, pag_recvd: HashMap<u64, Vec<PagData>> }
-impl<'a> Handler for Server<'a> {
     fn on_open(&mut self, _: Handshake) -> ws::Result<()> {
-        println!("Connected to dashboard!");
+        eprintln!("Connected to dashboard!");
         Ok(())
     }
 
     fn on_message(&mut self, msg: Message) -> ws::Result<()> {
-        // update internal state
-        while let Ok((epoch, pag_data)) = self.pag_recv.try_recv() {
-            self.pag_recvd.entry(epoch).or_insert(Vec::new()).push(pag_data);
-        }
+        let mut pag_recvd = self.pag_recvd.lock().expect("cannot lock pag_recvd");
 
         let payload: serde_json::Value = match msg {
             Message::Text(msg) => serde_json::from_str(&msg).unwrap(),
@@ -288,7 +1024,7 @@ impl<'a> Handler for Server<'a> {
 
         match payload_type {
             "ALL" => {
-                if let Some(events) = self.pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
+                if let Some(events) = pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
                     let result: Vec<_> = events.iter().filter_map(|x| match x {
                         PagData::All(x) => Some(x),
                         _ => None
@@ -299,7 +1035,7 @@ impl<'a> Handler for Server<'a> {
                 }
             },
             "AGG" => {
-                if let Some(events) = self.pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
+                if let Some(events) = pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
                     let result: Vec<_> = events.iter().filter_map(|x| match x {
                         PagData::Agg(x) => Some(x),
                         _ => None
@@ -310,7 +1046,7 @@ impl<'a> Handler for Server<'a> {
                 }
             },
             "PAG" => {
-                if let Some(events) = self.pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
+                if let Some(events) = pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
                     let mut result: Vec<_> = events.iter()
                         .filter_map(|x| match x {
                             PagData::Pag(x) => {
@@ -335,7 +1071,7 @@ impl<'a> Handler for Server<'a> {
                 }
             },
             "MET" => {
-                if let Some(events) = self.pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
+                if let Some(events) = pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
                     let result: Vec<_> = events.iter().filter_map(|x| match x {
                         PagData::Met(x) => Some(x),
                         _ => None
@@ -345,8 +1081,19 @@ impl<'a> Handler for Server<'a> {
                     self.out.send(json!({"type": "MET", "payload": Vec::<u64>::new() }).to_string())?;
                 }
             }
+            "LAT" => {
+                let mut result: Vec<_> = pag_recvd.iter()
+                    .flat_map(|(epoch, events)| events.iter().filter_map(move |x| match x {
+                        PagData::Lat(x) => Some((*epoch, x)),
+                        _ => None
+                    }))
+                    .collect();
+                result.sort_by_key(|(epoch, _)| *epoch);
+                let result: Vec<_> = result.into_iter().map(|(_, x)| x).collect();
+                self.out.send(json!({"type": "LAT", "payload": result }).to_string())?;
+            }
             "INV" => {
-                if let Some(events) = self.pag_recvd.remove(&0) {
+                if let Some(events) = pag_recvd.remove(&0) {
                     let result: Vec<_> = events.iter().filter_map(|x| match x {
                         PagData::Inv(x) => Some(x),
                         _ => None
@@ -1,190 +1,144 @@
 //! Pag Construction
 //! Uses LogRecord representation to create a PAG that contains local and remote edges
 
-use std::collections::HashMap;
-use std::{io::Read, time::Duration};
-use std::cmp::Ordering;
-use std::hash::Hash;
-use std::convert::TryInto;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use std::sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}};
 
 use timely::dataflow::{channels::pact::Exchange, operators::generic::operator::Operator, Scope};
 use timely::dataflow::channels::pact::Pipeline;
 use timely::dataflow::Stream;
-use timely::dataflow::operators::filter::Filter;
 use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::flat_map::FlatMap;
 use timely::dataflow::operators::inspect::Inspect;
 use timely::dataflow::operators::concat::Concat;
-use timely::Data;
+use timely::dataflow::operators::capture::event::EventIterator;
 
-use st2_logformat::{ActivityType, EventType, LogRecord, OperatorId};
+use st2_logformat::{ActivityType, EventType, LogRecord};
 use ActivityType::{Busy, Waiting, Scheduling, Processing, Spinning, ControlMessage, DataMessage};
 use EventType::{Sent, Received, Start, End};
 use st2_logformat::pair::Pair;
-use st2_timely::{connect::Replayer, create_lrs};
+use st2_logformat::batch::LogRecordBatch;
+use st2_timely::{connect::CompEvent, create_lr_batches, diagnostics::StageTimer, filter::Filter, replay_throttled::{ReplaySpeed, PROGRESS}};
 
-use abomonation::Abomonation;
+pub use st2_logformat::pag::{Link, PagEdge, PagNode, TraversalType};
 
-use serde::Serialize;
+use crate::deterministic::Deterministic;
 
-
-/// A node in the PAG
-#[derive(Abomonation, Clone, PartialEq, Hash, Eq, Copy, Serialize)]
-pub struct PagNode {
-    /// Timestamp of the event (also a unique identifier!)
-    pub timestamp: st2_logformat::Timestamp,
-    /// Unique ID of the worker the event belongs to
-    pub worker_id: st2_logformat::Worker,
-    /// Epoch of PagNode
-    pub epoch: u64,
-    /// seq_no of PagNode
-    pub seq_no: u64,
-}
-
-impl Ord for PagNode {
-    fn cmp(&self, other: &PagNode) -> Ordering {
-        self.timestamp.cmp(&other.timestamp)
-    }
-}
-
-impl PartialOrd for PagNode {
-    fn partial_cmp(&self, other: &PagNode) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl<'a> From<&'a LogRecord> for PagNode {
-    fn from(record: &'a LogRecord) -> Self {
-        PagNode {
-            timestamp: record.timestamp,
-            worker_id: record.local_worker,
-            epoch: record.epoch,
-            seq_no: record.seq_no,
-        }
-    }
-}
-
-impl Default for PagNode {
-    fn default() -> Self {
-        PagNode {
-            timestamp: Default::default(),
-            worker_id: Default::default(),
-            epoch: Default::default(),
-            seq_no: Default::default()
-        }
-    }
-}
-
-impl std::fmt::Debug for PagNode {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        // write!(f, "{}|{:?}@w{} (s{})", self.epoch, self.timestamp, self.worker_id, self.seq_no)
-        write!(f, "{},{},{},{}", self.epoch, self.timestamp.as_nanos(), self.worker_id, self.seq_no)
-    }
+// @TODO currently, this creates a new pag per epoch, but never removes the old one.
+//       so state will continually grow and multiple pags exist side by side.
+// @TODO: add an optional checking operator that tests individual logrecord timelines for sanity
+// e.g. sched start -> sched end, no interleave, start & end always belong to scheduling,
+// sent/received always to remote messages, we don't see message types that we can't handle yet,
+// t1 is always > t0, same count of sent and received remote messages for every epoch
+// same results regardless of worker count, received events always have a remote worker
+// matched remote events are (remote-count / 2), remote event count is always even
+/// Creates a PAG (a Collection of `PagEdge`s, grouped by epoch) from the provided `Replayer`s.
+/// To be called from within a timely computation.
+/// Replay stops early once `is_running` is unset, completing all epochs in flight,
+/// and is paced according to `speed`. Only events selected by `filter` are analyzed.
+/// Once `repartition_by_epoch` was called, `LogRecord`s are repartitioned across
+/// workers by epoch before the PAG is constructed. Up to the PAG operators,
+/// records are passed around in columnar batches (cf. `LogRecordBatch`). In
+/// deterministic mode, the PAG is sorted by epoch (cf. `Deterministic`). The
+/// edges of epochs replay was stopped in the middle of are dropped (cf.
+/// `DropTruncated`), so no analysis reports a partial epoch.
+pub fn create_pag<S: Scope<Timestamp = Pair<u64, Duration>>, I: 'static + EventIterator<Pair<u64, Duration>, CompEvent>> (
+    scope: &mut S,
+    replayers: Vec<I>,
+    index: usize,
+    is_running: Option<Arc<AtomicBool>>,
+    throttle: u64,
+    speed: ReplaySpeed,
+    filter: Filter,
+) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
+    let records = create_lr_batches(scope, replayers, index, is_running, throttle, speed, filter);
+    let pag = match EPOCH_BLOCK.load(Ordering::Acquire) {
+        0 => records.construct_pag(index),
+        block => records.repartition(block).construct_pag(index),
+    };
+    pag.drop_truncated().deterministic()
 }
 
-/// Information on how to traverse an edge. This is used e.g. in critical
-/// participation to decide whether an edge should be included in the critical
-/// path calculation. A `Block`ed edge can't be traversed (e.g. waiting activities)
-#[derive(Abomonation, Hash, Clone, Eq, Ord, PartialEq, PartialOrd, Debug, Serialize)]
-pub enum TraversalType {
-    /// Unclear traversal
-    Undefined,
-    /// No traversal possible
-    Block,
-    /// Traversal possible
-    Unbounded,
+/// Operator that drops the PAG edges of epochs cut short by an interrupt
+pub trait DropTruncated<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Holds every epoch's edges until the epoch completes, and drops them if
+    /// replay was stopped before all of the epoch's events were replayed (cf.
+    /// `ReplayProgress::is_truncated`).
+    fn drop_truncated(&self) -> Stream<S, (PagEdge, S::Timestamp, isize)>;
 }
 
-/// An edge in the activity graph
-#[derive(Abomonation, Clone, PartialEq, Hash, Eq, Serialize)]
-pub struct PagEdge {
-    /// The source node
-    pub source: PagNode,
-    /// The destination node
-    pub destination: PagNode,
-    /// The activity type
-    pub edge_type: ActivityType,
-    /// An optional operator ID
-    pub operator_id: Option<OperatorId>,
-    /// Edge dependency information
-    pub traverse: TraversalType,
-    /// record count
-    pub length: Option<usize>,
-}
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> DropTruncated<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn drop_truncated(&self) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
+        let mut vector = Vec::new();
+        let mut pending = BTreeMap::new();
 
-impl PagEdge {
-    /// PagEdge's duration in ns.
-    /// Due to clock skew, we can't give guarantees that `to.timestamp > from.timestamp`.
-    /// We report a duration of 0 in the case that `to.timestamp < from.timestamp`.
-    pub fn duration(&self) -> u64 {
-        let dst_ts = self.destination.timestamp.as_nanos();
-        let src_ts = self.source.timestamp.as_nanos();
+        self.unary_frontier(Pipeline, "DropTruncated", move |_, _| { move |input, output| {
+            input.for_each(|cap, data| {
+                data.swap(&mut vector);
+                pending.entry(cap.time().clone())
+                    .or_insert_with(|| (cap.retain(), Vec::new()))
+                    .1.extend(vector.drain(..));
+            });
 
-        if src_ts > dst_ts {
-            0
-        } else {
-            (dst_ts - src_ts).try_into().unwrap()
-        }
+            // replay marks an epoch truncated before releasing its capabilities,
+            // so whether it was cut short is known once the frontier passed it
+            let frontier = input.frontier().frontier();
+            let complete: Vec<S::Timestamp> = pending.keys()
+                .filter(|time: &&S::Timestamp| !frontier.iter().any(|t| t.first <= time.first))
+                .cloned()
+                .collect();
+            for time in complete {
+                let (cap, edges) = pending.remove(&time).expect("pending time vanished");
+                if PROGRESS.is_truncated(time.first) {
+                    debug!("replay was stopped in epoch {}, dropping {} edges", time.first, edges.len());
+                } else {
+                    output.session(&cap).give_iterator(edges.into_iter());
+                }
+            }
+        }})
     }
 }
 
-impl Ord for PagEdge {
-    fn cmp(&self, other: &PagEdge) -> Ordering {
-        self.source.cmp(&other.source)
-    }
-}
+/// Epochs per block of `Repartition`, `0` if `create_pag` doesn't repartition
+static EPOCH_BLOCK: AtomicU64 = AtomicU64::new(0);
 
-impl PartialOrd for PagEdge {
-    fn partial_cmp(&self, other: &PagEdge) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
+/// Makes `create_pag` repartition `LogRecord`s across workers by blocks of
+/// `block` epochs (`--repartition`), e.g. so that all workers are busy although
+/// the source computation has fewer workers, or some of its workers are busier.
+pub fn repartition_by_epoch(block: u64) {
+    EPOCH_BLOCK.store(block, Ordering::Release);
 }
 
-impl Default for PagEdge {
-    fn default() -> Self {
-        PagEdge {
-            source: Default::default(),
-            destination: Default::default(),
-            edge_type: Waiting,
-            operator_id: None,
-            traverse: TraversalType::Block,
-            length: None,
-        }
-    }
+/// Operator that repartitions batches of `LogRecord`s across workers by epoch
+pub trait Repartition<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Sends the records of every block of `block` epochs to the same worker,
+    /// assigning blocks to workers round-robin. Records of a source worker in an
+    /// epoch stay in order, so local edges (which never cross epochs) are kept.
+    /// Batches spanning several blocks are split.
+    fn repartition(&self, block: u64) -> Stream<S, LogRecordBatch>;
 }
 
-impl std::fmt::Debug for PagEdge {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        // write!(f, "{:?} -> {:?} | {:?} {:?}\t| oid: {:?}\t",
-        //        self.source, self.destination,
-        //        self.traverse, self.edge_type,
-        //        self.operator_id)
-
-        write!(f, "{:?},{:?},{:?},{:?},{:?}",
-               self.source, self.destination,
-               self.edge_type, self.operator_id, self.length)
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> Repartition<S> for Stream<S, LogRecordBatch> {
+    fn repartition(&self, block: u64) -> Stream<S, LogRecordBatch> {
+        let mut vector = Vec::new();
+        let mut timer = StageTimer::new("Repartition", self.scope().index());
+        self.flat_map(move |batch| batch.split_by_epoch(|epoch| epoch / block))
+            .unary(Exchange::new(|(block, _): &(u64, LogRecordBatch)| *block), "Repartition", move |_, _| { move |input, output| {
+                input.for_each(|cap, data| {
+                    data.swap(&mut vector);
+                    let records: usize = vector.iter().map(|(_, batch)| batch.len()).sum();
+                    timer.time(records, || {
+                        let mut session = output.session(&cap);
+                        for (_, batch) in vector.drain(..) {
+                            session.give(batch);
+                        }
+                    });
+                });
+            }})
     }
 }
 
-// @TODO currently, this creates a new pag per epoch, but never removes the old one.
-//       so state will continually grow and multiple pags exist side by side.
-// @TODO: add an optional checking operator that tests individual logrecord timelines for sanity
-// e.g. sched start -> sched end, no interleave, start & end always belong to scheduling,
-// sent/received always to remote messages, we don't see message types that we can't handle yet,
-// t1 is always > t0, same count of sent and received remote messages for every epoch
-// same results regardless of worker count, received events always have a remote worker
-// matched remote events are (remote-count / 2), remote event count is always even
-/// Creates a PAG (a Collection of `PagEdge`s, grouped by epoch) from the provided `Replayer`s.
-/// To be called from within a timely computation.
-pub fn create_pag<S: Scope<Timestamp = Pair<u64, Duration>>, R: 'static + Read> (
-    scope: &mut S,
-    replayers: Vec<Replayer<S::Timestamp, R>>,
-    index: usize,
-    throttle: u64,
-) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
-    create_lrs(scope, replayers, index, throttle)
-        .construct_pag(index)
-}
-
 /// Dump PAG to file
 pub trait DumpPAG<S: Scope<Timestamp = Pair<u64, Duration>>> {
     /// Dump PAG to file
@@ -201,10 +155,10 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> DumpPAG<S> for Stream<S, (PagEdg
     }
 }
 
-/// Operator that converts a Stream of LogRecords to a PAG
+/// Operator that converts a Stream of batches of LogRecords to a PAG
 pub trait ConstructPAG<S: Scope<Timestamp = Pair<u64, Duration>>> {
-    /// Builds a PAG from `LogRecord` by concatenating local edges, control edges
-    /// and data edges.
+    /// Builds a PAG from `LogRecord` batches by concatenating local edges, control
+    /// edges and data edges.
     fn construct_pag(&self, index: usize) -> Stream<S, (PagEdge, S::Timestamp, isize)>;
     /// Takes `LogRecord`s and connects local edges (per epoch, per worker)
     fn make_local_edges(&self, index: usize) -> Stream<S, (PagEdge, S::Timestamp, isize)>;
@@ -212,9 +166,18 @@ pub trait ConstructPAG<S: Scope<Timestamp = Pair<u64, Duration>>> {
     fn build_local_edge(prev: &LogRecord, record: &LogRecord, next: &LogRecord) -> PagEdge;
     /// Takes `LogRecord`s and connects remote edges (per epoch, across workers)
     fn make_remote_edges(&self) -> Stream<S, (PagEdge, S::Timestamp, isize)>;
+    /// Tags `LogRecord`s with their worker's generation within their epoch, which
+    /// increases whenever the worker's `seq_no` resets or wraps around (e.g. because
+    /// the monitored job restarted mid-capture). Correlators are only unique within
+    /// a generation. Messages are matched within an epoch, so generations are
+    /// counted per epoch: a worker's records of an epoch arrive in order, but its
+    /// epochs may arrive interleaved (e.g. from several of its load-balanced
+    /// writers), and a reset counted in one epoch doesn't set its generations
+    /// apart from those of its peers in all later epochs.
+    fn tag_generations(&self) -> Stream<S, (u64, LogRecord)>;
 }
 
-impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, LogRecord> {
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, LogRecordBatch> {
     fn construct_pag(&self, index: usize) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
         self.make_local_edges(index)
             .concat(&self.make_remote_edges())
@@ -229,12 +192,19 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
         let mut prev2_buffer: HashMap<usize, LogRecord> = HashMap::new();
         let mut prev_buffer: HashMap<usize, LogRecord> = HashMap::new();
 
+        let mut timer = StageTimer::new("Local Edges", index);
         self.unary_frontier(Pipeline, "Local Edges", move |_, _| { move |input, output| {
-            input.for_each(|cap, data| {
+            input.for_each(|cap, data| timer.time(data.iter().map(LogRecordBatch::len).sum(), || {
                 data.swap(&mut vector);
-                for lr in vector.drain(..) {
+                for lr in vector.drain(..).flat_map(LogRecordBatch::into_records) {
                     let local_worker = lr.local_worker as usize;
 
+                    // the monitored job restarted (cf. `tag_generations`): don't connect across the reset
+                    if prev_buffer.get(&local_worker).map_or(false, |prev_lr| prev_lr.epoch == lr.epoch && lr.seq_no < prev_lr.seq_no) {
+                        prev_buffer.remove(&local_worker);
+                        prev2_buffer.remove(&local_worker);
+                    }
+
                     if let Some(prev_lr) = prev_buffer.remove(&local_worker) {
                         if let Some(prev2_lr) = prev2_buffer.remove(&local_worker) {
                             // we've seen two lrs from this local_worker before
@@ -258,7 +228,7 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
                     // move lr -> prev_lr
                     prev_buffer.insert(local_worker, lr);
                 }
-            });
+            }));
 
             trace!("made local edges");
         }})
@@ -341,30 +311,14 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
             operator_id,
             traverse,
             length,
+            link: None,
         }
     }
 
     fn make_remote_edges(&self) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
-        let narrowed = self.filter(|x| x.activity_type == ControlMessage || x.activity_type == DataMessage);
-
-        let sent = narrowed
-            .filter(|x| x.event_type == Sent)
-            .map(|x| ((Some(x.local_worker), x.remote_worker, x.correlator_id, x.channel_id), x));
-
-        let received = narrowed
-            .filter(|x| x.event_type == Received)
-            .map(|x| {
-                // ControlMessage sends are broadcasts; they have no receiver.
-                // x.remote_worker is None for them, so here, it has to be, too.
-                let receiver = if x.activity_type == ControlMessage {
-                    None
-                } else {
-                    Some(x.local_worker)
-                };
-                ((x.remote_worker, receiver, x.correlator_id, x.channel_id), x)
-            });
-
-        sent.join_edges(&received)
+        self.map(|batch| batch.select(&batch.activity_mask(&[ControlMessage, DataMessage])))
+            .tag_generations()
+            .match_messages()
             .map(|(from, to, t)| {
                 assert!(to.local_worker != from.local_worker);
                 (PagEdge {
@@ -374,77 +328,116 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
                 operator_id: None,
                 traverse: TraversalType::Unbounded,
                 length: from.length,
+                link: match (&from.origin, &to.origin) {
+                    (Some(from), Some(to)) => Some(Link::between(from, to)),
+                    _ => None,
+                },
                 }, t, 1)})
     }
+
+    fn tag_generations(&self) -> Stream<S, (u64, LogRecord)> {
+        let mut vector = Vec::new();
+        // epoch -> local_worker -> (generation, last seq_no)
+        let mut generations: BTreeMap<u64, HashMap<u64, (u64, u64)>> = BTreeMap::new();
+
+        self.unary_frontier(Pipeline, "Generations", move |_, _| { move |input, output| {
+            input.for_each(|cap, data| {
+                data.swap(&mut vector);
+                let mut session = output.session(&cap);
+                for lr in vector.drain(..).flat_map(LogRecordBatch::into_records) {
+                    let (generation, last_seq_no) = generations.entry(lr.epoch).or_insert_with(HashMap::new)
+                        .entry(lr.local_worker).or_insert((0, lr.seq_no));
+                    if lr.seq_no < *last_seq_no {
+                        *generation += 1;
+                    }
+                    *last_seq_no = lr.seq_no;
+                    session.give((*generation, lr));
+                }
+            });
+
+            // records of epoch `e` arrive at `Pair(e, _)`
+            let frontier = input.frontier().frontier();
+            while let Some(epoch) = generations.keys().next().cloned() {
+                if frontier.iter().any(|t| t.first <= epoch) {
+                    break;
+                }
+                generations.remove(&epoch);
+            }
+        }})
+    }
 }
 
-trait JoinEdges<S: Scope<Timestamp = Pair<u64, Duration>>, D> where D: Data + Hash + Eq + Abomonation + Send + Sync {
-    fn join_edges(&self, other: &Stream<S, (D, LogRecord)>) -> Stream<S, (LogRecord, LogRecord, S::Timestamp)>;
+/// The key remote messages are matched by: (generation, sender, receiver,
+/// correlator, channel). Correlators are scoped by generation, so that they
+/// aren't matched across restarts (cf. `tag_generations`).
+type MessageKey = (u64, Option<u64>, Option<u64>, Option<u64>, Option<u64>);
+
+/// The sent and received records of a message key that are still waiting for
+/// their counterparts
+#[derive(Default)]
+struct PendingMessages {
+    sent: Vec<LogRecord>,
+    received: Vec<LogRecord>,
 }
 
-impl<S: Scope<Timestamp = Pair<u64, Duration>>, D> JoinEdges<S, D>
-    for Stream<S, (D, LogRecord)>
-where D: Data + Hash + Eq + Abomonation + Send + Sync + std::fmt::Debug
-{
-    fn join_edges(&self, other: &Stream<S, (D, LogRecord)>) -> Stream<S, (LogRecord, LogRecord, S::Timestamp)> {
-        // exchange by epoch doesn't make sense for low epoch_in_flight counts
-        // let exchange = Exchange::new(|(_, x): &(_, LogRecord)| x.epoch);
-        // let exchange2 = Exchange::new(|(_, x): &(_, LogRecord)| x.epoch);
-
-        // exchange by local / remote worker doesn't make sense for STw > TCw
-        // let exchange = Exchange::new(|(_, x): &(_, LogRecord)| x.local_worker);
-        // let exchange2 = Exchange::new(|(_, x): &(_, LogRecord)| x.remote_worker.unwrap());
-
-        // @TODO: exchange by correlator_id works, but is surprisingly slow
-        let exchange = Exchange::new(|(_, x): &(_, LogRecord)| x.correlator_id.expect("no corr id"));
-        let exchange2 = Exchange::new(|(_, x): &(_, LogRecord)| x.correlator_id.expect("no corr id"));
-
-        // @TODO: in this join implementation, state continually grows.
-        // To fix this, check frontier and remove all state that is from older epochs
-        // (cross-epochs join shouldn't happen anyways)
-        self.binary(&other, exchange, exchange2, "HashJoin", |_capability, _info| {
-            let mut map1 = HashMap::new();
-            let mut map2 = HashMap::<_, Vec<LogRecord>>::new();
-
-            let mut vector1 = Vec::new();
-            let mut vector2 = Vec::new();
-
-            move |input1, input2, output| {
-                // Drain first input, check second map, update first map.
-                input1.for_each(|cap, data| {
-                    data.swap(&mut vector1);
-                    let mut session = output.session(&cap);
-                    for (key, val1) in vector1.drain(..) {
-                        if let Some(values) = map2.get(&key) {
-                            for val2 in values.iter() {
-                                // assert!(val1.epoch == val2.epoch);
-                                if val1.epoch == val2.epoch {
-                                    session.give((val1.clone(), val2.clone(), cap.time().clone()));
-                                }
-                            }
-                        }
+trait MatchMessages<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Matches sent and received message records into (sent, received, time) triples
+    fn match_messages(&self) -> Stream<S, (LogRecord, LogRecord, S::Timestamp)>;
+}
 
-                        map1.entry(key).or_insert(Vec::new()).push(val1);
-                    }
-                });
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> MatchMessages<S> for Stream<S, (u64, LogRecord)> {
+    fn match_messages(&self) -> Stream<S, (LogRecord, LogRecord, S::Timestamp)> {
+        // exchange by epoch doesn't make sense for low epoch_in_flight counts,
+        // exchange by local / remote worker doesn't make sense for STw > TCw.
+        // Both sides of a message share its correlator, so a single exchange of
+        // the records and a single keyed state serve both sides of the join.
+        let exchange = Exchange::new(|(_, x): &(u64, LogRecord)| x.correlator_id.expect("no corr id"));
 
-                input2.for_each(|cap, data| {
-                    data.swap(&mut vector2);
-                    let mut session = output.session(&cap);
-                    for (key, val2) in vector2.drain(..) {
-                        if let Some(values) = map1.get(&key) {
-                            for val1 in values.iter() {
-                                // assert!(val1.epoch == val2.epoch);
-                                if val1.epoch == val2.epoch {
-                                    session.give((val1.clone(), val2.clone(), cap.time().clone()));
-                                }
-                            }
-                        }
+        let mut vector = Vec::new();
+        // epoch -> message key -> pending records. Messages are only matched within
+        // an epoch, so an epoch's state is dropped once the frontier has passed it.
+        let mut pending: BTreeMap<u64, HashMap<MessageKey, PendingMessages>> = BTreeMap::new();
 
-                        map2.entry(key).or_insert(Vec::new()).push(val2);
+        let mut timer = StageTimer::new("HashJoin", self.scope().index());
+        self.unary_frontier(exchange, "HashJoin", move |_, _| { move |input, output| {
+            input.for_each(|cap, data| timer.time(data.len(), || {
+                data.swap(&mut vector);
+                let mut session = output.session(&cap);
+                for (generation, lr) in vector.drain(..) {
+                    let messages = pending.entry(lr.epoch).or_insert_with(HashMap::new);
+                    if lr.event_type == Sent {
+                        let key = (generation, Some(lr.local_worker), lr.remote_worker, lr.correlator_id, lr.channel_id);
+                        let messages = messages.entry(key).or_insert_with(PendingMessages::default);
+                        for received in messages.received.iter() {
+                            session.give((lr.clone(), received.clone(), cap.time().clone()));
+                        }
+                        messages.sent.push(lr);
+                    } else {
+                        // ControlMessage sends are broadcasts; they have no receiver.
+                        // lr.remote_worker is None for them, so here, it has to be, too.
+                        let receiver = if lr.activity_type == ControlMessage {
+                            None
+                        } else {
+                            Some(lr.local_worker)
+                        };
+                        let key = (generation, lr.remote_worker, receiver, lr.correlator_id, lr.channel_id);
+                        let messages = messages.entry(key).or_insert_with(PendingMessages::default);
+                        for sent in messages.sent.iter() {
+                            session.give((sent.clone(), lr.clone(), cap.time().clone()));
+                        }
+                        messages.received.push(lr);
                     }
-                });
+                }
+            }));
+
+            // records of epoch `e` arrive at `Pair(e, _)`
+            let frontier = input.frontier().frontier();
+            while let Some(epoch) = pending.keys().next().cloned() {
+                if frontier.iter().any(|t| t.first <= epoch) {
+                    break;
+                }
+                pending.remove(&epoch);
             }
-        })
+        }})
     }
 }
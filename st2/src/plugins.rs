@@ -0,0 +1,168 @@
+//! Analyses contributed by other crates, run by `st2 analyze --analyses`.
+//!
+//! An `Analysis` turns every completed epoch (cf. `hooks::EpochContext`) into
+//! named collections of metric values. Crates contribute analyses by submitting
+//! a `Plugin` to the registry; every plugin linked into a binary is discovered:
+//!
+//! ```ignore
+//! use st2::plugins::{Analysis, MetricCollection, Plugin};
+//!
+//! struct Idle;
+//!
+//! impl Analysis for Idle {
+//!     fn analyze(&mut self, epoch: &st2::hooks::EpochContext) -> Vec<MetricCollection> {
+//!         // ...
+//!     }
+//! }
+//!
+//! st2::plugins::inventory::submit! {
+//!     Plugin { name: "idle", description: "Idle time per worker", create: || Box::new(Idle) }
+//! }
+//! ```
+//!
+//! To make the CLI discover a plugin, add its crate as a dependency of `st2`
+//! and link it from `main.rs` (`extern crate ...;`). Plugins keep experimental
+//! algorithms out of the core: they only see completed epochs and can't change
+//! how PAGs are constructed.
+
+use crate::pag::PagEdge;
+use crate::hooks::EpochContext;
+use crate::store::Labels;
+
+use std::collections::BTreeMap;
+
+use st2_logformat::ActivityType;
+
+use crate::STError;
+
+/// Re-exported for `inventory::submit!`, so plugins don't need to depend on a matching version
+pub use inventory;
+
+/// Values of a metric, one per series (cf. `store::Sample`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricCollection {
+    /// The metric, e.g. `operator_busy_ns`
+    pub name: String,
+    /// Labels of every series and its value
+    pub values: Vec<(Labels, f64)>,
+}
+
+/// A per-epoch analysis
+pub trait Analysis: Send {
+    /// Analyzes a completed epoch. Epochs are analyzed in order, so analyses may
+    /// keep state across epochs.
+    fn analyze(&mut self, epoch: &EpochContext) -> Vec<MetricCollection>;
+}
+
+/// A registered analysis
+pub struct Plugin {
+    /// Name of the analysis in `--analyses`
+    pub name: &'static str,
+    /// One-line description, shown by `st2 analyze --list`
+    pub description: &'static str,
+    /// Creates an instance of the analysis
+    pub create: fn() -> Box<dyn Analysis>,
+}
+
+inventory::collect!(Plugin);
+
+/// All analyses ST2 ships with
+static BUILTIN: &[Plugin] = &[
+    Plugin {
+        name: "operator_busy",
+        description: "Time every operator spent working (label `operator`), off and on the critical path",
+        create: || Box::new(OperatorBusy),
+    },
+    Plugin {
+        name: "message_volume",
+        description: "Number and records of data messages between pairs of workers (labels `from`, `to`)",
+        create: || Box::new(MessageVolume),
+    },
+];
+
+/// The built-in and submitted analyses, by name
+pub fn registry() -> BTreeMap<&'static str, &'static Plugin> {
+    BUILTIN.iter()
+        .chain(inventory::iter::<Plugin>)
+        .map(|plugin| (plugin.name, plugin))
+        .collect()
+}
+
+/// Creates the analyses called `names`.
+pub fn create(names: &[String]) -> Result<Vec<(&'static str, Box<dyn Analysis>)>, STError> {
+    let registry = registry();
+    names.iter()
+        .map(|name| match registry.get(name.as_str()) {
+            Some(plugin) => Ok((plugin.name, (plugin.create)())),
+            None => Err(STError::Config(format!("unknown analysis {} (expected one of {})",
+                                        name, registry.keys().cloned().collect::<Vec<_>>().join(", ")))),
+        })
+        .collect()
+}
+
+fn label(key: &str, value: String) -> Labels {
+    let mut labels = Labels::new();
+    labels.insert(key.to_string(), value);
+    labels
+}
+
+fn operator(epoch: &EpochContext, edge: &PagEdge) -> Option<String> {
+    edge.operator_id.map(|id| crate::labels::name(id, epoch.operator_names).unwrap_or_else(|| id.to_string()))
+}
+
+/// cf. `BUILTIN`
+struct OperatorBusy;
+
+impl Analysis for OperatorBusy {
+    fn analyze(&mut self, epoch: &EpochContext) -> Vec<MetricCollection> {
+        let mut busy: BTreeMap<String, u64> = BTreeMap::new();
+        for edge in epoch.edges.iter().filter(|edge| edge.edge_type == ActivityType::Processing) {
+            if let Some(operator) = operator(epoch, edge) {
+                *busy.entry(operator).or_insert(0) += edge.duration();
+            }
+        }
+        let mut critical: BTreeMap<String, u64> = BTreeMap::new();
+        for edge in epoch.critical_path.iter().filter(|edge| edge.edge_type == ActivityType::Processing) {
+            if let Some(operator) = operator(epoch, edge) {
+                *critical.entry(operator).or_insert(0) += edge.duration();
+            }
+        }
+
+        let collection = |name: &str, values: BTreeMap<String, u64>| MetricCollection {
+            name: name.to_string(),
+            values: values.into_iter().map(|(operator, ns)| (label("operator", operator), ns as f64)).collect(),
+        };
+        vec![collection("operator_busy_ns", busy), collection("operator_busy_critical_ns", critical)]
+    }
+}
+
+/// cf. `BUILTIN`
+struct MessageVolume;
+
+impl Analysis for MessageVolume {
+    fn analyze(&mut self, epoch: &EpochContext) -> Vec<MetricCollection> {
+        // (from, to) -> (messages, records)
+        let mut volume: BTreeMap<(u64, u64), (u64, u64)> = BTreeMap::new();
+        for edge in epoch.edges.iter().filter(|edge| edge.edge_type == ActivityType::DataMessage) {
+            let entry = volume.entry((edge.source.worker_id, edge.destination.worker_id)).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += edge.length.unwrap_or(0) as u64;
+        }
+
+        let labels = |from: u64, to: u64| {
+            let mut labels = label("from", from.to_string());
+            labels.insert("to".to_string(), to.to_string());
+            labels
+        };
+        vec![
+            MetricCollection {
+                name: "messages".to_string(),
+                values: volume.iter().map(|((from, to), (messages, _))| (labels(*from, *to), *messages as f64)).collect(),
+            },
+            MetricCollection {
+                name: "message_records".to_string(),
+                values: volume.iter().map(|((from, to), (_, records))| (labels(*from, *to), *records as f64)).collect(),
+            },
+        ]
+    }
+}
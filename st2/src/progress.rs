@@ -0,0 +1,110 @@
+//! Progress reports on stderr, so long offline analyses don't appear to hang.
+
+use std::time::{Duration, Instant};
+
+use st2_timely::prefetch::{self, FileProgress};
+use st2_timely::replay_throttled::{ProgressSnapshot, PROGRESS};
+
+/// Reports the progress of this process's replay (cf. `replay_throttled::PROGRESS`)
+/// on stderr every `interval`, until replay has finished. The report includes the
+/// replayed bytes, events, and epochs, the current rate, and, unless the inputs
+/// are still being written (`follow`), an ETA. Files read by several I/O threads
+/// (cf. `st2_timely::prefetch`) are reported one by one, too.
+pub fn report(interval: Duration, follow: bool) {
+    std::thread::spawn(move || {
+        let mut last = (Instant::now(), PROGRESS.snapshot());
+        loop {
+            std::thread::sleep(interval);
+            let now = (Instant::now(), PROGRESS.snapshot());
+            if now.1.done {
+                return;
+            }
+            if now.1.started {
+                eprintln!("Progress: {}", line(&last, &now, follow));
+                let files = prefetch::files();
+                if files.len() > 1 {
+                    for file in files.iter() {
+                        eprintln!("  {}", file_line(file, follow));
+                    }
+                }
+            }
+            last = now;
+        }
+    });
+}
+
+/// A summary of the session, after `elapsed`: how many epochs replay completed,
+/// the events and bytes it consumed, and whether it was stopped in the middle
+/// of an epoch, which is then missing from the results.
+pub fn summary(elapsed: Duration, interrupted: bool) -> String {
+    let progress = PROGRESS.snapshot();
+    let mut line = format!("Session {} after {}", if interrupted { "interrupted" } else { "finished" }, duration(elapsed.as_secs_f64()));
+    if progress.started {
+        // the epochs before the frontier are complete, unless cut short
+        let completed = progress.truncated.unwrap_or(progress.epoch).min(progress.epoch);
+        line.push_str(&format!(": {} epochs completed, {} events ({}) replayed",
+                               completed, progress.events, bytes(progress.bytes as f64)));
+        if let Some(epoch) = progress.truncated {
+            line.push_str(&format!("; epoch {} was cut short and left out of the results", epoch));
+        }
+    }
+    line.push('.');
+    line
+}
+
+/// A progress report of the replay between `last` and `now`
+fn line(last: &(Instant, ProgressSnapshot), now: &(Instant, ProgressSnapshot), follow: bool) -> String {
+    let (then, before) = last;
+    let (at, progress) = now;
+
+    let elapsed = at.duration_since(*then).as_secs_f64();
+    let rate = if elapsed > 0.0 { progress.bytes.saturating_sub(before.bytes) as f64 / elapsed } else { 0.0 };
+
+    let mut line = format!("{}", bytes(progress.bytes as f64));
+    if !follow && progress.input_bytes > 0 {
+        let share = (progress.bytes as f64 / progress.input_bytes as f64).min(1.0);
+        line.push_str(&format!(" of {} ({:.1}%)", bytes(progress.input_bytes as f64), share * 100.0));
+    }
+    line.push_str(&format!(", {} events, epoch {}, {}/s", progress.events, progress.epoch, bytes(rate)));
+    if !follow && progress.input_bytes > progress.bytes && rate > 0.0 {
+        let eta = (progress.input_bytes - progress.bytes) as f64 / rate;
+        line.push_str(&format!(", ETA {}", duration(eta)));
+    }
+    line
+}
+
+/// A progress report of the I/O thread reading `file`
+fn file_line(file: &FileProgress, follow: bool) -> String {
+    let mut line = format!("{}: {}", file.name, bytes(file.bytes() as f64));
+    if !follow && file.size > 0 {
+        let share = (file.bytes() as f64 / file.size as f64).min(1.0);
+        line.push_str(&format!(" of {} ({:.1}%)", bytes(file.size as f64), share * 100.0));
+    }
+    line.push_str(&format!(", {} events", file.events()));
+    line
+}
+
+/// `n` bytes in binary units, e.g. `1.5 MiB`
+pub(crate) fn bytes(n: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut n = n;
+    let mut unit = 0;
+    while n >= 1024.0 && unit < UNITS.len() - 1 {
+        n /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{:.0} {}", n, UNITS[unit])
+    } else {
+        format!("{:.1} {}", n, UNITS[unit])
+    }
+}
+
+fn duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
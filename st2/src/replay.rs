@@ -0,0 +1,133 @@
+//! Readers of the source computation's events, from `*.dump` files (offline)
+//! or TCP streams (online). Once `enable_mmap` was called, files are replayed
+//! from memory maps (cf. `st2_timely::mmap`) instead of being read; once
+//! `enable_prefetch` was called, every file is read and decoded ahead of replay
+//! on an I/O thread of its own (cf. `st2_timely::prefetch`). Once
+//! `enable_faults` was called, faults are injected into every source (cf.
+//! `st2_timely::faults`). A `Recorder` writes the events it replays to a
+//! `*.dump` file, e.g. to analyze a live session again offline.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use timely::dataflow::operators::capture::{EventReader, EventWriter};
+use timely::dataflow::operators::capture::event::{EventIterator, EventPusher};
+
+use once_cell::sync::OnceCell;
+
+use tdiag_connect::receive as connect;
+use tdiag_connect::receive::ReplaySource;
+
+use st2_logformat::pair::Pair;
+
+use st2_timely::connect::{CompEvent, TraceEvent, TraceReplayer};
+use st2_timely::faults::{Faults, FaultyReplayer};
+use st2_timely::mmap::MmapReplayer;
+use st2_timely::prefetch::Prefetcher;
+
+use crate::STError;
+
+/// Whether files are replayed from memory maps, cf. `enable_mmap`
+static MMAP: AtomicBool = AtomicBool::new(false);
+/// How many batches I/O threads decode ahead of replay, cf. `enable_prefetch`
+static PREFETCH: AtomicUsize = AtomicUsize::new(0);
+/// Faults injected into every source, cf. `enable_faults`
+static FAULTS: OnceCell<Faults> = OnceCell::new();
+
+/// Makes `make_readers` map files instead of reading them (`--mmap`).
+pub fn enable_mmap() {
+    MMAP.store(true, Ordering::Release);
+}
+
+/// Makes `make_readers` read every file on an I/O thread that decodes up to
+/// `batches` batches of events ahead of replay (`--prefetch`). With `0`, the
+/// replay workers read the files themselves.
+pub fn enable_prefetch(batches: usize) {
+    PREFETCH.store(batches, Ordering::Release);
+}
+
+/// Makes `make_readers` inject `faults` into every source (`--inject-faults`).
+pub fn enable_faults(faults: Faults) {
+    FAULTS.set(faults).expect("faults already enabled");
+}
+
+/// Readers of worker `index` (of `peers` local workers) for `source`: every
+/// worker replays every `peers`th file or stream.
+pub fn make_readers(source: ReplaySource, index: usize, peers: usize) -> Result<Vec<TraceReplayer<impl Read + 'static>>, STError> {
+    let readers = open_readers(source, index, peers)?;
+    Ok(match FAULTS.get() {
+        Some(faults) => readers.into_iter()
+            .enumerate()
+            .map(|(i, reader)| {
+                let source = (i * peers + index) as u64;
+                let name = format!("source {}", source);
+                TraceReplayer::Faulty(Box::new(FaultyReplayer::new(reader, *faults, name, source)))
+            })
+            .collect(),
+        None => readers,
+    })
+}
+
+/// Readers of worker `index` for `source`, cf. `make_readers`
+fn open_readers(source: ReplaySource, index: usize, peers: usize) -> Result<Vec<TraceReplayer<impl Read + 'static>>, STError> {
+    let mmap = MMAP.load(Ordering::Acquire);
+    let prefetch = PREFETCH.load(Ordering::Acquire);
+    match source {
+        ReplaySource::Files(ref files) if mmap || prefetch > 0 => {
+            let mut files = files.lock().expect("replay files poisoned");
+            files.iter_mut()
+                .enumerate()
+                .filter(|(idx, _)| idx % peers == index)
+                .filter_map(|(_, path)| path.take())
+                .map(|path| if prefetch == 0 {
+                    map(&path).map(TraceReplayer::Mapped)
+                } else {
+                    let size = std::fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
+                    let replayer = if mmap {
+                        TraceReplayer::Mapped(map(&path)?)
+                    } else {
+                        let file = File::open(&path).map_err(|e| STError::io(format!("couldn't open {}", path.display()), e))?;
+                        TraceReplayer::Stream(EventReader::new(file))
+                    };
+                    Prefetcher::spawn(path.display().to_string(), size, replayer, prefetch)
+                        .map(TraceReplayer::Prefetched)
+                        .map_err(|e| STError::io(format!("couldn't spawn an I/O thread for {}", path.display()), e))
+                })
+                .collect()
+        }
+        source => Ok(connect::make_readers(source, index, peers)?
+            .into_iter()
+            .map(TraceReplayer::Stream)
+            .collect()),
+    }
+}
+
+/// Maps the file at `path`, cf. `MmapReplayer`
+fn map(path: &Path) -> Result<MmapReplayer, STError> {
+    MmapReplayer::open(path).map_err(|e| STError::io(format!("couldn't map {}", path.display()), e))
+}
+
+/// Replays the events of `I`, writing them to a `*.dump` file on the way
+pub struct Recorder<I> {
+    replayer: I,
+    writer: EventWriter<Pair<u64, Duration>, CompEvent, File>,
+}
+
+impl<I> Recorder<I> {
+    /// Records the events of `replayer` to `path`
+    pub fn create(replayer: I, path: &Path) -> Result<Self, STError> {
+        let file = File::create(path).map_err(|e| STError::io(format!("couldn't create {}", path.display()), e))?;
+        Ok(Recorder { replayer, writer: EventWriter::new(file) })
+    }
+}
+
+impl<I: EventIterator<Pair<u64, Duration>, CompEvent>> EventIterator<Pair<u64, Duration>, CompEvent> for Recorder<I> {
+    fn next(&mut self) -> Option<&TraceEvent> {
+        let event = self.replayer.next()?;
+        self.writer.push(event.clone());
+        Some(event)
+    }
+}
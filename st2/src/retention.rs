@@ -0,0 +1,125 @@
+//! Retention of what long-lived deployments keep on disk: recorded trace files,
+//! saved PAG snapshots, and history databases.
+//!
+//! Once `enable`d, commands hand their files to a background compactor (`spawn`)
+//! that applies the `Retention` right away and then every `INTERVAL`: it deletes
+//! files older than `Retention::max_age`, then the oldest remaining files until
+//! the rest fits into `Retention::max_bytes`. History databases delete their
+//! oldest rows instead and are vacuumed to give the space back (cf.
+//! `history::compact`).
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use once_cell::sync::{Lazy, OnceCell};
+
+use crate::STError;
+
+/// How often compactors apply the retention
+pub const INTERVAL: Duration = Duration::from_secs(60);
+
+/// How much to keep on disk
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Delete the oldest data once there is more than this many bytes of it
+    pub max_bytes: Option<u64>,
+    /// Delete data once it's older than this
+    pub max_age: Option<Duration>,
+}
+
+/// The enabled retention, cf. `enable`
+static RETENTION: OnceCell<Retention> = OnceCell::new();
+
+/// What compactors are running for, so every store gets compacted only once
+static COMPACTING: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Makes commands apply `retention` to the files they write.
+pub fn enable(retention: Retention) {
+    if retention != Retention::default() {
+        RETENTION.set(retention).expect("retention already enabled");
+    }
+}
+
+/// The enabled retention, if any
+pub fn enabled() -> Option<Retention> {
+    RETENTION.get().copied()
+}
+
+/// Runs `compact` with the enabled retention on a background thread, now and
+/// then every `INTERVAL`, for as long as the process runs. `compact` returns how
+/// much it deleted; `what` describes the compacted store in logs. Does nothing if
+/// no retention is enabled or `what` is compacted already.
+pub fn spawn<F>(what: String, mut compact: F)
+where F: FnMut(&Retention) -> Result<usize, STError> + Send + 'static {
+    let retention = match enabled() {
+        Some(retention) => retention,
+        None => return,
+    };
+    if !COMPACTING.lock().expect("compactors poisoned").insert(what.clone()) {
+        return;
+    }
+
+    std::thread::Builder::new()
+        .name("st2-compactor".to_string())
+        .spawn(move || loop {
+            match compact(&retention) {
+                Ok(0) => {}
+                Ok(deleted) => tracing::info!(deleted = deleted as u64, "compacted {}", what),
+                Err(e) => warn!("couldn't compact {}: {}", what, e),
+            }
+            std::thread::sleep(INTERVAL);
+        })
+        .expect("couldn't spawn compactor");
+}
+
+/// The files in `dir` whose names satisfy `matches`
+pub fn files_in(dir: &Path, matches: impl Fn(&str) -> bool) -> Result<Vec<PathBuf>, STError> {
+    let entries = fs::read_dir(dir).map_err(|e| STError::io(format!("couldn't list {}", dir.display()), e))?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| STError::io(format!("couldn't list {}", dir.display()), e))?;
+        let is_file = entry.file_type().map(|file_type| file_type.is_file()).unwrap_or(false);
+        if is_file && entry.file_name().to_str().map_or(false, |name| matches(name)) {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+impl Retention {
+    /// Deletes those of `files` that aren't retained, oldest (by modification
+    /// time) first. Returns how many files it deleted.
+    pub fn apply(&self, files: Vec<PathBuf>) -> Result<usize, STError> {
+        let now = SystemTime::now();
+        // files that vanished since they were listed don't count
+        let mut files: Vec<_> = files.into_iter()
+            .filter_map(|path| {
+                let metadata = fs::metadata(&path).ok()?;
+                Some((metadata.modified().unwrap_or(now), metadata.len(), path))
+            })
+            .collect();
+        files.sort();
+
+        let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+        let mut deleted = 0;
+        for (modified, len, path) in files {
+            let expired = self.max_age.map_or(false, |max_age| now.duration_since(modified).map_or(false, |age| age > max_age));
+            let over = self.max_bytes.map_or(false, |max_bytes| total > max_bytes);
+            if !expired && !over {
+                // all younger files are retained as well
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => deleted += 1,
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(STError::io(format!("couldn't delete {}", path.display()), e)),
+            }
+            total -= len;
+        }
+        Ok(deleted)
+    }
+}
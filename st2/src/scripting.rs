@@ -0,0 +1,261 @@
+//! Custom metrics and alert predicates, scripted in [Rhai](https://rhai.rs) in
+//! the `scripts` table of a `--config` file, so new signals don't require
+//! recompiling ST2:
+//!
+//! ```toml
+//! # summed over the epoch's edges
+//! [[scripts.metric]]
+//! name = "map_processing_ns"
+//! edge = 'if edge.operator == "Map" && edge.activity == "Processing" { edge.duration_ns } else { 0 }'
+//!
+//! # computed from the epoch's metrics, including those defined above
+//! [[scripts.metric]]
+//! name = "map_share"
+//! epoch = 'metrics.map_processing_ns / metrics.epoch_latency_ns'
+//!
+//! [[scripts.alert]]
+//! name = "map_dominates"
+//! when = 'metrics.map_share > 0.5 && operators.Map > 10_000_000.0'
+//! message = "Map dominates the epoch"
+//! ```
+//!
+//! Edge scripts see `edge`, with the fields `epoch`, `worker`, `dst_worker`,
+//! `operator` (its label or name, or id if unnamed), `operator_id`, `team`
+//! (cf. `labels`), `activity`, `traverse`, `start_ns`, `end_ns`, `duration_ns`,
+//! and `records`; missing values are `()`. They return a number (or a boolean, counted as 1 or 0).
+//! Epoch and alert scripts see `epoch`, `edges` (their number), `metrics` (the
+//! unlabeled `store::METRICS` and the metrics scripted so far), and the maps
+//! `operators`, `teams`, `activities`, and `workers` of `operator_critical_path_ns`,
+//! `team_critical_path_ns`, `activity_critical_path_ns`, and `worker_busy_ns`; all metric values are
+//! floats. Once the scripts are `install`ed, scripted metrics are part of every
+//! epoch's samples (cf. `store::epoch_samples`) and scripted alerts fire in
+//! `st2 alerts`.
+//!
+//! Edge scripts run for every edge, so they should stay simple. Scripts that
+//! fail, e.g. by running too long, are logged and skipped for the epoch.
+
+use crate::pag::PagEdge;
+use crate::store::Sample;
+
+use std::collections::BTreeMap;
+
+use once_cell::sync::OnceCell;
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use toml::Value;
+
+use crate::STError;
+
+/// Maximum number of operations per script evaluation, against runaway scripts
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// The installed scripts, cf. `install`
+static SCRIPTS: OnceCell<Scripts> = OnceCell::new();
+
+/// What a metric script is evaluated for
+enum Kind {
+    Edge,
+    Epoch,
+}
+
+struct MetricScript {
+    /// Leaked once per config, as `Sample` names are static
+    name: &'static str,
+    kind: Kind,
+    ast: AST,
+}
+
+struct AlertScript {
+    name: String,
+    message: Option<String>,
+    ast: AST,
+}
+
+/// Compiled scripts of a config file
+pub struct Scripts {
+    engine: Engine,
+    metrics: Vec<MetricScript>,
+    alerts: Vec<AlertScript>,
+}
+
+impl Scripts {
+    /// Compiles the `scripts` table of a config file.
+    pub fn compile(table: &toml::value::Table) -> Result<Self, STError> {
+        let invalid = |message: String| STError::Config(format!("Invalid --config: scripts: {}", message));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        let mut metrics = Vec::new();
+        let mut alerts = Vec::new();
+        for (key, value) in table {
+            let entries = match value {
+                Value::Array(entries) if key == "metric" || key == "alert" => entries,
+                _ => return Err(invalid(format!("unknown key {} (expected [[scripts.metric]] or [[scripts.alert]])", key))),
+            };
+            for entry in entries {
+                let string = |field: &str| entry.get(field).and_then(|value| value.as_str());
+                let name = string("name").ok_or_else(|| invalid(format!("a [[scripts.{}]] needs a name", key)))?;
+                let compile = |source: &str| engine.compile_expression(source)
+                    .map_err(|e| invalid(format!("{}: {}", name, e)));
+                if key == "metric" {
+                    let (kind, ast) = match (string("edge"), string("epoch")) {
+                        (Some(source), None) => (Kind::Edge, compile(source)?),
+                        (None, Some(source)) => (Kind::Epoch, compile(source)?),
+                        _ => return Err(invalid(format!("metric {} needs either an edge or an epoch script", name))),
+                    };
+                    let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+                    metrics.push(MetricScript { name, kind, ast });
+                } else {
+                    let ast = compile(string("when").ok_or_else(|| invalid(format!("alert {} needs a `when` script", name)))?)?;
+                    alerts.push(AlertScript { name: name.to_string(), message: string("message").map(|m| m.to_string()), ast });
+                }
+            }
+        }
+        Ok(Scripts { engine, metrics, alerts })
+    }
+
+    /// Names of the scripted metrics
+    pub fn metric_names(&self) -> Vec<&'static str> {
+        self.metrics.iter().map(|metric| metric.name).collect()
+    }
+
+    /// Whether any alerts are scripted
+    pub fn has_alerts(&self) -> bool {
+        !self.alerts.is_empty()
+    }
+
+    /// The scripted metrics of `epoch` with the PAG `edges`, given its `samples`
+    /// of `store::METRICS`. Operators are named by `operator_names`, or their id.
+    pub fn metrics(&self, epoch: u64, edges: &[PagEdge], samples: &[Sample], operator_names: &BTreeMap<u64, String>) -> Vec<Sample> {
+        let timestamp = samples.first().map(|sample| sample.timestamp).unwrap_or(0);
+        let mut scope = epoch_scope(epoch, edges, samples);
+        let mut scripted = Vec::new();
+        for metric in self.metrics.iter() {
+            let value = match metric.kind {
+                Kind::Edge => {
+                    let mut sum = 0.0;
+                    for edge in edges {
+                        let mut scope = Scope::new();
+                        scope.push("edge", edge_map(edge, operator_names));
+                        match self.engine.eval_ast_with_scope::<Dynamic>(&mut scope, &metric.ast).map_err(|e| e.to_string()).and_then(number) {
+                            Ok(value) => sum += value,
+                            Err(e) => {
+                                error!("metric script {} failed for an edge of epoch {}: {}", metric.name, epoch, e);
+                                break;
+                            }
+                        }
+                    }
+                    sum
+                }
+                Kind::Epoch => match self.engine.eval_ast_with_scope::<Dynamic>(&mut scope, &metric.ast).map_err(|e| e.to_string()).and_then(number) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        error!("metric script {} failed for epoch {}: {}", metric.name, epoch, e);
+                        continue;
+                    }
+                },
+            };
+
+            // later scripts see this metric
+            if let Some(metrics) = scope.get_value::<Map>("metrics") {
+                let mut metrics = metrics;
+                metrics.insert(metric.name.into(), Dynamic::from(value));
+                scope.set_value("metrics", metrics);
+            }
+            scripted.push(Sample { name: metric.name, labels: Default::default(), epoch, timestamp, value });
+        }
+        scripted
+    }
+
+    /// The scripted alerts that fire for `epoch` with the PAG `edges` and its
+    /// `samples` (including scripted metrics), as their name and message
+    pub fn alerts(&self, epoch: u64, edges: &[PagEdge], samples: &[Sample]) -> Vec<(&str, &str)> {
+        let mut scope = epoch_scope(epoch, edges, samples);
+        self.alerts.iter()
+            .filter(|alert| match self.engine.eval_ast_with_scope::<bool>(&mut scope, &alert.ast) {
+                Ok(fires) => fires,
+                Err(e) => {
+                    error!("alert script {} failed for epoch {}: {}", alert.name, epoch, e);
+                    false
+                }
+            })
+            .map(|alert| (alert.name.as_str(), alert.message.as_deref().unwrap_or("scripted alert fired")))
+            .collect()
+    }
+}
+
+/// Makes `scripts` part of every epoch's samples and alerts. Scripts can only be
+/// installed once per process.
+pub fn install(scripts: Scripts) -> Result<(), STError> {
+    SCRIPTS.set(scripts).map_err(|_| STError::Analysis("scripts are already installed".to_string()))
+}
+
+/// The installed scripts, if any
+pub fn installed() -> Option<&'static Scripts> {
+    SCRIPTS.get()
+}
+
+/// A script's result as a number
+fn number(value: Dynamic) -> Result<f64, String> {
+    if let Ok(int) = value.as_int() {
+        Ok(int as f64)
+    } else if let Ok(float) = value.as_float() {
+        Ok(float)
+    } else if let Ok(boolean) = value.as_bool() {
+        Ok(if boolean { 1.0 } else { 0.0 })
+    } else {
+        Err(format!("expected a number, got {}", value.type_name()))
+    }
+}
+
+fn edge_map(edge: &PagEdge, operator_names: &BTreeMap<u64, String>) -> Map {
+    let optional = |value: Option<u64>| value.map(|v| Dynamic::from(v as i64)).unwrap_or(Dynamic::UNIT);
+    let mut map = Map::new();
+    map.insert("epoch".into(), Dynamic::from(edge.source.epoch as i64));
+    map.insert("worker".into(), Dynamic::from(edge.source.worker_id as i64));
+    map.insert("dst_worker".into(), Dynamic::from(edge.destination.worker_id as i64));
+    map.insert("operator".into(), edge.operator_id
+               .map(|id| Dynamic::from(crate::labels::name(id, operator_names).unwrap_or_else(|| id.to_string())))
+               .unwrap_or(Dynamic::UNIT));
+    map.insert("operator_id".into(), optional(edge.operator_id));
+    map.insert("team".into(), edge.operator_id
+               .and_then(|id| crate::labels::of(id)).and_then(|label| label.team)
+               .map(Dynamic::from)
+               .unwrap_or(Dynamic::UNIT));
+    map.insert("activity".into(), Dynamic::from(format!("{:?}", edge.edge_type)));
+    map.insert("traverse".into(), Dynamic::from(format!("{:?}", edge.traverse)));
+    map.insert("start_ns".into(), Dynamic::from(edge.source.timestamp.as_nanos() as i64));
+    map.insert("end_ns".into(), Dynamic::from(edge.destination.timestamp.as_nanos() as i64));
+    map.insert("duration_ns".into(), Dynamic::from(edge.duration() as i64));
+    map.insert("records".into(), optional(edge.length.map(|length| length as u64)));
+    map
+}
+
+/// The variables of epoch and alert scripts
+fn epoch_scope(epoch: u64, edges: &[PagEdge], samples: &[Sample]) -> Scope<'static> {
+    let mut metrics = Map::new();
+    let mut operators = Map::new();
+    let mut teams = Map::new();
+    let mut activities = Map::new();
+    let mut workers = Map::new();
+    for sample in samples {
+        let value = Dynamic::from(sample.value);
+        match (sample.name, sample.labels.iter().next()) {
+            (name, None) => { metrics.insert(name.into(), value); }
+            ("operator_critical_path_ns", Some((_, operator))) => { operators.insert(operator.as_str().into(), value); }
+            ("team_critical_path_ns", Some((_, team))) => { teams.insert(team.as_str().into(), value); }
+            ("activity_critical_path_ns", Some((_, activity))) => { activities.insert(activity.as_str().into(), value); }
+            ("worker_busy_ns", Some((_, worker))) => { workers.insert(worker.as_str().into(), value); }
+            _ => (),
+        }
+    }
+
+    let mut scope = Scope::new();
+    scope.push("epoch", epoch as i64);
+    scope.push("edges", edges.len() as i64);
+    scope.push("metrics", metrics);
+    scope.push("operators", operators);
+    scope.push("teams", teams);
+    scope.push("activities", activities);
+    scope.push("workers", workers);
+    scope
+}
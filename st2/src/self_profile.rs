@@ -0,0 +1,280 @@
+//! Self-profiling: ST2 analyzing itself, to tune large deployments.
+//!
+//! Once `enable`d, every ST2 worker that is `attach`ed logs its own timely events
+//! as a PAG trace to `<dir>/<worker>.dump`, with an epoch per `EPOCH` of its run
+//! time. After the command, `report` feeds this trace into a second, single-worker
+//! PAG pipeline and prints where ST2 spent its time: per operator of the analysis
+//! dataflow, per activity on its critical paths, and per worker. The trace and
+//! the names of ST2's operators (`<dir>/operators.toml`, a `--config` file) are
+//! kept, so any command can analyze them further.
+
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::commands::alerts::EpochStats;
+use crate::commands::metrics::operator_label;
+use crate::store::completed_epochs;
+
+use timely::communication::allocator::Generic;
+use timely::dataflow::Stream;
+use timely::dataflow::operators::capture::EventWriter;
+use timely::logging::TimelyEvent;
+use timely::worker::Worker;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use serde_json::json;
+
+use st2_logformat::ActivityType;
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive as connect;
+use tdiag_connect::receive::ReplaySource;
+use st2_timely::connect::{DataflowEvents, PAGLogger, TcpStreamOrFile};
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::filter::Filter;
+
+use crate::{OutputFormat, STError};
+
+/// Length of an epoch of the self-profile
+pub const EPOCH: Duration = Duration::from_secs(1);
+
+/// Number of operators in the report
+const TOP_OPERATORS: usize = 15;
+
+/// The enabled self-profile, cf. `enable`
+static PROFILE: OnceCell<Profile> = OnceCell::new();
+
+struct Profile {
+    dir: PathBuf,
+    /// Indices of the workers that log to `dir`
+    workers: Mutex<Vec<usize>>,
+    /// Names of ST2's operators, by id
+    names: Mutex<BTreeMap<u64, String>>,
+}
+
+/// Makes `attach`ed workers log their own events to trace files in `dir`.
+/// Self-profiling can only be enabled once per process.
+pub fn enable(dir: &Path) -> Result<(), STError> {
+    std::fs::create_dir_all(dir).map_err(|e| STError::io(format!("Couldn't create {}", dir.display()), e))?;
+    let profile = Profile { dir: dir.to_path_buf(), workers: Mutex::new(Vec::new()), names: Mutex::new(BTreeMap::new()) };
+    PROFILE.set(profile).map_err(|_| STError::Analysis("self-profiling is already enabled".to_string()))
+}
+
+/// Logs the events of `worker`, an ST2 worker, if self-profiling is enabled.
+/// Like `st2_timely::connect::Adapter`, this has to be called before the
+/// worker's dataflows are built.
+pub fn attach(worker: &Worker<Generic>) {
+    let profile = match PROFILE.get() {
+        Some(profile) => profile,
+        None => return,
+    };
+
+    let path = profile.dir.join(format!("{}.dump", worker.index()));
+    let file = match File::create(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("couldn't self-profile worker {}: couldn't create {}: {}", worker.index(), path.display(), e);
+            return;
+        }
+    };
+    profile.workers.lock().unwrap().push(worker.index());
+
+    let writer = EventWriter::new(TcpStreamOrFile::File(file));
+    let mut logger = SelfLogger {
+        logger: Some(PAGLogger::with_writers(worker, vec![writer], 4096)),
+        index: worker.index(),
+        next_epoch: None,
+    };
+    worker
+        .log_register()
+        .insert::<TimelyEvent, _>("timely", move |_time, data| logger.publish(data, profile));
+}
+
+/// Logs a worker's events with an epoch per `EPOCH`
+struct SelfLogger {
+    /// `None` once logging stopped
+    logger: Option<PAGLogger>,
+    index: usize,
+    /// Start of the next epoch, once the worker's dataflows are built
+    next_epoch: Option<Duration>,
+}
+
+impl SelfLogger {
+    fn publish(&mut self, data: &mut Vec<(Duration, usize, TimelyEvent)>, profile: &Profile) {
+        if self.logger.is_none() {
+            return data.clear();
+        }
+
+        for (_t, _wid, event) in data.iter() {
+            if let TimelyEvent::Operates(e) = event {
+                // PAGs require the dataflow structure up front
+                if self.next_epoch.is_some() {
+                    warn!("stopped self-profiling worker {}: a dataflow was built after others ran", self.index);
+                    self.logger = None;
+                    return data.clear();
+                }
+                profile.names.lock().unwrap().insert(e.id as u64, e.name.clone());
+            }
+        }
+
+        let logger = self.logger.as_mut().expect("logging");
+        if let Some((t, _, _)) = data.first() {
+            match self.next_epoch {
+                Some(next) if *t >= next => {
+                    logger.tick_epoch();
+                    self.next_epoch = Some(*t + EPOCH);
+                }
+                None if data.iter().any(|(_, _, event)| if let TimelyEvent::Schedule(_) = event { true } else { false }) => {
+                    self.next_epoch = Some(*t + EPOCH);
+                }
+                _ => (),
+            }
+        }
+        logger.publish_batch(DataflowEvents::Timely(data));
+    }
+}
+
+impl Drop for SelfLogger {
+    fn drop(&mut self) {
+        // writes out the events of the last epoch
+        if let Some(logger) = self.logger.as_mut() {
+            logger.tick_epoch();
+        }
+    }
+}
+
+/// Where ST2 spent its time, collected at the single worker of `report`
+#[derive(Default)]
+struct Totals {
+    epochs: u64,
+    /// Busy time per operator
+    operators: BTreeMap<u64, u64>,
+    /// Critical path time per operator
+    critical_operators: BTreeMap<u64, u64>,
+    /// Critical path time per activity type
+    critical_activities: BTreeMap<String, u64>,
+    /// Busy time per worker
+    workers: BTreeMap<u64, u64>,
+}
+
+impl Totals {
+    fn add(&mut self, edges: &[PagEdge]) {
+        let stats = EpochStats::new(edges);
+        self.epochs += 1;
+        for edge in edges {
+            if let (Some(id), ActivityType::Processing) = (edge.operator_id, edge.edge_type) {
+                *self.operators.entry(id).or_insert(0) += edge.duration();
+            }
+        }
+        for edge in stats.critical_path.iter() {
+            if let Some(id) = edge.operator_id {
+                *self.critical_operators.entry(id).or_insert(0) += edge.duration();
+            }
+            *self.critical_activities.entry(format!("{:?}", edge.edge_type)).or_insert(0) += edge.duration();
+        }
+        for (worker, ns) in stats.busy {
+            *self.workers.entry(worker).or_insert(0) += ns;
+        }
+    }
+}
+
+/// Analyzes the trace ST2 logged of itself and prints where it spent its time.
+/// Does nothing if self-profiling isn't enabled.
+pub fn report(output_format: OutputFormat) -> Result<(), STError> {
+    let profile = match PROFILE.get() {
+        Some(profile) => profile,
+        None => return Ok(()),
+    };
+    let mut workers = profile.workers.lock().unwrap().clone();
+    if workers.is_empty() {
+        eprintln!("Nothing to self-profile: the command ran no ST2 workers");
+        return Ok(());
+    }
+    workers.sort();
+    let names = profile.names.lock().unwrap().clone();
+    write_names(&profile.dir, &names)?;
+
+    let files = workers.iter()
+        .map(|index| Some(profile.dir.join(format!("{}.dump", index))))
+        .collect::<Vec<_>>();
+    let replay_source = ReplaySource::Files(Arc::new(Mutex::new(files)));
+
+    let totals = Arc::new(Mutex::new(Totals::default()));
+    let collected = Arc::clone(&totals);
+    timely::execute(timely::Configuration::Thread, move |worker| {
+        let totals = Arc::clone(&collected);
+        let readers = connect::make_readers(replay_source.clone(), worker.index(), 1).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = pag::create_pag(scope, readers, 0, None, 1, ReplaySpeed::Unbounded, Filter::default());
+            completed_epochs(&pag, "SelfProfile", move |_epoch, edges, _ahead| totals.lock().unwrap().add(&edges));
+        });
+    })
+        .map_err(|x| STError::Analysis(format!("error in the self-profile's timely computation: {}", x)))?;
+
+    let totals = std::mem::replace(&mut *totals.lock().unwrap(), Totals::default());
+    print(&totals, &names, &profile.dir, output_format);
+    Ok(())
+}
+
+/// Writes ST2's operator names as `--config` file `<dir>/operators.toml`.
+fn write_names(dir: &Path, names: &BTreeMap<u64, String>) -> Result<(), STError> {
+    let table = names.iter()
+        .map(|(id, name)| (id.to_string(), toml::Value::String(name.clone())))
+        .collect::<toml::value::Table>();
+    let mut config = toml::value::Table::new();
+    config.insert("operator-names".to_string(), toml::Value::Table(table));
+    let path = dir.join("operators.toml");
+    let contents = toml::to_string(&toml::Value::Table(config)).expect("names serialize to TOML");
+    std::fs::write(&path, contents).map_err(|e| STError::io(format!("Couldn't write {}", path.display()), e))
+}
+
+fn print(totals: &Totals, names: &BTreeMap<u64, String>, dir: &Path, output_format: OutputFormat) {
+    let busy: u64 = totals.operators.values().sum();
+    let critical: u64 = totals.critical_activities.values().sum();
+    let share = |ns: u64, sum: u64| if sum == 0 { 0.0 } else { ns as f64 / sum as f64 * 100.0 };
+    let ms = |ns: u64| ns as f64 / 1_000_000.0;
+
+    let mut operators: Vec<_> = totals.operators.iter().map(|(id, ns)| (*id, *ns)).collect();
+    operators.sort_by_key(|(_, ns)| std::cmp::Reverse(*ns));
+    operators.truncate(TOP_OPERATORS);
+
+    match output_format {
+        OutputFormat::Text => {
+            println!("Self-profile of ST2 ({} epochs of {:?}, trace in {}):", totals.epochs, EPOCH, dir.display());
+            println!("\n{:<24}{:>14}{:>10}{:>18}{:>10}", "operator", "busy (ms)", "%", "critical (ms)", "%");
+            for (id, ns) in operators.iter() {
+                let critical_ns = totals.critical_operators.get(id).cloned().unwrap_or(0);
+                println!("{:<24}{:>14.3}{:>10.1}{:>18.3}{:>10.1}",
+                         operator_label(*id, names), ms(*ns), share(*ns, busy), ms(critical_ns), share(critical_ns, critical));
+            }
+            println!("\n{:<24}{:>18}{:>10}", "activity", "critical (ms)", "%");
+            for (activity, ns) in totals.critical_activities.iter() {
+                println!("{:<24}{:>18.3}{:>10.1}", activity, ms(*ns), share(*ns, critical));
+            }
+            println!("\n{:<24}{:>14}", "worker", "busy (ms)");
+            for (worker, ns) in totals.workers.iter() {
+                println!("{:<24}{:>14.3}", worker, ms(*ns));
+            }
+        }
+        OutputFormat::Json => {
+            let operators = operators.iter().map(|(id, ns)| json!({
+                "operator": operator_label(*id, names),
+                "busy_ns": ns,
+                "critical_path_ns": totals.critical_operators.get(id).cloned().unwrap_or(0),
+            })).collect::<Vec<_>>();
+            output_format.print("", json!({
+                "self_profile": dir,
+                "epochs": totals.epochs,
+                "operators": operators,
+                "critical_path_activities": totals.critical_activities,
+                "worker_busy_ns": totals.workers,
+            }));
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Source locations of operators, so hotspots point to code like in profilers.
+//!
+//! Programs can name their operators with their location, e.g.
+//! `Map@src/main.rs:42` or `Map (src/main.rs:42:13)`, or locations can be given
+//! in a `--labels` file (`source = "src/main.rs:42"`, cf. `labels`). Reports show
+//! operators with their location, and with `--source-url` link it, e.g. to
+//! `https://github.com/org/repo/blob/main/{file}#L{line}`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use once_cell::sync::OnceCell;
+
+/// The enabled `--source-url` template, cf. `enable_links`
+static URL_TEMPLATE: OnceCell<String> = OnceCell::new();
+
+/// A location in the monitored program's code
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SourceLocation {
+    /// Path of the file, e.g. `src/main.rs`
+    pub file: String,
+    /// Line in the file, from 1
+    pub line: u64,
+    /// Column in the line, from 1, if known
+    pub column: Option<u64>,
+}
+
+/// Makes `SourceLocation::url` fill in `template`, e.g.
+/// `https://github.com/org/repo/blob/main/{file}#L{line}`.
+pub fn enable_links(template: String) {
+    URL_TEMPLATE.set(template).expect("source links already enabled");
+}
+
+impl SourceLocation {
+    /// Link to the location, if `enable_links` was called
+    pub fn url(&self) -> Option<String> {
+        URL_TEMPLATE.get().map(|template| template
+            .replace("{file}", &self.file)
+            .replace("{line}", &self.line.to_string())
+            .replace("{column}", &self.column.unwrap_or(1).to_string()))
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.column {
+            Some(column) => write!(f, "{}:{}:{}", self.file, self.line, column),
+            None => write!(f, "{}:{}", self.file, self.line),
+        }
+    }
+}
+
+impl FromStr for SourceLocation {
+    type Err = String;
+
+    /// Parses `<file>:<line>` or `<file>:<line>:<column>`, where the file has an
+    /// extension, e.g. `src/main.rs:42`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{} is not a source location such as src/main.rs:42", s);
+        let parts: Vec<&str> = s.split(':').collect();
+        let number = |part: &str| part.parse::<u64>().ok().filter(|n| *n > 0);
+        let (file, line, column) = match parts.as_slice() {
+            [file @ .., line, column] if !file.is_empty() && number(line).is_some() && number(column).is_some() =>
+                (file.join(":"), number(line), number(column)),
+            [file @ .., line] if !file.is_empty() && number(line).is_some() => (file.join(":"), number(line), None),
+            _ => return Err(invalid()),
+        };
+        let has_extension = file.rsplit('/').next().and_then(|name| name.rfind('.').map(|dot| &name[dot + 1 ..]))
+            .map_or(false, |extension| !extension.is_empty() && extension.len() <= 5
+                && extension.chars().all(|c| c.is_ascii_alphanumeric()) && extension.chars().any(|c| c.is_ascii_alphabetic()));
+        if !has_extension {
+            return Err(invalid());
+        }
+        Ok(SourceLocation { file, line: line.expect("parsed line"), column })
+    }
+}
+
+/// Splits the source location (if any) off the operator name `name`, e.g.
+/// `Map@src/main.rs:42` into `Map` and `src/main.rs:42`. Names that are only a
+/// location are kept as they are.
+pub fn split(name: &str) -> (&str, Option<SourceLocation>) {
+    let separators: &[char] = &[' ', '@', '(', ')', '[', ']', ','];
+    let mut start = 0;
+    for token in name.split(separators) {
+        if let Ok(location) = token.parse::<SourceLocation>() {
+            let rest = name[.. start].trim_end_matches(separators);
+            let rest = rest.strip_suffix(" at").unwrap_or(rest).trim_end();
+            return (if rest.is_empty() { name } else { rest }, Some(location));
+        }
+        start += token.len() + 1;
+    }
+    (name, None)
+}
+
+/// The source location of operator `id`: the one of its `--labels` entry, or
+/// the one in its label, its name in `operator_names`, or its name in the trace
+pub fn of(id: u64, operator_names: &BTreeMap<u64, String>) -> Option<SourceLocation> {
+    let label = crate::labels::of(id).unwrap_or_default();
+    label.source
+        .or_else(|| label.label.and_then(|label| split(&label).1))
+        .or_else(|| operator_names.get(&id).and_then(|name| split(name).1))
+        .or_else(|| st2_timely::operators::get(id as usize).and_then(|operator| split(&operator.name).1))
+}
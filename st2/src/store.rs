@@ -0,0 +1,220 @@
+//! Per-epoch metric samples and a store of their recent history.
+//!
+//! When an epoch completes, its PAG is summarized into `Sample`s of the metrics in
+//! `METRICS`, e.g. the epoch's latency or an operator's time on its critical path.
+//! A series is a metric with a set of labels, e.g. `operator_critical_path_ns`
+//! with `operator="Map"`. The `MetricsStore` keeps the samples of the most recent
+//! epochs, so they can be queried by metric name, labels, and time range.
+
+use crate::pag::PagEdge;
+use crate::budget::PendingEpochs;
+use crate::commands::alerts::EpochStats;
+
+use timely::dataflow::{Scope, Stream};
+use timely::dataflow::channels::pact::Exchange;
+use timely::dataflow::operators::generic::operator::Operator;
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use st2_logformat::pair::Pair;
+use st2_timely::diagnostics::StageTimer;
+
+/// Labels of a series, e.g. `operator` → `Map`
+pub type Labels = BTreeMap<String, String>;
+
+/// Names and descriptions of the per-epoch metrics
+pub const METRICS: &[(&str, &str)] = &[
+    ("epoch_latency_ns", "Time from the epoch's first to its last event"),
+    ("critical_path_ns", "Duration of the epoch's critical path"),
+    ("operator_critical_path_ns", "Time of an operator (label `operator`) on the epoch's critical path"),
+    ("team_critical_path_ns", "Time of a team's (label `team`, cf. `labels`) operators on the epoch's critical path"),
+    ("tag_critical_path_ns", "Time of operators with a tag (label `tag`, cf. `labels`) on the epoch's critical path"),
+    ("activity_critical_path_ns", "Time of an activity type (label `activity`) on the epoch's critical path"),
+    ("worker_busy_ns", "Busy time of a worker's (label `worker`) local activities"),
+    ("skew", "Busiest worker's busy time / the workers' average busy time"),
+    ("backlog_epochs", "Number of epochs the source computation is ahead of the analysis"),
+];
+
+/// Names and descriptions of `METRICS` and the installed scripted metrics
+pub fn metrics() -> Vec<(&'static str, &'static str)> {
+    let scripted = crate::scripting::installed().map(|scripts| scripts.metric_names()).unwrap_or_default();
+    METRICS.iter().cloned()
+        .chain(scripted.into_iter().map(|name| (name, "Scripted in the config file")))
+        .collect()
+}
+
+/// The value of a metric in an epoch
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Sample {
+    /// The metric, one of `METRICS` or a scripted metric (cf. `scripting`)
+    pub name: &'static str,
+    /// Labels of the sample's series
+    pub labels: Labels,
+    /// The epoch
+    pub epoch: u64,
+    /// Time of the epoch's last event, in ns since the Unix epoch
+    pub timestamp: u64,
+    /// The metric's value
+    pub value: f64,
+}
+
+/// Summarizes the PAG `edges` of `epoch` into samples of all `METRICS`, followed
+/// by the installed scripted metrics (cf. `scripting`).
+/// The source computation is `ahead` epochs ahead of `epoch`. Operators are
+/// labeled by their label (cf. `labels`) or name in `operator_names`, or their id.
+pub fn epoch_samples(epoch: u64, edges: &[PagEdge], ahead: u64, operator_names: &BTreeMap<u64, String>) -> Vec<Sample> {
+    let stats = EpochStats::new(edges);
+    let timestamp = edges.iter().map(|edge| edge.destination.timestamp).max().unwrap_or_default().as_nanos() as u64;
+    let sample = |name, labels: Vec<(&str, String)>, value| Sample {
+        name,
+        labels: labels.into_iter().map(|(key, value)| (key.to_string(), value)).collect(),
+        epoch,
+        timestamp,
+        value,
+    };
+
+    let mut operators: BTreeMap<String, u64> = BTreeMap::new();
+    let mut teams: BTreeMap<String, u64> = BTreeMap::new();
+    let mut tags: BTreeMap<String, u64> = BTreeMap::new();
+    let mut activities: BTreeMap<String, u64> = BTreeMap::new();
+    for edge in stats.critical_path.iter() {
+        if let Some(id) = edge.operator_id {
+            let operator = crate::labels::name(id, operator_names).unwrap_or_else(|| id.to_string());
+            *operators.entry(operator).or_insert(0) += edge.duration();
+            if let Some(label) = crate::labels::of(id) {
+                if let Some(team) = label.team {
+                    *teams.entry(team).or_insert(0) += edge.duration();
+                }
+                for tag in label.tags {
+                    *tags.entry(tag).or_insert(0) += edge.duration();
+                }
+            }
+        }
+        *activities.entry(format!("{:?}", edge.edge_type)).or_insert(0) += edge.duration();
+    }
+
+    let mut samples = vec![
+        sample("epoch_latency_ns", vec![], stats.latency as f64),
+        sample("critical_path_ns", vec![], stats.critical_path.iter().map(|edge| edge.duration()).sum::<u64>() as f64),
+        sample("skew", vec![], stats.skew),
+        sample("backlog_epochs", vec![], ahead as f64),
+    ];
+    samples.extend(operators.into_iter().map(|(operator, ns)| sample("operator_critical_path_ns", vec![("operator", operator)], ns as f64)));
+    samples.extend(teams.into_iter().map(|(team, ns)| sample("team_critical_path_ns", vec![("team", team)], ns as f64)));
+    samples.extend(tags.into_iter().map(|(tag, ns)| sample("tag_critical_path_ns", vec![("tag", tag)], ns as f64)));
+    samples.extend(activities.into_iter().map(|(activity, ns)| sample("activity_critical_path_ns", vec![("activity", activity)], ns as f64)));
+    let busy: BTreeMap<_, _> = stats.busy.iter().collect();
+    samples.extend(busy.into_iter().map(|(worker, ns)| sample("worker_busy_ns", vec![("worker", worker.to_string())], *ns as f64)));
+    if let Some(scripts) = crate::scripting::installed() {
+        let scripted = scripts.metrics(epoch, edges, &samples, operator_names);
+        samples.extend(scripted);
+    }
+    samples
+}
+
+/// Calls `logic` with every completed epoch of `pag`, its PAG edges, and the number
+/// of epochs the source computation is ahead of it (cf. `backlog_epochs`). All edges
+/// are collected at the first ST2 peer, which completes epochs in order, within the
+/// memory budget (cf. `budget`).
+pub fn completed_epochs<G, F>(pag: &Stream<G, (PagEdge, Pair<u64, Duration>, isize)>, name: &str, mut logic: F)
+where
+    G: Scope<Timestamp = Pair<u64, Duration>>,
+    F: FnMut(u64, Vec<PagEdge>, u64) + 'static,
+{
+    let mut vector = Vec::new();
+    let mut pending = PendingEpochs::new(name);
+    let mut latest = 0;
+    let mut timer = StageTimer::new(name, pag.scope().index());
+    pag.sink(Exchange::new(|_: &(PagEdge, Pair<u64, Duration>, isize)| 0), name, move |input| {
+        input.for_each(|_cap, data| timer.time(data.len(), || {
+            data.swap(&mut vector);
+            for (edge, _t, _diff) in vector.drain(..) {
+                latest = std::cmp::max(latest, edge.source.epoch);
+                pending.push(edge);
+            }
+        }));
+
+        // edges of epoch `e` are produced at `Pair(e, _)`
+        let frontier = input.frontier().frontier();
+        while let Some(epoch) = pending.first() {
+            if frontier.iter().any(|t| t.first <= epoch) {
+                break;
+            }
+            // epochs dropped for the memory budget are skipped
+            let edges = match pending.take_until(epoch + 1).remove(&epoch) {
+                Some(edges) => edges,
+                None => continue,
+            };
+            let ahead = latest - epoch;
+            timer.time(0, || {
+                tracing::debug!(epoch, edges = edges.len() as u64, ahead, "epoch completed");
+                logic(epoch, edges, ahead)
+            });
+        }
+    });
+}
+
+/// The samples of the most recent epochs
+pub struct MetricsStore {
+    /// Number of epochs to keep
+    retention: usize,
+    epochs: VecDeque<(u64, Vec<Sample>)>,
+}
+
+impl MetricsStore {
+    /// A store keeping the samples of the `retention` most recent epochs
+    pub fn new(retention: usize) -> Self {
+        MetricsStore { retention, epochs: VecDeque::new() }
+    }
+
+    /// Adds the `samples` of `epoch`, dropping the oldest epoch if the store is full.
+    /// Epochs have to be inserted in order.
+    pub fn insert(&mut self, epoch: u64, samples: Vec<Sample>) {
+        self.epochs.push_back((epoch, samples));
+        while self.epochs.len() > self.retention {
+            self.epochs.pop_front();
+        }
+    }
+
+    /// The stored epochs, from oldest to newest
+    pub fn epochs(&self) -> impl Iterator<Item = u64> + '_ {
+        self.epochs.iter().map(|(epoch, _)| *epoch)
+    }
+
+    /// The samples of `epoch`, if it's stored
+    pub fn samples(&self, epoch: u64) -> Option<&[Sample]> {
+        self.epochs.iter().find(|(e, _)| *e == epoch).map(|(_, samples)| &samples[..])
+    }
+
+    /// Keys of all stored labels
+    pub fn label_keys(&self) -> BTreeSet<&str> {
+        self.all_samples().flat_map(|sample| sample.labels.keys().map(|key| key.as_str())).collect()
+    }
+
+    /// Stored values of the label `key`
+    pub fn label_values(&self, key: &str) -> BTreeSet<&str> {
+        self.all_samples().filter_map(|sample| sample.labels.get(key).map(|value| value.as_str())).collect()
+    }
+
+    /// The series of metric `name` whose labels include `labels`, with their
+    /// `(timestamp, value)` points from `from` to `to` (in ns since the Unix epoch, inclusive)
+    pub fn query(&self, name: &str, labels: &Labels, from: u64, to: u64) -> BTreeMap<Labels, Vec<(u64, f64)>> {
+        let mut series: BTreeMap<Labels, Vec<(u64, f64)>> = BTreeMap::new();
+        for sample in self.all_samples() {
+            if sample.name != name || sample.timestamp < from || sample.timestamp > to {
+                continue;
+            }
+            if labels.iter().all(|(key, value)| sample.labels.get(key) == Some(value)) {
+                series.entry(sample.labels.clone()).or_insert_with(Vec::new).push((sample.timestamp, sample.value));
+            }
+        }
+        series
+    }
+
+    fn all_samples(&self) -> impl Iterator<Item = &Sample> {
+        self.epochs.iter().flat_map(|(_, samples)| samples.iter())
+    }
+}
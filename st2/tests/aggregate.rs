@@ -0,0 +1,61 @@
+//! Tests of `st2 aggregate`: merging what leaf instances forward into global
+//! metrics and critical paths.
+
+use std::time::Duration;
+
+use timely::dataflow::operators::{Capture, ToStream};
+use timely::dataflow::operators::capture::Extract;
+
+use st2::commands::aggregate::{MergeCriticalPaths, MergeMetrics};
+use st2::commands::metrics::MetricsSummary;
+use st2::pag::{PagEdge, PagNode, TraversalType};
+use st2_logformat::ActivityType;
+use st2_logformat::pair::Pair;
+
+fn edge(epoch: u64, worker_id: u64, (from_ms, to_ms): (u64, u64), operator_id: u64) -> PagEdge {
+    let node = |ms| PagNode { timestamp: Duration::from_millis(ms), worker_id, epoch, seq_no: 0 };
+    PagEdge {
+        source: node(from_ms),
+        destination: node(to_ms),
+        edge_type: ActivityType::Processing,
+        operator_id: Some(operator_id),
+        traverse: TraversalType::Unbounded,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn summaries_of_leaves_are_summed() {
+    let summaries: Vec<MetricsSummary> = vec![
+        (0, 0, ActivityType::Processing, 2, 10, 5),
+        (1, 1, ActivityType::Processing, 1, 4, 0),
+        (0, 0, ActivityType::Processing, 3, 20, 1),
+    ];
+    let merged = timely::execute_directly(move |worker| {
+        worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| summaries.to_stream(scope).merge_metrics().capture())
+    });
+    let mut merged: Vec<MetricsSummary> = merged.extract().into_iter().flat_map(|(_, summaries)| summaries).collect();
+    merged.sort();
+    assert_eq!(merged, vec![(0, 0, ActivityType::Processing, 5, 30, 6), (1, 1, ActivityType::Processing, 1, 4, 0)]);
+}
+
+#[test]
+fn critical_paths_span_the_partial_pags_of_all_leaves() {
+    // one leaf analyzes worker 0, another worker 1; worker 0 is slower in
+    // epoch 1, worker 1 in epoch 2
+    let edges = vec![
+        edge(1, 0, (0, 10), 1), edge(1, 0, (10, 30), 2),
+        edge(1, 1, (0, 25), 1),
+        edge(2, 0, (100, 105), 1),
+        edge(2, 1, (100, 110), 1), edge(2, 1, (110, 112), 2),
+    ];
+    let paths = timely::execute_directly(move |worker| {
+        worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| edges.to_stream(scope).merge_critical_paths().capture())
+    });
+    let mut paths: Vec<Vec<PagEdge>> = paths.extract().into_iter().flat_map(|(_, paths)| paths).collect();
+    paths.sort_by_key(|path| path[0].source.epoch);
+    assert_eq!(paths, vec![
+        vec![edge(1, 0, (0, 10), 1), edge(1, 0, (10, 30), 2)],
+        vec![edge(2, 1, (100, 110), 1), edge(2, 1, (110, 112), 2)],
+    ]);
+}
@@ -0,0 +1,128 @@
+//! Tests of `st2 alerts`: parsing rules, and evaluating them on a window of epochs.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use st2::STError;
+use st2::commands::alerts::{evaluate, Alert, Metric, Rule, Window};
+use st2::pag::{PagEdge, PagNode, TraversalType};
+use st2_logformat::ActivityType;
+
+const MS: f64 = 1_000_000.0;
+
+fn rule(rule: &str) -> Rule {
+    rule.parse().unwrap_or_else(|e| panic!("{:?} doesn't parse: {}", rule, e))
+}
+
+fn parse_error(rule: &str) -> String {
+    match rule.parse::<Rule>() {
+        Err(STError::Config(message)) => message,
+        result => panic!("{:?} parsed: {:?}", rule, result),
+    }
+}
+
+#[test]
+fn rules_compare_a_metric_with_a_threshold() {
+    let latency = rule(" latency > 500ms ");
+    assert_eq!((latency.text.as_str(), latency.metric, latency.below, latency.threshold), ("latency > 500ms", Metric::Latency, false, 500.0 * MS));
+
+    let share = rule("cp_share( Map ) > 40%");
+    assert_eq!((share.metric, share.threshold), (Metric::CpShare("Map".to_string()), 0.4));
+    let share = rule("cp_share(team:core) > 0.25");
+    assert_eq!((share.metric, share.threshold), (Metric::CpShare("team:core".to_string()), 0.25));
+
+    let backlog = rule("backlog>10");
+    assert_eq!((backlog.metric, backlog.below, backlog.threshold), (Metric::Backlog, false, 10.0));
+    let skew = rule("skew < 1.5");
+    assert_eq!((skew.metric, skew.below, skew.threshold), (Metric::Skew, true, 1.5));
+}
+
+#[test]
+fn invalid_rules_are_rejected() {
+    assert_eq!(parse_error("latency"), "latency: expected METRIC > THRESHOLD or METRIC < THRESHOLD");
+    assert_eq!(parse_error("throughput > 5"), "throughput > 5: unknown metric throughput (expected latency, cp_share(OPERATOR), backlog, or skew)");
+    assert_eq!(parse_error("cp_share() > 40%"), "cp_share() > 40%: cp_share needs an operator id or name, team:<TEAM>, or tag:<TAG>");
+    assert_eq!(parse_error("cp_share(Map) > most%"), "cp_share(Map) > most%: invalid share most%");
+    assert_eq!(parse_error("skew > inf"), "skew > inf: invalid threshold inf");
+    assert!(parse_error("latency > soon").starts_with("invalid time: soon"));
+}
+
+fn edge(epoch: u64, (from, from_ms): (u64, u64), (to, to_ms): (u64, u64), edge_type: ActivityType, operator_id: Option<u64>) -> PagEdge {
+    let node = |worker_id, ms| PagNode { timestamp: Duration::from_millis(ms), worker_id, epoch, seq_no: 0 };
+    PagEdge {
+        source: node(from, from_ms),
+        destination: node(to, to_ms),
+        edge_type,
+        operator_id,
+        traverse: TraversalType::Unbounded,
+        ..Default::default()
+    }
+}
+
+/// A window of two epochs, 3 epochs behind the source computation. In the first,
+/// worker 0 runs `Map` (10ms) and sends its output to worker 1 (2ms), which runs
+/// `Filter` (8ms, and 5ms before); in the second, `Map` (2ms) and `Filter` (1ms)
+/// run on their own.
+fn window() -> Window {
+    let edges: BTreeMap<u64, Vec<PagEdge>> = vec![
+        (1, vec![
+            edge(1, (0, 0), (0, 10), ActivityType::Processing, Some(1)),
+            edge(1, (0, 10), (1, 12), ActivityType::DataMessage, None),
+            edge(1, (1, 0), (1, 5), ActivityType::Processing, Some(2)),
+            edge(1, (1, 12), (1, 20), ActivityType::Processing, Some(2)),
+        ]),
+        (2, vec![
+            edge(2, (0, 100), (0, 102), ActivityType::Processing, Some(1)),
+            edge(2, (1, 100), (1, 101), ActivityType::Processing, Some(2)),
+        ]),
+    ].into_iter().collect();
+    Window::new(&edges, 3)
+}
+
+fn alerts(rules: &[&str], window: &Window) -> Vec<Alert> {
+    let rules: Vec<Rule> = rules.iter().map(|text| rule(text)).collect();
+    let operator_names = vec![(1, "Map".to_string()), (2, "Filter".to_string())].into_iter().collect();
+    evaluate(&rules, (0, 3), window, &operator_names)
+}
+
+/// (rule, value, epoch) of the fired `rules`
+fn fired(rules: &[&str]) -> Vec<(String, f64, u64)> {
+    alerts(rules, &window()).into_iter().map(|alert| (alert.rule, alert.value, alert.epoch)).collect()
+}
+
+#[test]
+fn rules_fire_for_their_window() {
+    let alert = |rule: &str, value: f64, epoch: u64| (rule.to_string(), value, epoch);
+
+    // the slowest epoch's latency
+    assert_eq!(fired(&["latency > 10ms", "latency > 30ms", "latency < 30ms"]), vec![
+        alert("latency > 10ms", 20.0 * MS, 1),
+        alert("latency < 30ms", 20.0 * MS, 1),
+    ]);
+    // the share in both epochs' critical paths (22ms), of which `Map` ran 10 + 2ms,
+    // determined by the epoch where it's highest
+    assert_eq!(fired(&["cp_share(Map) > 50%", "cp_share(1) > 50%", "cp_share(Filter) > 40%"]), vec![
+        alert("cp_share(Map) > 50%", 12.0 / 22.0, 2),
+        alert("cp_share(1) > 50%", 12.0 / 22.0, 2),
+    ]);
+    // workers were busy 10 and 13ms in the first epoch, 2 and 1ms in the second
+    assert_eq!(fired(&["skew > 1.2", "skew > 1.5"]), vec![alert("skew > 1.2", 2.0 / 1.5, 2)]);
+    assert_eq!(fired(&["backlog > 2", "backlog > 3"]), vec![alert("backlog > 2", 3.0, 2)]);
+}
+
+#[test]
+fn alerts_describe_the_offending_epoch() {
+    let alerts = alerts(&["latency > 10ms"], &window());
+    assert_eq!(alerts.len(), 1);
+    let alert = &alerts[0];
+    assert_eq!(alert.message, "latency > 10ms: 20.0ms in epoch 1 (window 0..=3, threshold 10.0ms)");
+    assert_eq!((alert.window, alert.threshold, alert.latency), ((0, 3), 10.0 * MS, 20_000_000));
+    let path: Vec<(ActivityType, Option<u64>)> = alert.critical_path.iter().map(|edge| (edge.edge_type, edge.operator_id)).collect();
+    assert_eq!(path, vec![(ActivityType::Processing, Some(1)), (ActivityType::DataMessage, None), (ActivityType::Processing, Some(2))]);
+}
+
+#[test]
+fn empty_windows_fire_nothing() {
+    let empty = Window::new(&BTreeMap::new(), 0);
+    assert!(alerts(&["latency < 1ms", "cp_share(Map) < 50%", "skew < 2", "backlog < 1"], &empty).is_empty());
+}
@@ -0,0 +1,143 @@
+//! Tests of `st2 anonymize`: names are replaced by salted pseudonyms, which are
+//! stable across traces, and everything else is kept.
+
+mod common;
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use st2::OutputFormat;
+use st2::commands::anonymize;
+use st2_logformat::{ActivityType, EventType, LogRecord, Origin};
+use st2_logformat::encoding::Encoding;
+use st2_logformat::tagged::{self, Value};
+use st2_logformat::trace::{Compression, TraceHeader, TraceWriter};
+
+/// A user-defined tag, e.g. of span labels
+const LABEL: tagged::Tag = tagged::USER_TAGS + 1;
+
+/// The pseudonym of `name` as documented: `<kind>-` and 12 hex digits of
+/// SHA-256 over the salt, a zero byte, and the name
+fn pseudonym(kind: &str, salt: &str, name: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(salt.as_bytes());
+    hasher.input(&[0]);
+    hasher.input(name.as_bytes());
+    let hash: String = hasher.result().iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}", kind, &hash[.. 12])
+}
+
+/// A record of `worker` on `host` running `operator`, optionally labelled.
+/// Names are ids into `names`, the order in which the trace interns them, or
+/// dangling if they aren't in it.
+fn scheduled(worker: u64, seq_no: u64, ms: u64, names: &[&str], host: &str, operator: &str, label: Option<&str>) -> LogRecord {
+    let id = |name: &str| names.iter().position(|n| *n == name).map(|id| id as u32).unwrap_or(99);
+    let mut record = common::record(worker, seq_no, ms, ActivityType::Scheduling, EventType::Start);
+    record.operator_id = Some(seq_no);
+    record.origin = Some(Origin { host: id(host), process_id: worker as u32, thread_id: 0 });
+    tagged::set(&mut record.tagged, tagged::OPERATOR_NAME, Value::U64(id(operator) as u64));
+    if let Some(label) = label {
+        tagged::set(&mut record.tagged, LABEL, Value::Bytes(label.as_bytes().to_vec()));
+    }
+    record
+}
+
+fn write_trace(path: &Path, names: &[&str], records: &[LogRecord]) {
+    let header = TraceHeader::new(2, "test".to_string(), Encoding::Compact, Compression::None);
+    let mut writer = TraceWriter::new(&header, BufWriter::new(File::create(path).expect("couldn't create trace"))).expect("couldn't write header");
+    for (id, name) in names.iter().enumerate() {
+        assert_eq!(writer.intern(name) as usize, id);
+    }
+    writer.write_batch(records).expect("couldn't write records");
+    writer.finish().expect("couldn't finish trace");
+}
+
+/// The host, operator name, and label of every record of the trace at `path`
+fn read_names(path: &Path) -> (Vec<LogRecord>, Vec<(String, String, Option<String>)>) {
+    let (records, reader) = common::read_trace(path);
+    let names = records.iter().map(|record| {
+        let resolve = |id: u32| reader.names().resolve(id).expect("name dropped").to_string();
+        let host = resolve(record.origin.expect("origin dropped").host);
+        let operator = match tagged::get(&record.tagged, tagged::OPERATOR_NAME) {
+            Some(Value::U64(id)) => resolve(*id as u32),
+            other => panic!("operator name dropped: {:?}", other),
+        };
+        let label = match tagged::get(&record.tagged, LABEL) {
+            Some(Value::Bytes(bytes)) => Some(String::from_utf8(bytes.clone()).expect("label isn't text")),
+            _ => None,
+        };
+        (host, operator, label)
+    }).collect();
+    (records, names)
+}
+
+fn temp_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("st2-anonymize-{}-{}", test, std::process::id()));
+    std::fs::create_dir_all(&dir).expect("couldn't create directory");
+    dir
+}
+
+#[test]
+fn names_are_replaced_consistently() {
+    let dir = temp_dir("consistent");
+    let (input, output) = (dir.join("in.st2"), dir.join("out.st2"));
+    let names = ["alpha", "beta", "Map", "Filter"];
+    let records = vec![
+        scheduled(0, 1, 0, &names, "alpha", "Map", Some("customer-42")),
+        scheduled(1, 2, 1, &names, "alpha", "Map", None),
+        scheduled(1, 3, 2, &names, "beta", "Filter", Some("customer-42")),
+        // the operator's name isn't in the trace's name table
+        scheduled(0, 4, 3, &names, "beta", "Reduce", Some("customer-7")),
+    ];
+    write_trace(&input, &names, &records);
+
+    anonymize::run(&input, &output, "pepper", OutputFormat::Json).expect("anonymize failed");
+    let (anonymized, renamed) = read_names(&output);
+    std::fs::remove_dir_all(&dir).ok();
+
+    let host = |name: &str| pseudonym("host", "pepper", name);
+    let op = |name: &str| pseudonym("op", "pepper", name);
+    let label = |name: &str| pseudonym("label", "pepper", name);
+    assert_eq!(renamed, vec![
+        (host("alpha"), op("Map"), Some(label("customer-42"))),
+        (host("alpha"), op("Map"), None),
+        (host("beta"), op("Filter"), Some(label("customer-42"))),
+        (host("beta"), "op-unknown".to_string(), Some(label("customer-7"))),
+    ]);
+    assert_ne!(host("alpha"), host("beta"));
+
+    // everything but the names is kept
+    let strip = |record: &LogRecord| (record.seq_no, record.epoch, record.timestamp, record.local_worker, record.operator_id,
+                                      record.origin.map(|origin| (origin.process_id, origin.thread_id)));
+    assert_eq!(anonymized.iter().map(strip).collect::<Vec<_>>(), records.iter().map(strip).collect::<Vec<_>>());
+}
+
+#[test]
+fn pseudonyms_are_stable_for_a_salt() {
+    let dir = temp_dir("stable");
+    // two traces of one computation, which intern their names in different orders
+    let first = (dir.join("0.st2"), ["alpha", "Map"]);
+    let second = (dir.join("1.st2"), ["Map", "alpha"]);
+    for (path, names) in [&first, &second].iter() {
+        write_trace(path, names, &[scheduled(0, 1, 0, names, "alpha", "Map", Some("customer-42"))]);
+    }
+
+    let anonymized = |input: &Path, salt: &str| {
+        let output = input.with_extension(format!("{}.st2", salt));
+        anonymize::run(input, &output, salt, OutputFormat::Json).expect("anonymize failed");
+        read_names(&output).1
+    };
+    let (of_first, again, of_second) = (anonymized(&first.0, "pepper"), anonymized(&first.0, "pepper"), anonymized(&second.0, "pepper"));
+    let salted = anonymized(&first.0, "salt");
+    std::fs::remove_dir_all(&dir).ok();
+
+    assert_eq!(of_first, again);
+    assert_eq!(of_first, of_second);
+    let ((host, op, label), (salted_host, salted_op, salted_label)) = (&of_first[0], &salted[0]);
+    assert_ne!(host, salted_host);
+    assert_ne!(op, salted_op);
+    assert_ne!(label, salted_label);
+}
@@ -0,0 +1,103 @@
+//! Tests of `api`'s protocols: WebSocket handshakes and frames, server-sent
+//! events, CORS origins, and query parameters.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use st2::commands::api::{event, parse_url, serve_websockets, AllowedOrigins};
+
+/// Opens a WebSocket to `path` at `addr` with the sample key of RFC 6455, and
+/// returns the stream and the handshake's response head
+fn handshake(addr: std::net::SocketAddr, path: &str, origin: Option<&str>) -> (BufReader<TcpStream>, Vec<String>) {
+    let mut stream = TcpStream::connect(addr).expect("couldn't connect");
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let origin = origin.map(|origin| format!("Origin: {}\r\n", origin)).unwrap_or_default();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{}\r\n", path, addr, origin).unwrap();
+
+    let mut reader = BufReader::new(stream);
+    let mut head = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).expect("couldn't read handshake");
+        let line = line.trim_end().to_string();
+        if line.is_empty() {
+            break;
+        }
+        head.push(line);
+    }
+    (reader, head)
+}
+
+#[test]
+fn websockets_accept_handshakes_and_push_text_frames() {
+    let (websockets, addr) = serve_websockets("127.0.0.1:0", None, AllowedOrigins::default()).expect("couldn't serve");
+    let (mut reader, head) = handshake(addr, "/ws", None);
+    assert!(head[0].starts_with("HTTP/1.1 101"), "{:?}", head);
+    assert!(head.iter().any(|line| line == "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="), "{:?}", head);
+
+    // the connection may only be registered once the handshake was answered, so
+    // the summary is pushed until it arrives
+    let summary = r#"{"epoch":1}"#;
+    let mut frame = [0u8; 13];
+    for attempt in 0.. {
+        websockets.send(summary).expect("couldn't push");
+        reader.get_mut().set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        match reader.read_exact(&mut frame) {
+            Ok(()) => break,
+            Err(e) if attempt < 25 => eprintln!("no frame yet: {}", e),
+            Err(e) => panic!("no frame pushed: {}", e),
+        }
+    }
+    // a final, unmasked text frame of 11 bytes
+    assert_eq!(&frame[..2], &[0x81, 11]);
+    assert_eq!(&frame[2..], summary.as_bytes());
+}
+
+#[test]
+fn websockets_reject_other_paths_and_origins() {
+    let origins = AllowedOrigins(vec!["http://localhost:8080".to_string()]);
+    let (_websockets, addr) = serve_websockets("127.0.0.1:0", None, origins).expect("couldn't serve");
+
+    let (_, head) = handshake(addr, "/other", None);
+    assert!(head[0].starts_with("HTTP/1.1 404"), "{:?}", head);
+    let (_, head) = handshake(addr, "/ws", Some("http://evil.example"));
+    assert!(head[0].starts_with("HTTP/1.1 403"), "{:?}", head);
+    let (_, head) = handshake(addr, "/ws", Some("http://localhost:8080"));
+    assert!(head[0].starts_with("HTTP/1.1 101"), "{:?}", head);
+}
+
+#[test]
+fn origins_are_allowed_explicitly() {
+    let none = AllowedOrigins::default();
+    assert_eq!(none.allow("http://localhost:8080"), None);
+
+    let some = AllowedOrigins(vec!["http://localhost:8080".to_string(), "https://ui.example".to_string()]);
+    assert_eq!(some.allow("https://ui.example"), Some("https://ui.example"));
+    assert_eq!(some.allow("http://localhost"), None);
+
+    let any = AllowedOrigins(vec!["*".to_string()]);
+    assert_eq!(any.allow("http://localhost:8080"), Some("*"));
+}
+
+#[test]
+fn events_have_one_data_field_per_line() {
+    assert_eq!(event("epoch", r#"{"epoch":1}"#), "event: epoch\ndata: {\"epoch\":1}\n\n");
+    assert_eq!(event("epoch", "{\n  \"epoch\": 1\n}"), "event: epoch\ndata: {\ndata:   \"epoch\": 1\ndata: }\n\n");
+}
+
+#[test]
+fn query_parameters_are_percent_decoded() {
+    let (path, parameters) = parse_url("/metrics?name=latency%20p99&operator=Map%2FFilter&to=5+6");
+    assert_eq!(path, "/metrics");
+    assert_eq!(parameters, vec![
+        ("name".to_string(), "latency p99".to_string()),
+        ("operator".to_string(), "Map/Filter".to_string()),
+        ("to".to_string(), "5 6".to_string()),
+    ]);
+
+    let (path, parameters) = parse_url("/epochs");
+    assert_eq!(path, "/epochs");
+    assert!(parameters.is_empty());
+}
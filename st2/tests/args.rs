@@ -0,0 +1,84 @@
+//! Tests of parsing subcommands' arguments (`from_args`): defaults, the
+//! `--config` file as fallback, and invalid values.
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{App, ArgMatches};
+
+use st2::STError;
+use st2::commands::{alerts, export, flamegraph, metrics, trim};
+use st2::config::{Args, Config};
+
+/// The arguments of `command_line` of `subcommand`, and the config `toml`
+fn parse(subcommand: App<'static, 'static>, command_line: &str, toml: &str) -> (ArgMatches<'static>, Config) {
+    let command_line: Vec<OsString> = command_line.split_whitespace().map(OsString::from).collect();
+    let matches = subcommand.get_matches_from_safe(command_line).expect("invalid command line");
+    (matches, toml.parse().expect("invalid config"))
+}
+
+/// The message of a config error
+fn config_error<T>(result: Result<T, STError>) -> String {
+    match result {
+        Err(STError::Config(message)) => message,
+        Err(e) => panic!("not a config error: {}", e),
+        Ok(_) => panic!("parsed"),
+    }
+}
+
+#[test]
+fn defaults_apply_to_omitted_arguments() {
+    let (matches, config) = parse(metrics::subcommand(), "metrics --forward 127.0.0.1:9000 --forward-pag", "");
+    let args = Args { matches: &matches, config: &config, subcommand: Some("metrics") };
+    let options = metrics::from_args(&args).expect("arguments don't parse");
+    assert_eq!(options.output_path, PathBuf::from("metrics.csv"));
+    assert_eq!(options.forward, Some("127.0.0.1:9000".parse().unwrap()));
+    assert!(options.forward_pag);
+    assert_eq!((options.breakdown_path, options.summary), (None, false));
+
+    let (matches, config) = parse(export::subcommand(), "export --edges edges.csv --min-weight 2ms", "");
+    let args = Args { matches: &matches, config: &config, subcommand: Some("export") };
+    let options = export::from_args(&args).expect("arguments don't parse");
+    assert_eq!(options.epochs, 0 .. std::u64::MAX);
+    assert_eq!((options.edges_path, options.metrics_path), (Some(PathBuf::from("edges.csv")), None));
+    assert_eq!(options.min_weight, Duration::from_millis(2));
+}
+
+#[test]
+fn config_files_fill_in_arguments() {
+    let toml = "[trim]\nfrom = 1.5\nepochs = \"10..\"\n";
+    let (matches, config) = parse(trim::subcommand(), "trim in.st2 out.st2 --to 4", toml);
+    let args = Args { matches: &matches, config: &config, subcommand: Some("trim") };
+    let options = trim::from_args(&args).expect("arguments don't parse");
+    assert_eq!((options.input, options.output), (PathBuf::from("in.st2"), PathBuf::from("out.st2")));
+    assert_eq!(options.time, Duration::from_millis(1500) .. Duration::from_secs(4));
+    assert_eq!(options.epochs, 10 .. std::u64::MAX);
+
+    let toml = "[alerts]\nrule = [\"latency > 50ms\", \"skew > 2\"]\nwindow = 5\n";
+    let (matches, config) = parse(alerts::subcommand(), "alerts", toml);
+    let args = Args { matches: &matches, config: &config, subcommand: Some("alerts") };
+    let options = alerts::from_args(&args).expect("arguments don't parse");
+    let rules: Vec<_> = options.rules.iter().map(|rule| rule.text.as_str()).collect();
+    assert_eq!((rules, options.window), (vec!["latency > 50ms", "skew > 2"], 5));
+}
+
+#[test]
+fn invalid_arguments_are_rejected() {
+    let (matches, config) = parse(flamegraph::subcommand(), "flamegraph --window 0", "");
+    let args = Args { matches: &matches, config: &config, subcommand: Some("flamegraph") };
+    assert_eq!(config_error(flamegraph::from_args(&args)), "Invalid --window: has to be at least 1");
+
+    let (matches, config) = parse(flamegraph::subcommand(), "flamegraph --epochs 5", "");
+    let args = Args { matches: &matches, config: &config, subcommand: Some("flamegraph") };
+    assert_eq!(config_error(flamegraph::from_args(&args)), "Invalid --epochs: 5 (expected FROM..TO)");
+
+    let (matches, config) = parse(trim::subcommand(), "trim in.st2 out.st2 --from soon", "");
+    let args = Args { matches: &matches, config: &config, subcommand: Some("trim") };
+    assert_eq!(config_error(trim::from_args(&args)), "Invalid --from: soon (expected a number of seconds)");
+
+    // without rules, unless alerts are scripted
+    let (matches, config) = parse(alerts::subcommand(), "alerts", "");
+    let args = Args { matches: &matches, config: &config, subcommand: Some("alerts") };
+    assert_eq!(config_error(alerts::from_args(&args)), "Invalid --rule: no rules given (nor alerts scripted in the --config)");
+}
@@ -0,0 +1,138 @@
+//! Tests of `--auth` files: parsing grants, matching endpoints, and
+//! authenticating bearer tokens and client certificates.
+
+use st2::STError;
+use st2::auth::{access_token, bearer, Auth, Credential, Denied};
+
+/// SHA-256 of `password`
+const PASSWORD_SHA256: &str = "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8";
+
+fn auth(toml: &str) -> Auth {
+    toml.parse().expect("invalid --auth")
+}
+
+#[test]
+fn bad_grants_are_rejected() {
+    for toml in &[
+        "not toml = = =",
+        // unknown tables and keys
+        "[[user]]\ntoken = \"secret\"\nendpoints = [\"*\"]\n",
+        "token = \"secret\"\n",
+        "[token]\ntoken = \"secret\"\nendpoints = [\"*\"]\n",
+        // a token without its secret, a client without its fingerprint
+        "[[token]]\nendpoints = [\"*\"]\n",
+        "[[client]]\nsha256 = \"abcd\"\nendpoints = [\"*\"]\n",
+        // endpoints missing, or not strings
+        "[[token]]\ntoken = \"secret\"\n",
+        "[[token]]\ntoken = \"secret\"\nendpoints = \"*\"\n",
+        "[[token]]\ntoken = \"secret\"\nendpoints = [1]\n",
+    ] {
+        match toml.parse::<Auth>() {
+            Err(STError::Config(_)) => (),
+            result => panic!("{:?} accepted: {:?}", toml, result),
+        }
+    }
+}
+
+#[test]
+fn plain_and_hashed_tokens_authenticate() {
+    let plain = auth("[[token]]\ntoken = \"password\"\nendpoints = [\"/epochs\"]\n");
+    let hashed = auth(&format!("[[token]]\nsha256 = \"{}\"\nendpoints = [\"/epochs\"]\n", PASSWORD_SHA256));
+    // hashes are compared regardless of their case
+    let upper = auth(&format!("[[token]]\nsha256 = \"{}\"\nendpoints = [\"/epochs\"]\n", PASSWORD_SHA256.to_uppercase()));
+
+    for auth in &[plain, hashed, upper] {
+        assert_eq!(auth.authorize(&[Credential::Token("password")], "/epochs"), Ok(()));
+        assert_eq!(auth.authorize(&[Credential::Token("Password")], "/epochs"), Err(Denied::Unauthenticated));
+        assert_eq!(auth.authorize(&[Credential::Token(PASSWORD_SHA256)], "/epochs"), Err(Denied::Unauthenticated));
+    }
+}
+
+#[test]
+fn missing_or_wrong_credentials_are_denied() {
+    let auth = auth("[[token]]\ntoken = \"password\"\nendpoints = [\"/epochs\"]\n");
+
+    assert_eq!(auth.authorize(&[], "/epochs"), Err(Denied::Unauthenticated));
+    assert_eq!(auth.authorize(&[Credential::Token("")], "/epochs"), Err(Denied::Unauthenticated));
+    assert_eq!(auth.authorize(&[Credential::Token("wrong")], "/epochs"), Err(Denied::Unauthenticated));
+    // a certificate isn't a token
+    assert_eq!(auth.authorize(&[Credential::Certificate(b"password")], "/epochs"), Err(Denied::Unauthenticated));
+    // known credentials, but not for this endpoint
+    assert_eq!(auth.authorize(&[Credential::Token("password")], "/metrics"), Err(Denied::Forbidden));
+    // unknown credentials are ignored next to known ones
+    assert_eq!(auth.authorize(&[Credential::Token("wrong"), Credential::Token("password")], "/epochs"), Ok(()));
+
+    assert_eq!(Denied::Unauthenticated.status(), 401);
+    assert_eq!(Denied::Forbidden.status(), 403);
+}
+
+#[test]
+fn client_certificates_authenticate_by_fingerprint() {
+    // SHA-256 of the "DER encoding" `password`, as `openssl` prints fingerprints
+    let fingerprint = PASSWORD_SHA256.to_uppercase().as_bytes()
+        .chunks(2)
+        .map(|pair| std::str::from_utf8(pair).unwrap())
+        .collect::<Vec<_>>()
+        .join(":");
+    let auth = auth(&format!("[[client]]\nfingerprint = \"{}\"\nendpoints = [\"GetCriticalPath\"]\n", fingerprint));
+
+    assert_eq!(auth.authorize(&[Credential::Certificate(b"password")], "GetCriticalPath"), Ok(()));
+    assert_eq!(auth.authorize(&[Credential::Certificate(b"password")], "TriggerSnapshot"), Err(Denied::Forbidden));
+    assert_eq!(auth.authorize(&[Credential::Token("password")], "GetCriticalPath"), Err(Denied::Unauthenticated));
+}
+
+#[test]
+fn wildcards_match_suffixes_only() {
+    let auth = auth("[[token]]\ntoken = \"password\"\nendpoints = [\"/epochs/*\", \"/metrics\", \"Get*\"]\n");
+    let authorize = |endpoint| auth.authorize(&[Credential::Token("password")], endpoint);
+
+    assert_eq!(authorize("/epochs/3"), Ok(()));
+    assert_eq!(authorize("/epochs/"), Ok(()));
+    assert_eq!(authorize("GetCriticalPath"), Ok(()));
+    assert_eq!(authorize("/metrics"), Ok(()));
+
+    // prefixes of the pattern, and the pattern elsewhere in the endpoint, don't match
+    assert_eq!(authorize("/epochs"), Err(Denied::Forbidden));
+    assert_eq!(authorize("/api/epochs/3"), Err(Denied::Forbidden));
+    assert_eq!(authorize("TriggerSnapshotGet"), Err(Denied::Forbidden));
+    // patterns without `*` match exactly
+    assert_eq!(authorize("/metrics/"), Err(Denied::Forbidden));
+    assert_eq!(authorize("/metricsx"), Err(Denied::Forbidden));
+    assert_eq!(authorize("/Metrics"), Err(Denied::Forbidden));
+    // `*` isn't a wildcard within a pattern, nor in an endpoint
+    assert_eq!(authorize("*"), Err(Denied::Forbidden));
+
+    let all = self::auth("[[token]]\ntoken = \"password\"\nendpoints = [\"*\"]\n");
+    assert_eq!(all.authorize(&[Credential::Token("password")], "/anything"), Ok(()));
+    let none = self::auth("[[token]]\ntoken = \"password\"\nendpoints = []\n");
+    assert_eq!(none.authorize(&[Credential::Token("password")], "/epochs"), Err(Denied::Forbidden));
+}
+
+#[test]
+fn bearer_tokens_are_extracted() {
+    assert_eq!(bearer("Bearer secret"), Some("secret"));
+    assert_eq!(bearer("  bearer   secret  "), Some("secret"));
+    assert_eq!(bearer("BEARER a+b/c="), Some("a+b/c="));
+    assert_eq!(bearer("Basic c2VjcmV0"), None);
+    assert_eq!(bearer("Bearer"), None);
+    assert_eq!(bearer("Bearersecret"), None);
+    assert_eq!(bearer(""), None);
+}
+
+#[test]
+fn access_tokens_are_percent_decoded() {
+    assert_eq!(access_token("access_token=secret"), Some("secret".to_string()));
+    assert_eq!(access_token("from=3&access_token=a%2Bb%2Fc%3D&to=5"), Some("a+b/c=".to_string()));
+    assert_eq!(access_token("from=3"), None);
+    assert_eq!(access_token("my_access_token=secret"), None);
+
+    // a base64-style token, from the header or the query
+    let auth = auth("[[token]]\ntoken = \"a+b/c=\"\nendpoints = [\"/subscribe\"]\n");
+    assert_eq!(auth.authorize_url("/subscribe?access_token=a%2Bb%2Fc%3D", None), Ok(()));
+    assert_eq!(auth.authorize_url("/subscribe", Some("Bearer a+b/c=")), Ok(()));
+    assert_eq!(auth.authorize_url("/subscribe?access_token=a%2Bb%2Fc%3D", Some("Bearer wrong")), Ok(()));
+    // the query isn't part of the endpoint
+    assert_eq!(auth.authorize_url("/epochs?access_token=a%2Bb%2Fc%3D", None), Err(Denied::Forbidden));
+    assert_eq!(auth.authorize_url("/subscribe?access_token=wrong", None), Err(Denied::Unauthenticated));
+    assert_eq!(auth.authorize_url("/subscribe", None), Err(Denied::Unauthenticated));
+}
@@ -0,0 +1,89 @@
+//! Helpers shared by the integration tests
+
+// every test uses some of the helpers
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+use timely::dataflow::ProbeHandle;
+use timely::dataflow::operators::capture::event::EventIterator;
+use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::operators::probe::Probe;
+
+use st2::pag::{self, PagEdge};
+use st2_logformat::{ActivityType, EventType, LogRecord};
+use st2_logformat::pair::Pair;
+use st2_logformat::trace::TraceReader;
+use st2_timely::connect::CompEvent;
+use st2_timely::filter::Filter;
+use st2_timely::replay_throttled::ReplaySpeed;
+
+/// The log records and the PAG (by epoch, with every epoch's edges sorted) ST2
+/// constructs from the sources of `replayers`, which is called for a fresh
+/// replayer of every source per construction.
+pub fn construct<F, I>(replayers: F) -> (Vec<LogRecord>, BTreeMap<u64, Vec<PagEdge>>)
+where
+    F: Fn() -> Vec<I> + Send + Sync + 'static,
+    I: EventIterator<Pair<u64, Duration>, CompEvent> + 'static,
+{
+    let (records, mut pag) = timely::execute_directly(move |worker| {
+        let records = Rc::new(RefCell::new(Vec::new()));
+        let pag = Rc::new(RefCell::new(BTreeMap::new()));
+
+        let mut probe = ProbeHandle::new();
+        let (records_in, pag_in) = (Rc::clone(&records), Rc::clone(&pag));
+        worker.dataflow(|scope| {
+            st2_timely::create_lrs(scope, replayers(), 0, None, 1, ReplaySpeed::Unbounded, Filter::default())
+                .inspect(move |record| records_in.borrow_mut().push(record.clone()))
+                .probe_with(&mut probe);
+            pag::create_pag(scope, replayers(), 0, None, 1, ReplaySpeed::Unbounded, Filter::default())
+                .inspect(move |(edge, t, _)| pag_in.borrow_mut().entry(t.first).or_insert_with(Vec::new).push(edge.clone()))
+                .probe_with(&mut probe);
+        });
+        while !probe.done() { worker.step(); }
+
+        let records: Vec<LogRecord> = records.borrow().clone();
+        let pag: BTreeMap<u64, Vec<PagEdge>> = pag.borrow().clone();
+        (records, pag)
+    });
+    // edges arrive in a different order from run to run
+    for edges in pag.values_mut() {
+        edges.sort();
+    }
+    (records, pag)
+}
+
+/// A record of `worker` in epoch 1, at `ms` on its clock
+pub fn record(worker: u64, seq_no: u64, ms: u64, activity_type: ActivityType, event_type: EventType) -> LogRecord {
+    LogRecord {
+        seq_no,
+        epoch: 1,
+        timestamp: Duration::from_millis(ms),
+        local_worker: worker,
+        activity_type,
+        event_type,
+        remote_worker: None,
+        operator_id: None,
+        channel_id: None,
+        correlator_id: None,
+        length: None,
+        origin: None,
+        tagged: Vec::new(),
+    }
+}
+
+/// The records of the trace file at `path`, and its reader (e.g. for its names)
+pub fn read_trace(path: &Path) -> (Vec<LogRecord>, TraceReader) {
+    let mut reader = TraceReader::new(BufReader::new(File::open(path).expect("couldn't open trace"))).expect("couldn't read header");
+    let mut records = Vec::new();
+    while let Some(batch) = reader.next_batch().expect("couldn't read trace") {
+        records.extend(batch);
+    }
+    (records, reader)
+}
@@ -0,0 +1,119 @@
+//! Tests of `--config` files: their precedence over defaults, the command line's
+//! over theirs, arrays for options given several times, and their checks against
+//! the command line's arguments.
+
+use std::ffi::OsString;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use st2::STError;
+use st2::config::{Args, Config};
+
+/// A command line like `st2`'s, with some of its arguments
+fn app() -> App<'static, 'static> {
+    App::new("st2")
+        .arg(Arg::with_name("output_format").long("output").possible_values(&["text", "json"]).default_value("text"))
+        .arg(Arg::with_name("from_file").long("from-file").takes_value(true))
+        .arg(Arg::with_name("follow").long("follow").requires("from_file"))
+        .arg(Arg::with_name("deterministic").long("deterministic").conflicts_with("follow"))
+        .subcommand(SubCommand::with_name("alerts")
+            .arg(Arg::with_name("rule").long("rule").takes_value(true).multiple(true).number_of_values(1))
+            .arg(Arg::with_name("window").long("window").default_value("1")))
+        .subcommand(SubCommand::with_name("dashboard")
+            .arg(Arg::with_name("listen").long("listen").takes_value(true)))
+}
+
+/// `command_line` split at spaces, with the program name
+fn command_line(command_line: &str) -> Vec<OsString> {
+    std::iter::once("st2").chain(command_line.split_whitespace()).map(OsString::from).collect()
+}
+
+/// The arguments of `command_line`, and the config `toml`
+fn parse(command_line: &[OsString], toml: &str) -> (ArgMatches<'static>, Config) {
+    let matches = app().get_matches_from_safe(command_line.iter().cloned()).expect("invalid command line");
+    (matches, toml.parse().expect("invalid config"))
+}
+
+/// The result of checking the config `toml` against `command_line`
+fn check(command_line: &str, toml: &str) -> Result<(), STError> {
+    let command_line = self::command_line(command_line);
+    let (matches, config) = parse(&command_line, toml);
+    config.check(&app(), &command_line, matches.subcommand_name())
+}
+
+#[test]
+fn command_line_overrides_config() {
+    let toml = "output = \"json\"\n[alerts]\nwindow = 10\n";
+
+    let (matches, config) = parse(&command_line("--output text alerts --window 5"), toml);
+    let args = Args { matches: &matches, config: &config, subcommand: None };
+    assert_eq!(args.value_of("output_format"), Some("text"));
+    let (_, alerts) = args.subcommand();
+    assert_eq!(alerts.expect("no subcommand").value_of("window"), Some("5"));
+
+    let (matches, config) = parse(&command_line("alerts"), toml);
+    let args = Args { matches: &matches, config: &config, subcommand: None };
+    assert_eq!(args.value_of("output_format"), Some("json"));
+    let (_, alerts) = args.subcommand();
+    assert_eq!(alerts.expect("no subcommand").value_of("window"), Some("10"));
+
+    // defaults apply if neither sets an argument
+    let (matches, config) = parse(&command_line("alerts"), "");
+    let args = Args { matches: &matches, config: &config, subcommand: None };
+    assert_eq!(args.value_of("output_format"), Some("text"));
+}
+
+#[test]
+fn arrays_set_options_given_several_times() {
+    let toml = "[alerts]\nrule = [\"latency > 50ms\", \"skew > 2\"]\n";
+
+    let (matches, config) = parse(&command_line("alerts"), toml);
+    let args = Args { matches: &matches, config: &config, subcommand: None };
+    let (_, alerts) = args.subcommand();
+    assert_eq!(alerts.expect("no subcommand").all_values_of("rule"), vec!["latency > 50ms", "skew > 2"]);
+
+    // the command line's values replace the config's
+    let (matches, config) = parse(&command_line("alerts --rule busy>0"), toml);
+    let args = Args { matches: &matches, config: &config, subcommand: None };
+    let (_, alerts) = args.subcommand();
+    assert_eq!(alerts.expect("no subcommand").all_values_of("rule"), vec!["busy>0"]);
+
+    assert!(check("alerts", toml).is_ok());
+}
+
+#[test]
+fn unknown_keys_are_rejected() {
+    assert!(check("alerts", "from-file = \"traces\"\n[alerts]\nwindow = 2\n[dashboard]\nlisten = \"0.0.0.0:3012\"\n").is_ok());
+
+    for toml in &[
+        "form-file = \"traces\"\n",
+        // a global argument in a subcommand's table
+        "[alerts]\nfrom-file = \"traces\"\n",
+        // tables of subcommands not run are checked, too
+        "[dashboard]\nrule = \"skew > 2\"\n",
+        "[dashbaord]\nlisten = \"0.0.0.0:3012\"\n",
+    ] {
+        match check("alerts", toml) {
+            Err(STError::Config(_)) => (),
+            result => panic!("{:?} accepted: {:?}", toml, result),
+        }
+    }
+}
+
+#[test]
+fn config_values_are_checked_like_the_command_line() {
+    assert!(check("alerts", "from-file = \"traces\"\nfollow = true\n").is_ok());
+    assert!(check("--from-file traces alerts", "follow = true\n").is_ok());
+
+    for (command_line, toml) in &[
+        ("alerts", "output = \"yaml\"\n"),
+        ("alerts", "from-file = \"traces\"\nfollow = true\ndeterministic = true\n"),
+        ("--from-file traces --follow alerts", "deterministic = true\n"),
+        ("alerts", "follow = true\n"),
+    ] {
+        match check(command_line, toml) {
+            Err(STError::Config(_)) => (),
+            result => panic!("{:?} accepted with `{}`: {:?}", toml, command_line, result),
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! End-to-end tests of the online connect path: a small instrumented timely &
+//! differential computation runs in a child process (this test binary, running
+//! `source_computation`) and streams its events to the test, which accepts the
+//! connections like `-i`/`-p` do and runs the `metrics` pipeline over them,
+//! right away or on a `record`ing of them.
+//!
+//! The child is attached through `SNAILTRAIL_ADDR`; `TIMELY_WORKER_LOG_ADDR` is
+//! removed from its environment, as the adapter doesn't attach to computations
+//! already logging there.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex, atomic::AtomicBool};
+
+use differential_dataflow::input::InputSession;
+use differential_dataflow::operators::Count;
+
+use tdiag_connect::receive::ReplaySource;
+
+use st2::OutputFormat;
+use st2::commands::record::Format;
+use st2_timely::connect::Adapter;
+use st2_timely::filter::Filter;
+use st2_timely::replay_throttled::ReplaySpeed;
+
+/// Set in the environment of the child process
+const CHILD: &str = "ST2_E2E_CHILD";
+/// Workers of the source computation
+const SOURCE_PEERS: usize = 2;
+/// Epochs of the source computation
+const EPOCHS: u64 = 10;
+
+/// The source computation, only run as the child process of the other tests
+#[test]
+fn source_computation() {
+    if std::env::var_os(CHILD).is_none() {
+        return;
+    }
+
+    timely::execute(timely::Configuration::Process(SOURCE_PEERS), |worker| {
+        let adapter = Adapter::attach(worker);
+
+        let mut input = InputSession::new();
+        let probe = worker.dataflow(|scope| {
+            input.to_collection(scope)
+                .map(|x: u64| x % 7)
+                .count()
+                .probe()
+        });
+
+        for round in 0 .. EPOCHS {
+            if worker.index() == 0 {
+                (0 .. 20).for_each(|i| input.insert(round * 20 + i));
+            }
+            input.advance_to(round + 1);
+            input.flush();
+            while probe.less_than(input.time()) { worker.step(); }
+
+            adapter.tick_epoch();
+        }
+    }).expect("source computation failed");
+}
+
+/// Spawns the source computation, attached to a listener of this process, and
+/// accepts its connections, one per source peer, as `open_sockets` does
+fn spawn_source() -> (Child, ReplaySource) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("couldn't listen");
+    let addr = listener.local_addr().expect("couldn't listen");
+
+    let child = Command::new(std::env::current_exe().expect("couldn't find the test binary"))
+        .args(&["--exact", "source_computation", "--nocapture"])
+        .env(CHILD, "1")
+        .env("SNAILTRAIL_ADDR", addr.to_string())
+        .env_remove("TIMELY_WORKER_LOG_ADDR")
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("couldn't spawn the source computation");
+
+    let sockets: Vec<Option<TcpStream>> = listener.incoming()
+        .take(SOURCE_PEERS)
+        .map(|stream| Some(stream.expect("couldn't accept the source computation")))
+        .collect();
+    (child, ReplaySource::Tcp(Arc::new(Mutex::new(sockets))))
+}
+
+/// Runs the `metrics` pipeline over `replay_source`, writing to `output_path`
+fn metrics(replay_source: ReplaySource, output_path: &Path) {
+    st2::commands::metrics::run(
+        timely::Configuration::Thread,
+        replay_source,
+        Arc::new(AtomicBool::new(true)),
+        ReplaySpeed::Unbounded,
+        Filter::default(),
+        output_path,
+        None,
+        false,
+        None,
+        false,
+        &BTreeMap::new(),
+        OutputFormat::Text).expect("metrics failed");
+}
+
+/// Checks that the metrics `csv` cover the whole source computation
+fn check_metrics(csv: &str) {
+    let mut lines = csv.lines();
+    assert_eq!(lines.next(), Some("epoch,from_worker,to_worker,activity_type,#(activities),t(activities),#(records)"));
+    let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+    assert!(rows.iter().all(|row| row.len() == 7), "malformed metrics:\n{}", csv);
+
+    // every epoch of the computation shows up ...
+    let epochs: BTreeSet<u64> = rows.iter().map(|row| row[0].parse().expect("invalid epoch")).collect();
+    assert_eq!(epochs, (0 .. EPOCHS).collect(), "epochs missing from metrics:\n{}", csv);
+
+    // ... with both workers processing and exchanging data
+    let workers: BTreeSet<&str> = rows.iter().map(|row| row[1]).collect();
+    assert_eq!(workers.len(), SOURCE_PEERS, "workers missing from metrics:\n{}", csv);
+    let activities: BTreeSet<&str> = rows.iter().map(|row| row[3]).collect();
+    assert!(activities.contains("Processing"), "no processing in metrics:\n{}", csv);
+    assert!(rows.iter().any(|row| row[3] == "DataMessage" && row[1] != row[2]), "no data exchanged in metrics:\n{}", csv);
+
+    // the input was inserted on worker 0 and exchanged to all others
+    let records: u64 = rows.iter()
+        .filter(|row| row[3] == "DataMessage" && row[1] == "0" && row[2] != "0")
+        .map(|row| row[6].parse::<u64>().expect("invalid record count"))
+        .sum();
+    assert!(records > 0, "no records sent from worker 0:\n{}", csv);
+}
+
+#[test]
+fn online_metrics() {
+    if std::env::var_os(CHILD).is_some() {
+        return;
+    }
+
+    let (mut child, replay_source) = spawn_source();
+
+    let dir = std::env::temp_dir().join(format!("st2-e2e-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("couldn't create output directory");
+    let output_path = dir.join("metrics.csv");
+
+    metrics(replay_source, &output_path);
+    assert!(child.wait().expect("source computation didn't run").success(), "source computation failed");
+
+    let csv = std::fs::read_to_string(&output_path).expect("couldn't read metrics");
+    std::fs::remove_dir_all(&dir).ok();
+    check_metrics(&csv);
+}
+
+#[test]
+fn recorded_metrics() {
+    if std::env::var_os(CHILD).is_some() {
+        return;
+    }
+
+    let (mut child, replay_source) = spawn_source();
+
+    let dir = std::env::temp_dir().join(format!("st2-e2e-recorded-{}", std::process::id()));
+    let recording = dir.join("recording");
+    st2::commands::record::run(
+        timely::Configuration::Thread,
+        replay_source,
+        Arc::new(AtomicBool::new(true)),
+        &recording,
+        Format::Dump).expect("record failed");
+    assert!(child.wait().expect("source computation didn't run").success(), "source computation failed");
+
+    // the recording is analyzed like `-f <recording>` does
+    let files = (0 .. SOURCE_PEERS).map(|peer| Some(recording.join(format!("{}.dump", peer)))).collect();
+    let output_path = dir.join("metrics.csv");
+    metrics(ReplaySource::Files(Arc::new(Mutex::new(files))), &output_path);
+
+    let csv = std::fs::read_to_string(&output_path).expect("couldn't read metrics");
+    std::fs::remove_dir_all(&dir).ok();
+    check_metrics(&csv);
+}
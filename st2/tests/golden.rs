@@ -0,0 +1,103 @@
+//! Golden-trace regression tests: replays the traces checked into
+//! `tests/golden/<case>/` (the `*.dump` files of an example computation, cf.
+//! `tests/golden/README.md`) and compares the constructed PAG and its per-epoch
+//! metrics to the stored `pag.json` and `metrics.json`. A difference means that
+//! a change to the adapter or PAG construction changed ST2's results.
+//!
+//! Run with `ST2_BLESS=1` to (re)write the golden files of all cases, e.g. after
+//! an intended change or when adding a case, and review their diff.
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+use timely::dataflow::operators::capture::EventReader;
+
+use st2::pag::PagEdge;
+use st2::store;
+
+/// Directories of the golden cases
+fn cases() -> Vec<PathBuf> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut cases: Vec<PathBuf> = std::fs::read_dir(&root).expect("couldn't read tests/golden")
+        .map(|entry| entry.expect("couldn't read tests/golden").path())
+        .filter(|path| path.is_dir())
+        .collect();
+    cases.sort();
+    cases
+}
+
+/// The `<worker>.dump` files of `case`, by worker
+fn dumps(case: &Path) -> Vec<PathBuf> {
+    let mut dumps: Vec<(usize, PathBuf)> = std::fs::read_dir(case).expect("couldn't read case")
+        .map(|entry| entry.expect("couldn't read case").path())
+        .filter_map(|path| {
+            let worker = path.file_name()?.to_str()?.strip_suffix(".dump")?.parse().ok()?;
+            Some((worker, path))
+        })
+        .collect();
+    dumps.sort();
+    dumps.into_iter().map(|(_, path)| path).collect()
+}
+
+/// The PAG ST2 constructs from `dumps`, by epoch, with every epoch's edges sorted
+fn construct(dumps: Vec<PathBuf>) -> BTreeMap<u64, Vec<PagEdge>> {
+    let (_, pag) = common::construct(move || dumps.iter()
+        .map(|path| EventReader::<_, st2_timely::connect::CompEvent, _>::new(File::open(path).expect("couldn't open trace")))
+        .collect());
+    pag
+}
+
+/// The golden JSON of the PAG and of its metrics
+fn results(pag: &BTreeMap<u64, Vec<PagEdge>>) -> (Value, Value) {
+    let operator_names = BTreeMap::new();
+    let metrics: BTreeMap<u64, Vec<store::Sample>> = pag.iter()
+        .map(|(epoch, edges)| (*epoch, store::epoch_samples(*epoch, edges, 0, &operator_names)))
+        .collect();
+    (serde_json::to_value(pag).expect("couldn't serialize PAG"), serde_json::to_value(metrics).expect("couldn't serialize metrics"))
+}
+
+/// Panics with the first epoch in which `actual` differs from the golden `expected`.
+fn compare(case: &Path, file: &str, actual: &Value, expected: &Value) {
+    if actual == expected {
+        return;
+    }
+    let empty = serde_json::Map::new();
+    let (actual, expected) = (actual.as_object().unwrap_or(&empty), expected.as_object().unwrap_or(&empty));
+    let epoch = actual.keys().chain(expected.keys())
+        .find(|epoch| actual.get(*epoch) != expected.get(*epoch))
+        .expect("results differ in some epoch");
+    panic!("{}/{} differs in epoch {}:\nexpected: {}\nactual:   {}\n(run with ST2_BLESS=1 if the change is intended)",
+           case.display(), file, epoch,
+           expected.get(epoch).map(Value::to_string).unwrap_or_else(|| "nothing".to_string()),
+           actual.get(epoch).map(Value::to_string).unwrap_or_else(|| "nothing".to_string()));
+}
+
+#[test]
+fn golden_traces() {
+    let bless = std::env::var_os("ST2_BLESS").is_some();
+    let cases = cases();
+    assert!(!cases.is_empty(), "no golden cases in tests/golden, cf. tests/golden/README.md");
+
+    for case in cases {
+        let dumps = dumps(&case);
+        assert!(!dumps.is_empty(), "{} has no *.dump files", case.display());
+        let (pag, metrics) = results(&construct(dumps));
+
+        for (file, actual) in [("pag.json", &pag), ("metrics.json", &metrics)].iter() {
+            let path = case.join(file);
+            if bless {
+                let json = serde_json::to_string_pretty(actual).expect("couldn't serialize results");
+                std::fs::write(&path, json + "\n").expect("couldn't write golden file");
+                continue;
+            }
+            let golden = std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("couldn't read {} ({}), run with ST2_BLESS=1 to create it", path.display(), e));
+            let expected: Value = serde_json::from_str(&golden).expect("invalid golden file");
+            compare(&case, file, actual, &expected);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Tests of `st2 merge`: estimating clock offsets from message delays, and
+//! merging trace files with skew-corrected timestamps and re-interned names.
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use st2::OutputFormat;
+use st2::commands::merge::{estimate_offsets, Offsets};
+use st2_logformat::{ActivityType, LogRecord, Origin};
+use st2_logformat::encoding::Encoding;
+use st2_logformat::tagged::{self, Value};
+use st2_logformat::trace::{Compression, TraceHeader, TraceWriter};
+
+const MS: i64 = 1_000_000;
+
+fn offsets(delays: &[((u64, u64), i64)]) -> Offsets {
+    estimate_offsets(&delays.iter().cloned().collect())
+}
+
+#[test]
+fn offsets_split_round_trips() {
+    // worker 1's clock is 4ms ahead: messages to it seem to take 5ms instead
+    // of 1ms, and messages from it -3ms
+    assert_eq!(offsets(&[((0, 1), 5 * MS), ((1, 0), -3 * MS)]), vec![(0, 0), (1, 4 * MS)].into_iter().collect());
+    // symmetric delays: no skew
+    assert_eq!(offsets(&[((0, 1), MS), ((1, 0), MS)]), vec![(0, 0), (1, 0)].into_iter().collect());
+}
+
+#[test]
+fn one_way_delays_only_correct_causality() {
+    assert_eq!(offsets(&[((0, 1), 3 * MS)]), vec![(0, 0), (1, 0)].into_iter().collect());
+    // received before it was sent: worker 1's clock is behind by at least 2ms
+    assert_eq!(offsets(&[((0, 1), -2 * MS)]), vec![(0, 0), (1, -2 * MS)].into_iter().collect());
+}
+
+#[test]
+fn offsets_propagate_from_the_lowest_worker() {
+    // 2 is 3ms ahead of 1, which is 4ms ahead of 0
+    let chained = offsets(&[((0, 1), 5 * MS), ((1, 0), -3 * MS), ((1, 2), 4 * MS), ((2, 1), -2 * MS)]);
+    assert_eq!(chained, vec![(0, 0), (1, 4 * MS), (2, 7 * MS)].into_iter().collect());
+
+    // workers that never exchanged messages are their own references
+    let disconnected = offsets(&[((0, 1), 5 * MS), ((1, 0), -3 * MS), ((2, 3), -MS), ((3, 2), 3 * MS)]);
+    assert_eq!(disconnected, vec![(0, 0), (1, 4 * MS), (2, 0), (3, -2 * MS)].into_iter().collect());
+}
+
+fn message(mut record: LogRecord, remote: u64, correlator: u64) -> LogRecord {
+    record.remote_worker = Some(remote);
+    record.channel_id = Some(1);
+    record.correlator_id = Some(correlator);
+    record
+}
+
+/// Writes the trace file of `worker` on `host`, which runs `operator`, to `path`.
+/// Every file interns its names with the same ids, which the merged trace has
+/// to tell apart.
+fn write_trace(path: &Path, worker: u64, host: &str, operator: &str, mut records: Vec<LogRecord>) {
+    let header = TraceHeader::new(1, "test".to_string(), Encoding::Compact, Compression::None);
+    let mut writer = TraceWriter::new(&header, BufWriter::new(File::create(path).expect("couldn't create trace"))).expect("couldn't write header");
+    let operator = writer.intern(operator);
+    let host = writer.intern(host);
+    for record in records.iter_mut() {
+        record.origin = Some(Origin { host, process_id: worker as u32, thread_id: 0 });
+        if record.activity_type == ActivityType::Scheduling {
+            record.operator_id = Some(worker);
+            tagged::set(&mut record.tagged, tagged::OPERATOR_NAME, Value::U64(operator as u64));
+        }
+    }
+    writer.write_batch(&records).expect("couldn't write records");
+    writer.finish().expect("couldn't finish trace");
+}
+
+#[test]
+fn merged_traces_are_corrected_and_renamed() {
+    use ActivityType::{DataMessage, Scheduling};
+    use st2_logformat::EventType::{End, Received, Sent, Start};
+    use common::record;
+
+    let dir: PathBuf = std::env::temp_dir().join(format!("st2-merge-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("couldn't create directory");
+    let (first, second, merged) = (dir.join("0.st2"), dir.join("1.st2"), dir.join("merged.st2"));
+
+    // worker 1's clock is 4ms ahead of worker 0's, messages take 1ms
+    write_trace(&first, 0, "alpha", "Map", vec![
+        record(0, 1, 0, Scheduling, Start),
+        message(record(0, 2, 1, DataMessage, Sent), 1, 1),
+        record(0, 3, 2, Scheduling, End),
+        message(record(0, 4, 10, DataMessage, Received), 1, 2),
+    ]);
+    write_trace(&second, 1, "beta", "Filter", vec![
+        record(1, 1, 5, Scheduling, Start),
+        message(record(1, 2, 6, DataMessage, Received), 0, 1),
+        message(record(1, 3, 13, DataMessage, Sent), 0, 2),
+        record(1, 4, 14, Scheduling, End),
+    ]);
+
+    st2::commands::merge::run(&[&first, &second], &merged, Encoding::Compact, Compression::None, OutputFormat::Json).expect("merge failed");
+    let (records, reader) = common::read_trace(&merged);
+    std::fs::remove_dir_all(&dir).ok();
+
+    // in order of the corrected timestamps, ties in order of the inputs
+    let timeline: Vec<(u64, u64, Duration)> = records.iter().map(|r| (r.local_worker, r.seq_no, r.timestamp)).collect();
+    let ms = Duration::from_millis;
+    assert_eq!(timeline, vec![
+        (0, 1, ms(0)), (0, 2, ms(1)), (1, 1, ms(1)), (0, 3, ms(2)), (1, 2, ms(2)), (1, 3, ms(9)), (0, 4, ms(10)), (1, 4, ms(10)),
+    ]);
+
+    // names refer to the merged trace's name table
+    let names = reader.names();
+    let by_worker: BTreeMap<u64, (String, String)> = records.iter()
+        .filter(|r| r.activity_type == Scheduling)
+        .map(|r| {
+            let host = names.resolve(r.origin.expect("origin dropped").host).expect("host not renamed");
+            let operator = match tagged::get(&r.tagged, tagged::OPERATOR_NAME) {
+                Some(Value::U64(id)) => names.resolve(*id as u32).expect("operator not renamed"),
+                other => panic!("operator name dropped: {:?}", other),
+            };
+            (r.local_worker, (host.to_string(), operator.to_string()))
+        })
+        .collect();
+    assert_eq!(by_worker[&0], ("alpha".to_string(), "Map".to_string()));
+    assert_eq!(by_worker[&1], ("beta".to_string(), "Filter".to_string()));
+}
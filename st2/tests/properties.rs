@@ -0,0 +1,137 @@
+//! Property-based tests of log record and PAG construction: invariants that
+//! have to hold for every trace, checked on randomized synthetic traces
+//! (cf. `st2_timely::synthetic`).
+
+mod common;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+use proptest::prelude::*;
+
+use st2::pag::{PagEdge, PagNode};
+use st2_logformat::{ActivityType, EventType, LogRecord};
+use st2_logformat::pag::critical_path;
+use st2_timely::synthetic::{MessagePattern, Workload};
+
+fn workloads() -> impl Strategy<Value = Workload> {
+    let messages = prop_oneof![Just(MessagePattern::Silent), Just(MessagePattern::Ring), Just(MessagePattern::AllToAll)];
+    (1 .. 5usize, 1 .. 5usize, 1 .. 5u64, 0 .. 2000u64, 10 .. 200u64, messages, 1 .. 100usize, 1.0 .. 4.0f64)
+        .prop_map(|(workers, operators, epochs, interval, operator_time, messages, records, skew)| Workload {
+            workers,
+            operators,
+            epochs,
+            epoch_interval: Duration::from_micros(interval),
+            operator_time: Duration::from_micros(operator_time),
+            messages,
+            records,
+            skew,
+        })
+}
+
+/// The log records and the PAG (by epoch) ST2 constructs from `workload`
+fn construct(workload: &Workload) -> (Vec<LogRecord>, BTreeMap<u64, Vec<PagEdge>>) {
+    let workload = workload.clone();
+    common::construct(move || (0 .. workload.workers).map(|source| workload.replayer(source)).collect())
+}
+
+/// Whether `edges` form a directed acyclic graph
+fn is_acyclic(edges: &[PagEdge]) -> bool {
+    let mut incoming: HashMap<PagNode, usize> = HashMap::new();
+    let mut outgoing: HashMap<PagNode, Vec<PagNode>> = HashMap::new();
+    for edge in edges {
+        incoming.entry(edge.source).or_insert(0);
+        *incoming.entry(edge.destination).or_insert(0) += 1;
+        outgoing.entry(edge.source).or_insert_with(Vec::new).push(edge.destination);
+    }
+
+    // Kahn's algorithm: all nodes are removed iff there's no cycle
+    let mut ready: Vec<PagNode> = incoming.iter().filter(|(_, count)| **count == 0).map(|(node, _)| *node).collect();
+    let mut removed = 0;
+    while let Some(node) = ready.pop() {
+        removed += 1;
+        for next in outgoing.get(&node).into_iter().flatten() {
+            let count = incoming.get_mut(next).expect("destination counted");
+            *count -= 1;
+            if *count == 0 {
+                ready.push(*next);
+            }
+        }
+    }
+    removed == incoming.len()
+}
+
+/// Whether the local edges of a worker form a single path through its nodes
+fn is_path(edges: &[&PagEdge]) -> bool {
+    let sources: HashSet<PagNode> = edges.iter().map(|edge| edge.source).collect();
+    let destinations: HashSet<PagNode> = edges.iter().map(|edge| edge.destination).collect();
+    let nodes: HashSet<PagNode> = sources.union(&destinations).cloned().collect();
+    // no node is left or entered twice, and exactly one node starts the path
+    sources.len() == edges.len() && destinations.len() == edges.len()
+        && nodes.len() == edges.len() + 1
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn every_start_has_an_end(workload in workloads()) {
+        let (records, _) = construct(&workload);
+        prop_assert_eq!(records.len() as u64, workload.records());
+
+        // (worker, operator) -> epoch of the open Start
+        let mut open: HashMap<(u64, u64), u64> = HashMap::new();
+        let mut records = records;
+        records.sort_by_key(|record| (record.local_worker, record.seq_no));
+        for record in records.iter().filter(|record| record.activity_type == ActivityType::Scheduling) {
+            let key = (record.local_worker, record.operator_id.expect("scheduled operator"));
+            match record.event_type {
+                EventType::Start => prop_assert!(open.insert(key, record.epoch).is_none(), "{:?} started twice", key),
+                EventType::End => prop_assert_eq!(open.remove(&key), Some(record.epoch), "{:?} ended without a Start", key),
+                _ => prop_assert!(false, "unexpected scheduling event {:?}", record),
+            }
+        }
+        prop_assert!(open.is_empty(), "Starts without End: {:?}", open);
+    }
+
+    #[test]
+    fn pag_is_acyclic_and_a_path_per_worker(workload in workloads()) {
+        let (_, pag) = construct(&workload);
+        prop_assert_eq!(pag.len() as u64, workload.epochs);
+
+        for (epoch, edges) in pag.iter() {
+            prop_assert!(is_acyclic(edges), "epoch {} has a cycle", epoch);
+
+            let mut local: HashMap<u64, Vec<&PagEdge>> = HashMap::new();
+            for edge in edges.iter().filter(|edge| edge.source.worker_id == edge.destination.worker_id) {
+                prop_assert_eq!(edge.source.epoch, *epoch);
+                local.entry(edge.source.worker_id).or_insert_with(Vec::new).push(edge);
+            }
+            prop_assert_eq!(local.len(), workload.workers);
+            for (worker, edges) in local.iter() {
+                prop_assert!(is_path(edges), "local edges of w{} in epoch {} aren't a path", worker, epoch);
+            }
+        }
+    }
+
+    #[test]
+    fn critical_path_spans_the_epoch(workload in workloads()) {
+        let (_, pag) = construct(&workload);
+
+        for (epoch, edges) in pag.iter() {
+            let path = critical_path(edges);
+            prop_assert!(path.windows(2).all(|pair| pair[0].destination == pair[1].source), "critical path of epoch {} is broken", epoch);
+
+            let weight: u64 = path.iter().map(PagEdge::duration).sum();
+            let first = edges.iter().map(|edge| edge.source.timestamp).min().expect("edges");
+            let last = edges.iter().map(|edge| edge.destination.timestamp).max().expect("edges");
+            let span = (last - first).as_nanos() as u64;
+            prop_assert!(weight <= span, "critical path of epoch {} is longer than the epoch", epoch);
+            // waiting on data messages blocks the path at operator boundaries;
+            // without them, the critical path leads from the epoch's start to its end
+            if workload.messages == MessagePattern::Silent {
+                prop_assert_eq!(weight, span, "critical path of epoch {} doesn't span it", epoch);
+            }
+        }
+    }
+}
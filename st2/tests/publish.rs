@@ -0,0 +1,84 @@
+//! Tests of the aggregation levels of metric sinks (cf. `publish::Level`).
+
+use std::collections::BTreeMap;
+
+use st2::STError;
+use st2::commands::publish::{Level, SampleFilter, SinkConfig};
+use st2::store::Sample;
+
+fn sample(name: &'static str, labels: &[(&str, &str)], value: f64) -> Sample {
+    Sample {
+        name,
+        labels: labels.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+        epoch: 1,
+        timestamp: 0,
+        value,
+    }
+}
+
+/// The samples of `level` of `samples`
+fn apply(level: Level, samples: &[Sample], operator_names: &BTreeMap<u64, String>) -> Vec<Sample> {
+    SampleFilter { level, ..Default::default() }.apply(samples, operator_names)
+}
+
+#[test]
+fn levels_parse_with_the_detail_alias() {
+    assert_eq!("operator".parse::<Level>().expect("level doesn't parse"), Level::Operator);
+    assert_eq!("detail".parse::<Level>().expect("level doesn't parse"), Level::Operator);
+    assert_eq!("scope".parse::<Level>().expect("level doesn't parse"), Level::Scope);
+    match "operators".parse::<Level>() {
+        Err(STError::Config(message)) => assert_eq!(message, "invalid level=operators (expected operator, scope, activity, or summary)"),
+        result => panic!("parsed: {:?}", result),
+    }
+
+    let sink: SinkConfig = "jsonl:- level=detail every=2".parse().expect("sink doesn't parse");
+    assert_eq!(sink.filter, SampleFilter { level: Level::Operator, every: 2, ..Default::default() });
+}
+
+#[test]
+fn scopes_sum_their_operators() {
+    // `Join` and `Filter` are in the `Iterate` scope of `Dataflow`, `Map` is not
+    st2_timely::operators::record(0, "Dataflow", &[0]);
+    st2_timely::operators::record(1, "Iterate", &[0, 1]);
+    st2_timely::operators::record(2, "Join", &[0, 1, 2]);
+    st2_timely::operators::record(3, "Map", &[0, 3]);
+    st2_timely::operators::record(4, "Filter", &[0, 1, 4]);
+    let operator_names = vec![(2, "Join".to_string()), (3, "Map".to_string()), (4, "Filter".to_string())].into_iter().collect();
+
+    let samples = vec![
+        sample("epoch_latency_ns", &[], 100.0),
+        sample("operator_critical_path_ns", &[("operator", "Join")], 5.0),
+        sample("operator_critical_path_ns", &[("operator", "Filter")], 3.0),
+        sample("operator_critical_path_ns", &[("operator", "Map")], 7.0),
+        sample("activity_critical_path_ns", &[("activity", "Processing")], 15.0),
+        // an operator replay hasn't seen is scoped by its name
+        sample("operator_critical_path_ns", &[("operator", "Iterate/Reduce")], 1.0),
+        sample("operator_critical_path_ns", &[("operator", "Reduce")], 2.0),
+    ];
+    assert_eq!(apply(Level::Scope, &samples, &operator_names), vec![
+        sample("epoch_latency_ns", &[], 100.0),
+        sample("operator_critical_path_ns", &[("scope", "Dataflow/Iterate")], 8.0),
+        sample("operator_critical_path_ns", &[("scope", "Dataflow")], 7.0),
+        sample("activity_critical_path_ns", &[("activity", "Processing")], 15.0),
+        sample("operator_critical_path_ns", &[("scope", "Iterate")], 1.0),
+        sample("operator_critical_path_ns", &[("scope", "dataflow")], 2.0),
+    ]);
+
+    // coarser levels drop labeled samples instead
+    assert_eq!(apply(Level::Activity, &samples, &operator_names), vec![samples[0].clone(), samples[4].clone()]);
+    assert_eq!(apply(Level::Summary, &samples, &operator_names), vec![samples[0].clone()]);
+    assert_eq!(apply(Level::Operator, &samples, &operator_names), samples);
+}
+
+#[test]
+fn scopes_keep_other_labels_apart() {
+    let samples = vec![
+        sample("worker_busy_ns", &[("operator", "Outer/Map"), ("worker", "0")], 4.0),
+        sample("worker_busy_ns", &[("operator", "Outer/Filter"), ("worker", "1")], 2.0),
+        sample("worker_busy_ns", &[("operator", "Outer/Join"), ("worker", "0")], 1.0),
+    ];
+    assert_eq!(apply(Level::Scope, &samples, &BTreeMap::new()), vec![
+        sample("worker_busy_ns", &[("scope", "Outer"), ("worker", "0")], 5.0),
+        sample("worker_busy_ns", &[("scope", "Outer"), ("worker", "1")], 2.0),
+    ]);
+}
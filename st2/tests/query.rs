@@ -0,0 +1,156 @@
+//! Tests of `st2 query`: parsing queries, reporting errors where they occur,
+//! and evaluating them over a small PAG.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use st2::STError;
+use st2::commands::query::{Query, Value};
+use st2::pag::{PagEdge, PagNode, TraversalType};
+use st2_logformat::ActivityType;
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+fn edge(epoch: u64, (from, from_ms): (u64, u64), (to, to_ms): (u64, u64), edge_type: ActivityType, operator_id: Option<u64>) -> PagEdge {
+    let node = |worker_id, timestamp| PagNode { timestamp, worker_id, epoch, seq_no: 0 };
+    PagEdge {
+        source: node(from, ms(from_ms)),
+        destination: node(to, ms(to_ms)),
+        edge_type,
+        operator_id,
+        traverse: if edge_type == ActivityType::Waiting { TraversalType::Block } else { TraversalType::Unbounded },
+        ..Default::default()
+    }
+}
+
+/// Two epochs: in the first, worker 0 runs `Map` and sends its output to worker 1,
+/// which runs `Filter`; in the second, worker 0 runs `Map` again.
+fn pag() -> Vec<PagEdge> {
+    vec![
+        edge(1, (0, 0), (0, 10), ActivityType::Processing, Some(1)),
+        edge(1, (0, 10), (0, 30), ActivityType::Waiting, None),
+        edge(1, (0, 10), (1, 12), ActivityType::DataMessage, None),
+        edge(1, (1, 12), (1, 20), ActivityType::Processing, Some(2)),
+        edge(2, (0, 100), (0, 105), ActivityType::Processing, Some(1)),
+    ]
+}
+
+fn operator_names() -> BTreeMap<u64, String> {
+    vec![(1, "Map".to_string()), (2, "Filter".to_string())].into_iter().collect()
+}
+
+/// The columns and rows `query` evaluates to over `pag`
+fn eval(query: &str) -> (Vec<String>, Vec<Vec<Value>>) {
+    let query: Query = query.parse().unwrap_or_else(|e| panic!("{:?} doesn't parse: {}", query, e));
+    let table = query.eval(&pag(), &operator_names()).expect("query failed");
+    (table.columns, table.rows)
+}
+
+fn parse_error(query: &str) -> String {
+    match query.parse::<Query>() {
+        Err(STError::Config(message)) => message,
+        result => panic!("{:?} parsed: {:?}", query, result),
+    }
+}
+
+fn text(s: &str) -> Value {
+    Value::Text(s.to_string())
+}
+
+fn time(ms: u64) -> Value {
+    Value::Time(ms * 1_000_000)
+}
+
+#[test]
+fn errors_point_at_their_column() {
+    assert_eq!(parse_error("edges"), "expected `from`, found `edges` (at column 1)");
+    assert_eq!(parse_error("from nodes"), "unknown source: nodes, expected edges or cp (at column 6)");
+    assert_eq!(parse_error("from edges where epoch = 1"), "expected `|`, found `where` (at column 12)");
+    assert_eq!(parse_error("from edges | frob"),
+               "unknown stage: frob, expected where, group by, count, sum, avg, min, max, sort, limit, or select (at column 14)");
+    assert_eq!(parse_error("from edges | where epoch"), "unexpected end of query, expected a comparison (at column 25)");
+    assert_eq!(parse_error("from edges | where epoch , 1"), "expected a comparison, found `,` (at column 26)");
+    assert_eq!(parse_error("from edges | group worker"), "expected `by`, found `worker` (at column 20)");
+    assert_eq!(parse_error("from edges | median(duration)"),
+               "unknown stage: median, expected where, group by, count, sum, avg, min, max, sort, limit, or select (at column 14)");
+    assert_eq!(parse_error("from edges | sum duration"), "expected `(`, found `duration` (at column 18)");
+    assert_eq!(parse_error("from edges | limit ten"), "invalid limit: ten (at column 20)");
+    assert_eq!(parse_error("from edges | where type = \"Map"), "unterminated string (at column 27)");
+    assert_eq!(parse_error("from edges | where epoch ! 1"), "unexpected character: ! (at column 26)");
+    // columns count characters, not bytes
+    assert_eq!(parse_error("from edges | where type = \"Größe\" | frob"),
+               "unknown stage: frob, expected where, group by, count, sum, avg, min, max, sort, limit, or select (at column 37)");
+}
+
+#[test]
+fn conditions_of_a_stage_all_hold() {
+    let (_, rows) = eval("from edges | where type = processing and worker = 1 | select operator");
+    assert_eq!(rows, vec![vec![Value::Int(2)]]);
+
+    let (_, rows) = eval("from edges | where duration >= 8ms and duration < 20ms | select duration");
+    assert_eq!(rows, vec![vec![time(10)], vec![time(8)]]);
+}
+
+#[test]
+fn stages_apply_in_order() {
+    let (_, rows) = eval("from edges | sort duration desc | limit 1 | select type");
+    assert_eq!(rows, vec![vec![text("Waiting")]]);
+
+    let (_, rows) = eval("from edges | limit 1 | sort duration desc | select type");
+    assert_eq!(rows, vec![vec![text("Processing")]]);
+
+    // a filter after aggregating filters the groups
+    let (_, rows) = eval("from edges | group by worker | count | where count > 1");
+    assert_eq!(rows, vec![vec![Value::Int(0), Value::Int(4)]]);
+}
+
+#[test]
+fn groups_are_aggregated() {
+    let (columns, rows) = eval("from edges | group by worker | sum(duration), count, max(records)");
+    assert_eq!(columns, vec!["worker", "sum(duration)", "count", "max(records)"]);
+    assert_eq!(rows, vec![
+        vec![Value::Int(0), time(10 + 20 + 2 + 5), Value::Int(4), Value::Null],
+        vec![Value::Int(1), time(8), Value::Int(1), Value::Null],
+    ]);
+
+    // without aggregates, groups are counted
+    let (columns, rows) = eval("from edges | group by type");
+    assert_eq!(columns, vec!["type", "count"]);
+    assert_eq!(rows, vec![
+        vec![text("DataMessage"), Value::Int(1)],
+        vec![text("Processing"), Value::Int(3)],
+        vec![text("Waiting"), Value::Int(1)],
+    ]);
+
+    // without groups, the whole table is aggregated
+    let (_, rows) = eval("from edges | where epoch = 1 | avg(duration)");
+    assert_eq!(rows, vec![vec![time((10 + 20 + 2 + 8) / 4)]]);
+}
+
+#[test]
+fn operators_are_referred_to_by_id_or_name() {
+    let (_, by_name) = eval("from edges | where operator = map | select epoch, start");
+    let (_, by_id) = eval("from edges | where operator = 1 | select epoch, start");
+    assert_eq!(by_name, vec![vec![Value::Int(1), time(0)], vec![Value::Int(2), time(100)]]);
+    assert_eq!(by_name, by_id);
+}
+
+#[test]
+fn critical_paths_skip_waiting() {
+    let (_, rows) = eval("from cp | group by epoch | sum(duration), count");
+    assert_eq!(rows, vec![
+        vec![Value::Int(1), time(10 + 2 + 8), Value::Int(3)],
+        vec![Value::Int(2), time(5), Value::Int(1)],
+    ]);
+}
+
+#[test]
+fn unknown_columns_are_rejected() {
+    let query: Query = "from edges | select nope".parse().expect("query doesn't parse");
+    match query.eval(&pag(), &operator_names()) {
+        Err(STError::Config(message)) => assert!(message.starts_with("unknown column: nope"), "{}", message),
+        result => panic!("evaluated: {:?}", result),
+    }
+}
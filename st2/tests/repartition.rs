@@ -0,0 +1,76 @@
+//! Tests of `--repartition`: constructing the PAG from log records repartitioned
+//! across ST2 workers by epoch yields the same PAG. The block size applies to
+//! the whole process (`pag::repartition_by_epoch`), so this binary has a single test.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use timely::dataflow::ProbeHandle;
+use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::operators::probe::Probe;
+
+use st2::pag::{self, PagEdge};
+use st2_timely::filter::Filter;
+use st2_timely::replay_throttled::ReplaySpeed;
+use st2_timely::synthetic::{MessagePattern, Workload};
+
+fn workload() -> Workload {
+    Workload {
+        workers: 2,
+        operators: 3,
+        epochs: 6,
+        epoch_interval: Duration::from_millis(1),
+        operator_time: Duration::from_micros(100),
+        messages: MessagePattern::AllToAll,
+        records: 10,
+        skew: 2.0,
+    }
+}
+
+/// The PAG (by epoch, with every epoch's edges sorted) `st2_workers` ST2 workers
+/// construct from `workload`, whose source workers are distributed round-robin
+fn construct(workload: &Workload, st2_workers: usize) -> BTreeMap<u64, Vec<PagEdge>> {
+    let workload = workload.clone();
+    let pag = Arc::new(Mutex::new(BTreeMap::new()));
+    let pag_out = Arc::clone(&pag);
+
+    timely::execute(timely::Configuration::Process(st2_workers), move |worker| {
+        let (index, peers) = (worker.index(), worker.peers());
+        let replayers: Vec<_> = (0 .. workload.workers)
+            .filter(|source| source % peers == index)
+            .map(|source| workload.replayer(source))
+            .collect();
+
+        let mut probe = ProbeHandle::new();
+        let pag = Arc::clone(&pag);
+        worker.dataflow(|scope| {
+            pag::create_pag(scope, replayers, index, None, 1, ReplaySpeed::Unbounded, Filter::default())
+                .inspect(move |(edge, t, _)| pag.lock().unwrap().entry(t.first).or_insert_with(Vec::new).push(edge.clone()))
+                .probe_with(&mut probe);
+        });
+        while !probe.done() { worker.step(); }
+    }).expect("timely failed");
+
+    let mut pag = pag_out.lock().unwrap().clone();
+    // edges arrive in a different order from run to run
+    for edges in pag.values_mut() {
+        edges.sort();
+    }
+    pag
+}
+
+#[test]
+fn repartitioning_keeps_the_pag() {
+    // three ST2 workers for two source workers, so one of them stays idle
+    // without repartitioning
+    let workload = workload();
+    let expected = construct(&workload, 3);
+    assert!(!expected.is_empty());
+
+    for block in [1, 2, 4].iter() {
+        pag::repartition_by_epoch(*block);
+        assert_eq!(construct(&workload, 3), expected, "with --repartition {}", block);
+    }
+    pag::repartition_by_epoch(0);
+}
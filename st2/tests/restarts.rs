@@ -0,0 +1,105 @@
+//! Tests of PAG construction across restarts of the source computation, after
+//! which its workers' `seq_no`s start over mid-trace.
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use timely::dataflow::operators::capture::event::Event;
+use timely::logging::{StartStop, TimelyEvent};
+
+use st2::pag::PagEdge;
+use st2_logformat::ActivityType;
+use st2_timely::connect::{CompEvent, TraceEvent};
+use st2_timely::synthetic::{MessagePattern, SyntheticReplayer, Workload};
+
+fn workload() -> Workload {
+    Workload {
+        workers: 2,
+        operators: 3,
+        epochs: 3,
+        epoch_interval: Duration::from_millis(1),
+        operator_time: Duration::from_micros(100),
+        messages: MessagePattern::AllToAll,
+        records: 10,
+        skew: 1.0,
+    }
+}
+
+/// The events of a worker, restarted after its first operator in `epoch`: the
+/// `seq_no`s of all later events start over at 1. Returns the events and the
+/// time of the first event after the restart.
+fn restart(events: Vec<TraceEvent>, epoch: u64) -> (Vec<TraceEvent>, Duration) {
+    let is_first_stop = |(e, _, _, (_, _, x)): &&CompEvent| *e == epoch && match x {
+        TimelyEvent::Schedule(schedule) => schedule.start_stop == StartStop::Stop,
+        _ => false,
+    };
+    let (base, at) = events.iter()
+        .filter_map(|event| match event { Event::Messages(_, batch) => Some(batch), _ => None })
+        .flatten()
+        .skip_while(|event| !is_first_stop(event))
+        .nth(1)
+        .map(|(_, seq_no, _, (t, _, _))| (*seq_no, *t))
+        .expect("no events after the restart");
+
+    let events = events.into_iter().map(|event| match event {
+        Event::Messages(time, batch) => Event::Messages(time, batch.into_iter()
+            .map(|(e, seq_no, length, x)| (e, if seq_no >= base { seq_no - base + 1 } else { seq_no }, length, x))
+            .collect()),
+        progress => progress,
+    }).collect();
+    (events, at)
+}
+
+/// An edge without the `seq_no`s of its nodes, which differ after a restart
+type Key = (u64, Duration, u64, Duration, ActivityType);
+
+fn key(edge: &PagEdge) -> Key {
+    (edge.source.worker_id, edge.source.timestamp, edge.destination.worker_id, edge.destination.timestamp, edge.edge_type)
+}
+
+fn is_remote(edge: &PagEdge) -> bool {
+    edge.source.worker_id != edge.destination.worker_id
+}
+
+#[test]
+fn restarts_keep_messages_of_both_sides() {
+    let workload = workload();
+    let original = workload.clone();
+    let (_, pag) = common::construct(move || (0 .. original.workers).map(|source| original.replayer(source)).collect());
+
+    let restarted = workload.clone();
+    let restarts: Vec<Duration> = (0 .. workload.workers).map(|worker| restart(workload.events(worker), 2).1).collect();
+    let (_, restarted_pag) = common::construct(move || (0 .. restarted.workers)
+        .map(|source| SyntheticReplayer::new(restart(restarted.events(source), 2).0))
+        .collect());
+
+    assert_eq!(pag.keys().collect::<Vec<_>>(), restarted_pag.keys().collect::<Vec<_>>());
+    for (epoch, edges) in pag.iter() {
+        let restarted_edges = &restarted_pag[epoch];
+
+        // every message is matched with its counterpart on the other side, as without the restart
+        let remote = |edges: &[PagEdge]| -> BTreeMap<Key, usize> {
+            let mut keys = BTreeMap::new();
+            for edge in edges.iter().filter(|edge| is_remote(edge)) {
+                *keys.entry(key(edge)).or_insert(0) += 1;
+            }
+            keys
+        };
+        assert!(edges.iter().any(is_remote), "epoch {} has no messages", epoch);
+        assert_eq!(remote(edges), remote(restarted_edges), "messages of epoch {}", epoch);
+
+        // activities are kept, but not connected across the restart
+        let local: Vec<Key> = edges.iter().filter(|edge| !is_remote(edge)).map(key).collect();
+        for edge in restarted_edges.iter().filter(|edge| !is_remote(edge)) {
+            assert!(local.contains(&key(edge)), "epoch {}: {:?} wasn't constructed without the restart", epoch, edge);
+            let restarted_at = restarts[edge.source.worker_id as usize];
+            assert!(*epoch != 2 || edge.source.timestamp >= restarted_at || edge.destination.timestamp < restarted_at,
+                    "epoch {}: {:?} connects activities across the restart", epoch, edge);
+        }
+        if *epoch != 2 {
+            assert_eq!(local.len(), restarted_edges.iter().filter(|edge| !is_remote(edge)).count(), "activities of epoch {}", epoch);
+        }
+    }
+}